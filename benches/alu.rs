@@ -0,0 +1,61 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use i8080_emulator::cpu::CPU;
+use i8080_emulator::memory::{Memory, Memory8080};
+
+#[cfg(feature = "threaded")]
+use i8080_emulator::{threaded::ThreadedCore, I8080Core};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// loop: ADD B; SUB C; ANA D; XRA E; ORA H; CMP L; INR A; DCR B; ADI 0x33; JMP loop
+const ALU_LOOP: [u8; 14] = [
+    0x80, 0x91, 0xa2, 0xab, 0xb4, 0xbd, 0x3c, 0x05, 0xc6, 0x33, 0xc3, 0x00, 0x00, 0x00,
+];
+
+fn alu_cpu() -> CPU {
+    let memory = Rc::new(RefCell::new(Memory8080::new_empty()));
+    for (i, b) in ALU_LOOP.iter().enumerate() {
+        memory.borrow_mut().write(i, *b);
+    }
+    let mut cpu = CPU::new(memory);
+    cpu.regs.c = 0x11;
+    cpu.regs.d = 0x5a;
+    cpu.regs.e = 0xc3;
+    cpu.regs.h = 0x0f;
+    cpu.regs.l = 0x80;
+    cpu
+}
+
+fn alu_loop(c: &mut Criterion) {
+    let mut cpu = alu_cpu();
+
+    c.bench_function("alu loop, 10000 instructions", |b| {
+        b.iter(|| {
+            for _ in 0..10_000 {
+                let op = cpu.fetch();
+                black_box(cpu.exec(op));
+            }
+        })
+    });
+}
+
+#[cfg(feature = "threaded")]
+fn alu_loop_threaded(c: &mut Criterion) {
+    let mut core = ThreadedCore::new(alu_cpu());
+
+    c.bench_function("alu loop, 10000 instructions, threaded", |b| {
+        b.iter(|| {
+            for _ in 0..10_000 {
+                black_box(core.step());
+            }
+        })
+    });
+}
+
+#[cfg(not(feature = "threaded"))]
+criterion_group!(benches, alu_loop);
+#[cfg(feature = "threaded")]
+criterion_group!(benches, alu_loop, alu_loop_threaded);
+criterion_main!(benches);
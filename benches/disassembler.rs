@@ -0,0 +1,40 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use i8080_emulator::disassembler::Disassembler;
+use i8080_emulator::memory::{Memory, Memory8080};
+
+// MVI A, 0x3e; LXI H, 0x2400; MOV M, A; INX H; CPI 0x40; JNZ 0x0005; OUT 0x03
+const CODE: [u8; 16] = [
+    0x3e, 0x3e, 0x21, 0x00, 0x24, 0x77, 0x23, 0xfe, 0x40, 0xc2, 0x05, 0x00, 0xd3, 0x03, 0x00, 0x00,
+];
+
+fn trace_formatting(c: &mut Criterion) {
+    let mut memory = Memory8080::new_empty();
+    for (i, b) in CODE.iter().enumerate() {
+        memory.write(i, *b);
+    }
+    let disassembler = Disassembler::new();
+    let pcs = [0u16, 2, 5, 6, 7, 9, 12];
+
+    c.bench_function("disassemble (String)", |b| {
+        b.iter(|| {
+            for pc in pcs.iter() {
+                let op = memory.read((*pc).into());
+                black_box(disassembler.disassemble(&memory, pc, &op, &0x2400));
+            }
+        })
+    });
+
+    c.bench_function("disassemble_into (buffer)", |b| {
+        let mut buf = [0; 48];
+        b.iter(|| {
+            for pc in pcs.iter() {
+                let op = memory.read((*pc).into());
+                black_box(disassembler.disassemble_into(&memory, pc, &op, &0x2400, &mut buf));
+            }
+        })
+    });
+}
+
+criterion_group!(benches, trace_formatting);
+criterion_main!(benches);
@@ -0,0 +1,62 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use i8080_emulator::cpu::CPU;
+use i8080_emulator::loader::load_bytes;
+use i8080_emulator::machines::cpm::CpmMachine;
+use i8080_emulator::memory::{AccessObserver, Checks, Memory, Memory8080, Unchecked};
+use i8080_emulator::Machine;
+
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+// Runs a CP/M test ROM from start to warm boot, returning the
+// instructions it took
+fn run_rom(program: &[u8]) -> u64 {
+    let mut machine = CpmMachine::new(program, io::sink()).unwrap();
+    let mut instructions = 0;
+    while !machine.is_finished() {
+        black_box(machine.next().unwrap());
+        instructions += 1;
+    }
+    instructions
+}
+
+// Runs a CP/M test ROM on a bare CPU whose memory has `observer`, with
+// the BDOS calls trapped and ignored, returning the instructions it took
+fn run_observed<O: AccessObserver + 'static>(program: &[u8], observer: O) -> u64 {
+    let mut memory = Memory8080::with_observer(Box::new([0x00; 0x10000]), observer);
+    load_bytes(&mut memory, program, 0x0100).unwrap();
+    memory.write(0x0005, 0xc9);
+    let mut cpu = CPU::new(Rc::new(RefCell::new(memory)));
+    cpu.pc = 0x0100;
+    cpu.add_trap(0x0005, |_, _| None);
+    let mut instructions = 0;
+    while cpu.pc != 0x0000 {
+        black_box(cpu.step());
+        instructions += 1;
+    }
+    instructions
+}
+
+fn test_roms(c: &mut Criterion) {
+    let mut group = c.benchmark_group("exec");
+    for name in ["TST8080", "8080PRE"].iter() {
+        let program = std::fs::read(format!("cpu_tests/{}.COM", name)).unwrap();
+        group.throughput(Throughput::Elements(run_rom(&program)));
+        group.bench_function(*name, |b| b.iter(|| run_rom(&program)));
+    }
+    group.finish();
+}
+
+fn observers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("observer");
+    let program = std::fs::read("cpu_tests/8080PRE.COM").unwrap();
+    group.throughput(Throughput::Elements(run_observed(&program, Unchecked)));
+    group.bench_function("checks", |b| b.iter(|| run_observed(&program, Checks::default())));
+    group.bench_function("unchecked", |b| b.iter(|| run_observed(&program, Unchecked)));
+    group.finish();
+}
+
+criterion_group!(benches, test_roms, observers);
+criterion_main!(benches);
@@ -0,0 +1,28 @@
+use i8080_emulator::bare::{BareConfig, BareMachine};
+use i8080_emulator::Machine;
+
+use std::env;
+use std::fs;
+
+// Runs a raw binary as a bare program: `bare PROGRAM [--cpm]`
+fn main() {
+    let mut args = env::args().skip(1);
+    let path = args.next().expect("Needs a program");
+    let config = match args.next().as_deref() {
+        Some("--cpm") => BareConfig::cpm(),
+        _ => BareConfig::default(),
+    };
+    let program = match fs::read(&path) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", path, e);
+            return;
+        }
+    };
+    let mut machine = BareMachine::with_config(&program, config);
+    let result = machine.run();
+    print!("{}", machine.output());
+    if let Err(e) = result {
+        eprintln!("\nProgram stopped: {}", e);
+    }
+}
@@ -0,0 +1,24 @@
+use i8080_emulator::monitor::MonitorMachine;
+use i8080_emulator::Machine;
+
+use std::io::{self, BufRead, Write};
+
+// Boots the built-in monitor with stdin and stdout as its console
+fn main() {
+    let mut machine = MonitorMachine::new();
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        if let Err(e) = machine.run() {
+            print!("{}", machine.take_output());
+            eprintln!("\nMachine stopped: {}", e);
+            return;
+        }
+        print!("{}", machine.take_output().replace("\r\n", "\n"));
+        io::stdout().flush().unwrap();
+        match lines.next() {
+            Some(Ok(line)) => machine.send(&format!("{}\r", line)),
+            _ => return,
+        }
+    }
+}
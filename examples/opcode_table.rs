@@ -0,0 +1,11 @@
+use i8080_emulator::opcodes;
+
+use std::io;
+
+// Prints the opcode table as JSON, for comparing with other emulators
+fn main() {
+    let stdout = io::stdout();
+    if let Err(e) = opcodes::write_json(&mut stdout.lock()) {
+        eprintln!("Could not write the table: {}", e);
+    }
+}
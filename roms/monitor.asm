@@ -0,0 +1,269 @@
+; Tiny serial monitor for the built-in machine profile (src/monitor.rs).
+;
+; Commands, one per line, hex arguments:
+;   D aaaa          dump 16 bytes from aaaa
+;   E aaaa bb ...   deposit bytes from aaaa on
+;   G aaaa          call aaaa; RET returns to the monitor
+;   L               load Intel HEX records until an end record
+;
+; Assembles with any Intel-syntax 8080 assembler into monitor.bin.
+
+SSTAT   EQU     10H             ; serial status: bit 0 RX ready, bit 1 TX ready
+SDATA   EQU     11H             ; serial data
+STACK   EQU     0FF00H
+LINBUF  EQU     0FF00H          ; 64-byte line buffer above the stack
+
+        ORG     0
+
+START:  LXI     SP,STACK
+        LXI     H,BANNER
+        CALL    PUTS
+MAIN:   LXI     SP,STACK
+        LXI     H,PROMPT
+        CALL    PUTS
+        CALL    GETLINE
+        LXI     H,LINBUF
+        CALL    SKIPSP
+        MOV     A,M
+        ORA     A
+        JZ      MAIN
+        INX     H
+        CPI     'D'
+        JZ      DUMP
+        CPI     'E'
+        JZ      DEPOS
+        CPI     'G'
+        JZ      GO
+        CPI     'L'
+        JZ      LOAD
+ERROR:  LXI     H,ERRMSG
+        CALL    PUTS
+        JMP     MAIN
+
+; D aaaa
+DUMP:   CALL    GETHEX
+        JC      ERROR
+        XCHG
+        CALL    PHEX4
+        MVI     A,':'
+        CALL    PUTC
+        MVI     B,16
+DUMP1:  MVI     A,' '
+        CALL    PUTC
+        MOV     A,M
+        CALL    PHEX2
+        INX     H
+        DCR     B
+        JNZ     DUMP1
+        CALL    CRLF
+        JMP     MAIN
+
+; E aaaa bb ...
+DEPOS:  CALL    GETHEX
+        JC      ERROR
+        PUSH    D
+DEPOS1: CALL    GETHEX
+        JC      DEPOS2
+        XTHL
+        MOV     M,E
+        INX     H
+        XTHL
+        JMP     DEPOS1
+DEPOS2: POP     D
+        MOV     A,M
+        ORA     A
+        JNZ     ERROR
+        JMP     MAIN
+
+; G aaaa
+GO:     CALL    GETHEX
+        JC      ERROR
+        LXI     H,MAIN
+        PUSH    H
+        XCHG
+        PCHL
+
+; L, then :LLAAAATTDD...CC records
+LOAD:   CALL    GETC
+        CPI     ':'
+        JNZ     LOAD
+        MVI     C,0             ; running checksum
+        CALL    GETBYTE
+        MOV     B,A             ; data length
+        CALL    GETBYTE
+        MOV     H,A
+        CALL    GETBYTE
+        MOV     L,A
+        CALL    GETBYTE
+        MOV     D,A             ; record type
+        MOV     A,B
+        ORA     A
+        JZ      LOAD2
+LOAD1:  CALL    GETBYTE
+        INR     D
+        DCR     D
+        JNZ     LOAD1S          ; only data records are stored
+        MOV     M,A
+LOAD1S: INX     H
+        DCR     B
+        JNZ     LOAD1
+LOAD2:  CALL    GETBYTE         ; checksum
+        MOV     A,C
+        ORA     A
+        JNZ     ERROR
+        MVI     A,'.'
+        CALL    PUTC
+        MOV     A,D
+        CPI     1
+        JNZ     LOAD
+        CALL    CRLF
+        JMP     MAIN
+
+; Reads two hex digits into A and adds them to the checksum in C.
+; Clobbers E. Bad digits abort to ERROR.
+GETBYTE: CALL   GETC
+        CALL    HEXVAL
+        JC      ERROR
+        RLC
+        RLC
+        RLC
+        RLC
+        MOV     E,A
+        CALL    GETC
+        CALL    HEXVAL
+        JC      ERROR
+        ORA     E
+        MOV     E,A
+        ADD     C
+        MOV     C,A
+        MOV     A,E
+        RET
+
+; Parses a hex number at HL into DE, leaving HL after it.
+; Carry set if there were no digits. Clobbers A, B.
+GETHEX: CALL    SKIPSP
+        LXI     D,0
+        MVI     B,0
+GETHX1: MOV     A,M
+        CALL    HEXVAL
+        JC      GETHX2
+        XCHG
+        DAD     H
+        DAD     H
+        DAD     H
+        DAD     H
+        ORA     L
+        MOV     L,A
+        XCHG
+        INX     H
+        INR     B
+        JMP     GETHX1
+GETHX2: MOV     A,B
+        CPI     1
+        RET
+
+; Converts the hex digit in A to its value. Carry set if not a digit.
+HEXVAL: SUI     '0'
+        RC
+        CPI     10
+        CMC
+        RNC
+        SUI     'A'-'0'
+        RC
+        CPI     6
+        CMC
+        RC
+        ADI     10
+        RET
+
+SKIPSP: MOV     A,M
+        CPI     ' '
+        RNZ
+        INX     H
+        JMP     SKIPSP
+
+; Reads a line into LINBUF, zero-terminated and uppercased, with echo.
+GETLINE: LXI    H,LINBUF
+        MVI     B,0
+GETLN1: CALL    GETC
+        CPI     0DH
+        JZ      GETLN4
+        CPI     0AH
+        JZ      GETLN1
+        CPI     08H
+        JZ      GETLN3
+        MOV     C,A
+        MOV     A,B
+        CPI     63
+        JNC     GETLN1
+        MOV     A,C
+        CPI     'a'
+        JC      GETLN2
+        CPI     'z'+1
+        JNC     GETLN2
+        ANI     0DFH
+GETLN2: MOV     M,A
+        INX     H
+        INR     B
+        CALL    PUTC
+        JMP     GETLN1
+GETLN3: MOV     A,B
+        ORA     A
+        JZ      GETLN1
+        DCX     H
+        DCR     B
+        MVI     A,08H
+        CALL    PUTC
+        MVI     A,' '
+        CALL    PUTC
+        MVI     A,08H
+        CALL    PUTC
+        JMP     GETLN1
+GETLN4: MVI     M,0
+        JMP     CRLF
+
+PHEX4:  MOV     A,H
+        CALL    PHEX2
+        MOV     A,L
+PHEX2:  PUSH    PSW
+        RRC
+        RRC
+        RRC
+        RRC
+        CALL    PHEX1
+        POP     PSW
+PHEX1:  ANI     0FH
+        ADI     '0'
+        CPI     '9'+1
+        JC      PUTC
+        ADI     7
+        JMP     PUTC
+
+CRLF:   MVI     A,0DH
+        CALL    PUTC
+        MVI     A,0AH
+        JMP     PUTC
+
+PUTS:   MOV     A,M
+        ORA     A
+        RZ
+        CALL    PUTC
+        INX     H
+        JMP     PUTS
+
+; The emulated port is always ready to transmit, so PUTC does not poll
+; TX ready. Polling would also read as waiting for input to the host.
+PUTC:   OUT     SDATA
+        RET
+
+GETC:   IN      SSTAT
+        ANI     1
+        JZ      GETC
+        IN      SDATA
+        RET
+
+BANNER: DB      '8080 MONITOR',0DH,0AH,0
+PROMPT: DB      '> ',0
+ERRMSG: DB      '?',0DH,0AH,0
+
+        END
@@ -0,0 +1,261 @@
+//! The arithmetic and logic operations of the 8080, written as plain
+//! functions over a `(value, flags)` pair so they can be tested without a
+//! CPU. Every function takes the current flag byte and returns the updated
+//! one; flags an operation does not affect are passed through untouched.
+
+use crate::registers::Flag;
+
+const S: u8 = 1 << Flag::S as u8;
+const Z: u8 = 1 << Flag::Z as u8;
+const A: u8 = 1 << Flag::A as u8;
+const P: u8 = 1 << Flag::P as u8;
+const C: u8 = 1 << Flag::C as u8;
+const SZAP: u8 = S | Z | A | P;
+const ALL: u8 = SZAP | C;
+
+// Sign, zero and parity flags for every possible result byte, so all three
+// can be set with a single lookup.
+const SZP_FLAGS: [u8; 256] = szp_table();
+
+const fn szp_table() -> [u8; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let n = i as u8;
+        let mut f = 0;
+        if n & 0x80 == 0x80 {
+            f |= S;
+        }
+        if n == 0x00 {
+            f |= Z;
+        }
+        if n.count_ones() & 0x01 == 0x00 {
+            f |= P;
+        }
+        table[i] = f;
+        i += 1;
+    }
+    table
+}
+
+#[inline]
+fn flag(set: bool, f: u8) -> u8 {
+    if set { f } else { 0 }
+}
+
+#[inline]
+fn szp(n: u8) -> u8 {
+    SZP_FLAGS[n as usize]
+}
+
+#[inline]
+pub fn inr(n: u8, f: u8) -> (u8, u8) {
+    let r = n.wrapping_add(1);
+    let f = (f & !SZAP) | szp(r) | flag((n & 0x0f) + 1 > 0x0f, A);
+    (r, f)
+}
+
+#[inline]
+pub fn dcr(n: u8, f: u8) -> (u8, u8) {
+    let r = n.wrapping_sub(1);
+    let f = (f & !SZAP) | szp(r) | flag((r & 0x0f) != 0x0f, A);
+    (r, f)
+}
+
+#[inline]
+pub fn add(a: u8, b: u8, f: u8) -> (u8, u8) {
+    let r = a.wrapping_add(b);
+    let f = (f & !ALL) | szp(r)
+        | flag((a & 0x0f) + (b & 0x0f) > 0x0f, A)
+        | flag(u16::from(a) + u16::from(b) > 0xff, C);
+    (r, f)
+}
+
+#[inline]
+pub fn adc(a: u8, b: u8, f: u8) -> (u8, u8) {
+    let carry = f & C;
+    let r = a.wrapping_add(b).wrapping_add(carry);
+    let f = (f & !ALL) | szp(r)
+        | flag((a & 0x0f) + (b & 0x0f) + carry > 0x0f, A)
+        | flag(u16::from(a) + u16::from(b) + u16::from(carry) > 0xff, C);
+    (r, f)
+}
+
+#[inline]
+pub fn sub(a: u8, b: u8, f: u8) -> (u8, u8) {
+    let r = a.wrapping_sub(b);
+    let f = (f & !ALL) | szp(r)
+        | flag((a as i8 & 0x0f) - (b as i8 & 0x0f) >= 0x00, A)
+        | flag(a < b, C);
+    (r, f)
+}
+
+#[inline]
+pub fn sbb(a: u8, b: u8, f: u8) -> (u8, u8) {
+    let carry = f & C;
+    let r = a.wrapping_sub(b).wrapping_sub(carry);
+    let f = (f & !ALL) | szp(r)
+        | flag((a as i8 & 0x0f) - (b as i8 & 0x0f) - (carry as i8) >= 0x00, A)
+        | flag(u16::from(a) < u16::from(b) + u16::from(carry), C);
+    (r, f)
+}
+
+#[inline]
+pub fn ana(a: u8, b: u8, f: u8) -> (u8, u8) {
+    let r = a & b;
+    (r, (f & !ALL) | szp(r) | flag(((a | b) & 0x08) != 0, A))
+}
+
+#[inline]
+pub fn xra(a: u8, b: u8, f: u8) -> (u8, u8) {
+    let r = a ^ b;
+    (r, (f & !ALL) | szp(r))
+}
+
+#[inline]
+pub fn ora(a: u8, b: u8, f: u8) -> (u8, u8) {
+    let r = a | b;
+    (r, (f & !ALL) | szp(r))
+}
+
+/// Compares by subtracting, leaving the accumulator unchanged.
+#[inline]
+pub fn cmp(a: u8, b: u8, f: u8) -> (u8, u8) {
+    (a, sub(a, b, f).1)
+}
+
+#[inline]
+pub fn rlc(a: u8, f: u8) -> (u8, u8) {
+    (a.rotate_left(1), (f & !C) | (a >> 7))
+}
+
+#[inline]
+pub fn rrc(a: u8, f: u8) -> (u8, u8) {
+    (a.rotate_right(1), (f & !C) | (a & 0x01))
+}
+
+#[inline]
+pub fn ral(a: u8, f: u8) -> (u8, u8) {
+    ((a << 1) | (f & C), (f & !C) | (a >> 7))
+}
+
+#[inline]
+pub fn rar(a: u8, f: u8) -> (u8, u8) {
+    ((a >> 1) | ((f & C) << 7), (f & !C) | (a & 0x01))
+}
+
+#[inline]
+pub fn stc(a: u8, f: u8) -> (u8, u8) {
+    (a, f | C)
+}
+
+#[inline]
+pub fn cmc(a: u8, f: u8) -> (u8, u8) {
+    (a, f ^ C)
+}
+
+/// Adds `n` to HL, only touching the carry flag.
+#[inline]
+pub fn dad(hl: u16, n: u16, f: u8) -> (u16, u8) {
+    let (r, carry) = hl.overflowing_add(n);
+    (r, (f & !C) | flag(carry, C))
+}
+
+#[inline]
+pub fn daa(a: u8, f: u8) -> (u8, u8) {
+    let hi = a >> 4;
+    let lo = a & 0x0f;
+    let mut correction = 0;
+    let mut carry = f & C == C;
+    if lo > 9 || f & A == A {
+        correction += 0x06;
+    }
+
+    if hi > 9 || carry || (hi >= 9 && lo > 9) {
+        correction += 0x60;
+        carry = true;
+    }
+    let (r, f) = add(a, correction, f);
+    (r, (f & !C) | flag(carry, C))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alu;
+
+    const FIXED: u8 = 0x02;
+
+    #[test]
+    fn szp_table() {
+        for n in 0..=0xffu8 {
+            let f = alu::szp(n);
+            assert_eq!(f & 0x80 == 0x80, n & 0x80 == 0x80);
+            assert_eq!(f & 0x40 == 0x40, n == 0);
+            assert_eq!(f & 0x04 == 0x04, n.count_ones() % 2 == 0);
+            assert_eq!(f & !0xc4, 0);
+        }
+    }
+
+    #[test]
+    fn add_sets_carry_and_aux_carry() {
+        assert_eq!(alu::add(0xff, 0x01, FIXED), (0x00, 0x57));
+        assert_eq!(alu::add(0x3c, 0x05, FIXED), (0x41, 0x16));
+    }
+
+    #[test]
+    fn adc_uses_carry_in() {
+        assert_eq!(alu::adc(0x01, 0x01, 0x01).0, 0x03);
+        assert_eq!(alu::adc(0x01, 0x01, 0x00).0, 0x02);
+    }
+
+    #[test]
+    fn sub_borrows() {
+        let (r, f) = alu::sub(0x01, 0x02, FIXED);
+        assert_eq!(r, 0xff);
+        assert_eq!(f, 0x87);
+    }
+
+    #[test]
+    fn cmp_keeps_accumulator() {
+        assert_eq!(alu::cmp(0x10, 0x10, FIXED), (0x10, 0x56));
+    }
+
+    #[test]
+    fn inr_dcr_keep_carry() {
+        assert_eq!(alu::inr(0xff, 0x01).1 & 0x01, 0x01);
+        assert_eq!(alu::dcr(0x00, FIXED | 0x01), (0xff, 0x87));
+    }
+
+    #[test]
+    fn logic_clears_carry() {
+        assert_eq!(alu::ana(0xf0, 0x0f, FIXED | 0x01), (0x00, 0x56));
+        assert_eq!(alu::xra(0xff, 0xff, FIXED | 0x01), (0x00, 0x46));
+        assert_eq!(alu::ora(0x01, 0x00, FIXED | 0x01), (0x01, 0x02));
+    }
+
+    #[test]
+    fn rotates() {
+        assert_eq!(alu::rlc(0x80, FIXED), (0x01, 0x03));
+        assert_eq!(alu::rrc(0x01, FIXED), (0x80, 0x03));
+        assert_eq!(alu::ral(0x80, FIXED), (0x00, 0x03));
+        assert_eq!(alu::rar(0x00, 0x03), (0x80, 0x02));
+    }
+
+    #[test]
+    fn carry_operations() {
+        assert_eq!(alu::stc(0x12, FIXED), (0x12, 0x03));
+        assert_eq!(alu::cmc(0x12, 0x03), (0x12, 0x02));
+        assert_eq!(alu::dad(0x8000, 0x8000, 0xd6), (0x0000, 0xd7));
+        assert_eq!(alu::dad(0x1234, 0x1111, 0x03), (0x2345, 0x02));
+    }
+
+    #[test]
+    fn daa_adjusts_bcd() {
+        let (r, f) = alu::add(0x19, 0x28, FIXED);
+        assert_eq!(alu::daa(r, f).0, 0x47);
+        let (r, f) = alu::add(0x99, 0x01, FIXED);
+        let (r, f) = alu::daa(r, f);
+        assert_eq!(r, 0x00);
+        assert_eq!(f & 0x01, 0x01);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::disassembler::{Disassembler, Instruction, Operand, Reg16, Reg8};
+use crate::memory::Memory;
+use crate::opcodes::OPCODES;
+
+/// Lower and upper bound on the clock cycles a piece of code can take.
+/// The bounds differ when conditional calls and returns are involved, since
+/// those take longer when the branch is taken. Both stop at `u32::MAX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleCost {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl CycleCost {
+    fn add(self, other: CycleCost) -> CycleCost {
+        CycleCost {
+            min: self.min.saturating_add(other.min),
+            max: self.max.saturating_add(other.max),
+        }
+    }
+}
+
+/// Length in bytes of the instruction starting with `op`.
+pub fn instruction_length(op: u8) -> u16 {
+    u16::from(OPCODES[op as usize].length)
+}
+
+/// Cycles taken by the instruction starting with `op`. `min` is the
+/// not-taken case of a conditional call or return.
+pub fn instruction_cycles(op: u8) -> CycleCost {
+    let info = OPCODES[op as usize];
+    CycleCost {
+        min: info.cycles.into(),
+        max: info.cycles_taken.into(),
+    }
+}
+
+/// Estimates the cost of executing the instructions in `start..end` once,
+/// in order. Control flow is not followed: every instruction in the range
+/// is counted exactly once, so this is meant for straight-line code.
+pub fn block_cost(memory: &impl Memory, start: u16, end: u16) -> CycleCost {
+    let mut cost = CycleCost { min: 0, max: 0 };
+    // Counted in bytes, so an instruction running past 0xffff ends the
+    // walk instead of wrapping back to the bottom of memory
+    let len = u32::from(end.saturating_sub(start));
+    let mut offset = 0;
+    while offset < len {
+        let op = memory.read(usize::from(start) + offset as usize);
+        cost = cost.add(instruction_cycles(op));
+        offset += u32::from(instruction_length(op));
+    }
+    cost
+}
+
+/// Estimates the cost of a simple loop whose body spans `start..end` (the
+/// closing branch included) and runs `iterations` times.
+///
+/// A classic delay loop such as
+///
+/// ```text
+/// loop: DCR B
+///       JNZ loop
+/// ```
+///
+/// costs `15 * B` cycles.
+pub fn loop_cost(memory: &impl Memory, start: u16, end: u16, iterations: u32) -> CycleCost {
+    let body = block_cost(memory, start, end);
+    CycleCost {
+        min: body.min.saturating_mul(iterations),
+        max: body.max.saturating_mul(iterations),
+    }
+}
+
+/// A common instruction pattern found in a listing, covering `len`
+/// instructions from `addr` on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Idiom {
+    pub addr: u16,
+    pub len: usize,
+    pub note: &'static str,
+}
+
+// Matches the idiom starting at the head of `code`, if any.
+fn match_idiom(code: &[Instruction]) -> Option<(usize, &'static str)> {
+    use Operand::*;
+    let a = Reg(Reg8::A);
+    let first = code.first()?;
+    let second = code.get(1);
+    let ops = first.operands.as_slice();
+    match (first.mnemonic, ops, second.map(|i| (i.mnemonic, i.operands.as_slice()))) {
+        ("LXI", [RegPair(Reg16::HL), _], Some(("SPHL", _))) => Some((2, "stack setup via HL")),
+        ("LXI", [RegPair(Reg16::SP), _], _) => Some((1, "stack setup")),
+        ("LXI", [RegPair(rp), _], Some(("DAD", [RegPair(dad)]))) if rp == dad => {
+            Some((2, "HL += constant (address math)"))
+        }
+        ("CMA", _, Some(("INR", [r]))) if *r == a => Some((2, "negate A")),
+        ("DCR", [r], Some(("JNZ", [Addr(target)]))) if *target <= first.addr && *r != Mem => {
+            Some((2, "counted loop"))
+        }
+        ("DAD", [RegPair(Reg16::HL)], _) => Some((1, "HL *= 2")),
+        ("XRA", [r], _) | ("SUB", [r], _) if *r == a => Some((1, "clear A")),
+        ("ORA", [r], _) | ("ANA", [r], _) if *r == a => Some((1, "test A, clear carry")),
+        ("XCHG", _, Some(("XCHG", _))) => Some((2, "no-op")),
+        _ => None,
+    }
+}
+
+/// Looks for well-known idioms in a straight-line listing, such as
+/// `XRA A` to clear the accumulator or `LXI H, n` followed by `SPHL` to
+/// set up the stack. Each instruction is part of at most one idiom.
+pub fn find_idioms(code: &[Instruction]) -> Vec<Idiom> {
+    let mut idioms = Vec::new();
+    let mut i = 0;
+    while i < code.len() {
+        match match_idiom(&code[i..]) {
+            Some((len, note)) => {
+                idioms.push(Idiom { addr: code[i].addr, len, note });
+                i += len;
+            }
+            None => i += 1,
+        }
+    }
+    idioms
+}
+
+/// Disassembles `start..end` one instruction per line, with recognized
+/// idioms noted as a comment on their first line.
+pub fn annotated_listing(disassembler: &Disassembler, memory: &impl Memory, start: u16, end: u16) -> Vec<String> {
+    let code: Vec<Instruction> = disassembler.instructions(memory, start).until(end).collect();
+    let idioms = find_idioms(&code);
+    code.into_iter()
+        .map(|instruction| match idioms.iter().find(|idiom| idiom.addr == instruction.addr) {
+            Some(idiom) => format!("{} ; {}", instruction.text, idiom.note),
+            None => instruction.text,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::{annotated_listing, block_cost, find_idioms, instruction_length, loop_cost, CycleCost};
+    use crate::disassembler::Disassembler;
+    use crate::memory::{Memory, Memory8080};
+
+    #[test]
+    fn lengths() {
+        assert_eq!(instruction_length(0x00), 1);
+        assert_eq!(instruction_length(0x3e), 2);
+        assert_eq!(instruction_length(0xfe), 2);
+        assert_eq!(instruction_length(0xdb), 2);
+        assert_eq!(instruction_length(0x21), 3);
+        assert_eq!(instruction_length(0xc4), 3);
+        assert_eq!(instruction_length(0xc9), 1);
+    }
+
+    #[test]
+    fn straight_line_block() {
+        let mut memory = Memory8080::new_empty();
+        // MVI B, 0x10; LXI H, 0x2000; MOV A, M; RZ
+        for (i, b) in [0x06, 0x10, 0x21, 0x00, 0x20, 0x7e, 0xc8].iter().enumerate() {
+            memory.write(i, *b);
+        }
+        assert_eq!(block_cost(&memory, 0, 7), CycleCost { min: 29, max: 35 });
+    }
+
+    #[test]
+    fn delay_loop() {
+        let mut memory = Memory8080::new_empty();
+        // loop: DCR B; JNZ loop
+        for (i, b) in [0x05, 0xc2, 0x00, 0x00].iter().enumerate() {
+            memory.write(i, *b);
+        }
+        assert_eq!(loop_cost(&memory, 0, 4, 0x10), CycleCost { min: 240, max: 240 });
+        assert_eq!(loop_cost(&memory, 0, 4, u32::MAX), CycleCost { min: u32::MAX, max: u32::MAX });
+    }
+
+    #[test]
+    fn block_at_the_top_of_memory() {
+        let mut memory = Memory8080::new_empty();
+        // NOP; JMP 0x0000, its operand past the end of memory
+        memory.write(0xfffd, 0x00);
+        memory.write(0xfffe, 0xc3);
+        assert_eq!(block_cost(&memory, 0xfffd, 0xffff), CycleCost { min: 14, max: 14 });
+        assert_eq!(block_cost(&memory, 0xffff, 0x0000), CycleCost { min: 0, max: 0 });
+    }
+
+    fn load(code: &[u8]) -> Memory8080 {
+        let mut memory = Memory8080::new_empty();
+        for (i, b) in code.iter().enumerate() {
+            memory.write(0x100 + i, *b);
+        }
+        memory
+    }
+
+    #[test]
+    fn idioms_in_listing() {
+        // LXI H, 0x2400; SPHL; XRA A; LXI D, 0x0010; DAD D; ORA A
+        let memory = load(&[0x21, 0x00, 0x24, 0xf9, 0xaf, 0x11, 0x10, 0x00, 0x19, 0xb7]);
+        let disassembler = Disassembler::new();
+        let code: Vec<_> = [0x100u16, 0x103, 0x104, 0x105, 0x108, 0x109].iter()
+            .map(|pc| disassembler.decode(&memory, pc, &0))
+            .collect();
+        let notes: Vec<_> = find_idioms(&code).iter().map(|i| (i.addr, i.len, i.note)).collect();
+        assert_eq!(notes, [
+            (0x100, 2, "stack setup via HL"),
+            (0x104, 1, "clear A"),
+            (0x105, 2, "HL += constant (address math)"),
+            (0x109, 1, "test A, clear carry"),
+        ]);
+    }
+
+    #[test]
+    fn counted_loop_only_branches_backwards() {
+        // loop: DCR B; JNZ loop; DCR C; JNZ 0x0200
+        let memory = load(&[0x05, 0xc2, 0x00, 0x01, 0x0d, 0xc2, 0x00, 0x02]);
+        let listing = annotated_listing(&Disassembler::new(), &memory, 0x100, 0x108);
+        assert_eq!(listing.len(), 4);
+        assert_eq!(listing[0], "100    DCR B ; counted loop");
+        assert_eq!(listing[2], "104    DCR C");
+    }
+}
@@ -0,0 +1,586 @@
+//! A small two-pass assembler for Intel 8080 mnemonics, handy for tests
+//! and for building short programs without hand-encoding them.
+//!
+//! Each line holds at most one statement, optionally preceded by a
+//! `label:` and followed by a `; comment`. Besides the instructions, as
+//! listed in `opcodes::OPCODES`, the directives `ORG`, `EQU`, `DB` and
+//! `DW` are understood. Operands are separated by commas; numeric ones
+//! are expressions of numbers (`42`, `0x2a`, `2Ah`, `101010b`), character
+//! literals (`'*'`), symbols and `$`, the address of the current
+//! statement, joined with `+` and `-`. Mnemonics, registers and symbols
+//! are not case sensitive.
+//!
+//! `assemble_object` assembles a module of a larger program instead, for
+//! `linker::link` to put together with the others at whatever address it
+//! is to load at. A module names the symbols it shares with `PUBLIC` and
+//! the ones it uses from other modules with `EXTRN`.
+//!
+//! ```
+//! use i8080_emulator::assembler::assemble;
+//!
+//! let code = assemble("
+//!         MVI  B, 3
+//! loop:   DCR  B          ; count down
+//!         JNZ  loop
+//!         HLT
+//! ", 0x0100).unwrap();
+//! assert_eq!(code, [0x06, 0x03, 0x05, 0xc2, 0x02, 0x01, 0x76]);
+//! ```
+
+use crate::linker::{External, Object, Public};
+use crate::opcodes::OPCODES;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+/// What is wrong with a line of source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    /// No instruction has this mnemonic and these operands.
+    UnknownInstruction(String),
+    UndefinedSymbol(String),
+    /// A label or `EQU` names a symbol defined already.
+    DuplicateSymbol(String),
+    /// An operand that is not a valid expression or string.
+    BadOperand(String),
+    /// A value too large for the byte or word it goes in.
+    OutOfRange(i64),
+    /// `ORG` below the address reached so far or below the origin, or
+    /// code running past 0xffff.
+    BadAddress(i64),
+    /// A statement put together wrongly, such as `EQU` without a name.
+    Syntax(&'static str),
+    /// An expression in an object module that the linker could not fix
+    /// up, such as a label in a byte or the difference of two external
+    /// symbols.
+    NotRelocatable(String),
+}
+
+/// An assembly error and the line it is on, counting from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsmErrorKind::UnknownInstruction(text) => write!(f, "unknown instruction `{}`", text),
+            AsmErrorKind::UndefinedSymbol(name) => write!(f, "undefined symbol `{}`", name),
+            AsmErrorKind::DuplicateSymbol(name) => write!(f, "`{}` is defined twice", name),
+            AsmErrorKind::BadOperand(text) => write!(f, "bad operand `{}`", text),
+            AsmErrorKind::OutOfRange(value) => write!(f, "{} does not fit", value),
+            AsmErrorKind::BadAddress(addr) => write!(f, "address 0x{:x} is out of order or range", addr),
+            AsmErrorKind::Syntax(reason) => f.write_str(reason),
+            AsmErrorKind::NotRelocatable(text) => write!(f, "`{}` cannot be relocated", text),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl Error for AsmError {}
+
+// One line of source, split up but not yet evaluated
+struct Statement<'a> {
+    line: usize,
+    label: Option<&'a str>,
+    // The mnemonic or directive, uppercased
+    op: String,
+    operands: Vec<&'a str>,
+}
+
+// What a statement turns into, worked out in the first pass
+enum Item {
+    Nothing,
+    Org(u32),
+    Instruction { opcode: u8, length: u8 },
+    Bytes,
+    Words,
+    Public,
+}
+
+// The value of an expression or symbol. In an object module `relocations`
+// counts how many times the load address of the module is in it, and
+// `external` is a symbol of another module to be added in.
+#[derive(Debug, Clone, PartialEq)]
+struct Value {
+    number: i64,
+    relocations: i64,
+    external: Option<String>,
+}
+
+impl Value {
+    fn absolute(number: i64) -> Self {
+        Value { number, relocations: 0, external: None }
+    }
+
+    // An address in the code being assembled
+    fn address(number: u32) -> Self {
+        Value { number: i64::from(number), relocations: 1, external: None }
+    }
+}
+
+// What the second pass produces: the code and, for an object module,
+// what the linker has to fix up in it
+#[derive(Default)]
+struct Output {
+    code: Vec<u8>,
+    relocations: Vec<u16>,
+    externals: Vec<External>,
+    publics: Vec<Public>,
+}
+
+/// Assembles `source` for loading at `org` and returns the bytes from
+/// `org` up to the end of the last statement. Gaps left by `ORG` are
+/// filled with 0x00.
+pub fn assemble(source: &str, org: u16) -> Result<Vec<u8>, AsmError> {
+    Ok(assemble_output(source, org, false)?.code)
+}
+
+/// Assembles `source` as one module of a program for `linker::link`.
+/// Addresses start at 0 at the start of the module, so `ORG` sets an
+/// offset into it, and every word operand holding an address in the
+/// module is recorded for the linker to relocate.
+///
+/// ```
+/// use i8080_emulator::assembler::assemble_object;
+/// use i8080_emulator::linker::link;
+///
+/// let main = assemble_object("
+///         EXTRN print
+///         LXI  H, message
+///         CALL print
+///         HLT
+/// message: DB 'hi', 0
+/// ").unwrap();
+/// let print = assemble_object("
+///         PUBLIC print
+/// print:  RET
+/// ").unwrap();
+/// let code = link(&[main, print], 0x8000).unwrap();
+/// assert_eq!(code[..7], [0x21, 0x07, 0x80, 0xcd, 0x0a, 0x80, 0x76]);
+/// ```
+pub fn assemble_object(source: &str) -> Result<Object, AsmError> {
+    let output = assemble_output(source, 0, true)?;
+    Ok(Object {
+        code: output.code,
+        relocations: output.relocations,
+        publics: output.publics,
+        externals: output.externals,
+    })
+}
+
+fn assemble_output(source: &str, org: u16, object: bool) -> Result<Output, AsmError> {
+    let statements = source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| parse_line(i + 1, text))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut symbols = HashMap::new();
+
+    // First pass: addresses of labels and the length of everything
+    let mut addr = u32::from(org);
+    let mut items = Vec::with_capacity(statements.len());
+    for statement in &statements {
+        let error = |kind| AsmError { line: statement.line, kind };
+        let item = match statement.op.as_str() {
+            "" => Item::Nothing,
+            "EQU" => {
+                let name = statement.label.ok_or_else(|| error(AsmErrorKind::Syntax("EQU without a name")))?;
+                let value = single(statement)?;
+                let value = eval(value, &symbols, addr).map_err(error)?;
+                define(&mut symbols, name, value).map_err(error)?;
+                items.push(Item::Nothing);
+                continue;
+            }
+            "EXTRN" if !object => return Err(error(AsmErrorKind::Syntax("EXTRN outside an object module"))),
+            "EXTRN" => {
+                for name in &statement.operands {
+                    if !is_symbol(name) {
+                        return Err(error(AsmErrorKind::BadOperand(name.to_string())));
+                    }
+                    let external = Value { number: 0, relocations: 0, external: Some(name.to_ascii_uppercase()) };
+                    define(&mut symbols, name, external).map_err(error)?;
+                }
+                Item::Nothing
+            }
+            "PUBLIC" => Item::Public,
+            "ORG" => {
+                let value = eval(single(statement)?, &symbols, addr).map_err(error)?.number;
+                if value < i64::from(addr) || value > 0xffff {
+                    return Err(error(AsmErrorKind::BadAddress(value)));
+                }
+                Item::Org(value as u32)
+            }
+            "DB" => Item::Bytes,
+            "DW" => Item::Words,
+            _ => {
+                let (opcode, length) = lookup(statement).map_err(error)?;
+                Item::Instruction { opcode, length }
+            }
+        };
+        if let Some(name) = statement.label {
+            define(&mut symbols, name, Value::address(addr)).map_err(error)?;
+        }
+        addr = match item {
+            Item::Nothing | Item::Public => addr,
+            Item::Org(to) => to,
+            Item::Instruction { length, .. } => addr + u32::from(length),
+            Item::Bytes => addr + statement.operands.iter().map(|o| byte_count(o)).sum::<u32>(),
+            Item::Words => addr + 2 * statement.operands.len() as u32,
+        };
+        if addr > 0x10000 {
+            return Err(error(AsmErrorKind::BadAddress(i64::from(addr))));
+        }
+        items.push(item);
+    }
+
+    // Second pass: the bytes
+    let mut output = Output::default();
+    for (statement, item) in statements.iter().zip(&items) {
+        let error = |kind| AsmError { line: statement.line, kind };
+        let here = u32::from(org) + output.code.len() as u32;
+        let value = |text: &str| eval(text, &symbols, here).map_err(error);
+        let byte = |text: &str| {
+            let value = value(text)?;
+            if object && (value.relocations != 0 || value.external.is_some()) {
+                return Err(error(AsmErrorKind::NotRelocatable(text.to_string())));
+            }
+            byte(value.number).map_err(error)
+        };
+        match *item {
+            Item::Nothing => {}
+            Item::Org(to) => output.code.resize((to - u32::from(org)) as usize, 0x00),
+            Item::Instruction { opcode, length } => {
+                output.code.push(opcode);
+                match length {
+                    2 => output.code.push(byte(last(statement))?),
+                    3 => output.push_word(last(statement), value(last(statement))?, object).map_err(error)?,
+                    _ => {}
+                }
+            }
+            Item::Bytes => {
+                for operand in &statement.operands {
+                    match string(operand) {
+                        Some(text) if text.len() != 1 => output.code.extend_from_slice(text.as_bytes()),
+                        _ => output.code.push(byte(operand)?),
+                    }
+                }
+            }
+            Item::Words => {
+                for operand in &statement.operands {
+                    output.push_word(operand, value(operand)?, object).map_err(error)?;
+                }
+            }
+            Item::Public => {
+                for name in &statement.operands {
+                    let upper = name.to_ascii_uppercase();
+                    let value = symbols.get(&upper).ok_or_else(|| error(AsmErrorKind::UndefinedSymbol(upper.clone())))?;
+                    if value.external.is_some() || !(0..=1).contains(&value.relocations) {
+                        return Err(error(AsmErrorKind::NotRelocatable(name.to_string())));
+                    }
+                    let number = word(value.number).map_err(error)?;
+                    output.publics.push(Public { name: upper, value: number, relocatable: value.relocations == 1 });
+                }
+            }
+        }
+    }
+    Ok(output)
+}
+
+impl Output {
+    // Adds a word operand, noting in an object module what the linker is
+    // to add to it
+    fn push_word(&mut self, text: &str, value: Value, object: bool) -> Result<(), AsmErrorKind> {
+        let offset = self.code.len() as u16;
+        if object {
+            match value.relocations {
+                0 => {}
+                1 => self.relocations.push(offset),
+                _ => return Err(AsmErrorKind::NotRelocatable(text.to_string())),
+            }
+            if let Some(name) = value.external {
+                self.externals.push(External { offset, name });
+            }
+        }
+        self.code.extend_from_slice(&word(value.number)?.to_le_bytes());
+        Ok(())
+    }
+}
+
+fn parse_line(line: usize, text: &str) -> Result<Statement<'_>, AsmError> {
+    let text = text[..comment_start(text)].trim();
+    let (label, rest) = match text.split_once(':') {
+        Some((label, rest)) if is_symbol(label.trim()) => (Some(label.trim()), rest.trim()),
+        _ => (None, text),
+    };
+    let (word, operands) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let operands = operands.trim();
+    // `name EQU value`, with the name where the mnemonic usually goes
+    if let Some((equ, value)) = operands.split_once(char::is_whitespace) {
+        if label.is_none() && equ.eq_ignore_ascii_case("EQU") {
+            if !is_symbol(word) {
+                return Err(AsmError { line, kind: AsmErrorKind::Syntax("EQU without a name") });
+            }
+            let operands = split_operands(value.trim());
+            return Ok(Statement { line, label: Some(word), op: "EQU".to_string(), operands });
+        }
+    }
+    let op = word.to_ascii_uppercase();
+    Ok(Statement { line, label, op, operands: split_operands(operands) })
+}
+
+// Where a `;` comment starts, ignoring any inside quotes
+fn comment_start(text: &str) -> usize {
+    let mut quote = None;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, ';') => return i,
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    text.len()
+}
+
+fn split_operands(text: &str) -> Vec<&str> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    let mut operands = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, ',') => {
+                operands.push(text[start..i].trim());
+                start = i + 1;
+            }
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+fn is_symbol(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '?' || c == '@')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '?' || c == '@')
+}
+
+fn define(symbols: &mut HashMap<String, Value>, name: &str, value: Value) -> Result<(), AsmErrorKind> {
+    let name = name.to_ascii_uppercase();
+    if symbols.contains_key(&name) {
+        return Err(AsmErrorKind::DuplicateSymbol(name));
+    }
+    symbols.insert(name, value);
+    Ok(())
+}
+
+fn single<'a>(statement: &Statement<'a>) -> Result<&'a str, AsmError> {
+    match statement.operands[..] {
+        [operand] => Ok(operand),
+        _ => Err(AsmError { line: statement.line, kind: AsmErrorKind::Syntax("expected a single operand") }),
+    }
+}
+
+fn last<'a>(statement: &Statement<'a>) -> &'a str {
+    statement.operands.last().copied().unwrap_or("")
+}
+
+// Finds the opcode for an instruction. Register operands are part of the
+// mnemonic in `OPCODES`; an instruction taking data has one operand more.
+// The first match is the documented encoding.
+fn lookup(statement: &Statement) -> Result<(u8, u8), AsmErrorKind> {
+    let registers: Vec<String> = statement.operands.iter().map(|o| o.to_ascii_uppercase()).collect();
+    let with = |count: usize| {
+        let mut mnemonic = statement.op.clone();
+        if count > 0 {
+            mnemonic.push(' ');
+            mnemonic.push_str(&registers[..count].join(", "));
+        }
+        mnemonic
+    };
+    let find = |mnemonic: &str, data: bool| {
+        OPCODES.iter().position(|info| info.mnemonic == mnemonic && (info.length > 1) == data)
+    };
+    let all = registers.len();
+    if let Some(op) = find(&with(all), false) {
+        return Ok((op as u8, 1));
+    }
+    if all > 0 {
+        if let Some(op) = find(&with(all - 1), true) {
+            return Ok((op as u8, OPCODES[op].length));
+        }
+    }
+    let mut text = statement.op.clone();
+    if all > 0 {
+        text.push(' ');
+        text.push_str(&statement.operands.join(", "));
+    }
+    Err(AsmErrorKind::UnknownInstruction(text))
+}
+
+// The contents of a quoted string operand
+fn string(operand: &str) -> Option<&str> {
+    let quoted = operand.len() >= 2
+        && (operand.starts_with('\'') && operand.ends_with('\'') || operand.starts_with('"') && operand.ends_with('"'));
+    if quoted { Some(&operand[1..operand.len() - 1]) } else { None }
+}
+
+fn byte_count(operand: &str) -> u32 {
+    match string(operand) {
+        Some(text) if text.len() != 1 => text.len() as u32,
+        _ => 1,
+    }
+}
+
+fn byte(value: i64) -> Result<u8, AsmErrorKind> {
+    if (-0x80..=0xff).contains(&value) { Ok(value as u8) } else { Err(AsmErrorKind::OutOfRange(value)) }
+}
+
+fn word(value: i64) -> Result<u16, AsmErrorKind> {
+    if (-0x8000..=0xffff).contains(&value) { Ok(value as u16) } else { Err(AsmErrorKind::OutOfRange(value)) }
+}
+
+// Evaluates terms joined with `+` and `-`, with `$` standing for `here`.
+// An external symbol can only be added, and only one of them.
+fn eval(text: &str, symbols: &HashMap<String, Value>, here: u32) -> Result<Value, AsmErrorKind> {
+    let bad = || AsmErrorKind::BadOperand(text.to_string());
+    let mut total = Value::absolute(0);
+    let mut rest = text.trim();
+    let mut sign = 1;
+    if let Some(negated) = rest.strip_prefix('-') {
+        sign = -1;
+        rest = negated.trim_start();
+    }
+    loop {
+        let end = if let Some(quoted) = rest.strip_prefix('\'') {
+            quoted.find('\'').map(|i| i + 2).ok_or_else(bad)?
+        } else {
+            rest.find(['+', '-']).unwrap_or(rest.len())
+        };
+        let term = rest[..end].trim();
+        let value = match term {
+            "$" => Value::address(here),
+            _ => match string(term) {
+                Some(text) if text.len() == 1 => Value::absolute(i64::from(text.as_bytes()[0])),
+                Some(_) => return Err(bad()),
+                None if is_symbol(term) && !is_number(term) => {
+                    let name = term.to_ascii_uppercase();
+                    symbols.get(&name).cloned().ok_or(AsmErrorKind::UndefinedSymbol(name))?
+                }
+                None => Value::absolute(number(term).ok_or_else(bad)?),
+            },
+        };
+        total.number += sign * value.number;
+        total.relocations += sign * value.relocations;
+        if value.external.is_some() {
+            if sign < 0 || total.external.is_some() {
+                return Err(AsmErrorKind::NotRelocatable(text.to_string()));
+            }
+            total.external = value.external;
+        }
+        rest = rest[end..].trim_start();
+        match rest.chars().next() {
+            None => return Ok(total),
+            Some(c) => {
+                sign = if c == '+' { 1 } else { -1 };
+                rest = rest[1..].trim_start();
+            }
+        }
+    }
+}
+
+// Whether a symbol-like term is really a number with a suffix, e.g. `0FFh`
+fn is_number(term: &str) -> bool {
+    term.starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn number(term: &str) -> Option<i64> {
+    let lower = term.to_ascii_lowercase();
+    let (digits, radix) = if let Some(hex) = lower.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        (hex, 16)
+    } else if let Some(binary) = lower.strip_suffix('b').filter(|b| b.chars().all(|c| c == '0' || c == '1')) {
+        (binary, 2)
+    } else {
+        (&lower[..], 10)
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    i64::from_str_radix(digits, radix).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{assemble, AsmError, AsmErrorKind};
+
+    #[test]
+    fn instructions() {
+        let code = assemble(
+            "
+            MOV A, M
+            mov m, b
+            MVI A, 0x3E
+            LXI SP, 2400h
+            PUSH PSW
+            RST 7
+            OUT 1
+            ADI -1
+            CALL 0
+            ",
+            0,
+        );
+        assert_eq!(code.unwrap(), [0x7e, 0x70, 0x3e, 0x3e, 0x31, 0x00, 0x24, 0xf5, 0xff, 0xd3, 0x01, 0xc6, 0xff, 0xcd, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn labels_and_directives() {
+        let code = assemble(
+            "
+    COUNT   EQU 2
+    SCREEN: EQU 0x2400 + 0x10
+            ORG 0x104
+    start:  LXI H, message
+            MVI B, COUNT
+            JMP done ; a forward reference
+    message: DB 'Hi; there', 0, '!', 10b
+            DW SCREEN, $
+    done:   HLT
+            ",
+            0x100,
+        );
+        let mut expected = vec![0x00; 4];
+        expected.extend_from_slice(&[0x21, 0x0c, 0x01, 0x06, 0x02, 0xc3, 0x1c, 0x01]);
+        expected.extend_from_slice(b"Hi; there\x00!\x02");
+        expected.extend_from_slice(&[0x10, 0x24, 0x18, 0x01, 0x76]);
+        assert_eq!(code.unwrap(), expected);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| assemble(source, 0).unwrap_err();
+        assert_eq!(error("NOP\nMOV A, X"), AsmError { line: 2, kind: AsmErrorKind::UnknownInstruction("MOV A, X".into()) });
+        assert_eq!(error("JMP nowhere").kind, AsmErrorKind::UndefinedSymbol("NOWHERE".into()));
+        assert_eq!(error("a: NOP\nA: NOP").kind, AsmErrorKind::DuplicateSymbol("A".into()));
+        assert_eq!(error("MVI A, 256").kind, AsmErrorKind::OutOfRange(256));
+        assert_eq!(error("ORG 10\nORG 5").kind, AsmErrorKind::BadAddress(5));
+        assert_eq!(error("DB 1+").kind, AsmErrorKind::BadOperand("1+".into()));
+        assert_eq!(error("ORG 0xffff\nJMP 0").kind, AsmErrorKind::BadAddress(0x10002));
+        assert_eq!(error("NOP\nMVI A, X").to_string(), "line 2: undefined symbol `X`");
+    }
+}
@@ -0,0 +1,293 @@
+//! A machine for running tiny standalone programs with no setup, such as
+//! the examples in an assembly tutorial. It traps the conventions those
+//! programs use instead of real hardware:
+//!
+//! - OUT to the console port (0 by default) prints the byte in A;
+//! - IN from the console port reads the next byte of input, or 0 once the
+//!   input is used up;
+//! - HLT ends the program.
+//!
+//! Optionally, the CP/M conventions used by many test programs are trapped
+//! as well: a CALL to 0x0005 with C = 2 prints E, with C = 9 prints the
+//! `$`-terminated string at DE, and a jump to 0x0000 ends the program.
+//! `machines::cpm::CpmMachine` does the same for `.COM` files with the
+//! output going to any `Write` sink.
+//!
+//! ```
+//! use i8080_emulator::bare::BareMachine;
+//! use i8080_emulator::Machine;
+//!
+//! // MVI A, 'H'; OUT 0; MVI A, 'i'; OUT 0; HLT
+//! let mut machine = BareMachine::new(&[0x3e, b'H', 0xd3, 0x00, 0x3e, b'i', 0xd3, 0x00, 0x76]);
+//! machine.run().unwrap();
+//! assert_eq!(machine.output(), "Hi");
+//! ```
+
+use crate::cpu::{Event, Timestamp, CPU};
+use crate::error::EmulatorError;
+use crate::loader::{load_bytes, LoadError};
+use crate::machines::cpm::{console_call, BDOS};
+use crate::memory::{Memory, Memory8080};
+use crate::Machine;
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// How a `BareMachine` loads and talks to its program. The default loads
+/// at 0x0000 with the console on port 0, without CP/M traps or a step
+/// limit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BareConfig {
+    /// Where the program is loaded and started.
+    pub origin: u16,
+    pub console_port: u8,
+    /// Trap the CP/M BDOS console calls and warm boot.
+    pub cpm: bool,
+    /// Give up with `EmulatorError::StepLimit` after this many
+    /// instructions, so a program that never halts cannot hang the host.
+    pub step_limit: Option<u64>,
+}
+
+impl BareConfig {
+    /// The usual setup for CP/M programs: loaded at 0x0100 with the BDOS
+    /// calls trapped.
+    pub fn cpm() -> Self {
+        BareConfig {
+            origin: 0x0100,
+            cpm: true,
+            ..BareConfig::default()
+        }
+    }
+}
+
+/// 64K of RAM holding one program and a console, see the module
+/// documentation.
+pub struct BareMachine {
+    pub cpu: CPU,
+    config: BareConfig,
+    input: VecDeque<u8>,
+    output: String,
+    // Set by the trap at `BDOS` for `next` to handle the call
+    bdos_called: Rc<Cell<bool>>,
+    steps: u64,
+    finished: bool,
+}
+
+impl BareMachine {
+    /// Loads `program` at 0x0000 with the default configuration.
+    pub fn new(program: &[u8]) -> Self {
+        Self::with_config(program, BareConfig::default())
+    }
+
+    /// Loads `program` as `config` says.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the program does not fit in memory above the origin.
+    /// `try_with_config` returns the error instead.
+    pub fn with_config(program: &[u8], config: BareConfig) -> Self {
+        Self::try_with_config(program, config).expect("program does not fit in memory")
+    }
+
+    /// Loads `program` as `config` says, failing if it does not fit in
+    /// memory above the origin.
+    pub fn try_with_config(program: &[u8], config: BareConfig) -> Result<Self, LoadError> {
+        let mut memory = Memory8080::new_empty();
+        load_bytes(&mut memory, program, config.origin)?;
+        if config.cpm {
+            // The BDOS returns straight away once the call is handled
+            memory.write(usize::from(BDOS), 0xc9);
+        }
+        let mut cpu = CPU::new(Rc::new(RefCell::new(memory)));
+        cpu.pc = config.origin;
+        let bdos_called = Rc::new(Cell::new(false));
+        if config.cpm {
+            let called = bdos_called.clone();
+            cpu.add_trap(BDOS, move |_, _| {
+                called.set(true);
+                None
+            });
+        }
+        Ok(BareMachine {
+            cpu,
+            config,
+            input: VecDeque::new(),
+            output: String::new(),
+            bdos_called,
+            steps: 0,
+            finished: false,
+        })
+    }
+
+    /// Queues `text` for the program to read from the console port.
+    pub fn send(&mut self, text: &str) {
+        self.input.extend(text.bytes());
+    }
+
+    /// Everything the program printed so far.
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    // Prints the BDOS console output the program asked for
+    fn bdos(&mut self) {
+        let output = &mut self.output;
+        console_call(&self.cpu, |c| output.push(char::from(c)));
+    }
+}
+
+impl Machine for BareMachine {
+    type Event = Event;
+
+    fn next(&mut self) -> Result<Event, EmulatorError> {
+        if let Some(limit) = self.config.step_limit {
+            if self.steps >= limit {
+                return Err(EmulatorError::StepLimit(limit));
+            }
+        }
+        self.steps += 1;
+        let (event, _) = self.cpu.step();
+        match event {
+            Event::Output(port, value, _) if port == self.config.console_port => {
+                self.output.push(char::from(value));
+            }
+            Event::Input(port, _) if port == self.config.console_port => {
+                let value = self.input.pop_front().unwrap_or(0x00);
+                self.cpu.complete_input(value);
+            }
+            Event::Input(port, _) | Event::Output(port, _, _) => return Err(EmulatorError::UnmappedPort(port)),
+            Event::Halt(_) => self.finished = true,
+            Event::Fault(fault) => return Err(EmulatorError::Fault(fault)),
+            Event::Normal(_) => {}
+        }
+        if self.bdos_called.take() {
+            self.bdos();
+        }
+        if self.config.cpm && self.cpu.pc == 0x0000 {
+            self.finished = true;
+        }
+        Ok(event)
+    }
+
+    /// Runs the program until it ends.
+    fn run(&mut self) -> Result<(), EmulatorError> {
+        while !self.is_finished() {
+            self.next()?;
+        }
+        Ok(())
+    }
+
+    /// Starts the program over from its origin, keeping memory and any
+    /// input not read yet.
+    fn reset(&mut self) {
+        self.cpu.reset();
+        self.cpu.pc = self.config.origin;
+        self.finished = false;
+    }
+
+    fn cycles(&self) -> Timestamp {
+        self.cpu.cycles()
+    }
+
+    /// Whether the program has ended.
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bare::{BareConfig, BareMachine};
+    use crate::cpu::{ClockCycles, Timestamp};
+    use crate::error::EmulatorError;
+    use crate::{Limit, Machine};
+
+    #[test]
+    fn run_in_slices() {
+        // loop: INR B; JMP loop
+        let mut machine = BareMachine::new(&[0x04, 0xc3, 0x00, 0x00]);
+        assert_eq!(machine.run_cycles(30).unwrap(), 30);
+        assert_eq!(machine.run_cycles(1).unwrap(), 5);
+        assert_eq!(machine.cycles(), 35);
+        machine.run_until(|machine| machine.cpu.regs.b == 10).unwrap();
+        assert_eq!(machine.cpu.pc, 0x0001);
+
+        let mut limited = BareMachine::with_config(&[0x04, 0xc3, 0x00, 0x00], BareConfig { step_limit: Some(3), ..BareConfig::default() });
+        assert_eq!(limited.run_cycles(100), Err(EmulatorError::StepLimit(3)));
+    }
+
+    // A machine whose steps each take longer than a ClockCycles can count
+    struct Slow(Timestamp);
+
+    impl Machine for Slow {
+        type Event = ();
+
+        fn next(&mut self) -> Result<(), EmulatorError> {
+            self.0 += 1 << 33;
+            Ok(())
+        }
+
+        fn run(&mut self) -> Result<(), EmulatorError> {
+            loop {
+                self.next()?;
+            }
+        }
+
+        fn reset(&mut self) {
+            self.0 = 0;
+        }
+
+        fn cycles(&self) -> Timestamp {
+            self.0
+        }
+    }
+
+    #[test]
+    fn long_slices_saturate() {
+        assert_eq!(Slow(0).run_cycles(1).unwrap(), ClockCycles::MAX);
+    }
+
+    #[test]
+    fn echo_until_end_of_input() {
+        // loop: IN 0; ORA A; JZ done; OUT 0; JMP loop; done: HLT
+        let program = [0xdb, 0x00, 0xb7, 0xca, 0x0b, 0x00, 0xd3, 0x00, 0xc3, 0x00, 0x00, 0x76];
+        let mut machine = BareMachine::new(&program);
+        machine.send("echo");
+        machine.run().unwrap();
+        assert!(machine.is_finished());
+        assert_eq!(machine.output(), "echo");
+    }
+
+    #[test]
+    fn cpm_conventions() {
+        // MVI C, 9; LXI D, msg; CALL 5; MVI C, 2; MVI E, '!'; CALL 5; JMP 0;
+        // msg: DB 'Hello$'
+        let mut program = vec![0x0e, 0x09, 0x11, 0x12, 0x01, 0xcd, 0x05, 0x00,
+                               0x0e, 0x02, 0x1e, b'!', 0xcd, 0x05, 0x00, 0xc3, 0x00, 0x00];
+        program.extend_from_slice(b"Hello$");
+        let mut machine = BareMachine::with_config(&program, BareConfig::cpm());
+        machine.run().unwrap();
+        assert_eq!(machine.output(), "Hello!");
+    }
+
+    #[test]
+    fn other_ports_and_runaways() {
+        // OUT 1
+        let mut machine = BareMachine::new(&[0xd3, 0x01]);
+        assert_eq!(machine.run(), Err(EmulatorError::UnmappedPort(0x01)));
+        // JMP 0
+        let config = BareConfig { step_limit: Some(100), ..BareConfig::default() };
+        let mut machine = BareMachine::with_config(&[0xc3, 0x00, 0x00], config);
+        assert_eq!(machine.run(), Err(EmulatorError::StepLimit(100)));
+        let mut machine = BareMachine::new(&[0xc3, 0x00, 0x00]);
+        assert_eq!(machine.run_with_limit(Limit::Steps(50)), Err(EmulatorError::StepLimit(50)));
+        assert_eq!(machine.run_with_limit(Limit::Cycles(1000)), Err(EmulatorError::CycleLimit(1000)));
+        assert_eq!(machine.cycles(), 50 * 10 + 1000);
+        let mut machine = BareMachine::new(&[0x00, 0x76]);
+        assert_eq!(machine.run_with_limit(Limit::Steps(2)), Ok(()));
+
+        let config = BareConfig { origin: 0xff00, ..BareConfig::default() };
+        assert!(BareMachine::try_with_config(&[0x00; 0x101], config).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+//! Runs, disassembles and debugs 8080 programs from the command line.
+//!
+//! Usage:
+//!
+//! ```text
+//! i8080 run FILE [--machine cpm|altair] [--max-steps N] [--dump STATE] [ARGS...]
+//! i8080 disasm FILE [--org ADDR]
+//! i8080 debug FILE [--machine cpm|altair]
+//! ```
+//!
+//! `run` runs a program with the console on stdin and stdout, as a CP/M
+//! `.COM` file by default, with any ARGS as its command line. `--machine
+//! altair` loads it at 0000 on an Altair with a 2SIO console instead.
+//! From a terminal, keys go to the program as they are typed, Ctrl-C
+//! included, and Ctrl-] quits. If SIGINT or SIGTERM stops the run,
+//! `--dump` writes the registers and memory as they were to STATE.
+//! `disasm` lists a binary as if loaded at ADDR, 0100 unless given.
+//! `debug` loads a program as `run` would and drops into the monitor
+//! style debugger of `repl`, which reads its commands from stdin.
+//!
+//! Addresses and counts are decimal unless they start with `0x`.
+
+use i8080_emulator::disassembler::Disassembler;
+use i8080_emulator::loader::load_bytes;
+use i8080_emulator::machines::altair::AltairMachine;
+use i8080_emulator::machines::cpm::CpmMachine;
+use i8080_emulator::memory::Memory8080;
+use i8080_emulator::error::EmulatorError;
+use i8080_emulator::repl::{Inspect, Repl};
+use i8080_emulator::shutdown::{write_snapshot, Shutdown};
+use i8080_emulator::terminal::{CtrlC, Keyboard, RawMode};
+
+use std::convert::TryFrom;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+const USAGE: &str = "\
+usage: i8080 run FILE [--machine cpm|altair] [--max-steps N] [--dump STATE] [ARGS...]
+       i8080 disasm FILE [--org ADDR]
+       i8080 debug FILE [--machine cpm|altair]";
+
+// The command line after the subcommand
+struct Options {
+    file: String,
+    machine: String,
+    org: u16,
+    max_steps: Option<u64>,
+    dump: Option<String>,
+    args: Vec<String>,
+}
+
+// Prints `message` and exits with the usage error status
+fn fail(message: impl Display) -> ! {
+    eprintln!("i8080: {}", message);
+    process::exit(2);
+}
+
+fn parse_number(text: &str) -> u64 {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.unwrap_or_else(|_| fail(format!("bad number {:?}", text)))
+}
+
+fn parse_options(args: &[String]) -> Options {
+    let mut options = Options { file: String::new(), machine: "cpm".to_string(), org: 0x0100, max_steps: None, dump: None, args: Vec::new() };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| fail(format!("{} needs a value", arg))).as_str();
+        match arg.as_str() {
+            "--machine" => options.machine = value().to_string(),
+            "--org" => {
+                let org = parse_number(value());
+                options.org = u16::try_from(org).unwrap_or_else(|_| fail(format!("no address {:#x}", org)));
+            }
+            "--max-steps" => options.max_steps = Some(parse_number(value())),
+            "--dump" => options.dump = Some(value().to_string()),
+            _ if options.file.is_empty() => options.file = arg.clone(),
+            _ => options.args.push(arg.clone()),
+        }
+    }
+    if options.file.is_empty() {
+        fail(USAGE);
+    }
+    options
+}
+
+fn read_program(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|e| fail(format!("could not read {}: {}", path, e)))
+}
+
+fn cpm(options: &Options) -> CpmMachine<io::Stdout> {
+    let mut machine = CpmMachine::new(&read_program(&options.file), io::stdout()).unwrap_or_else(|e| fail(e));
+    let args: Vec<&str> = options.args.iter().map(String::as_str).collect();
+    machine.set_args(&args);
+    machine
+}
+
+fn altair(options: &Options) -> AltairMachine<io::Stdout> {
+    AltairMachine::new(&read_program(&options.file), io::stdout()).unwrap_or_else(|e| fail(e))
+}
+
+// Runs with the console on stdin, a key at a time if it is a terminal
+fn run_machine<M: Inspect>(mut machine: M, options: &Options, set_input: impl FnOnce(&mut M, Box<dyn Read>)) {
+    let raw = if io::stdin().is_terminal() { RawMode::enter().ok() } else { None };
+    let exit = match raw {
+        Some(_) => {
+            eprint!("i8080: press Ctrl-] to quit\r\n");
+            let keyboard = Keyboard::stdin(CtrlC::PassThrough);
+            let exit = keyboard.exit_requested();
+            set_input(&mut machine, Box::new(keyboard));
+            exit
+        }
+        None => {
+            set_input(&mut machine, Box::new(io::stdin()));
+            Arc::new(AtomicBool::new(false))
+        }
+    };
+
+    let shutdown = Shutdown::on_signals().unwrap_or_else(|e| fail(format!("could not catch signals: {}", e)));
+
+    let mut steps = 0;
+    let result = machine.run_until(|machine| {
+        steps += 1;
+        machine.is_finished()
+            || exit.load(Ordering::Relaxed)
+            || shutdown.is_requested()
+            || options.max_steps.is_some_and(|max| steps > max)
+    });
+    let result = match options.max_steps {
+        Some(max) if result.is_ok() && steps > max && !exit.load(Ordering::Relaxed) && !shutdown.is_requested() => {
+            Err(EmulatorError::StepLimit(max))
+        }
+        _ => result,
+    };
+    let _ = io::stdout().flush();
+    drop(raw);
+    if let Err(e) = result {
+        eprintln!("\ni8080: {}", e);
+        process::exit(1);
+    }
+    if shutdown.is_requested() {
+        eprintln!("\ni8080: stopped by a signal");
+        if let Some(path) = &options.dump {
+            dump(&machine, path);
+        }
+        process::exit(130);
+    }
+}
+
+fn dump(machine: &impl Inspect, path: &str) {
+    let written = machine.cpu().save_state().map_err(|e| e.to_string()).and_then(|state| {
+        let mut file = fs::File::create(path).map_err(|e| e.to_string())?;
+        write_snapshot(&state, &mut file).map_err(|e| e.to_string())
+    });
+    if let Err(e) = written {
+        eprintln!("i8080: could not write {}: {}", path, e);
+    }
+}
+
+fn debug_machine(machine: impl Inspect) {
+    let mut repl = Repl::new(machine);
+    if let Err(e) = repl.run(io::stdin().lock(), io::stdout()) {
+        fail(format!("console error: {}", e));
+    }
+}
+
+fn disasm(options: &Options) {
+    let program = read_program(&options.file);
+    let mut memory = Memory8080::new_empty();
+    load_bytes(&mut memory, &program, options.org).unwrap_or_else(|e| fail(e));
+    if program.is_empty() {
+        return;
+    }
+    let end = options.org.wrapping_add((program.len() - 1) as u16);
+    let disassembler = Disassembler::new();
+    let mut out = io::stdout().lock();
+    for instruction in disassembler.instructions(&memory, options.org).until(end) {
+        if writeln!(out, "{}", instruction.text).is_err() {
+            return;
+        }
+    }
+}
+
+fn unknown_machine(machine: &str) -> ! {
+    fail(format!("unknown machine {:?}, expected cpm or altair", machine))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = args.first().unwrap_or_else(|| fail(USAGE));
+    let options = parse_options(&args[1..]);
+    match (command.as_str(), options.machine.as_str()) {
+        ("run", "cpm") => {
+            run_machine(cpm(&options), &options, |machine, keys| machine.set_input(keys));
+        }
+        ("run", "altair") => {
+            run_machine(altair(&options), &options, |machine, keys| machine.set_input(keys));
+        }
+        ("debug", "cpm") => debug_machine(cpm(&options)),
+        ("debug", "altair") => debug_machine(altair(&options)),
+        ("run", machine) | ("debug", machine) => unknown_machine(machine),
+        ("disasm", _) => disasm(&options),
+        _ => fail(USAGE),
+    }
+}
@@ -0,0 +1,273 @@
+//! A terminal debugger: registers, disassembly around PC, a memory pane
+//! and breakpoints, all driven through the emulator's public API.
+//!
+//! Usage: `tui [ROM [ADDR]]` loads ROM at ADDR (hex, default 100) and
+//! starts there. Without a ROM it boots the built-in monitor at 0000.
+//!
+//! Keys: `s` step, `r` run, `p` pause, up/down move the cursor in the
+//! disassembly, `b` toggles a breakpoint at the cursor, PgUp/PgDn scroll
+//! memory, `q` quits.
+
+use i8080_emulator::cpu::{CpuBuilder, Event, CPU};
+use i8080_emulator::devices::serial::Serial;
+use i8080_emulator::disassembler::Disassembler;
+use i8080_emulator::io::PortBus;
+use i8080_emulator::loader::LoadMap;
+use i8080_emulator::memory::{Memory, Memory8080};
+use i8080_emulator::opcodes::OPCODES;
+use i8080_emulator::{monitor, I8080Core};
+
+use ratatui::crossterm::event::{self, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::env;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+// Instructions executed between redraws while running
+const RUN_SLICE: usize = 20_000;
+
+struct Debugger {
+    cpu: CPU,
+    bus: PortBus,
+    disassembler: Disassembler,
+    breakpoints: BTreeSet<u16>,
+    // Address of the disassembly line the cursor is on
+    cursor: u16,
+    memory_view: u16,
+    // Memory as it was when execution last resumed, to mark what changed
+    previous: Vec<u8>,
+    running: bool,
+    status: String,
+}
+
+impl Debugger {
+    fn new(image: [u8; 0x10000], start: u16, bus: PortBus) -> Self {
+        let cpu = CpuBuilder::new().memory(Rc::new(RefCell::new(Memory8080::new(image)))).pc(start).build();
+        Debugger {
+            cpu,
+            bus,
+            disassembler: Disassembler::new(),
+            breakpoints: BTreeSet::new(),
+            cursor: start,
+            memory_view: start & 0xfff0,
+            previous: image.to_vec(),
+            running: false,
+            status: "stopped".to_string(),
+        }
+    }
+
+    // Executes one instruction, returning why execution should stop, if
+    // it should
+    fn step(&mut self) -> Option<String> {
+        match self.bus.step(&mut self.cpu) {
+            Event::Halt(_) => Some("halted".to_string()),
+            Event::Fault(fault) => Some(fault.to_string()),
+            _ if self.breakpoints.contains(&self.cpu.pc) => Some(format!("breakpoint at {:04x}", self.cpu.pc)),
+            _ => None,
+        }
+    }
+
+    fn run_slice(&mut self) {
+        for _ in 0..RUN_SLICE {
+            if let Some(reason) = self.step() {
+                self.stop(reason);
+                return;
+            }
+        }
+    }
+
+    fn snapshot(&mut self) {
+        let memory = self.cpu.memory.borrow();
+        self.previous = (0..0x10000).map(|addr| memory.peek(addr)).collect();
+    }
+
+    fn stop(&mut self, reason: String) {
+        self.running = false;
+        self.status = reason;
+        self.cursor = self.cpu.pc;
+    }
+
+    // Addresses of the instructions to list, with PC near the middle.
+    // Code before PC is decoded from a few bytes back, which lines up with
+    // the real instruction boundaries in all but contrived cases.
+    fn listing(&self, lines: usize) -> Vec<u16> {
+        let memory = self.cpu.memory.borrow();
+        let length = |addr: u16| {
+            let op = memory.peek(addr.into());
+            u16::from(OPCODES[usize::from(op)].length)
+        };
+        let mut addrs = Vec::new();
+        let mut addr = self.cursor.saturating_sub(3 * lines as u16 / 2);
+        while addr < self.cursor {
+            addrs.push(addr);
+            addr = addr.wrapping_add(length(addr));
+        }
+        if addr != self.cursor {
+            addrs.clear();
+        }
+        let before = addrs.len().min(lines / 2);
+        let mut listing = addrs.split_off(addrs.len() - before);
+        let mut addr = self.cursor;
+        while listing.len() < lines {
+            listing.push(addr);
+            addr = addr.wrapping_add(length(addr));
+        }
+        listing
+    }
+
+    fn move_cursor(&mut self, down: bool) {
+        let listing = self.listing(64);
+        if let Some(i) = listing.iter().position(|&addr| addr == self.cursor) {
+            let i = if down { i + 1 } else { i.saturating_sub(1) };
+            self.cursor = listing[i.min(listing.len() - 1)];
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, status] = Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] = Layout::horizontal([Constraint::Length(28), Constraint::Min(0)]).areas(main);
+        let [registers, _] = Layout::vertical([Constraint::Length(12), Constraint::Min(0)]).areas(left);
+        let [code, memory] = Layout::vertical([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(right);
+
+        let state = self.cpu.state();
+        let f = state.f;
+        let flag = |bit: u8, name: char| if f & (1 << bit) != 0 { name } else { '-' };
+        let register_lines = vec![
+            Line::from(format!("A  {:02x}   F  {:02x}", state.a, state.f)),
+            Line::from(format!("B  {:02x}   C  {:02x}", state.b, state.c)),
+            Line::from(format!("D  {:02x}   E  {:02x}", state.d, state.e)),
+            Line::from(format!("H  {:02x}   L  {:02x}", state.h, state.l)),
+            Line::from(format!("SP {:04x}", state.sp)),
+            Line::from(format!("PC {:04x}", state.pc)),
+            Line::from(format!("flags {}{}{}{}{}", flag(7, 'S'), flag(6, 'Z'), flag(4, 'A'), flag(2, 'P'), flag(0, 'C'))),
+            Line::from(format!("INTE {}  {}", u8::from(state.inte), if state.halted { "HALTED" } else { "" })),
+        ];
+        frame.render_widget(Paragraph::new(register_lines).block(Block::bordered().title("Registers")), registers);
+
+        let memory8080 = self.cpu.memory.borrow();
+        let rows = usize::from(code.height.saturating_sub(2));
+        let code_lines: Vec<Line> = self.listing(rows).into_iter().map(|addr| {
+            let op = memory8080.peek(addr.into());
+            let text = self.disassembler.disassemble(&*memory8080, &addr, &op, &0);
+            let marker = if self.breakpoints.contains(&addr) { '*' } else { ' ' };
+            let line = Line::from(format!("{}{} {}", marker, if addr == state.pc { '>' } else { ' ' }, text));
+            if addr == self.cursor {
+                line.style(Style::default().bg(Color::DarkGray))
+            } else if addr == state.pc {
+                line.style(Style::default().fg(Color::Yellow))
+            } else {
+                line
+            }
+        }).collect();
+        frame.render_widget(Paragraph::new(code_lines).block(Block::bordered().title("Disassembly")), code);
+
+        let rows = u32::from(memory.height.saturating_sub(2));
+        let end = (u32::from(self.memory_view) + rows * 16).clamp(1, 0x10000) - 1;
+        let dump = memory8080.hexdump_diff(self.memory_view..=end as u16, &self.previous[usize::from(self.memory_view)..]);
+        let memory_lines: Vec<Line> = dump.lines().map(|line| Line::from(line.to_string())).collect();
+        frame.render_widget(Paragraph::new(memory_lines).block(Block::bordered().title("Memory (* changed)")), memory);
+
+        let mode = if self.running { "running" } else { &self.status };
+        frame.render_widget(
+            Paragraph::new(format!(" {} | s step  r run  p pause  b breakpoint  PgUp/PgDn memory  q quit", mode)),
+            status,
+        );
+    }
+
+    fn handle_key(&mut self, key: KeyCode) -> bool {
+        match key {
+            KeyCode::Char('q') => return false,
+            KeyCode::Char('s') if !self.running => {
+                self.snapshot();
+                let reason = self.step();
+                self.stop(reason.unwrap_or_else(|| "stopped".to_string()));
+            }
+            KeyCode::Char('r') if !self.running => {
+                self.snapshot();
+                self.running = true;
+                // Step off a breakpoint at PC before checking for them again
+                if let Some(reason) = self.step() {
+                    if !reason.starts_with("breakpoint") {
+                        self.stop(reason);
+                    }
+                }
+            }
+            KeyCode::Char('p') => self.stop("paused".to_string()),
+            KeyCode::Char('b') if !self.breakpoints.remove(&self.cursor) => {
+                self.breakpoints.insert(self.cursor);
+            }
+            KeyCode::Up => self.move_cursor(false),
+            KeyCode::Down => self.move_cursor(true),
+            KeyCode::PageUp => self.memory_view = self.memory_view.wrapping_sub(0x100),
+            KeyCode::PageDown => self.memory_view = self.memory_view.wrapping_add(0x100),
+            _ => {}
+        }
+        true
+    }
+}
+
+fn run(terminal: &mut DefaultTerminal, debugger: &mut Debugger) -> io::Result<()> {
+    loop {
+        terminal.draw(|frame| debugger.draw(frame))?;
+        if debugger.running {
+            debugger.run_slice();
+        }
+        let timeout = if debugger.running { Duration::ZERO } else { Duration::from_millis(250) };
+        if event::poll(timeout)? {
+            if let event::Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !debugger.handle_key(key.code) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut bus = PortBus::new();
+    let (image, start) = match args.get(1) {
+        Some(path) => {
+            let addr = match args.get(2).map(|addr| u16::from_str_radix(addr, 16)) {
+                None => 0x100,
+                Some(Ok(addr)) => addr,
+                Some(Err(e)) => {
+                    eprintln!("Bad load address: {}", e);
+                    return;
+                }
+            };
+            match LoadMap::new(0x00).file(path, addr).load() {
+                Ok(image) => (image, addr),
+                Err(e) => {
+                    eprintln!("Could not load ROM: {}", e);
+                    return;
+                }
+            }
+        }
+        None => {
+            let mut image = [0x00; 0x10000];
+            image[..monitor::ROM.len()].copy_from_slice(monitor::ROM);
+            // Nothing is typed into the monitor, it just waits for input
+            let serial = Rc::new(RefCell::new(Serial::new(monitor::SERIAL_PORT)));
+            bus.map(monitor::SERIAL_PORT, serial.clone());
+            bus.map(monitor::SERIAL_PORT + 1, serial);
+            (image, 0x0000)
+        }
+    };
+
+    let mut debugger = Debugger::new(image, start, bus);
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, &mut debugger);
+    ratatui::restore();
+    if let Err(e) = result {
+        eprintln!("Terminal error: {}", e);
+    }
+}
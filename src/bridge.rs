@@ -0,0 +1,196 @@
+//! Hands what a machine did over to a frontend running on another thread,
+//! one batch per video frame.
+//!
+//! The emulation thread feeds every instruction's `Event` to an
+//! `EventSender` and calls `end_frame` at each frame boundary. The frame's
+//! port writes, sound triggers and faults then go to the frontend in one
+//! `FrameBatch` over a bounded channel. Sending never blocks: when the
+//! frontend has fallen behind and the channel is full, the frame is merged
+//! into the next one instead, and the batch says how many frames it
+//! covers. A batch holds at most a fixed number of events, so a frontend
+//! that stops reading altogether cannot make it grow without bound.
+//!
+//! ```
+//! use i8080_emulator::bridge::{bridge, FrameEvent};
+//! use i8080_emulator::cpu::Event;
+//! use std::thread;
+//!
+//! let (mut sender, frames) = bridge(2);
+//! sender.sound_port(0x03);
+//! let emulation = thread::spawn(move || {
+//!     sender.record(&Event::Output(0x03, 0x01, 10));
+//!     sender.end_frame();
+//! });
+//! emulation.join().unwrap();
+//! let batch = frames.recv().unwrap();
+//! assert_eq!(batch.events[1], FrameEvent::Sound { port: 0x03, triggered: 0x01 });
+//! ```
+
+use crate::cpu::{Event, Fault};
+
+use std::mem;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+/// Events a batch holds before further port writes are dropped.
+pub const DEFAULT_MAX_EVENTS: usize = 4096;
+
+/// Something the frontend may want to show or play.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameEvent {
+    /// An OUT instruction wrote `value` to `port`.
+    PortWrite { port: u8, value: u8 },
+    /// Bits that went from 0 to 1 in a write to a port marked with
+    /// `EventSender::sound_port`, as boards trigger their sound effects.
+    Sound { port: u8, triggered: u8 },
+    Fault(Fault),
+}
+
+/// The events of one or more frames, in the order they happened.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameBatch {
+    /// Number of the last frame in the batch, counting from 0.
+    pub frame: u64,
+    /// How many frames the batch covers, more than 1 when the frontend
+    /// fell behind.
+    pub frames: u32,
+    pub events: Vec<FrameEvent>,
+    /// Port writes left out because the batch was full. Sound triggers and
+    /// faults are always kept.
+    pub dropped: usize,
+}
+
+/// Creates a bridge holding up to `capacity` batches the frontend has not
+/// taken yet. The receiving end is a plain `mpsc::Receiver`.
+pub fn bridge(capacity: usize) -> (EventSender, Receiver<FrameBatch>) {
+    let (tx, rx) = mpsc::sync_channel(capacity);
+    let sender = EventSender {
+        tx,
+        batch: FrameBatch::default(),
+        frame: 0,
+        max_events: DEFAULT_MAX_EVENTS,
+        sound_ports: [false; 0x100],
+        last_sound: [0x00; 0x100],
+    };
+    (sender, rx)
+}
+
+/// The emulation thread's end of a bridge.
+pub struct EventSender {
+    tx: SyncSender<FrameBatch>,
+    // The frame being collected, along with any the frontend had no room
+    // for
+    batch: FrameBatch,
+    frame: u64,
+    max_events: usize,
+    sound_ports: [bool; 0x100],
+    // Last value written to each sound port, to find the rising bits
+    last_sound: [u8; 0x100],
+}
+
+impl EventSender {
+    /// Reports rising bits in writes to `port` as `FrameEvent::Sound` as
+    /// well as the write itself.
+    pub fn sound_port(&mut self, port: u8) {
+        self.sound_ports[usize::from(port)] = true;
+    }
+
+    /// Sets how many events a batch holds. See `FrameBatch::dropped`.
+    pub fn set_max_events(&mut self, max_events: usize) {
+        self.max_events = max_events;
+    }
+
+    /// Records what an instruction did, if it matters to a frontend.
+    pub fn record(&mut self, event: &Event) {
+        match *event {
+            Event::Output(port, value, _) => {
+                self.push(FrameEvent::PortWrite { port, value });
+                let port = usize::from(port);
+                if self.sound_ports[port] {
+                    let triggered = value & !self.last_sound[port];
+                    self.last_sound[port] = value;
+                    if triggered != 0 {
+                        self.push(FrameEvent::Sound { port: port as u8, triggered });
+                    }
+                }
+            }
+            Event::Fault(fault) => self.push(FrameEvent::Fault(fault)),
+            _ => {}
+        }
+    }
+
+    pub fn push(&mut self, event: FrameEvent) {
+        let keep = !matches!(event, FrameEvent::PortWrite { .. });
+        if keep || self.batch.events.len() < self.max_events {
+            self.batch.events.push(event);
+        } else {
+            self.batch.dropped += 1;
+        }
+    }
+
+    /// Closes the current frame and offers it to the frontend without
+    /// waiting. Returns `false` once the frontend has hung up.
+    pub fn end_frame(&mut self) -> bool {
+        self.batch.frame = self.frame;
+        self.batch.frames += 1;
+        self.frame += 1;
+        match self.tx.try_send(mem::take(&mut self.batch)) {
+            Ok(()) => true,
+            // Keep it and carry on collecting into it
+            Err(TrySendError::Full(batch)) => {
+                self.batch = batch;
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bridge::{bridge, FrameEvent};
+    use crate::cpu::{Event, Fault};
+
+    #[test]
+    fn frames_are_coalesced_when_the_frontend_lags() {
+        let (mut sender, frames) = bridge(1);
+        sender.record(&Event::Output(0x10, 1, 10));
+        sender.record(&Event::Normal(4));
+        assert!(sender.end_frame());
+        sender.record(&Event::Output(0x10, 2, 10));
+        assert!(sender.end_frame());
+        sender.record(&Event::Fault(Fault::Guard(0x2300)));
+        assert!(sender.end_frame());
+
+        let first = frames.try_recv().unwrap();
+        assert_eq!((first.frame, first.frames), (0, 1));
+        assert_eq!(first.events, [FrameEvent::PortWrite { port: 0x10, value: 1 }]);
+        assert!(frames.try_recv().is_err());
+
+        assert!(sender.end_frame());
+        let merged = frames.try_recv().unwrap();
+        assert_eq!((merged.frame, merged.frames), (3, 3));
+        assert_eq!(merged.events, [FrameEvent::PortWrite { port: 0x10, value: 2 }, FrameEvent::Fault(Fault::Guard(0x2300))]);
+
+        drop(frames);
+        assert!(!sender.end_frame());
+    }
+
+    #[test]
+    fn sound_triggers_and_limits() {
+        let (mut sender, frames) = bridge(4);
+        sender.sound_port(0x05);
+        sender.set_max_events(2);
+        for value in [0x01, 0x03, 0x02, 0x06] {
+            sender.record(&Event::Output(0x05, value, 10));
+        }
+        sender.end_frame();
+        let batch = frames.recv().unwrap();
+        assert_eq!(batch.events, [
+            FrameEvent::PortWrite { port: 0x05, value: 0x01 },
+            FrameEvent::Sound { port: 0x05, triggered: 0x01 },
+            FrameEvent::Sound { port: 0x05, triggered: 0x02 },
+            FrameEvent::Sound { port: 0x05, triggered: 0x04 },
+        ]);
+        assert_eq!(batch.dropped, 3);
+    }
+}
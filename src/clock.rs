@@ -0,0 +1,174 @@
+//! Running a machine at the speed of the real thing.
+//!
+//! A `Throttle` is fed the cycles of every instruction, from its `Event`,
+//! and sleeps whenever emulation has got ahead of the wall clock, so that
+//! a machine runs at its authentic 2 MHz, or whatever speed is set, on a
+//! host that could go much faster. Sleeps are only taken once there is at
+//! least a millisecond to wait, to keep their overhead down.
+//!
+//! ```no_run
+//! use i8080_emulator::clock::Throttle;
+//! use i8080_emulator::cpu::CPU;
+//! use i8080_emulator::memory::Memory8080;
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new_empty())));
+//! let mut throttle = Throttle::new(2_000_000);
+//! loop {
+//!     let (event, _) = cpu.step();
+//!     throttle.record(&event);
+//!     throttle.throttle();
+//! }
+//! ```
+
+use crate::cpu::{ClockCycles, Event, Timestamp};
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The clock rate of a typical 8080 system.
+pub const DEFAULT_HZ: u64 = 2_000_000;
+
+// Waits shorter than this are put off until there is more to wait
+const MIN_SLEEP: Duration = Duration::from_millis(1);
+
+// Falling further behind than this, after the host stalled for instance,
+// starts the count over rather than racing to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+/// How fast a `Throttle` lets the CPU run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    /// Clock cycles per second.
+    Hz(u64),
+    /// As fast as the host goes, for turbo modes.
+    Unlimited,
+}
+
+/// Turns the cycles a machine runs into sleeps. See the module
+/// documentation.
+pub struct Throttle {
+    speed: Speed,
+    // When counting started, and the cycles run since
+    start: Instant,
+    cycles: Timestamp,
+}
+
+impl Throttle {
+    pub fn new(hz: u64) -> Self {
+        Self::with_speed(Speed::Hz(hz))
+    }
+
+    pub fn with_speed(speed: Speed) -> Self {
+        Throttle {
+            speed,
+            start: Instant::now(),
+            cycles: 0,
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Changes the speed from now on.
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.restart(Instant::now());
+    }
+
+    pub fn add_cycles(&mut self, cycles: ClockCycles) {
+        self.cycles += Timestamp::from(cycles);
+    }
+
+    /// Counts the cycles an instruction took.
+    pub fn record(&mut self, event: &Event) {
+        self.add_cycles(event.cycles());
+    }
+
+    /// Wall-clock time the cycles counted so far take at the current
+    /// speed.
+    pub fn emulated_time(&self) -> Duration {
+        match self.speed {
+            Speed::Hz(0) | Speed::Unlimited => Duration::ZERO,
+            Speed::Hz(hz) => {
+                let nanos = u128::from(self.cycles) * 1_000_000_000 / u128::from(hz);
+                Duration::from_nanos(nanos.min(u128::from(u64::MAX)) as u64)
+            }
+        }
+    }
+
+    /// How long to sleep at `now` for the wall clock to catch up with
+    /// emulation. Starts counting over when emulation has fallen too far
+    /// behind.
+    pub fn wait_at(&mut self, now: Instant) -> Duration {
+        if self.speed == Speed::Unlimited {
+            return Duration::ZERO;
+        }
+        let emulated = self.emulated_time();
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed > emulated + MAX_LAG {
+            self.restart(now);
+            return Duration::ZERO;
+        }
+        emulated.saturating_sub(elapsed)
+    }
+
+    /// Sleeps if emulation is a millisecond or more ahead of the wall
+    /// clock.
+    pub fn throttle(&mut self) {
+        let wait = self.wait_at(Instant::now());
+        if wait >= MIN_SLEEP {
+            thread::sleep(wait);
+        }
+    }
+
+    fn restart(&mut self, now: Instant) {
+        self.start = now;
+        self.cycles = 0;
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new(DEFAULT_HZ)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::{Speed, Throttle};
+    use crate::cpu::Event;
+
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn waits_for_the_wall_clock() {
+        let mut throttle = Throttle::new(2_000_000);
+        let start = throttle.start;
+        throttle.record(&Event::Normal(4));
+        throttle.add_cycles(19_996);
+        assert_eq!(throttle.emulated_time(), Duration::from_millis(10));
+        assert_eq!(throttle.wait_at(start), Duration::from_millis(10));
+        assert_eq!(throttle.wait_at(start + Duration::from_millis(4)), Duration::from_millis(6));
+        assert_eq!(throttle.wait_at(start + Duration::from_millis(50)), Duration::ZERO);
+
+        // Far behind: the backlog is dropped
+        assert_eq!(throttle.wait_at(start + Duration::from_secs(1)), Duration::ZERO);
+        throttle.add_cycles(2_000);
+        assert_eq!(throttle.wait_at(start + Duration::from_secs(1)), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn unlimited() {
+        let mut throttle = Throttle::with_speed(Speed::Unlimited);
+        throttle.add_cycles(1_000_000);
+        assert_eq!(throttle.wait_at(Instant::now()), Duration::ZERO);
+        throttle.set_speed(Speed::Hz(1_000));
+        assert_eq!(throttle.speed(), Speed::Hz(1_000));
+        throttle.add_cycles(1);
+        throttle.throttle();
+        assert_eq!(throttle.emulated_time(), Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,129 @@
+//! Memory contention between the CPU and a video circuit sharing its RAM.
+//!
+//! On boards where the display is scanned out of main memory, the video
+//! circuit has priority on the RAM while it is drawing lines, and a CPU
+//! access to the framebuffer in that time waits for a free slot. A
+//! `VideoContention` set on the CPU adds those wait states: every access
+//! to the framebuffer by an instruction that starts during active display
+//! costs extra cycles. Outside active display, in vertical blanking, the
+//! CPU runs at full speed.
+//!
+//! Contention is off unless set with `CPU::set_contention`.
+//!
+//! ```
+//! use i8080_emulator::contention::VideoContention;
+//! use i8080_emulator::cpu::CPU;
+//! use i8080_emulator::memory::Memory8080;
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! // STA 0x2400
+//! let mut memory = [0x00; 0x10000];
+//! memory[..3].copy_from_slice(&[0x32, 0x00, 0x24]);
+//! let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+//! cpu.set_contention(Some(VideoContention::default()));
+//! assert_eq!(cpu.step().1, 13 + 1);
+//! ```
+
+use crate::cpu::{ClockCycles, Timestamp};
+
+use core::ops::{Range, RangeInclusive};
+
+/// When and where the video circuit holds up the CPU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VideoContention {
+    /// The framebuffer.
+    pub range: RangeInclusive<u16>,
+    /// Clock cycles per video frame. Frames start at cycle 0.
+    pub frame: Timestamp,
+    /// The part of each frame, in cycles from its start, spent drawing
+    /// lines.
+    pub active: Range<Timestamp>,
+    /// Wait cycles added per framebuffer access during active display.
+    pub wait: ClockCycles,
+}
+
+impl Default for VideoContention {
+    /// A 2 MHz board drawing a 60 Hz frame of 262 lines, 224 of them
+    /// visible, out of 0x2400-0x3fff, one wait state per access.
+    fn default() -> Self {
+        VideoContention {
+            range: 0x2400..=0x3fff,
+            frame: 33_333,
+            active: 0..28_500,
+            wait: 1,
+        }
+    }
+}
+
+impl VideoContention {
+    /// Whether the display is being drawn at cycle `now`.
+    pub fn is_active(&self, now: Timestamp) -> bool {
+        self.frame > 0 && self.active.contains(&(now % self.frame))
+    }
+
+    /// Wait cycles for an instruction starting at cycle `now` that made
+    /// `accesses` framebuffer accesses.
+    pub fn wait_cycles(&self, now: Timestamp, accesses: u32) -> ClockCycles {
+        if self.is_active(now) {
+            accesses * self.wait
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::contention::VideoContention;
+    use crate::cpu::CPU;
+    use crate::memory::Memory8080;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn waits_only_during_active_display() {
+        let contention = VideoContention { frame: 100, active: 10..50, wait: 2, ..VideoContention::default() };
+        assert_eq!(contention.wait_cycles(5, 3), 0);
+        assert_eq!(contention.wait_cycles(10, 3), 6);
+        assert_eq!(contention.wait_cycles(149, 1), 2);
+        assert_eq!(contention.wait_cycles(150, 1), 0);
+    }
+
+    #[test]
+    fn cpu_accesses() {
+        // LXI H, 0x2400; MOV A, M; SHLD 0x3ffe; LDA 0x2000
+        let code = [0x21, 0x00, 0x24, 0x7e, 0x22, 0xfe, 0x3f, 0x3a, 0x00, 0x20];
+        let mut memory = [0x00; 0x10000];
+        memory[..code.len()].copy_from_slice(&code);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        cpu.set_contention(Some(VideoContention { frame: 1000, active: 0..30, wait: 3, ..VideoContention::default() }));
+        assert_eq!(cpu.step().1, 10);
+        assert_eq!(cpu.step().1, 7 + 3);
+        assert_eq!(cpu.step().1, 16 + 2 * 3);
+        assert_eq!(cpu.step().1, 13);
+        assert_eq!(cpu.cycles(), 10 + 10 + 22 + 13);
+
+        // Blanking: the CPU is left alone
+        cpu.pc = 0x0003;
+        assert_eq!(cpu.step().1, 7);
+        cpu.set_contention(None);
+        cpu.pc = 0x0003;
+        cpu.advance_cycles(1000 - 62);
+        assert_eq!(cpu.step().1, 7);
+    }
+
+    #[test]
+    fn tracing_adds_no_waits() {
+        // JMP 0x2400, run from the framebuffer
+        let mut memory = [0x00; 0x10000];
+        memory[0x2400..0x2403].copy_from_slice(&[0xc3, 0x00, 0x24]);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        cpu.pc = 0x2400;
+        cpu.set_contention(Some(VideoContention::default()));
+        assert_eq!(cpu.step().1, 10 + 3);
+        cpu.set_trace_hook(|_| {});
+        assert_eq!(cpu.step().1, 10 + 3);
+    }
+}
@@ -0,0 +1,253 @@
+//! Which addresses a program executed, read and wrote, to tell code from
+//! data in a ROM before disassembling it.
+//!
+//! `Coverage` wraps the CPU's memory and marks every address accessed.
+//! Instructions are marked executed along with their operand bytes, which
+//! do not count as reads. The marks can be taken out as a bitmap or as a
+//! list of address ranges.
+//!
+//! ```
+//! use i8080_emulator::coverage::{Coverage, Mark};
+//! use i8080_emulator::cpu::CPU;
+//! use i8080_emulator::memory::Memory8080;
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! // LDA 0x0010; STA 0x2000; HLT
+//! let mut memory = [0x00; 0x10000];
+//! memory[..7].copy_from_slice(&[0x3a, 0x10, 0x00, 0x32, 0x00, 0x20, 0x76]);
+//! let coverage = Rc::new(RefCell::new(Coverage::new(Memory8080::new(memory))));
+//! let mut cpu = CPU::new(coverage.clone());
+//! while !cpu.is_halted() {
+//!     cpu.step();
+//! }
+//! let coverage = coverage.borrow();
+//! assert_eq!(coverage.ranges(Mark::EXECUTED), [0x0000..=0x0006]);
+//! assert_eq!(coverage.ranges(Mark::READ), [0x0010..=0x0010]);
+//! assert_eq!(coverage.ranges(Mark::WRITTEN), [0x2000..=0x2000]);
+//! ```
+
+use crate::memory::Memory;
+use crate::opcodes::OPCODES;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::ops::{BitOr, RangeInclusive};
+
+/// A set of ways an address was accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mark(u8);
+
+impl Mark {
+    pub const NONE: Mark = Mark(0x00);
+    /// Part of an instruction the CPU ran.
+    pub const EXECUTED: Mark = Mark(0x01);
+    /// Read as data.
+    pub const READ: Mark = Mark(0x02);
+    pub const WRITTEN: Mark = Mark(0x04);
+
+    pub fn contains(self, other: Mark) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(self, other: Mark) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl BitOr for Mark {
+    type Output = Mark;
+
+    fn bitor(self, rhs: Mark) -> Mark {
+        Mark(self.0 | rhs.0)
+    }
+}
+
+/// A memory that marks the addresses accessed in the memory it wraps.
+/// `peek` marks nothing.
+pub struct Coverage<M: Memory> {
+    inner: M,
+    marks: Vec<Cell<u8>>,
+    // The bytes of the instruction being run, whose operand reads are
+    // part of executing it
+    instruction: Cell<(u16, u8)>,
+}
+
+impl<M: Memory> Coverage<M> {
+    pub fn new(inner: M) -> Self {
+        Coverage {
+            inner,
+            marks: vec![Cell::new(0); 0x10000],
+            instruction: Cell::new((0, 0)),
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    /// How `addr` was accessed.
+    pub fn mark(&self, addr: u16) -> Mark {
+        Mark(self.marks[usize::from(addr)].get())
+    }
+
+    /// Forgets every access so far.
+    pub fn clear(&mut self) {
+        self.marks.iter().for_each(|mark| mark.set(0));
+    }
+
+    /// One bit per address, set where the address has any of `marks`:
+    /// 0x2000 bytes, bit 0 of the first byte for address 0.
+    pub fn bitmap(&self, marks: Mark) -> Vec<u8> {
+        let mut bitmap = vec![0x00; 0x2000];
+        for (addr, mark) in self.marks.iter().enumerate() {
+            if Mark(mark.get()).intersects(marks) {
+                bitmap[addr / 8] |= 1 << (addr % 8);
+            }
+        }
+        bitmap
+    }
+
+    /// The runs of addresses with any of `marks`, lowest first.
+    pub fn ranges(&self, marks: Mark) -> Vec<RangeInclusive<u16>> {
+        let mut ranges = Vec::new();
+        let mut start = None;
+        for addr in 0..=0xffff_u16 {
+            match (self.mark(addr).intersects(marks), start) {
+                (true, None) => start = Some(addr),
+                (false, Some(first)) => {
+                    ranges.push(first..=addr - 1);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(first) = start {
+            ranges.push(first..=0xffff);
+        }
+        ranges
+    }
+
+    /// How many addresses have any of `marks`.
+    pub fn count(&self, marks: Mark) -> usize {
+        self.marks.iter().filter(|mark| Mark(mark.get()).intersects(marks)).count()
+    }
+
+    fn set(&self, i: usize, mark: Mark) {
+        let cell = &self.marks[i & 0xffff];
+        cell.set(cell.get() | mark.0);
+    }
+
+    fn note_read(&self, i: usize) {
+        let (start, len) = self.instruction.get();
+        let offset = (i as u16).wrapping_sub(start);
+        if offset >= u16::from(len) {
+            self.set(i, Mark::READ);
+        }
+    }
+}
+
+impl<M: Memory> Memory for Coverage<M> {
+    fn read(&self, i: usize) -> u8 {
+        self.note_read(i);
+        self.inner.read(i)
+    }
+
+    fn write(&mut self, i: usize, data: u8) {
+        self.set(i, Mark::WRITTEN);
+        self.inner.write(i, data);
+    }
+
+    fn read16(&self, i: usize) -> u16 {
+        self.note_read(i);
+        self.note_read((i + 1) & 0xffff);
+        self.inner.read16(i)
+    }
+
+    fn write16(&mut self, i: usize, data: u16) {
+        self.set(i, Mark::WRITTEN);
+        self.set(i + 1, Mark::WRITTEN);
+        self.inner.write16(i, data);
+    }
+
+    fn peek(&self, i: usize) -> u8 {
+        self.inner.peek(i)
+    }
+
+    fn note_fetch(&self, addr: u16) {
+        let len = OPCODES[usize::from(self.inner.peek(usize::from(addr)))].length;
+        for offset in 0..len {
+            self.set(usize::from(addr.wrapping_add(offset.into())), Mark::EXECUTED);
+        }
+        self.instruction.set((addr, len));
+        self.inner.note_fetch(addr);
+    }
+
+    fn is_executable(&self, addr: u16) -> bool {
+        self.inner.is_executable(addr)
+    }
+
+    fn take_guard_hit(&self) -> Option<u16> {
+        self.inner.take_guard_hit()
+    }
+
+    fn take_contended_accesses(&self) -> u32 {
+        self.inner.take_contended_accesses()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coverage::{Coverage, Mark};
+    use crate::cpu::CPU;
+    use crate::memory::{Memory, Memory8080};
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn marks_and_exports() {
+        // LXI SP, 0x3000; LHLD 0x0100; CALL 0x0020; HLT
+        // 0x0020: MOV A, M; RET
+        let mut memory = [0x00; 0x10000];
+        memory[..10].copy_from_slice(&[0x31, 0x00, 0x30, 0x2a, 0x00, 0x01, 0xcd, 0x20, 0x00, 0x76]);
+        memory[0x20..0x22].copy_from_slice(&[0x7e, 0xc9]);
+        memory[0x100..0x102].copy_from_slice(&[0x07, 0x00]);
+        let coverage = Rc::new(RefCell::new(Coverage::new(Memory8080::new(memory))));
+        let mut cpu = CPU::new(coverage.clone());
+        while !cpu.is_halted() {
+            cpu.step();
+        }
+
+        let coverage = coverage.borrow();
+        assert_eq!(coverage.ranges(Mark::EXECUTED), [0x0000..=0x0009, 0x0020..=0x0021]);
+        // The word read by LHLD, then the byte at HL and the return address
+        assert_eq!(coverage.ranges(Mark::READ), [0x0007..=0x0007, 0x0100..=0x0101, 0x2ffe..=0x2fff]);
+        assert_eq!(coverage.ranges(Mark::WRITTEN), [0x2ffe..=0x2fff]);
+        assert_eq!(coverage.mark(0x0007), Mark::EXECUTED | Mark::READ);
+        assert_eq!(coverage.count(Mark::READ | Mark::WRITTEN), 5);
+
+        let bitmap = coverage.bitmap(Mark::EXECUTED);
+        assert_eq!(bitmap.len(), 0x2000);
+        assert_eq!(bitmap[..5], [0xff, 0x03, 0x00, 0x00, 0x03]);
+    }
+
+    #[test]
+    fn ranges_reach_the_top() {
+        let mut coverage = Coverage::new(Memory8080::new([0x00; 0x10000]));
+        coverage.write(0xffff, 1);
+        coverage.write(0x0000, 1);
+        assert_eq!(coverage.ranges(Mark::WRITTEN), [0x0000..=0x0000, 0xffff..=0xffff]);
+        coverage.clear();
+        assert!(coverage.ranges(Mark::WRITTEN).is_empty());
+    }
+}
@@ -1,56 +1,943 @@
+use crate::alu;
 use crate::memory::{Memory, Memory8080};
 use crate::registers::{Registers, Flag};
-use crate::device::Device;
-use crate::disassembler::{Disassembler};
-
-use std::cell::RefCell;
-use std::rc::Rc;
-
-type ClockCycles = u32;
+use crate::contention::VideoContention;
+use crate::disassembler::{Disassembler, Peek};
+use crate::opcodes::OPCODES;
+use crate::symbols::SymbolTable;
+use crate::error::EmulatorError;
+use crate::i8085::{self, InterruptLine};
+use crate::I8080Core;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt;
+use core::ops::RangeInclusive;
+
+/// Clock cycles taken by a single instruction, at most 18 on an 8080.
+/// Anything accumulating cycles over time uses `Timestamp` instead.
+pub type ClockCycles = u32;
+/// A running count of clock cycles. It wraps around on overflow, which at
+/// 2 MHz happens after some 290,000 years; elapsed time between two
+/// timestamps is `later.wrapping_sub(earlier)`.
+pub type Timestamp = u64;
 type Port = u8;
 
+/// What an executed instruction needs from the machine around the CPU.
+///
+/// Port writes are reported synchronously: `exec` returns
+/// `Event::Output` for the OUT instruction itself, after PC has moved past
+/// it but before anything else runs. A machine that delivers the event to
+/// its device before calling `fetch` again therefore gives the device the
+/// write before the next instruction starts, and before any interrupt
+/// accepted with `interrupt` or `inter_handle` at that instruction
+/// boundary.
+///
+/// Port reads work the same way: `exec` returns `Event::Input` for the IN
+/// instruction, and the machine must supply the byte read with
+/// `CPU::complete_input` before calling `fetch` again. Until then A keeps
+/// its old value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Event {
     Output(Port, u8, ClockCycles),
+    /// An IN instruction read the port. Resolve it with
+    /// `CPU::complete_input`.
     Input(Port, ClockCycles),
+    /// HLT ran and the CPU is now halted. Until an interrupt is accepted,
+    /// every further step does nothing but burn 4 cycles as
+    /// `Event::Normal`.
     Halt(ClockCycles),
     Normal(ClockCycles),
+    /// A debugging check stopped the instruction before it ran. PC still
+    /// points at it and no cycles were taken.
+    Fault(Fault),
+}
+
+/// A debugging check that stopped an instruction from running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// PC reached an address marked non-executable with
+    /// `Memory8080::set_no_execute`.
+    NoExecute(u16),
+    /// The instruction accessed an address in a guard range set with
+    /// `Memory8080::set_guard`. Unlike `NoExecute`, this is reported
+    /// after the instruction has run, in place of its own event.
+    Guard(u16),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::NoExecute(addr) => write!(f, "execution of non-executable address 0x{:04x}", addr),
+            Fault::Guard(addr) => write!(f, "access to guard address 0x{:04x}", addr),
+        }
+    }
+}
+
+impl Event {
+    /// Clock cycles taken by the instruction that produced the event.
+    pub fn cycles(&self) -> ClockCycles {
+        match *self {
+            Event::Output(_, _, c) | Event::Input(_, c) | Event::Halt(c) | Event::Normal(c) => c,
+            Event::Fault(_) => 0,
+        }
+    }
+
+    // The same event taking `cycles` cycles instead
+    fn with_cycles(self, cycles: ClockCycles) -> Event {
+        match self {
+            Event::Output(port, value, _) => Event::Output(port, value, cycles),
+            Event::Input(port, _) => Event::Input(port, cycles),
+            Event::Halt(_) => Event::Halt(cycles),
+            Event::Normal(_) => Event::Normal(cycles),
+            Event::Fault(fault) => Event::Fault(fault),
+        }
+    }
+
+    // The same event taking `extra` more cycles
+    fn stretched(self, extra: ClockCycles) -> Event {
+        match self {
+            Event::Output(port, value, c) => Event::Output(port, value, c + extra),
+            Event::Input(port, c) => Event::Input(port, c + extra),
+            Event::Halt(c) => Event::Halt(c + extra),
+            Event::Normal(c) => Event::Normal(c + extra),
+            Event::Fault(fault) => Event::Fault(fault),
+        }
+    }
+}
+
+/// Outcome of `CPU::step_n`.
+pub struct BatchResult {
+    /// Number of instructions executed.
+    pub steps: usize,
+    /// Clock cycles taken by those instructions.
+    pub cycles: Timestamp,
+    /// The I/O or halt event that ended the batch early, if any. The
+    /// instruction producing it is included in `steps` and `cycles`.
+    pub event: Option<Event>,
+}
+
+/// Everything about one instruction, from `CPU::step_result`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecResult {
+    /// The event `step` would have returned.
+    pub event: Event,
+    /// Clock cycles the instruction took, 0 for a fault.
+    pub cycles: ClockCycles,
+    /// The CPU's cycle count when the instruction started, so that events
+    /// from a run can be put on a timeline.
+    pub start: Timestamp,
+    pub opcode: u8,
+    /// Where the instruction was fetched from.
+    pub pc_before: u16,
+    /// Where the next one will be, after any jump.
+    pub pc_after: u16,
+}
+
+impl ExecResult {
+    /// The cycle count once the instruction finished.
+    pub fn end(&self) -> Timestamp {
+        self.start.wrapping_add(Timestamp::from(self.cycles))
+    }
+}
+
+// Borrowed memory with the instruction bytes at `pc` laid over it
+struct Borrowed<'a, M: Memory> {
+    memory: &'a mut M,
+    pc: u16,
+    code: &'a [u8],
+}
+
+impl<'a, M: Memory> Borrowed<'a, M> {
+    fn code_byte(&self, i: usize) -> Option<u8> {
+        let offset = (i as u16).wrapping_sub(self.pc);
+        self.code.get(usize::from(offset)).copied()
+    }
+}
+
+impl<'a, M: Memory> Memory for Borrowed<'a, M> {
+    fn read(&self, i: usize) -> u8 {
+        self.code_byte(i).unwrap_or_else(|| self.memory.read(i))
+    }
+
+    fn write(&mut self, i: usize, data: u8) {
+        self.memory.write(i, data);
+    }
+
+    fn read16(&self, i: usize) -> u16 {
+        u16::from_le_bytes([self.read(i), self.read((i + 1) & 0xffff)])
+    }
+
+    fn write16(&mut self, i: usize, data: u16) {
+        self.memory.write16(i, data);
+    }
+
+    fn peek(&self, i: usize) -> u8 {
+        self.code_byte(i).unwrap_or_else(|| self.memory.peek(i))
+    }
+}
+
+/// Executes one instruction on `state` and `memory` without a `CPU` of
+/// their own, for fuzzing against another core. The instruction comes
+/// from `code`, laid over memory at `state.pc`, or from memory itself
+/// when `code` is empty; everything else, stack and data alike, is read
+/// from and written to `memory`. The core is a plain 8080.
+///
+/// IN leaves A alone, so store the byte read in `state.a` afterwards.
+///
+/// ```
+/// use i8080_emulator::cpu::{execute_one, CpuState};
+/// use i8080_emulator::memory::{Memory, Memory8080};
+///
+/// // PUSH B
+/// let mut state = CpuState { b: 0x12, c: 0x34, sp: 0x2000, ..CpuState::default() };
+/// let mut memory = Memory8080::new_empty();
+/// let result = execute_one(&mut state, &mut memory, &[0xc5]);
+/// assert_eq!((result.cycles, state.sp, state.pc), (11, 0x1ffe, 0x0001));
+/// assert_eq!(memory.read16(0x1ffe), 0x1234);
+/// ```
+pub fn execute_one<M: Memory>(state: &mut CpuState, memory: &mut M, code: &[u8]) -> ExecResult {
+    let memory = Borrowed { memory, pc: state.pc, code };
+    let mut cpu = CPU::new(Rc::new(RefCell::new(memory)));
+    cpu.set_state(state);
+    let result = cpu.step_result();
+    *state = cpu.state();
+    result
+}
+
+/// The programmer-visible state of an 8080, for copying state between
+/// cores and comparing them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuState {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub pc: u16,
+    pub sp: u16,
+    /// Interrupt enable flip-flop.
+    pub inte: bool,
+    /// Whether the CPU is halted, waiting for an interrupt.
+    pub halted: bool,
+}
+
+/// The registers as `Registers` shows them, then SP and PC, with `EI` and
+/// `HLT` added when they apply.
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let regs = Registers { a: self.a, f: self.f, b: self.b, c: self.c, d: self.d, e: self.e, h: self.h, l: self.l };
+        write!(f, "{} SP={:04X} PC={:04X}", regs, self.sp, self.pc)?;
+        if self.inte {
+            f.write_str(" EI")?;
+        }
+        if self.halted {
+            f.write_str(" HLT")?;
+        }
+        Ok(())
+    }
+}
+
+impl CpuState {
+    /// What changed from `self` to `other`, register by register and flag
+    /// by flag. `SaveState::diff` adds memory.
+    pub fn diff(&self, other: &CpuState) -> StateDiff {
+        let registers = [
+            ("A", u16::from(self.a), u16::from(other.a)),
+            ("B", u16::from(self.b), u16::from(other.b)),
+            ("C", u16::from(self.c), u16::from(other.c)),
+            ("D", u16::from(self.d), u16::from(other.d)),
+            ("E", u16::from(self.e), u16::from(other.e)),
+            ("H", u16::from(self.h), u16::from(other.h)),
+            ("L", u16::from(self.l), u16::from(other.l)),
+            ("SP", self.sp, other.sp),
+            ("PC", self.pc, other.pc),
+            ("INTE", u16::from(self.inte), u16::from(other.inte)),
+            ("HALTED", u16::from(self.halted), u16::from(other.halted)),
+        ];
+        let flags = [Flag::S, Flag::Z, Flag::A, Flag::P, Flag::C];
+        StateDiff {
+            registers: registers.iter().copied().filter(|(_, old, new)| old != new).collect(),
+            flags: flags.iter().copied()
+                .filter(|&flag| Flag::is_flag(self.f, flag) != Flag::is_flag(other.f, flag))
+                .map(|flag| (flag, Flag::is_flag(other.f, flag)))
+                .collect(),
+            memory: Vec::new(),
+        }
+    }
+}
+
+/// The differences between two snapshots, from `CpuState::diff` or
+/// `SaveState::diff`, for checking that an instruction touched only what
+/// it should have.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateDiff {
+    /// Registers that changed as `(name, old, new)`. The names are `A` to
+    /// `L`, `SP`, `PC`, `INTE` and `HALTED`, the last two as 0 or 1.
+    pub registers: Vec<(&'static str, u16, u16)>,
+    /// Flags that changed, with their new value.
+    pub flags: Vec<(Flag, bool)>,
+    /// Bytes of memory that changed as `(address, old, new)`.
+    pub memory: Vec<(u16, u8, u8)>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty() && self.flags.is_empty() && self.memory.is_empty()
+    }
+
+    /// The old and new value of register `name`, if it changed.
+    pub fn register(&self, name: &str) -> Option<(u16, u16)> {
+        self.registers.iter().find(|(n, _, _)| *n == name).map(|&(_, old, new)| (old, new))
+    }
+
+    /// Whether every change is to one of `registers` or to memory in
+    /// `addresses`. Flags are not checked.
+    pub fn only(&self, registers: &[&str], addresses: RangeInclusive<u16>) -> bool {
+        self.registers.iter().all(|(name, _, _)| registers.contains(name))
+            && self.memory.iter().all(|(addr, _, _)| addresses.contains(addr))
+    }
 }
 
-pub struct CPU {
+/// The changes separated by commas, such as `SP: F000 -> EFFE, Z: set,
+/// [EFFE]: 00 -> 03`.
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut separator = "";
+        for &(name, old, new) in &self.registers {
+            match name {
+                "SP" | "PC" => write!(f, "{}{}: {:04X} -> {:04X}", separator, name, old, new)?,
+                _ => write!(f, "{}{}: {:02X} -> {:02X}", separator, name, old, new)?,
+            }
+            separator = ", ";
+        }
+        for &(flag, set) in &self.flags {
+            write!(f, "{}{:?}: {}", separator, flag, if set { "set" } else { "cleared" })?;
+            separator = ", ";
+        }
+        for &(addr, old, new) in &self.memory {
+            write!(f, "{}[{:04X}]: {:02X} -> {:02X}", separator, addr, old, new)?;
+            separator = ", ";
+        }
+        Ok(())
+    }
+}
+
+/// Everything needed to put a CPU and its memory back the way they were,
+/// from `CPU::save_state`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SaveState {
+    pub cpu: CpuState,
+    pub cycles: Timestamp,
+    /// All 64K of memory.
+    pub memory: Vec<u8>,
+}
+
+impl SaveState {
+    /// What changed from `self` to `other`, memory included.
+    pub fn diff(&self, other: &SaveState) -> StateDiff {
+        let mut diff = self.cpu.diff(&other.cpu);
+        diff.memory = self.memory.iter().zip(&other.memory).enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(addr, (&old, &new))| (addr as u16, old, new))
+            .collect();
+        diff
+    }
+}
+
+/// An instruction about to run, as passed to the trace hook.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub pc: u16,
+    pub opcode: u8,
+    /// The instruction as the CPU's disassembler formats it.
+    pub disassembly: String,
+    /// Where the instruction is as `NAME+0xN`, if symbols are set.
+    pub symbol: Option<String>,
+    /// Registers and flags before the instruction runs.
+    pub state: CpuState,
+    /// Where the instruction sends PC, for jumps, calls, returns, restarts
+    /// and PCHL.
+    pub branch: Option<Branch>,
+}
+
+/// The control flow of a jump, call, return, restart or PCHL in a
+/// `TraceRecord`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Branch {
+    /// Where PC goes if the branch is taken. For returns this is the
+    /// address on top of the stack.
+    pub target: u16,
+    /// Whether the branch depends on a flag.
+    pub conditional: bool,
+    /// Whether the branch will be taken, going by the flags before the
+    /// instruction runs. Always true for unconditional ones.
+    pub taken: bool,
+}
+
+type TraceHook = Box<dyn FnMut(&TraceRecord)>;
+type TrapHandler<M> = Box<dyn FnMut(&mut CPU<M>)>;
+
+/// Which accuracy features a core has, so test harnesses can decide what
+/// to expect from it instead of going by the crate version. New fields
+/// are added as features land, which is why the struct cannot be built
+/// outside the crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CoreCapabilities {
+    /// The auxiliary carry comes out as on an 8080 for every instruction,
+    /// including ANA, the subtractions and DAA.
+    pub exact_aux_carry: bool,
+    /// Interrupts are only accepted after the instruction following EI,
+    /// as on real hardware.
+    pub ei_delay: bool,
+    /// Bits 1, 3 and 5 of the flags always read as 1, 0 and 0, even after
+    /// POP PSW.
+    pub fixed_psw_bits: bool,
+    pub undocumented_opcodes: UndocumentedOpcodes,
+}
+
+/// What a core does with the twelve opcodes the 8080 does not document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UndocumentedOpcodes {
+    /// They run as their documented twins, as on real silicon: 0x08 and
+    /// the like as NOP, 0xcb as JMP, 0xd9 as RET and 0xdd, 0xed and 0xfd
+    /// as CALL.
+    Aliases,
+}
+
+/// How POP PSW treats the flag bits that are fixed on real hardware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PswPop {
+    /// Bits 3 and 5 read back as 0 and bit 1 as 1, as on an 8080.
+    Hardware,
+    /// All eight popped bits are kept, as some less careful emulators do.
+    /// Meant for A/B testing programs that depend on it.
+    Raw,
+}
+
+/// Which CPU of the family to behave as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuModel {
+    I8080,
+    /// The 8085's RIM and SIM, interrupt lines and cycle counts. See
+    /// `i8085`.
+    I8085,
+}
+
+/// An 8080 driving memory `M` through the `Memory` trait, a flat
+/// `Memory8080` unless told otherwise.
+pub struct CPU<M: Memory = Memory8080> {
     pub regs: Registers,
-    pub memory: Rc<RefCell<Memory8080>>,
+    pub memory: Rc<RefCell<M>>,
     pub pc: u16,
     sp: u16,
     inter: bool,
+    halted: bool,
+    cycles: Timestamp,
+    // Set between fetch and exec, when PC points into the operand bytes
+    mid_instruction: bool,
+    // Set by fetch when the opcode may not run, reported by exec
+    fault: Option<Fault>,
+    psw_pop: PswPop,
+    model: CpuModel,
+    lines: i8085::Lines,
     disassembler: Disassembler,
+    trace: Option<TraceHook>,
+    contention: Option<VideoContention>,
+    traps: BTreeMap<u16, TrapHandler<M>>,
+}
+
+/// Sets a CPU up before it runs, for when the start address, stack and
+/// model are known up front.
+///
+/// ```
+/// use i8080_emulator::cpu::{CpuBuilder, CpuModel};
+///
+/// let cpu = CpuBuilder::new().pc(0x0100).sp(0xf000).model(CpuModel::I8085).build();
+/// assert_eq!((cpu.pc, cpu.sp()), (0x0100, 0xf000));
+/// ```
+pub struct CpuBuilder<M: Memory = Memory8080> {
+    memory: Rc<RefCell<M>>,
+    regs: Registers,
+    pc: u16,
+    sp: u16,
+    interrupts_enabled: bool,
+    model: CpuModel,
+    psw_pop: PswPop,
+    trace: Option<TraceHook>,
+    symbols: Option<SymbolTable>,
+    contention: Option<VideoContention>,
+}
+
+impl Default for CpuBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpuBuilder {
+    /// A CPU with empty `Memory8080` memory unless `memory` is set, and
+    /// otherwise as `CPU::new` leaves it.
+    pub fn new() -> Self {
+        CpuBuilder {
+            memory: Rc::new(RefCell::new(Memory8080::new_empty())),
+            regs: Registers::new(),
+            pc: 0x0000,
+            sp: 0x0000,
+            interrupts_enabled: false,
+            model: CpuModel::I8080,
+            psw_pop: PswPop::Hardware,
+            trace: None,
+            symbols: None,
+            contention: None,
+        }
+    }
+}
+
+impl<M: Memory> CpuBuilder<M> {
+    pub fn memory<N: Memory>(self, memory: Rc<RefCell<N>>) -> CpuBuilder<N> {
+        CpuBuilder {
+            memory,
+            regs: self.regs,
+            pc: self.pc,
+            sp: self.sp,
+            interrupts_enabled: self.interrupts_enabled,
+            model: self.model,
+            psw_pop: self.psw_pop,
+            trace: self.trace,
+            symbols: self.symbols,
+            contention: self.contention,
+        }
+    }
+
+    /// Where the CPU starts running.
+    pub fn pc(mut self, pc: u16) -> Self {
+        self.pc = pc;
+        self
+    }
+
+    pub fn sp(mut self, sp: u16) -> Self {
+        self.sp = sp;
+        self
+    }
+
+    /// Preloads the registers and flags.
+    pub fn regs(mut self, regs: Registers) -> Self {
+        self.regs = regs;
+        self
+    }
+
+    pub fn interrupts_enabled(mut self, enabled: bool) -> Self {
+        self.interrupts_enabled = enabled;
+        self
+    }
+
+    pub fn model(mut self, model: CpuModel) -> Self {
+        self.model = model;
+        self
+    }
+
+    pub fn psw_pop(mut self, mode: PswPop) -> Self {
+        self.psw_pop = mode;
+        self
+    }
+
+    /// See `CPU::set_trace_hook`.
+    pub fn trace(mut self, hook: impl FnMut(&TraceRecord) + 'static) -> Self {
+        self.trace = Some(Box::new(hook));
+        self
+    }
+
+    /// See `CPU::set_symbols`.
+    pub fn symbols(mut self, symbols: SymbolTable) -> Self {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    /// See `CPU::set_contention`.
+    pub fn contention(mut self, contention: VideoContention) -> Self {
+        self.contention = Some(contention);
+        self
+    }
+
+    pub fn build(self) -> CPU<M> {
+        let mut cpu = CPU::new(self.memory);
+        cpu.regs = self.regs;
+        cpu.pc = self.pc;
+        cpu.sp = self.sp;
+        cpu.inter = self.interrupts_enabled;
+        cpu.model = self.model;
+        cpu.psw_pop = self.psw_pop;
+        cpu.trace = self.trace;
+        cpu.contention = self.contention;
+        if let Some(symbols) = self.symbols {
+            cpu.set_symbols(symbols);
+        }
+        cpu
+    }
 }
 
-impl CPU {
-    pub fn new(memory: Rc<RefCell<Memory8080>>) -> Self {
+impl<M: Memory> CPU<M> {
+    pub fn new(memory: Rc<RefCell<M>>) -> Self {
         CPU {
             regs: Registers::new(),
             memory,
             pc: 0,
             sp: 0x0000, // 0xf000,
             inter: false,
+            halted: false,
+            cycles: 0,
+            mid_instruction: false,
+            fault: None,
+            psw_pop: PswPop::Hardware,
+            model: CpuModel::I8080,
+            lines: i8085::Lines::default(),
             disassembler: Disassembler::new(),
+            trace: None,
+            contention: None,
+            traps: BTreeMap::new(),
         }
     }
 
+    /// Accepts an interrupt vectoring to `addr` if interrupts are enabled.
+    /// Only call this between instructions, after the previous
+    /// instruction's event has been delivered. Interrupts are never
+    /// recognized between `fetch` and `exec`, while PC still points into
+    /// the instruction's operand bytes.
     pub fn inter_handle(&mut self, addr: u16) -> Option<Event> {
-        if self.inter {
+        if self.inter && !self.mid_instruction {
             self.inter = false;
+            self.halted = false;
             self.push(self.pc);
             self.pc = addr;
+            self.cycles = self.cycles.wrapping_add(17);
             return Some(Event::Normal(17));
         }
         None
     }
 
+    /// Accepts an interrupt if interrupts are enabled, executing `op` as
+    /// the instruction the interrupting device placed on the data bus
+    /// during the acknowledge cycle, usually an RST. Returns the event of
+    /// that instruction, with the cycles it took, or `None` if the
+    /// interrupt was not accepted.
+    ///
+    /// Only single-byte instructions can be supplied; the operands of a
+    /// longer one would be read from memory at PC. A halted CPU has PC
+    /// past the HLT already, so an RST returns to the instruction after it.
+    /// The same boundary rules as `inter_handle` apply.
+    pub fn interrupt(&mut self, op: u8) -> Option<Event> {
+        debug_assert_eq!(OPCODES[usize::from(op)].length, 1, "interrupt opcodes are single bytes");
+        if self.inter && !self.mid_instruction {
+            self.inter = false;
+            self.halted = false;
+            return Some(self.exec_inline(op));
+        }
+        None
+    }
+
+    /// Accepts an interrupt as RST `n` if interrupts are enabled, which is
+    /// what most 8080 boards put on the data bus. Same as `interrupt` with
+    /// the opcode of RST `n`, which goes to `n * 8`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is more than 7.
+    pub fn raise_rst(&mut self, n: u8) -> Option<Event> {
+        assert!(n < 8, "there is no RST {}", n);
+        self.interrupt(0xc7 | n << 3)
+    }
+
+    /// Resets the CPU as the RESET input does: PC goes to 0x0000,
+    /// interrupts are disabled and a halted CPU starts running again. The
+    /// other registers keep their values, and the cycle count keeps
+    /// counting.
+    pub fn reset(&mut self) {
+        self.pc = 0x0000;
+        self.inter = false;
+        self.halted = false;
+        self.mid_instruction = false;
+        self.fault = None;
+        self.lines = i8085::Lines::default();
+    }
+
+    pub fn model(&self) -> CpuModel {
+        self.model
+    }
+
+    /// Makes the CPU behave as an 8080 or an 8085 from the next
+    /// instruction on.
+    pub fn set_model(&mut self, model: CpuModel) {
+        self.model = model;
+    }
+
+    /// Sets the level of one of the 8085's interrupt inputs. See
+    /// `service_interrupt_lines`.
+    pub fn set_interrupt_line(&mut self, line: InterruptLine, high: bool) {
+        self.lines.set(line, high);
+    }
+
+    /// Accepts the highest priority request on the 8085's interrupt lines
+    /// that is not masked: a TRAP always, the others only with interrupts
+    /// enabled. Returns `None` if there is none or the CPU is an 8080.
+    /// The same boundary rules as `inter_handle` apply.
+    pub fn service_interrupt_lines(&mut self) -> Option<Event> {
+        if self.model != CpuModel::I8085 || self.mid_instruction {
+            return None;
+        }
+        let line = self.lines.pending(self.inter)?;
+        self.lines.accept(line);
+        self.inter = false;
+        self.halted = false;
+        self.push(self.pc);
+        self.pc = line.vector();
+        self.cycles = self.cycles.wrapping_add(Timestamp::from(i8085::INTERRUPT_CYCLES));
+        Some(Event::Normal(i8085::INTERRUPT_CYCLES))
+    }
+
+    /// Sets the 8085's serial input pin, read by RIM.
+    pub fn set_sid(&mut self, high: bool) {
+        self.lines.sid = high;
+    }
+
+    /// The 8085's serial output pin, as last set by SIM.
+    pub fn sod(&self) -> bool {
+        self.lines.sod
+    }
+
+    // Whether the condition in bits 3-5 of a conditional jump, call or
+    // return holds
+    fn condition(&self, op: u8) -> bool {
+        let flag = |f| self.regs.get_flag(f);
+        let y = (op >> 3) & 0x07;
+        // NZ, Z, NC, C, PO, PE, P, M
+        let set = match y >> 1 {
+            0 => flag(Flag::Z),
+            1 => flag(Flag::C),
+            2 => flag(Flag::P),
+            _ => flag(Flag::S),
+        };
+        set == (y & 1 == 1)
+    }
+
+    /// Whether the CPU stands between two instructions, as it always does
+    /// outside `step` unless `fetch` was called without the matching
+    /// `exec`. Interrupts and snapshots wait for a boundary.
+    pub fn at_boundary(&self) -> bool {
+        !self.mid_instruction
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    /// Presets the stack pointer, as LXI SP would.
+    pub fn set_sp(&mut self, sp: u16) {
+        self.sp = sp;
+    }
+
+    /// Whether the interrupt enable flip-flop is set. The whole register
+    /// set, this included, is in `I8080Core::state`.
+    pub fn interrupts_enabled(&self) -> bool {
+        self.inter
+    }
+
+    /// Sets or clears the interrupt enable flip-flop, as EI and DI do.
+    pub fn set_interrupts_enabled(&mut self, enabled: bool) {
+        self.inter = enabled;
+    }
+
+    /// Whether HLT has stopped the CPU. It stays halted until an interrupt
+    /// is accepted.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Clock cycles executed since the CPU was created, counting accepted
+    /// interrupts and time spent halted.
+    pub fn cycles(&self) -> Timestamp {
+        self.cycles
+    }
+
+    /// Counts `cycles` that passed without an instruction running, such as
+    /// a skipped delay loop or a DMA transfer holding the CPU off the bus.
+    pub fn advance_cycles(&mut self, cycles: ClockCycles) {
+        self.cycles = self.cycles.wrapping_add(Timestamp::from(cycles));
+    }
+
+    // Winds the cycle count back, for stepping backwards
+    #[cfg(feature = "std")]
+    pub(crate) fn set_cycles(&mut self, cycles: Timestamp) {
+        self.cycles = cycles;
+    }
+
+    /// Delivers the byte read by the IN instruction that just returned
+    /// `Event::Input`, the way the data bus would: it lands in A and no
+    /// flags change.
+    pub fn complete_input(&mut self, value: u8) {
+        self.regs.a = value;
+    }
+
+    /// Calls `hook` with a `TraceRecord` for every instruction fetched,
+    /// replacing any hook set before. The record is only built while a
+    /// hook is set, so tracing costs nothing when it is off.
+    pub fn set_trace_hook(&mut self, hook: impl FnMut(&TraceRecord) + 'static) {
+        self.trace = Some(Box::new(hook));
+    }
+
+    pub fn clear_trace_hook(&mut self) {
+        self.trace = None;
+    }
+
+    /// Runs `handler` whenever PC reaches `addr`, in place of whatever is
+    /// in memory there, then returns to the caller as RET does, taking as
+    /// many cycles. The handler gets the registers and memory as the guest
+    /// called it; a value it returns is handed back in A. Replaces any
+    /// handler set at `addr` before.
+    ///
+    /// ```
+    /// use i8080_emulator::cpu::CPU;
+    /// use i8080_emulator::memory::{Memory, Memory8080};
+    /// use std::cell::RefCell;
+    /// use std::rc::Rc;
+    ///
+    /// // CALL 0x0005, with a trap standing in for the BDOS there
+    /// let mut memory = Memory8080::new_empty();
+    /// memory.write(0x0100, 0xcd);
+    /// memory.write16(0x0101, 0x0005);
+    /// let mut cpu = CPU::new(Rc::new(RefCell::new(memory)));
+    /// cpu.pc = 0x0100;
+    /// cpu.set_sp(0x2000);
+    /// cpu.add_trap(0x0005, |regs, _| Some(regs.c + 1));
+    /// cpu.regs.c = 0x0b;
+    /// cpu.step();
+    /// cpu.step();
+    /// assert_eq!((cpu.pc, cpu.regs.a), (0x0103, 0x0c));
+    /// ```
+    pub fn add_trap(&mut self, addr: u16, mut handler: impl FnMut(&mut Registers, &mut M) -> Option<u8> + 'static) {
+        self.set_trap(addr, Box::new(move |cpu: &mut CPU<M>| {
+            if let Some(a) = handler(&mut cpu.regs, &mut *cpu.memory.borrow_mut()) {
+                cpu.regs.a = a;
+            }
+        }));
+    }
+
+    // A trap whose handler gets the whole CPU, for `HostCalls::install`
+    pub(crate) fn set_trap(&mut self, addr: u16, handler: TrapHandler<M>) {
+        self.traps.insert(addr, handler);
+    }
+
+    pub fn remove_trap(&mut self, addr: u16) {
+        self.traps.remove(&addr);
+    }
+
+    // Runs the trap handler at PC, if there is one, and returns whether
+    // it ran
+    fn run_trap(&mut self) -> bool {
+        if self.traps.is_empty() {
+            return false;
+        }
+        let pc = self.pc;
+        match self.traps.remove(&pc) {
+            Some(mut handler) => {
+                handler(self);
+                // Unless the handler set another trap in its place
+                self.traps.entry(pc).or_insert(handler);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Names addresses in the trace hook's disassembly and gives each
+    /// `TraceRecord` the symbol its PC falls under.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.disassembler.set_symbols(symbols);
+    }
+
+    /// Selects how POP PSW restores the flags. Defaults to
+    /// `PswPop::Hardware`.
+    pub fn set_psw_pop(&mut self, mode: PswPop) {
+        self.psw_pop = mode;
+    }
+
     fn get_m(&self) -> u8 {
         self.memory.borrow().read(self.regs.get_hl().into())
     }
 
+    /// Executes one instruction and returns its event together with the
+    /// cycles it took.
+    ///
+    /// The instruction is atomic to anything watching the CPU: the trace
+    /// hook sees the state from before it and callers see the state after
+    /// it, never registers it has only partly updated. Only a caller
+    /// driving `fetch` and `exec` by hand can stop in between, where
+    /// `at_boundary` is false.
+    pub fn step(&mut self) -> (Event, ClockCycles) {
+        let op = self.fetch();
+        let event = self.exec(op);
+        let cycles = event.cycles();
+        (event, cycles)
+    }
+
+    /// `step`, also reporting where and when the instruction ran. A
+    /// halted CPU reports a NOP that leaves PC where it is.
+    pub fn step_result(&mut self) -> ExecResult {
+        let pc_before = self.pc;
+        let start = self.cycles;
+        let opcode = self.fetch();
+        let event = self.exec(opcode);
+        ExecResult { event, cycles: event.cycles(), start, opcode, pc_before, pc_after: self.pc }
+    }
+
+    /// Executes instructions until at least `cycles` clock cycles have
+    /// passed and returns how many did, which can be a few more than asked
+    /// for. A halted CPU burns the time waiting for an interrupt.
+    ///
+    /// Port accesses are not resolved: IN leaves A alone and OUT goes
+    /// nowhere. Use `PortBus::run_for` for a machine with devices. A fault
+    /// stops the run early; the next `step` reports it.
+    pub fn run_for(&mut self, cycles: Timestamp) -> Timestamp {
+        let mut elapsed = 0;
+        while elapsed < cycles {
+            match self.step() {
+                (Event::Fault(_), _) => break,
+                (_, taken) => elapsed += Timestamp::from(taken),
+            }
+        }
+        elapsed
+    }
+
+    /// Executes up to `n` instructions in a tight loop, only returning early
+    /// when an instruction produces an event the caller has to act on (IN,
+    /// OUT or HLT). Plain instructions are accumulated into the result.
+    pub fn step_n(&mut self, n: usize) -> BatchResult {
+        let mut result = BatchResult { steps: 0, cycles: 0, event: None };
+        while result.steps < n {
+            let op = self.fetch();
+            let event = self.exec(op);
+            result.steps += 1;
+            result.cycles += Timestamp::from(event.cycles());
+            if let Event::Normal(_) = event {
+                continue;
+            }
+            result.event = Some(event);
+            break;
+        }
+        result
+    }
+
     fn set_m(&mut self, data: u8) {
         self.memory.borrow_mut().write(self.regs.get_hl().into(), data);
     }
@@ -63,112 +950,50 @@ impl CPU {
         self.memory.borrow_mut().write(addr, self.regs.a);
     }
 
-    // Arithmetic instructions
+    // Arithmetic and bitwise instructions
     fn inr(&mut self, n: u8) -> u8 {
-        let r = n.wrapping_add(1);
-        self.regs.set_flag(Flag::S, (r & 0x80) == 0x80);
-        self.regs.set_flag(Flag::Z, r == 0x00);
-        self.regs.set_flag(Flag::A, (n & 0x0f) + 1 > 0x0f);
-        self.regs.set_flag(Flag::P, r.count_ones() & 0x01 == 0x00);
+        let (r, f) = alu::inr(n, self.regs.f);
+        self.regs.f = f;
         r
     }
 
     fn dcr(&mut self, n: u8) -> u8 {
-        let r = n.wrapping_sub(1);
-        self.regs.set_flag(Flag::S, (r & 0x80) == 0x80);
-        self.regs.set_flag(Flag::Z, r == 0x00);
-        self.regs.set_flag(Flag::A, (r & 0x0f) != 0x0f);
-        self.regs.set_flag(Flag::P, r.count_ones() & 0x01 == 0x00);
+        let (r, f) = alu::dcr(n, self.regs.f);
+        self.regs.f = f;
         r
     }
 
-    fn add(&mut self, regm1: u8, regm2: u8) -> u8 {
-        let n = regm1.wrapping_add(regm2);
-        self.regs.set_flag(Flag::S, (n & 0x80) == 0x80);
-        self.regs.set_flag(Flag::Z, n == 0x00);
-        self.regs.set_flag(Flag::A, (regm1 & 0x0f) + (regm2 & 0x0f) > 0x0f);
-        self.regs.set_flag(Flag::P, n.count_ones() & 0x01 == 0x00);
-        self.regs.set_flag(Flag::C, u16::from(regm1) + u16::from(regm2) > 0xff);
-        n
-    }
-
-    fn adc(&mut self, regm1: u8, regm2: u8) -> u8 {
-        let carry = if self.regs.get_flag(Flag::C) { 1 } else { 0 };
-
-        let n = regm1.wrapping_add(regm2).wrapping_add(carry);
-        self.regs.set_flag(Flag::S, (n & 0x80) == 0x80);
-        self.regs.set_flag(Flag::Z, n == 0x00);
-        self.regs.set_flag(Flag::A, (regm1 & 0x0f) + (regm2 & 0x0f) + carry > 0x0f);
-        self.regs.set_flag(Flag::P, n.count_ones() & 0x01 == 0x00);
-        self.regs.set_flag(Flag::C, u16::from(regm1) + u16::from(regm2) + u16::from(carry) > 0xff);
-        n
-    }
-
-    fn sub(&mut self, regm1: u8, regm2: u8) -> u8 {
-        let n = regm1.wrapping_sub(regm2);
-        self.regs.set_flag(Flag::S, (n & 0x80) == 0x80);
-        self.regs.set_flag(Flag::Z, n == 0x00);
-        self.regs.set_flag(Flag::A, (regm1 as i8 & 0x0f) - (regm2 as i8 & 0x0f) >= 0x00);
-        self.regs.set_flag(Flag::P, n.count_ones() & 0x01 == 0x00);
-        self.regs.set_flag(Flag::C, u16::from(regm1) < u16::from(regm2));
-        n
-    }
-
-    fn sbb(&mut self, regm1: u8, regm2: u8) -> u8 {
-        let carry = if self.regs.get_flag(Flag::C) { 1 } else { 0 };
-
-        let n = regm1.wrapping_sub(regm2).wrapping_sub(carry);
-        self.regs.set_flag(Flag::S, (n & 0x80) == 0x80);
-        self.regs.set_flag(Flag::Z, n == 0x00);
-        self.regs.set_flag(Flag::A, (regm1 as i8 & 0x0f) - (regm2 as i8 & 0x0f) - (carry as i8 & 0x0f) >= 0x00);
-        self.regs.set_flag(Flag::P, n.count_ones() & 0x01 == 0x00);
-        self.regs.set_flag(Flag::C, u16::from(regm1) < u16::from(regm2) + u16::from(carry));
-        n
-    }
-
-    // Bitwise instructions
-    fn ana(&mut self, regm1: u8, regm2: u8) -> u8 {
-        let n = regm1 & regm2;
-        self.regs.set_flag(Flag::S, (n & 0x80) == 0x80);
-        self.regs.set_flag(Flag::Z, n == 0x00);
-        self.regs.set_flag(Flag::A, ((regm1 | regm2) & 0x08) != 0);
-        self.regs.set_flag(Flag::P, n.count_ones() & 0x01 == 0x00);
-        self.regs.set_flag(Flag::C, false);
-        n
+    // Applies a two operand ALU operation to the accumulator
+    fn alu(&mut self, op: fn(u8, u8, u8) -> (u8, u8), n: u8) {
+        let (a, f) = op(self.regs.a, n, self.regs.f);
+        self.regs.a = a;
+        self.regs.f = f;
     }
 
-    fn xra(&mut self, regm1: u8, regm2: u8) -> u8 {
-        let n = regm1 ^ regm2;
-        self.regs.set_flag(Flag::S, (n & 0x80) == 0x80);
-        self.regs.set_flag(Flag::Z, n == 0x00);
-        self.regs.set_flag(Flag::A, false);
-        self.regs.set_flag(Flag::P, n.count_ones() & 0x01 == 0x00);
-        self.regs.set_flag(Flag::C, false);
-        n
+    // Applies a single operand ALU operation (rotates, DAA) to the accumulator
+    fn alu_a(&mut self, op: fn(u8, u8) -> (u8, u8)) {
+        let (a, f) = op(self.regs.a, self.regs.f);
+        self.regs.a = a;
+        self.regs.f = f;
     }
 
-    fn ora(&mut self, regm1: u8, regm2: u8) -> u8 {
-        let n = regm1 | regm2;
-        self.regs.set_flag(Flag::S, (n & 0x80) == 0x80);
-        self.regs.set_flag(Flag::Z, n == 0x00);
-        self.regs.set_flag(Flag::A, false);
-        self.regs.set_flag(Flag::P, n.count_ones() & 0x01 == 0x00);
-        self.regs.set_flag(Flag::C, false);
-        n
-    }
-
-    fn cmp(&mut self, regm1: u8, regm2: u8) {
-        self.sub(regm1, regm2);
+    fn dad(&mut self, n: u16) {
+        let (hl, f) = alu::dad(self.regs.get_hl(), n, self.regs.f);
+        self.regs.set_hl(hl);
+        self.regs.f = f;
     }
 
     // Jump instructions
-    fn jmp(&mut self, cond: bool) {
+    // Jumps take 10 cycles whether or not they are taken, since the
+    // address is read either way.
+    fn jmp(&mut self, cond: bool) -> Event {
         if cond {
             let addr = self.memory.borrow().read16(self.pc.into());
             self.pc = addr;
         } else {
             self.pc = self.pc.wrapping_add(2);
         }
+        Event::Normal(10)
     }
 
     fn call(&mut self, cond: bool) -> Event {
@@ -184,10 +1009,16 @@ impl CPU {
         }
     }
 
-    fn ret(&mut self, cond: bool) -> Event {
+    fn ret(&mut self) -> Event {
+        self.pc = self.pop();
+        Event::Normal(10)
+    }
+
+    // Conditional returns spend an extra cycle testing the condition,
+    // making a taken one slower than a plain RET.
+    fn ret_if(&mut self, cond: bool) -> Event {
         if cond {
             self.pc = self.pop();
-
             Event::Normal(11)
         } else {
             Event::Normal(5)
@@ -206,34 +1037,100 @@ impl CPU {
     }
 
     fn rst(&mut self, addr: u16) {
-        self.memory.borrow_mut().write16(self.sp.wrapping_add(2).into(),
-                            self.pc);
-        self.pc = 0x0000 | addr;
+        self.push(self.pc);
+        self.pc = addr;
     }
 }
 
-impl Device<Event> for CPU {
-    fn fetch(&mut self) -> u8 {
-        let op = self.memory.borrow().read(self.pc.into());
-        // let code = self.disassembler.disassemble(&self.memory, &self.pc, &op, &self.regs.get_hl());
-        // println!("{: <25}     pc: {:04x}, sp: {:04x}, a: {:02x}, b: {:02x}, c: {:02x}, d: {:02x}, e: {:02x}, h: {:02x}, l: {:02x}, f: {:02x}", 
-                 // code,
-                 // self.pc,
-                 // self.sp,
-                 // self.regs.a,
-                 // self.regs.b,
-                 // self.regs.c,
-                 // self.regs.d,
-                 // self.regs.e,
-                 // self.regs.h,
-                 // self.regs.l,
-                 // self.regs.f);
+impl<M: Memory> CPU<M> {
+    /// Reads the opcode at PC and advances PC past it. If PC is in a
+    /// non-executable region, PC stays put and the following `exec`
+    /// reports the fault instead of executing anything. A halted CPU
+    /// fetches nothing. At a trap address the handler runs and the
+    /// opcode returned is RET.
+    pub fn fetch(&mut self) -> u8 {
+        if self.halted {
+            return 0x00;
+        }
+        if self.run_trap() {
+            // RET, which takes PC from the stack
+            self.mid_instruction = true;
+            return 0xc9;
+        }
+        let memory = self.memory.borrow();
+        if !memory.is_executable(self.pc) {
+            self.fault = Some(Fault::NoExecute(self.pc));
+            return 0x00;
+        }
+        memory.note_fetch(self.pc);
+        let op = memory.read(self.pc.into());
+        if self.trace.is_some() {
+            let record = TraceRecord {
+                pc: self.pc,
+                opcode: op,
+                disassembly: self.disassembler.disassemble(&Peek(&*memory), &self.pc, &op, &self.regs.get_hl()),
+                symbol: self.disassembler.symbols().and_then(|symbols| symbols.describe(self.pc)),
+                state: self.state(),
+                branch: self.branch(&*memory, op),
+            };
+            drop(memory);
+            if let Some(hook) = &mut self.trace {
+                hook(&record);
+            }
+        } else {
+            drop(memory);
+        }
         self.pc = self.pc.wrapping_add(1);
+        self.mid_instruction = true;
         op
     }
 
-    fn exec(&mut self, op: u8) -> Event {
-        match op {
+    // Where the instruction `op` at PC would branch to. Peeks at memory so
+    // that guards, watchpoints and devices are not set off by bytes the
+    // instruction itself may not touch.
+    fn branch(&self, memory: &M, op: u8) -> Option<Branch> {
+        let byte = |addr: u16| memory.peek(usize::from(addr));
+        let word = |addr: u16| u16::from_le_bytes([byte(addr), byte(addr.wrapping_add(1))]);
+        let operand = word(self.pc.wrapping_add(1));
+        let condition = self.condition(op);
+        let (target, conditional) = match op {
+            0xc3 | 0xcb | 0xcd | 0xdd | 0xed | 0xfd => (operand, false),
+            0xc9 | 0xd9 => (word(self.sp), false),
+            0xe9 => (self.regs.get_hl(), false),
+            _ => match op & 0xc7 {
+                0xc2 | 0xc4 => (operand, true),
+                0xc0 => (word(self.sp), true),
+                0xc7 => (u16::from(op & 0x38), false),
+                _ => return None,
+            },
+        };
+        Some(Branch { target, conditional, taken: !conditional || condition })
+    }
+
+    /// Executes `op`, reading any operands from memory at PC.
+    pub fn exec(&mut self, op: u8) -> Event {
+        self.exec_inline(op)
+    }
+
+    // The body of `exec`, always inlined so the threaded backend can
+    // specialize it for a constant opcode.
+    #[inline(always)]
+    pub(crate) fn exec_inline(&mut self, op: u8) -> Event {
+        self.mid_instruction = false;
+        if let Some(fault) = self.fault.take() {
+            return Event::Fault(fault);
+        }
+        if self.halted {
+            self.cycles = self.cycles.wrapping_add(4);
+            return Event::Normal(4);
+        }
+        let i8085 = self.model == CpuModel::I8085;
+        let jump_taken = i8085 && op & 0xc7 == 0xc2 && self.condition(op);
+        let event = match op {
+            // RIM, SIM
+            0x20 if i8085 => { self.regs.a = self.lines.rim(self.inter); Event::Normal(4) }
+            0x30 if i8085 => { self.lines.sim(self.regs.a); Event::Normal(4) }
+
             // NOP
             0x00 => Event::Normal(4),
             0x10 => Event::Normal(4),
@@ -247,25 +1144,25 @@ impl Device<Event> for CPU {
             // LXI
             0x01 => { 
                 let data = self.memory.borrow().read16(self.pc.into());
-                self.pc += 2;
+                self.pc = self.pc.wrapping_add(2);
                 self.regs.set_bc(data);
                 Event::Normal(10)
             }
             0x11 => {
                 let data = self.memory.borrow().read16(self.pc.into());
-                self.pc += 2;
+                self.pc = self.pc.wrapping_add(2);
                 self.regs.set_de(data);
                 Event::Normal(10)
             }
             0x21 => {
                 let data = self.memory.borrow().read16(self.pc.into());
-                self.pc += 2;
+                self.pc = self.pc.wrapping_add(2);
                 self.regs.set_hl(data);
                 Event::Normal(10)
             }
             0x31 => {
                 let data = self.memory.borrow().read16(self.pc.into());
-                self.pc += 2;
+                self.pc = self.pc.wrapping_add(2);
                 self.sp = data;
                 Event::Normal(10)
             }
@@ -275,10 +1172,10 @@ impl Device<Event> for CPU {
             0x12 => { self.stax(self.regs.get_de()); Event::Normal(7) },
 
             // INX
-            0x03 => { self.regs.set_bc(self.regs.get_bc() + 1); Event::Normal(5) }
-            0x13 => { self.regs.set_de(self.regs.get_de() + 1); Event::Normal(5) }
-            0x23 => { self.regs.set_hl(self.regs.get_hl() + 1); Event::Normal(5) }
-            0x33 => { self.sp += 1; Event::Normal(5) }
+            0x03 => { self.regs.set_bc(self.regs.get_bc().wrapping_add(1)); Event::Normal(5) }
+            0x13 => { self.regs.set_de(self.regs.get_de().wrapping_add(1)); Event::Normal(5) }
+            0x23 => { self.regs.set_hl(self.regs.get_hl().wrapping_add(1)); Event::Normal(5) }
+            0x33 => { self.sp = self.sp.wrapping_add(1); Event::Normal(5) }
 
             // INR
             0x04 => { self.regs.b = self.inr(self.regs.b); Event::Normal(5) }
@@ -309,96 +1206,96 @@ impl Device<Event> for CPU {
             0x3d => { self.regs.a = self.dcr(self.regs.a); Event::Normal(5) }
 
             // DCX
-            0x0b => { self.regs.set_bc(self.regs.get_bc() - 1); Event::Normal(5) }
-            0x1b => { self.regs.set_de(self.regs.get_de() - 1); Event::Normal(5) }
-            0x2b => { self.regs.set_hl(self.regs.get_hl() - 1); Event::Normal(5) }
-            0x3b => { self.sp -= 1; Event::Normal(5) }
+            0x0b => { self.regs.set_bc(self.regs.get_bc().wrapping_sub(1)); Event::Normal(5) }
+            0x1b => { self.regs.set_de(self.regs.get_de().wrapping_sub(1)); Event::Normal(5) }
+            0x2b => { self.regs.set_hl(self.regs.get_hl().wrapping_sub(1)); Event::Normal(5) }
+            0x3b => { self.sp = self.sp.wrapping_sub(1); Event::Normal(5) }
 
             // ADD
-            0x80 => { self.regs.a = self.add(self.regs.a, self.regs.b); Event::Normal(4) }
-            0x81 => { self.regs.a = self.add(self.regs.a, self.regs.c); Event::Normal(4) }
-            0x82 => { self.regs.a = self.add(self.regs.a, self.regs.d); Event::Normal(4) }
-            0x83 => { self.regs.a = self.add(self.regs.a, self.regs.e); Event::Normal(4) }
-            0x84 => { self.regs.a = self.add(self.regs.a, self.regs.h); Event::Normal(4) }
-            0x85 => { self.regs.a = self.add(self.regs.a, self.regs.l); Event::Normal(4) }
-            0x86 => { self.regs.a = self.add(self.regs.a, self.get_m()); Event::Normal(7) }
-            0x87 => { self.regs.a = self.add(self.regs.a, self.regs.a); Event::Normal(4) }
+            0x80 => { self.alu(alu::add, self.regs.b); Event::Normal(4) }
+            0x81 => { self.alu(alu::add, self.regs.c); Event::Normal(4) }
+            0x82 => { self.alu(alu::add, self.regs.d); Event::Normal(4) }
+            0x83 => { self.alu(alu::add, self.regs.e); Event::Normal(4) }
+            0x84 => { self.alu(alu::add, self.regs.h); Event::Normal(4) }
+            0x85 => { self.alu(alu::add, self.regs.l); Event::Normal(4) }
+            0x86 => { self.alu(alu::add, self.get_m()); Event::Normal(7) }
+            0x87 => { self.alu(alu::add, self.regs.a); Event::Normal(4) }
 
             // SUB
-            0x90 => { self.regs.a = self.sub(self.regs.a, self.regs.b); Event::Normal(4) }
-            0x91 => { self.regs.a = self.sub(self.regs.a, self.regs.c); Event::Normal(4) }
-            0x92 => { self.regs.a = self.sub(self.regs.a, self.regs.d); Event::Normal(4) }
-            0x93 => { self.regs.a = self.sub(self.regs.a, self.regs.e); Event::Normal(4) }
-            0x94 => { self.regs.a = self.sub(self.regs.a, self.regs.h); Event::Normal(4) }
-            0x95 => { self.regs.a = self.sub(self.regs.a, self.regs.l); Event::Normal(4) }
-            0x96 => { self.regs.a = self.sub(self.regs.a, self.get_m()); Event::Normal(7) }
-            0x97 => { self.regs.a = self.sub(self.regs.a, self.regs.a); Event::Normal(4) }
+            0x90 => { self.alu(alu::sub, self.regs.b); Event::Normal(4) }
+            0x91 => { self.alu(alu::sub, self.regs.c); Event::Normal(4) }
+            0x92 => { self.alu(alu::sub, self.regs.d); Event::Normal(4) }
+            0x93 => { self.alu(alu::sub, self.regs.e); Event::Normal(4) }
+            0x94 => { self.alu(alu::sub, self.regs.h); Event::Normal(4) }
+            0x95 => { self.alu(alu::sub, self.regs.l); Event::Normal(4) }
+            0x96 => { self.alu(alu::sub, self.get_m()); Event::Normal(7) }
+            0x97 => { self.alu(alu::sub, self.regs.a); Event::Normal(4) }
 
             // ADC
-            0x88 => { self.regs.a = self.adc(self.regs.a, self.regs.b); Event::Normal(4) }
-            0x89 => { self.regs.a = self.adc(self.regs.a, self.regs.c); Event::Normal(4) }
-            0x8a => { self.regs.a = self.adc(self.regs.a, self.regs.d); Event::Normal(4) }
-            0x8b => { self.regs.a = self.adc(self.regs.a, self.regs.e); Event::Normal(4) }
-            0x8c => { self.regs.a = self.adc(self.regs.a, self.regs.h); Event::Normal(4) }
-            0x8d => { self.regs.a = self.adc(self.regs.a, self.regs.l); Event::Normal(4) }
-            0x8e => { self.regs.a = self.adc(self.regs.a, self.get_m()); Event::Normal(7) }
-            0x8f => { self.regs.a = self.adc(self.regs.a, self.regs.a); Event::Normal(4) }
+            0x88 => { self.alu(alu::adc, self.regs.b); Event::Normal(4) }
+            0x89 => { self.alu(alu::adc, self.regs.c); Event::Normal(4) }
+            0x8a => { self.alu(alu::adc, self.regs.d); Event::Normal(4) }
+            0x8b => { self.alu(alu::adc, self.regs.e); Event::Normal(4) }
+            0x8c => { self.alu(alu::adc, self.regs.h); Event::Normal(4) }
+            0x8d => { self.alu(alu::adc, self.regs.l); Event::Normal(4) }
+            0x8e => { self.alu(alu::adc, self.get_m()); Event::Normal(7) }
+            0x8f => { self.alu(alu::adc, self.regs.a); Event::Normal(4) }
 
             // SBB
-            0x98 => { self.regs.a = self.sbb(self.regs.a, self.regs.b); Event::Normal(4) }
-            0x99 => { self.regs.a = self.sbb(self.regs.a, self.regs.c); Event::Normal(4) }
-            0x9a => { self.regs.a = self.sbb(self.regs.a, self.regs.d); Event::Normal(4) }
-            0x9b => { self.regs.a = self.sbb(self.regs.a, self.regs.e); Event::Normal(4) }
-            0x9c => { self.regs.a = self.sbb(self.regs.a, self.regs.h); Event::Normal(4) }
-            0x9d => { self.regs.a = self.sbb(self.regs.a, self.regs.l); Event::Normal(4) }
-            0x9e => { self.regs.a = self.sbb(self.regs.a, self.get_m()); Event::Normal(7) }
-            0x9f => { self.regs.a = self.sbb(self.regs.a, self.regs.a); Event::Normal(4) }
+            0x98 => { self.alu(alu::sbb, self.regs.b); Event::Normal(4) }
+            0x99 => { self.alu(alu::sbb, self.regs.c); Event::Normal(4) }
+            0x9a => { self.alu(alu::sbb, self.regs.d); Event::Normal(4) }
+            0x9b => { self.alu(alu::sbb, self.regs.e); Event::Normal(4) }
+            0x9c => { self.alu(alu::sbb, self.regs.h); Event::Normal(4) }
+            0x9d => { self.alu(alu::sbb, self.regs.l); Event::Normal(4) }
+            0x9e => { self.alu(alu::sbb, self.get_m()); Event::Normal(7) }
+            0x9f => { self.alu(alu::sbb, self.regs.a); Event::Normal(4) }
 
             // ANA
-            0xa0 => { self.regs.a = self.ana(self.regs.a, self.regs.b); Event::Normal(4) }
-            0xa1 => { self.regs.a = self.ana(self.regs.a, self.regs.c); Event::Normal(4) }
-            0xa2 => { self.regs.a = self.ana(self.regs.a, self.regs.d); Event::Normal(4) }
-            0xa3 => { self.regs.a = self.ana(self.regs.a, self.regs.e); Event::Normal(4) }
-            0xa4 => { self.regs.a = self.ana(self.regs.a, self.regs.h); Event::Normal(4) }
-            0xa5 => { self.regs.a = self.ana(self.regs.a, self.regs.l); Event::Normal(4) }
-            0xa6 => { self.regs.a = self.ana(self.regs.a, self.get_m()); Event::Normal(7) }
-            0xa7 => { self.regs.a = self.ana(self.regs.a, self.regs.a); Event::Normal(4) }
+            0xa0 => { self.alu(alu::ana, self.regs.b); Event::Normal(4) }
+            0xa1 => { self.alu(alu::ana, self.regs.c); Event::Normal(4) }
+            0xa2 => { self.alu(alu::ana, self.regs.d); Event::Normal(4) }
+            0xa3 => { self.alu(alu::ana, self.regs.e); Event::Normal(4) }
+            0xa4 => { self.alu(alu::ana, self.regs.h); Event::Normal(4) }
+            0xa5 => { self.alu(alu::ana, self.regs.l); Event::Normal(4) }
+            0xa6 => { self.alu(alu::ana, self.get_m()); Event::Normal(7) }
+            0xa7 => { self.alu(alu::ana, self.regs.a); Event::Normal(4) }
 
             // XRA
-            0xa8 => { self.regs.a = self.xra(self.regs.a, self.regs.b); Event::Normal(4) }
-            0xa9 => { self.regs.a = self.xra(self.regs.a, self.regs.c); Event::Normal(4) }
-            0xaa => { self.regs.a = self.xra(self.regs.a, self.regs.d); Event::Normal(4) }
-            0xab => { self.regs.a = self.xra(self.regs.a, self.regs.e); Event::Normal(4) }
-            0xac => { self.regs.a = self.xra(self.regs.a, self.regs.h); Event::Normal(4) }
-            0xad => { self.regs.a = self.xra(self.regs.a, self.regs.l); Event::Normal(4) }
-            0xae => { self.regs.a = self.xra(self.regs.a, self.get_m()); Event::Normal(7) }
-            0xaf => { self.regs.a = self.xra(self.regs.a, self.regs.a); Event::Normal(4) }
+            0xa8 => { self.alu(alu::xra, self.regs.b); Event::Normal(4) }
+            0xa9 => { self.alu(alu::xra, self.regs.c); Event::Normal(4) }
+            0xaa => { self.alu(alu::xra, self.regs.d); Event::Normal(4) }
+            0xab => { self.alu(alu::xra, self.regs.e); Event::Normal(4) }
+            0xac => { self.alu(alu::xra, self.regs.h); Event::Normal(4) }
+            0xad => { self.alu(alu::xra, self.regs.l); Event::Normal(4) }
+            0xae => { self.alu(alu::xra, self.get_m()); Event::Normal(7) }
+            0xaf => { self.alu(alu::xra, self.regs.a); Event::Normal(4) }
 
             // ORA
-            0xb0 => { self.regs.a = self.ora(self.regs.a, self.regs.b); Event::Normal(4) }
-            0xb1 => { self.regs.a = self.ora(self.regs.a, self.regs.c); Event::Normal(4) }
-            0xb2 => { self.regs.a = self.ora(self.regs.a, self.regs.d); Event::Normal(4) }
-            0xb3 => { self.regs.a = self.ora(self.regs.a, self.regs.e); Event::Normal(4) }
-            0xb4 => { self.regs.a = self.ora(self.regs.a, self.regs.h); Event::Normal(4) }
-            0xb5 => { self.regs.a = self.ora(self.regs.a, self.regs.l); Event::Normal(4) }
-            0xb6 => { self.regs.a = self.ora(self.regs.a, self.get_m()); Event::Normal(7) }
-            0xb7 => { self.regs.a = self.ora(self.regs.a, self.regs.a); Event::Normal(4) }
+            0xb0 => { self.alu(alu::ora, self.regs.b); Event::Normal(4) }
+            0xb1 => { self.alu(alu::ora, self.regs.c); Event::Normal(4) }
+            0xb2 => { self.alu(alu::ora, self.regs.d); Event::Normal(4) }
+            0xb3 => { self.alu(alu::ora, self.regs.e); Event::Normal(4) }
+            0xb4 => { self.alu(alu::ora, self.regs.h); Event::Normal(4) }
+            0xb5 => { self.alu(alu::ora, self.regs.l); Event::Normal(4) }
+            0xb6 => { self.alu(alu::ora, self.get_m()); Event::Normal(7) }
+            0xb7 => { self.alu(alu::ora, self.regs.a); Event::Normal(4) }
 
             // CMP
-            0xb8 => { self.cmp(self.regs.a, self.regs.b); Event::Normal(4) }
-            0xb9 => { self.cmp(self.regs.a, self.regs.c); Event::Normal(4) }
-            0xba => { self.cmp(self.regs.a, self.regs.d); Event::Normal(4) }
-            0xbb => { self.cmp(self.regs.a, self.regs.e); Event::Normal(4) }
-            0xbc => { self.cmp(self.regs.a, self.regs.h); Event::Normal(4) }
-            0xbd => { self.cmp(self.regs.a, self.regs.l); Event::Normal(4) }
-            0xbe => { self.cmp(self.regs.a, self.get_m()); Event::Normal(7) }
-            0xbf => { self.cmp(self.regs.a, self.regs.a); Event::Normal(4) }
+            0xb8 => { self.alu(alu::cmp, self.regs.b); Event::Normal(4) }
+            0xb9 => { self.alu(alu::cmp, self.regs.c); Event::Normal(4) }
+            0xba => { self.alu(alu::cmp, self.regs.d); Event::Normal(4) }
+            0xbb => { self.alu(alu::cmp, self.regs.e); Event::Normal(4) }
+            0xbc => { self.alu(alu::cmp, self.regs.h); Event::Normal(4) }
+            0xbd => { self.alu(alu::cmp, self.regs.l); Event::Normal(4) }
+            0xbe => { self.alu(alu::cmp, self.get_m()); Event::Normal(7) }
+            0xbf => { self.alu(alu::cmp, self.regs.a); Event::Normal(4) }
 
             // ADI
             0xc6 => { 
                 let data = self.memory.borrow().read(self.pc.into());
                 self.pc = self.pc.wrapping_add(1);
-                self.regs.a = self.add(self.regs.a, data);
+                self.alu(alu::add, data);
                 Event::Normal(7)
             }
 
@@ -406,7 +1303,7 @@ impl Device<Event> for CPU {
             0xce => { 
                 let data = self.memory.borrow().read(self.pc.into());
                 self.pc = self.pc.wrapping_add(1);
-                self.regs.a = self.adc(self.regs.a, data);
+                self.alu(alu::adc, data);
                 Event::Normal(7)
             }
 
@@ -414,7 +1311,7 @@ impl Device<Event> for CPU {
             0xd6 => { 
                 let data = self.memory.borrow().read(self.pc.into());
                 self.pc = self.pc.wrapping_add(1);
-                self.regs.a = self.sub(self.regs.a, data);
+                self.alu(alu::sub, data);
                 Event::Normal(7)
             }
 
@@ -422,7 +1319,7 @@ impl Device<Event> for CPU {
             0xde => { 
                 let data = self.memory.borrow().read(self.pc.into());
                 self.pc = self.pc.wrapping_add(1);
-                self.regs.a = self.sbb(self.regs.a, data);
+                self.alu(alu::sbb, data);
                 Event::Normal(7)
             }
 
@@ -430,7 +1327,7 @@ impl Device<Event> for CPU {
             0xe6 => {
                 let data = self.memory.borrow().read(self.pc.into());
                 self.pc = self.pc.wrapping_add(1);
-                self.regs.a = self.ana(self.regs.a, data);
+                self.alu(alu::ana, data);
                 Event::Normal(7)
             }
 
@@ -438,7 +1335,7 @@ impl Device<Event> for CPU {
             0xee => {
                 let data = self.memory.borrow().read(self.pc.into());
                 self.pc = self.pc.wrapping_add(1);
-                self.regs.a = self.xra(self.regs.a, data);
+                self.alu(alu::xra, data);
                 Event::Normal(7)
             }
 
@@ -446,7 +1343,7 @@ impl Device<Event> for CPU {
             0xf6 => {
                 let data = self.memory.borrow().read(self.pc.into());
                 self.pc = self.pc.wrapping_add(1);
-                self.regs.a = self.ora(self.regs.a, data);
+                self.alu(alu::ora, data);
                 Event::Normal(7)
             }
 
@@ -454,48 +1351,21 @@ impl Device<Event> for CPU {
             0xfe => {
                 let data = self.memory.borrow().read(self.pc.into());
                 self.pc = self.pc.wrapping_add(1);
-                self.cmp(self.regs.a, data);
+                self.alu(alu::cmp, data);
                 Event::Normal(7)
             }
 
             // RLC
-            0x07 => {
-                let carry = (self.regs.a & 0x80) >> 7;
-                let n = (self.regs.a << 1) | carry;
-                self.regs.set_flag(Flag::C, carry == 1);
-                self.regs.a = n;
-
-                Event::Normal(4)
-            }
+            0x07 => { self.alu_a(alu::rlc); Event::Normal(4) }
 
             // RRC
-            0x0f => {
-                let carry = self.regs.a & 0x01;
-                let n = if carry == 1 { 0x80 | (self.regs.a >> 1) } else { self.regs.a >> 1 };
-                self.regs.set_flag(Flag::C, carry == 1);
-                self.regs.a = n;
-
-                Event::Normal(4)
-            }
+            0x0f => { self.alu_a(alu::rrc); Event::Normal(4) }
 
             // RAL
-            0x17 => {
-                let carry = (self.regs.a & 0x80) >> 7;
-                let n = (self.regs.a << 1) | u8::from(self.regs.get_flag(Flag::C));
-                self.regs.set_flag(Flag::C, carry == 1);
-                self.regs.a = n;
-                Event::Normal(4)
-            }
+            0x17 => { self.alu_a(alu::ral); Event::Normal(4) }
 
             // RAR
-            0x1f => {
-                let lo = self.regs.a & 1;
-                let carry: u8 = if self.regs.get_flag(Flag::C) { 0x80 } else { 0 };
-                self.regs.a = self.regs.a >> 1;
-                self.regs.a |= carry;
-                self.regs.set_flag(Flag::C, lo == 1);
-                Event::Normal(4)
-            }
+            0x1f => { self.alu_a(alu::rar); Event::Normal(4) }
 
             // CMA
             0x2f => {
@@ -504,62 +1374,22 @@ impl Device<Event> for CPU {
             }
 
             // CMC
-            0x3f => {
-                let carry = self.regs.get_flag(Flag::C);
-                self.regs.set_flag(Flag::C, !carry);
-                Event::Normal(4)
-            }
+            0x3f => { self.alu_a(alu::cmc); Event::Normal(4) }
 
             // DAA
-            0x27 => {
-                let hi = self.regs.a >> 4;
-                let lo = self.regs.a & 0x0f;
-                let mut res = 0;
-                let mut carry = self.regs.get_flag(Flag::C);
-                if lo > 9 || self.regs.get_flag(Flag::A) {
-                    res += 0x06;
-                }
-
-                if hi > 9 || carry || (hi >= 9 && lo > 9) {
-                    res += 0x60;
-                    carry = true;
-                }
-                self.regs.a = self.add(self.regs.a, res);
-                self.regs.set_flag(Flag::C, carry);
-                Event::Normal(4)
-            }
+            0x27 => { self.alu_a(alu::daa); Event::Normal(4) }
 
             // STC
-            0x37 => { self.regs.set_flag(Flag::C, true); Event::Normal(4) }
+            0x37 => { self.alu_a(alu::stc); Event::Normal(4) }
 
             // DAD
-            0x09 => { 
-                let n = self.regs.get_hl().wrapping_add(self.regs.get_bc());
-                self.regs.set_flag(Flag::C, self.regs.get_hl() > 0xffff - self.regs.get_bc());
-                self.regs.set_hl(n); 
-                Event::Normal(10) 
-            }
-            0x19 => { 
-                let n = self.regs.get_hl().wrapping_add(self.regs.get_de());
-                self.regs.set_flag(Flag::C, self.regs.get_hl() > 0xffff - self.regs.get_de());
-                self.regs.set_hl(n); 
-                Event::Normal(10) 
-            }
-            0x29 => { 
-                let n = self.regs.get_hl().wrapping_add(self.regs.get_hl());
-                self.regs.set_flag(Flag::C, self.regs.get_hl() > 0xffff - self.regs.get_hl());
-                self.regs.set_hl(n); 
-                Event::Normal(10) 
-            }
-            0x39 => { 
-                let n = self.regs.get_hl().wrapping_add(self.sp);
-                self.regs.set_flag(Flag::C, self.regs.get_hl() > 0xffff - self.sp);
-                self.regs.set_hl(n); 
-                Event::Normal(10) 
-            }
+            0x09 => { self.dad(self.regs.get_bc()); Event::Normal(10) }
+            0x19 => { self.dad(self.regs.get_de()); Event::Normal(10) }
+            0x29 => { self.dad(self.regs.get_hl()); Event::Normal(10) }
+            0x39 => { self.dad(self.sp); Event::Normal(10) }
 
             // MOV B regm
-            0x40 => { self.regs.b = self.regs.b; Event::Normal(5) }
+            0x40 => Event::Normal(5),
             0x41 => { self.regs.b = self.regs.c; Event::Normal(5) }
             0x42 => { self.regs.b = self.regs.d; Event::Normal(5) }
             0x43 => { self.regs.b = self.regs.e; Event::Normal(5) }
@@ -570,7 +1400,7 @@ impl Device<Event> for CPU {
 
             // MOV C regsm
             0x48 => { self.regs.c = self.regs.b; Event::Normal(5) }
-            0x49 => { self.regs.c = self.regs.c; Event::Normal(5) }
+            0x49 => Event::Normal(5),
             0x4a => { self.regs.c = self.regs.d; Event::Normal(5) }
             0x4b => { self.regs.c = self.regs.e; Event::Normal(5) }
             0x4c => { self.regs.c = self.regs.h; Event::Normal(5) }
@@ -581,7 +1411,7 @@ impl Device<Event> for CPU {
             // MOV D regsm
             0x50 => { self.regs.d = self.regs.b; Event::Normal(5) }
             0x51 => { self.regs.d = self.regs.c; Event::Normal(5) }
-            0x52 => { self.regs.d = self.regs.d; Event::Normal(5) }
+            0x52 => Event::Normal(5),
             0x53 => { self.regs.d = self.regs.e; Event::Normal(5) }
             0x54 => { self.regs.d = self.regs.h; Event::Normal(5) }
             0x55 => { self.regs.d = self.regs.l; Event::Normal(5) }
@@ -592,7 +1422,7 @@ impl Device<Event> for CPU {
             0x58 => { self.regs.e = self.regs.b; Event::Normal(5) }
             0x59 => { self.regs.e = self.regs.c; Event::Normal(5) }
             0x5a => { self.regs.e = self.regs.d; Event::Normal(5) }
-            0x5b => { self.regs.e = self.regs.e; Event::Normal(5) }
+            0x5b => Event::Normal(5),
             0x5c => { self.regs.e = self.regs.h; Event::Normal(5) }
             0x5d => { self.regs.e = self.regs.l; Event::Normal(5) }
             0x5e => { self.regs.e = self.get_m(); Event::Normal(7) }
@@ -603,7 +1433,7 @@ impl Device<Event> for CPU {
             0x61 => { self.regs.h = self.regs.c; Event::Normal(5) }
             0x62 => { self.regs.h = self.regs.d; Event::Normal(5) }
             0x63 => { self.regs.h = self.regs.e; Event::Normal(5) }
-            0x64 => { self.regs.h = self.regs.h; Event::Normal(5) }
+            0x64 => Event::Normal(5),
             0x65 => { self.regs.h = self.regs.l; Event::Normal(5) }
             0x66 => { self.regs.h = self.get_m(); Event::Normal(7) }
             0x67 => { self.regs.h = self.regs.a; Event::Normal(5) }
@@ -614,7 +1444,7 @@ impl Device<Event> for CPU {
             0x6a => { self.regs.l = self.regs.d; Event::Normal(5) }
             0x6b => { self.regs.l = self.regs.e; Event::Normal(5) }
             0x6c => { self.regs.l = self.regs.h; Event::Normal(5) }
-            0x6d => { self.regs.l = self.regs.l; Event::Normal(5) }
+            0x6d => Event::Normal(5),
             0x6e => { self.regs.l = self.get_m(); Event::Normal(7) }
             0x6f => { self.regs.l = self.regs.a; Event::Normal(5) }
 
@@ -635,7 +1465,7 @@ impl Device<Event> for CPU {
             0x7c => { self.regs.a = self.regs.h; Event::Normal(5) }
             0x7d => { self.regs.a = self.regs.l; Event::Normal(5) }
             0x7e => { self.regs.a = self.get_m(); Event::Normal(7) }
-            0x7f => { self.regs.a = self.regs.a; Event::Normal(5) }
+            0x7f => Event::Normal(5),
 
             // MVI
             0x06 => {
@@ -692,7 +1522,7 @@ impl Device<Event> for CPU {
                 let addr = self.memory.borrow().read16(self.pc.into());
                 self.pc = self.pc.wrapping_add(2);
                 self.memory.borrow_mut().write(addr.into(), self.regs.l);
-                self.memory.borrow_mut().write((addr + 1).into(), self.regs.h);
+                self.memory.borrow_mut().write(addr.wrapping_add(1).into(), self.regs.h);
                 Event::Normal(16)
             }
 
@@ -734,32 +1564,32 @@ impl Device<Event> for CPU {
             }
 
             // JMP
-            0xc3 => { self.jmp(true); Event::Normal(13) }
-            0xcb => { self.jmp(true); Event::Normal(13) }
+            0xc3 => self.jmp(true),
+            0xcb => self.jmp(true),
 
             // JC
-            0xda => { self.jmp(self.regs.get_flag(Flag::C)); Event::Normal(13) }
+            0xda => self.jmp(self.regs.get_flag(Flag::C)),
 
             // JNC
-            0xd2 => { self.jmp(!self.regs.get_flag(Flag::C)); Event::Normal(13) }
+            0xd2 => self.jmp(!self.regs.get_flag(Flag::C)),
 
             // JZ
-            0xca => { self.jmp(self.regs.get_flag(Flag::Z)); Event::Normal(13) }
+            0xca => self.jmp(self.regs.get_flag(Flag::Z)),
 
             // JNZ
-            0xc2 => { self.jmp(!self.regs.get_flag(Flag::Z)); Event::Normal(13) }
+            0xc2 => self.jmp(!self.regs.get_flag(Flag::Z)),
 
             // JP
-            0xf2 => { self.jmp(!self.regs.get_flag(Flag::S)); Event::Normal(13) }
+            0xf2 => self.jmp(!self.regs.get_flag(Flag::S)),
 
             // JM
-            0xfa => { self.jmp(self.regs.get_flag(Flag::S)); Event::Normal(13) }
+            0xfa => self.jmp(self.regs.get_flag(Flag::S)),
 
             // JPE
-            0xea => { self.jmp(self.regs.get_flag(Flag::P)); Event::Normal(13) }
+            0xea => self.jmp(self.regs.get_flag(Flag::P)),
 
             // JPO
-            0xe2 => { self.jmp(!self.regs.get_flag(Flag::P)); Event::Normal(13) }
+            0xe2 => self.jmp(!self.regs.get_flag(Flag::P)),
 
             // PCHL
             0xe9 => { self.pc = self.regs.get_hl(); Event::Normal(5) }
@@ -814,32 +1644,32 @@ impl Device<Event> for CPU {
             0xe4 => self.call(!self.regs.get_flag(Flag::P)),
 
             // RET
-            0xc9 => self.ret(true),
-            0xd9 => self.ret(true),
+            0xc9 => self.ret(),
+            0xd9 => self.ret(),
 
             // RC
-            0xd8 => self.ret(self.regs.get_flag(Flag::C)),
+            0xd8 => self.ret_if(self.regs.get_flag(Flag::C)),
 
             // RNC
-            0xd0 => self.ret(!self.regs.get_flag(Flag::C)),
+            0xd0 => self.ret_if(!self.regs.get_flag(Flag::C)),
 
             // RZ
-            0xc8 => self.ret(self.regs.get_flag(Flag::Z)),
+            0xc8 => self.ret_if(self.regs.get_flag(Flag::Z)),
 
             // RNZ
-            0xc0 => self.ret(!self.regs.get_flag(Flag::Z)),
+            0xc0 => self.ret_if(!self.regs.get_flag(Flag::Z)),
 
             // RM
-            0xf8 => self.ret(self.regs.get_flag(Flag::S)),
+            0xf8 => self.ret_if(self.regs.get_flag(Flag::S)),
 
             // RP
-            0xf0 => self.ret(!self.regs.get_flag(Flag::S)),
+            0xf0 => self.ret_if(!self.regs.get_flag(Flag::S)),
 
             // RPE
-            0xe8 => self.ret(self.regs.get_flag(Flag::P)),
+            0xe8 => self.ret_if(self.regs.get_flag(Flag::P)),
 
             // RPO
-            0xe0 => self.ret(!self.regs.get_flag(Flag::P)),
+            0xe0 => self.ret_if(!self.regs.get_flag(Flag::P)),
 
             // PUSH
             0xc5 => { self.push(self.regs.get_bc()); Event::Normal(11) }
@@ -851,7 +1681,17 @@ impl Device<Event> for CPU {
             0xc1 => { let data = self.pop(); self.regs.set_bc(data); Event::Normal(10) }
             0xd1 => { let data = self.pop(); self.regs.set_de(data); Event::Normal(10) }
             0xe1 => { let data = self.pop(); self.regs.set_hl(data); Event::Normal(10) }
-            0xf1 => { let data = self.pop(); self.regs.set_af(data); Event::Normal(10) }
+            0xf1 => {
+                let data = self.pop();
+                match self.psw_pop {
+                    PswPop::Hardware => self.regs.set_af(data),
+                    PswPop::Raw => {
+                        self.regs.a = (data >> 8) as u8;
+                        self.regs.f = data as u8;
+                    }
+                }
+                Event::Normal(10)
+            }
 
             // EI
             0xfb => { self.inter = true; Event::Normal(4) }
@@ -862,7 +1702,6 @@ impl Device<Event> for CPU {
             0xdb => { 
                 let port = self.memory.borrow().read(self.pc.into());
                 self.pc = self.pc.wrapping_add(1);
-                // println!("Read byte from input device: {}", data);
                 Event::Input(port, 10)
             }
 
@@ -870,12 +1709,11 @@ impl Device<Event> for CPU {
             0xd3 => {
                 let port = self.memory.borrow().read(self.pc.into());
                 self.pc = self.pc.wrapping_add(1);
-                // println!("Send byte to input device: {}", port);
                 Event::Output(port, self.regs.a, 10)
             }
 
             // HLT
-            0x76 => Event::Halt(7),
+            0x76 => { self.halted = true; Event::Halt(7) }
 
             // RST
             0xc7 => { self.rst(0b0000_0000_0000_0000); Event::Normal(11) }
@@ -888,31 +1726,866 @@ impl Device<Event> for CPU {
             0xff => { self.rst(0b0000_0000_0011_1000); Event::Normal(11) }
 
             // _ => panic!("Instruction not implemented: {:x}", op),
+        };
+        let event = match i8085 {
+            true => {
+                let cycles = i8085::cycles(op, event.cycles(), jump_taken);
+                event.with_cycles(cycles)
+            }
+            false => event,
+        };
+        let memory = self.memory.borrow();
+        let guard_hit = memory.take_guard_hit();
+        let event = match &self.contention {
+            Some(contention) => event.stretched(contention.wait_cycles(self.cycles, memory.take_contended_accesses())),
+            None => event,
+        };
+        drop(memory);
+        // The instruction has run even when it hit a guard, so its cycles count
+        self.cycles = self.cycles.wrapping_add(Timestamp::from(event.cycles()));
+        match guard_hit {
+            Some(addr) => Event::Fault(Fault::Guard(addr)),
+            None => event,
+        }
+    }
+}
+
+impl CPU<Memory8080> {
+    /// A CPU at 0x0000 in 64K of zeroed RAM, built without the 64K ever
+    /// being on the stack.
+    pub fn new_empty() -> Self {
+        CPU::new(Rc::new(RefCell::new(Memory8080::new_empty())))
+    }
+
+    /// Adds wait states for framebuffer accesses during active display, or
+    /// with `None` takes them away again. See `contention`.
+    pub fn set_contention(&mut self, contention: Option<VideoContention>) {
+        let range = contention.as_ref().map(|c| c.range.clone());
+        self.memory.borrow_mut().set_contended(range);
+        self.contention = contention;
+    }
+
+    /// Captures the CPU and the contents of its memory, for a save file or
+    /// a rewind point. Snapshots are only taken between instructions: one
+    /// taken between `fetch` and `exec` could not be resumed, so it fails
+    /// with `EmulatorError::MidInstruction`.
+    pub fn save_state(&self) -> Result<SaveState, EmulatorError> {
+        if !self.at_boundary() {
+            return Err(EmulatorError::MidInstruction(self.pc.wrapping_sub(1)));
+        }
+        Ok(SaveState {
+            cpu: self.state(),
+            cycles: self.cycles,
+            memory: self.memory.borrow().contents().to_vec(),
+        })
+    }
+
+    /// Puts the CPU and its memory back as `state` has them. Memory is
+    /// restored as is, regardless of the vector page policy; debugging
+    /// aids such as no-execute and guard ranges are left alone.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `state.memory` is not exactly 64K.
+    pub fn load_state(&mut self, state: &SaveState) {
+        self.set_state(&state.cpu);
+        self.cycles = state.cycles;
+        self.mid_instruction = false;
+        self.fault = None;
+        self.memory.borrow_mut().set_contents(&state.memory);
+    }
+}
+
+impl<M: Memory> I8080Core for CPU<M> {
+    fn step(&mut self) -> Event {
+        CPU::step(self).0
+    }
+
+    fn state(&self) -> CpuState {
+        CpuState {
+            a: self.regs.a,
+            f: self.regs.f,
+            b: self.regs.b,
+            c: self.regs.c,
+            d: self.regs.d,
+            e: self.regs.e,
+            h: self.regs.h,
+            l: self.regs.l,
+            pc: self.pc,
+            sp: self.sp,
+            inte: self.inter,
+            halted: self.halted,
+        }
+    }
+
+    fn set_state(&mut self, state: &CpuState) {
+        self.regs.a = state.a;
+        self.regs.f = state.f;
+        self.regs.b = state.b;
+        self.regs.c = state.c;
+        self.regs.d = state.d;
+        self.regs.e = state.e;
+        self.regs.h = state.h;
+        self.regs.l = state.l;
+        self.pc = state.pc;
+        self.sp = state.sp;
+        self.inter = state.inte;
+        self.halted = state.halted;
+    }
+
+    fn interrupt(&mut self, op: u8) -> Option<Event> {
+        CPU::interrupt(self, op)
+    }
+
+    fn interrupt_at(&mut self, addr: u16) -> Option<Event> {
+        self.inter_handle(addr)
+    }
+
+    fn capabilities(&self) -> CoreCapabilities {
+        CoreCapabilities {
+            exact_aux_carry: true,
+            ei_delay: false,
+            fixed_psw_bits: self.psw_pop == PswPop::Hardware,
+            undocumented_opcodes: UndocumentedOpcodes::Aliases,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::cpu::{CPU};
-    use crate::device::{Device};
-    use crate::memory::{Memory};
-    use crate::registers::{Flag};
+    use crate::cpu::{execute_one, Branch, CpuBuilder, CpuModel, CPU, CpuState, Event, ExecResult, Fault, PswPop, Timestamp, UndocumentedOpcodes};
+    use crate::error::EmulatorError;
+    use crate::hooks::{Hooks, OpClass};
+    use crate::I8080Core;
+    use crate::opcodes::OPCODES;
+    use crate::symbols::SymbolTable;
+    use crate::memory::{Memory, Memory8080};
+    use crate::registers::{Flag, Registers};
+
+    use proptest::prelude::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn cpu_from(memory: [u8; 0x10000]) -> CPU {
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        cpu.sp = 0xf000;
+        cpu
+    }
+
+    #[test]
+    fn test_step_n() {
+        // MVI A, 0x01; INR A; INR A; OUT 0x02; NOP
+        let mut memory = [0x00; 0x10000];
+        memory[..6].copy_from_slice(&[0x3e, 0x01, 0x3c, 0x3c, 0xd3, 0x02]);
+        let mut cpu = cpu_from(memory);
+        let result = cpu.step_n(2);
+        assert_eq!(result.steps, 2);
+        assert_eq!(result.cycles, 12);
+        assert!(result.event.is_none());
+
+        let result = cpu.step_n(100);
+        assert_eq!(result.steps, 2);
+        assert_eq!(result.cycles, 15);
+        assert!(matches!(result.event, Some(Event::Output(0x02, 0x03, 10))));
+        assert_eq!(cpu.pc, 6);
+    }
+
+    // Records each port write together with the PC at the time it arrived
+    fn run_recording_outputs(cpu: &mut CPU, steps: usize, interrupt_at: Option<u16>) -> Vec<(u8, u8, u16)> {
+        let mut writes = Vec::new();
+        for _ in 0..steps {
+            let op = cpu.fetch();
+            if let Event::Output(port, value, _) = cpu.exec(op) {
+                writes.push((port, value, cpu.pc));
+            }
+            if let Some(vector) = interrupt_at {
+                cpu.inter_handle(vector);
+            }
+        }
+        writes
+    }
+
+    #[test]
+    fn test_out_visible_before_next_instruction() {
+        // MVI A, 0x11; OUT 0x01; INR A; OUT 0x02
+        let mut memory = [0x00; 0x10000];
+        memory[..7].copy_from_slice(&[0x3e, 0x11, 0xd3, 0x01, 0x3c, 0xd3, 0x02]);
+        let mut cpu = cpu_from(memory);
+        let writes = run_recording_outputs(&mut cpu, 4, None);
+        assert_eq!(writes, vec![(0x01, 0x11, 0x0004), (0x02, 0x12, 0x0007)]);
+    }
+
+    #[test]
+    fn test_out_visible_before_interrupt() {
+        // MVI A, 0x22; OUT 0x03, with an interrupt pending once OUT runs
+        let mut memory = [0x00; 0x10000];
+        memory[0x100..0x104].copy_from_slice(&[0x3e, 0x22, 0xd3, 0x03]);
+        let mut cpu = cpu_from(memory);
+        cpu.pc = 0x100;
+        run_recording_outputs(&mut cpu, 1, None);
+        cpu.inter = true;
+        let writes = run_recording_outputs(&mut cpu, 1, Some(0x08));
+        // The write arrives with PC at the boundary after OUT, before the
+        // interrupt moves it to the vector and pushes that boundary
+        assert_eq!(writes, vec![(0x03, 0x22, 0x0104)]);
+        assert_eq!(cpu.pc, 0x08);
+        assert_eq!(cpu.memory.borrow().read16(cpu.sp.into()), 0x0104);
+    }
+
+    #[test]
+    fn test_interrupt_only_at_instruction_boundary() {
+        for op in 0..=0xffu8 {
+            let length = OPCODES[op as usize].length;
+            if length == 1 {
+                continue;
+            }
+            let mut memory = [0x00; 0x10000];
+            memory[0x100] = op;
+            memory[0x101] = 0x34;
+            memory[0x102] = 0x12;
+            let mut cpu = cpu_from(memory);
+            cpu.pc = 0x100;
+            cpu.inter = true;
+
+            let op = cpu.fetch();
+            assert!(cpu.inter_handle(0x38).is_none(), "opcode {:02x}", op);
+            cpu.exec(op);
+            let boundary = cpu.pc;
+            assert!(cpu.inter_handle(0x38).is_some(), "opcode {:02x}", op);
+            assert_eq!(cpu.pc, 0x38);
+            assert_eq!(cpu.memory.borrow().read16(cpu.sp.into()), boundary, "opcode {:02x}", op);
+            // Operand bytes were consumed as operands, not re-executed
+            if boundary != 0x1234 {
+                assert_eq!(boundary, 0x100 + u16::from(length), "opcode {:02x}", op);
+            }
+        }
+    }
+
+    #[test]
+    fn test_interrupt_with_opcode() {
+        // EI; HLT
+        let mut memory = [0x00; 0x10000];
+        memory[0x100..0x102].copy_from_slice(&[0xfb, 0x76]);
+        let mut cpu = cpu_from(memory);
+        cpu.pc = 0x100;
+        assert!(cpu.interrupt(0xff).is_none());
+        let op = cpu.fetch();
+        cpu.exec(op);
+        let op = cpu.fetch();
+        assert!(matches!(cpu.exec(op), Event::Halt(_)));
+        // RST 7 wakes the CPU and returns to the instruction after HLT
+        assert!(matches!(cpu.interrupt(0xff), Some(Event::Normal(11))));
+        assert_eq!(cpu.pc, 0x38);
+        assert_eq!(cpu.sp, 0xeffe);
+        assert_eq!(cpu.memory.borrow().read16(0xeffe), 0x102);
+        // Interrupts stay disabled until the handler enables them again
+        assert!(cpu.interrupt(0xff).is_none());
+    }
+
+    #[test]
+    fn test_halted_until_interrupt() {
+        // EI; HLT; INR A
+        let mut memory = [0x00; 0x10000];
+        memory[..3].copy_from_slice(&[0xfb, 0x76, 0x3c]);
+        let mut cpu = cpu_from(memory);
+        assert!(matches!(run_core(&mut cpu, 2), CpuState { pc: 2, halted: true, .. }));
+        assert!(cpu.is_halted());
+        for _ in 0..3 {
+            assert!(matches!(cpu.step(), (Event::Normal(4), 4)));
+        }
+        assert_eq!((cpu.pc, cpu.regs.a), (2, 0));
+        assert_eq!(cpu.step_n(10).cycles, 40);
+        cpu.inter_handle(0x10);
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.memory.borrow().read16(cpu.sp.into()), 2);
+    }
+
+    #[test]
+    fn test_run_for_frames() {
+        // EI; HLT; JMP 0x0000, with an RST 1 handler at 0x08: INR B; RET
+        let mut memory = [0x00; 0x10000];
+        memory[..5].copy_from_slice(&[0xfb, 0x76, 0xc3, 0x00, 0x00]);
+        memory[0x08..0x0a].copy_from_slice(&[0x04, 0xc9]);
+        let mut cpu = cpu_from(memory);
+        for frame in 1..=3 {
+            let elapsed = cpu.run_for(1000);
+            assert!((1000..1004).contains(&elapsed), "{}", elapsed);
+            assert!(cpu.is_halted());
+            assert!(cpu.interrupt(0xcf).is_some());
+            cpu.run_for(1);
+            assert_eq!(cpu.regs.b, frame);
+        }
+    }
+
+    #[test]
+    fn test_sp_and_interrupt_enable() {
+        // PUSH B; EI
+        let mut memory = [0x00; 0x10000];
+        memory[..2].copy_from_slice(&[0xc5, 0xfb]);
+        let mut cpu = cpu_from(memory);
+        cpu.set_sp(0x2000);
+        cpu.step();
+        assert_eq!(cpu.sp(), 0x1ffe);
+        assert!(!cpu.interrupts_enabled());
+        cpu.step();
+        assert!(cpu.interrupts_enabled());
+        assert_eq!((cpu.state().sp, cpu.state().inte), (0x1ffe, true));
+        cpu.set_interrupts_enabled(false);
+        assert!(cpu.interrupt(0xcf).is_none());
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        // LXI SP, 0x2000; MVI A, 0x42; PUSH PSW; EI; INR A
+        let mut memory = [0x00; 0x10000];
+        memory[..8].copy_from_slice(&[0x31, 0x00, 0x20, 0x3e, 0x42, 0xf5, 0xfb, 0x3c]);
+        let mut cpu = cpu_from(memory);
+        for _ in 0..4 {
+            cpu.step();
+        }
+        let saved = cpu.save_state().unwrap();
+        assert_eq!((saved.cpu.pc, saved.cpu.sp, saved.cpu.a, saved.cpu.inte), (7, 0x1ffe, 0x42, true));
+        assert_eq!(saved.cycles, 10 + 7 + 11 + 4);
+        assert_eq!(saved.memory[0x1fff], 0x42);
+
+        let after = |cpu: &mut CPU| {
+            cpu.step();
+            (cpu.state(), cpu.cycles())
+        };
+        let expected = after(&mut cpu);
+        cpu.memory.borrow_mut().write(0x1fff, 0x00);
+        cpu.load_state(&saved);
+        assert_eq!(cpu.memory.borrow().read(0x1fff), 0x42);
+        assert_eq!(after(&mut cpu), expected);
+
+        // Into a different CPU, which has nothing in memory yet
+        let mut other = cpu_from([0x00; 0x10000]);
+        other.load_state(&saved);
+        assert_eq!(after(&mut other), expected);
+    }
+
+    #[test]
+    fn test_raise_rst() {
+        let mut cpu = cpu_from([0x00; 0x10000]);
+        cpu.pc = 0x1234;
+        cpu.set_sp(0x2000);
+        assert!(cpu.raise_rst(3).is_none());
+        cpu.set_interrupts_enabled(true);
+        assert!(matches!(cpu.raise_rst(3), Some(Event::Normal(11))));
+        assert_eq!((cpu.pc, cpu.sp()), (0x0018, 0x1ffe));
+        assert_eq!(cpu.memory.borrow().read16(0x1ffe), 0x1234);
+        assert!(!cpu.interrupts_enabled());
+    }
+
+    #[test]
+    #[should_panic(expected = "there is no RST 8")]
+    fn test_raise_rst_range() {
+        cpu_from([0x00; 0x10000]).raise_rst(8);
+    }
+
+    #[test]
+    fn test_trace_hook() {
+        // MVI B, 0x10; INR B; HLT
+        let mut memory = [0x00; 0x10000];
+        memory[..4].copy_from_slice(&[0x06, 0x10, 0x04, 0x76]);
+        let mut cpu = cpu_from(memory);
+        let records = Rc::new(RefCell::new(Vec::new()));
+        let log = records.clone();
+        cpu.set_trace_hook(move |record| log.borrow_mut().push(record.clone()));
+        let mut symbols = SymbolTable::new();
+        symbols.insert("START", 0x0000);
+        cpu.set_symbols(symbols);
+        cpu.step();
+        cpu.step();
+        cpu.clear_trace_hook();
+        cpu.step();
+        let records = records.borrow();
+        assert_eq!(records.len(), 2);
+        assert_eq!((records[0].pc, records[0].opcode, records[0].state.b), (0x0000, 0x06, 0x00));
+        assert_eq!((records[1].pc, records[1].opcode, records[1].state.b), (0x0002, 0x04, 0x10));
+        assert_eq!(records[1].disassembly, "2    INR B");
+        assert_eq!(records[1].symbol.as_deref(), Some("START+0x2"));
+        assert_eq!(records[1].branch, None);
+    }
+
+    #[test]
+    fn test_observers_see_whole_instructions() {
+        // LXI SP, 0x2000; LXI H, 0x1234; PUSH H; LXI H, 0xabcd; XTHL; POP B;
+        // DAD B; CALL 0x0020; HLT ... 0x0020: RET
+        let code = [0x31, 0x00, 0x20, 0x21, 0x34, 0x12, 0xe5, 0x21, 0xcd, 0xab, 0xe3, 0xc1, 0x09, 0xcd, 0x20, 0x00, 0x76];
+        let mut memory = [0x00; 0x10000];
+        memory[..code.len()].copy_from_slice(&code);
+        memory[0x20] = 0xc9;
+        let mut cpu = cpu_from(memory);
+        let traced = Rc::new(RefCell::new(Vec::new()));
+        let log = traced.clone();
+        cpu.set_trace_hook(move |record| log.borrow_mut().push(record.state));
+        let retired = Rc::new(RefCell::new(Vec::new()));
+        let log = retired.clone();
+        let mut hooks = Hooks::new();
+        hooks.on(OpClass::ALL, move |cpu: &CPU, _| {
+            log.borrow_mut().push((cpu.state(), cpu.save_state().is_ok()));
+        });
+
+        let (mut before, mut after) = (Vec::new(), Vec::new());
+        while !cpu.is_halted() {
+            before.push(cpu.state());
+            hooks.step(&mut cpu);
+            after.push(cpu.state());
+        }
+        assert_eq!(*traced.borrow(), before);
+        let retired = retired.borrow();
+        assert_eq!(retired.len(), 8);
+        for (state, saved) in retired.iter() {
+            assert!(after.contains(state));
+            assert!(saved);
+        }
+
+        // Only a caller stopping between fetch and exec sees the middle
+        cpu.reset();
+        cpu.fetch();
+        assert!(!cpu.at_boundary());
+        assert_eq!(cpu.save_state().unwrap_err(), EmulatorError::MidInstruction(0x0000));
+        cpu.exec(0x31);
+        assert!(cpu.at_boundary());
+        assert!(cpu.save_state().is_ok());
+    }
+
+    #[test]
+    fn test_trace_branches() {
+        // LXI SP, 0x2000; XRA A; JNZ 0x0000; CZ 0x0010; RST 1;
+        // ... 0x0010: RC; PCHL
+        let mut memory = [0x00; 0x10000];
+        let code = [0x31, 0x00, 0x20, 0xaf, 0xc2, 0x00, 0x00, 0xcc, 0x10, 0x00, 0xcf];
+        memory[..code.len()].copy_from_slice(&code);
+        memory[0x10..0x12].copy_from_slice(&[0xd8, 0xe9]);
+        let mut cpu = cpu_from(memory);
+        cpu.regs.set_hl(0x1234);
+        let records = Rc::new(RefCell::new(Vec::new()));
+        let log = records.clone();
+        cpu.set_trace_hook(move |record| log.borrow_mut().push(record.branch));
+        for _ in 0..6 {
+            cpu.step();
+        }
+        let branch = |target, conditional, taken| Some(Branch { target, conditional, taken });
+        assert_eq!(*records.borrow(), [
+            None,
+            None,
+            branch(0x0000, true, false),
+            branch(0x0010, true, true),
+            branch(0x000a, true, false),
+            branch(0x1234, false, true),
+        ]);
+        cpu.pc = 0x000a;
+        records.borrow_mut().clear();
+        cpu.step();
+        assert_eq!(*records.borrow(), [branch(0x0008, false, true)]);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let mut cpu = cpu_from([0x00; 0x10000]);
+        let capabilities = cpu.capabilities();
+        assert!(capabilities.exact_aux_carry);
+        assert!(!capabilities.ei_delay);
+        assert!(capabilities.fixed_psw_bits);
+        assert_eq!(capabilities.undocumented_opcodes, UndocumentedOpcodes::Aliases);
+        cpu.set_psw_pop(PswPop::Raw);
+        assert!(!cpu.capabilities().fixed_psw_bits);
+    }
+
+    #[test]
+    fn test_stack_and_words_wrap() {
+        // PUSH B; XTHL; POP D; SHLD 0xffff
+        let mut memory = [0x00; 0x10000];
+        memory[0x100..0x106].copy_from_slice(&[0xc5, 0xe3, 0xd1, 0x22, 0xff, 0xff]);
+        let mut cpu = cpu_from(memory);
+        cpu.pc = 0x100;
+        cpu.sp = 0x0001;
+        cpu.regs.set_bc(0xabcd);
+        cpu.regs.set_hl(0x1234);
+        cpu.step();
+        assert_eq!(cpu.sp, 0xffff);
+        assert_eq!(cpu.memory.borrow().read(0x0000), 0xab);
+        cpu.step();
+        assert_eq!((cpu.regs.get_hl(), cpu.memory.borrow().read16(0xffff)), (0xabcd, 0x1234));
+        cpu.step();
+        assert_eq!((cpu.regs.get_de(), cpu.sp), (0x1234, 0x0001));
+        cpu.step();
+        assert_eq!(cpu.memory.borrow().read16(0xffff), 0xabcd);
+    }
+
+    #[test]
+    fn test_operands_wrap() {
+        // LXI B/D/H/SP with the opcode at 0xfffe and the operand's high
+        // byte at 0x0000
+        for &op in &[0x01, 0x11, 0x21, 0x31] {
+            let mut memory = [0x00; 0x10000];
+            memory[0xfffe] = op;
+            memory[0xffff] = 0x34;
+            memory[0x0000] = 0x12;
+            let mut cpu = cpu_from(memory);
+            cpu.pc = 0xfffe;
+            assert_eq!(cpu.step(), (Event::Normal(10), 10));
+            let loaded = match op {
+                0x01 => cpu.regs.get_bc(),
+                0x11 => cpu.regs.get_de(),
+                0x21 => cpu.regs.get_hl(),
+                _ => cpu.sp,
+            };
+            assert_eq!((loaded, cpu.pc), (0x1234, 0x0001));
+        }
+    }
+
+    #[test]
+    fn test_state_display_and_events() {
+        let state = CpuState { a: 0x3e, f: 0x46, pc: 0x0100, sp: 0xf000, inte: true, ..CpuState::default() };
+        assert_eq!(state.to_string(), "A=3E F=-Z-P- BC=0000 DE=0000 HL=0000 SP=F000 PC=0100 EI");
+
+        // OUT 0x01
+        let mut memory = [0x00; 0x10000];
+        memory[..2].copy_from_slice(&[0xd3, 0x01]);
+        let mut cpu = cpu_from(memory);
+        assert_eq!(cpu.step().0, Event::Output(0x01, 0x00, 10));
+    }
+
+    #[test]
+    fn test_state_diff() {
+        // CALL 0x0010
+        let mut memory = [0x00; 0x10000];
+        memory[..3].copy_from_slice(&[0xcd, 0x10, 0x00]);
+        let mut cpu = cpu_from(memory);
+        cpu.set_sp(0x2000);
+        let before = cpu.save_state().unwrap();
+        cpu.step();
+        let diff = before.diff(&cpu.save_state().unwrap());
+        assert!(diff.only(&["SP", "PC"], 0x1ffe..=0x1fff));
+        assert_eq!(diff.register("PC"), Some((0x0000, 0x0010)));
+        assert_eq!(diff.to_string(), "SP: 2000 -> 1FFE, PC: 0000 -> 0010, [1FFE]: 00 -> 03");
+
+        let flags = CpuState { f: 0x03, ..CpuState::default() }.diff(&CpuState { f: 0x42, ..CpuState::default() });
+        assert_eq!(flags.flags, [(Flag::Z, true), (Flag::C, false)]);
+        assert!(before.cpu.diff(&before.cpu).is_empty());
+    }
+
+    #[test]
+    fn test_step_result() {
+        // MVI A, 1; JMP 0x0010; ... HLT
+        let mut memory = [0x00; 0x10000];
+        memory[..5].copy_from_slice(&[0x3e, 0x01, 0xc3, 0x10, 0x00]);
+        memory[0x10] = 0x76;
+        let mut cpu = cpu_from(memory);
+        let mvi = cpu.step_result();
+        assert_eq!(mvi, ExecResult { event: Event::Normal(7), cycles: 7, start: 0, opcode: 0x3e, pc_before: 0x0000, pc_after: 0x0002 });
+        let jmp = cpu.step_result();
+        assert_eq!((jmp.pc_after, jmp.start, jmp.end()), (0x0010, 7, 17));
+        assert_eq!(cpu.step_result().event, Event::Halt(7));
+        let halted = cpu.step_result();
+        assert_eq!((halted.opcode, halted.pc_before, halted.pc_after, halted.cycles), (0x00, 0x0011, 0x0011, 4));
+    }
+
+    #[test]
+    fn test_traps() {
+        // CALL 0x1000; CALL 0x1000
+        let mut memory = [0x00; 0x10000];
+        memory[..6].copy_from_slice(&[0xcd, 0x00, 0x10, 0xcd, 0x00, 0x10]);
+        memory[0x1000] = 0x76;
+        let mut cpu = cpu_from(memory);
+        cpu.set_sp(0x2000);
+        // Copies DE to the address in HL, leaving A alone
+        cpu.add_trap(0x1000, |regs, memory: &mut Memory8080| {
+            memory.write16(usize::from(regs.get_hl()), regs.get_de());
+            None
+        });
+        cpu.regs.set_de(0xbeef);
+        cpu.regs.set_hl(0x3000);
+        cpu.regs.a = 0x55;
+        assert_eq!(cpu.step(), (Event::Normal(17), 17));
+        assert_eq!(cpu.step(), (Event::Normal(10), 10));
+        assert_eq!((cpu.pc, cpu.sp(), cpu.regs.a), (0x0003, 0x2000, 0x55));
+        assert_eq!(cpu.memory.borrow().read16(0x3000), 0xbeef);
+
+        // Without the trap the guest code at 0x1000 runs
+        cpu.remove_trap(0x1000);
+        cpu.step();
+        assert_eq!(cpu.step().0, Event::Halt(7));
+    }
+
+    #[test]
+    fn test_execute_one() {
+        let mut memory = Memory8080::new_empty();
+        memory.write(0x0200, 0x7f);
+        let mut state = CpuState { pc: 0x0100, ..CpuState::default() };
+
+        // LDA 0x0200 from the stream, with memory at 0x0100 left alone
+        let lda = execute_one(&mut state, &mut memory, &[0x3a, 0x00, 0x02]);
+        assert_eq!((lda.opcode, lda.cycles, lda.pc_after), (0x3a, 13, 0x0103));
+        assert_eq!((state.a, state.pc), (0x7f, 0x0103));
+        assert_eq!(memory.read(0x0100), 0x00);
+
+        // STA 0x0300, then from memory itself: INR A
+        execute_one(&mut state, &mut memory, &[0x32, 0x00, 0x03]);
+        assert_eq!(memory.read(0x0300), 0x7f);
+        memory.write(0x0106, 0x3c);
+        execute_one(&mut state, &mut memory, &[]);
+        assert_eq!((state.a, state.pc, state.f & 0x80), (0x80, 0x0107, 0x80));
+    }
+
+    #[test]
+    fn test_builder() {
+        // PUSH B; POP PSW; RIM
+        let mut memory = [0x00; 0x10000];
+        memory[0x100..0x103].copy_from_slice(&[0xc5, 0xf1, 0x20]);
+        let mut regs = Registers::new();
+        regs.set_bc(0x12ff);
+        let records = Rc::new(RefCell::new(Vec::new()));
+        let traced = records.clone();
+        let mut cpu = CpuBuilder::new()
+            .memory(Rc::new(RefCell::new(Memory8080::new(memory))))
+            .pc(0x0100)
+            .sp(0x2000)
+            .regs(regs)
+            .interrupts_enabled(true)
+            .model(CpuModel::I8085)
+            .psw_pop(PswPop::Raw)
+            .trace(move |record| traced.borrow_mut().push(record.pc))
+            .build();
+        assert!(cpu.interrupts_enabled());
+        cpu.step();
+        cpu.step();
+        assert_eq!((cpu.regs.get_af(), cpu.sp()), (0x12ff, 0x2000));
+        cpu.step();
+        assert_eq!(cpu.regs.a & 0x08, 0x08);
+        assert_eq!(*records.borrow(), [0x0100, 0x0101, 0x0102]);
+    }
+
+    #[test]
+    fn test_register_pairs_wrap() {
+        for rp in 0..4 {
+            // INX rp; DCX rp; DCX rp
+            let mut cpu = cpu_from([0x00; 0x10000]);
+            set_rp(&mut cpu, rp, 0xffff);
+            run_program(&mut cpu, &[0x03 | rp << 4]);
+            assert_eq!(get_rp(&cpu, rp), 0x0000);
+            run_program(&mut cpu, &[0x0b | rp << 4]);
+            assert_eq!(get_rp(&cpu, rp), 0xffff);
+            set_rp(&mut cpu, rp, 0x0000);
+            run_program(&mut cpu, &[0x0b | rp << 4]);
+            assert_eq!(get_rp(&cpu, rp), 0xffff);
+        }
+
+        // DAD H carries out of the original HL, not the doubled one
+        for &(hl, doubled, carry) in &[(0x8000, 0x0000, true), (0x7fff, 0xfffe, false), (0xffff, 0xfffe, true)] {
+            let mut cpu = cpu_from([0x00; 0x10000]);
+            cpu.regs.set_hl(hl);
+            run_program(&mut cpu, &[0x29]);
+            assert_eq!((cpu.regs.get_hl(), cpu.regs.get_flag(Flag::C)), (doubled, carry));
+        }
+    }
+
+    #[test]
+    fn test_reset() {
+        // EI; HLT
+        let mut memory = [0x00; 0x10000];
+        memory[0x100..0x102].copy_from_slice(&[0xfb, 0x76]);
+        let mut cpu = cpu_from(memory);
+        cpu.pc = 0x100;
+        cpu.regs.b = 0x12;
+        cpu.step();
+        cpu.step();
+        cpu.reset();
+        let state = cpu.state();
+        assert_eq!((state.pc, state.inte, state.halted, state.b), (0x0000, false, false, 0x12));
+        assert_eq!(cpu.cycles(), 11);
+    }
+
+    #[test]
+    fn test_cycle_counter() {
+        // EI; HLT, with RST 1 at 0x08
+        let mut memory = [0x00; 0x10000];
+        memory[..2].copy_from_slice(&[0xfb, 0x76]);
+        let mut cpu = cpu_from(memory);
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.cycles(), 4 + 7 + 4);
+        cpu.interrupt(0xcf);
+        assert_eq!(cpu.cycles(), 15 + 11);
+        // The counter wraps rather than overflowing
+        cpu.cycles = Timestamp::MAX - 1;
+        cpu.step();
+        assert_eq!(cpu.cycles(), 2);
+        assert_eq!(cpu.cycles().wrapping_sub(Timestamp::MAX - 1), 4);
+    }
+
+    #[test]
+    fn test_run_for_stops_on_fault() {
+        let mut cpu = cpu_from([0x00; 0x10000]);
+        cpu.memory.borrow_mut().set_no_execute(0x10..=0x1f);
+        assert_eq!(cpu.run_for(1000), 0x10 * 4);
+        assert!(matches!(cpu.step(), (Event::Fault(Fault::NoExecute(0x10)), 0)));
+    }
+
+    #[test]
+    fn test_rst_pushes_return_address() {
+        // RST 1
+        let mut memory = [0x00; 0x10000];
+        memory[0x100] = 0xcf;
+        let mut cpu = cpu_from(memory);
+        cpu.pc = 0x100;
+        let op = cpu.fetch();
+        cpu.exec(op);
+        assert_eq!(cpu.pc, 0x08);
+        assert_eq!(cpu.sp, 0xeffe);
+        assert_eq!(cpu.memory.borrow().read16(0xeffe), 0x101);
+    }
+
+    // Distinct values in every register, with HL pointing at 0x2010
+    fn mov_setup(op: u8) -> CPU {
+        let mut memory = [0x00; 0x10000];
+        memory[0] = op;
+        memory[0x2010] = 0x99;
+        let mut cpu = cpu_from(memory);
+        cpu.regs.a = 0xa0;
+        cpu.regs.b = 0xb0;
+        cpu.regs.c = 0xc0;
+        cpu.regs.d = 0xd0;
+        cpu.regs.e = 0xe0;
+        cpu.regs.h = 0x20;
+        cpu.regs.l = 0x10;
+        cpu.regs.f = 0xd7;
+        cpu
+    }
+
+    fn mov_regs(r: &Registers) -> [u8; 8] {
+        [r.a, r.b, r.c, r.d, r.e, r.h, r.l, r.f]
+    }
+
+    fn mov_step(cpu: &mut CPU) -> u32 {
+        let op = cpu.fetch();
+        cpu.exec(op).cycles()
+    }
+
+    macro_rules! mov_rr {
+        ($name:ident, $op:expr, $dst:ident, $src:ident) => {
+            #[test]
+            fn $name() {
+                let mut cpu = mov_setup($op);
+                let value = cpu.regs.$src;
+                let expected = Registers { $dst: value, ..cpu.regs };
+                assert_eq!(mov_step(&mut cpu), 5);
+                assert_eq!(cpu.regs.$dst, value);
+                assert_eq!(cpu.regs.$src, value);
+                assert_eq!(mov_regs(&cpu.regs), mov_regs(&expected));
+                assert_eq!(cpu.memory.borrow().read(0x2010), 0x99);
+            }
+        };
+    }
+
+    macro_rules! mov_from_m {
+        ($name:ident, $op:expr, $dst:ident) => {
+            #[test]
+            fn $name() {
+                let mut cpu = mov_setup($op);
+                let expected = Registers { $dst: 0x99, ..cpu.regs };
+                assert_eq!(mov_step(&mut cpu), 7);
+                assert_eq!(mov_regs(&cpu.regs), mov_regs(&expected));
+                assert_eq!(cpu.memory.borrow().read(0x2010), 0x99);
+            }
+        };
+    }
+
+    macro_rules! mov_to_m {
+        ($name:ident, $op:expr, $src:ident) => {
+            #[test]
+            fn $name() {
+                let mut cpu = mov_setup($op);
+                let before = mov_regs(&cpu.regs);
+                assert_eq!(mov_step(&mut cpu), 7);
+                assert_eq!(cpu.memory.borrow().read(0x2010), cpu.regs.$src);
+                assert_eq!(mov_regs(&cpu.regs), before);
+            }
+        };
+    }
+
+    mov_rr!(test_mov_b_b, 0x40, b, b);
+    mov_rr!(test_mov_b_c, 0x41, b, c);
+    mov_rr!(test_mov_b_d, 0x42, b, d);
+    mov_rr!(test_mov_b_e, 0x43, b, e);
+    mov_rr!(test_mov_b_h, 0x44, b, h);
+    mov_rr!(test_mov_b_l, 0x45, b, l);
+    mov_from_m!(test_mov_b_m, 0x46, b);
+    mov_rr!(test_mov_b_a, 0x47, b, a);
+    mov_rr!(test_mov_c_b, 0x48, c, b);
+    mov_rr!(test_mov_c_c, 0x49, c, c);
+    mov_rr!(test_mov_c_d, 0x4a, c, d);
+    mov_rr!(test_mov_c_e, 0x4b, c, e);
+    mov_rr!(test_mov_c_h, 0x4c, c, h);
+    mov_rr!(test_mov_c_l, 0x4d, c, l);
+    mov_from_m!(test_mov_c_m, 0x4e, c);
+    mov_rr!(test_mov_c_a, 0x4f, c, a);
+    mov_rr!(test_mov_d_b, 0x50, d, b);
+    mov_rr!(test_mov_d_c, 0x51, d, c);
+    mov_rr!(test_mov_d_d, 0x52, d, d);
+    mov_rr!(test_mov_d_e, 0x53, d, e);
+    mov_rr!(test_mov_d_h, 0x54, d, h);
+    mov_rr!(test_mov_d_l, 0x55, d, l);
+    mov_from_m!(test_mov_d_m, 0x56, d);
+    mov_rr!(test_mov_d_a, 0x57, d, a);
+    mov_rr!(test_mov_e_b, 0x58, e, b);
+    mov_rr!(test_mov_e_c, 0x59, e, c);
+    mov_rr!(test_mov_e_d, 0x5a, e, d);
+    mov_rr!(test_mov_e_e, 0x5b, e, e);
+    mov_rr!(test_mov_e_h, 0x5c, e, h);
+    mov_rr!(test_mov_e_l, 0x5d, e, l);
+    mov_from_m!(test_mov_e_m, 0x5e, e);
+    mov_rr!(test_mov_e_a, 0x5f, e, a);
+    mov_rr!(test_mov_h_b, 0x60, h, b);
+    mov_rr!(test_mov_h_c, 0x61, h, c);
+    mov_rr!(test_mov_h_d, 0x62, h, d);
+    mov_rr!(test_mov_h_e, 0x63, h, e);
+    mov_rr!(test_mov_h_h, 0x64, h, h);
+    mov_rr!(test_mov_h_l, 0x65, h, l);
+    mov_from_m!(test_mov_h_m, 0x66, h);
+    mov_rr!(test_mov_h_a, 0x67, h, a);
+    mov_rr!(test_mov_l_b, 0x68, l, b);
+    mov_rr!(test_mov_l_c, 0x69, l, c);
+    mov_rr!(test_mov_l_d, 0x6a, l, d);
+    mov_rr!(test_mov_l_e, 0x6b, l, e);
+    mov_rr!(test_mov_l_h, 0x6c, l, h);
+    mov_rr!(test_mov_l_l, 0x6d, l, l);
+    mov_from_m!(test_mov_l_m, 0x6e, l);
+    mov_rr!(test_mov_l_a, 0x6f, l, a);
+    mov_to_m!(test_mov_m_b, 0x70, b);
+    mov_to_m!(test_mov_m_c, 0x71, c);
+    mov_to_m!(test_mov_m_d, 0x72, d);
+    mov_to_m!(test_mov_m_e, 0x73, e);
+    mov_to_m!(test_mov_m_h, 0x74, h);
+    mov_to_m!(test_mov_m_l, 0x75, l);
+    mov_to_m!(test_mov_m_a, 0x77, a);
+    mov_rr!(test_mov_a_b, 0x78, a, b);
+    mov_rr!(test_mov_a_c, 0x79, a, c);
+    mov_rr!(test_mov_a_d, 0x7a, a, d);
+    mov_rr!(test_mov_a_e, 0x7b, a, e);
+    mov_rr!(test_mov_a_h, 0x7c, a, h);
+    mov_rr!(test_mov_a_l, 0x7d, a, l);
+    mov_from_m!(test_mov_a_m, 0x7e, a);
+    mov_rr!(test_mov_a_a, 0x7f, a, a);
 
     #[test]
     fn test_get_m() {
-        let mut cpu = CPU::new_empty();
+        let mut cpu = cpu_from([0; 0x10000]);
         cpu.regs.set_hl(1);
-        cpu.memory.write(1, 0xff);
+        cpu.memory.borrow_mut().write(1, 0xff);
         assert_eq!(cpu.get_m(), 0xff);
     }
 
     #[test]
     fn test_set_m() {
-        let mut cpu = CPU::new_empty();
+        let mut cpu = cpu_from([0; 0x10000]);
         cpu.regs.set_hl(1);
         cpu.set_m(0xff);
-        assert_eq!(cpu.memory.read(1), 0xff);
+        assert_eq!(cpu.memory.borrow().read(1), 0xff);
     }
     #[test]
     fn test_jmp() {
@@ -920,19 +2593,35 @@ mod tests {
         memory[0] = 0xc3;
         memory[1] = 0xff;
         memory[2] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         let op = cpu.fetch();
         cpu.exec(op);
         assert_eq!(cpu.pc, 0x02ff);
     }
 
+    #[test]
+    fn test_branch_cycles() {
+        // Cycles for JNZ, CNZ, RNZ and the unconditional forms, with Z
+        // clear (taken) and set (not taken)
+        for &(op, taken, not_taken) in &[(0xc2, 10, 10), (0xc3, 10, 10), (0xc4, 17, 11), (0xcd, 17, 17),
+                                          (0xc0, 11, 5), (0xc9, 10, 10)] {
+            for &(zero, expected) in &[(false, taken), (true, not_taken)] {
+                let mut memory = [0; 0x10000];
+                memory[0] = op;
+                let mut cpu = cpu_from(memory);
+                cpu.regs.set_flag(Flag::Z, zero);
+                assert_eq!(cpu.step().1, expected, "opcode {:02x}, Z={}", op, zero);
+            }
+        }
+    }
+
     #[test]
     fn test_jc() {
         let mut memory = [0; 0x10000];
         memory[0] = 0xda;
         memory[1] = 0xff;
         memory[2] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.set_flag(Flag::C, true);
         let op = cpu.fetch();
         cpu.exec(op);
@@ -945,7 +2634,7 @@ mod tests {
         memory[0] = 0xd2;
         memory[1] = 0xff;
         memory[2] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.set_flag(Flag::C, true);
         let op = cpu.fetch();
         cpu.exec(op);
@@ -958,7 +2647,7 @@ mod tests {
         memory[0] = 0xca;
         memory[1] = 0xff;
         memory[2] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.set_flag(Flag::Z, false);
         let op = cpu.fetch();
         cpu.exec(op);
@@ -971,7 +2660,7 @@ mod tests {
         memory[0] = 0xc2;
         memory[1] = 0xff;
         memory[2] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.set_flag(Flag::Z, false);
         let op = cpu.fetch();
         cpu.exec(op);
@@ -984,7 +2673,7 @@ mod tests {
         memory[0] = 0xf2;
         memory[1] = 0xff;
         memory[2] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.set_flag(Flag::S, false);
         let op = cpu.fetch();
         cpu.exec(op);
@@ -997,7 +2686,7 @@ mod tests {
         memory[0] = 0xfa;
         memory[1] = 0xff;
         memory[2] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.set_flag(Flag::S, true);
         let op = cpu.fetch();
         cpu.exec(op);
@@ -1010,7 +2699,7 @@ mod tests {
         memory[0] = 0xea;
         memory[1] = 0xff;
         memory[2] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.set_flag(Flag::P, true);
         let op = cpu.fetch();
         cpu.exec(op);
@@ -1023,7 +2712,7 @@ mod tests {
         memory[0] = 0xe2;
         memory[1] = 0xff;
         memory[2] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.set_flag(Flag::P, false);
         let op = cpu.fetch();
         cpu.exec(op);
@@ -1036,12 +2725,12 @@ mod tests {
         memory[0] = 0xcd;
         memory[1] = 0xff;
         memory[2] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         let op = cpu.fetch();
         cpu.exec(op);
         assert_eq!(cpu.pc, 0x02ff);
         assert_eq!(cpu.sp, 0xeffe);
-        assert_eq!(cpu.memory.read16(0xeffe), 0x03);
+        assert_eq!(cpu.memory.borrow().read16(0xeffe), 0x03);
     }
 
     #[test]
@@ -1050,13 +2739,13 @@ mod tests {
         memory[0] = 0xdc;
         memory[1] = 0xff;
         memory[2] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.set_flag(Flag::C, true);
         let op = cpu.fetch();
         cpu.exec(op);
         assert_eq!(cpu.pc, 0x02ff);
         assert_eq!(cpu.sp, 0xeffe);
-        assert_eq!(cpu.memory.read16(0xeffe), 0x03);
+        assert_eq!(cpu.memory.borrow().read16(0xeffe), 0x03);
     }
 
     #[test]
@@ -1065,13 +2754,13 @@ mod tests {
         memory[0] = 0xcd;
         memory[1] = 0xff;
         memory[2] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.set_flag(Flag::C, true);
         let op = cpu.fetch();
         cpu.exec(op);
         assert_eq!(cpu.pc, 0x02ff);
         assert_eq!(cpu.sp, 0xeffe);
-        assert_eq!(cpu.memory.read16(0xeffe), 0x03);
+        assert_eq!(cpu.memory.borrow().read16(0xeffe), 0x03);
     }
 
     #[test]
@@ -1080,13 +2769,13 @@ mod tests {
         memory[0] = 0xcd;
         memory[1] = 0xff;
         memory[2] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.set_flag(Flag::C, true);
         let op = cpu.fetch();
         cpu.exec(op);
         assert_eq!(cpu.pc, 0x02ff);
         assert_eq!(cpu.sp, 0xeffe);
-        assert_eq!(cpu.memory.read16(0xeffe), 0x03);
+        assert_eq!(cpu.memory.borrow().read16(0xeffe), 0x03);
     }
 
     #[test]
@@ -1095,7 +2784,7 @@ mod tests {
         memory[0] = 0x21;
         memory[1] = 0x02;
         memory[2] = 0xff;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         let op = cpu.fetch();
         cpu.exec(op);
         assert_eq!(cpu.regs.get_hl(), 0xff02);
@@ -1105,11 +2794,11 @@ mod tests {
     fn test_push() {
         let mut memory = [0x00; 0x10000];
         memory[0] = 0xd5;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.set_de(0xff02);
         let op = cpu.fetch();
         cpu.exec(op);
-        assert_eq!(cpu.memory.read16(0xf000 - 2), 0xff02);
+        assert_eq!(cpu.memory.borrow().read16(0xf000 - 2), 0xff02);
     }
 
     #[test]
@@ -1118,7 +2807,7 @@ mod tests {
         memory[0x00] = 0xd1;
         memory[0xf000 - 2] = 0xff;
         memory[0xf000 - 1] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.sp -= 2;
         let op = cpu.fetch();
         cpu.exec(op);
@@ -1129,7 +2818,7 @@ mod tests {
     fn test_xchg() {
         let mut memory = [0x00; 0x10000];
         memory[0] = 0xeb;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.set_de(0xff02);
         cpu.regs.set_hl(0x1001);
         let op = cpu.fetch();
@@ -1143,7 +2832,7 @@ mod tests {
         let mut memory = [0x00; 0x10000];
         memory[0] = 0x0e;
         memory[1] = 0xff;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         let op = cpu.fetch();
         cpu.exec(op);
         assert_eq!(cpu.regs.c, 0xff);
@@ -1154,7 +2843,7 @@ mod tests {
         let mut memory = [0x00; 0x10000];
         memory[0] = 0xe6;
         memory[1] = 0x00;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.a = 0xff;
         let op = cpu.fetch();
         cpu.exec(op);
@@ -1166,23 +2855,146 @@ mod tests {
         let mut memory = [0x00; 0x10000];
         memory[0] = 0xc6;
         memory[1] = 0x01;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.a = 0xfe;
         let op = cpu.fetch();
         cpu.exec(op);
         assert_eq!(cpu.regs.a, 0xff);
     }
 
+    #[test]
+    fn test_pop_psw_modes() {
+        // POP PSW with 0xff on the stack
+        let mut memory = [0x00; 0x10000];
+        memory[0] = 0xf1;
+        memory[0xf000] = 0xff;
+        let mut cpu = cpu_from(memory);
+        let op = cpu.fetch();
+        cpu.exec(op);
+        assert_eq!(cpu.regs.f, 0xd7);
+
+        let mut cpu = cpu_from(memory);
+        cpu.set_psw_pop(PswPop::Raw);
+        let op = cpu.fetch();
+        cpu.exec(op);
+        assert_eq!(cpu.regs.f, 0xff);
+    }
+
+    // Runs `n` instructions on any core, as a differential test would.
+    fn run_core(core: &mut impl I8080Core, n: usize) -> CpuState {
+        for _ in 0..n {
+            core.step();
+        }
+        core.state()
+    }
+
+    #[test]
+    fn test_core_state_round_trip() {
+        // MVI B, 0x12; INX B; EI
+        let mut memory = [0x00; 0x10000];
+        memory[0x100..0x104].copy_from_slice(&[0x06, 0x12, 0x03, 0xfb]);
+        let mut cpu = cpu_from(memory);
+        cpu.set_state(&CpuState { pc: 0x100, sp: 0x2400, ..CpuState::default() });
+        let state = run_core(&mut cpu, 3);
+        assert_eq!(state, CpuState { b: 0x12, c: 0x01, pc: 0x104, sp: 0x2400, inte: true, ..CpuState::default() });
+        assert!(I8080Core::interrupt_at(&mut cpu, 0x08).is_some());
+        assert_eq!(cpu.state().pc, 0x08);
+        assert_eq!(cpu.memory.borrow().read16(0x23fe), 0x104);
+    }
+
+    #[test]
+    fn test_core_interrupts_agree() {
+        // EI; NOP ... then RST 7 or a vector to 0x0038, both through the
+        // CPU itself and through the trait
+        let mut memory = [0x00; 0x10000];
+        memory[0x100] = 0xfb;
+        let start = CpuState { pc: 0x100, sp: 0x2400, ..CpuState::default() };
+        let interrupts: [fn(&mut CPU) -> Option<Event>; 4] = [
+            |cpu| cpu.interrupt(0xff),
+            |cpu| (cpu as &mut dyn I8080Core).interrupt(0xff),
+            |cpu| cpu.inter_handle(0x0038),
+            |cpu| (cpu as &mut dyn I8080Core).interrupt_at(0x0038),
+        ];
+        for interrupt in interrupts {
+            let mut cpu = cpu_from(memory);
+            cpu.set_state(&start);
+            cpu.step();
+            cpu.step();
+            assert!(interrupt(&mut cpu).is_some());
+            assert_eq!((cpu.pc, cpu.sp(), cpu.interrupts_enabled()), (0x0038, 0x23fe, false));
+            assert_eq!(cpu.memory.borrow().read16(0x23fe), 0x102);
+        }
+    }
+
+    #[test]
+    fn test_no_execute_fault() {
+        // JMP 0x2400
+        let mut memory = [0x00; 0x10000];
+        memory[0..3].copy_from_slice(&[0xc3, 0x00, 0x24]);
+        let mut cpu = cpu_from(memory);
+        cpu.memory.borrow_mut().set_no_execute(0x2400..=0x3fff);
+        let op = cpu.fetch();
+        assert!(matches!(cpu.exec(op), Event::Normal(_)));
+        for _ in 0..2 {
+            let op = cpu.fetch();
+            match cpu.exec(op) {
+                Event::Fault(fault) => assert_eq!(fault, Fault::NoExecute(0x2400)),
+                _ => panic!("expected a fault"),
+            }
+            assert_eq!(cpu.pc, 0x2400);
+        }
+    }
+
+    #[test]
+    fn test_stack_guard_fault() {
+        // LXI SP, 0x2402; PUSH B; PUSH B
+        let mut memory = [0x00; 0x10000];
+        memory[0..5].copy_from_slice(&[0x31, 0x02, 0x24, 0xc5, 0xc5]);
+        let mut cpu = cpu_from(memory);
+        cpu.memory.borrow_mut().set_guard(0x2300..=0x23ff);
+        for _ in 0..2 {
+            let op = cpu.fetch();
+            assert!(matches!(cpu.exec(op), Event::Normal(_)));
+        }
+        let op = cpu.fetch();
+        match cpu.exec(op) {
+            Event::Fault(fault) => assert_eq!(fault, Fault::Guard(0x23ff)),
+            _ => panic!("expected a fault"),
+        }
+        assert_eq!(cpu.sp, 0x23fe);
+        // LXI (10) and both PUSHes (11 each) are counted, faulting one included
+        assert_eq!(cpu.cycles(), 32);
+    }
+
+    #[test]
+    fn test_in_completes_into_a() {
+        // IN 0x01; ANI 0x04
+        let mut memory = [0x00; 0x10000];
+        memory[0..4].copy_from_slice(&[0xdb, 0x01, 0xe6, 0x04]);
+        let mut cpu = cpu_from(memory);
+        cpu.regs.f = 0x03;
+        let op = cpu.fetch();
+        match cpu.exec(op) {
+            Event::Input(port, 10) => cpu.complete_input(if port == 0x01 { 0x0c } else { 0x00 }),
+            _ => panic!("expected an input event"),
+        }
+        assert_eq!(cpu.regs.a, 0x0c);
+        assert_eq!(cpu.regs.f, 0x03);
+        let op = cpu.fetch();
+        cpu.exec(op);
+        assert_eq!(cpu.regs.a, 0x04);
+    }
+
     #[test]
     fn test_cpi() {
         let mut memory = [0x00; 0x10000];
         memory[0] = 0xfe;
         memory[1] = 0x02;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         cpu.regs.a = 0x01;
         let op = cpu.fetch();
         cpu.exec(op);
-        assert_eq!(cpu.regs.f, 0x97);
+        assert_eq!(cpu.regs.f, 0x87);
     }
 
     #[test]
@@ -1192,9 +3004,120 @@ mod tests {
         memory[1] = 0x02;
         memory[2] = 0xff;
         memory[0xff02] = 0xff;
-        let mut cpu = CPU::new(memory);
+        let mut cpu = cpu_from(memory);
         let op = cpu.fetch();
         cpu.exec(op);
         assert_eq!(cpu.regs.a, 0xff);
     }
+
+    // Loads `program` at address 0 and runs it to completion, one step per
+    // instruction.
+    fn run_program(cpu: &mut CPU, program: &[u8]) {
+        for (i, b) in program.iter().enumerate() {
+            cpu.memory.borrow_mut().write(i, *b);
+        }
+        cpu.pc = 0;
+        while usize::from(cpu.pc) < program.len() {
+            let op = cpu.fetch();
+            cpu.exec(op);
+        }
+    }
+
+    // The register pair selected by the `rp` field of an opcode, with SP as
+    // the fourth pair.
+    fn get_rp(cpu: &CPU, rp: u8) -> u16 {
+        match rp {
+            0 => cpu.regs.get_bc(),
+            1 => cpu.regs.get_de(),
+            2 => cpu.regs.get_hl(),
+            _ => cpu.sp,
+        }
+    }
+
+    fn set_rp(cpu: &mut CPU, rp: u8, data: u16) {
+        match rp {
+            0 => cpu.regs.set_bc(data),
+            1 => cpu.regs.set_de(data),
+            2 => cpu.regs.set_hl(data),
+            _ => cpu.sp = data,
+        }
+    }
+
+    // Stack and data addresses stay clear of the program at the bottom of
+    // memory and of the last byte, where 16-bit accesses run off the end.
+    proptest! {
+        #[test]
+        fn prop_push_pop_moves_pair(rp in 0u8..3, data: u16, sp in 0x0102u16..=0xffff) {
+            // PUSH rp; POP D
+            let mut cpu = cpu_from([0x00; 0x10000]);
+            set_rp(&mut cpu, rp, data);
+            cpu.sp = sp;
+            run_program(&mut cpu, &[0xc5 | rp << 4, 0xd1]);
+            prop_assert_eq!(cpu.regs.get_de(), data);
+            prop_assert_eq!(cpu.sp, sp);
+        }
+
+        #[test]
+        fn prop_push_pop_psw(af: u16, sp in 0x0102u16..=0xffff) {
+            // PUSH PSW; MVI A, 0x00; POP PSW
+            let mut cpu = cpu_from([0x00; 0x10000]);
+            cpu.regs.set_af(af);
+            let expected = cpu.regs.get_af();
+            cpu.sp = sp;
+            run_program(&mut cpu, &[0xf5, 0x3e, 0x00, 0xf1]);
+            prop_assert_eq!(cpu.regs.get_af(), expected);
+            prop_assert_eq!(cpu.sp, sp);
+        }
+
+        #[test]
+        fn prop_inx_dcx_identity(rp in 0u8..4, data: u16) {
+            let mut cpu = cpu_from([0x00; 0x10000]);
+            set_rp(&mut cpu, rp, data);
+            run_program(&mut cpu, &[0x03 | rp << 4]);
+            prop_assert_eq!(get_rp(&cpu, rp), data.wrapping_add(1));
+            run_program(&mut cpu, &[0x0b | rp << 4]);
+            prop_assert_eq!(get_rp(&cpu, rp), data);
+        }
+
+        #[test]
+        fn prop_dad_adds_modulo_2_16(rp in 0u8..4, hl: u16, data: u16) {
+            let mut cpu = cpu_from([0x00; 0x10000]);
+            set_rp(&mut cpu, rp, data);
+            cpu.regs.set_hl(hl);
+            let addend = get_rp(&cpu, rp);
+            let flags = cpu.regs.f;
+            run_program(&mut cpu, &[0x09 | rp << 4]);
+            let (sum, carry) = hl.overflowing_add(addend);
+            prop_assert_eq!(cpu.regs.get_hl(), sum);
+            prop_assert_eq!(cpu.regs.get_flag(Flag::C), carry);
+            prop_assert_eq!(cpu.regs.f & !0x01, flags & !0x01);
+        }
+
+        #[test]
+        fn prop_xthl_swaps_with_stack_top(hl: u16, top: u16, sp in 0x0100u16..0xffff) {
+            let mut cpu = cpu_from([0x00; 0x10000]);
+            cpu.regs.set_hl(hl);
+            cpu.sp = sp;
+            cpu.memory.borrow_mut().write16(sp.into(), top);
+            run_program(&mut cpu, &[0xe3]);
+            prop_assert_eq!(cpu.regs.get_hl(), top);
+            prop_assert_eq!(cpu.memory.borrow().read16(sp.into()), hl);
+            run_program(&mut cpu, &[0xe3]);
+            prop_assert_eq!(cpu.regs.get_hl(), hl);
+            prop_assert_eq!(cpu.memory.borrow().read16(sp.into()), top);
+            prop_assert_eq!(cpu.sp, sp);
+        }
+
+        #[test]
+        fn prop_shld_lhld_round_trip(hl: u16, addr in 0x0100u16..0xffff) {
+            // SHLD addr; LXI H, 0x0000; LHLD addr
+            let [lo, hi] = addr.to_le_bytes();
+            let mut cpu = cpu_from([0x00; 0x10000]);
+            cpu.regs.set_hl(hl);
+            run_program(&mut cpu, &[0x22, lo, hi, 0x21, 0x00, 0x00, 0x2a, lo, hi]);
+            prop_assert_eq!(cpu.regs.get_hl(), hl);
+            prop_assert_eq!(cpu.memory.borrow().read(addr.into()), hl.to_le_bytes()[0]);
+            prop_assert_eq!(cpu.memory.borrow().read(usize::from(addr) + 1), hl.to_le_bytes()[1]);
+        }
+    }
 }
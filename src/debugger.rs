@@ -0,0 +1,334 @@
+//! Breakpoints and watchpoints for stopping a running program.
+//!
+//! A `Debugger` owns a CPU and the port bus it talks to and runs them until
+//! something it was told to look out for happens: PC reaching a
+//! breakpoint, a watched memory range being read or written, or an IN or
+//! OUT on a watched port. Memory watchpoints are checked by `Memory8080`
+//! itself on every access the CPU makes, so they catch the stray write
+//! whatever instruction makes it.
+//!
+//! With rewind on, every step also records what it is about to change,
+//! and `step_back` undoes steps one at a time, newest first.
+//!
+//! `assemble` patches the program as it stands, one line of assembly at
+//! a time, for trying out a fix without rebuilding the ROM.
+//!
+//! ```
+//! use i8080_emulator::cpu::CPU;
+//! use i8080_emulator::debugger::{Debugger, StopReason};
+//! use i8080_emulator::io::PortBus;
+//! use i8080_emulator::memory::{Access, Memory8080};
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! // loop: INR M; INX H; JMP loop
+//! let mut memory = [0x00; 0x10000];
+//! memory[0x100..0x105].copy_from_slice(&[0x34, 0x23, 0xc3, 0x00, 0x01]);
+//! let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+//! cpu.pc = 0x100;
+//! cpu.regs.set_hl(0x2000);
+//! let mut debugger = Debugger::new(cpu, PortBus::new());
+//! debugger.watch(0x2004..=0x2004, Access::Write);
+//! assert_eq!(debugger.run(), StopReason::Watchpoint { pc: 0x100, addr: 0x2004, access: Access::Write });
+//! ```
+
+use crate::assembler::{self, AsmError};
+use crate::cpu::{CpuState, Event, Fault, Timestamp, CPU};
+use crate::io::PortBus;
+use crate::memory::Access;
+use crate::I8080Core;
+
+use std::collections::{BTreeSet, VecDeque};
+use std::ops::RangeInclusive;
+
+/// Why `Debugger::run` or `Debugger::step` stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// PC reached a breakpoint. The instruction there has not run yet.
+    Breakpoint(u16),
+    /// The instruction at `pc` accessed a watched address. It has run
+    /// to completion.
+    Watchpoint { pc: u16, addr: u16, access: Access },
+    /// The instruction at `pc` read (IN) or wrote (OUT) a watched port.
+    /// The access has been passed on to the port bus.
+    Port { pc: u16, port: u8, access: Access },
+    /// HLT at `pc` ran.
+    Halted(u16),
+    Fault(Fault),
+    /// The single step asked for is done and nothing else happened.
+    Step,
+}
+
+/// A CPU and its port bus under the control of breakpoints and
+/// watchpoints. See the module documentation.
+pub struct Debugger {
+    pub cpu: CPU,
+    pub bus: PortBus,
+    breakpoints: BTreeSet<u16>,
+    ports: BTreeSet<(u8, bool)>,
+    rewind: Option<Rewind>,
+}
+
+// The last steps taken, newest at the back, with what undoes them
+struct Rewind {
+    depth: usize,
+    steps: VecDeque<Undo>,
+}
+
+struct Undo {
+    state: CpuState,
+    cycles: Timestamp,
+    writes: Vec<(u16, u8)>,
+}
+
+impl Debugger {
+    pub fn new(cpu: CPU, bus: PortBus) -> Self {
+        Debugger {
+            cpu,
+            bus,
+            breakpoints: BTreeSet::new(),
+            ports: BTreeSet::new(),
+            rewind: None,
+        }
+    }
+
+    /// Keeps what is needed to undo the last `depth` steps; 0 turns
+    /// rewind off. Only the CPU and memory are wound back: devices on the
+    /// port bus keep whatever state IN and OUT left them in.
+    pub fn set_rewind_depth(&mut self, depth: usize) {
+        self.cpu.memory.borrow_mut().set_journal(depth > 0);
+        self.rewind = match depth {
+            0 => None,
+            depth => Some(Rewind { depth, steps: VecDeque::new() }),
+        };
+    }
+
+    /// How many steps `step_back` can undo.
+    pub fn rewind_len(&self) -> usize {
+        self.rewind.as_ref().map_or(0, |rewind| rewind.steps.len())
+    }
+
+    /// Undoes the last step, putting back the registers, the cycle count
+    /// and every byte it wrote. Returns `false`, changing nothing, if
+    /// there is no step left to undo.
+    pub fn step_back(&mut self) -> bool {
+        let undo = match self.rewind.as_mut().and_then(|rewind| rewind.steps.pop_back()) {
+            Some(undo) => undo,
+            None => return false,
+        };
+        self.cpu.memory.borrow_mut().undo(&undo.writes);
+        self.cpu.set_state(&undo.state);
+        self.cpu.set_cycles(undo.cycles);
+        true
+    }
+
+    /// Assembles `line`, a single statement in the syntax of the
+    /// `assembler` module, and writes it into memory at `addr`, ROM or
+    /// not. Returns the address just past what was written, where the
+    /// next line would go. The patch is not undone by `step_back`.
+    pub fn assemble(&mut self, addr: u16, line: &str) -> Result<u16, AsmError> {
+        let code = assembler::assemble(line, addr)?;
+        self.cpu.memory.borrow_mut().patch(addr, &code);
+        Ok(addr.wrapping_add(code.len() as u16))
+    }
+
+    /// Stops before the instruction at `addr` runs.
+    pub fn set_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn clear_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Stops after any instruction that accesses `range` going `access`'s
+    /// way. Instruction fetches count as reads.
+    pub fn watch(&mut self, range: RangeInclusive<u16>, access: Access) {
+        self.cpu.memory.borrow_mut().set_watch(range, access);
+    }
+
+    /// Removes a watchpoint set with exactly the same arguments.
+    pub fn unwatch(&mut self, range: RangeInclusive<u16>, access: Access) {
+        self.cpu.memory.borrow_mut().clear_watch(range, access);
+    }
+
+    /// Stops after any IN from (`Access::Read`) or OUT to
+    /// (`Access::Write`) `port`.
+    pub fn watch_port(&mut self, port: u8, access: Access) {
+        self.ports.insert((port, access == Access::Write));
+    }
+
+    pub fn unwatch_port(&mut self, port: u8, access: Access) {
+        self.ports.remove(&(port, access == Access::Write));
+    }
+
+    /// Executes one instruction, ignoring any breakpoint at PC, and says
+    /// what it ran into.
+    pub fn step(&mut self) -> StopReason {
+        let pc = self.cpu.pc;
+        let before = (self.cpu.state(), self.cpu.cycles());
+        let event = self.bus.step(&mut self.cpu);
+        if let Some(rewind) = &mut self.rewind {
+            if rewind.steps.len() == rewind.depth {
+                rewind.steps.pop_front();
+            }
+            let writes = self.cpu.memory.borrow_mut().take_journal();
+            rewind.steps.push_back(Undo { state: before.0, cycles: before.1, writes });
+        }
+        if let Some((addr, access)) = self.cpu.memory.borrow().take_watch_hit() {
+            return StopReason::Watchpoint { pc, addr, access };
+        }
+        match event {
+            Event::Fault(fault) => StopReason::Fault(fault),
+            Event::Halt(_) => StopReason::Halted(pc),
+            Event::Input(port, _) if self.ports.contains(&(port, false)) => {
+                StopReason::Port { pc, port, access: Access::Read }
+            }
+            Event::Output(port, _, _) if self.ports.contains(&(port, true)) => {
+                StopReason::Port { pc, port, access: Access::Write }
+            }
+            _ => StopReason::Step,
+        }
+    }
+
+    /// Runs until a breakpoint, watchpoint, HLT or fault stops the
+    /// program. A breakpoint at PC when called is stepped over, so calling
+    /// `run` again resumes after stopping at one. A CPU already halted
+    /// stops straight away, as nothing here raises an interrupt to wake
+    /// it.
+    pub fn run(&mut self) -> StopReason {
+        loop {
+            if self.cpu.is_halted() {
+                return StopReason::Halted(self.cpu.pc.wrapping_sub(1));
+            }
+            match self.step() {
+                StopReason::Step => {}
+                reason => return reason,
+            }
+            if self.breakpoints.contains(&self.cpu.pc) {
+                return StopReason::Breakpoint(self.cpu.pc);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::AsmErrorKind;
+    use crate::cpu::{Fault, CPU};
+    use crate::debugger::{Debugger, StopReason};
+    use crate::io::PortBus;
+    use crate::memory::{Access, Memory, Memory8080, VectorPagePolicy};
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn debugger(code: &[u8]) -> Debugger {
+        let mut memory = [0x00; 0x10000];
+        memory[..code.len()].copy_from_slice(code);
+        Debugger::new(CPU::new(Rc::new(RefCell::new(Memory8080::new(memory)))), PortBus::new())
+    }
+
+    #[test]
+    fn breakpoints() {
+        // loop: INR B; JMP loop
+        let mut debugger = debugger(&[0x04, 0xc3, 0x00, 0x00]);
+        debugger.set_breakpoint(0x0000);
+        debugger.set_breakpoint(0x0001);
+        assert_eq!(debugger.run(), StopReason::Breakpoint(0x0001));
+        assert_eq!(debugger.run(), StopReason::Breakpoint(0x0000));
+        assert_eq!(debugger.run(), StopReason::Breakpoint(0x0001));
+        assert_eq!(debugger.cpu.regs.b, 2);
+        debugger.clear_breakpoint(0x0001);
+        assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), [0x0000]);
+        assert_eq!(debugger.run(), StopReason::Breakpoint(0x0000));
+        assert_eq!(debugger.cpu.regs.b, 2);
+    }
+
+    #[test]
+    fn watchpoints() {
+        // LDA 0x3000; STA 0x3001; OUT 0x10; IN 0x11; HLT
+        let mut debugger = debugger(&[0x3a, 0x00, 0x30, 0x32, 0x01, 0x30, 0xd3, 0x10, 0xdb, 0x11, 0x76]);
+        debugger.watch(0x3000..=0x3001, Access::Read);
+        debugger.watch(0x3001..=0x3001, Access::Write);
+        debugger.watch_port(0x10, Access::Write);
+        debugger.watch_port(0x11, Access::Read);
+        assert_eq!(debugger.run(), StopReason::Watchpoint { pc: 0x0000, addr: 0x3000, access: Access::Read });
+        assert_eq!(debugger.run(), StopReason::Watchpoint { pc: 0x0003, addr: 0x3001, access: Access::Write });
+        assert_eq!(debugger.run(), StopReason::Port { pc: 0x0006, port: 0x10, access: Access::Write });
+        assert_eq!(debugger.run(), StopReason::Port { pc: 0x0008, port: 0x11, access: Access::Read });
+        assert_eq!(debugger.run(), StopReason::Halted(0x000a));
+        assert_eq!(debugger.run(), StopReason::Halted(0x000a));
+    }
+
+    #[test]
+    fn step_back() {
+        // LXI SP, 0x3000; loop: INR B; PUSH B; SHLD 0x2000; JMP loop
+        let mut debugger = debugger(&[0x31, 0x00, 0x30, 0x04, 0xc5, 0x22, 0x00, 0x20, 0xc3, 0x03, 0x00]);
+        assert!(!debugger.step_back());
+        debugger.set_rewind_depth(6);
+        debugger.step();
+        let start = debugger.cpu.save_state().unwrap();
+        for _ in 0..5 {
+            debugger.step();
+        }
+        assert_eq!(debugger.rewind_len(), 6);
+        let end = debugger.cpu.save_state().unwrap();
+        assert_eq!(debugger.cpu.regs.b, 2);
+
+        for _ in 0..5 {
+            assert!(debugger.step_back());
+        }
+        assert_eq!(debugger.cpu.save_state().unwrap(), start);
+        for _ in 0..5 {
+            debugger.step();
+        }
+        assert_eq!(debugger.cpu.save_state().unwrap(), end);
+
+        // Only the last 6 steps are kept
+        for _ in 0..10 {
+            debugger.step();
+        }
+        for _ in 0..6 {
+            assert!(debugger.step_back());
+        }
+        assert!(!debugger.step_back());
+        assert_eq!(debugger.cpu.pc, 0x0004);
+
+        debugger.set_rewind_depth(0);
+        debugger.step();
+        assert_eq!(debugger.rewind_len(), 0);
+    }
+
+    #[test]
+    fn assemble() {
+        // loop: INR B; JMP loop
+        let mut debugger = debugger(&[0x04, 0xc3, 0x00, 0x00]);
+        debugger.cpu.memory.borrow_mut().set_vector_page_policy(VectorPagePolicy::Ignore);
+        debugger.set_rewind_depth(4);
+        debugger.step();
+        assert_eq!(debugger.assemble(0x0001, "JMP 0x2000"), Ok(0x0004));
+        assert_eq!(debugger.assemble(0x2000, "MVI B, 0x80 ; patched"), Ok(0x2002));
+        debugger.set_breakpoint(0x2002);
+        assert_eq!(debugger.run(), StopReason::Breakpoint(0x2002));
+        assert_eq!(debugger.cpu.regs.b, 0x80);
+        while debugger.step_back() {}
+        assert_eq!(debugger.cpu.memory.borrow().read16(0x0002), 0x2000);
+
+        let error = debugger.assemble(0x2000, "JMP nowhere").unwrap_err();
+        assert_eq!(error.kind, AsmErrorKind::UndefinedSymbol("NOWHERE".to_string()));
+        assert_eq!(debugger.cpu.memory.borrow().read(0x2000), 0x06);
+    }
+
+    #[test]
+    fn faults_stop_the_run() {
+        let mut debugger = debugger(&[0x00, 0x00]);
+        debugger.cpu.memory.borrow_mut().set_no_execute(0x0001..=0x0001);
+        assert_eq!(debugger.run(), StopReason::Fault(Fault::NoExecute(0x0001)));
+        assert_eq!(debugger.cpu.pc, 0x0001);
+    }
+}
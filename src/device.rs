@@ -1,5 +1,331 @@
-pub trait Device<T> {
-    // Better names...
-    fn fetch(&mut self) -> u8;
-    fn exec(&mut self, op: u8) -> T;
+use crate::cpu::ClockCycles;
+
+use alloc::vec::Vec;
+use core::error::Error;
+use core::fmt;
+
+/// A peripheral attached to the CPU's I/O ports.
+///
+/// The machine owning the device forwards IN and OUT instructions to
+/// `read` and `write`, advances it with `tick` by the cycles each
+/// instruction took, and calls `reset` when the whole machine is reset.
+pub trait Device {
+    /// Puts the device back into its power-on state.
+    fn reset(&mut self) {}
+
+    /// Advances the device's notion of time by `cycles` CPU clock cycles.
+    fn tick(&mut self, _cycles: ClockCycles) {}
+
+    /// Handles an IN from `port`, returning the byte placed on the bus.
+    fn read(&mut self, port: u8) -> u8;
+
+    /// Handles an OUT of `value` to `port`.
+    fn write(&mut self, port: u8, value: u8);
+
+    /// The device's state, to save next to the CPU's, or `None` for a
+    /// device with nothing worth saving. Where the device is wired to,
+    /// such as its ports or a host stream, is left to whoever builds the
+    /// machine.
+    fn save_state(&self) -> Option<DeviceState> {
+        None
+    }
+
+    /// Puts back a state of the device's current version, leaving the
+    /// device as it was if the state is corrupt. Go through `restore`,
+    /// which checks the id and migrates older states first.
+    fn load_state(&mut self, _state: &DeviceState) -> Result<(), StateError> {
+        Err(StateError::Unsupported)
+    }
+
+    /// Turns `state`, saved by an older version of the device, into a
+    /// later version, for `restore` to call until it is current. A device
+    /// changing its format bumps its version and converts the old one
+    /// here, so old save states keep loading; by default there are no
+    /// older versions.
+    fn migrate(&self, state: DeviceState) -> Result<DeviceState, StateError> {
+        Err(StateError::Version(state.version))
+    }
+}
+
+/// Names the format of a device's saved state. It stays the same for as
+/// long as the device exists, whatever its version. The devices in this
+/// crate have theirs as `STATE_ID`, next to `STATE_VERSION`, such as
+/// `Timer::STATE_ID`, `*b"8253"`.
+pub type DeviceId = [u8; 4];
+
+/// A device's state as `Device::save_state` saved it: which device, the
+/// version of its format and the fields, written in order a
+/// little-endian `push` at a time and read back the same way with
+/// `reader`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceState {
+    pub id: DeviceId,
+    pub version: u16,
+    pub data: Vec<u8>,
+}
+
+impl DeviceState {
+    pub fn new(id: DeviceId, version: u16) -> Self {
+        DeviceState { id, version, data: Vec::new() }
+    }
+
+    pub fn push_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn push_bool(&mut self, value: bool) {
+        self.data.push(u8::from(value));
+    }
+
+    pub fn push_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn push_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn push_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Pushes the length of `bytes` as a u32, then the bytes.
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.push_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn reader(&self) -> StateReader<'_> {
+        StateReader { data: &self.data }
+    }
+}
+
+/// Reads the fields of a `DeviceState` back in the order they were
+/// pushed. Running out of data is `StateError::Corrupt`.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        if self.data.len() < N {
+            return Err(StateError::Corrupt);
+        }
+        let (bytes, rest) = self.data.split_at(N);
+        self.data = rest;
+        let mut array = [0x00; N];
+        array.copy_from_slice(bytes);
+        Ok(array)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    /// Anything but 0 or 1 is corrupt.
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    /// Bytes pushed with `push_bytes`.
+    pub fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.u32()? as usize;
+        if self.data.len() < len {
+            return Err(StateError::Corrupt);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// Checks that every field has been read, so that a state with bytes
+    /// left over is corrupt too.
+    pub fn end(self) -> Result<(), StateError> {
+        match self.data.is_empty() {
+            true => Ok(()),
+            false => Err(StateError::Corrupt),
+        }
+    }
+}
+
+/// Why a device state could not be loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// The state was saved by another kind of device.
+    WrongDevice { expected: DeviceId, found: DeviceId },
+    /// The state has a version the device cannot migrate from, such as
+    /// one newer than the device.
+    Version(u16),
+    /// The state ends early, has bytes left over or holds a value the
+    /// device cannot have.
+    Corrupt,
+    /// The device does not save its state.
+    Unsupported,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let id = |id: &DeviceId| id.iter().map(|&byte| char::from(byte)).collect::<alloc::string::String>();
+        match self {
+            StateError::WrongDevice { expected, found } => {
+                write!(f, "state of a {} device given to a {} device", id(found), id(expected))
+            }
+            StateError::Version(version) => write!(f, "cannot load version {} of the device state", version),
+            StateError::Corrupt => f.write_str("device state is corrupt"),
+            StateError::Unsupported => f.write_str("device has no state to load"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+/// Loads `state` into `device`, first migrating it to the device's
+/// current version if it is older.
+pub fn restore<D: Device + ?Sized>(device: &mut D, mut state: DeviceState) -> Result<(), StateError> {
+    let current = device.save_state().ok_or(StateError::Unsupported)?;
+    if state.id != current.id {
+        return Err(StateError::WrongDevice { expected: current.id, found: state.id });
+    }
+    while state.version < current.version {
+        let version = state.version;
+        state = device.migrate(state)?;
+        // A migration that goes nowhere would loop forever
+        if state.id != current.id || state.version <= version {
+            return Err(StateError::Version(version));
+        }
+    }
+    if state.version != current.version {
+        return Err(StateError::Version(state.version));
+    }
+    device.load_state(&state)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{restore, Device, DeviceState, StateError};
+
+    // Latches the last byte written and counts elapsed cycles
+    struct Latch {
+        value: u8,
+        cycles: u32,
+    }
+
+    impl Device for Latch {
+        fn reset(&mut self) {
+            self.value = 0;
+            self.cycles = 0;
+        }
+
+        fn tick(&mut self, cycles: u32) {
+            self.cycles += cycles;
+        }
+
+        fn read(&mut self, _port: u8) -> u8 {
+            self.value
+        }
+
+        fn write(&mut self, _port: u8, value: u8) {
+            self.value = value;
+        }
+
+        // Version 1 had only the value
+        fn save_state(&self) -> Option<DeviceState> {
+            let mut state = DeviceState::new(*b"LTCH", 2);
+            state.push_u8(self.value);
+            state.push_u32(self.cycles);
+            Some(state)
+        }
+
+        fn load_state(&mut self, state: &DeviceState) -> Result<(), StateError> {
+            let mut fields = state.reader();
+            let (value, cycles) = (fields.u8()?, fields.u32()?);
+            fields.end()?;
+            *self = Latch { value, cycles };
+            Ok(())
+        }
+
+        fn migrate(&self, mut state: DeviceState) -> Result<DeviceState, StateError> {
+            match state.version {
+                1 => {
+                    state.version = 2;
+                    state.push_u32(0);
+                    Ok(state)
+                }
+                version => Err(StateError::Version(version)),
+            }
+        }
+    }
+
+    #[test]
+    fn lifecycle() {
+        let mut latch = Latch { value: 0, cycles: 0 };
+        latch.write(0x01, 0x42);
+        latch.tick(10);
+        assert_eq!(latch.read(0x01), 0x42);
+        assert_eq!(latch.cycles, 10);
+        latch.reset();
+        assert_eq!(latch.read(0x01), 0x00);
+        assert_eq!(latch.cycles, 0);
+    }
+
+    #[test]
+    fn default_tick_and_reset() {
+        struct Constant;
+        impl Device for Constant {
+            fn read(&mut self, _port: u8) -> u8 {
+                0xff
+            }
+            fn write(&mut self, _port: u8, _value: u8) {}
+        }
+
+        let mut device = Constant;
+        device.tick(100);
+        device.reset();
+        assert_eq!(device.read(0), 0xff);
+    }
+
+    #[test]
+    fn save_and_restore() {
+        let mut latch = Latch { value: 0x42, cycles: 10 };
+        let saved = latch.save_state().unwrap();
+        latch.reset();
+        restore(&mut latch, saved.clone()).unwrap();
+        assert_eq!((latch.value, latch.cycles), (0x42, 10));
+
+        let mut old = DeviceState::new(*b"LTCH", 1);
+        old.push_u8(0x17);
+        restore(&mut latch, old).unwrap();
+        assert_eq!((latch.value, latch.cycles), (0x17, 0));
+
+        let mut wrong = saved.clone();
+        wrong.id = *b"TIMR";
+        assert_eq!(restore(&mut latch, wrong), Err(StateError::WrongDevice { expected: *b"LTCH", found: *b"TIMR" }));
+        for version in [0, 3] {
+            let state = DeviceState { version, ..saved.clone() };
+            assert_eq!(restore(&mut latch, state), Err(StateError::Version(version)));
+        }
+        let mut long = saved.clone();
+        long.push_u8(0);
+        assert_eq!(restore(&mut latch, long), Err(StateError::Corrupt));
+        let short = DeviceState { data: saved.data[..4].to_vec(), ..saved };
+        assert_eq!(restore(&mut latch, short), Err(StateError::Corrupt));
+        assert_eq!((latch.value, latch.cycles), (0x17, 0));
+    }
 }
@@ -0,0 +1,192 @@
+use crate::device::{Device, DeviceId, DeviceState, StateError};
+
+use std::collections::BTreeMap;
+
+/// Commands a guest program writes to the command port.
+pub mod cmd {
+    /// Ask the host to take a snapshot of the machine.
+    pub const SNAPSHOT: u8 = 0x01;
+    /// Drop a marker with the current argument into the trace.
+    pub const MARKER: u8 = 0x02;
+    /// Report that the checkpoint numbered by the argument was reached.
+    pub const CHECKPOINT: u8 = 0x03;
+    /// Report that a self-checking test passed.
+    pub const PASS: u8 = 0x04;
+    /// Report that a self-checking test failed, with the argument as the
+    /// failure code.
+    pub const FAIL: u8 = 0x05;
+    /// Start a span named by the current argument in the trace.
+    pub const SPAN_BEGIN: u8 = 0x06;
+    /// End the span started last.
+    pub const SPAN_END: u8 = 0x07;
+}
+
+/// Value read back from the command port, so guest code can tell it is
+/// running under this emulator before using the port.
+pub const PRESENT: u8 = 0xe8;
+
+/// Something the guest asked the host to do through the control port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlRequest {
+    Snapshot,
+    Marker(u8),
+    Checkpoint(u8),
+    Pass,
+    Fail(u8),
+    SpanBegin(u8),
+    SpanEnd,
+    /// A command byte this device does not know.
+    Unknown(u8),
+}
+
+/// An "emulator trapdoor" on two consecutive ports that lets guest code
+/// talk to the host: `port + 1` latches an argument and writing a command
+/// (see `cmd`) to `port` queues a request. The host picks requests up with
+/// `take_requests`, typically after each OUT.
+///
+/// ```text
+///       MVI A, 7
+///       OUT 0xff      ; argument
+///       MVI A, 3
+///       OUT 0xfe      ; checkpoint 7 reached
+/// ```
+///
+/// Markers and spans are numbered by the guest; the host can give the
+/// numbers names with `set_label` for the trace to show.
+///
+/// Real hardware has nothing on these ports, so only attach this device
+/// for test and development builds of guest software.
+pub struct ControlPort {
+    port: u8,
+    argument: u8,
+    requests: Vec<ControlRequest>,
+    labels: BTreeMap<u8, String>,
+}
+
+impl ControlPort {
+    pub const STATE_ID: DeviceId = *b"CTRL";
+    pub const STATE_VERSION: u16 = 1;
+
+    pub fn new(port: u8) -> Self {
+        ControlPort {
+            port,
+            argument: 0,
+            requests: Vec::new(),
+            labels: BTreeMap::new(),
+        }
+    }
+
+    /// Names the marker or span the guest numbers `id`.
+    pub fn set_label(&mut self, id: u8, label: &str) {
+        self.labels.insert(id, label.to_string());
+    }
+
+    /// The name given to marker or span `id`, or "marker" and the number
+    /// if it has none.
+    pub fn label(&self, id: u8) -> String {
+        match self.labels.get(&id) {
+            Some(label) => label.clone(),
+            None => format!("marker {}", id),
+        }
+    }
+
+    /// Returns the requests made since the last call, oldest first.
+    pub fn take_requests(&mut self) -> Vec<ControlRequest> {
+        std::mem::take(&mut self.requests)
+    }
+}
+
+impl Device for ControlPort {
+    fn reset(&mut self) {
+        self.argument = 0;
+        self.requests.clear();
+    }
+
+    fn read(&mut self, port: u8) -> u8 {
+        if port == self.port {
+            PRESENT
+        } else if port == self.port.wrapping_add(1) {
+            self.argument
+        } else {
+            0xff
+        }
+    }
+
+    fn write(&mut self, port: u8, value: u8) {
+        if port == self.port.wrapping_add(1) {
+            self.argument = value;
+        } else if port == self.port {
+            self.requests.push(match value {
+                cmd::SNAPSHOT => ControlRequest::Snapshot,
+                cmd::MARKER => ControlRequest::Marker(self.argument),
+                cmd::CHECKPOINT => ControlRequest::Checkpoint(self.argument),
+                cmd::PASS => ControlRequest::Pass,
+                cmd::FAIL => ControlRequest::Fail(self.argument),
+                cmd::SPAN_BEGIN => ControlRequest::SpanBegin(self.argument),
+                cmd::SPAN_END => ControlRequest::SpanEnd,
+                other => ControlRequest::Unknown(other),
+            });
+        }
+    }
+
+    /// The latched argument. Requests not yet taken and labels belong to
+    /// the host.
+    fn save_state(&self) -> Option<DeviceState> {
+        let mut state = DeviceState::new(ControlPort::STATE_ID, ControlPort::STATE_VERSION);
+        state.push_u8(self.argument);
+        Some(state)
+    }
+
+    fn load_state(&mut self, state: &DeviceState) -> Result<(), StateError> {
+        let mut fields = state.reader();
+        let argument = fields.u8()?;
+        fields.end()?;
+        self.argument = argument;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::Device;
+    use crate::devices::control::{cmd, ControlPort, ControlRequest, PRESENT};
+
+    #[test]
+    fn queues_requests_with_argument() {
+        let mut control = ControlPort::new(0xfe);
+        control.write(0xfe, cmd::SNAPSHOT);
+        control.write(0xff, 7);
+        control.write(0xfe, cmd::CHECKPOINT);
+        control.write(0xfe, cmd::FAIL);
+        control.write(0xfe, 0x42);
+        assert_eq!(control.take_requests(), [
+            ControlRequest::Snapshot,
+            ControlRequest::Checkpoint(7),
+            ControlRequest::Fail(7),
+            ControlRequest::Unknown(0x42),
+        ]);
+        assert!(control.take_requests().is_empty());
+    }
+
+    #[test]
+    fn spans_and_labels() {
+        let mut control = ControlPort::new(0xfe);
+        control.set_label(3, "level load");
+        control.write(0xff, 3);
+        control.write(0xfe, cmd::SPAN_BEGIN);
+        control.write(0xfe, cmd::SPAN_END);
+        assert_eq!(control.take_requests(), [ControlRequest::SpanBegin(3), ControlRequest::SpanEnd]);
+        assert_eq!(control.label(3), "level load");
+        assert_eq!(control.label(4), "marker 4");
+    }
+
+    #[test]
+    fn detectable_by_guest() {
+        let mut control = ControlPort::new(0xfe);
+        control.write(0xff, 0x12);
+        assert_eq!(control.read(0xfe), PRESENT);
+        assert_eq!(control.read(0xff), 0x12);
+        control.reset();
+        assert_eq!(control.read(0xff), 0x00);
+    }
+}
@@ -0,0 +1,808 @@
+use crate::cpu::CPU;
+use crate::device::{Device, DeviceId, DeviceState, StateError};
+use crate::loader::LoadError;
+use crate::memory::{Memory, Memory8080};
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+/// Ports of a `DiskController`, as offsets from its base port.
+pub mod port {
+    /// Selects the drive, 0 for A.
+    pub const DRIVE: u8 = 0;
+    pub const TRACK: u8 = 1;
+    pub const SECTOR: u8 = 2;
+    /// Writing starts a command (see `READ` and `WRITE`); reading returns
+    /// the status of the last one, 0 if it succeeded or a `DiskError` code.
+    pub const COMMAND: u8 = 3;
+    pub const DMA_LOW: u8 = 4;
+    pub const DMA_HIGH: u8 = 5;
+
+    /// Reads the selected sector into memory at the DMA address.
+    pub const READ: u8 = 0;
+    /// Writes the selected sector from memory at the DMA address.
+    pub const WRITE: u8 = 1;
+}
+
+/// Drives a `DiskController` has, A to P.
+pub const DRIVES: usize = 16;
+
+/// What unformatted parts of an image read as, the byte CP/M formats
+/// disks with.
+pub const EMPTY: u8 = 0xe5;
+
+/// The layout of a disk image: sectors are stored track by track, in
+/// order, with no headers or gaps in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub tracks: u16,
+    /// Sectors per track.
+    pub sectors: u16,
+    /// Bytes per sector.
+    pub sector_size: usize,
+    /// Number of the first sector on a track, usually 1 for floppies.
+    pub first_sector: u16,
+}
+
+impl Geometry {
+    /// The 8" single-sided, single-density disk CP/M 2.2 was distributed
+    /// on: 77 tracks of 26 sectors of 128 bytes, 256256 bytes in all.
+    pub const IBM_3740: Geometry = Geometry { tracks: 77, sectors: 26, sector_size: 128, first_sector: 1 };
+
+    /// Size of a full image in bytes.
+    pub fn size(&self) -> u64 {
+        u64::from(self.tracks) * u64::from(self.sectors) * self.sector_size as u64
+    }
+
+    // Where `sector` of `track` starts in the image
+    fn offset(&self, track: u16, sector: u16) -> Result<u64, DiskError> {
+        if track >= self.tracks {
+            return Err(DiskError::BadTrack);
+        }
+        let index = sector.wrapping_sub(self.first_sector);
+        if index >= self.sectors {
+            return Err(DiskError::BadSector);
+        }
+        let sector = u64::from(track) * u64::from(self.sectors) + u64::from(index);
+        Ok(sector * self.sector_size as u64)
+    }
+}
+
+/// Why a sector could not be read or written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskError {
+    /// No image is in the drive.
+    NoDisk,
+    BadTrack,
+    BadSector,
+    ReadOnly,
+    /// The image file could not be read or written.
+    Io,
+}
+
+impl DiskError {
+    /// The status a `DiskController` reports for the error.
+    pub fn code(self) -> u8 {
+        match self {
+            DiskError::NoDisk => 1,
+            DiskError::BadTrack => 2,
+            DiskError::BadSector => 3,
+            DiskError::ReadOnly => 4,
+            DiskError::Io => 5,
+        }
+    }
+}
+
+impl fmt::Display for DiskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DiskError::NoDisk => "no disk in the drive",
+            DiskError::BadTrack => "no such track",
+            DiskError::BadSector => "no such sector",
+            DiskError::ReadOnly => "the disk is read only",
+            DiskError::Io => "the disk image could not be accessed",
+        })
+    }
+}
+
+impl std::error::Error for DiskError {}
+
+/// Anything a disk image can be kept in.
+pub trait Storage: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> Storage for T {}
+
+/// A raw `.img` or `.dsk` disk image. An image shorter than its geometry
+/// says reads as `EMPTY` past its end and grows when written there.
+pub struct DiskImage {
+    storage: Box<dyn Storage>,
+    geometry: Geometry,
+    read_only: bool,
+}
+
+impl DiskImage {
+    pub fn new(storage: impl Storage + 'static, geometry: Geometry) -> Self {
+        DiskImage {
+            storage: Box::new(storage),
+            geometry,
+            read_only: false,
+        }
+    }
+
+    /// Opens the image file at `path`, writes going back to the file. A
+    /// file that cannot be opened for writing is opened read only.
+    pub fn open(path: impl AsRef<Path>, geometry: Geometry) -> Result<Self, LoadError> {
+        let path = path.as_ref();
+        match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => Ok(Self::new(file, geometry)),
+            Err(_) => {
+                let file = File::open(path).map_err(|e| LoadError::Io(path.to_path_buf(), e))?;
+                let mut image = Self::new(file, geometry);
+                image.read_only = true;
+                Ok(image)
+            }
+        }
+    }
+
+    /// An image held in memory, such as one built for a test. Pass an
+    /// empty `Vec` for a blank, formatted disk.
+    pub fn from_bytes(bytes: Vec<u8>, geometry: Geometry) -> Self {
+        Self::new(Cursor::new(bytes), geometry)
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Write-protects the disk, or lifts the protection.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Reads `sector` of `track` into `buf`, which holds a sector.
+    pub fn read_sector(&mut self, track: u16, sector: u16, buf: &mut [u8]) -> Result<(), DiskError> {
+        let offset = self.geometry.offset(track, sector)?;
+        let buf = &mut buf[..self.geometry.sector_size];
+        self.storage.seek(SeekFrom::Start(offset)).map_err(|_| DiskError::Io)?;
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.storage.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return Err(DiskError::Io),
+            }
+        }
+        buf[filled..].fill(EMPTY);
+        Ok(())
+    }
+
+    /// Writes `sector` of `track` from `buf`, which holds a sector.
+    pub fn write_sector(&mut self, track: u16, sector: u16, buf: &[u8]) -> Result<(), DiskError> {
+        let offset = self.geometry.offset(track, sector)?;
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        self.storage.seek(SeekFrom::Start(offset)).map_err(|_| DiskError::Io)?;
+        self.storage.write_all(&buf[..self.geometry.sector_size]).map_err(|_| DiskError::Io)?;
+        self.storage.flush().map_err(|_| DiskError::Io)
+    }
+}
+
+/// A floppy controller on six consecutive ports (see `port`) that moves
+/// whole sectors between the disks in its drives and memory. Set the
+/// drive, track, sector and DMA address, then write a command; it is done
+/// by the time the OUT completes.
+pub struct DiskController<M: Memory = Memory8080> {
+    port: u8,
+    memory: Rc<RefCell<M>>,
+    drives: Vec<Option<DiskImage>>,
+    drive: u8,
+    track: u8,
+    sector: u8,
+    dma: u16,
+    status: u8,
+}
+
+impl<M: Memory> DiskController<M> {
+    pub const STATE_ID: DeviceId = *b"DISK";
+    pub const STATE_VERSION: u16 = 1;
+
+    /// A controller at `port` doing DMA to `memory`, which should be the
+    /// CPU's memory.
+    pub fn new(port: u8, memory: Rc<RefCell<M>>) -> Self {
+        DiskController {
+            port,
+            memory,
+            drives: (0..DRIVES).map(|_| None).collect(),
+            drive: 0,
+            track: 0,
+            sector: 0,
+            dma: 0,
+            status: 0,
+        }
+    }
+
+    /// Puts `image` in `drive`, returning the disk that was in it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `drive` is not below `DRIVES`.
+    pub fn insert(&mut self, drive: usize, image: DiskImage) -> Option<DiskImage> {
+        self.drives[drive].replace(image)
+    }
+
+    pub fn eject(&mut self, drive: usize) -> Option<DiskImage> {
+        self.drives.get_mut(drive)?.take()
+    }
+
+    pub fn drive(&self, drive: usize) -> Option<&DiskImage> {
+        self.drives.get(drive)?.as_ref()
+    }
+
+    pub fn drive_mut(&mut self, drive: usize) -> Option<&mut DiskImage> {
+        self.drives.get_mut(drive)?.as_mut()
+    }
+
+    fn transfer(&mut self, command: u8) -> Result<(), DiskError> {
+        let (track, sector, dma) = (u16::from(self.track), u16::from(self.sector), usize::from(self.dma));
+        let image = self.drives.get_mut(usize::from(self.drive)).and_then(Option::as_mut).ok_or(DiskError::NoDisk)?;
+        let mut buf = vec![0; image.geometry().sector_size];
+        let mut memory = self.memory.borrow_mut();
+        if command == port::READ {
+            image.read_sector(track, sector, &mut buf)?;
+            for (i, &byte) in buf.iter().enumerate() {
+                memory.write((dma + i) & 0xffff, byte);
+            }
+        } else {
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = memory.read((dma + i) & 0xffff);
+            }
+            image.write_sector(track, sector, &buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl<M: Memory> Device for DiskController<M> {
+    fn reset(&mut self) {
+        self.drive = 0;
+        self.track = 0;
+        self.sector = 0;
+        self.dma = 0;
+        self.status = 0;
+    }
+
+    fn read(&mut self, port: u8) -> u8 {
+        match port.wrapping_sub(self.port) {
+            port::DRIVE => self.drive,
+            port::TRACK => self.track,
+            port::SECTOR => self.sector,
+            port::COMMAND => self.status,
+            port::DMA_LOW => self.dma as u8,
+            port::DMA_HIGH => (self.dma >> 8) as u8,
+            _ => 0xff,
+        }
+    }
+
+    fn write(&mut self, port: u8, value: u8) {
+        match port.wrapping_sub(self.port) {
+            port::DRIVE => self.drive = value,
+            port::TRACK => self.track = value,
+            port::SECTOR => self.sector = value,
+            port::COMMAND => {
+                self.status = match value {
+                    port::READ | port::WRITE => self.transfer(value).map_or_else(DiskError::code, |_| 0),
+                    // Unknown commands fail like a bad sector
+                    _ => DiskError::BadSector.code(),
+                }
+            }
+            port::DMA_LOW => self.dma = (self.dma & 0xff00) | u16::from(value),
+            port::DMA_HIGH => self.dma = (self.dma & 0x00ff) | (u16::from(value) << 8),
+            _ => {}
+        }
+    }
+
+    /// The registers. Disks are written as the guest goes, so they stay
+    /// in the drives as they are.
+    fn save_state(&self) -> Option<DeviceState> {
+        let mut state = DeviceState::new(Self::STATE_ID, Self::STATE_VERSION);
+        for register in [self.drive, self.track, self.sector, self.status] {
+            state.push_u8(register);
+        }
+        state.push_u16(self.dma);
+        Some(state)
+    }
+
+    fn load_state(&mut self, state: &DeviceState) -> Result<(), StateError> {
+        let mut fields = state.reader();
+        let (drive, track, sector, status) = (fields.u8()?, fields.u8()?, fields.u8()?, fields.u8()?);
+        let dma = fields.u16()?;
+        fields.end()?;
+        self.drive = drive;
+        self.track = track;
+        self.sector = sector;
+        self.status = status;
+        self.dma = dma;
+        Ok(())
+    }
+}
+
+/// The entry points of the CP/M 2.2 BIOS, in jump table order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    Boot,
+    WarmBoot,
+    ConsoleStatus,
+    ConsoleIn,
+    ConsoleOut,
+    List,
+    Punch,
+    Reader,
+    Home,
+    SelectDisk,
+    SetTrack,
+    SetSector,
+    SetDma,
+    Read,
+    Write,
+    ListStatus,
+    SectorTranslate,
+}
+
+impl Entry {
+    pub const ALL: [Entry; 17] = [
+        Entry::Boot, Entry::WarmBoot, Entry::ConsoleStatus, Entry::ConsoleIn, Entry::ConsoleOut, Entry::List,
+        Entry::Punch, Entry::Reader, Entry::Home, Entry::SelectDisk, Entry::SetTrack, Entry::SetSector,
+        Entry::SetDma, Entry::Read, Entry::Write, Entry::ListStatus, Entry::SectorTranslate,
+    ];
+
+    /// Whether the caller of `Bios::call` has to handle the entry: the
+    /// character devices are left to the machine.
+    pub fn is_character_io(self) -> bool {
+        matches!(self, Entry::ConsoleStatus | Entry::ConsoleIn | Entry::ConsoleOut | Entry::List
+                       | Entry::Punch | Entry::Reader | Entry::ListStatus)
+    }
+}
+
+/// How CP/M lays out its file system on a disk, the parts of its disk
+/// parameter block that do not follow from the geometry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    /// Bytes per allocation block, a power of two from 1024 to 16384.
+    pub block_size: u16,
+    pub directory_entries: u16,
+    /// Tracks at the start of the disk holding the system rather than
+    /// files.
+    pub reserved_tracks: u16,
+    /// Sector skew applied by SECTRAN, 0 for none.
+    pub skew: u16,
+}
+
+impl Format {
+    /// The standard format of `Geometry::IBM_3740` disks.
+    pub const IBM_3740: Format = Format { block_size: 1024, directory_entries: 64, reserved_tracks: 2, skew: 6 };
+}
+
+/// Size of the CCP and BDOS together, loaded from the system tracks on
+/// boot.
+pub const SYSTEM_SIZE: u16 = 0x1600;
+
+// Bytes of the BIOS jump table
+const JUMP_TABLE: u16 = 3 * Entry::ALL.len() as u16;
+
+// A drive the BIOS knows about
+struct Drive {
+    geometry: Geometry,
+    // Where its disk parameter header is
+    dph: u16,
+    // The skewed record for each record of a track
+    skew: Vec<u16>,
+}
+
+impl Drive {
+    fn records_per_sector(&self) -> u16 {
+        (self.geometry.sector_size / 128) as u16
+    }
+
+    fn records_per_track(&self) -> u16 {
+        self.geometry.sectors * self.records_per_sector()
+    }
+}
+
+/// A CP/M 2.2 BIOS done on the host, for running a real CCP and BDOS on
+/// the disks of a `DiskController`.
+///
+/// `install` puts the jump table at the BIOS base address with a RET at
+/// every entry, along with the disk parameter tables, and the CCP and
+/// BDOS are expected just below it as CP/M 2.2 lays itself out: the CCP
+/// at `base - SYSTEM_SIZE` and the BDOS 0x800 bytes above that. Call
+/// `call` before every step: when the CPU is at an entry it is carried
+/// out and the CPU returns on its own. BOOT and WBOOT load the CCP and
+/// BDOS from the system tracks of drive A and jump to the CCP. The
+/// character devices are handed back to the caller, which answers in A as
+/// the BIOS would.
+///
+/// Records are 128 bytes, as CP/M sees them; disks with larger sectors
+/// are blocked and deblocked here.
+///
+/// ```no_run
+/// use i8080_emulator::cpu::CPU;
+/// use i8080_emulator::devices::disk::{Bios, DiskController, DiskImage, Entry, Geometry};
+/// use i8080_emulator::memory::Memory8080;
+/// use std::cell::RefCell;
+/// use std::rc::Rc;
+///
+/// let memory = Rc::new(RefCell::new(Memory8080::new_empty()));
+/// let mut cpu = CPU::new(memory.clone());
+/// let controller = Rc::new(RefCell::new(DiskController::new(0x08, memory.clone())));
+/// controller.borrow_mut().insert(0, DiskImage::open("cpm22.dsk", Geometry::IBM_3740).unwrap());
+/// let mut bios = Bios::new(0xfa00, controller);
+/// bios.install(&mut *memory.borrow_mut());
+/// cpu.pc = 0xfa00;
+/// loop {
+///     match bios.call(&mut cpu).unwrap() {
+///         Some(Entry::ConsoleOut) => print!("{}", cpu.regs.c as char),
+///         Some(Entry::ConsoleStatus) => cpu.regs.a = 0x00,
+///         _ => {}
+///     }
+///     cpu.step();
+/// }
+/// ```
+pub struct Bios<M: Memory = Memory8080> {
+    base: u16,
+    controller: Rc<RefCell<DiskController<M>>>,
+    formats: Vec<Format>,
+    drives: Vec<Option<Drive>>,
+    drive: usize,
+    track: u16,
+    record: u16,
+    dma: u16,
+}
+
+impl<M: Memory> Bios<M> {
+    /// A BIOS at `base` for the disks in `controller`.
+    pub fn new(base: u16, controller: Rc<RefCell<DiskController<M>>>) -> Self {
+        Bios {
+            base,
+            controller,
+            formats: vec![Format::IBM_3740; DRIVES],
+            drives: Vec::new(),
+            drive: 0,
+            track: 0,
+            record: 0,
+            dma: 0x0080,
+        }
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    /// Where the CCP starts.
+    pub fn ccp(&self) -> u16 {
+        self.base.wrapping_sub(SYSTEM_SIZE)
+    }
+
+    /// Sets the file system format of `drive`, `Format::IBM_3740` until
+    /// set. Takes effect on the next `install`.
+    pub fn set_format(&mut self, drive: usize, format: Format) {
+        self.formats[drive] = format;
+    }
+
+    /// Writes the jump table and the disk parameter tables for the drives
+    /// that have a disk in them. Install again after changing disks for
+    /// another geometry.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the tables do not fit between the base address and the
+    /// end of memory, or if a sector is not a multiple of 128 bytes.
+    pub fn install(&mut self, memory: &mut impl Memory) {
+        let table_end = self.base.checked_add(JUMP_TABLE).expect("the BIOS tables run past 0xffff");
+        for i in 0..JUMP_TABLE {
+            memory.write(usize::from(self.base) + usize::from(i), if i % 3 == 0 { 0xc9 } else { 0x00 });
+        }
+        // All drives share the directory buffer
+        let dirbuf = usize::from(table_end);
+        let mut next = dirbuf + 128;
+        let controller = self.controller.borrow();
+        self.drives = (0..DRIVES).map(|n| {
+            let geometry = controller.drive(n)?.geometry();
+            assert!(geometry.sector_size % 128 == 0, "sectors are not a multiple of 128 bytes");
+            let format = self.formats[n];
+            let mut drive = Drive { geometry, dph: next as u16, skew: Vec::new() };
+            drive.skew = skew_table(drive.records_per_track(), format.skew);
+
+            let spt = drive.records_per_track();
+            let records = u32::from(spt) * u32::from(geometry.tracks.saturating_sub(format.reserved_tracks));
+            let block_records = format.block_size / 128;
+            let dsm = (records / u32::from(block_records)).saturating_sub(1) as u16;
+            let exm = if dsm < 256 { format.block_size / 1024 } else { format.block_size / 2048 }.saturating_sub(1);
+            let directory_blocks = (u32::from(format.directory_entries) * 32).div_ceil(u32::from(format.block_size));
+            let al = !(0xffffu32 >> directory_blocks.min(16)) as u16;
+            let cks = format.directory_entries / 4;
+            let (dpb, csv) = (next + 16, next + 16 + 15);
+            let alv = csv + usize::from(cks);
+            let end = alv + usize::from(dsm / 8) + 1;
+            assert!(end <= 0x10000, "the BIOS tables run past 0xffff");
+
+            // DPH: no translation table, as SECTRAN skews here
+            let words = [0x0000, 0x0000, 0x0000, 0x0000, dirbuf as u16, dpb as u16, csv as u16, alv as u16];
+            for (i, &word) in words.iter().enumerate() {
+                memory.write16(next + 2 * i, word);
+            }
+            let bytes = [block_records.trailing_zeros() as u8, (block_records - 1) as u8, exm as u8];
+            memory.write16(dpb, spt);
+            for (i, &byte) in bytes.iter().enumerate() {
+                memory.write(dpb + 2 + i, byte);
+            }
+            memory.write16(dpb + 5, dsm);
+            memory.write16(dpb + 7, format.directory_entries - 1);
+            memory.write(dpb + 9, (al >> 8) as u8);
+            memory.write(dpb + 10, al as u8);
+            memory.write16(dpb + 11, cks);
+            memory.write16(dpb + 13, format.reserved_tracks);
+            next = end;
+            Some(drive)
+        }).collect();
+    }
+
+    /// The entry the CPU is at, if it is at one.
+    pub fn entry(&self, pc: u16) -> Option<Entry> {
+        let offset = pc.wrapping_sub(self.base);
+        if offset < JUMP_TABLE && offset % 3 == 0 {
+            Some(Entry::ALL[usize::from(offset / 3)])
+        } else {
+            None
+        }
+    }
+
+    /// Carries out the entry the CPU is at, if it is at one, and returns
+    /// it. Character device entries are left to the caller. Only booting
+    /// fails, when the system cannot be read from drive A; a failed READ
+    /// or WRITE returns 1 in A to the BDOS.
+    pub fn call(&mut self, cpu: &mut CPU<M>) -> Result<Option<Entry>, DiskError> {
+        let entry = match self.entry(cpu.pc) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let bc = cpu.regs.get_bc();
+        match entry {
+            Entry::Boot | Entry::WarmBoot => {
+                if entry == Entry::Boot {
+                    cpu.memory.borrow_mut().write(0x0004, 0x00);
+                }
+                self.boot(cpu)?;
+            }
+            Entry::Home => self.track = 0,
+            Entry::SelectDisk => {
+                self.drive = usize::from(cpu.regs.c);
+                let dph = self.drives.get(self.drive).and_then(Option::as_ref).map_or(0x0000, |drive| drive.dph);
+                cpu.regs.set_hl(dph);
+            }
+            Entry::SetTrack => self.track = bc,
+            Entry::SetSector => self.record = bc,
+            Entry::SetDma => self.dma = bc,
+            Entry::Read | Entry::Write => {
+                let result = self.transfer(&cpu.memory, entry == Entry::Write);
+                cpu.regs.a = if result.is_ok() { 0 } else { 1 };
+            }
+            Entry::SectorTranslate => {
+                let skew = self.drives.get(self.drive).and_then(Option::as_ref).map(|drive| &drive.skew);
+                let record = skew.and_then(|skew| skew.get(usize::from(bc)).copied()).unwrap_or(bc);
+                cpu.regs.set_hl(record);
+            }
+            _ => {}
+        }
+        Ok(Some(entry))
+    }
+
+    // Loads the CCP and BDOS, which follow the boot record on drive A,
+    // sets up page zero and enters the CCP
+    fn boot(&mut self, cpu: &mut CPU<M>) -> Result<(), DiskError> {
+        let ccp = self.ccp();
+        self.drive = 0;
+        self.dma = ccp;
+        for record in 1..=SYSTEM_SIZE / 128 {
+            let spt = self.drives.first().and_then(Option::as_ref).ok_or(DiskError::NoDisk)?.records_per_track();
+            self.track = record / spt;
+            self.record = record % spt;
+            self.transfer(&cpu.memory, false)?;
+            self.dma = self.dma.wrapping_add(128);
+        }
+        self.dma = 0x0080;
+        let mut memory = cpu.memory.borrow_mut();
+        memory.write(0x0000, 0xc3);
+        memory.write16(0x0001, self.base.wrapping_add(3));
+        memory.write(0x0005, 0xc3);
+        memory.write16(0x0006, ccp.wrapping_add(0x806));
+        cpu.regs.c = memory.read(0x0004);
+        cpu.pc = ccp;
+        Ok(())
+    }
+
+    // Reads or writes the 128-byte record set with SETTRK and SETSEC
+    fn transfer(&mut self, memory: &RefCell<M>, write: bool) -> Result<(), DiskError> {
+        let drive = self.drives.get(self.drive).and_then(Option::as_ref).ok_or(DiskError::NoDisk)?;
+        // SETSEC takes any 16-bit value from the guest
+        let sector = drive.geometry.first_sector.checked_add(self.record / drive.records_per_sector()).ok_or(DiskError::BadSector)?;
+        let offset = usize::from(self.record % drive.records_per_sector()) * 128;
+        let mut controller = self.controller.borrow_mut();
+        let image = controller.drive_mut(self.drive).ok_or(DiskError::NoDisk)?;
+        let mut buf = vec![0; image.geometry().sector_size];
+        let dma = usize::from(self.dma);
+        if write {
+            if buf.len() > 128 {
+                image.read_sector(self.track, sector, &mut buf)?;
+            }
+            let memory = memory.borrow();
+            for (i, byte) in buf[offset..offset + 128].iter_mut().enumerate() {
+                *byte = memory.read((dma + i) & 0xffff);
+            }
+            image.write_sector(self.track, sector, &buf)
+        } else {
+            image.read_sector(self.track, sector, &mut buf)?;
+            let mut memory = memory.borrow_mut();
+            for (i, &byte) in buf[offset..offset + 128].iter().enumerate() {
+                memory.write((dma + i) & 0xffff, byte);
+            }
+            Ok(())
+        }
+    }
+}
+
+// The physical record for each logical record of a track with `skew`
+// records between consecutive ones, moving on to the next free record
+// when one is taken
+fn skew_table(records: u16, skew: u16) -> Vec<u16> {
+    if skew <= 1 {
+        return Vec::new();
+    }
+    let mut taken = vec![false; usize::from(records)];
+    let mut table = Vec::with_capacity(usize::from(records));
+    let mut next = 0;
+    for _ in 0..records {
+        while taken[usize::from(next)] {
+            next = (next + 1) % records;
+        }
+        taken[usize::from(next)] = true;
+        table.push(next);
+        next = (next + skew) % records;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::device::{restore, Device};
+    use crate::devices::disk::{port, Bios, DiskController, DiskError, DiskImage, Entry, Geometry, EMPTY};
+    use crate::memory::{Memory, Memory8080};
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn controller_ports() {
+        let geometry = Geometry { tracks: 2, sectors: 4, sector_size: 128, first_sector: 1 };
+        let mut bytes = vec![0x00; 0x100];
+        bytes.extend(vec![0x5a; 0x80]);
+        let memory = Rc::new(RefCell::new(Memory8080::new_empty()));
+        let mut controller = DiskController::new(0x08, memory.clone());
+        controller.insert(0, DiskImage::from_bytes(bytes, geometry));
+
+        // Track 0 sector 3 into 0x1000
+        controller.write(0x08 + port::SECTOR, 3);
+        controller.write(0x08 + port::DMA_HIGH, 0x10);
+        controller.write(0x08 + port::COMMAND, port::READ);
+        assert_eq!(controller.read(0x08 + port::COMMAND), 0);
+        assert_eq!(memory.borrow().read(0x107f), 0x5a);
+
+        // Past the end of the image, then back out to track 1 sector 4
+        controller.write(0x08 + port::SECTOR, 4);
+        controller.write(0x08 + port::COMMAND, port::READ);
+        assert_eq!(memory.borrow().read(0x1000), EMPTY);
+        memory.borrow_mut().write(0x1000, 0x42);
+        controller.write(0x08 + port::TRACK, 1);
+        controller.write(0x08 + port::COMMAND, port::WRITE);
+        let mut sector = [0x00; 128];
+        controller.drive_mut(0).unwrap().read_sector(1, 4, &mut sector).unwrap();
+        assert_eq!(sector[0], 0x42);
+
+        controller.write(0x08 + port::TRACK, 2);
+        controller.write(0x08 + port::COMMAND, port::READ);
+        assert_eq!(controller.read(0x08 + port::COMMAND), DiskError::BadTrack.code());
+        controller.drive_mut(0).unwrap().set_read_only(true);
+        controller.write(0x08 + port::TRACK, 0);
+        controller.write(0x08 + port::COMMAND, port::WRITE);
+        assert_eq!(controller.read(0x08 + port::COMMAND), DiskError::ReadOnly.code());
+        controller.write(0x08 + port::DRIVE, 1);
+        controller.write(0x08 + port::COMMAND, port::READ);
+        assert_eq!(controller.read(0x08 + port::COMMAND), DiskError::NoDisk.code());
+
+        let mut copy = DiskController::new(0x08, memory.clone());
+        restore(&mut copy, controller.save_state().unwrap()).unwrap();
+        let registers = |controller: &mut DiskController| (0..6).map(|i| controller.read(0x08 + i)).collect::<Vec<_>>();
+        assert_eq!(registers(&mut copy), registers(&mut controller));
+    }
+
+    #[test]
+    fn bios_disk_calls() {
+        // The system: the CCP's first record holds HLT
+        let mut image = vec![0x00; 26 * 128 * 2];
+        image[128] = 0x76;
+        let memory = Rc::new(RefCell::new(Memory8080::new_empty()));
+        let mut cpu = CPU::new(memory.clone());
+        let controller = Rc::new(RefCell::new(DiskController::new(0x08, memory.clone())));
+        controller.borrow_mut().insert(0, DiskImage::from_bytes(image, Geometry::IBM_3740));
+        let mut bios = Bios::new(0xfa00, controller.clone());
+        bios.install(&mut *memory.borrow_mut());
+
+        cpu.pc = 0xfa00;
+        assert_eq!(bios.call(&mut cpu), Ok(Some(Entry::Boot)));
+        assert_eq!(cpu.pc, 0xe400);
+        assert_eq!(memory.borrow().read(0xe400), 0x76);
+        assert_eq!(memory.borrow().read16(0x0006), 0xec06);
+
+        // SELDSK: the DPB of a standard 8" disk
+        let mut call = |entry: usize, bc: u16| {
+            cpu.regs.set_bc(bc);
+            cpu.pc = 0xfa00 + 3 * entry as u16;
+            bios.call(&mut cpu).unwrap();
+            (cpu.regs.a, cpu.regs.get_hl())
+        };
+        assert_eq!(call(9, 1).1, 0x0000);
+        let dph = usize::from(call(9, 0).1);
+        let dpb = usize::from(memory.borrow().read16(dph + 10));
+        let dpb = (0..15).map(|i| memory.borrow().read(dpb + i)).collect::<Vec<_>>();
+        assert_eq!(dpb, [26, 0, 3, 7, 0, 242, 0, 63, 0, 0xc0, 0x00, 16, 0, 2, 0]);
+        assert_eq!(call(16, 1).1, 6);
+
+        // Record 6 of track 2 out of 0x0080 and back into 0x0100
+        memory.borrow_mut().write(0x0080, 0x99);
+        call(10, 2);
+        call(11, 6);
+        assert_eq!(call(14, 0).0, 0);
+        call(12, 0x0100);
+        assert_eq!(call(13, 0).0, 0);
+        assert_eq!(memory.borrow().read(0x0100), 0x99);
+        let mut sector = [0x00; 128];
+        controller.borrow_mut().drive_mut(0).unwrap().read_sector(2, 7, &mut sector).unwrap();
+        assert_eq!(sector[0], 0x99);
+        call(10, 77);
+        assert_eq!(call(13, 0).0, 1);
+        call(10, 0);
+        call(11, 0xffff);
+        assert_eq!(call(13, 0).0, 1);
+    }
+
+    #[test]
+    fn bios_deblocks_large_sectors() {
+        let geometry = Geometry { tracks: 4, sectors: 8, sector_size: 512, first_sector: 0 };
+        let memory = Rc::new(RefCell::new(Memory8080::new_empty()));
+        let mut cpu = CPU::new(memory.clone());
+        let controller = Rc::new(RefCell::new(DiskController::new(0x08, memory.clone())));
+        controller.borrow_mut().insert(0, DiskImage::from_bytes(Vec::new(), geometry));
+        let mut bios = Bios::new(0xfa00, controller.clone());
+        bios.install(&mut *memory.borrow_mut());
+        for (entry, bc) in [(9, 0), (10, 1), (11, 5), (12, 0x0200), (14, 0)].iter().copied() {
+            memory.borrow_mut().write(0x0200, 0x11);
+            cpu.regs.set_bc(bc);
+            cpu.pc = 0xfa00 + 3 * entry;
+            bios.call(&mut cpu).unwrap();
+        }
+        assert_eq!(cpu.regs.a, 0);
+        let mut sector = [0x00; 512];
+        controller.borrow_mut().drive_mut(0).unwrap().read_sector(1, 1, &mut sector).unwrap();
+        assert_eq!((sector[127], sector[128], sector[256]), (EMPTY, 0x11, EMPTY));
+    }
+}
@@ -0,0 +1,265 @@
+use crate::cpu::{ClockCycles, Timestamp, CPU};
+use crate::device::Device;
+use crate::memory::Memory;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// Clock cycles an 8257 takes to move a byte.
+pub const CYCLES_PER_BYTE: ClockCycles = 4;
+
+/// A block of bytes to move. Device ports are read or written once per
+/// byte, as a controller's DMA acknowledge line would.
+#[derive(Clone)]
+pub enum Transfer {
+    /// Bytes read from `port` of `device` go to memory from `address` up.
+    DeviceToMemory { device: Rc<RefCell<dyn Device>>, port: u8, address: u16, count: u16 },
+    /// Bytes from `address` up are written to `port` of `device`.
+    MemoryToDevice { device: Rc<RefCell<dyn Device>>, port: u8, address: u16, count: u16 },
+    /// Bytes from `source` up are copied to `address` up.
+    MemoryToMemory { source: u16, address: u16, count: u16 },
+}
+
+impl Transfer {
+    fn count(&self) -> u16 {
+        match *self {
+            Transfer::DeviceToMemory { count, .. }
+            | Transfer::MemoryToDevice { count, .. }
+            | Transfer::MemoryToMemory { count, .. } => count,
+        }
+    }
+}
+
+/// How a transfer shares the bus with the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// The whole block moves at once while the CPU waits.
+    Burst,
+    /// One byte moves between instructions, slowing the CPU down rather
+    /// than stopping it.
+    CycleSteal,
+}
+
+/// A transfer starting or finishing, with the CPU's cycle count at the
+/// time. `id` is what `DmaController::start` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaEvent {
+    Started { id: usize, cycle: Timestamp },
+    Completed { id: usize, cycle: Timestamp },
+}
+
+// A transfer in the queue and how far it has got
+struct Pending {
+    id: usize,
+    transfer: Transfer,
+    done: u16,
+}
+
+/// Moves blocks of bytes between devices and memory without the CPU, and
+/// charges the bus time it takes to the CPU's clock. Transfers run one at
+/// a time in the order they were started, when the machine calls `step`
+/// between instructions:
+///
+/// ```
+/// use i8080_emulator::cpu::CPU;
+/// use i8080_emulator::devices::dma::{DmaController, Transfer};
+/// use i8080_emulator::memory::{Memory, Memory8080};
+/// use std::cell::RefCell;
+/// use std::rc::Rc;
+///
+/// let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new_empty())));
+/// cpu.memory.borrow_mut().write(0x1000, 0xaa);
+/// let mut dma = DmaController::new();
+/// dma.start(Transfer::MemoryToMemory { source: 0x1000, address: 0x2400, count: 0x100 });
+/// assert_eq!(dma.step(&mut cpu), 0x100 * 4);
+/// assert_eq!(cpu.memory.borrow().read(0x2400), 0xaa);
+/// ```
+///
+/// The cycles `step` returns have passed for the rest of the machine too,
+/// so they go to its devices and `Throttle` as an instruction's would.
+/// Events are queued for `take_events` unless a callback is set.
+pub struct DmaController {
+    mode: Mode,
+    cycles_per_byte: ClockCycles,
+    queue: VecDeque<Pending>,
+    next_id: usize,
+    events: Vec<DmaEvent>,
+    callback: Option<Box<dyn FnMut(DmaEvent)>>,
+}
+
+impl Default for DmaController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DmaController {
+    /// Burst transfers at `CYCLES_PER_BYTE`.
+    pub fn new() -> Self {
+        DmaController {
+            mode: Mode::Burst,
+            cycles_per_byte: CYCLES_PER_BYTE,
+            queue: VecDeque::new(),
+            next_id: 0,
+            events: Vec::new(),
+            callback: None,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_cycles_per_byte(mut self, cycles: ClockCycles) -> Self {
+        self.cycles_per_byte = cycles;
+        self
+    }
+
+    /// Queues `transfer` and returns the id its events carry.
+    pub fn start(&mut self, transfer: Transfer) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queue.push_back(Pending { id, transfer, done: 0 });
+        id
+    }
+
+    /// Whether any transfer is still to finish.
+    pub fn is_busy(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// Drops every transfer, finished or not, without completion events.
+    pub fn cancel(&mut self) {
+        self.queue.clear();
+    }
+
+    /// Hands every event to `callback` as it happens instead of queueing
+    /// it.
+    pub fn set_callback(&mut self, callback: impl FnMut(DmaEvent) + 'static) {
+        self.callback = Some(Box::new(callback));
+    }
+
+    /// Returns the events queued since the last call, oldest first.
+    pub fn take_events(&mut self) -> Vec<DmaEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Takes the bus for as long as the mode allows: the rest of the
+    /// current transfer in burst mode, one byte when stealing cycles.
+    /// Adds the cycles the bus was held to `cpu`'s count and returns
+    /// them; 0 when there is nothing to do.
+    pub fn step<M: Memory>(&mut self, cpu: &mut CPU<M>) -> ClockCycles {
+        let pending = match self.queue.front_mut() {
+            Some(pending) => pending,
+            None => return 0,
+        };
+        let first = pending.done == 0;
+        let id = pending.id;
+        let remaining = pending.transfer.count() - pending.done;
+        let bytes = match self.mode {
+            Mode::Burst => remaining,
+            Mode::CycleSteal => remaining.min(1),
+        };
+        for _ in 0..bytes {
+            move_byte(&pending.transfer, pending.done, cpu);
+            pending.done += 1;
+        }
+        let finished = pending.done == pending.transfer.count();
+        if first {
+            self.notify(DmaEvent::Started { id, cycle: cpu.cycles() });
+        }
+        let cycles = ClockCycles::from(bytes) * self.cycles_per_byte;
+        cpu.advance_cycles(cycles);
+        if finished {
+            self.queue.pop_front();
+            self.notify(DmaEvent::Completed { id, cycle: cpu.cycles() });
+        }
+        cycles
+    }
+
+    fn notify(&mut self, event: DmaEvent) {
+        match &mut self.callback {
+            Some(callback) => callback(event),
+            None => self.events.push(event),
+        }
+    }
+}
+
+// Moves byte `n` of `transfer`
+fn move_byte<M: Memory>(transfer: &Transfer, n: u16, cpu: &CPU<M>) {
+    match transfer {
+        Transfer::DeviceToMemory { device, port, address, .. } => {
+            let byte = device.borrow_mut().read(*port);
+            cpu.memory.borrow_mut().write(usize::from(address.wrapping_add(n)), byte);
+        }
+        Transfer::MemoryToDevice { device, port, address, .. } => {
+            let byte = cpu.memory.borrow().read(usize::from(address.wrapping_add(n)));
+            device.borrow_mut().write(*port, byte);
+        }
+        Transfer::MemoryToMemory { source, address, .. } => {
+            let mut memory = cpu.memory.borrow_mut();
+            let byte = memory.read(usize::from(source.wrapping_add(n)));
+            memory.write(usize::from(address.wrapping_add(n)), byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::device::Device;
+    use crate::devices::dma::{DmaController, DmaEvent, Mode, Transfer};
+    use crate::memory::{Memory, Memory8080};
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Reads count up from 0x10 and writes are kept
+    #[derive(Default)]
+    struct Fifo {
+        next: u8,
+        written: Vec<u8>,
+    }
+
+    impl Device for Fifo {
+        fn read(&mut self, _port: u8) -> u8 {
+            self.next += 1;
+            0x0f + self.next
+        }
+
+        fn write(&mut self, _port: u8, value: u8) {
+            self.written.push(value);
+        }
+    }
+
+    #[test]
+    fn transfers_steal_cycles() {
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new_empty())));
+        let fifo = Rc::new(RefCell::new(Fifo::default()));
+        let mut dma = DmaController::new().with_mode(Mode::CycleSteal).with_cycles_per_byte(3);
+        let read = dma.start(Transfer::DeviceToMemory { device: fifo.clone(), port: 0x20, address: 0x3000, count: 3 });
+        let write = dma.start(Transfer::MemoryToDevice { device: fifo.clone(), port: 0x21, address: 0x3001, count: 2 });
+        assert!(dma.is_busy());
+
+        // NOPs in between
+        cpu.step();
+        let mut stolen = 0;
+        while dma.is_busy() {
+            stolen += dma.step(&mut cpu);
+            cpu.step();
+        }
+        assert_eq!(dma.step(&mut cpu), 0);
+        assert_eq!(stolen, 5 * 3);
+        assert_eq!(cpu.cycles(), 6 * 4 + 5 * 3);
+        assert_eq!(cpu.memory.borrow().read16(0x3000), 0x1110);
+        assert_eq!(fifo.borrow().written, [0x11, 0x12]);
+        assert_eq!(dma.take_events(), [
+            DmaEvent::Started { id: read, cycle: 4 },
+            DmaEvent::Completed { id: read, cycle: 4 * 3 + 3 * 3 },
+            DmaEvent::Started { id: write, cycle: 4 * 4 + 3 * 3 },
+            DmaEvent::Completed { id: write, cycle: 5 * 4 + 5 * 3 },
+        ]);
+    }
+}
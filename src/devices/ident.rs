@@ -0,0 +1,121 @@
+use crate::device::{Device, DeviceId, DeviceState, StateError};
+
+/// Bits in the capability byte, set by whoever builds the machine for
+/// what it has attached.
+pub mod caps {
+    /// A serial port, as in `devices::serial`.
+    pub const SERIAL: u8 = 0x01;
+    /// A real-time clock, as in `devices::rtc`.
+    pub const RTC: u8 = 0x02;
+    pub const WATCHDOG: u8 = 0x04;
+    /// A control port, as in `devices::control`.
+    pub const CONTROL: u8 = 0x08;
+    /// Periodic interrupts.
+    pub const INTERRUPTS: u8 = 0x10;
+    /// More than 64K of memory behind a bank-select port.
+    pub const BANKED_MEMORY: u8 = 0x20;
+}
+
+/// First bytes read from the port, to tell this emulator from a floating
+/// bus or other hardware.
+pub const SIGNATURE: &[u8; 4] = b"I80E";
+
+/// An identification port for test ROMs that want to know what they run
+/// on. Reads return `SIGNATURE`, then the crate's major, minor and patch
+/// version, then the capability byte (see `caps`), and 0x00 after that.
+/// Any write starts the sequence over.
+///
+/// ```text
+///       OUT 0xfe      ; rewind, any value
+///       IN  0xfe      ; 'I', or 0xff with nothing attached
+///       ...           ; 'E', then the version
+///       IN  0xfe      ; capabilities
+///       ANI 0x01      ; serial port?
+///       JZ  skip_uart_tests
+/// ```
+pub struct Ident {
+    bytes: [u8; 8],
+    next: usize,
+}
+
+impl Ident {
+    pub const STATE_ID: DeviceId = *b"IDNT";
+    pub const STATE_VERSION: u16 = 1;
+
+    pub fn new(capabilities: u8) -> Self {
+        let version = |part: &str| part.parse().unwrap_or(0);
+        let mut bytes = [0x00; 8];
+        bytes[..4].copy_from_slice(SIGNATURE);
+        bytes[4] = version(env!("CARGO_PKG_VERSION_MAJOR"));
+        bytes[5] = version(env!("CARGO_PKG_VERSION_MINOR"));
+        bytes[6] = version(env!("CARGO_PKG_VERSION_PATCH"));
+        bytes[7] = capabilities;
+        Ident { bytes, next: 0 }
+    }
+}
+
+impl Device for Ident {
+    fn reset(&mut self) {
+        self.next = 0;
+    }
+
+    fn read(&mut self, _port: u8) -> u8 {
+        let byte = self.bytes.get(self.next).copied().unwrap_or(0x00);
+        self.next = (self.next + 1).min(self.bytes.len());
+        byte
+    }
+
+    fn write(&mut self, _port: u8, _value: u8) {
+        self.next = 0;
+    }
+
+    /// How far through the sequence reads have got. The bytes themselves
+    /// are the running crate's.
+    fn save_state(&self) -> Option<DeviceState> {
+        let mut state = DeviceState::new(Ident::STATE_ID, Ident::STATE_VERSION);
+        state.push_u8(self.next as u8);
+        Some(state)
+    }
+
+    fn load_state(&mut self, state: &DeviceState) -> Result<(), StateError> {
+        let mut fields = state.reader();
+        let next = usize::from(fields.u8()?);
+        fields.end()?;
+        if next > self.bytes.len() {
+            return Err(StateError::Corrupt);
+        }
+        self.next = next;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::devices::ident::{caps, Ident};
+    use crate::io::PortBus;
+    use crate::memory::Memory8080;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn guest_reads_the_sequence() {
+        // OUT 0xfe; LXI H, 0x2000; MVI B, 9
+        // loop: IN 0xfe; MOV M, A; INX H; DCR B; JNZ loop; HLT
+        let code = [0xd3, 0xfe, 0x21, 0x00, 0x20, 0x06, 0x09, 0xdb, 0xfe, 0x77, 0x23, 0x05, 0xc2, 0x07, 0x00, 0x76];
+        let mut memory = [0x00; 0x10000];
+        memory[..code.len()].copy_from_slice(&code);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        let mut bus = PortBus::new();
+        bus.map(0xfe, Rc::new(RefCell::new(Ident::new(caps::SERIAL | caps::RTC))));
+        // Left half-read by an earlier run
+        bus.read(0xfe);
+        while !cpu.is_halted() {
+            bus.step(&mut cpu);
+        }
+        let memory = cpu.memory.borrow();
+        assert_eq!(memory.contents()[0x2000..0x2004], *b"I80E");
+        assert_eq!(memory.contents()[0x2004..0x2009], [0, 1, 0, 0x03, 0x00]);
+    }
+}
@@ -0,0 +1,327 @@
+//! Playing back input recorded by MAME with `-record`, for checking this
+//! core against long recordings made on another emulator.
+//!
+//! Only a subset of the .inp format is read, that of MAME 0.146 onwards
+//! (version 3): a 64-byte header, uncompressed, then a zlib stream of
+//! frames. The header holds `MAGIC` at 0x00, the start time as a u64 at
+//! 0x08, the major and minor version at 0x10 and 0x11, the system name
+//! at 0x14 (12 bytes) and the MAME version that made it at 0x20 (32
+//! bytes), the names padded with NULs. Each frame, all numbers little
+//! endian, is the emulated time as i32 seconds and i64 attoseconds, the
+//! speed as a u32 fraction of 1 << 20, then the digital value of every
+//! input port of the system as a u32, in the order of the ports' tags.
+//! Systems with analog inputs record more after each port and cannot be
+//! read.
+//!
+//! A recording does not say how many ports the system has or what their
+//! bits are, so an `InpPort` for each, from the MAME driver, maps the bits
+//! to the names of `InputState` buttons. `Playback` then presses and
+//! releases those a frame at a time:
+//!
+//! ```no_run
+//! use i8080_emulator::devices::inp::{InpPort, Playback};
+//! use i8080_emulator::devices::input::InputState;
+//! use i8080_emulator::machines::composite::MachineBuilder;
+//! use std::cell::RefCell;
+//! use std::fs::File;
+//! use std::rc::Rc;
+//!
+//! let layout = vec![
+//!     InpPort::new().button("COIN", 0x01).button("P1_START", 0x04).button("P1_FIRE", 0x10),
+//!     InpPort::new().button("TILT", 0x04),
+//! ];
+//! let mut playback = Playback::read(File::open("invaders.inp").unwrap(), layout).unwrap();
+//! let input = Rc::new(RefCell::new(InputState::space_invaders()));
+//! let mut machine = MachineBuilder::new().build();
+//! machine.bus.map_input(0x01, input.clone());
+//! machine.bus.map_input(0x02, input.clone());
+//! while playback.next_frame(&mut input.borrow_mut()) {
+//!     machine.run_frame(33_333).unwrap();
+//! }
+//! ```
+
+use crate::devices::input::InputState;
+use crate::inflate::inflate_zlib;
+
+use std::convert::TryInto;
+use std::io::{self, Read};
+
+/// The bytes an .inp file starts with.
+pub const MAGIC: [u8; 8] = *b"MAMEINP\0";
+/// The major version of the format read.
+pub const VERSION: u8 = 3;
+
+const HEADER_LEN: usize = 0x40;
+// The time and speed before the ports of each frame
+const FRAME_HEADER_LEN: usize = 16;
+
+/// What the header of a recording says.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InpHeader {
+    /// When recording started, in seconds since 1970.
+    pub basetime: u64,
+    /// The format's major and minor version.
+    pub version: (u8, u8),
+    /// The MAME short name of the system recorded, such as `invaders`.
+    pub system: String,
+    /// The MAME build that recorded it.
+    pub app: String,
+}
+
+/// One frame of a recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InpFrame {
+    /// The emulated time at the start of the frame.
+    pub seconds: i32,
+    pub attoseconds: i64,
+    /// The speed MAME was running at, 1 << 20 being full speed.
+    pub speed: u32,
+    /// The digital value of each port.
+    pub ports: Vec<u32>,
+}
+
+/// A whole recording, read with the number of ports its system has.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recording {
+    pub header: InpHeader,
+    pub frames: Vec<InpFrame>,
+}
+
+impl Recording {
+    /// Reads a recording of a system with `ports` input ports. The frames
+    /// not dividing evenly, a sign of the wrong port count or of analog
+    /// inputs, is an `InvalidData` error, as is anything outside the
+    /// subset in the module documentation.
+    pub fn read(mut input: impl Read, ports: usize) -> io::Result<Self> {
+        let mut file = Vec::new();
+        input.read_to_end(&mut file)?;
+        if file.len() < HEADER_LEN || file[..8] != MAGIC {
+            return Err(invalid("not a MAME input recording".to_string()));
+        }
+        let version = (file[0x10], file[0x11]);
+        if version.0 != VERSION {
+            return Err(invalid(format!("input recording version {}.{} is not supported", version.0, version.1)));
+        }
+        let header = InpHeader {
+            basetime: u64::from_le_bytes(file[0x08..0x10].try_into().unwrap()),
+            version,
+            system: text(&file[0x14..0x20]),
+            app: text(&file[0x20..0x40]),
+        };
+
+        let data = inflate_zlib(&file[HEADER_LEN..]).map_err(|e| invalid(e.to_string()))?;
+        let frame_len = FRAME_HEADER_LEN + 4 * ports;
+        if data.len() % frame_len != 0 {
+            return Err(invalid(format!("input recording does not divide into frames of {} ports", ports)));
+        }
+        let frames = data
+            .chunks(frame_len)
+            .map(|frame| InpFrame {
+                seconds: i32::from_le_bytes(frame[0..4].try_into().unwrap()),
+                attoseconds: i64::from_le_bytes(frame[4..12].try_into().unwrap()),
+                speed: u32::from_le_bytes(frame[12..16].try_into().unwrap()),
+                ports: frame[FRAME_HEADER_LEN..]
+                    .chunks(4)
+                    .map(|value| u32::from_le_bytes(value.try_into().unwrap()))
+                    .collect(),
+            })
+            .collect();
+        Ok(Recording { header, frames })
+    }
+}
+
+fn text(field: &[u8]) -> String {
+    let end = field.iter().position(|&byte| byte == 0x00).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// A button and its bit in a MAME port
+struct InpButton {
+    name: &'static str,
+    mask: u32,
+    active_low: bool,
+}
+
+/// Which `InputState` buttons the bits of a MAME input port stand for.
+/// Bits with no button, such as DIP switches, are ignored.
+#[derive(Default)]
+pub struct InpPort {
+    buttons: Vec<InpButton>,
+}
+
+impl InpPort {
+    pub fn new() -> Self {
+        InpPort::default()
+    }
+
+    /// Adds a button that is pressed while the bits in `mask` are set,
+    /// `IP_ACTIVE_HIGH` in the driver.
+    pub fn button(self, name: &'static str, mask: u32) -> Self {
+        self.add(name, mask, false)
+    }
+
+    /// Adds a button that is pressed while the bits in `mask` are clear,
+    /// `IP_ACTIVE_LOW` in the driver.
+    pub fn active_low_button(self, name: &'static str, mask: u32) -> Self {
+        self.add(name, mask, true)
+    }
+
+    fn add(mut self, name: &'static str, mask: u32, active_low: bool) -> Self {
+        self.buttons.push(InpButton { name, mask, active_low });
+        self
+    }
+}
+
+/// A recording being played back into an `InputState` a frame at a time.
+pub struct Playback {
+    recording: Recording,
+    layout: Vec<InpPort>,
+    next: usize,
+}
+
+impl Playback {
+    /// Plays `recording` back with `layout` describing each of its ports.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `layout` does not have a port for each one recorded.
+    pub fn new(recording: Recording, layout: Vec<InpPort>) -> Self {
+        if let Some(frame) = recording.frames.first() {
+            assert_eq!(frame.ports.len(), layout.len(), "the recording has {} ports", frame.ports.len());
+        }
+        Playback { recording, layout, next: 0 }
+    }
+
+    /// Reads a recording of a system with the ports in `layout`.
+    pub fn read(input: impl Read, layout: Vec<InpPort>) -> io::Result<Self> {
+        Ok(Playback::new(Recording::read(input, layout.len())?, layout))
+    }
+
+    pub fn header(&self) -> &InpHeader {
+        &self.recording.header
+    }
+
+    /// Sets the buttons of `input` as they were during the next frame.
+    /// Once the recording has run out, returns `false` having released
+    /// every button in the layout.
+    pub fn next_frame(&mut self, input: &mut InputState) -> bool {
+        let frame = match self.recording.frames.get(self.next) {
+            Some(frame) => frame,
+            None => {
+                for button in self.layout.iter().flat_map(|port| &port.buttons) {
+                    input.clear(button.name);
+                }
+                return false;
+            }
+        };
+        for (port, &value) in self.layout.iter().zip(&frame.ports) {
+            for button in &port.buttons {
+                input.set_pressed(button.name, (value & button.mask != 0) != button.active_low);
+            }
+        }
+        self.next += 1;
+        true
+    }
+
+    /// The number of frames played back so far.
+    pub fn frame(&self) -> usize {
+        self.next
+    }
+
+    /// The number of frames in the recording.
+    pub fn len(&self) -> usize {
+        self.recording.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.recording.frames.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::devices::inp::{InpPort, Playback, Recording, MAGIC};
+    use crate::devices::input::InputState;
+    use crate::inflate::adler32;
+    use crate::io::InputDevice;
+
+    // An .inp file of `frames`, each a list of port values, stored
+    // uncompressed in the zlib stream
+    fn inp_file(version: u8, frames: &[&[u32]]) -> Vec<u8> {
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&1_700_000_000u64.to_le_bytes());
+        file.extend_from_slice(&[version, 0, 0, 0]);
+        file.extend_from_slice(b"invaders\0\0\0\0");
+        file.extend_from_slice(&[0x00; 32]);
+        file[0x20..0x25].copy_from_slice(b"0.261");
+
+        let mut data = Vec::new();
+        for (n, ports) in frames.iter().enumerate() {
+            data.extend_from_slice(&0i32.to_le_bytes());
+            data.extend_from_slice(&(n as i64 * 16_666_666_666_666_666).to_le_bytes());
+            data.extend_from_slice(&(1u32 << 20).to_le_bytes());
+            for value in *ports {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        file.extend_from_slice(&[0x78, 0x01, 0x01]);
+        file.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        file.extend_from_slice(&(!(data.len() as u16)).to_le_bytes());
+        file.extend_from_slice(&data);
+        file.extend_from_slice(&adler32(&data).to_be_bytes());
+        file
+    }
+
+    fn layout() -> Vec<InpPort> {
+        vec![
+            InpPort::new(),
+            InpPort::new().active_low_button("COIN", 0x01).button("P1_START", 0x04),
+        ]
+    }
+
+    #[test]
+    fn read_a_recording() {
+        let file = inp_file(3, &[&[0xff, 0x01], &[0xff, 0x00]]);
+        let recording = Recording::read(&file[..], 2).unwrap();
+        assert_eq!(recording.header.system, "invaders");
+        assert_eq!(recording.header.app, "0.261");
+        assert_eq!(recording.header.version, (3, 0));
+        assert_eq!(recording.frames.len(), 2);
+        assert_eq!(recording.frames[1].attoseconds, 16_666_666_666_666_666);
+        assert_eq!(recording.frames[1].speed, 1 << 20);
+        assert_eq!(recording.frames[1].ports, [0xff, 0x00]);
+
+        let error = |file: &[u8], ports| Recording::read(file, ports).unwrap_err().to_string();
+        assert_eq!(error(&file, 3), "input recording does not divide into frames of 3 ports");
+        assert_eq!(error(&inp_file(2, &[]), 2), "input recording version 2.0 is not supported");
+        assert_eq!(error(&file[..0x30], 2), "not a MAME input recording");
+        assert_eq!(error(&file[..file.len() - 1], 2), "zlib stream ends early");
+    }
+
+    #[test]
+    fn play_back() {
+        let file = inp_file(3, &[&[0x00, 0x01], &[0x00, 0x00], &[0xff, 0x05], &[0x00, 0x04]]);
+        let mut playback = Playback::read(&file[..], layout()).unwrap();
+        assert_eq!(playback.len(), 4);
+        let mut input = InputState::space_invaders();
+
+        let mut frames = Vec::new();
+        while playback.next_frame(&mut input) {
+            frames.push((input.is_pressed("COIN"), input.is_pressed("P1_START"), input.input(0x01)));
+        }
+        assert_eq!(frames, [(false, false, 0x08), (true, false, 0x09), (false, true, 0x0c), (true, true, 0x0d)]);
+        assert_eq!(playback.frame(), 4);
+        assert_eq!(input.input(0x01), 0x08);
+    }
+
+    #[test]
+    #[should_panic(expected = "the recording has 2 ports")]
+    fn layout_must_match() {
+        let recording = Recording::read(&inp_file(3, &[&[0x00, 0x00]])[..], 2).unwrap();
+        Playback::new(recording, vec![InpPort::new()]);
+    }
+}
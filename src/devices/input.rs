@@ -0,0 +1,196 @@
+use crate::io::InputDevice;
+
+// A button and where it shows up
+struct Button {
+    name: &'static str,
+    port: u8,
+    mask: u8,
+    // Pressing it clears the bit rather than setting it
+    active_low: bool,
+    pressed: bool,
+}
+
+/// Buttons, joysticks and DIP switches read through input ports, by name.
+/// Each is a bit of a port, which reads as its idle value with the bits of
+/// the buttons held down flipped. The front-end only maps its key events
+/// to button names:
+///
+/// ```
+/// use i8080_emulator::devices::input::InputState;
+/// use i8080_emulator::io::InputDevice;
+///
+/// let mut input = InputState::new()
+///     .with_port(0x01, 0x08)
+///     .button("COIN", 0x01, 0)
+///     .button("FIRE", 0x01, 4);
+/// input.set("FIRE");
+/// assert_eq!(input.input(0x01), 0x18);
+/// input.clear("FIRE");
+/// assert_eq!(input.input(0x01), 0x08);
+/// ```
+pub struct InputState {
+    buttons: Vec<Button>,
+    idle: [u8; 0x100],
+}
+
+impl Default for InputState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InputState {
+    /// No buttons, with every port reading 0x00.
+    pub fn new() -> Self {
+        InputState {
+            buttons: Vec::new(),
+            idle: [0x00; 0x100],
+        }
+    }
+
+    /// Sets what `port` reads with nothing pressed, for bits that are
+    /// always set on the board.
+    pub fn with_port(mut self, port: u8, idle: u8) -> Self {
+        self.idle[usize::from(port)] = idle;
+        self
+    }
+
+    /// Adds a button that sets `bit` of `port` while pressed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bit` is above 7.
+    pub fn button(self, name: &'static str, port: u8, bit: u8) -> Self {
+        self.add(name, port, bit, false)
+    }
+
+    /// Adds a button that clears `bit` of `port` while pressed. The bit
+    /// is set in the port's idle value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bit` is above 7.
+    pub fn active_low_button(self, name: &'static str, port: u8, bit: u8) -> Self {
+        self.add(name, port, bit, true)
+    }
+
+    fn add(mut self, name: &'static str, port: u8, bit: u8, active_low: bool) -> Self {
+        assert!(bit < 8, "there is no bit {}", bit);
+        let mask = 1 << bit;
+        if active_low {
+            self.idle[usize::from(port)] |= mask;
+        }
+        self.buttons.push(Button { name, port, mask, active_low, pressed: false });
+        self
+    }
+
+    /// The Space Invaders cabinet: coin slot, start buttons and both
+    /// players' controls on ports 1 and 2, with the DIP switches off.
+    pub fn space_invaders() -> Self {
+        InputState::new()
+            .with_port(0x01, 0x08)
+            .button("COIN", 0x01, 0)
+            .button("P2_START", 0x01, 1)
+            .button("P1_START", 0x01, 2)
+            .button("P1_FIRE", 0x01, 4)
+            .button("P1_LEFT", 0x01, 5)
+            .button("P1_RIGHT", 0x01, 6)
+            .button("TILT", 0x02, 2)
+            .button("P2_FIRE", 0x02, 4)
+            .button("P2_LEFT", 0x02, 5)
+            .button("P2_RIGHT", 0x02, 6)
+    }
+
+    /// Presses or releases every button called `name`. Returns `false` if
+    /// there is no such button.
+    pub fn set_pressed(&mut self, name: &str, pressed: bool) -> bool {
+        let mut found = false;
+        for button in self.buttons.iter_mut().filter(|button| button.name == name) {
+            button.pressed = pressed;
+            found = true;
+        }
+        found
+    }
+
+    /// Presses `name`.
+    pub fn set(&mut self, name: &str) -> bool {
+        self.set_pressed(name, true)
+    }
+
+    /// Releases `name`.
+    pub fn clear(&mut self, name: &str) -> bool {
+        self.set_pressed(name, false)
+    }
+
+    pub fn is_pressed(&self, name: &str) -> bool {
+        self.buttons.iter().any(|button| button.name == name && button.pressed)
+    }
+
+    /// Releases every button.
+    pub fn release_all(&mut self) {
+        for button in &mut self.buttons {
+            button.pressed = false;
+        }
+    }
+
+    /// The names of the buttons, in the order they were added.
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.buttons.iter().map(|button| button.name)
+    }
+}
+
+impl InputDevice for InputState {
+    fn input(&mut self, port: u8) -> u8 {
+        let pressed = self.buttons.iter().filter(|button| button.port == port && button.pressed);
+        pressed.fold(self.idle[usize::from(port)], |value, button| {
+            if button.active_low {
+                value & !button.mask
+            } else {
+                value | button.mask
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::devices::input::InputState;
+    use crate::io::{InputDevice, PortBus};
+    use crate::memory::Memory8080;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn buttons() {
+        let mut input = InputState::new()
+            .with_port(0x10, 0x40)
+            .button("UP", 0x10, 0)
+            .active_low_button("DOWN", 0x10, 7)
+            .button("UP", 0x11, 3);
+        assert_eq!(input.input(0x10), 0xc0);
+        assert!(input.set("UP") && input.set("DOWN"));
+        assert!(input.is_pressed("UP"));
+        assert_eq!((input.input(0x10), input.input(0x11)), (0x41, 0x08));
+        assert!(!input.set("LEFT"));
+        input.release_all();
+        assert_eq!(input.input(0x10), 0xc0);
+        assert_eq!(input.names().collect::<Vec<_>>(), ["UP", "DOWN", "UP"]);
+    }
+
+    #[test]
+    fn on_the_bus() {
+        let input = Rc::new(RefCell::new(InputState::space_invaders()));
+        let mut bus = PortBus::new();
+        bus.map_input(0x01, input.clone());
+        input.borrow_mut().set("COIN");
+
+        // IN 0x01
+        let mut memory = [0x00; 0x10000];
+        memory[..2].copy_from_slice(&[0xdb, 0x01]);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        bus.step(&mut cpu);
+        assert_eq!(cpu.regs.a, 0x09);
+    }
+}
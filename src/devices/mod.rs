@@ -0,0 +1,14 @@
+//! Peripherals implementing the `Device` trait.
+
+pub mod control;
+pub mod disk;
+pub mod dma;
+pub mod ident;
+pub mod inp;
+pub mod input;
+pub mod rtc;
+pub mod serial;
+pub mod sound;
+pub mod tape;
+pub mod timer;
+pub mod watchdog;
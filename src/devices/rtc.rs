@@ -0,0 +1,239 @@
+use crate::cpu::{ClockCycles, Timestamp};
+use crate::device::{Device, DeviceId, DeviceState, StateError};
+
+use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the clock gets the current time from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeSource {
+    /// Wall-clock time of the host.
+    Host,
+    /// Starts at `epoch` (seconds since 1970-01-01 UTC) and advances with
+    /// emulated cycles only, so runs are reproducible.
+    Fixed { epoch: u64 },
+}
+
+/// Registers readable through the data port, selected by writing their
+/// index to the select port.
+pub mod reg {
+    pub const SECONDS: u8 = 0;
+    pub const MINUTES: u8 = 1;
+    pub const HOURS: u8 = 2;
+    pub const DAY: u8 = 3;
+    pub const MONTH: u8 = 4;
+    /// Year modulo 100.
+    pub const YEAR: u8 = 5;
+    /// 0 is Sunday.
+    pub const WEEKDAY: u8 = 6;
+    /// Low byte of the CP/M 3 date, days since 1977-12-31.
+    pub const CPM_DAYS_LO: u8 = 7;
+    pub const CPM_DAYS_HI: u8 = 8;
+}
+
+// Days from 1970-01-01 to 1977-12-31, the day before CP/M's day 1
+const CPM_EPOCH_DAYS: u64 = 2921;
+
+/// A real-time clock on two consecutive ports: `port` selects a register
+/// (see `reg`) and `port + 1` reads it. Selecting a register latches the
+/// whole time, so reading several registers in a row is consistent.
+pub struct Rtc {
+    port: u8,
+    source: TimeSource,
+    clock_hz: u64,
+    cycles: Timestamp,
+    selected: u8,
+    latched: [u8; 9],
+}
+
+impl Rtc {
+    pub const STATE_ID: DeviceId = *b"RTCK";
+    pub const STATE_VERSION: u16 = 1;
+
+    pub fn new(port: u8, source: TimeSource) -> Self {
+        Rtc {
+            port,
+            source,
+            clock_hz: 2_000_000,
+            cycles: 0,
+            selected: 0,
+            latched: [0; 9],
+        }
+    }
+
+    /// Sets the CPU clock used to turn cycles into seconds for
+    /// `TimeSource::Fixed`. Defaults to 2 MHz.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `hz` is 0.
+    pub fn with_clock_rate(mut self, hz: u64) -> Self {
+        assert!(hz > 0, "the clock rate must not be 0");
+        self.clock_hz = hz;
+        self
+    }
+
+    /// Current time in seconds since 1970-01-01 UTC.
+    pub fn now(&self) -> u64 {
+        match self.source {
+            TimeSource::Host => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            TimeSource::Fixed { epoch } => epoch + self.cycles / self.clock_hz,
+        }
+    }
+
+    fn latch(&mut self) {
+        let now = self.now();
+        let days = now / 86400;
+        let secs = now % 86400;
+        let (year, month, day) = civil_from_days(days);
+        let cpm_days = days.saturating_sub(CPM_EPOCH_DAYS);
+
+        self.latched = [
+            (secs % 60) as u8,
+            (secs / 60 % 60) as u8,
+            (secs / 3600) as u8,
+            day,
+            month,
+            (year % 100) as u8,
+            // 1970-01-01 was a Thursday
+            ((days + 4) % 7) as u8,
+            cpm_days as u8,
+            (cpm_days >> 8) as u8,
+        ];
+    }
+}
+
+// Converts days since 1970-01-01 to a (year, month, day) date in the
+// proleptic Gregorian calendar.
+fn civil_from_days(days: u64) -> (u64, u8, u8) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl Device for Rtc {
+    fn reset(&mut self) {
+        self.cycles = 0;
+        self.selected = 0;
+        self.latched = [0; 9];
+    }
+
+    fn tick(&mut self, cycles: ClockCycles) {
+        self.cycles = self.cycles.wrapping_add(Timestamp::from(cycles));
+    }
+
+    fn read(&mut self, port: u8) -> u8 {
+        if port == self.port.wrapping_add(1) {
+            self.latched.get(self.selected as usize).copied().unwrap_or(0xff)
+        } else {
+            0xff
+        }
+    }
+
+    fn write(&mut self, port: u8, value: u8) {
+        if port == self.port {
+            self.selected = value;
+            self.latch();
+        }
+    }
+
+    /// The cycles counted and the latched time. Under `TimeSource::Host`
+    /// the time read after loading is the host's again.
+    fn save_state(&self) -> Option<DeviceState> {
+        let mut state = DeviceState::new(Rtc::STATE_ID, Rtc::STATE_VERSION);
+        state.push_u64(self.cycles);
+        state.push_u8(self.selected);
+        state.push_bytes(&self.latched);
+        Some(state)
+    }
+
+    fn load_state(&mut self, state: &DeviceState) -> Result<(), StateError> {
+        let mut fields = state.reader();
+        let cycles = fields.u64()?;
+        let selected = fields.u8()?;
+        let latched = fields.bytes()?.try_into().map_err(|_| StateError::Corrupt)?;
+        fields.end()?;
+        self.cycles = cycles;
+        self.selected = selected;
+        self.latched = latched;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{restore, Device};
+    use crate::devices::rtc::{reg, Rtc, TimeSource};
+
+    // 2024-02-29 13:45:30 UTC, a Thursday
+    const LEAP_DAY: u64 = 1_709_214_330;
+
+    fn read(rtc: &mut Rtc, register: u8) -> u8 {
+        rtc.write(0x40, register);
+        rtc.read(0x41)
+    }
+
+    #[test]
+    fn fixed_epoch() {
+        let mut rtc = Rtc::new(0x40, TimeSource::Fixed { epoch: LEAP_DAY });
+        assert_eq!(read(&mut rtc, reg::SECONDS), 30);
+        assert_eq!(read(&mut rtc, reg::MINUTES), 45);
+        assert_eq!(read(&mut rtc, reg::HOURS), 13);
+        assert_eq!(read(&mut rtc, reg::DAY), 29);
+        assert_eq!(read(&mut rtc, reg::MONTH), 2);
+        assert_eq!(read(&mut rtc, reg::YEAR), 24);
+        assert_eq!(read(&mut rtc, reg::WEEKDAY), 4);
+    }
+
+    #[test]
+    fn advances_with_cycles() {
+        let mut rtc = Rtc::new(0x40, TimeSource::Fixed { epoch: LEAP_DAY }).with_clock_rate(1000);
+        rtc.tick(30_000);
+        assert_eq!(read(&mut rtc, reg::SECONDS), 0);
+        assert_eq!(read(&mut rtc, reg::MINUTES), 46);
+        rtc.reset();
+        assert_eq!(read(&mut rtc, reg::SECONDS), 30);
+    }
+
+    #[test]
+    #[should_panic(expected = "the clock rate must not be 0")]
+    fn stopped_clock() {
+        let _ = Rtc::new(0x40, TimeSource::Fixed { epoch: LEAP_DAY }).with_clock_rate(0);
+    }
+
+    #[test]
+    fn cpm_day_count() {
+        // 1978-01-01 is CP/M day 1
+        let mut rtc = Rtc::new(0x40, TimeSource::Fixed { epoch: 252_460_800 });
+        assert_eq!(read(&mut rtc, reg::CPM_DAYS_LO), 1);
+        assert_eq!(read(&mut rtc, reg::CPM_DAYS_HI), 0);
+    }
+
+    #[test]
+    fn save_state() {
+        let mut rtc = Rtc::new(0x40, TimeSource::Fixed { epoch: LEAP_DAY }).with_clock_rate(1000);
+        rtc.tick(30_000);
+        rtc.write(0x40, reg::MINUTES);
+        let mut copy = Rtc::new(0x40, TimeSource::Fixed { epoch: LEAP_DAY }).with_clock_rate(1000);
+        restore(&mut copy, rtc.save_state().unwrap()).unwrap();
+        assert_eq!(copy.read(0x41), 46);
+        assert_eq!(read(&mut copy, reg::SECONDS), 0);
+    }
+
+    #[test]
+    fn unknown_register() {
+        let mut rtc = Rtc::new(0x40, TimeSource::Host);
+        assert_eq!(read(&mut rtc, 0x20), 0xff);
+        assert_eq!(rtc.read(0x42), 0xff);
+    }
+}
@@ -0,0 +1,513 @@
+use crate::cpu::ClockCycles;
+use crate::device::{Device, DeviceId, DeviceState, StateError};
+use crate::error::EmulatorError;
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+/// Status port bit set while a received byte is waiting to be read.
+pub const RX_READY: u8 = 0x01;
+/// Status port bit set when the transmitter can take a byte. The host
+/// drains output instantly, so it is always set.
+pub const TX_READY: u8 = 0x02;
+
+/// A polled serial port on two consecutive ports: `port` reads the status
+/// (see `RX_READY` and `TX_READY`) and `port + 1` reads and writes data.
+///
+/// The host queues bytes for the guest with `send` and collects what the
+/// guest transmitted with `take_output`. A guest polling the status port
+/// with nothing left to receive marks the port as starved, which is the
+/// host's cue to feed it more input.
+pub struct Serial {
+    port: u8,
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    starved: bool,
+}
+
+impl Serial {
+    pub const STATE_ID: DeviceId = *b"SERL";
+    pub const STATE_VERSION: u16 = 1;
+
+    pub fn new(port: u8) -> Self {
+        Serial {
+            port,
+            input: VecDeque::new(),
+            output: Vec::new(),
+            starved: false,
+        }
+    }
+
+    /// Queues `data` for the guest to receive.
+    pub fn send(&mut self, data: &[u8]) {
+        self.input.extend(data);
+        self.starved = false;
+    }
+
+    /// Returns the bytes transmitted since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Whether the guest has polled for input since the queue ran dry.
+    pub fn is_starved(&self) -> bool {
+        self.starved
+    }
+}
+
+impl Device for Serial {
+    fn reset(&mut self) {
+        self.input.clear();
+        self.output.clear();
+        self.starved = false;
+    }
+
+    fn read(&mut self, port: u8) -> u8 {
+        if port == self.port {
+            if self.input.is_empty() {
+                self.starved = true;
+                TX_READY
+            } else {
+                TX_READY | RX_READY
+            }
+        } else if port == self.port.wrapping_add(1) {
+            self.input.pop_front().unwrap_or(0x00)
+        } else {
+            0xff
+        }
+    }
+
+    fn write(&mut self, port: u8, value: u8) {
+        if port == self.port.wrapping_add(1) {
+            self.output.push(value);
+        }
+    }
+
+    /// The bytes waiting for the guest. Output not yet taken is the
+    /// host's.
+    fn save_state(&self) -> Option<DeviceState> {
+        let mut state = DeviceState::new(Serial::STATE_ID, Serial::STATE_VERSION);
+        state.push_bytes(&self.input.iter().copied().collect::<Vec<_>>());
+        state.push_bool(self.starved);
+        Some(state)
+    }
+
+    fn load_state(&mut self, state: &DeviceState) -> Result<(), StateError> {
+        let mut fields = state.reader();
+        let (input, starved) = (fields.bytes()?, fields.bool()?);
+        fields.end()?;
+        self.input = input.iter().copied().collect();
+        self.starved = starved;
+        Ok(())
+    }
+}
+
+/// Bits of an 8251's status, read from `port + 1` of a `Uart`.
+pub mod status {
+    pub const TX_READY: u8 = 0x01;
+    pub const RX_READY: u8 = 0x02;
+    pub const TX_EMPTY: u8 = 0x04;
+    /// Set while the other end is connected.
+    pub const DSR: u8 = 0x80;
+}
+
+/// Bits of an 8251 command, written to `port + 1` of a `Uart` after the
+/// mode.
+pub mod command {
+    pub const TX_ENABLE: u8 = 0x01;
+    pub const RX_ENABLE: u8 = 0x04;
+    /// Makes the next control write a mode instruction again.
+    pub const INTERNAL_RESET: u8 = 0x40;
+}
+
+// Transmitter and receiver on, as after the usual 0x37 command
+const DEFAULT_COMMAND: u8 = command::TX_ENABLE | command::RX_ENABLE;
+
+/// An 8251 USART connected to a host stream: `port` reads and writes
+/// data and `port + 1` reads the status (see `status`) and takes mode
+/// and command words (see `command`). Programs that initialise the chip
+/// with a mode and a command work, and so do programs that never do,
+/// since the transmitter and receiver start out enabled. The line format
+/// in the mode word changes nothing.
+///
+/// Input is read on a thread of its own, so a guest polling the status
+/// never blocks; bytes are sent to the output as the guest writes them.
+/// With `accept` the other end can be a telnet client:
+///
+/// ```no_run
+/// use i8080_emulator::devices::serial::Uart;
+/// use std::net::TcpListener;
+///
+/// let listener = TcpListener::bind("127.0.0.1:8080")?;
+/// // Waits for someone to connect
+/// let console = Uart::accept(0x00, &listener)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+///
+/// Telnet option negotiation is passed through to the guest as data.
+///
+/// By default the transmitter is always ready, as the host takes output
+/// instantly. With `with_cycles_per_byte` it paces itself like the chip:
+/// a byte written takes that many cycles to shift out, with room for one
+/// more behind it, so `TX_READY` drops while both are taken and
+/// `TX_EMPTY` until the last has gone. Nothing written is lost, even by a
+/// guest that writes without looking at the status.
+///
+/// ```
+/// use i8080_emulator::device::Device;
+/// use i8080_emulator::devices::serial::{status, Uart};
+/// use i8080_emulator::devices::tape::cycles_per_byte;
+///
+/// // 9600 baud on a 2 MHz 8080
+/// let mut uart = Uart::new(0x00, std::io::empty(), Vec::new())
+///     .with_cycles_per_byte(cycles_per_byte(9600, 2_000_000));
+/// uart.write(0x00, b'A');
+/// uart.write(0x00, b'B');
+/// assert_eq!(uart.read(0x01) & status::TX_READY, 0);
+/// uart.tick(2083);
+/// assert_eq!(uart.read(0x01) & (status::TX_READY | status::TX_EMPTY), status::TX_READY);
+/// uart.tick(2083);
+/// assert_eq!(uart.read(0x01) & status::TX_EMPTY, status::TX_EMPTY);
+/// assert_eq!(uart.output(), b"AB");
+/// ```
+pub struct Uart<W: Write> {
+    port: u8,
+    input: Receiver<Result<Vec<u8>, io::ErrorKind>>,
+    received: VecDeque<u8>,
+    output: W,
+    // The next control write is a mode instruction
+    expect_mode: bool,
+    command: u8,
+    // The input has ended
+    closed: bool,
+    // The first I/O error, for the machine to report
+    error: Option<EmulatorError>,
+    cycles_per_byte: ClockCycles,
+    // Cycles until everything written has been shifted out
+    sending: ClockCycles,
+}
+
+impl<W: Write> Uart<W> {
+    pub const STATE_ID: DeviceId = *b"8251";
+    pub const STATE_VERSION: u16 = 1;
+
+    /// A UART receiving from `input` and transmitting to `output`.
+    pub fn new(port: u8, mut input: impl Read + Send + 'static, output: W) -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0; 256];
+            loop {
+                let chunk = match input.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => Ok(buffer[..n].to_vec()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e.kind()),
+                };
+                let failed = chunk.is_err();
+                if tx.send(chunk).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Uart {
+            port,
+            input: rx,
+            received: VecDeque::new(),
+            output,
+            expect_mode: true,
+            command: DEFAULT_COMMAND,
+            closed: false,
+            error: None,
+            cycles_per_byte: 0,
+            sending: 0,
+        }
+    }
+
+    /// Makes each byte take `cycles` to transmit; 0, the default, sends
+    /// them instantly. See the type's documentation.
+    pub fn with_cycles_per_byte(mut self, cycles: ClockCycles) -> Self {
+        self.cycles_per_byte = cycles;
+        self
+    }
+
+    /// Whether the input has ended, because the other end hung up or a
+    /// read failed. Bytes received before then can still be read.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Returns and clears the first read or write error.
+    pub fn take_error(&mut self) -> Option<EmulatorError> {
+        self.error.take()
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    // Collects whatever the input thread has received
+    fn poll(&mut self) {
+        loop {
+            match self.input.try_recv() {
+                Ok(Ok(bytes)) => self.received.extend(bytes),
+                Ok(Err(kind)) => {
+                    self.error.get_or_insert(EmulatorError::Input(kind));
+                    self.closed = true;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        self.poll();
+        let mut status = 0x00;
+        if self.command & command::TX_ENABLE != 0 {
+            // The holding register is free once at most the byte in the
+            // shift register is left
+            if self.sending <= self.cycles_per_byte {
+                status |= status::TX_READY;
+            }
+            if self.sending == 0 {
+                status |= status::TX_EMPTY;
+            }
+        }
+        if self.command & command::RX_ENABLE != 0 && !self.received.is_empty() {
+            status |= status::RX_READY;
+        }
+        if !self.closed {
+            status |= status::DSR;
+        }
+        status
+    }
+}
+
+impl Uart<io::Stdout> {
+    /// A UART on the host's terminal.
+    pub fn stdio(port: u8) -> Self {
+        Uart::new(port, io::stdin(), io::stdout())
+    }
+}
+
+impl Uart<TcpStream> {
+    /// A UART on a TCP connection, with Nagle's algorithm off so that
+    /// characters go out as they are typed.
+    pub fn tcp(port: u8, stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(Uart::new(port, stream.try_clone()?, stream))
+    }
+
+    /// Waits for a connection on `listener` and puts a UART on it.
+    pub fn accept(port: u8, listener: &TcpListener) -> io::Result<Self> {
+        let (stream, _) = listener.accept()?;
+        Uart::tcp(port, stream)
+    }
+}
+
+impl<W: Write> Device for Uart<W> {
+    fn reset(&mut self) {
+        self.expect_mode = true;
+        self.command = DEFAULT_COMMAND;
+        self.sending = 0;
+    }
+
+    fn tick(&mut self, cycles: ClockCycles) {
+        self.sending = self.sending.saturating_sub(cycles);
+    }
+
+    fn read(&mut self, port: u8) -> u8 {
+        if port == self.port {
+            self.poll();
+            self.received.pop_front().unwrap_or(0x00)
+        } else if port == self.port.wrapping_add(1) {
+            self.status()
+        } else {
+            0xff
+        }
+    }
+
+    fn write(&mut self, port: u8, value: u8) {
+        if port == self.port {
+            if self.command & command::TX_ENABLE == 0 {
+                return;
+            }
+            self.sending = self.sending.saturating_add(self.cycles_per_byte);
+            let written = self.output.write_all(&[value]).and_then(|_| self.output.flush());
+            if let Err(e) = written {
+                self.error.get_or_insert(EmulatorError::Output(e.kind()));
+            }
+        } else if port == self.port.wrapping_add(1) {
+            if self.expect_mode {
+                self.expect_mode = false;
+            } else if value & command::INTERNAL_RESET != 0 {
+                self.expect_mode = true;
+            } else {
+                self.command = value;
+            }
+        }
+    }
+
+    /// The chip's mode and command, the time left to transmit and the
+    /// bytes received but not yet read. Whether the host stream is still
+    /// open goes with the stream.
+    fn save_state(&self) -> Option<DeviceState> {
+        let mut state = DeviceState::new(Self::STATE_ID, Self::STATE_VERSION);
+        state.push_bool(self.expect_mode);
+        state.push_u8(self.command);
+        state.push_u32(self.sending);
+        state.push_bytes(&self.received.iter().copied().collect::<Vec<_>>());
+        Some(state)
+    }
+
+    fn load_state(&mut self, state: &DeviceState) -> Result<(), StateError> {
+        let mut fields = state.reader();
+        let (expect_mode, command, sending) = (fields.bool()?, fields.u8()?, fields.u32()?);
+        let received = fields.bytes()?;
+        fields.end()?;
+        self.expect_mode = expect_mode;
+        self.command = command;
+        self.sending = sending;
+        self.received = received.iter().copied().collect();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{restore, Device};
+    use crate::devices::serial::{command, status, Serial, Uart, RX_READY, TX_READY};
+
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    // Polls the status until `bits` are as wanted, or gives up after a
+    // few seconds
+    fn wait_for<W: Write>(uart: &mut Uart<W>, bits: u8, set: bool) -> bool {
+        (0..500).any(|_| {
+            let ready = (uart.read(0x01) & bits != 0) == set;
+            if !ready {
+                thread::sleep(Duration::from_millis(10));
+            }
+            ready
+        })
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut serial = Serial::new(0x10);
+        assert_eq!(serial.read(0x10), TX_READY);
+        assert!(serial.is_starved());
+        serial.send(b"hi");
+        assert!(!serial.is_starved());
+        assert_eq!(serial.read(0x10), TX_READY | RX_READY);
+        assert_eq!(serial.read(0x11), b'h');
+        assert_eq!(serial.read(0x11), b'i');
+        assert_eq!(serial.read(0x10), TX_READY);
+        serial.write(0x11, b'!');
+        serial.write(0x10, b'?');
+        assert_eq!(serial.take_output(), b"!");
+        assert!(serial.take_output().is_empty());
+    }
+
+    #[test]
+    fn uart_programming() {
+        let mut uart = Uart::new(0x00, &b"ok"[..], Vec::new());
+        assert!(wait_for(&mut uart, status::RX_READY, true));
+        assert_eq!(uart.read(0x00), b'o');
+        // Mode, then a command with the receiver off
+        uart.write(0x01, 0x4e);
+        uart.write(0x01, command::TX_ENABLE);
+        assert_eq!(uart.read(0x01) & (status::TX_READY | status::RX_READY), status::TX_READY);
+        uart.write(0x00, b'!');
+        uart.write(0x01, command::INTERNAL_RESET);
+        uart.write(0x01, 0x4e);
+        uart.write(0x01, 0x00);
+        uart.write(0x00, b'?');
+        assert_eq!(uart.output(), b"!");
+        assert!(wait_for(&mut uart, status::DSR, false));
+        assert!(uart.is_closed());
+        assert_eq!(uart.read(0x01), 0x00);
+        assert!(uart.take_error().is_none());
+    }
+
+    #[test]
+    fn uart_pacing() {
+        let mut uart = Uart::new(0x00, std::io::empty(), Vec::new()).with_cycles_per_byte(100);
+        let tx = status::TX_READY | status::TX_EMPTY;
+        assert_eq!(uart.read(0x01) & tx, tx);
+        uart.write(0x00, b'a');
+        assert_eq!(uart.read(0x01) & tx, status::TX_READY);
+        uart.write(0x00, b'b');
+        assert_eq!(uart.read(0x01) & tx, 0);
+        // Not lost though written while busy
+        uart.write(0x00, b'c');
+        uart.tick(199);
+        assert_eq!(uart.read(0x01) & tx, 0);
+        uart.tick(1);
+        assert_eq!(uart.read(0x01) & tx, status::TX_READY);
+        uart.tick(100);
+        assert_eq!(uart.read(0x01) & tx, tx);
+        assert_eq!(uart.output(), b"abc");
+
+        uart.write(0x00, b'd');
+        uart.write(0x00, b'e');
+        uart.reset();
+        assert_eq!(uart.read(0x01) & tx, tx);
+    }
+
+    #[test]
+    fn save_state() {
+        let mut serial = Serial::new(0x10);
+        serial.send(b"abc");
+        serial.read(0x11);
+        let mut copy = Serial::new(0x10);
+        restore(&mut copy, serial.save_state().unwrap()).unwrap();
+        assert_eq!((copy.read(0x11), copy.read(0x11), copy.read(0x10)), (b'b', b'c', TX_READY));
+
+        let mut uart = Uart::new(0x00, &b"xy"[..], Vec::new()).with_cycles_per_byte(100);
+        assert!(wait_for(&mut uart, status::RX_READY, true));
+        uart.write(0x01, 0x4e);
+        uart.write(0x01, command::TX_ENABLE | command::RX_ENABLE);
+        uart.write(0x00, b'!');
+        let mut copy = Uart::new(0x00, std::io::empty(), Vec::new()).with_cycles_per_byte(100);
+        restore(&mut copy, uart.save_state().unwrap()).unwrap();
+        assert_eq!(copy.read(0x01) & (status::TX_EMPTY | status::RX_READY), status::RX_READY);
+        assert_eq!((copy.read(0x00), copy.read(0x00)), (b'x', b'y'));
+        copy.tick(100);
+        // The command stays, as it follows the mode
+        copy.write(0x01, 0x00);
+        assert_eq!(copy.read(0x01) & status::TX_READY, 0);
+    }
+
+    #[test]
+    fn uart_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"DIR\r").unwrap();
+            let mut reply = [0; 2];
+            stream.read_exact(&mut reply).unwrap();
+            reply
+        });
+        let mut uart = Uart::accept(0x00, &listener).unwrap();
+        let mut line = Vec::new();
+        while line.len() < 4 && wait_for(&mut uart, status::RX_READY, true) {
+            line.push(uart.read(0x00));
+        }
+        assert_eq!(line, b"DIR\r");
+        uart.write(0x00, b'A');
+        uart.write(0x00, b'>');
+        assert_eq!(&client.join().unwrap(), b"A>");
+        assert!(wait_for(&mut uart, status::DSR, false));
+    }
+}
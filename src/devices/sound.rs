@@ -0,0 +1,228 @@
+use crate::cpu::{ClockCycles, Timestamp};
+use crate::device::{Device, DeviceId, DeviceState, StateError};
+use crate::io::UNMAPPED_INPUT;
+
+use std::convert::TryInto;
+
+/// Whether a sound was switched on or off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Start,
+    Stop,
+}
+
+/// A sound trigger bit changing, `cycle` clock cycles after the device
+/// started counting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SoundEvent {
+    pub name: &'static str,
+    pub edge: Edge,
+    pub cycle: Timestamp,
+}
+
+// A sound and the bit switching it
+struct Sound {
+    name: &'static str,
+    port: u8,
+    mask: u8,
+}
+
+/// Output ports whose bits switch sounds on and off, as on boards where
+/// each bit drives a discrete sound circuit. Every change of a trigger bit
+/// becomes a `SoundEvent`, so the front-end only has to start and stop
+/// its samples. Events are queued for `take_events` unless a callback is
+/// set, which can pass them on to an audio thread:
+///
+/// ```
+/// use i8080_emulator::devices::sound::{Edge, SoundDevice};
+/// use i8080_emulator::device::Device;
+/// use std::sync::mpsc;
+///
+/// let (tx, rx) = mpsc::channel();
+/// let mut sound = SoundDevice::new().sound("SHOT", 0x03, 1);
+/// sound.set_callback(move |event| {
+///     let _ = tx.send(event);
+/// });
+/// sound.write(0x03, 0x02);
+/// assert_eq!(rx.recv().unwrap().edge, Edge::Start);
+/// ```
+///
+/// The cycle count comes from `tick`, so the machine has to tick the
+/// device for events to carry the time they happened.
+pub struct SoundDevice {
+    sounds: Vec<Sound>,
+    // Last value written to each port
+    latched: [u8; 0x100],
+    cycles: Timestamp,
+    events: Vec<SoundEvent>,
+    callback: Option<Box<dyn FnMut(SoundEvent)>>,
+}
+
+impl Default for SoundDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SoundDevice {
+    pub const STATE_ID: DeviceId = *b"SNDS";
+    pub const STATE_VERSION: u16 = 1;
+
+    pub fn new() -> Self {
+        SoundDevice {
+            sounds: Vec::new(),
+            latched: [0x00; 0x100],
+            cycles: 0,
+            events: Vec::new(),
+            callback: None,
+        }
+    }
+
+    /// Adds a sound that plays while `bit` of `port` is set.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bit` is above 7.
+    pub fn sound(mut self, name: &'static str, port: u8, bit: u8) -> Self {
+        assert!(bit < 8, "there is no bit {}", bit);
+        self.sounds.push(Sound { name, port, mask: 1 << bit });
+        self
+    }
+
+    /// The sounds of Space Invaders on ports 3 and 5.
+    pub fn space_invaders() -> Self {
+        SoundDevice::new()
+            .sound("UFO", 0x03, 0)
+            .sound("SHOT", 0x03, 1)
+            .sound("PLAYER_DIE", 0x03, 2)
+            .sound("INVADER_DIE", 0x03, 3)
+            .sound("EXTRA_LIFE", 0x03, 4)
+            .sound("FLEET_1", 0x05, 0)
+            .sound("FLEET_2", 0x05, 1)
+            .sound("FLEET_3", 0x05, 2)
+            .sound("FLEET_4", 0x05, 3)
+            .sound("UFO_HIT", 0x05, 4)
+    }
+
+    /// Hands every event to `callback` as it happens instead of queueing
+    /// it.
+    pub fn set_callback(&mut self, callback: impl FnMut(SoundEvent) + 'static) {
+        self.callback = Some(Box::new(callback));
+    }
+
+    /// Returns the events queued since the last call, oldest first.
+    pub fn take_events(&mut self) -> Vec<SoundEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Whether the sound called `name` is switched on.
+    pub fn is_playing(&self, name: &str) -> bool {
+        self.sounds.iter().any(|sound| sound.name == name && self.latched[usize::from(sound.port)] & sound.mask != 0)
+    }
+
+    // Reports the sounds on `port` that `value` switches
+    fn latch(&mut self, port: u8, value: u8) {
+        let changed = self.latched[usize::from(port)] ^ value;
+        self.latched[usize::from(port)] = value;
+        for sound in self.sounds.iter().filter(|sound| sound.port == port && changed & sound.mask != 0) {
+            let edge = if value & sound.mask != 0 { Edge::Start } else { Edge::Stop };
+            let event = SoundEvent { name: sound.name, edge, cycle: self.cycles };
+            match &mut self.callback {
+                Some(callback) => callback(event),
+                None => self.events.push(event),
+            }
+        }
+    }
+}
+
+impl Device for SoundDevice {
+    /// Stops every sound still playing and starts counting cycles over.
+    fn reset(&mut self) {
+        for port in 0..=0xff {
+            self.latch(port, 0x00);
+        }
+        self.cycles = 0;
+    }
+
+    fn tick(&mut self, cycles: ClockCycles) {
+        self.cycles += Timestamp::from(cycles);
+    }
+
+    fn read(&mut self, _port: u8) -> u8 {
+        UNMAPPED_INPUT
+    }
+
+    fn write(&mut self, port: u8, value: u8) {
+        self.latch(port, value);
+    }
+
+    /// What was last written to each port and the cycle count.
+    fn save_state(&self) -> Option<DeviceState> {
+        let mut state = DeviceState::new(SoundDevice::STATE_ID, SoundDevice::STATE_VERSION);
+        state.push_bytes(&self.latched);
+        state.push_u64(self.cycles);
+        Some(state)
+    }
+
+    /// Starts and stops sounds to match, with events as if the guest had
+    /// written the ports at the saved cycle count.
+    fn load_state(&mut self, state: &DeviceState) -> Result<(), StateError> {
+        let mut fields = state.reader();
+        let latched: [u8; 0x100] = fields.bytes()?.try_into().map_err(|_| StateError::Corrupt)?;
+        let cycles = fields.u64()?;
+        fields.end()?;
+        self.cycles = cycles;
+        for (port, &value) in latched.iter().enumerate() {
+            self.latch(port as u8, value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{restore, Device};
+    use crate::devices::sound::{Edge, SoundDevice, SoundEvent};
+
+    #[test]
+    fn edges() {
+        let mut sound = SoundDevice::space_invaders();
+        sound.write(0x03, 0x03);
+        sound.tick(100);
+        sound.write(0x03, 0x01);
+        sound.write(0x05, 0x01);
+        assert!(sound.is_playing("UFO") && !sound.is_playing("SHOT"));
+        sound.tick(50);
+        sound.reset();
+        let event = |name, edge, cycle| SoundEvent { name, edge, cycle };
+        assert_eq!(sound.take_events(), [
+            event("UFO", Edge::Start, 0),
+            event("SHOT", Edge::Start, 0),
+            event("SHOT", Edge::Stop, 100),
+            event("FLEET_1", Edge::Start, 100),
+            event("UFO", Edge::Stop, 150),
+            event("FLEET_1", Edge::Stop, 150),
+        ]);
+        assert!(sound.take_events().is_empty());
+    }
+
+    #[test]
+    fn save_state() {
+        let mut sound = SoundDevice::space_invaders();
+        sound.write(0x03, 0x03);
+        sound.tick(100);
+        let saved = sound.save_state().unwrap();
+
+        let mut copy = SoundDevice::space_invaders();
+        copy.write(0x05, 0x01);
+        copy.take_events();
+        restore(&mut copy, saved).unwrap();
+        assert!(copy.is_playing("UFO") && copy.is_playing("SHOT") && !copy.is_playing("FLEET_1"));
+        let event = |name, edge| SoundEvent { name, edge, cycle: 100 };
+        assert_eq!(copy.take_events(), [
+            event("UFO", Edge::Start),
+            event("SHOT", Edge::Start),
+            event("FLEET_1", Edge::Stop),
+        ]);
+    }
+}
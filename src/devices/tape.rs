@@ -0,0 +1,331 @@
+use crate::cpu::ClockCycles;
+use crate::device::{Device, DeviceId, DeviceState, StateError};
+use crate::error::EmulatorError;
+use crate::loader::LoadError;
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// Status bit set when the reader has a byte waiting or the punch can
+/// take one.
+pub const READY: u8 = 0x01;
+/// Status bit set once the reader has run off the end of the tape.
+pub const END_OF_TAPE: u8 = 0x02;
+
+// Start bit, 8 data bits and a stop bit
+const BITS_PER_BYTE: u64 = 10;
+
+/// Clock cycles a byte takes at `baud` on a CPU running at `clock_hz`,
+/// with a start and a stop bit.
+///
+/// # Panics
+///
+/// Panics if `baud` is 0.
+pub fn cycles_per_byte(baud: u32, clock_hz: u64) -> ClockCycles {
+    (clock_hz * BITS_PER_BYTE / u64::from(baud)) as ClockCycles
+}
+
+/// A paper tape or cassette reader on two consecutive ports: `port`
+/// reads the status (see `READY` and `END_OF_TAPE`) and `port + 1` the
+/// byte under the head, which starts the next one moving in. Tapes can
+/// be changed while the machine runs, so a loader in ROM or a BASIC's
+/// `CLOAD` can read programs that were not in memory at boot:
+///
+/// ```
+/// use i8080_emulator::device::Device;
+/// use i8080_emulator::devices::tape::{cycles_per_byte, TapeReader, READY};
+///
+/// // 300 baud on a 2 MHz 8080
+/// let mut reader = TapeReader::new(0x06).with_cycles_per_byte(cycles_per_byte(300, 2_000_000));
+/// reader.mount(b"10 PRINT".to_vec());
+/// assert_eq!(reader.read(0x07), b'1');
+/// assert_eq!(reader.read(0x06) & READY, 0);
+/// reader.tick(66_667);
+/// assert_eq!(reader.read(0x06) & READY, READY);
+/// ```
+///
+/// With the default of 0 cycles per byte the tape reads as fast as the
+/// program can take it.
+pub struct TapeReader {
+    port: u8,
+    tape: Vec<u8>,
+    position: usize,
+    cycles_per_byte: ClockCycles,
+    // Cycles until the next byte is under the head
+    wait: ClockCycles,
+}
+
+impl TapeReader {
+    pub const STATE_ID: DeviceId = *b"TAPR";
+    pub const STATE_VERSION: u16 = 1;
+
+    /// A reader with no tape in it.
+    pub fn new(port: u8) -> Self {
+        TapeReader {
+            port,
+            tape: Vec::new(),
+            position: 0,
+            cycles_per_byte: 0,
+            wait: 0,
+        }
+    }
+
+    /// A reader with the tape image at `path` in it.
+    pub fn open(port: u8, path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        let path = path.as_ref();
+        let tape = std::fs::read(path).map_err(|e| LoadError::Io(path.to_path_buf(), e))?;
+        let mut reader = TapeReader::new(port);
+        reader.mount(tape);
+        Ok(reader)
+    }
+
+    pub fn with_cycles_per_byte(mut self, cycles: ClockCycles) -> Self {
+        self.cycles_per_byte = cycles;
+        self
+    }
+
+    /// Puts `tape` in at its start, replacing any tape there was.
+    pub fn mount(&mut self, tape: Vec<u8>) {
+        self.tape = tape;
+        self.rewind();
+    }
+
+    /// Takes the tape out, leaving the reader at the end of an empty one.
+    pub fn eject(&mut self) -> Vec<u8> {
+        self.position = 0;
+        std::mem::take(&mut self.tape)
+    }
+
+    pub fn rewind(&mut self) {
+        self.position = 0;
+        self.wait = 0;
+    }
+
+    /// How many bytes have been read.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.position >= self.tape.len()
+    }
+}
+
+impl Device for TapeReader {
+    /// Stops the tape where it is; resetting the machine does not rewind
+    /// it.
+    fn reset(&mut self) {
+        self.wait = 0;
+    }
+
+    fn tick(&mut self, cycles: ClockCycles) {
+        self.wait = self.wait.saturating_sub(cycles);
+    }
+
+    fn read(&mut self, port: u8) -> u8 {
+        if port == self.port {
+            match (self.is_at_end(), self.wait) {
+                (true, _) => END_OF_TAPE,
+                (false, 0) => READY,
+                (false, _) => 0x00,
+            }
+        } else if port == self.port.wrapping_add(1) {
+            // Too early, or past the end, and the last byte is still there
+            if self.wait > 0 || self.is_at_end() {
+                return self.position.checked_sub(1).map_or(0x00, |last| self.tape[last]);
+            }
+            let byte = self.tape[self.position];
+            self.position += 1;
+            self.wait = self.cycles_per_byte;
+            byte
+        } else {
+            0xff
+        }
+    }
+
+    fn write(&mut self, _port: u8, _value: u8) {}
+
+    /// The tape in the reader, as tapes are small, and how far it has
+    /// got.
+    fn save_state(&self) -> Option<DeviceState> {
+        let mut state = DeviceState::new(TapeReader::STATE_ID, TapeReader::STATE_VERSION);
+        state.push_bytes(&self.tape);
+        state.push_u32(self.position as u32);
+        state.push_u32(self.wait);
+        Some(state)
+    }
+
+    fn load_state(&mut self, state: &DeviceState) -> Result<(), StateError> {
+        let mut fields = state.reader();
+        let tape = fields.bytes()?;
+        let (position, wait) = (fields.u32()? as usize, fields.u32()?);
+        fields.end()?;
+        if position > tape.len() {
+            return Err(StateError::Corrupt);
+        }
+        self.tape = tape.to_vec();
+        self.position = position;
+        self.wait = wait;
+        Ok(())
+    }
+}
+
+/// A tape punch on two consecutive ports: `port` reads the status, with
+/// `READY` set when the punch can take a byte, and `port + 1` takes the
+/// bytes to punch. Punched bytes go straight to the output; one written
+/// while the punch is busy is punched all the same.
+pub struct TapePunch<W: Write> {
+    port: u8,
+    output: W,
+    cycles_per_byte: ClockCycles,
+    // Cycles until the punch is ready again
+    busy: ClockCycles,
+    // The first write that failed, reported by the machine
+    error: Option<EmulatorError>,
+}
+
+impl<W: Write> TapePunch<W> {
+    pub const STATE_ID: DeviceId = *b"TAPP";
+    pub const STATE_VERSION: u16 = 1;
+
+    pub fn new(port: u8, output: W) -> Self {
+        TapePunch {
+            port,
+            output,
+            cycles_per_byte: 0,
+            busy: 0,
+            error: None,
+        }
+    }
+
+    pub fn with_cycles_per_byte(mut self, cycles: ClockCycles) -> Self {
+        self.cycles_per_byte = cycles;
+        self
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    pub fn into_output(self) -> W {
+        self.output
+    }
+
+    /// Returns and clears the first write error.
+    pub fn take_error(&mut self) -> Option<EmulatorError> {
+        self.error.take()
+    }
+}
+
+impl TapePunch<File> {
+    /// A punch writing a new tape image at `path`.
+    pub fn create(port: u8, path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|e| LoadError::Io(path.to_path_buf(), e))?;
+        Ok(TapePunch::new(port, file))
+    }
+}
+
+impl<W: Write> Device for TapePunch<W> {
+    fn reset(&mut self) {
+        self.busy = 0;
+    }
+
+    fn tick(&mut self, cycles: ClockCycles) {
+        self.busy = self.busy.saturating_sub(cycles);
+    }
+
+    fn read(&mut self, port: u8) -> u8 {
+        match (port == self.port, self.busy) {
+            (true, 0) => READY,
+            (true, _) => 0x00,
+            (false, _) => 0xff,
+        }
+    }
+
+    fn write(&mut self, port: u8, value: u8) {
+        if port != self.port.wrapping_add(1) {
+            return;
+        }
+        if let Err(e) = self.output.write_all(&[value]) {
+            self.error.get_or_insert(EmulatorError::Output(e.kind()));
+        }
+        self.busy = self.cycles_per_byte;
+    }
+
+    /// How long the punch is busy for. What it punched has gone to the
+    /// output.
+    fn save_state(&self) -> Option<DeviceState> {
+        let mut state = DeviceState::new(Self::STATE_ID, Self::STATE_VERSION);
+        state.push_u32(self.busy);
+        Some(state)
+    }
+
+    fn load_state(&mut self, state: &DeviceState) -> Result<(), StateError> {
+        let mut fields = state.reader();
+        let busy = fields.u32()?;
+        fields.end()?;
+        self.busy = busy;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{restore, Device};
+    use crate::devices::tape::{TapePunch, TapeReader, END_OF_TAPE, READY};
+
+    // Copies the tape to the punch a byte at a time, ticking both by
+    // `step` cycles between polls
+    fn copy(reader: &mut TapeReader, punch: &mut TapePunch<Vec<u8>>, step: u32) -> u32 {
+        let mut cycles = 0;
+        while reader.read(0x06) & END_OF_TAPE == 0 {
+            if reader.read(0x06) & READY != 0 && punch.read(0x08) & READY != 0 {
+                let byte = reader.read(0x07);
+                punch.write(0x09, byte);
+            }
+            reader.tick(step);
+            punch.tick(step);
+            cycles += step;
+        }
+        cycles
+    }
+
+    #[test]
+    fn copy_a_tape() {
+        let mut reader = TapeReader::new(0x06).with_cycles_per_byte(100);
+        reader.mount(vec![0x01, 0x02, 0x03]);
+        let mut punch = TapePunch::new(0x08, Vec::new()).with_cycles_per_byte(150);
+        // The punch is the slower: bytes go at 0, 150 and 300 cycles
+        assert_eq!(copy(&mut reader, &mut punch, 10), 310);
+        assert_eq!(punch.output(), &[0x01, 0x02, 0x03]);
+        assert!(reader.is_at_end());
+        assert_eq!(reader.read(0x07), 0x03);
+
+        reader.rewind();
+        assert_eq!((reader.read(0x06), reader.read(0x07), reader.position()), (READY, 0x01, 1));
+        assert_eq!(reader.read(0x07), 0x01);
+        assert_eq!(reader.eject(), [0x01, 0x02, 0x03]);
+        assert_eq!(reader.read(0x06), END_OF_TAPE);
+    }
+
+    #[test]
+    fn save_state() {
+        let mut reader = TapeReader::new(0x06).with_cycles_per_byte(100);
+        reader.mount(vec![0x01, 0x02, 0x03]);
+        reader.read(0x07);
+        let mut punch = TapePunch::new(0x08, Vec::new()).with_cycles_per_byte(150);
+        punch.write(0x09, 0x01);
+        punch.tick(50);
+
+        let mut reader_copy = TapeReader::new(0x06).with_cycles_per_byte(100);
+        restore(&mut reader_copy, reader.save_state().unwrap()).unwrap();
+        let mut punch_copy = TapePunch::new(0x08, Vec::new()).with_cycles_per_byte(150);
+        restore(&mut punch_copy, punch.save_state().unwrap()).unwrap();
+        assert_eq!(reader_copy.read(0x06), 0x00);
+        // Bytes go at 100 and 250 cycles
+        assert_eq!(copy(&mut reader_copy, &mut punch_copy, 10), 260);
+        assert_eq!(punch_copy.output(), &[0x02, 0x03]);
+    }
+}
@@ -0,0 +1,562 @@
+use crate::cpu::{ClockCycles, Timestamp};
+use crate::device::{Device, DeviceId, DeviceState, StateError, StateReader};
+use crate::irq::IrqLine;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Bits of the control word written to `port + 3`.
+pub mod control {
+    /// Bits 7 and 6 pick the counter the word is for.
+    pub const COUNTER_SHIFT: u8 = 6;
+    /// Bits 5 and 4: latch the count for reading, or how counts are
+    /// read and written.
+    pub const LATCH: u8 = 0x00;
+    pub const LSB: u8 = 0x10;
+    pub const MSB: u8 = 0x20;
+    pub const LSB_MSB: u8 = 0x30;
+    /// Bits 3 to 1 hold the mode, 0 to 5.
+    pub const MODE_SHIFT: u8 = 1;
+    /// Bit 0 asks for BCD counting, which is not modelled.
+    pub const BCD: u8 = 0x01;
+}
+
+/// A counter's output changing, `cycle` CPU clock cycles after the timer
+/// started counting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputChange {
+    pub counter: usize,
+    pub high: bool,
+    pub cycle: Timestamp,
+}
+
+// How a counter's value goes through its port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Lsb,
+    Msb,
+    LsbMsb,
+}
+
+struct Counter {
+    mode: u8,
+    access: Access,
+    // Count loaded at the start of each period, 1 to 0x10000
+    reload: u32,
+    count: u32,
+    // Whether a count has been written since the mode was set
+    counting: bool,
+    output: bool,
+    // The LSB of a two-byte count waiting for its MSB
+    pending_lsb: Option<u8>,
+    // Whether the next read of a two-byte count gives the MSB
+    read_msb: bool,
+    latched: Option<u16>,
+    irq: Option<(Rc<RefCell<IrqLine>>, u8)>,
+}
+
+impl Counter {
+    fn new() -> Self {
+        Counter {
+            mode: 0,
+            access: Access::LsbMsb,
+            reload: 0x10000,
+            count: 0,
+            counting: false,
+            // Undefined on the chip; high keeps a mode 2 or 3 counter from
+            // starting with a rising edge
+            output: true,
+            pending_lsb: None,
+            read_msb: false,
+            latched: None,
+            irq: None,
+        }
+    }
+
+    // The count as the program reads it; in mode 3 it goes down by two
+    fn value(&self) -> u16 {
+        match self.mode {
+            3 => (self.count * 2) as u16,
+            _ => self.count as u16,
+        }
+    }
+
+    // Half of the period a mode 3 counter spends at the current output.
+    // A count of 1 is not allowed on the chip and runs as 2 here.
+    fn half_period(&self) -> u32 {
+        if self.output {
+            self.reload.div_ceil(2)
+        } else {
+            (self.reload / 2).max(1)
+        }
+    }
+
+    fn load(&mut self, count: u16) {
+        self.reload = if count == 0 { 0x10000 } else { u32::from(count) };
+        // Modes 2 and 3 take a new count at the end of the period
+        if self.counting && (self.mode == 2 || self.mode == 3) {
+            return;
+        }
+        self.counting = true;
+        self.count = match self.mode {
+            3 => self.half_period(),
+            _ => self.reload,
+        };
+    }
+
+    // One input clock. Returns whether the output changed.
+    fn clock(&mut self) -> bool {
+        if !self.counting {
+            return false;
+        }
+        let before = self.output;
+        match self.mode {
+            0 => {
+                self.count = self.count.wrapping_sub(1) & 0xffff;
+                if self.count == 0 {
+                    self.output = true;
+                }
+            }
+            2 => {
+                self.count -= 1;
+                match self.count {
+                    1 => self.output = false,
+                    0 => {
+                        self.count = self.reload;
+                        self.output = true;
+                    }
+                    _ => {}
+                }
+            }
+            3 => {
+                self.count -= 1;
+                if self.count == 0 {
+                    self.output = !self.output;
+                    self.count = self.half_period();
+                }
+            }
+            4 => {
+                self.count = self.count.wrapping_sub(1) & 0xffff;
+                // Low for the one clock after the count runs out
+                self.output = self.count != 0;
+            }
+            _ => {}
+        }
+        self.output != before
+    }
+
+    fn save(&self, state: &mut DeviceState) {
+        state.push_u8(self.mode);
+        state.push_u8(self.access as u8);
+        state.push_u32(self.reload);
+        state.push_u32(self.count);
+        state.push_bool(self.counting);
+        state.push_bool(self.output);
+        state.push_bool(self.pending_lsb.is_some());
+        state.push_u8(self.pending_lsb.unwrap_or(0));
+        state.push_bool(self.read_msb);
+        state.push_bool(self.latched.is_some());
+        state.push_u16(self.latched.unwrap_or(0));
+    }
+
+    // A counter as `save` left it, not yet wired to an interrupt line
+    fn restore(fields: &mut StateReader) -> Result<Counter, StateError> {
+        let mode = match fields.u8()? {
+            mode @ 0..=5 => mode,
+            _ => return Err(StateError::Corrupt),
+        };
+        let access = match fields.u8()? {
+            0 => Access::Lsb,
+            1 => Access::Msb,
+            2 => Access::LsbMsb,
+            _ => return Err(StateError::Corrupt),
+        };
+        let reload = fields.u32()?;
+        let count = fields.u32()?;
+        if !(1..=0x10000).contains(&reload) || count > 0x10000 {
+            return Err(StateError::Corrupt);
+        }
+        let counting = fields.bool()?;
+        let output = fields.bool()?;
+        let pending_lsb = (fields.bool()?, fields.u8()?);
+        let read_msb = fields.bool()?;
+        let latched = (fields.bool()?, fields.u16()?);
+        Ok(Counter {
+            mode,
+            access,
+            reload,
+            count,
+            counting,
+            output,
+            pending_lsb: Some(pending_lsb.1).filter(|_| pending_lsb.0),
+            read_msb,
+            latched: Some(latched.1).filter(|_| latched.0),
+            irq: None,
+        })
+    }
+}
+
+type OutputCallback = Box<dyn FnMut(OutputChange)>;
+
+/// An 8253 programmable interval timer: three 16-bit counters on `port`
+/// to `port + 2`, set up through a control word on `port + 3` (see
+/// `control`). Counters run off the CPU clock divided by `with_divider`.
+///
+/// The modes with the gate input tied high are modelled:
+///
+/// - 0: the output goes high when the count runs out and stays high.
+/// - 2: the output drops for one clock every `count` clocks, for tick
+///   interrupts.
+/// - 3: a square wave with a period of `count` clocks, for tones.
+/// - 4: the output drops for one clock when the count runs out.
+///
+/// Modes 1 and 5 wait for a rising edge on the gate, which never comes,
+/// so counters set to them do not count. BCD counting is not supported.
+///
+/// A counter's output can drive an `IrqLine`, raising it with an
+/// instruction as the output goes high and lowering it as it goes low;
+/// with an edge-triggered line that gives one interrupt per period in
+/// mode 2:
+///
+/// ```
+/// use i8080_emulator::device::Device;
+/// use i8080_emulator::devices::timer::{control, Timer};
+/// use i8080_emulator::irq::{IrqLine, Trigger};
+/// use std::cell::RefCell;
+/// use std::rc::Rc;
+///
+/// let irq = Rc::new(RefCell::new(IrqLine::new(Trigger::Edge)));
+/// let mut timer = Timer::new(0x40);
+/// timer.connect_irq(0, irq.clone(), 0xcf);
+/// // Counter 0, mode 2, every 100 clocks
+/// timer.write(0x43, control::LSB | 2 << control::MODE_SHIFT);
+/// timer.write(0x40, 100);
+/// timer.tick(99);
+/// assert!(!irq.borrow().is_pending());
+/// timer.tick(1);
+/// assert!(irq.borrow().is_pending());
+/// ```
+///
+/// Output changes can also go to a callback, to drive a speaker from a
+/// mode 3 counter for instance.
+pub struct Timer {
+    port: u8,
+    divider: ClockCycles,
+    // CPU cycles since the last input clock
+    phase: ClockCycles,
+    cycles: Timestamp,
+    counters: [Counter; 3],
+    callback: Option<OutputCallback>,
+}
+
+impl Timer {
+    pub const STATE_ID: DeviceId = *b"8253";
+    pub const STATE_VERSION: u16 = 1;
+
+    pub fn new(port: u8) -> Self {
+        Timer {
+            port,
+            divider: 1,
+            phase: 0,
+            cycles: 0,
+            counters: [Counter::new(), Counter::new(), Counter::new()],
+            callback: None,
+        }
+    }
+
+    /// Clocks the counters once every `divider` CPU cycles instead of
+    /// every cycle, for a timer on a slower clock of its own.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `divider` is 0.
+    pub fn with_divider(mut self, divider: ClockCycles) -> Self {
+        assert!(divider > 0, "the divider must be at least 1");
+        self.divider = divider;
+        self
+    }
+
+    /// Drives `irq` from the output of `counter`, with `op` as the
+    /// instruction supplied on acknowledge.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `counter` is not 0, 1 or 2.
+    pub fn connect_irq(&mut self, counter: usize, irq: Rc<RefCell<IrqLine>>, op: u8) {
+        self.counters[counter].irq = Some((irq, op));
+    }
+
+    /// Calls `callback` whenever an output changes.
+    pub fn set_callback(&mut self, callback: impl FnMut(OutputChange) + 'static) {
+        self.callback = Some(Box::new(callback));
+    }
+
+    /// Whether the output of `counter` is high.
+    pub fn output(&self, counter: usize) -> bool {
+        self.counters[counter].output
+    }
+
+    /// The current count of `counter`, without latching it.
+    pub fn count(&self, counter: usize) -> u16 {
+        self.counters[counter].value()
+    }
+
+    // Passes on a change of `counter`'s output
+    fn notify(&mut self, counter: usize) {
+        let c = &self.counters[counter];
+        let high = c.output;
+        if let Some((irq, op)) = &c.irq {
+            match high {
+                true => irq.borrow_mut().raise(*op),
+                false => irq.borrow_mut().lower(),
+            }
+        }
+        if let Some(callback) = &mut self.callback {
+            callback(OutputChange { counter, high, cycle: self.cycles });
+        }
+    }
+
+    fn write_control(&mut self, value: u8) {
+        let counter = usize::from(value >> control::COUNTER_SHIFT);
+        // 3 is the 8254's read-back command
+        if counter == 3 {
+            return;
+        }
+        let c = &mut self.counters[counter];
+        let access = match value & control::LSB_MSB {
+            control::LATCH => {
+                if c.latched.is_none() {
+                    c.latched = Some(c.value());
+                }
+                return;
+            }
+            control::LSB => Access::Lsb,
+            control::MSB => Access::Msb,
+            _ => Access::LsbMsb,
+        };
+        c.access = access;
+        c.mode = match (value >> control::MODE_SHIFT) & 0x07 {
+            mode @ 0..=5 => mode,
+            // 6 and 7 are 2 and 3 again
+            mode => mode - 4,
+        };
+        c.counting = false;
+        c.pending_lsb = None;
+        c.read_msb = false;
+        c.latched = None;
+        // Setting the mode drives the output low in mode 0, high otherwise
+        let output = c.mode != 0;
+        if c.output != output {
+            c.output = output;
+            self.notify(counter);
+        }
+    }
+
+    fn write_count(&mut self, counter: usize, value: u8) {
+        let c = &mut self.counters[counter];
+        let count = match (c.access, c.pending_lsb.take()) {
+            (Access::Lsb, _) => u16::from(value),
+            (Access::Msb, _) => u16::from(value) << 8,
+            (Access::LsbMsb, None) => {
+                c.pending_lsb = Some(value);
+                return;
+            }
+            (Access::LsbMsb, Some(lsb)) => u16::from_le_bytes([lsb, value]),
+        };
+        c.load(count);
+    }
+
+    fn read_count(&mut self, counter: usize) -> u8 {
+        let c = &mut self.counters[counter];
+        let value = c.latched.unwrap_or_else(|| c.value());
+        let [lsb, msb] = value.to_le_bytes();
+        match c.access {
+            Access::Lsb => {
+                c.latched = None;
+                lsb
+            }
+            Access::Msb => {
+                c.latched = None;
+                msb
+            }
+            Access::LsbMsb => {
+                c.read_msb = !c.read_msb;
+                if c.read_msb {
+                    lsb
+                } else {
+                    c.latched = None;
+                    msb
+                }
+            }
+        }
+    }
+}
+
+impl Device for Timer {
+    fn reset(&mut self) {
+        // The wiring to interrupt lines stays
+        for counter in &mut self.counters {
+            *counter = Counter { irq: counter.irq.take(), ..Counter::new() };
+        }
+        self.phase = 0;
+        self.cycles = 0;
+    }
+
+    fn tick(&mut self, cycles: ClockCycles) {
+        for _ in 0..cycles {
+            self.cycles += 1;
+            self.phase += 1;
+            if self.phase < self.divider {
+                continue;
+            }
+            self.phase = 0;
+            for counter in 0..3 {
+                if self.counters[counter].clock() {
+                    self.notify(counter);
+                }
+            }
+        }
+    }
+
+    fn read(&mut self, port: u8) -> u8 {
+        match port.wrapping_sub(self.port) {
+            counter @ 0..=2 => self.read_count(usize::from(counter)),
+            _ => 0xff,
+        }
+    }
+
+    fn write(&mut self, port: u8, value: u8) {
+        match port.wrapping_sub(self.port) {
+            counter @ 0..=2 => self.write_count(usize::from(counter), value),
+            3 => self.write_control(value),
+            _ => {}
+        }
+    }
+
+    /// The counters and the time, without the interrupt wiring or the
+    /// divider.
+    fn save_state(&self) -> Option<DeviceState> {
+        let mut state = DeviceState::new(Timer::STATE_ID, Timer::STATE_VERSION);
+        state.push_u32(self.phase);
+        state.push_u64(self.cycles);
+        for counter in &self.counters {
+            counter.save(&mut state);
+        }
+        Some(state)
+    }
+
+    fn load_state(&mut self, state: &DeviceState) -> Result<(), StateError> {
+        let mut fields = state.reader();
+        let phase = fields.u32()?;
+        let cycles = fields.u64()?;
+        let counters = [Counter::restore(&mut fields)?, Counter::restore(&mut fields)?, Counter::restore(&mut fields)?];
+        fields.end()?;
+        self.phase = phase;
+        self.cycles = cycles;
+        for (counter, loaded) in self.counters.iter_mut().zip(counters) {
+            *counter = Counter { irq: counter.irq.take(), ..loaded };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{restore, Device};
+    use crate::devices::timer::{control, OutputChange, Timer};
+    use crate::irq::{IrqLine, Trigger};
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn one_shot_and_latch() {
+        let mut timer = Timer::new(0x10);
+        // Counter 1, mode 0, 0x0105 clocks
+        timer.write(0x13, 1 << control::COUNTER_SHIFT | control::LSB_MSB);
+        timer.write(0x11, 0x05);
+        timer.tick(10);
+        assert_eq!(timer.count(1), 0x0000);
+        timer.write(0x11, 0x01);
+        timer.tick(0x100);
+        timer.write(0x13, 1 << control::COUNTER_SHIFT | control::LATCH);
+        timer.tick(2);
+        assert_eq!((timer.read(0x11), timer.read(0x11)), (0x05, 0x00));
+        assert!(!timer.output(1));
+        timer.tick(3);
+        assert!(timer.output(1));
+        // Stays high as the count wraps
+        timer.tick(10);
+        assert!(timer.output(1));
+        assert_eq!(timer.count(1), 0xfff6);
+    }
+
+    #[test]
+    fn square_wave() {
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let log = changes.clone();
+        let mut timer = Timer::new(0x40).with_divider(2);
+        timer.set_callback(move |change| log.borrow_mut().push(change));
+        // Counter 2, mode 3, 5 clocks: high for 3, low for 2
+        timer.write(0x43, 2 << control::COUNTER_SHIFT | control::LSB | 3 << control::MODE_SHIFT);
+        timer.write(0x42, 5);
+        timer.tick(20);
+        let change = |high, cycle| OutputChange { counter: 2, high, cycle };
+        assert_eq!(*changes.borrow(), [change(false, 6), change(true, 10), change(false, 16), change(true, 20)]);
+
+        // Modes 1 and 5 never see their gate trigger
+        timer.write(0x43, control::LSB | 1 << control::MODE_SHIFT);
+        timer.write(0x40, 3);
+        timer.tick(20);
+        assert_eq!(timer.count(0), 3);
+        timer.write(0x43, 2 << control::COUNTER_SHIFT | control::LSB);
+        assert!(!timer.output(2));
+        timer.reset();
+        assert!(timer.output(2));
+    }
+
+    #[test]
+    fn square_wave_of_one() {
+        // Counter 0, mode 3, 1 clock: runs as 2, toggling every clock
+        let mut timer = Timer::new(0x40);
+        timer.write(0x43, control::LSB | 3 << control::MODE_SHIFT);
+        timer.write(0x40, 1);
+        let outputs: Vec<_> = (0..4).map(|_| {
+            timer.tick(1);
+            timer.output(0)
+        }).collect();
+        assert_eq!(outputs, [false, true, false, true]);
+    }
+
+    #[test]
+    fn save_state() {
+        let mut timer = Timer::new(0x40);
+        // Counter 0 in mode 2 with half a count written, counter 1 in
+        // mode 3 with a count latched
+        timer.write(0x43, control::LSB_MSB | 2 << control::MODE_SHIFT);
+        timer.write(0x40, 0x10);
+        timer.write(0x43, 1 << control::COUNTER_SHIFT | control::LSB | 3 << control::MODE_SHIFT);
+        timer.write(0x41, 7);
+        timer.tick(5);
+        timer.write(0x43, 1 << control::COUNTER_SHIFT | control::LATCH);
+        let saved = timer.save_state().unwrap();
+
+        let irq = Rc::new(RefCell::new(IrqLine::new(Trigger::Edge)));
+        let mut copy = Timer::new(0x40);
+        copy.connect_irq(0, irq.clone(), 0xff);
+        restore(&mut copy, saved).unwrap();
+        assert_eq!(copy.read(0x41), timer.read(0x41));
+        for _ in 0..2 {
+            copy.write(0x40, 0x00);
+            timer.write(0x40, 0x00);
+        }
+        for _ in 0..40 {
+            timer.tick(1);
+            copy.tick(1);
+            assert_eq!((copy.count(0), copy.output(0)), (timer.count(0), timer.output(0)));
+            assert_eq!((copy.count(1), copy.output(1)), (timer.count(1), timer.output(1)));
+        }
+        // The wiring the copy was built with is kept
+        assert!(irq.borrow().is_pending());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::cpu::{ClockCycles, Timestamp};
+use crate::device::{Device, DeviceId, DeviceState, StateError};
+
+/// A watchdog timer on one port. Any write to the port kicks it; if
+/// `timeout` cycles pass without one, it expires. The machine checks
+/// `take_expired` after ticking it and resets itself with
+/// `Machine::reset` when it returns true, as boards with a watchdog do to
+/// recover from a crashed program.
+///
+/// ```text
+/// loop: ...
+///       OUT 0x06      ; kick the watchdog, any value
+///       JMP loop
+/// ```
+pub struct Watchdog {
+    port: u8,
+    timeout: Timestamp,
+    elapsed: Timestamp,
+    expired: bool,
+}
+
+impl Watchdog {
+    pub const STATE_ID: DeviceId = *b"WDOG";
+    pub const STATE_VERSION: u16 = 1;
+
+    pub fn new(port: u8, timeout: Timestamp) -> Self {
+        Watchdog {
+            port,
+            timeout,
+            elapsed: 0,
+            expired: false,
+        }
+    }
+
+    /// Whether the watchdog has expired since the last call. The timeout
+    /// starts over once it has.
+    pub fn take_expired(&mut self) -> bool {
+        std::mem::take(&mut self.expired)
+    }
+}
+
+impl Device for Watchdog {
+    fn reset(&mut self) {
+        self.elapsed = 0;
+        self.expired = false;
+    }
+
+    fn tick(&mut self, cycles: ClockCycles) {
+        self.elapsed += Timestamp::from(cycles);
+        if self.elapsed >= self.timeout {
+            self.elapsed = 0;
+            self.expired = true;
+        }
+    }
+
+    /// Nothing to read; the bus floats.
+    fn read(&mut self, _port: u8) -> u8 {
+        0xff
+    }
+
+    fn write(&mut self, port: u8, _value: u8) {
+        if port == self.port {
+            self.elapsed = 0;
+        }
+    }
+
+    /// The time since the last kick, and whether the watchdog expired
+    /// without the machine noticing.
+    fn save_state(&self) -> Option<DeviceState> {
+        let mut state = DeviceState::new(Watchdog::STATE_ID, Watchdog::STATE_VERSION);
+        state.push_u64(self.elapsed);
+        state.push_bool(self.expired);
+        Some(state)
+    }
+
+    fn load_state(&mut self, state: &DeviceState) -> Result<(), StateError> {
+        let mut fields = state.reader();
+        let (elapsed, expired) = (fields.u64()?, fields.bool()?);
+        fields.end()?;
+        self.elapsed = elapsed;
+        self.expired = expired;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::device::Device;
+    use crate::devices::watchdog::Watchdog;
+    use crate::io::PortBus;
+    use crate::memory::Memory8080;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn expires_unless_kicked() {
+        let mut watchdog = Watchdog::new(0x06, 100);
+        watchdog.tick(60);
+        watchdog.write(0x06, 0x00);
+        watchdog.tick(60);
+        assert!(!watchdog.take_expired());
+        watchdog.write(0x07, 0x00);
+        watchdog.tick(60);
+        assert!(watchdog.take_expired());
+        assert!(!watchdog.take_expired());
+        watchdog.tick(60);
+        watchdog.reset();
+        watchdog.tick(60);
+        assert!(!watchdog.take_expired());
+    }
+
+    #[test]
+    fn resets_a_hung_program() {
+        // 0x0000: INR B; MVI C, 4
+        // kick: OUT 0x06; DCR C; JNZ kick
+        // hang: JMP hang
+        let code = [0x04, 0x0e, 0x04, 0xd3, 0x06, 0x0d, 0xc2, 0x03, 0x00, 0xc3, 0x09, 0x00];
+        let mut memory = [0x00; 0x10000];
+        memory[..code.len()].copy_from_slice(&code);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        let watchdog = Rc::new(RefCell::new(Watchdog::new(0x06, 1000)));
+        let mut bus = PortBus::new();
+        bus.map(0x06, watchdog.clone());
+
+        let mut resets = 0;
+        while cpu.cycles() < 5000 {
+            let event = bus.step(&mut cpu);
+            watchdog.borrow_mut().tick(event.cycles());
+            if watchdog.borrow_mut().take_expired() {
+                cpu.reset();
+                resets += 1;
+            }
+        }
+        assert_eq!(resets, 4);
+        assert_eq!(cpu.regs.b, 5);
+    }
+}
@@ -1,378 +1,1068 @@
-use std::collections::HashMap;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::vec;
+use core::fmt;
+use core::ops::RangeInclusive;
 use crate::memory::{Memory};
+use crate::opcodes::{OpcodeInfo, OPCODES};
+use crate::symbols::SymbolTable;
 
-#[derive(Debug)]
-enum Opcode {
-    SingleOpcode(&'static str),
-    Immediate8(&'static str),
-    Immediate16(&'static str),
-    DirectAdress(&'static str),
+/// Mnemonic, length and cycles of every opcode, indexed by the opcode
+/// byte. The same data as `opcodes::OPCODES`, but in a static, so every
+/// lookup indexes the one table rather than a copy of the constant.
+pub static OPCODE_TABLE: [OpcodeInfo; 256] = OPCODES;
 
-    RegPairFirstOperand(&'static str, &'static str),
-    RegPairSecOperand(&'static str),
-    RegPairAndImm(&'static str),
+/// Which family of mnemonics the disassembler prints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mnemonics {
+    /// `MOV A, B`, `LXI H`, `JNZ`, as in Intel's manuals.
+    Intel,
+    /// `LD A,B`, `LD HL,nn`, `JP NZ,nn`, as in Z80 listings.
+    Zilog,
 }
 
+/// How numeric operands are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Literals {
+    /// `0x3e`
+    Prefixed,
+    /// `3Eh`, with a leading `0` when the first digit is a letter.
+    Suffixed,
+}
+
+/// Disassembly syntax, set with `Disassembler::set_syntax`. The default is
+/// uppercase Intel mnemonics with `0x` literals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyntaxStyle {
+    pub mnemonics: Mnemonics,
+    pub lowercase: bool,
+    pub literals: Literals,
+}
+
+impl Default for SyntaxStyle {
+    fn default() -> Self {
+        SyntaxStyle {
+            mnemonics: Mnemonics::Intel,
+            lowercase: false,
+            literals: Literals::Prefixed,
+        }
+    }
+}
+
+/// An 8-bit register operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg8 {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+}
+
+/// A register pair operand. `PSW` is A and the flags, used by PUSH and POP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg16 {
+    BC,
+    DE,
+    HL,
+    SP,
+    PSW,
+}
+
+/// One operand of a decoded instruction, in the order Intel syntax lists
+/// them (destination first).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Reg(Reg8),
+    /// The memory byte addressed by HL, written `M`.
+    Mem,
+    RegPair(Reg16),
+    Imm8(u8),
+    Imm16(u16),
+    /// A memory address, as taken by jumps, calls and direct loads and
+    /// stores.
+    Addr(u16),
+    Port(u8),
+    /// The restart number of an RST, 0 to 7.
+    Restart(u8),
+}
+
+/// A decoded instruction, with its operands as values and as the text
+/// `disassemble` would produce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub opcode: u8,
+    /// The bare Intel mnemonic, e.g. `"MOV"` or `"LXI"`.
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+    pub text: String,
+}
+
+/// One instruction of a listing made by `Disassembler::disassemble_range`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub addr: u16,
+    /// The opcode and any bytes of operands following it.
+    pub bytes: Vec<u8>,
+    /// The bare Intel mnemonic, or `"DB"` for bytes that do not make a
+    /// whole instruction.
+    pub mnemonic: &'static str,
+    /// The operands, or for `DB` one `Imm8` per byte.
+    pub operands: Vec<Operand>,
+}
+
+impl Instruction {
+    /// Bytes the instruction takes up, 1 for a `DB`.
+    pub fn length(&self) -> u16 {
+        match self.mnemonic {
+            "DB" => 1,
+            _ => u16::from(OPCODES[usize::from(self.opcode)].length),
+        }
+    }
+}
+
+impl Line {
+    fn data(addr: u16, byte: u8) -> Line {
+        Line { addr, bytes: vec![byte], mnemonic: "DB", operands: vec![Operand::Imm8(byte)] }
+    }
+}
+
+// A byte slice seen as memory starting at `org`. Anything outside it
+// reads as 0x00 and writes are dropped.
+struct Slice<'a> {
+    bytes: &'a [u8],
+    org: u16,
+}
+
+impl<'a> Memory for Slice<'a> {
+    fn read(&self, i: usize) -> u8 {
+        let offset = (i as u16).wrapping_sub(self.org);
+        self.bytes.get(usize::from(offset)).copied().unwrap_or(0x00)
+    }
+
+    fn write(&mut self, _i: usize, _data: u8) {}
+
+    fn read16(&self, i: usize) -> u16 {
+        u16::from_le_bytes([self.read(i), self.read(i.wrapping_add(1))])
+    }
+
+    fn write16(&mut self, _i: usize, _data: u16) {}
+}
+
+// Memory seen through `peek`, so that listing it sets nothing off
+pub(crate) struct Peek<'a, M: Memory>(pub(crate) &'a M);
+
+impl<'a, M: Memory> Memory for Peek<'a, M> {
+    fn read(&self, i: usize) -> u8 {
+        self.0.peek(i)
+    }
+
+    fn write(&mut self, _i: usize, _data: u8) {}
+
+    fn read16(&self, i: usize) -> u16 {
+        u16::from_le_bytes([self.read(i), self.read((i + 1) & 0xffff)])
+    }
+
+    fn write16(&mut self, _i: usize, _data: u16) {}
+}
+
+/// Instructions decoded one after another, from
+/// `Disassembler::instructions`.
+pub struct Instructions<'a, M: Memory> {
+    disassembler: &'a Disassembler,
+    memory: Peek<'a, M>,
+    addr: u16,
+    end: Option<u16>,
+    hl: u16,
+    done: bool,
+}
+
+impl<'a, M: Memory> Instructions<'a, M> {
+    /// Stops before the first instruction at or past `end`, or on
+    /// reaching the top of memory.
+    pub fn until(mut self, end: u16) -> Self {
+        self.end = Some(end);
+        self
+    }
+
+    /// The value of HL shown for `M` operands, 0 unless set.
+    pub fn hl(mut self, hl: u16) -> Self {
+        self.hl = hl;
+        self
+    }
+}
+
+impl<'a, M: Memory> Iterator for Instructions<'a, M> {
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Instruction> {
+        if self.done || self.end.is_some_and(|end| self.addr >= end) {
+            return None;
+        }
+        let instruction = self.disassembler.decode(&self.memory, &self.addr, &self.hl);
+        let (next, wrapped) = self.addr.overflowing_add(instruction.length());
+        self.addr = next;
+        self.done = wrapped && self.end.is_some();
+        Some(instruction)
+    }
+}
+
+// Register operand selected by a 3-bit field of the opcode.
+fn reg(field: u8) -> Operand {
+    match field & 0x07 {
+        0 => Operand::Reg(Reg8::B),
+        1 => Operand::Reg(Reg8::C),
+        2 => Operand::Reg(Reg8::D),
+        3 => Operand::Reg(Reg8::E),
+        4 => Operand::Reg(Reg8::H),
+        5 => Operand::Reg(Reg8::L),
+        6 => Operand::Mem,
+        _ => Operand::Reg(Reg8::A),
+    }
+}
+
+// Register pair selected by bits 4-5 of the opcode. PUSH and POP use PSW in
+// place of SP.
+fn reg_pair(op: u8, psw: bool) -> Operand {
+    Operand::RegPair(match (op >> 4) & 0x03 {
+        0 => Reg16::BC,
+        1 => Reg16::DE,
+        2 => Reg16::HL,
+        _ if psw => Reg16::PSW,
+        _ => Reg16::SP,
+    })
+}
+
+fn operands(memory: &impl Memory, pc: u16, op: u8) -> Vec<Operand> {
+    let imm8 = || memory.read(pc.wrapping_add(1).into());
+    let imm16 = || memory.read16(pc.wrapping_add(1).into());
+    let (x, y, z) = (op >> 6, (op >> 3) & 0x07, op & 0x07);
+    match (x, z) {
+        (1, _) if op == 0x76 => vec![],
+        (1, _) => vec![reg(y), reg(z)],
+        (2, _) => vec![reg(z)],
+        (0, 1) if y & 1 == 0 => vec![reg_pair(op, false), Operand::Imm16(imm16())],
+        (0, 1) | (0, 3) => vec![reg_pair(op, false)],
+        (0, 2) if y < 4 => vec![reg_pair(op, false)],
+        (0, 2) => vec![Operand::Addr(imm16())],
+        (0, 4) | (0, 5) => vec![reg(y)],
+        (0, 6) => vec![reg(y), Operand::Imm8(imm8())],
+        (3, 1) | (3, 5) if y & 1 == 0 => vec![reg_pair(op, true)],
+        (3, 2) | (3, 4) | (3, 5) => vec![Operand::Addr(imm16())],
+        (3, 3) if y < 2 => vec![Operand::Addr(imm16())],
+        (3, 3) if y < 4 => vec![Operand::Port(imm8())],
+        (3, 6) => vec![Operand::Imm8(imm8())],
+        (3, 7) => vec![Operand::Restart(y)],
+        _ => vec![],
+    }
+}
+
+/// Formats instructions as text. Intel mnemonics come from
+/// `OPCODE_TABLE`, so every one of the 256 opcodes has one.
 pub struct Disassembler {
-    ins: HashMap<u8, Opcode>
+    port_names: BTreeMap<u8, String>,
+    syntax: SyntaxStyle,
+    // Address ranges listed as `DB` bytes rather than instructions
+    data: Vec<RangeInclusive<u16>>,
+    symbols: Option<SymbolTable>,
+}
+
+impl Default for Disassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Formats into a byte slice, silently dropping whatever does not fit.
+struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> fmt::Write for SliceWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+// Lowercases everything written through it, for `SyntaxStyle::lowercase`.
+struct Lowercase<'a, W: fmt::Write>(&'a mut W);
+
+impl<'a, W: fmt::Write> fmt::Write for Lowercase<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.0.write_char(c.to_ascii_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+// A 16-bit operand in a Zilog template: an address's name, or a number
+enum Word<'a> {
+    Symbol(&'a str),
+    Literal(Literal),
+}
+
+impl<'a> fmt::Display for Word<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Word::Symbol(name) => f.write_str(name),
+            Word::Literal(literal) => literal.fmt(f),
+        }
+    }
+}
+
+// A numeric operand, formatted according to `Literals`.
+struct Literal(u16, Literals);
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.1 {
+            Literals::Prefixed => write!(f, "0x{:x}", self.0),
+            Literals::Suffixed => {
+                let mut top = self.0;
+                while top > 0xf {
+                    top >>= 4;
+                }
+                if top > 9 {
+                    f.write_str("0")?;
+                }
+                write!(f, "{:X}h", self.0)
+            }
+        }
+    }
 }
 
+// Z80 spellings of every opcode. `{n}` and `{nn}` stand for the 8- and
+// 16-bit operand following the opcode, `{r}` for the RST target.
+const ZILOG: [&str; 256] = [
+    "NOP",             // 0x00
+    "LD BC,{nn}",      // 0x01
+    "LD (BC),A",       // 0x02
+    "INC BC",          // 0x03
+    "INC B",           // 0x04
+    "DEC B",           // 0x05
+    "LD B,{n}",        // 0x06
+    "RLCA",            // 0x07
+    "NOP",             // 0x08
+    "ADD HL,BC",       // 0x09
+    "LD A,(BC)",       // 0x0a
+    "DEC BC",          // 0x0b
+    "INC C",           // 0x0c
+    "DEC C",           // 0x0d
+    "LD C,{n}",        // 0x0e
+    "RRCA",            // 0x0f
+    "NOP",             // 0x10
+    "LD DE,{nn}",      // 0x11
+    "LD (DE),A",       // 0x12
+    "INC DE",          // 0x13
+    "INC D",           // 0x14
+    "DEC D",           // 0x15
+    "LD D,{n}",        // 0x16
+    "RLA",             // 0x17
+    "NOP",             // 0x18
+    "ADD HL,DE",       // 0x19
+    "LD A,(DE)",       // 0x1a
+    "DEC DE",          // 0x1b
+    "INC E",           // 0x1c
+    "DEC E",           // 0x1d
+    "LD E,{n}",        // 0x1e
+    "RRA",             // 0x1f
+    "NOP",             // 0x20
+    "LD HL,{nn}",      // 0x21
+    "LD ({nn}),HL",    // 0x22
+    "INC HL",          // 0x23
+    "INC H",           // 0x24
+    "DEC H",           // 0x25
+    "LD H,{n}",        // 0x26
+    "DAA",             // 0x27
+    "NOP",             // 0x28
+    "ADD HL,HL",       // 0x29
+    "LD HL,({nn})",    // 0x2a
+    "DEC HL",          // 0x2b
+    "INC L",           // 0x2c
+    "DEC L",           // 0x2d
+    "LD L,{n}",        // 0x2e
+    "CPL",             // 0x2f
+    "NOP",             // 0x30
+    "LD SP,{nn}",      // 0x31
+    "LD ({nn}),A",     // 0x32
+    "INC SP",          // 0x33
+    "INC (HL)",        // 0x34
+    "DEC (HL)",        // 0x35
+    "LD (HL),{n}",     // 0x36
+    "SCF",             // 0x37
+    "NOP",             // 0x38
+    "ADD HL,SP",       // 0x39
+    "LD A,({nn})",     // 0x3a
+    "DEC SP",          // 0x3b
+    "INC A",           // 0x3c
+    "DEC A",           // 0x3d
+    "LD A,{n}",        // 0x3e
+    "CCF",             // 0x3f
+    "LD B,B",          // 0x40
+    "LD B,C",          // 0x41
+    "LD B,D",          // 0x42
+    "LD B,E",          // 0x43
+    "LD B,H",          // 0x44
+    "LD B,L",          // 0x45
+    "LD B,(HL)",       // 0x46
+    "LD B,A",          // 0x47
+    "LD C,B",          // 0x48
+    "LD C,C",          // 0x49
+    "LD C,D",          // 0x4a
+    "LD C,E",          // 0x4b
+    "LD C,H",          // 0x4c
+    "LD C,L",          // 0x4d
+    "LD C,(HL)",       // 0x4e
+    "LD C,A",          // 0x4f
+    "LD D,B",          // 0x50
+    "LD D,C",          // 0x51
+    "LD D,D",          // 0x52
+    "LD D,E",          // 0x53
+    "LD D,H",          // 0x54
+    "LD D,L",          // 0x55
+    "LD D,(HL)",       // 0x56
+    "LD D,A",          // 0x57
+    "LD E,B",          // 0x58
+    "LD E,C",          // 0x59
+    "LD E,D",          // 0x5a
+    "LD E,E",          // 0x5b
+    "LD E,H",          // 0x5c
+    "LD E,L",          // 0x5d
+    "LD E,(HL)",       // 0x5e
+    "LD E,A",          // 0x5f
+    "LD H,B",          // 0x60
+    "LD H,C",          // 0x61
+    "LD H,D",          // 0x62
+    "LD H,E",          // 0x63
+    "LD H,H",          // 0x64
+    "LD H,L",          // 0x65
+    "LD H,(HL)",       // 0x66
+    "LD H,A",          // 0x67
+    "LD L,B",          // 0x68
+    "LD L,C",          // 0x69
+    "LD L,D",          // 0x6a
+    "LD L,E",          // 0x6b
+    "LD L,H",          // 0x6c
+    "LD L,L",          // 0x6d
+    "LD L,(HL)",       // 0x6e
+    "LD L,A",          // 0x6f
+    "LD (HL),B",       // 0x70
+    "LD (HL),C",       // 0x71
+    "LD (HL),D",       // 0x72
+    "LD (HL),E",       // 0x73
+    "LD (HL),H",       // 0x74
+    "LD (HL),L",       // 0x75
+    "HALT",            // 0x76
+    "LD (HL),A",       // 0x77
+    "LD A,B",          // 0x78
+    "LD A,C",          // 0x79
+    "LD A,D",          // 0x7a
+    "LD A,E",          // 0x7b
+    "LD A,H",          // 0x7c
+    "LD A,L",          // 0x7d
+    "LD A,(HL)",       // 0x7e
+    "LD A,A",          // 0x7f
+    "ADD A,B",         // 0x80
+    "ADD A,C",         // 0x81
+    "ADD A,D",         // 0x82
+    "ADD A,E",         // 0x83
+    "ADD A,H",         // 0x84
+    "ADD A,L",         // 0x85
+    "ADD A,(HL)",      // 0x86
+    "ADD A,A",         // 0x87
+    "ADC A,B",         // 0x88
+    "ADC A,C",         // 0x89
+    "ADC A,D",         // 0x8a
+    "ADC A,E",         // 0x8b
+    "ADC A,H",         // 0x8c
+    "ADC A,L",         // 0x8d
+    "ADC A,(HL)",      // 0x8e
+    "ADC A,A",         // 0x8f
+    "SUB B",           // 0x90
+    "SUB C",           // 0x91
+    "SUB D",           // 0x92
+    "SUB E",           // 0x93
+    "SUB H",           // 0x94
+    "SUB L",           // 0x95
+    "SUB (HL)",        // 0x96
+    "SUB A",           // 0x97
+    "SBC A,B",         // 0x98
+    "SBC A,C",         // 0x99
+    "SBC A,D",         // 0x9a
+    "SBC A,E",         // 0x9b
+    "SBC A,H",         // 0x9c
+    "SBC A,L",         // 0x9d
+    "SBC A,(HL)",      // 0x9e
+    "SBC A,A",         // 0x9f
+    "AND B",           // 0xa0
+    "AND C",           // 0xa1
+    "AND D",           // 0xa2
+    "AND E",           // 0xa3
+    "AND H",           // 0xa4
+    "AND L",           // 0xa5
+    "AND (HL)",        // 0xa6
+    "AND A",           // 0xa7
+    "XOR B",           // 0xa8
+    "XOR C",           // 0xa9
+    "XOR D",           // 0xaa
+    "XOR E",           // 0xab
+    "XOR H",           // 0xac
+    "XOR L",           // 0xad
+    "XOR (HL)",        // 0xae
+    "XOR A",           // 0xaf
+    "OR B",            // 0xb0
+    "OR C",            // 0xb1
+    "OR D",            // 0xb2
+    "OR E",            // 0xb3
+    "OR H",            // 0xb4
+    "OR L",            // 0xb5
+    "OR (HL)",         // 0xb6
+    "OR A",            // 0xb7
+    "CP B",            // 0xb8
+    "CP C",            // 0xb9
+    "CP D",            // 0xba
+    "CP E",            // 0xbb
+    "CP H",            // 0xbc
+    "CP L",            // 0xbd
+    "CP (HL)",         // 0xbe
+    "CP A",            // 0xbf
+    "RET NZ",          // 0xc0
+    "POP BC",          // 0xc1
+    "JP NZ,{nn}",      // 0xc2
+    "JP {nn}",         // 0xc3
+    "CALL NZ,{nn}",    // 0xc4
+    "PUSH BC",         // 0xc5
+    "ADD A,{n}",       // 0xc6
+    "RST {r}",         // 0xc7
+    "RET Z",           // 0xc8
+    "RET",             // 0xc9
+    "JP Z,{nn}",       // 0xca
+    "JP {nn}",         // 0xcb
+    "CALL Z,{nn}",     // 0xcc
+    "CALL {nn}",       // 0xcd
+    "ADC A,{n}",       // 0xce
+    "RST {r}",         // 0xcf
+    "RET NC",          // 0xd0
+    "POP DE",          // 0xd1
+    "JP NC,{nn}",      // 0xd2
+    "OUT ({n}),A",     // 0xd3
+    "CALL NC,{nn}",    // 0xd4
+    "PUSH DE",         // 0xd5
+    "SUB {n}",         // 0xd6
+    "RST {r}",         // 0xd7
+    "RET C",           // 0xd8
+    "RET",             // 0xd9
+    "JP C,{nn}",       // 0xda
+    "IN A,({n})",      // 0xdb
+    "CALL C,{nn}",     // 0xdc
+    "CALL {nn}",       // 0xdd
+    "SBC A,{n}",       // 0xde
+    "RST {r}",         // 0xdf
+    "RET PO",          // 0xe0
+    "POP HL",          // 0xe1
+    "JP PO,{nn}",      // 0xe2
+    "EX (SP),HL",      // 0xe3
+    "CALL PO,{nn}",    // 0xe4
+    "PUSH HL",         // 0xe5
+    "AND {n}",         // 0xe6
+    "RST {r}",         // 0xe7
+    "RET PE",          // 0xe8
+    "JP (HL)",         // 0xe9
+    "JP PE,{nn}",      // 0xea
+    "EX DE,HL",        // 0xeb
+    "CALL PE,{nn}",    // 0xec
+    "CALL {nn}",       // 0xed
+    "XOR {n}",         // 0xee
+    "RST {r}",         // 0xef
+    "RET P",           // 0xf0
+    "POP AF",          // 0xf1
+    "JP P,{nn}",       // 0xf2
+    "DI",              // 0xf3
+    "CALL P,{nn}",     // 0xf4
+    "PUSH AF",         // 0xf5
+    "OR {n}",          // 0xf6
+    "RST {r}",         // 0xf7
+    "RET M",           // 0xf8
+    "LD SP,HL",        // 0xf9
+    "JP M,{nn}",       // 0xfa
+    "EI",              // 0xfb
+    "CALL M,{nn}",     // 0xfc
+    "CALL {nn}",       // 0xfd
+    "CP {n}",          // 0xfe
+    "RST {r}",         // 0xff
+];
+
 impl Disassembler {
     pub fn disassemble(&self, memory: &impl Memory, pc: &u16, op: &u8, rp: &u16) -> String {
-        let code = self.ins.get(op);
-        match code {
-            Some(Opcode::SingleOpcode(n)) => {
-                format!("{:x}    {}", pc, n)
-            }
-            Some(Opcode::Immediate8(n)) => {
-                let imm8 = memory.read((pc + 1).into());
-                format!("{:x}    {} 0x{:x}", pc, n, imm8)
-            }
-            Some(Opcode::Immediate16(n)) => {
-                let imm16 = memory.read16((pc + 1).into());
-                format!("{:x}    {} 0x{:x}", pc, n, imm16)
-            }
-            Some(Opcode::DirectAdress(n)) => {
-                let imm16 = memory.read16((pc + 1).into());
-                format!("{:x}    {} $(0x{:x})", pc, n, imm16)
+        let mut code = String::new();
+        self.write_instruction(&mut code, memory, *pc, *op, *rp)
+            .expect("Writing to a String cannot fail");
+        code
+    }
+
+    /// Decodes the instruction at `pc`, returning its operands as typed
+    /// values alongside the formatted text.
+    pub fn decode(&self, memory: &impl Memory, pc: &u16, rp: &u16) -> Instruction {
+        let op = memory.read((*pc).into());
+        let (mnemonic, operands) = if self.is_data(*pc, op) {
+            ("DB", vec![Operand::Imm8(op)])
+        } else {
+            let mnemonic = OPCODE_TABLE[op as usize].mnemonic;
+            (mnemonic.split(' ').next().unwrap_or(mnemonic), operands(memory, *pc, op))
+        };
+        Instruction {
+            addr: *pc,
+            opcode: op,
+            mnemonic,
+            operands,
+            text: self.disassemble(memory, pc, &op, rp),
+        }
+    }
+
+    /// Decodes the instructions in `memory` from `start` on, lazily and
+    /// without side effects: memory is read with `peek`. The iterator
+    /// wraps round the top of memory and never ends by itself, so bound
+    /// it with `Instructions::until` or `take`:
+    ///
+    /// ```
+    /// use i8080_emulator::disassembler::Disassembler;
+    /// use i8080_emulator::memory::Memory8080;
+    ///
+    /// let mut memory = [0x00; 0x10000];
+    /// memory[0x100..0x105].copy_from_slice(&[0x3e, 0x01, 0xc3, 0x00, 0x01]);
+    /// let memory = Memory8080::new(memory);
+    /// let disassembler = Disassembler::new();
+    /// let listing: Vec<_> = disassembler.instructions(&memory, 0x100).until(0x105).map(|i| i.mnemonic).collect();
+    /// assert_eq!(listing, ["MVI", "JMP"]);
+    /// ```
+    pub fn instructions<'a, M: Memory>(&'a self, memory: &'a M, start: u16) -> Instructions<'a, M> {
+        Instructions { disassembler: self, memory: Peek(memory), addr: start, end: None, hl: 0, done: false }
+    }
+
+    /// Decodes `bytes` as code loaded at `org`, one `Line` per instruction,
+    /// without needing a CPU. Bytes marked as data and an instruction cut
+    /// short by the end of `bytes` are listed as `DB`, one line per byte.
+    pub fn disassemble_range(&self, bytes: &[u8], org: u16) -> Vec<Line> {
+        let memory = Slice { bytes, org };
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let addr = org.wrapping_add(offset as u16);
+            let op = bytes[offset];
+            let length = usize::from(OPCODE_TABLE[usize::from(op)].length);
+            if offset + length > bytes.len() {
+                let rest = bytes[offset..].iter().enumerate();
+                lines.extend(rest.map(|(i, &b)| Line::data(addr.wrapping_add(i as u16), b)));
+                break;
             }
-            Some(Opcode::RegPairFirstOperand(n1, n2)) => {
-                format!("{:x}    {} $(0x{:x}), {}", pc, n1, rp, n2)
+            let line = if self.is_data(addr, op) {
+                Line::data(addr, op)
+            } else {
+                let mnemonic = OPCODE_TABLE[usize::from(op)].mnemonic;
+                Line {
+                    addr,
+                    bytes: bytes[offset..offset + length].to_vec(),
+                    mnemonic: mnemonic.split(' ').next().unwrap_or(mnemonic),
+                    operands: operands(&memory, addr, op),
+                }
+            };
+            offset += line.bytes.len();
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// Same as `disassemble`, but formats into `buf` without allocating and
+    /// returns the number of bytes written. Output that does not fit in
+    /// `buf` is truncated; 48 bytes is enough for any instruction unless a
+    /// long port name is attached.
+    pub fn disassemble_into(&self, memory: &impl Memory, pc: &u16, op: &u8, rp: &u16, buf: &mut [u8]) -> usize {
+        let mut writer = SliceWriter { buf, len: 0 };
+        self.write_instruction(&mut writer, memory, *pc, *op, *rp)
+            .expect("SliceWriter never fails");
+        writer.len
+    }
+
+    fn write_instruction(&self, w: &mut impl fmt::Write, memory: &impl Memory, pc: u16, op: u8, rp: u16) -> fmt::Result {
+        write!(w, "{:x}    ", pc)?;
+        if self.syntax.lowercase {
+            self.write_body(&mut Lowercase(w), memory, pc, op, rp)?;
+        } else {
+            self.write_body(w, memory, pc, op, rp)?;
+        }
+        // Port names are the user's own text and are left as given.
+        if (op == 0xd3 || op == 0xdb) && !self.is_data(pc, op) {
+            let port = memory.read(pc.wrapping_add(1).into());
+            if let Some(name) = self.port_names.get(&port) {
+                write!(w, " ; {}", name)?;
             }
-            Some(Opcode::RegPairSecOperand(n)) => {
-                format!("{:x}    {} $(0x{:x})", pc, n, rp)
+        }
+        Ok(())
+    }
+
+    fn write_body(&self, w: &mut impl fmt::Write, memory: &impl Memory, pc: u16, op: u8, rp: u16) -> fmt::Result {
+        let imm8 = || Literal(memory.read(pc.wrapping_add(1).into()).into(), self.syntax.literals);
+        let imm16 = || Literal(memory.read16(pc.wrapping_add(1).into()), self.syntax.literals);
+        if self.is_data(pc, op) {
+            return write!(w, "DB {}", Literal(op.into(), self.syntax.literals));
+        }
+        if self.syntax.mnemonics == Mnemonics::Zilog {
+            let nn = || match self.symbol(memory, pc, op) {
+                Some(name) => Word::Symbol(name),
+                None => Word::Literal(imm16()),
+            };
+            return self.write_template(w, ZILOG[op as usize], op, imm8, nn);
+        }
+        // Fixed operands come from the mnemonic, with M shown as the
+        // address in HL. Immediate data follows after a space.
+        let info = OPCODE_TABLE[usize::from(op)];
+        let (name, fixed) = info.mnemonic.split_once(' ').unwrap_or((info.mnemonic, ""));
+        w.write_str(name)?;
+        for (i, operand) in fixed.split(", ").filter(|o| !o.is_empty()).enumerate() {
+            w.write_str(if i == 0 { " " } else { ", " })?;
+            match operand {
+                "M" => write!(w, "$({})", Literal(rp, self.syntax.literals))?,
+                _ => w.write_str(operand)?,
             }
-            Some(Opcode::RegPairAndImm(n)) => {
-                let imm8 = memory.read((pc + 1).into());
-                format!("{:x}    {} $(0x{:x}), 0x{:x}", pc, n, rp, imm8)
+        }
+        match info.length {
+            2 => write!(w, " {}", imm8()),
+            3 if name == "LXI" => write!(w, " {}", imm16()),
+            3 => match self.symbol(memory, pc, op) {
+                Some(name) => write!(w, " {}", name),
+                None => write!(w, " $({})", imm16()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn write_template<'s>(&self, w: &mut impl fmt::Write, template: &str, op: u8,
+                          imm8: impl Fn() -> Literal, imm16: impl Fn() -> Word<'s>) -> fmt::Result {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = start + rest[start..].find('}').expect("Unterminated operand in template");
+            w.write_str(&rest[..start])?;
+            match &rest[start + 1..end] {
+                "n" => write!(w, "{}", imm8())?,
+                "nn" => write!(w, "{}", imm16())?,
+                "r" => write!(w, "{}", Literal((op & 0x38).into(), self.syntax.literals))?,
+                operand => unreachable!("Unknown operand {{{}}} in template", operand),
             }
-            _ => panic!("Could not disassemble: {:x}", op),
+            rest = &rest[end + 1..];
         }
+        w.write_str(rest)
+    }
+
+    // The name of the address the 3-byte instruction at `pc` refers to,
+    // if it has one. LXI loads a number rather than an address, so its
+    // operand is left alone.
+    fn symbol(&self, memory: &impl Memory, pc: u16, op: u8) -> Option<&str> {
+        if op & 0xcf == 0x01 {
+            return None;
+        }
+        self.symbols.as_ref()?.name(memory.read16(pc.wrapping_add(1).into()))
+    }
+
+    /// Prints the target of jumps and calls, and the address of direct
+    /// loads and stores, by name when `symbols` has one for it. In the
+    /// lowercase syntax, names are lowercased along with everything else.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = Some(symbols);
+    }
+
+    pub fn clear_symbols(&mut self) {
+        self.symbols = None;
+    }
+
+    pub fn symbols(&self) -> Option<&SymbolTable> {
+        self.symbols.as_ref()
+    }
+
+    /// Lists the bytes in `range` as data, one `DB` per byte, instead of
+    /// decoding them as instructions. An instruction whose operands would
+    /// run into data is listed as `DB` too.
+    pub fn mark_data(&mut self, range: RangeInclusive<u16>) {
+        self.data.push(range);
+    }
+
+    // Whether the instruction at `pc` overlaps bytes marked as data
+    fn is_data(&self, pc: u16, op: u8) -> bool {
+        let length = u16::from(OPCODE_TABLE[usize::from(op)].length);
+        (0..length).any(|i| {
+            let addr = pc.wrapping_add(i);
+            self.data.iter().any(|range| range.contains(&addr))
+        })
+    }
+
+    /// Changes the mnemonics, case and number format used from now on.
+    pub fn set_syntax(&mut self, syntax: SyntaxStyle) {
+        self.syntax = syntax;
+    }
+
+    /// Names a port so IN/OUT instructions on it are annotated with the
+    /// device behind it, e.g. `OUT 0x3 ; shift register result`.
+    pub fn name_port(&mut self, port: u8, name: impl Into<String>) {
+        self.port_names.insert(port, name.into());
     }
+
     pub fn new() -> Self {
-        let opcodes = vec![
-            (0x00, Opcode::SingleOpcode("NOP")),
-            (0x10, Opcode::SingleOpcode("NOP")),
-            (0x20, Opcode::SingleOpcode("NOP")),
-            (0x30, Opcode::SingleOpcode("NOP")),
-            (0x08, Opcode::SingleOpcode("NOP")),
-            (0x18, Opcode::SingleOpcode("NOP")),
-            (0x28, Opcode::SingleOpcode("NOP")),
-            (0x38, Opcode::SingleOpcode("NOP")),
-
-
-            (0x07, Opcode::SingleOpcode("RLC")),
-            (0x17, Opcode::SingleOpcode("RAL")),
-            (0x0f, Opcode::SingleOpcode("RRC")),
-            (0x1f, Opcode::SingleOpcode("RAR")),
-
-            (0x27, Opcode::SingleOpcode("DAA")),
-            (0x37, Opcode::SingleOpcode("STC")),
-            (0x2f, Opcode::SingleOpcode("CMA")),
-            (0x3f, Opcode::SingleOpcode("CMC")),
-            (0xe3, Opcode::SingleOpcode("XTHL")),
-            (0xf3, Opcode::SingleOpcode("DI")),
-
-            (0xc9, Opcode::SingleOpcode("RET")),
-            (0xd9, Opcode::SingleOpcode("RET")),
-            (0xc8, Opcode::SingleOpcode("RZ")),
-            (0xd8, Opcode::SingleOpcode("RC")),
-            (0xe8, Opcode::SingleOpcode("RPE")),
-            (0xf8, Opcode::SingleOpcode("RM")),
-            (0xc0, Opcode::SingleOpcode("RNZ")),
-            (0xd0, Opcode::SingleOpcode("RNC")),
-            (0xe0, Opcode::SingleOpcode("RPO")),
-            (0xf0, Opcode::SingleOpcode("RP")),
-
-            (0xc2, Opcode::DirectAdress("JNZ")),
-            (0xc3, Opcode::DirectAdress("JMP")),
-            (0xca, Opcode::DirectAdress("JZ")),
-            (0xcb, Opcode::DirectAdress("JMP")),
-            (0xd2, Opcode::DirectAdress("JNC")),
-            (0xda, Opcode::DirectAdress("JC")),
-            (0xe2, Opcode::DirectAdress("JPO")),
-            (0xea, Opcode::DirectAdress("JPE")),
-            (0xf2, Opcode::DirectAdress("JP")),
-            (0xfa, Opcode::DirectAdress("JM")),
-
-            (0xc4, Opcode::DirectAdress("CNZ")),
-            (0xcc, Opcode::DirectAdress("CZ")),
-            (0xcd, Opcode::DirectAdress("CALL")),
-            (0xd4, Opcode::DirectAdress("CNC")),
-            (0xdc, Opcode::DirectAdress("CC")),
-            (0xdd, Opcode::DirectAdress("CALL")),
-            (0xe4, Opcode::DirectAdress("CPO")),
-            (0xec, Opcode::DirectAdress("CPE")),
-            (0xed, Opcode::DirectAdress("CALL")),
-            (0xf4, Opcode::DirectAdress("CP")),
-            (0xfc, Opcode::DirectAdress("CM")),
-            (0xfd, Opcode::DirectAdress("CALL")),
-
-            (0xe9, Opcode::SingleOpcode("PCHL")),
-            (0xf9, Opcode::SingleOpcode("SPHL")),
-            (0xeb, Opcode::SingleOpcode("XCHG")),
-            (0xfb, Opcode::SingleOpcode("EI")),
-
-            (0xc7, Opcode::SingleOpcode("RST 0")),
-            (0xcf, Opcode::SingleOpcode("RST 1")),
-            (0xd7, Opcode::SingleOpcode("RST 2")),
-            (0xdf, Opcode::SingleOpcode("RST 3")),
-            (0xe7, Opcode::SingleOpcode("RST 4")),
-            (0xef, Opcode::SingleOpcode("RST 5")),
-            (0xf7, Opcode::SingleOpcode("RST 6")),
-            (0xff, Opcode::SingleOpcode("RST 7")),
-              
-            // Is this a double register?
-            (0x02, Opcode::SingleOpcode("STAX B")),
-            (0x12, Opcode::SingleOpcode("STAX D")),
-
-            (0x22, Opcode::DirectAdress("SHLD")),
-            (0x2a, Opcode::DirectAdress("LHLD")),
-            (0x32, Opcode::DirectAdress("STA")),
-            (0x3a, Opcode::DirectAdress("LDA")),
-
-            // Is this a double register?
-            (0x03, Opcode::SingleOpcode("INX BC")),
-            (0x13, Opcode::SingleOpcode("INX DE")),
-            (0x23, Opcode::SingleOpcode("INX HL")),
-            (0x33, Opcode::SingleOpcode("INX SP")),
-
-            // Is this a double register?
-            (0x04, Opcode::SingleOpcode("INR B")),
-            (0x14, Opcode::SingleOpcode("INR D")),
-            (0x24, Opcode::SingleOpcode("INR H")),
-            (0x34, Opcode::RegPairSecOperand("INR")),
-            (0x0c, Opcode::SingleOpcode("INR C")),
-            (0x1c, Opcode::SingleOpcode("INR E")),
-            (0x2c, Opcode::SingleOpcode("INR L")),
-            (0x3c, Opcode::SingleOpcode("INR A")),
-
-            // Is this a double register?
-            (0x05, Opcode::SingleOpcode("DCR B")),
-            (0x15, Opcode::SingleOpcode("DCR D")),
-            (0x25, Opcode::SingleOpcode("DCR H")),
-            (0x35, Opcode::RegPairSecOperand("DCR")),
-            (0x0d, Opcode::SingleOpcode("DCR C")),
-            (0x1d, Opcode::SingleOpcode("DCR E")),
-            (0x2d, Opcode::SingleOpcode("DCR L")),
-            (0x3d, Opcode::SingleOpcode("DCR A")),
-
-            // Is this a double register?
-            (0x09, Opcode::SingleOpcode("DAD B")),
-            (0x19, Opcode::SingleOpcode("DAD D")),
-            (0x29, Opcode::SingleOpcode("DAD H")),
-            (0x39, Opcode::SingleOpcode("DAD SP")),
-
-            // Is this a double register?
-            (0x0a, Opcode::SingleOpcode("LDAX B")),
-            (0x1a, Opcode::SingleOpcode("LDAX D")),
-
-            // Is this a double register?
-            (0x0b, Opcode::SingleOpcode("DCX B")),
-            (0x1b, Opcode::SingleOpcode("DCX D")),
-            (0x2b, Opcode::SingleOpcode("DCX H")),
-            (0x3b, Opcode::SingleOpcode("DCX SP")),
-
-            // Is this a double register?
-            (0x01, Opcode::Immediate16("LXI BC")),
-            (0x11, Opcode::Immediate16("LXI DE")),
-            (0x21, Opcode::Immediate16("LXI HL")),
-            (0x31, Opcode::Immediate16("LXI SP")),
-
-            (0x40, Opcode::SingleOpcode("MOV B, B")),
-            (0x50, Opcode::SingleOpcode("MOV D, B")),
-            (0x60, Opcode::SingleOpcode("MOV H, B")),
-            (0x70, Opcode::RegPairFirstOperand("MOV", "B")),
-
-            (0x41, Opcode::SingleOpcode("MOV B, C")),
-            (0x51, Opcode::SingleOpcode("MOV D, C")),
-            (0x61, Opcode::SingleOpcode("MOV H, C")),
-            (0x71, Opcode::RegPairFirstOperand("MOV", "C")),
-
-            (0x42, Opcode::SingleOpcode("MOV B, D")),
-            (0x52, Opcode::SingleOpcode("MOV D, D")),
-            (0x62, Opcode::SingleOpcode("MOV H, D")),
-            (0x72, Opcode::RegPairFirstOperand("MOV", "D")),
-
-            (0x43, Opcode::SingleOpcode("MOV B, E")),
-            (0x53, Opcode::SingleOpcode("MOV D, E")),
-            (0x63, Opcode::SingleOpcode("MOV H, E")),
-            (0x73, Opcode::RegPairFirstOperand("MOV", "E")),
-
-            (0x44, Opcode::SingleOpcode("MOV B, H")),
-            (0x54, Opcode::SingleOpcode("MOV D, H")),
-            (0x64, Opcode::SingleOpcode("MOV H, H")),
-            (0x74, Opcode::RegPairFirstOperand("MOV", "H")),
-
-            (0x45, Opcode::SingleOpcode("MOV B, L")),
-            (0x55, Opcode::SingleOpcode("MOV D, L")),
-            (0x65, Opcode::SingleOpcode("MOV H, L")),
-            (0x75, Opcode::RegPairFirstOperand("MOV", "L")),
-
-            (0x46, Opcode::RegPairSecOperand("MOV C")),
-            (0x56, Opcode::RegPairSecOperand("MOV E")),
-            (0x66, Opcode::RegPairSecOperand("MOV L")),
-            (0x76, Opcode::RegPairSecOperand("MOV A")),
-
-            (0x47, Opcode::SingleOpcode("MOV B, A")),
-            (0x57, Opcode::SingleOpcode("MOV D, A")),
-            (0x67, Opcode::SingleOpcode("MOV H, A")),
-            (0x77, Opcode::RegPairFirstOperand("MOV", "A")),
-
-            (0x48, Opcode::SingleOpcode("MOV C, B")),
-            (0x58, Opcode::SingleOpcode("MOV E, B")),
-            (0x68, Opcode::SingleOpcode("MOV L, B")),
-            (0x78, Opcode::SingleOpcode("MOV A, B")),
-
-            (0x49, Opcode::SingleOpcode("MOV C, C")),
-            (0x59, Opcode::SingleOpcode("MOV E, C")),
-            (0x69, Opcode::SingleOpcode("MOV L, C")),
-            (0x79, Opcode::SingleOpcode("MOV A, C")),
-
-            (0x4a, Opcode::SingleOpcode("MOV C, D")),
-            (0x5a, Opcode::SingleOpcode("MOV E, D")),
-            (0x6a, Opcode::SingleOpcode("MOV L, D")),
-            (0x7a, Opcode::SingleOpcode("MOV A, D")),
-
-            (0x4b, Opcode::SingleOpcode("MOV C, E")),
-            (0x5b, Opcode::SingleOpcode("MOV E, E")),
-            (0x6b, Opcode::SingleOpcode("MOV L, E")),
-            (0x7b, Opcode::SingleOpcode("MOV A, E")),
-
-            (0x4c, Opcode::SingleOpcode("MOV C, H")),
-            (0x5c, Opcode::SingleOpcode("MOV E, H")),
-            (0x6c, Opcode::SingleOpcode("MOV L, H")),
-            (0x7c, Opcode::SingleOpcode("MOV A, H")),
-
-            (0x4d, Opcode::SingleOpcode("MOV C, L")),
-            (0x5d, Opcode::SingleOpcode("MOV E, L")),
-            (0x6d, Opcode::SingleOpcode("MOV L, L")),
-            (0x7d, Opcode::SingleOpcode("MOV A, L")),
-
-            (0x4e, Opcode::DirectAdress("MOV C")),
-            (0x5e, Opcode::DirectAdress("MOV E")),
-            (0x6e, Opcode::DirectAdress("MOV L")),
-            (0x7e, Opcode::DirectAdress("MOV A")),
-
-            (0x4f, Opcode::SingleOpcode("MOV C, A")),
-            (0x5f, Opcode::SingleOpcode("MOV E, A")),
-            (0x6f, Opcode::SingleOpcode("MOV L, A")),
-            (0x7f, Opcode::SingleOpcode("MOV A, A")),
-
-            (0x06, Opcode::Immediate8("MVI B")),
-            (0x0e, Opcode::Immediate8("MVI C")),
-            (0x16, Opcode::Immediate8("MVI D")),
-            (0x1e, Opcode::Immediate8("MVI E")),
-            (0x26, Opcode::Immediate8("MVI H")),
-            (0x2e, Opcode::Immediate8("MVI L")),
-            (0x36, Opcode::RegPairAndImm("MVI")),
-            (0x3e, Opcode::Immediate8("MVI A")),
-
-
-
-            (0x80, Opcode::SingleOpcode("ADD B")),
-            (0x81, Opcode::SingleOpcode("ADD C")),
-            (0x82, Opcode::SingleOpcode("ADD D")),
-            (0x83, Opcode::SingleOpcode("ADD E")),
-            (0x84, Opcode::SingleOpcode("ADD H")),
-            (0x85, Opcode::SingleOpcode("ADD L")),
-            (0x86, Opcode::RegPairSecOperand("ADD")),
-            (0x87, Opcode::SingleOpcode("ADD A")),
-
-            (0xc6, Opcode::Immediate8("ADI")),
-
-            (0x88, Opcode::SingleOpcode("ADC B")),
-            (0x89, Opcode::SingleOpcode("ADC C")),
-            (0x8a, Opcode::SingleOpcode("ADC D")),
-            (0x8b, Opcode::SingleOpcode("ADC E")),
-            (0x8c, Opcode::SingleOpcode("ADC H")),
-            (0x8d, Opcode::SingleOpcode("ADC L")),
-            (0x8e, Opcode::RegPairSecOperand("ADC")),
-            (0x8f, Opcode::SingleOpcode("ADC A")),
-
-            (0xce, Opcode::Immediate8("ACI")),
-
-            (0x90, Opcode::SingleOpcode("SUB B")),
-            (0x91, Opcode::SingleOpcode("SUB C")),
-            (0x92, Opcode::SingleOpcode("SUB D")),
-            (0x93, Opcode::SingleOpcode("SUB E")),
-            (0x94, Opcode::SingleOpcode("SUB H")),
-            (0x95, Opcode::SingleOpcode("SUB L")),
-            (0x96, Opcode::RegPairSecOperand("SUB")),
-            (0x97, Opcode::SingleOpcode("SUB A")),
-
-            (0xd6, Opcode::Immediate8("SUI")),
-
-            (0x98, Opcode::SingleOpcode("SBB B")),
-            (0x99, Opcode::SingleOpcode("SBB C")),
-            (0x9a, Opcode::SingleOpcode("SBB D")),
-            (0x9b, Opcode::SingleOpcode("SBB E")),
-            (0x9c, Opcode::SingleOpcode("SBB H")),
-            (0x9d, Opcode::SingleOpcode("SBB L")),
-            (0x9e, Opcode::RegPairSecOperand("SBB")),
-            (0x9f, Opcode::SingleOpcode("SBB A")),
-
-            (0xde, Opcode::Immediate8("SBI")),
-
-            (0xa0, Opcode::SingleOpcode("ANA B")),
-            (0xa1, Opcode::SingleOpcode("ANA C")),
-            (0xa2, Opcode::SingleOpcode("ANA D")),
-            (0xa3, Opcode::SingleOpcode("ANA E")),
-            (0xa4, Opcode::SingleOpcode("ANA H")),
-            (0xa5, Opcode::SingleOpcode("ANA L")),
-            (0xa6, Opcode::RegPairSecOperand("ANA")),
-            (0xa7, Opcode::SingleOpcode("ANA A")),
-
-            (0xe6, Opcode::Immediate8("ANI")),
-
-            (0xa8, Opcode::SingleOpcode("XRA B")),
-            (0xa9, Opcode::SingleOpcode("XRA C")),
-            (0xaa, Opcode::SingleOpcode("XRA D")),
-            (0xab, Opcode::SingleOpcode("XRA E")),
-            (0xac, Opcode::SingleOpcode("XRA H")),
-            (0xad, Opcode::SingleOpcode("XRA L")),
-            (0xae, Opcode::RegPairSecOperand("XRA")),
-            (0xaf, Opcode::SingleOpcode("XRA A")),
-
-            (0xee, Opcode::Immediate8("XRI")),
-
-            (0xb0, Opcode::SingleOpcode("ORA B")),
-            (0xb1, Opcode::SingleOpcode("ORA C")),
-            (0xb2, Opcode::SingleOpcode("ORA D")),
-            (0xb3, Opcode::SingleOpcode("ORA E")),
-            (0xb4, Opcode::SingleOpcode("ORA H")),
-            (0xb5, Opcode::SingleOpcode("ORA L")),
-            (0xb6, Opcode::RegPairSecOperand("ORA")),
-            (0xb7, Opcode::SingleOpcode("ORA A")),
-
-            (0xf6, Opcode::Immediate8("ORI")),
-
-            (0xb8, Opcode::SingleOpcode("CMP B")),
-            (0xb9, Opcode::SingleOpcode("CMP C")),
-            (0xba, Opcode::SingleOpcode("CMP D")),
-            (0xbb, Opcode::SingleOpcode("CMP E")),
-            (0xbc, Opcode::SingleOpcode("CMP H")),
-            (0xbd, Opcode::SingleOpcode("CMP L")),
-            (0xbe, Opcode::RegPairSecOperand("CMP")),
-            (0xbf, Opcode::SingleOpcode("CMP A")),
-
-            (0xfe, Opcode::Immediate8("CPI")),
-
-            (0xc1, Opcode::SingleOpcode("POP BC")),
-            (0xd1, Opcode::SingleOpcode("POP DE")),
-            (0xe1, Opcode::SingleOpcode("POP HL")),
-            (0xf1, Opcode::SingleOpcode("POP PSW")),
-
-            (0xc5, Opcode::SingleOpcode("PUSH BC")),
-            (0xd5, Opcode::SingleOpcode("PUSH DE")),
-            (0xe5, Opcode::SingleOpcode("PUSH HL")),
-            (0xf5, Opcode::SingleOpcode("PUSH PSW")),
-
-            (0xd3, Opcode::Immediate8("OUT")),
-            (0xdb, Opcode::Immediate8("IN")),
-        ];
         Disassembler {
-            ins: opcodes.into_iter().collect(),
+            port_names: BTreeMap::new(),
+            syntax: SyntaxStyle::default(),
+            data: Vec::new(),
+            symbols: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disassembler::{Disassembler, Line, OPCODE_TABLE, Literals, Mnemonics, Operand, Reg16, Reg8, SyntaxStyle};
+    use crate::opcodes::OPCODES;
+    use crate::memory::{Access, Memory, Memory8080};
+    use crate::symbols::SymbolTable;
+
+    #[test]
+    fn unnamed_port() {
+        let mut memory = Memory8080::new_empty();
+        memory.write(0, 0xd3);
+        memory.write(1, 0x03);
+        let disassembler = Disassembler::new();
+        assert_eq!(disassembler.disassemble(&memory, &0, &0xd3, &0), "0    OUT 0x3");
+    }
+
+    #[test]
+    fn named_port() {
+        let mut memory = Memory8080::new_empty();
+        memory.write(0, 0xdb);
+        memory.write(1, 0x01);
+        let mut disassembler = Disassembler::new();
+        disassembler.name_port(0x01, "player 1 controls");
+        assert_eq!(disassembler.disassemble(&memory, &0, &0xdb, &0),
+                   "0    IN 0x1 ; player 1 controls");
+    }
+
+    #[test]
+    fn disassemble_into_buffer() {
+        let mut memory = Memory8080::new_empty();
+        memory.write(0x100, 0x21);
+        memory.write16(0x101, 0x2400);
+        let disassembler = Disassembler::new();
+        let mut buf = [0; 48];
+        let n = disassembler.disassemble_into(&memory, &0x100, &0x21, &0, &mut buf);
+        assert_eq!(&buf[..n], disassembler.disassemble(&memory, &0x100, &0x21, &0).as_bytes());
+    }
+
+    #[test]
+    fn disassemble_into_truncates() {
+        let mut memory = Memory8080::new_empty();
+        memory.write(0, 0x21);
+        let disassembler = Disassembler::new();
+        let mut buf = [0; 6];
+        let n = disassembler.disassemble_into(&memory, &0, &0x21, &0, &mut buf);
+        assert_eq!(&buf[..n], b"0    L");
+    }
+
+    #[test]
+    fn suffixed_literals() {
+        let mut memory = Memory8080::new_empty();
+        memory.write(0, 0x3e);
+        memory.write(1, 0xfe);
+        let mut disassembler = Disassembler::new();
+        disassembler.set_syntax(SyntaxStyle { literals: Literals::Suffixed, ..SyntaxStyle::default() });
+        assert_eq!(disassembler.disassemble(&memory, &0, &0x3e, &0), "0    MVI A 0FEh");
+        memory.write(1, 0x3e);
+        assert_eq!(disassembler.disassemble(&memory, &0, &0x3e, &0), "0    MVI A 3Eh");
+    }
+
+    #[test]
+    fn zilog_mnemonics() {
+        let mut memory = Memory8080::new_empty();
+        // LD HL,2400h; LD (HL),A; JP NZ,5h; RST 38h
+        for (i, b) in [0x21, 0x00, 0x24, 0x77, 0xc2, 0x05, 0x00, 0xff].iter().enumerate() {
+            memory.write(i, *b);
         }
+        let mut disassembler = Disassembler::new();
+        disassembler.set_syntax(SyntaxStyle {
+            mnemonics: Mnemonics::Zilog,
+            lowercase: false,
+            literals: Literals::Suffixed,
+        });
+        assert_eq!(disassembler.disassemble(&memory, &0, &0x21, &0), "0    LD HL,2400h");
+        assert_eq!(disassembler.disassemble(&memory, &3, &0x77, &0), "3    LD (HL),A");
+        assert_eq!(disassembler.disassemble(&memory, &4, &0xc2, &0), "4    JP NZ,5h");
+        assert_eq!(disassembler.disassemble(&memory, &7, &0xff, &0), "7    RST 38h");
+    }
+
+    #[test]
+    fn lowercase_keeps_port_names() {
+        let mut memory = Memory8080::new_empty();
+        memory.write(0, 0xd3);
+        memory.write(1, 0x03);
+        let mut disassembler = Disassembler::new();
+        disassembler.name_port(0x03, "Shift Data");
+        disassembler.set_syntax(SyntaxStyle { lowercase: true, ..SyntaxStyle::default() });
+        assert_eq!(disassembler.disassemble(&memory, &0, &0xd3, &0), "0    out 0x3 ; Shift Data");
+    }
+
+    #[test]
+    fn decode_operands() {
+        let mut memory = Memory8080::new_empty();
+        // MOV M, A; MVI B, 0x10; LXI SP, 0x2400; STA 0x2000; PUSH PSW; IN 0x01; RST 7
+        let code = [0x77, 0x06, 0x10, 0x31, 0x00, 0x24, 0x32, 0x00, 0x20, 0xf5, 0xdb, 0x01, 0xff];
+        for (i, b) in code.iter().enumerate() {
+            memory.write(i, *b);
+        }
+        let disassembler = Disassembler::new();
+        let decode = |pc: u16| disassembler.decode(&memory, &pc, &0).operands;
+        assert_eq!(decode(0), [Operand::Mem, Operand::Reg(Reg8::A)]);
+        assert_eq!(decode(1), [Operand::Reg(Reg8::B), Operand::Imm8(0x10)]);
+        assert_eq!(decode(3), [Operand::RegPair(Reg16::SP), Operand::Imm16(0x2400)]);
+        assert_eq!(decode(6), [Operand::Addr(0x2000)]);
+        assert_eq!(decode(9), [Operand::RegPair(Reg16::PSW)]);
+        assert_eq!(decode(10), [Operand::Port(0x01)]);
+        assert_eq!(decode(12), [Operand::Restart(7)]);
+    }
+
+    #[test]
+    fn decode_text_and_mnemonic() {
+        let mut memory = Memory8080::new_empty();
+        memory.write(0, 0xc2);
+        memory.write16(1, 0x0005);
+        let disassembler = Disassembler::new();
+        let instruction = disassembler.decode(&memory, &0, &0);
+        assert_eq!(instruction.mnemonic, "JNZ");
+        assert_eq!(instruction.text, disassembler.disassemble(&memory, &0, &0xc2, &0));
+    }
+
+    #[test]
+    fn operand_count_matches_length() {
+        // Every byte after the opcode belongs to exactly one operand.
+        let memory = Memory8080::new_empty();
+        for op in 0..=0xffu8 {
+            let bytes: u8 = super::operands(&memory, 0, op).iter().map(|o| match o {
+                Operand::Imm8(_) | Operand::Port(_) => 1,
+                Operand::Imm16(_) | Operand::Addr(_) => 2,
+                _ => 0,
+            }).sum();
+            assert_eq!(bytes + 1, OPCODES[op as usize].length, "opcode 0x{:02x}", op);
+        }
+    }
+
+    #[test]
+    fn disassemble_range() {
+        // MVI A, 0x42; STA 0x2000; MOV M, A; then half a JMP
+        let code = [0x3e, 0x42, 0x32, 0x00, 0x20, 0x77, 0xc3, 0x00];
+        let lines = Disassembler::new().disassemble_range(&code, 0xfff8);
+        assert_eq!(lines, [
+            Line { addr: 0xfff8, bytes: vec![0x3e, 0x42], mnemonic: "MVI", operands: vec![Operand::Reg(Reg8::A), Operand::Imm8(0x42)] },
+            Line { addr: 0xfffa, bytes: vec![0x32, 0x00, 0x20], mnemonic: "STA", operands: vec![Operand::Addr(0x2000)] },
+            Line { addr: 0xfffd, bytes: vec![0x77], mnemonic: "MOV", operands: vec![Operand::Mem, Operand::Reg(Reg8::A)] },
+            Line { addr: 0xfffe, bytes: vec![0xc3], mnemonic: "DB", operands: vec![Operand::Imm8(0xc3)] },
+            Line { addr: 0xffff, bytes: vec![0x00], mnemonic: "DB", operands: vec![Operand::Imm8(0x00)] },
+        ]);
+        assert!(Disassembler::new().disassemble_range(&[], 0).is_empty());
+    }
+
+    #[test]
+    fn opcode_table() {
+        assert_eq!(OPCODE_TABLE, OPCODES);
+        assert_eq!(OPCODE_TABLE[0x76].mnemonic, "HLT");
+        assert_eq!((OPCODE_TABLE[0xcd].length, OPCODE_TABLE[0xcd].cycles), (3, 17));
+    }
+
+    #[test]
+    fn every_opcode_disassembles() {
+        let mut memory = Memory8080::new_empty();
+        let disassembler = Disassembler::new();
+        for op in 0..=0xffu8 {
+            memory.write(0x100, op);
+            let text = disassembler.disassemble(&memory, &0x100, &op, &0x2400);
+            let name = OPCODE_TABLE[op as usize].mnemonic.split(' ').next().unwrap();
+            assert!(text.starts_with(&format!("100    {}", name)), "opcode 0x{:02x}: {}", op, text);
+        }
+        let text = |op: u8| disassembler.disassemble(&memory, &0, &op, &0x2400);
+        assert_eq!(text(0x75), "0    MOV $(0x2400), L");
+        assert_eq!(text(0x76), "0    HLT");
+        assert_eq!(text(0x4e), "0    MOV C, $(0x2400)");
+        assert_eq!(text(0x36), "0    MVI $(0x2400) 0x0");
+    }
+
+    #[test]
+    fn symbols() {
+        // CALL 0x01a2; LXI H, 0x01a2; STA 0x2000; JMP 0x0300
+        let code = [0xcd, 0xa2, 0x01, 0x21, 0xa2, 0x01, 0x32, 0x00, 0x20, 0xc3, 0x00, 0x03];
+        let mut memory = Memory8080::new_empty();
+        for (i, b) in code.iter().enumerate() {
+            memory.write(i, *b);
+        }
+        let mut symbols = SymbolTable::new();
+        symbols.insert("PrintChar", 0x01a2);
+        symbols.insert("score", 0x2000);
+        let mut disassembler = Disassembler::new();
+        disassembler.set_symbols(symbols);
+        let text = |disassembler: &Disassembler, pc: u16| disassembler.disassemble(&memory, &pc, &code[usize::from(pc)], &0);
+        assert_eq!(text(&disassembler, 0), "0    CALL PrintChar");
+        assert_eq!(text(&disassembler, 3), "3    LXI H 0x1a2");
+        assert_eq!(text(&disassembler, 6), "6    STA score");
+        assert_eq!(text(&disassembler, 9), "9    JMP $(0x300)");
+
+        disassembler.set_syntax(SyntaxStyle { mnemonics: Mnemonics::Zilog, ..SyntaxStyle::default() });
+        assert_eq!(text(&disassembler, 0), "0    CALL PrintChar");
+        assert_eq!(text(&disassembler, 3), "3    LD HL,0x1a2");
+        assert_eq!(text(&disassembler, 6), "6    LD (score),A");
+        disassembler.clear_symbols();
+        assert_eq!(text(&disassembler, 0), "0    CALL 0x1a2");
+    }
+
+    #[test]
+    fn data_ranges() {
+        // LXI H, 0x2400; then two bytes of data; then OUT 0x01
+        let code = [0x21, 0x00, 0x24, 0xd3, 0x01, 0xd3, 0x01];
+        let mut memory = Memory8080::new_empty();
+        for (i, b) in code.iter().enumerate() {
+            memory.write(i, *b);
+        }
+        let mut disassembler = Disassembler::new();
+        disassembler.name_port(0x01, "printer");
+        disassembler.mark_data(0x0002..=0x0004);
+        assert_eq!(disassembler.disassemble(&memory, &0, &0x21, &0), "0    DB 0x21");
+        assert_eq!(disassembler.disassemble(&memory, &3, &0xd3, &0), "3    DB 0xd3");
+        assert_eq!(disassembler.disassemble(&memory, &5, &0xd3, &0), "5    OUT 0x1 ; printer");
+        assert_eq!(disassembler.decode(&memory, &4, &0).operands, [Operand::Imm8(0x01)]);
+        let lines = disassembler.disassemble_range(&code, 0);
+        let mnemonics: Vec<_> = lines.iter().map(|line| line.mnemonic).collect();
+        assert_eq!(mnemonics, ["DB", "NOP", "DB", "DB", "DB", "OUT"]);
+    }
+
+    #[test]
+    fn instruction_iterator() {
+        // LXI H, 0x2000; DB 0xaa; JMP 0xfffe ... 0xfffe: CALL
+        let mut bytes = [0x00; 0x10000];
+        bytes[..7].copy_from_slice(&[0x21, 0x00, 0x20, 0xaa, 0xc3, 0xfe, 0xff]);
+        bytes[0xfffe] = 0xcd;
+        let mut memory = Memory8080::new(bytes);
+        memory.set_watch(0x0000..=0xffff, Access::Read);
+        let mut disassembler = Disassembler::new();
+        disassembler.mark_data(0x0003..=0x0003);
+        let addrs: Vec<u16> = disassembler.instructions(&memory, 0x0000).until(0x0007).map(|i| i.addr).collect();
+        assert_eq!(addrs, [0x0000, 0x0003, 0x0004]);
+        assert_eq!(memory.take_watch_hit(), None);
+
+        // The CALL at the top runs past the end of memory, which ends a
+        // bounded listing and wraps an unbounded one
+        let top: Vec<_> = disassembler.instructions(&memory, 0xfffe).until(0xffff).map(|i| i.mnemonic).collect();
+        assert_eq!(top, ["CALL"]);
+        let wrapping: Vec<_> = disassembler.instructions(&memory, 0xfffe).take(2).map(|i| (i.addr, i.length())).collect();
+        assert_eq!(wrapping, [(0xfffe, 3), (0x0001, 1)]);
     }
 }
@@ -0,0 +1,83 @@
+use crate::cpu::Fault;
+
+use core::error::Error;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io;
+
+/// Errors a machine or the emulator core can report instead of panicking.
+/// Which variants exist depends on the `std` feature, so matches outside
+/// the crate need a wildcard arm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EmulatorError {
+    /// The program accessed a port that nothing is attached to.
+    UnmappedPort(u8),
+    /// The CPU halted at the given address and the machine has no way to
+    /// wake it up again.
+    Halted(u16),
+    /// A debugging check, such as a non-executable region, stopped the
+    /// CPU.
+    Fault(Fault),
+    /// The machine gave up after running the given number of
+    /// instructions.
+    StepLimit(u64),
+    /// The machine gave up after running the given number of clock
+    /// cycles.
+    CycleLimit(u64),
+    /// Passing the machine's output on to the host failed.
+    #[cfg(feature = "std")]
+    Output(io::ErrorKind),
+    /// Reading the machine's input from the host failed.
+    #[cfg(feature = "std")]
+    Input(io::ErrorKind),
+    /// A snapshot was asked for between fetching and executing the
+    /// instruction at the given address.
+    MidInstruction(u16),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulatorError::UnmappedPort(port) => write!(f, "access to unmapped port 0x{:02x}", port),
+            EmulatorError::Halted(pc) => write!(f, "CPU halted at 0x{:04x}", pc),
+            EmulatorError::Fault(fault) => write!(f, "fault: {}", fault),
+            EmulatorError::StepLimit(steps) => write!(f, "gave up after {} instructions", steps),
+            EmulatorError::CycleLimit(cycles) => write!(f, "gave up after {} clock cycles", cycles),
+            #[cfg(feature = "std")]
+            EmulatorError::Output(kind) => write!(f, "could not write output: {}", kind),
+            #[cfg(feature = "std")]
+            EmulatorError::Input(kind) => write!(f, "could not read input: {}", kind),
+            EmulatorError::MidInstruction(pc) => write!(f, "snapshot taken in the middle of the instruction at 0x{:04x}", pc),
+        }
+    }
+}
+
+impl Error for EmulatorError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Fault;
+    use crate::error::EmulatorError;
+
+    #[test]
+    fn display() {
+        assert_eq!(EmulatorError::UnmappedPort(0x03).to_string(), "access to unmapped port 0x03");
+        assert_eq!(EmulatorError::Halted(0x0100).to_string(), "CPU halted at 0x0100");
+        assert_eq!(EmulatorError::Fault(Fault::NoExecute(0x2400)).to_string(),
+                   "fault: execution of non-executable address 0x2400");
+        assert_eq!(EmulatorError::StepLimit(1000).to_string(), "gave up after 1000 instructions");
+        assert_eq!(EmulatorError::CycleLimit(4000).to_string(), "gave up after 4000 clock cycles");
+        assert_eq!(EmulatorError::MidInstruction(0x0100).to_string(),
+                   "snapshot taken in the middle of the instruction at 0x0100");
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn display_io() {
+        assert_eq!(EmulatorError::Output(std::io::ErrorKind::BrokenPipe).to_string(),
+                   "could not write output: broken pipe");
+        assert_eq!(EmulatorError::Input(std::io::ErrorKind::InvalidData).to_string(),
+                   "could not read input: invalid data");
+    }
+}
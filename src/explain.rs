@@ -0,0 +1,306 @@
+//! Plain-language explanations of executed instructions, for students
+//! learning the 8080.
+//!
+//! `Explainer::step` runs one instruction and describes what it did with
+//! the values involved:
+//!
+//! ```text
+//! ADD B: A(0x3C)+B(0x05)=0x41; flags: S=0 Z=0 A=1 P=1 C=0
+//! JNZ 0x0100: NZ is true, PC=0x0100
+//! PUSH B: pushed BC(0x1234), SP=0xEFFE
+//! ```
+
+use crate::cpu::{CpuState, Event, CPU};
+use crate::disassembler::{Disassembler, Instruction, Operand, Reg16, Reg8};
+use crate::flag_usage::flags_written;
+use crate::memory::Memory;
+use crate::opcodes::OPCODES;
+use crate::I8080Core;
+
+use std::fmt::Write;
+
+/// Executes instructions and explains each one.
+pub struct Explainer {
+    disassembler: Disassembler,
+}
+
+impl Default for Explainer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Explainer {
+    pub fn new() -> Self {
+        Explainer {
+            disassembler: Disassembler::new(),
+        }
+    }
+
+    /// Executes the instruction at PC and returns its event together with
+    /// an explanation. Port accesses are left for the caller to resolve as
+    /// usual.
+    pub fn step(&self, cpu: &mut CPU) -> (Event, String) {
+        if cpu.is_halted() {
+            return (cpu.step().0, "halted, waiting for an interrupt".to_string());
+        }
+        let before = cpu.state();
+        let (instruction, m) = {
+            let memory = cpu.memory.borrow();
+            let instruction = self.disassembler.decode(&*memory, &before.pc, &0);
+            let m = memory.read(usize::from(hl(&before)));
+            (instruction, m)
+        };
+        let (event, _) = cpu.step();
+        let after = cpu.state();
+        let m_after = cpu.memory.borrow().read(usize::from(hl(&before)));
+        let explanation = match event {
+            Event::Fault(fault) => format!("{}: {}", name(&instruction), fault),
+            _ => explain(&instruction, &before, &after, m, m_after),
+        };
+        (event, explanation)
+    }
+}
+
+/// Describes `instruction`, which took the CPU from `before` to `after`.
+/// `m` and `m_after` are the byte at the address in HL before the
+/// instruction, before and after it ran.
+pub fn explain(instruction: &Instruction, before: &CpuState, after: &CpuState, m: u8, m_after: u8) -> String {
+    let ops = &instruction.operands;
+    let source = |operand: &Operand| match *operand {
+        Operand::Reg(r) => (format!("{:?}(0x{:02X})", r, reg(before, r)), reg(before, r)),
+        Operand::Mem => (format!("M[0x{:04X}](0x{:02X})", hl(before), m), m),
+        Operand::Imm8(n) => (format!("0x{:02X}", n), n),
+        _ => unreachable!("not a byte operand"),
+    };
+    let rp = match ops.first() {
+        Some(Operand::RegPair(rp)) => *rp,
+        _ => Reg16::HL,
+    };
+    let detail = match instruction.mnemonic {
+        "ADD" | "ADI" | "ADC" | "ACI" | "SUB" | "SUI" | "SBB" | "SBI" | "ANA" | "ANI" | "XRA" | "XRI" | "ORA"
+        | "ORI" => {
+            let (text, _) = source(&ops[ops.len() - 1]);
+            let (symbol, carry) = match instruction.mnemonic {
+                "ADD" | "ADI" => ("+", String::new()),
+                "ADC" | "ACI" => ("+", format!("+CY({})", before.f & 0x01)),
+                "SUB" | "SUI" => ("-", String::new()),
+                "SBB" | "SBI" => ("-", format!("-CY({})", before.f & 0x01)),
+                "ANA" | "ANI" => ("&", String::new()),
+                "XRA" | "XRI" => ("^", String::new()),
+                _ => ("|", String::new()),
+            };
+            format!("A(0x{:02X}){}{}{}=0x{:02X}", before.a, symbol, text, carry, after.a)
+        }
+        "CMP" | "CPI" => {
+            let (text, value) = source(&ops[0]);
+            format!("A(0x{:02X})-{}=0x{:02X}, result discarded", before.a, text, before.a.wrapping_sub(value))
+        }
+        "INR" => {
+            let (text, value) = source(&ops[0]);
+            format!("{}+1=0x{:02X}", text, value.wrapping_add(1))
+        }
+        "DCR" => {
+            let (text, value) = source(&ops[0]);
+            format!("{}-1=0x{:02X}", text, value.wrapping_sub(1))
+        }
+        "MOV" | "MVI" => {
+            let (text, _) = source(&ops[1]);
+            match ops[0] {
+                Operand::Reg(r) => format!("{:?}={}", r, text),
+                _ => format!("M[0x{:04X}]={}", hl(before), text),
+            }
+        }
+        "LXI" => format!("{:?}=0x{:04X}", rp, pair(after, rp)),
+        "INX" => format!("{:?}(0x{:04X})+1=0x{:04X}", rp, pair(before, rp), pair(after, rp)),
+        "DCX" => format!("{:?}(0x{:04X})-1=0x{:04X}", rp, pair(before, rp), pair(after, rp)),
+        "DAD" => format!("HL(0x{:04X})+{:?}(0x{:04X})=0x{:04X}", hl(before), rp, pair(before, rp), hl(after)),
+        "PUSH" => format!("pushed {:?}(0x{:04X}), SP=0x{:04X}", rp, pair(before, rp), after.sp),
+        "POP" => format!("popped {:?}=0x{:04X}, SP=0x{:04X}", rp, pair(after, rp), after.sp),
+        mnemonic if is_branch(mnemonic) => {
+            let next = before.pc.wrapping_add(OPCODES[usize::from(instruction.opcode)].length.into());
+            let test = match condition(mnemonic) {
+                Some(condition) if !holds(condition, before.f) => {
+                    return with_flags(format!("{}: {} is false, continuing", name(instruction), condition),
+                                      instruction.opcode, after);
+                }
+                Some(condition) => format!("{} is true, ", condition),
+                None => String::new(),
+            };
+            if after.sp < before.sp {
+                format!("{}PC=0x{:04X}, return address 0x{:04X} pushed", test, after.pc, next)
+            } else {
+                format!("{}PC=0x{:04X}", test, after.pc)
+            }
+        }
+        _ => describe_changes(before, after, m, m_after),
+    };
+    with_flags(format!("{}: {}", name(instruction), detail), instruction.opcode, after)
+}
+
+// Appends the resulting flags if `op` sets any
+fn with_flags(mut text: String, op: u8, after: &CpuState) -> String {
+    if flags_written(op) != 0 {
+        let f = after.f;
+        text.push_str(&format!("; flags: S={} Z={} A={} P={} C={}",
+                               f >> 7 & 1, f >> 6 & 1, f >> 4 & 1, f >> 2 & 1, f & 1));
+    }
+    text
+}
+
+// The instruction in Intel syntax, e.g. "MVI A, 0x05"
+fn name(instruction: &Instruction) -> String {
+    let mut out = instruction.mnemonic.to_string();
+    for (i, operand) in instruction.operands.iter().enumerate() {
+        out.push_str(if i == 0 { " " } else { ", " });
+        let _ = match operand {
+            Operand::Reg(r) => write!(out, "{:?}", r),
+            Operand::Mem => write!(out, "M"),
+            Operand::RegPair(Reg16::BC) => write!(out, "B"),
+            Operand::RegPair(Reg16::DE) => write!(out, "D"),
+            Operand::RegPair(Reg16::HL) => write!(out, "H"),
+            Operand::RegPair(rp) => write!(out, "{:?}", rp),
+            Operand::Imm8(n) | Operand::Port(n) => write!(out, "0x{:02X}", n),
+            Operand::Imm16(n) | Operand::Addr(n) => write!(out, "0x{:04X}", n),
+            Operand::Restart(n) => write!(out, "{}", n),
+        };
+    }
+    out
+}
+
+fn is_branch(mnemonic: &str) -> bool {
+    matches!(mnemonic, "JMP" | "CALL" | "RET" | "PCHL" | "RST")
+        || (mnemonic.len() <= 3 && condition(mnemonic).is_some())
+}
+
+// The condition tested by a conditional jump, call or return
+fn condition(mnemonic: &str) -> Option<&str> {
+    let (kind, condition) = mnemonic.split_at(1);
+    match (kind, condition) {
+        ("J" | "C" | "R", "NZ" | "Z" | "NC" | "C" | "PO" | "PE" | "P" | "M") => Some(condition),
+        _ => None,
+    }
+}
+
+fn holds(condition: &str, f: u8) -> bool {
+    match condition {
+        "NZ" => f & 0x40 == 0,
+        "Z" => f & 0x40 != 0,
+        "NC" => f & 0x01 == 0,
+        "C" => f & 0x01 != 0,
+        "PO" => f & 0x04 == 0,
+        "PE" => f & 0x04 != 0,
+        "P" => f & 0x80 == 0,
+        _ => f & 0x80 != 0,
+    }
+}
+
+// Lists every register that changed, e.g. "A=0x41, SP=0xEFFE"
+fn describe_changes(before: &CpuState, after: &CpuState, m: u8, m_after: u8) -> String {
+    let mut changes = Vec::new();
+    for r in [Reg8::A, Reg8::B, Reg8::C, Reg8::D, Reg8::E, Reg8::H, Reg8::L] {
+        if reg(before, r) != reg(after, r) {
+            changes.push(format!("{:?}=0x{:02X}", r, reg(after, r)));
+        }
+    }
+    if before.sp != after.sp {
+        changes.push(format!("SP=0x{:04X}", after.sp));
+    }
+    if m != m_after && hl(before) == hl(after) {
+        changes.push(format!("M[0x{:04X}]=0x{:02X}", hl(after), m_after));
+    }
+    if before.inte != after.inte {
+        changes.push(format!("interrupts {}", if after.inte { "enabled" } else { "disabled" }));
+    }
+    if changes.is_empty() {
+        "no registers changed".to_string()
+    } else {
+        changes.join(", ")
+    }
+}
+
+fn reg(state: &CpuState, r: Reg8) -> u8 {
+    match r {
+        Reg8::A => state.a,
+        Reg8::B => state.b,
+        Reg8::C => state.c,
+        Reg8::D => state.d,
+        Reg8::E => state.e,
+        Reg8::H => state.h,
+        Reg8::L => state.l,
+    }
+}
+
+fn hl(state: &CpuState) -> u16 {
+    pair(state, Reg16::HL)
+}
+
+fn pair(state: &CpuState, rp: Reg16) -> u16 {
+    let join = |hi: u8, lo: u8| u16::from(hi) << 8 | u16::from(lo);
+    match rp {
+        Reg16::BC => join(state.b, state.c),
+        Reg16::DE => join(state.d, state.e),
+        Reg16::HL => join(state.h, state.l),
+        Reg16::SP => state.sp,
+        Reg16::PSW => join(state.a, state.f),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::explain::Explainer;
+    use crate::memory::Memory8080;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn explained(code: &[u8], steps: usize) -> Vec<String> {
+        let mut memory = [0x00; 0x10000];
+        memory[..code.len()].copy_from_slice(code);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        let explainer = Explainer::new();
+        (0..steps).map(|_| explainer.step(&mut cpu).1).collect()
+    }
+
+    #[test]
+    fn arithmetic() {
+        // MVI A, 0x3c; MVI B, 0x05; ADD B; CPI 0x41; INR B
+        let lines = explained(&[0x3e, 0x3c, 0x06, 0x05, 0x80, 0xfe, 0x41, 0x04], 5);
+        assert_eq!(lines, [
+            "MVI A, 0x3C: A=0x3C",
+            "MVI B, 0x05: B=0x05",
+            "ADD B: A(0x3C)+B(0x05)=0x41; flags: S=0 Z=0 A=1 P=1 C=0",
+            "CPI 0x41: A(0x41)-0x41=0x00, result discarded; flags: S=0 Z=1 A=1 P=1 C=0",
+            "INR B: B(0x05)+1=0x06; flags: S=0 Z=0 A=0 P=1 C=0",
+        ]);
+    }
+
+    #[test]
+    fn memory_and_stack() {
+        // LXI SP, 0x1000; LXI H, 0x2000; MVI M, 0x12; PUSH H; POP D; CMA
+        let lines = explained(&[0x31, 0x00, 0x10, 0x21, 0x00, 0x20, 0x36, 0x12, 0xe5, 0xd1, 0x2f], 6);
+        assert_eq!(lines, [
+            "LXI SP, 0x1000: SP=0x1000",
+            "LXI H, 0x2000: HL=0x2000",
+            "MVI M, 0x12: M[0x2000]=0x12",
+            "PUSH H: pushed HL(0x2000), SP=0x0FFE",
+            "POP D: popped DE=0x2000, SP=0x1000",
+            "CMA: A=0xFF",
+        ]);
+    }
+
+    #[test]
+    fn branches() {
+        // LXI SP, 0x1000; XRA A; JNZ 0x0000; CALL 0x0010; ... 0x0010: RET
+        let mut code = vec![0x31, 0x00, 0x10, 0xaf, 0xc2, 0x00, 0x00, 0xcd, 0x10, 0x00];
+        code.resize(0x10, 0x00);
+        code.push(0xc9);
+        let lines = explained(&code, 5);
+        assert_eq!(&lines[2..], [
+            "JNZ 0x0000: NZ is false, continuing",
+            "CALL 0x0010: PC=0x0010, return address 0x000A pushed",
+            "RET: PC=0x000A",
+        ]);
+    }
+}
@@ -0,0 +1,280 @@
+//! Fixed-capacity versions of the port bus and an instruction trace, for
+//! hosts with too little RAM to spare for growing collections. Nothing in
+//! here allocates: capacities are const generic parameters and devices
+//! are borrowed rather than reference-counted.
+//!
+//! ```
+//! use i8080_emulator::cpu::CPU;
+//! use i8080_emulator::devices::serial::Serial;
+//! use i8080_emulator::fixed::{StaticPortBus, TraceRing};
+//! use i8080_emulator::memory::Memory8080;
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! // MVI A, 'k'; OUT 0x11; HLT
+//! let mut memory = [0x00; 0x10000];
+//! memory[..5].copy_from_slice(&[0x3e, b'k', 0xd3, 0x11, 0x76]);
+//! let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+//! let serial = RefCell::new(Serial::new(0x10));
+//! let mut bus = StaticPortBus::<2>::new();
+//! bus.map(0x10, &serial);
+//! bus.map(0x11, &serial);
+//! let mut trace = TraceRing::<4>::new();
+//! while !cpu.is_halted() {
+//!     trace.record(&cpu);
+//!     bus.step(&mut cpu);
+//! }
+//! assert_eq!(serial.borrow_mut().take_output(), b"k");
+//! assert_eq!(trace.iter().map(|entry| entry.opcode).collect::<Vec<_>>(), [0x3e, 0xd3, 0x76]);
+//! ```
+
+use crate::cpu::{ClockCycles, CpuState, Event, CPU};
+use crate::device::Device;
+use crate::io::UNMAPPED_INPUT;
+use crate::memory::Memory;
+use crate::I8080Core;
+
+use core::cell::RefCell;
+
+// Marks a port with no device in `StaticPortBus`'s port tables
+const NONE: u8 = 0xff;
+
+/// A port bus for at most `N` devices, which the caller owns. The same
+/// device on several ports counts once. Unlike `io::PortBus`, devices are
+/// attached for both IN and OUT and the bus ticks and resets them.
+pub struct StaticPortBus<'a, const N: usize> {
+    devices: [Option<&'a RefCell<dyn Device + 'a>>; N],
+    // Per port, the index in `devices` of the device attached, or NONE
+    ports: [u8; 0x100],
+}
+
+impl<'a, const N: usize> Default for StaticPortBus<'a, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, const N: usize> StaticPortBus<'a, N> {
+    pub fn new() -> Self {
+        assert!(N < usize::from(NONE), "a StaticPortBus holds at most 254 devices");
+        StaticPortBus {
+            devices: [None; N],
+            ports: [NONE; 0x100],
+        }
+    }
+
+    /// Attaches `device` to `port` for IN and OUT.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `device` is new and `N` devices are attached already.
+    pub fn map(&mut self, port: u8, device: &'a RefCell<dyn Device + 'a>) {
+        let attached = self.devices.iter().position(|slot| match slot {
+            Some(other) => core::ptr::addr_eq(*other, device),
+            None => false,
+        });
+        let index = match attached {
+            Some(index) => index,
+            None => {
+                let free = self.devices.iter().position(Option::is_none).expect("StaticPortBus is full");
+                self.devices[free] = Some(device);
+                free
+            }
+        };
+        self.ports[usize::from(port)] = index as u8;
+    }
+
+    fn device(&self, port: u8) -> Option<&'a RefCell<dyn Device + 'a>> {
+        match self.ports[usize::from(port)] {
+            NONE => None,
+            index => self.devices[usize::from(index)],
+        }
+    }
+
+    /// Reads `port`, returning `UNMAPPED_INPUT` if nothing is attached.
+    pub fn read(&mut self, port: u8) -> u8 {
+        self.device(port).map_or(UNMAPPED_INPUT, |device| device.borrow_mut().read(port))
+    }
+
+    /// Writes `value` to `port`. Writes to unmapped ports are dropped.
+    pub fn write(&mut self, port: u8, value: u8) {
+        if let Some(device) = self.device(port) {
+            device.borrow_mut().write(port, value);
+        }
+    }
+
+    /// Executes one instruction on `cpu`, resolves its port access and
+    /// ticks every device by the cycles it took.
+    pub fn step(&mut self, cpu: &mut CPU) -> Event {
+        let op = cpu.fetch();
+        let event = cpu.exec(op);
+        match event {
+            Event::Input(port, _) => {
+                let value = self.read(port);
+                cpu.complete_input(value);
+            }
+            Event::Output(port, value, _) => self.write(port, value),
+            _ => {}
+        }
+        self.tick(event.cycles());
+        event
+    }
+
+    pub fn tick(&mut self, cycles: ClockCycles) {
+        for device in self.devices.iter().flatten() {
+            device.borrow_mut().tick(cycles);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for device in self.devices.iter().flatten() {
+            device.borrow_mut().reset();
+        }
+    }
+}
+
+/// One instruction in a `TraceRing`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub opcode: u8,
+    /// Registers and flags before the instruction ran.
+    pub state: CpuState,
+}
+
+/// The last `N` instructions executed, for a post-mortem after a crash.
+/// Recording one overwrites the oldest once the ring is full.
+pub struct TraceRing<const N: usize> {
+    entries: [TraceEntry; N],
+    // Where the next entry goes
+    next: usize,
+    len: usize,
+}
+
+impl<const N: usize> Default for TraceRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> TraceRing<N> {
+    pub fn new() -> Self {
+        let empty = TraceEntry { pc: 0, opcode: 0, state: CpuState::default() };
+        TraceRing {
+            entries: [empty; N],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Records the instruction `cpu` is about to run. Call it between
+    /// instructions.
+    pub fn record(&mut self, cpu: &CPU) {
+        let opcode = cpu.memory.borrow().peek(usize::from(cpu.pc));
+        self.push(TraceEntry { pc: cpu.pc, opcode, state: cpu.state() });
+    }
+
+    pub fn push(&mut self, entry: TraceEntry) {
+        if N == 0 {
+            return;
+        }
+        self.entries[self.next] = entry;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// The entries recorded, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &TraceEntry> {
+        let start = (self.next + N - self.len) % N.max(1);
+        (0..self.len).map(move |i| &self.entries[(start + i) % N])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CpuState;
+    #[cfg(feature = "std")]
+    use crate::cpu::CPU;
+    #[cfg(feature = "std")]
+    use crate::devices::{serial::Serial, watchdog::Watchdog};
+    #[cfg(feature = "std")]
+    use crate::fixed::StaticPortBus;
+    use crate::fixed::{TraceEntry, TraceRing};
+    #[cfg(feature = "std")]
+    use crate::memory::Memory8080;
+
+    #[cfg(feature = "std")]
+    use std::cell::RefCell;
+    #[cfg(feature = "std")]
+    use std::rc::Rc;
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn bus_ports_and_ticks() {
+        let serial = RefCell::new(Serial::new(0x10));
+        let watchdog = RefCell::new(Watchdog::new(0x20, 10));
+        let mut bus = StaticPortBus::<2>::new();
+        bus.map(0x10, &serial);
+        bus.map(0x11, &serial);
+        bus.map(0x20, &watchdog);
+        serial.borrow_mut().send(b"x");
+        assert_eq!(bus.read(0x11), b'x');
+        assert_eq!(bus.read(0x30), 0xff);
+        bus.write(0x11, b'y');
+        assert_eq!(serial.borrow_mut().take_output(), b"y");
+        bus.tick(10);
+        assert!(watchdog.borrow_mut().take_expired());
+
+        // IN 0x10
+        let mut memory = [0x00; 0x10000];
+        memory[..2].copy_from_slice(&[0xdb, 0x10]);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        bus.step(&mut cpu);
+        assert_eq!(cpu.regs.a, 0x02);
+        assert!(watchdog.borrow_mut().take_expired());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    #[should_panic(expected = "StaticPortBus is full")]
+    fn bus_capacity() {
+        let a = RefCell::new(Serial::new(0x10));
+        let b = RefCell::new(Serial::new(0x20));
+        let mut bus = StaticPortBus::<1>::new();
+        bus.map(0x10, &a);
+        bus.map(0x11, &a);
+        bus.map(0x20, &b);
+    }
+
+    #[test]
+    fn ring_keeps_the_newest() {
+        let entry = |pc| TraceEntry { pc, opcode: 0, state: CpuState::default() };
+        let mut ring = TraceRing::<3>::new();
+        assert!(ring.is_empty());
+        ring.push(entry(1));
+        ring.push(entry(2));
+        assert_eq!(ring.iter().map(|e| e.pc).collect::<Vec<_>>(), [1, 2]);
+        ring.push(entry(3));
+        ring.push(entry(4));
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.iter().map(|e| e.pc).collect::<Vec<_>>(), [2, 3, 4]);
+        ring.clear();
+        assert_eq!(ring.iter().count(), 0);
+
+        let mut none = TraceRing::<0>::new();
+        none.push(entry(1));
+        assert_eq!(none.iter().count(), 0);
+    }
+}
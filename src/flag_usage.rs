@@ -0,0 +1,153 @@
+//! Finding flag results that nothing ever looks at.
+//!
+//! `FlagUsage` is fed every executed instruction and tracks, for each flag
+//! an instruction sets, whether a later instruction reads it before it is
+//! overwritten. Flags that are always overwritten unread are dead
+//! computations: harmless on hardware, but worth knowing about when
+//! optimizing guest code, and a quick way to spot an emulator bug that
+//! makes a branch read the wrong flag.
+
+use crate::opcodes::OPCODES;
+
+use std::collections::HashMap;
+
+/// Flags set or cleared by `op`, as a mask in flag register layout.
+pub fn flags_written(op: u8) -> u8 {
+    OPCODES[usize::from(op)].flags_written
+}
+
+/// Flags whose value `op` depends on, as a mask in flag register layout.
+pub fn flags_read(op: u8) -> u8 {
+    OPCODES[usize::from(op)].flags_read
+}
+
+/// How the flags written by one instruction were used.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlagStats {
+    pub executions: u64,
+    /// Per flag bit, how often a value this instruction set was read.
+    pub consumed: [u64; 8],
+    /// Per flag bit, how often a value this instruction set was
+    /// overwritten without being read.
+    pub overwritten: [u64; 8],
+}
+
+impl FlagStats {
+    /// Flags this instruction set that were overwritten at least once and
+    /// never read.
+    pub fn dead_flags(&self) -> u8 {
+        (0..8)
+            .filter(|&bit| self.overwritten[bit] > 0 && self.consumed[bit] == 0)
+            .fold(0, |mask, bit| mask | 1 << bit)
+    }
+}
+
+/// Collects `FlagStats` per instruction address while a program runs.
+#[derive(Default)]
+pub struct FlagUsage {
+    // Per flag bit, the instruction that set its current value and
+    // whether that value has been read yet
+    writer: [Option<(u16, bool)>; 8],
+    stats: HashMap<u16, FlagStats>,
+}
+
+impl FlagUsage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the execution of `op` at `pc`. Call it once per instruction,
+    /// in execution order.
+    pub fn observe(&mut self, pc: u16, op: u8) {
+        let read = flags_read(op);
+        let written = flags_written(op);
+        for bit in 0..8 {
+            let mask = 1 << bit;
+            if read & mask != 0 {
+                if let Some((writer, consumed)) = self.writer[bit].as_mut() {
+                    if !*consumed {
+                        *consumed = true;
+                        self.stats.entry(*writer).or_default().consumed[bit] += 1;
+                    }
+                }
+            }
+            if written & mask != 0 {
+                if let Some((writer, false)) = self.writer[bit] {
+                    self.stats.entry(writer).or_default().overwritten[bit] += 1;
+                }
+                self.writer[bit] = Some((pc, false));
+            }
+        }
+        self.stats.entry(pc).or_default().executions += 1;
+    }
+
+    pub fn stats(&self, pc: u16) -> Option<&FlagStats> {
+        self.stats.get(&pc)
+    }
+
+    /// Addresses of instructions with dead flags, with the dead flags as a
+    /// mask, in address order.
+    pub fn dead_flags(&self) -> Vec<(u16, u8)> {
+        let mut dead: Vec<_> = self.stats.iter()
+            .map(|(pc, stats)| (*pc, stats.dead_flags()))
+            .filter(|(_, mask)| *mask != 0)
+            .collect();
+        dead.sort_unstable();
+        dead
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::flag_usage::{flags_read, flags_written, FlagUsage};
+    use crate::memory::Memory8080;
+    use crate::opcodes::OPCODES;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn tables() {
+        assert_eq!(flags_written(0x04), 0xd4); // INR B
+        assert_eq!(flags_written(0x39), 0x01); // DAD SP
+        assert_eq!(flags_written(0x76), 0x00); // HLT
+        assert_eq!(flags_read(0xc2), 0x40);    // JNZ
+        assert_eq!(flags_read(0xfc), 0x80);    // CM
+        assert_eq!(flags_read(0xe8), 0x04);    // RPE
+        assert_eq!(flags_read(0xc6), 0x00);    // ADI
+        // Data moves neither read nor write flags
+        for op in 0..=0xffu8 {
+            let mnemonic = OPCODES[op as usize].mnemonic;
+            if mnemonic.starts_with("MOV") || mnemonic.starts_with("MVI") || mnemonic.starts_with("LXI") {
+                assert_eq!(flags_read(op) | flags_written(op), 0, "{}", mnemonic);
+            }
+        }
+    }
+
+    #[test]
+    fn finds_dead_flags() {
+        // loop: DCR B; ADD C; DCR C; JNZ loop; HLT
+        let mut memory = [0x00; 0x10000];
+        memory[0x100..0x107].copy_from_slice(&[0x05, 0x81, 0x0d, 0xc2, 0x00, 0x01, 0x76]);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        cpu.pc = 0x100;
+        cpu.regs.c = 3;
+        let mut usage = FlagUsage::new();
+        loop {
+            let pc = cpu.pc;
+            let op = cpu.fetch();
+            usage.observe(pc, op);
+            if op == 0x76 {
+                break;
+            }
+            cpu.exec(op);
+        }
+        // Only DCR C's zero flag is ever looked at, by JNZ. Everything else
+        // is overwritten unread, either by DCR C or on the next iteration.
+        assert_eq!(usage.dead_flags(), [(0x100, 0xd4), (0x101, 0xd5), (0x102, 0x94)]);
+        let dcr_c = usage.stats(0x102).unwrap();
+        assert_eq!(dcr_c.executions, 3);
+        assert_eq!(dcr_c.consumed[6], 3);
+    }
+}
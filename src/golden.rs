@@ -0,0 +1,460 @@
+//! Golden-image tests of what a machine puts on screen: run it for a
+//! number of frames with no front-end, decode its framebuffer and compare
+//! the picture with one stored from a run known to be good. A change to
+//! the CPU, the scheduler or the video decoding that only shows as a
+//! wrong picture fails the test.
+//!
+//! ```no_run
+//! use i8080_emulator::golden::{assert_golden, capture, Tolerance};
+//! use i8080_emulator::machines::composite::{MachineBuilder, Rst};
+//! use i8080_emulator::memory::Memory8080;
+//! use i8080_emulator::video::Framebuffer;
+//!
+//! let mut machine = MachineBuilder::new()
+//!     .memory(Memory8080::new(i8080_emulator::loader::load_image("invaders.rom", 0).unwrap()))
+//!     .interrupt_every(16_667, Rst(2))
+//!     .build();
+//! let picture = capture(&mut machine, &Framebuffer::space_invaders(), 120, 33_333).unwrap();
+//! assert_golden("tests/golden/attract.png", &picture, Tolerance::EXACT);
+//! ```
+//!
+//! Golden images are PNG files, or raw RGBA bytes for any other
+//! extension. The PNGs are the ones `Image::write_png` writes: 8-bit
+//! RGBA, not interlaced, with the image data stored rather than
+//! compressed, which keeps them simple enough to read without a
+//! decompressor. Setting `UPDATE_VAR` writes the pictures as the new
+//! golden images instead of checking them.
+
+use crate::cpu::Timestamp;
+use crate::error::EmulatorError;
+use crate::inflate::adler32;
+use crate::machines::composite::CompositeMachine;
+use crate::memory::Memory;
+use crate::video::Framebuffer;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// The environment variable that makes `assert_golden` write golden
+/// images rather than compare with them, when set to anything but 0.
+pub const UPDATE_VAR: &str = "I8080_UPDATE_GOLDEN";
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+// The most a stored deflate block holds
+const STORED_BLOCK: usize = 0xffff;
+
+/// A picture as `Framebuffer::render` gives it: RGBA pixels packed as
+/// 0xRRGGBBAA, row by row from the top left.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u32>,
+}
+
+/// How far a picture may be from its golden image and still match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Tolerance {
+    /// How far each of red, green, blue and alpha may be off.
+    pub channel: u8,
+    /// How many pixels may be off by more than `channel`.
+    pub pixels: usize,
+}
+
+impl Tolerance {
+    pub const EXACT: Tolerance = Tolerance { channel: 0, pixels: 0 };
+}
+
+/// How a picture failed to match its golden image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The pictures are different sizes, given as width and height.
+    Size { expected: (usize, usize), actual: (usize, usize) },
+    /// `count` pixels are off by more than the tolerance, the first at
+    /// `x` and `y`.
+    Pixels { count: usize, x: usize, y: usize, expected: u32, actual: u32 },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Size { expected, actual } => {
+                write!(f, "picture is {}x{}, expected {}x{}", actual.0, actual.1, expected.0, expected.1)
+            }
+            Mismatch::Pixels { count, x, y, expected, actual } => write!(
+                f,
+                "{} pixels differ, the first at ({}, {}): {:08x}, expected {:08x}",
+                count, x, y, actual, expected
+            ),
+        }
+    }
+}
+
+/// Runs `machine` for `frames` frames of `quota` clock cycles each, as
+/// `CompositeMachine::run_frame` does, and returns the picture in its
+/// memory at the end.
+pub fn capture(
+    machine: &mut CompositeMachine,
+    framebuffer: &Framebuffer,
+    frames: usize,
+    quota: Timestamp,
+) -> Result<Image, EmulatorError> {
+    for _ in 0..frames {
+        machine.run_frame(quota)?;
+    }
+    let memory = machine.cpu.memory.borrow();
+    Ok(Image::render(framebuffer, &*memory))
+}
+
+/// Compares `actual` with the golden image at `path`, or with `UPDATE_VAR`
+/// set writes it there.
+///
+/// # Panics
+///
+/// Panics if the pictures do not match within `tolerance`, saving
+/// `actual` next to the golden image with `actual` added to its
+/// extension for a look, or if the golden image cannot be read.
+pub fn assert_golden(path: impl AsRef<Path>, actual: &Image, tolerance: Tolerance) {
+    let path = path.as_ref();
+    if std::env::var_os(UPDATE_VAR).is_some_and(|value| value != "0") {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).unwrap_or_else(|e| panic!("could not create {}: {}", dir.display(), e));
+        }
+        actual.save(path).unwrap_or_else(|e| panic!("could not write {}: {}", path.display(), e));
+        return;
+    }
+    let golden = Image::open(path, actual.width, actual.height).unwrap_or_else(|e| {
+        panic!("could not read golden image {}: {} (set {} to write it)", path.display(), e, UPDATE_VAR)
+    });
+    if let Err(mismatch) = actual.compare(&golden, tolerance) {
+        let extension = path.extension().map_or("actual".into(), |ext| format!("actual.{}", ext.to_string_lossy()));
+        let saved = path.with_extension(extension);
+        let _ = actual.save(&saved);
+        panic!("{} does not match: {}; the picture is in {}", path.display(), mismatch, saved.display());
+    }
+}
+
+impl Image {
+    /// The picture `framebuffer` shows from `memory`.
+    pub fn render(framebuffer: &Framebuffer, memory: &impl Memory) -> Self {
+        let (width, height) = framebuffer.size();
+        Image { width, height, pixels: framebuffer.render(memory) }
+    }
+
+    /// Matches if the pictures are the same size and at most
+    /// `tolerance.pixels` pixels are off by more than `tolerance.channel`.
+    pub fn compare(&self, golden: &Image, tolerance: Tolerance) -> Result<(), Mismatch> {
+        if (self.width, self.height) != (golden.width, golden.height) {
+            return Err(Mismatch::Size { expected: (golden.width, golden.height), actual: (self.width, self.height) });
+        }
+        let off = |(&actual, &expected): (&u32, &u32)| {
+            let (actual, expected) = (actual.to_be_bytes(), expected.to_be_bytes());
+            actual.iter().zip(&expected).any(|(a, e)| a.abs_diff(*e) > tolerance.channel)
+        };
+        let mut differing = self.pixels.iter().zip(&golden.pixels).enumerate().filter(|(_, pair)| off(*pair));
+        let (first, (&actual, &expected)) = match differing.next() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        let count = 1 + differing.count();
+        if count <= tolerance.pixels {
+            return Ok(());
+        }
+        Err(Mismatch::Pixels { count, x: first % self.width, y: first / self.width, expected, actual })
+    }
+
+    /// Writes the picture as a PNG file or, for any other extension, raw
+    /// RGBA bytes.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut file = BufWriter::new(File::create(path)?);
+        match is_png(path) {
+            true => self.write_png(&mut file)?,
+            false => self.write_raw(&mut file)?,
+        }
+        file.flush()
+    }
+
+    /// Reads a picture `save` wrote. Raw files do not say how big the
+    /// picture is, so they are taken to be `width` by `height`.
+    pub fn open(path: impl AsRef<Path>, width: usize, height: usize) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = BufReader::new(File::open(path)?);
+        match is_png(path) {
+            true => Image::read_png(file),
+            false => Image::read_raw(file, width, height),
+        }
+    }
+
+    /// Four bytes per pixel, red first.
+    pub fn write_raw(&self, mut output: impl Write) -> io::Result<()> {
+        for pixel in &self.pixels {
+            output.write_all(&pixel.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_raw(mut input: impl Read, width: usize, height: usize) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+        if bytes.len() != width * height * 4 {
+            return Err(invalid(format!("raw image is {} bytes, expected {}", bytes.len(), width * height * 4)));
+        }
+        let pixels = bytes.chunks(4).map(|rgba| u32::from_be_bytes([rgba[0], rgba[1], rgba[2], rgba[3]])).collect();
+        Ok(Image { width, height, pixels })
+    }
+
+    /// Writes an 8-bit RGBA PNG with the image data stored uncompressed.
+    pub fn write_png(&self, mut output: impl Write) -> io::Result<()> {
+        let size = |n: usize| u32::try_from(n).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "image too large"));
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&size(self.width)?.to_be_bytes());
+        header.extend_from_slice(&size(self.height)?.to_be_bytes());
+        // 8 bits per channel of RGBA, deflate, per-row filtering and no
+        // interlacing
+        header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+        // Each row is filter type 0, none, then its pixels
+        let mut rows = Vec::with_capacity(self.height * (1 + self.width * 4));
+        for row in self.pixels.chunks(self.width.max(1)) {
+            rows.push(0);
+            for pixel in row {
+                rows.extend_from_slice(&pixel.to_be_bytes());
+            }
+        }
+        // A zlib stream of stored deflate blocks
+        let mut data = vec![0x78, 0x01];
+        let mut blocks = rows.chunks(STORED_BLOCK).peekable();
+        if blocks.peek().is_none() {
+            data.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+        }
+        while let Some(block) = blocks.next() {
+            let len = block.len() as u16;
+            data.push(u8::from(blocks.peek().is_none()));
+            data.extend_from_slice(&len.to_le_bytes());
+            data.extend_from_slice(&(!len).to_le_bytes());
+            data.extend_from_slice(block);
+        }
+        data.extend_from_slice(&adler32(&rows).to_be_bytes());
+
+        output.write_all(&PNG_SIGNATURE)?;
+        write_chunk(&mut output, b"IHDR", &header)?;
+        write_chunk(&mut output, b"IDAT", &data)?;
+        write_chunk(&mut output, b"IEND", &[])
+    }
+
+    /// Reads a PNG of the kind `write_png` writes. Anything else,
+    /// compressed image data in particular, is an `InvalidData` error.
+    pub fn read_png(mut input: impl Read) -> io::Result<Self> {
+        let mut signature = [0x00; 8];
+        input.read_exact(&mut signature)?;
+        if signature != PNG_SIGNATURE {
+            return Err(invalid("not a PNG file"));
+        }
+        let mut header = None;
+        let mut data = Vec::new();
+        loop {
+            let (kind, chunk) = read_chunk(&mut input)?;
+            match &kind {
+                b"IHDR" => header = Some(chunk),
+                b"IDAT" => data.extend_from_slice(&chunk),
+                b"IEND" => break,
+                // Ancillary chunks have a lowercase first letter
+                _ if kind[0].is_ascii_lowercase() => {}
+                _ => return Err(invalid(format!("unsupported PNG chunk {}", String::from_utf8_lossy(&kind)))),
+            }
+        }
+        let header = header.ok_or_else(|| invalid("PNG has no header"))?;
+        if header.len() != 13 || header[8..] != [8, 6, 0, 0, 0] {
+            return Err(invalid("only 8-bit RGBA PNGs without interlacing are supported"));
+        }
+        let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+
+        let rows = inflate_stored(&data)?;
+        let stride = 1 + width * 4;
+        if rows.len() != height * stride {
+            return Err(invalid("PNG image data is the wrong size"));
+        }
+        let mut pixels = Vec::with_capacity(width * height);
+        for row in rows.chunks(stride.max(1)).take(height) {
+            if row[0] != 0 {
+                return Err(invalid("only PNGs without filtering are supported"));
+            }
+            pixels.extend(row[1..].chunks(4).map(|rgba| u32::from_be_bytes([rgba[0], rgba[1], rgba[2], rgba[3]])));
+        }
+        Ok(Image { width, height, pixels })
+    }
+}
+
+fn is_png(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn write_chunk(output: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    output.write_all(&(data.len() as u32).to_be_bytes())?;
+    output.write_all(kind)?;
+    output.write_all(data)?;
+    output.write_all(&crc32(&[kind, data]).to_be_bytes())
+}
+
+fn read_chunk(input: &mut impl Read) -> io::Result<([u8; 4], Vec<u8>)> {
+    let mut word = [0x00; 4];
+    input.read_exact(&mut word)?;
+    let mut kind = [0x00; 4];
+    input.read_exact(&mut kind)?;
+    let mut data = vec![0x00; u32::from_be_bytes(word) as usize];
+    input.read_exact(&mut data)?;
+    input.read_exact(&mut word)?;
+    if u32::from_be_bytes(word) != crc32(&[&kind, &data]) {
+        return Err(invalid("PNG chunk fails its CRC"));
+    }
+    Ok((kind, data))
+}
+
+// The data of a zlib stream made only of stored deflate blocks
+fn inflate_stored(stream: &[u8]) -> io::Result<Vec<u8>> {
+    let truncated = || invalid("PNG image data ends early");
+    if stream.len() < 6 || stream[0] & 0x0f != 8 || u16::from_be_bytes([stream[0], stream[1]]) % 31 != 0 {
+        return Err(invalid("PNG image data is not a zlib stream"));
+    }
+    let mut rest = &stream[2..];
+    let mut data = Vec::new();
+    loop {
+        let (&flags, after) = rest.split_first().ok_or_else(truncated)?;
+        if flags & 0x06 != 0 {
+            return Err(invalid("compressed PNGs are not supported, only ones written by write_png"));
+        }
+        let len = match after {
+            [lo, hi, ..] => usize::from(u16::from_le_bytes([*lo, *hi])),
+            _ => return Err(truncated()),
+        };
+        let block = after.get(4..4 + len).ok_or_else(truncated)?;
+        data.extend_from_slice(block);
+        rest = &after[4 + len..];
+        if flags & 0x01 != 0 {
+            break;
+        }
+    }
+    match rest.get(..4) {
+        Some(adler) if u32::from_be_bytes([adler[0], adler[1], adler[2], adler[3]]) == adler32(&data) => Ok(data),
+        _ => Err(invalid("PNG image data fails its checksum")),
+    }
+}
+
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::golden::{assert_golden, capture, crc32, Image, Mismatch, Tolerance};
+    use crate::machines::composite::{CompositeMachine, MachineBuilder, Rst};
+    use crate::memory::Memory8080;
+    use crate::video::{Framebuffer, BLACK, WHITE};
+
+    // Counts frames into the first byte of video RAM at 0x2400
+    fn counting_machine() -> CompositeMachine {
+        // 0x0000: LXI SP, 0x2000; EI; loop: HLT; JMP loop
+        // 0x0008 (RST 1): LDA 0x2400; INR A; STA 0x2400; EI; RET
+        let mut image = [0x00; 0x10000];
+        image[..8].copy_from_slice(&[0x31, 0x00, 0x20, 0xfb, 0x76, 0xc3, 0x04, 0x00]);
+        image[0x08..0x11].copy_from_slice(&[0x3a, 0x00, 0x24, 0x3c, 0x32, 0x00, 0x24, 0xfb, 0xc9]);
+        MachineBuilder::new().memory(Memory8080::new(image)).interrupt_every(1000, Rst(1)).build()
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("i8080_golden_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn frames_on_screen() {
+        let picture = capture(&mut counting_machine(), &Framebuffer::new(0x2400, 8, 1), 5, 1000).unwrap();
+        // The first interrupt comes a frame in, so four in five frames
+        assert_eq!(picture, Image { width: 8, height: 1, pixels: vec![BLACK, BLACK, WHITE, BLACK, BLACK, BLACK, BLACK, BLACK] });
+
+        for name in &["frames.png", "frames.raw"] {
+            let path = temp_path(name);
+            picture.save(&path).unwrap();
+            assert_golden(&path, &picture, Tolerance::EXACT);
+            assert_eq!(Image::open(&path, 8, 1).unwrap(), picture);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn tolerance() {
+        let golden = Image { width: 2, height: 2, pixels: vec![0x1020_30ff, 0x0000_00ff, 0xffff_ffff, 0x8080_80ff] };
+        let mut actual = golden.clone();
+        actual.pixels[0] = 0x1222_2eff;
+        actual.pixels[3] = 0x0000_00ff;
+        assert!(actual.compare(&golden, Tolerance { channel: 2, pixels: 1 }).is_ok());
+        assert_eq!(actual.compare(&golden, Tolerance { channel: 1, pixels: 1 }), Err(Mismatch::Pixels {
+            count: 2,
+            x: 0,
+            y: 0,
+            expected: 0x1020_30ff,
+            actual: 0x1222_2eff,
+        }));
+        let wide = Image { width: 4, height: 1, pixels: golden.pixels.clone() };
+        assert_eq!(wide.compare(&golden, Tolerance::EXACT), Err(Mismatch::Size { expected: (2, 2), actual: (4, 1) }));
+    }
+
+    #[test]
+    #[should_panic(expected = "1 pixels differ, the first at (1, 0): ffffffff, expected 000000ff")]
+    fn golden_mismatch() {
+        let path = temp_path("mismatch.png");
+        let golden = Image { width: 2, height: 1, pixels: vec![BLACK, BLACK] };
+        golden.save(&path).unwrap();
+        let result = std::panic::catch_unwind(|| {
+            assert_golden(&path, &Image { width: 2, height: 1, pixels: vec![BLACK, WHITE] }, Tolerance::EXACT)
+        });
+        let actual = path.with_extension("actual.png");
+        assert_eq!(Image::open(&actual, 0, 0).unwrap().pixels, [BLACK, WHITE]);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(actual).unwrap();
+        std::panic::resume_unwind(result.unwrap_err());
+    }
+
+    #[test]
+    fn png_files() {
+        // Big enough to need more than one stored block
+        let pixels = (0..200 * 100).map(|i| (i as u32).wrapping_mul(0x9e37_79b9) | 0xff).collect();
+        let picture = Image { width: 200, height: 100, pixels };
+        let mut png = Vec::new();
+        picture.write_png(&mut png).unwrap();
+        assert_eq!(png[..8], [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]);
+        assert_eq!(Image::read_png(&png[..]).unwrap(), picture);
+
+        let empty = Image { width: 0, height: 0, pixels: Vec::new() };
+        let mut png = Vec::new();
+        empty.write_png(&mut png).unwrap();
+        assert_eq!(Image::read_png(&png[..]).unwrap(), empty);
+
+        // The CRC of IEND, as every PNG ends
+        assert_eq!(crc32(&[b"IEND"]), 0xae42_6082);
+        let mut broken = png.clone();
+        broken[20] ^= 0x01;
+        assert!(Image::read_png(&broken[..]).is_err());
+        // A header changed to 8-bit RGB, with its CRC fixed up
+        let mut rgb = png;
+        rgb[25] = 2;
+        let crc = crc32(&[&rgb[12..29]]).to_be_bytes();
+        rgb[29..33].copy_from_slice(&crc);
+        assert_eq!(Image::read_png(&rgb[..]).unwrap_err().to_string(), "only 8-bit RGBA PNGs without interlacing are supported");
+    }
+}
@@ -0,0 +1,339 @@
+//! Running a machine on a thread of its own, for front-ends whose UI runs
+//! on another.
+//!
+//! Machines are built from `Rc<RefCell<…>>` parts and cannot cross
+//! threads, so `MachineHandle::spawn` takes a function that builds the
+//! machine on the worker thread. The handle then sends it commands over
+//! a channel: pause and resume it, step it, save and load its state or
+//! look at its memory. A paused machine waits for commands; a running one
+//! checks for them every `SLICE` steps, and pauses by itself once it is
+//! finished or reports an error. The machine's clock only runs while it
+//! is running: timers and periodic interrupts stand still while it is
+//! paused or being stepped. Port writes and the machine stopping are
+//! passed on to whoever subscribed.
+//!
+//! ```
+//! use i8080_emulator::bare::BareMachine;
+//! use i8080_emulator::handle::{MachineHandle, Notification};
+//!
+//! // MVI A, 'A'; OUT 0; loop: JMP loop
+//! let handle = MachineHandle::spawn(|| BareMachine::new(&[0x3e, 0x41, 0xd3, 0x00, 0xc3, 0x04, 0x00]));
+//! let notifications = handle.subscribe();
+//! handle.step(2);
+//! assert_eq!(notifications.recv().unwrap(), Notification::Output { port: 0x00, value: 0x41, cycle: 17 });
+//! assert_eq!(handle.state().unwrap().pc, 0x0004);
+//! assert_eq!(handle.peek(0x0000..=0x0001).unwrap(), [0x3e, 0x41]);
+//! ```
+
+use crate::clock::{Speed, Throttle};
+use crate::cpu::{CpuState, Event, SaveState, Timestamp};
+use crate::error::EmulatorError;
+use crate::memory::Memory;
+use crate::repl::Inspect;
+use crate::I8080Core;
+
+use std::ops::RangeInclusive;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+/// Steps a running machine takes between looking for commands.
+pub const SLICE: usize = 1000;
+
+/// What the worker passes on to subscribers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Notification {
+    /// An OUT instruction wrote `value` to `port`, with the machine's
+    /// cycle count afterwards.
+    Output { port: u8, value: u8, cycle: Timestamp },
+    /// The machine got to where `Machine::run` would have stopped and has
+    /// been paused.
+    Finished,
+    /// The machine reported an error and has been paused.
+    Stopped(EmulatorError),
+}
+
+enum Command {
+    Pause,
+    Resume,
+    Step(u64),
+    Reset,
+    SetSpeed(Speed),
+    State(Sender<CpuState>),
+    SaveState(Sender<Result<SaveState, EmulatorError>>),
+    LoadState(SaveState),
+    Peek(RangeInclusive<u16>, Sender<Vec<u8>>),
+    Subscribe(Sender<Notification>),
+}
+
+/// A machine running on a worker thread. See the module documentation.
+/// Dropping the handle stops the worker and waits for it.
+pub struct MachineHandle {
+    commands: Option<Sender<Command>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl MachineHandle {
+    /// Starts a worker thread, builds the machine on it with `build` and
+    /// leaves it paused.
+    pub fn spawn<T: Inspect<Event = Event>>(build: impl FnOnce() -> T + Send + 'static) -> Self {
+        let (commands, rx) = mpsc::channel();
+        let worker = thread::spawn(move || Worker::new(build(), rx).run());
+        MachineHandle { commands: Some(commands), worker: Some(worker) }
+    }
+
+    fn send(&self, command: Command) {
+        if let Some(commands) = &self.commands {
+            // A worker that has gone away has nothing to be told
+            let _ = commands.send(command);
+        }
+    }
+
+    // Sends a command carrying a reply channel and waits for the reply,
+    // `None` if the worker has gone away
+    fn ask<R>(&self, command: impl FnOnce(Sender<R>) -> Command) -> Option<R> {
+        let (tx, rx) = mpsc::channel();
+        self.send(command(tx));
+        rx.recv().ok()
+    }
+
+    pub fn pause(&self) {
+        self.send(Command::Pause);
+    }
+
+    /// Lets the machine run freely.
+    pub fn resume(&self) {
+        self.send(Command::Resume);
+    }
+
+    /// Runs `steps` steps, then pauses.
+    pub fn step(&self, steps: u64) {
+        self.send(Command::Step(steps));
+    }
+
+    /// Resets the machine with `Machine::reset`.
+    pub fn reset(&self) {
+        self.send(Command::Reset);
+    }
+
+    /// How fast the machine runs while resumed. Defaults to
+    /// `Speed::Unlimited`.
+    pub fn set_speed(&self, speed: Speed) {
+        self.send(Command::SetSpeed(speed));
+    }
+
+    /// The CPU's registers, once the commands sent before have been
+    /// carried out.
+    pub fn state(&self) -> Option<CpuState> {
+        self.ask(Command::State)
+    }
+
+    pub fn save_state(&self) -> Option<Result<SaveState, EmulatorError>> {
+        self.ask(Command::SaveState)
+    }
+
+    pub fn load_state(&self, state: SaveState) {
+        self.send(Command::LoadState(state));
+    }
+
+    /// A copy of the bytes in `range`, read without side effects.
+    pub fn peek(&self, range: RangeInclusive<u16>) -> Option<Vec<u8>> {
+        self.ask(|reply| Command::Peek(range, reply))
+    }
+
+    /// A channel receiving every `Notification` from now on.
+    pub fn subscribe(&self) -> Receiver<Notification> {
+        let (tx, rx) = mpsc::channel();
+        self.send(Command::Subscribe(tx));
+        rx
+    }
+}
+
+impl Drop for MachineHandle {
+    fn drop(&mut self) {
+        // Closing the channel tells the worker to finish
+        self.commands = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+// The machine's end of a handle
+struct Worker<T> {
+    machine: T,
+    commands: Receiver<Command>,
+    running: bool,
+    // Steps left of a `step` command
+    steps: u64,
+    throttle: Throttle,
+    subscribers: Vec<Sender<Notification>>,
+}
+
+impl<T: Inspect<Event = Event>> Worker<T> {
+    fn new(mut machine: T, commands: Receiver<Command>) -> Self {
+        machine.set_clock_paused(true);
+        Worker {
+            machine,
+            commands,
+            running: false,
+            steps: 0,
+            throttle: Throttle::with_speed(Speed::Unlimited),
+            subscribers: Vec::new(),
+        }
+    }
+
+    fn run(mut self) {
+        loop {
+            let busy = self.running || self.steps > 0;
+            let command = match busy {
+                true => match self.commands.try_recv() {
+                    Ok(command) => Some(command),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
+                },
+                false => match self.commands.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => return,
+                },
+            };
+            match command {
+                Some(command) => self.execute(command),
+                None => self.run_slice(),
+            }
+        }
+    }
+
+    fn execute(&mut self, command: Command) {
+        match command {
+            Command::Pause => {
+                self.set_running(false);
+                self.steps = 0;
+            }
+            Command::Resume => self.set_running(true),
+            Command::Step(steps) => {
+                self.set_running(false);
+                self.steps = steps;
+                // Carried out before any later command
+                while self.steps > 0 {
+                    self.run_slice();
+                }
+            }
+            Command::Reset => self.machine.reset(),
+            Command::SetSpeed(speed) => self.throttle.set_speed(speed),
+            Command::State(reply) => {
+                let _ = reply.send(self.machine.cpu().state());
+            }
+            Command::SaveState(reply) => {
+                let _ = reply.send(self.machine.cpu().save_state());
+            }
+            Command::LoadState(state) => self.machine.cpu_mut().load_state(&state),
+            Command::Peek(range, reply) => {
+                let memory = self.machine.cpu().memory.borrow();
+                let _ = reply.send(range.map(|addr| memory.peek(usize::from(addr))).collect());
+            }
+            Command::Subscribe(subscriber) => self.subscribers.push(subscriber),
+        }
+    }
+
+    // Steps taken while paused leave the machine's clock standing
+    fn set_running(&mut self, running: bool) {
+        self.running = running;
+        self.machine.set_clock_paused(!running);
+    }
+
+    // Runs up to `SLICE` steps, fewer when stepping
+    fn run_slice(&mut self) {
+        for _ in 0..SLICE {
+            if !self.running {
+                if self.steps == 0 {
+                    return;
+                }
+                self.steps -= 1;
+            }
+            match self.machine.next() {
+                Ok(event) => {
+                    if let Event::Output(port, value, _) = event {
+                        let cycle = self.machine.cycles();
+                        self.notify(Notification::Output { port, value, cycle });
+                    }
+                    if !self.running {
+                        continue;
+                    }
+                    self.throttle.record(&event);
+                    if self.machine.is_finished() {
+                        self.set_running(false);
+                        self.notify(Notification::Finished);
+                        return;
+                    }
+                }
+                Err(error) => {
+                    self.set_running(false);
+                    self.steps = 0;
+                    self.notify(Notification::Stopped(error));
+                    return;
+                }
+            }
+        }
+        self.throttle.throttle();
+    }
+
+    fn notify(&mut self, notification: Notification) {
+        self.subscribers.retain(|subscriber| subscriber.send(notification.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bare::BareMachine;
+    use crate::error::EmulatorError;
+    use crate::handle::{MachineHandle, Notification};
+    use crate::machines::composite::{MachineBuilder, Rst};
+    use crate::memory::Memory8080;
+
+    use std::time::Duration;
+
+    #[test]
+    fn run_pause_and_restore() {
+        // loop: INR B; JNZ loop; HLT; OUT 1
+        let handle = MachineHandle::spawn(|| BareMachine::new(&[0x04, 0xc2, 0x00, 0x00, 0x76, 0xd3, 0x01]));
+        let notifications = handle.subscribe();
+        handle.step(3);
+        let saved = handle.save_state().unwrap().unwrap();
+        assert_eq!((saved.cpu.b, saved.cpu.pc), (2, 0x0001));
+
+        // Runs until B wraps and the HLT ends the program
+        handle.resume();
+        assert_eq!(notifications.recv_timeout(Duration::from_secs(10)), Ok(Notification::Finished));
+        assert_eq!(handle.state().unwrap().pc, 0x0005);
+        let mut state = saved.clone();
+        state.cpu.pc = 0x0005;
+        handle.load_state(state);
+        handle.resume();
+        assert_eq!(notifications.recv_timeout(Duration::from_secs(10)), Ok(Notification::Stopped(EmulatorError::UnmappedPort(0x01))));
+
+        handle.load_state(saved);
+        handle.pause();
+        assert_eq!(handle.state().unwrap().b, 2);
+        handle.reset();
+        assert_eq!(handle.state().unwrap().pc, 0x0000);
+    }
+
+    #[test]
+    fn clock_stands_still_while_stepping() {
+        let handle = MachineHandle::spawn(|| {
+            // 0x0000: LXI SP, 0x2000; EI; loop: JMP loop
+            // 0x0010 (RST 2): INR B; EI; RET
+            let mut image = [0x00; 0x10000];
+            image[..7].copy_from_slice(&[0x31, 0x00, 0x20, 0xfb, 0xc3, 0x04, 0x00]);
+            image[0x10..0x13].copy_from_slice(&[0x04, 0xfb, 0xc9]);
+            MachineBuilder::new().memory(Memory8080::new(image)).interrupt_every(100, Rst(2)).build()
+        });
+        handle.step(50);
+        assert_eq!(handle.state().unwrap().b, 0);
+        handle.resume();
+        while handle.state().unwrap().b == 0 {
+            std::thread::yield_now();
+        }
+        handle.pause();
+    }
+}
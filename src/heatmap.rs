@@ -0,0 +1,224 @@
+//! How often each address was read and written, to find the RAM a ROM
+//! actually uses or a stack growing further than it should.
+//!
+//! `AccessCounts` wraps the CPU's memory and counts every read and write
+//! that goes through it. Instruction fetches count as reads and `peek`
+//! counts nothing. The counts can be had per address or summed over
+//! pages, and exported as CSV or as a PGM image of the address space.
+//!
+//! ```
+//! use i8080_emulator::cpu::CPU;
+//! use i8080_emulator::heatmap::AccessCounts;
+//! use i8080_emulator::memory::Memory8080;
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! // LXI SP, 0x2000; PUSH B; POP B; HLT
+//! let mut memory = [0x00; 0x10000];
+//! memory[..6].copy_from_slice(&[0x31, 0x00, 0x20, 0xc5, 0xc1, 0x76]);
+//! let counts = Rc::new(RefCell::new(AccessCounts::new(Memory8080::new(memory))));
+//! let mut cpu = CPU::new(counts.clone());
+//! while !cpu.is_halted() {
+//!     cpu.step();
+//! }
+//! let counts = counts.borrow();
+//! assert_eq!((counts.reads(0x1fff), counts.writes(0x1fff)), (1, 1));
+//! assert_eq!(counts.pages(0x100)[0x1f].writes, 2);
+//! ```
+
+use crate::memory::{Access, Memory};
+
+use std::cell::Cell;
+use std::io::{self, Write};
+
+/// The accesses to a run of addresses starting at `start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageCounts {
+    pub start: u16,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+/// A memory that counts the reads and writes of every address in the
+/// memory it wraps. Counts stop at `u32::MAX`.
+pub struct AccessCounts<M: Memory> {
+    inner: M,
+    reads: Vec<Cell<u32>>,
+    writes: Vec<Cell<u32>>,
+}
+
+impl<M: Memory> AccessCounts<M> {
+    pub fn new(inner: M) -> Self {
+        AccessCounts {
+            inner,
+            reads: vec![Cell::new(0); 0x10000],
+            writes: vec![Cell::new(0); 0x10000],
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    pub fn reads(&self, addr: u16) -> u32 {
+        self.reads[usize::from(addr)].get()
+    }
+
+    pub fn writes(&self, addr: u16) -> u32 {
+        self.writes[usize::from(addr)].get()
+    }
+
+    /// Forgets every access so far.
+    pub fn clear(&mut self) {
+        self.reads.iter().chain(&self.writes).for_each(|count| count.set(0));
+    }
+
+    /// The accesses summed over pages of `size` addresses, lowest first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is not a power of two between 1 and 0x10000.
+    pub fn pages(&self, size: usize) -> Vec<PageCounts> {
+        assert!(size.is_power_of_two() && size <= 0x10000, "bad page size {:#x}", size);
+        let sum = |counts: &[Cell<u32>]| counts.iter().map(|count| u64::from(count.get())).sum();
+        self.reads
+            .chunks(size)
+            .zip(self.writes.chunks(size))
+            .enumerate()
+            .map(|(page, (reads, writes))| PageCounts { start: (page * size) as u16, reads: sum(reads), writes: sum(writes) })
+            .collect()
+    }
+
+    /// Writes the counts per page of `size` addresses as CSV with a header
+    /// line, addresses in hex. Pages nothing accessed are left out.
+    ///
+    /// # Panics
+    ///
+    /// Panics as `pages` does.
+    pub fn write_csv(&self, w: &mut impl Write, size: usize) -> io::Result<()> {
+        writeln!(w, "address,reads,writes")?;
+        for page in self.pages(size).iter().filter(|page| page.reads + page.writes > 0) {
+            writeln!(w, "{:04x},{},{}", page.start, page.reads, page.writes)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a 256 by 256 binary PGM image with a pixel per address, a
+    /// row per page of 0x100. Untouched addresses are black and the rest
+    /// get brighter on a log scale up to white for the busiest one.
+    /// `access` picks reads or writes, `None` counts both.
+    pub fn write_pgm(&self, w: &mut impl Write, access: Option<Access>) -> io::Result<()> {
+        let counts: Vec<u64> = self
+            .reads
+            .iter()
+            .zip(&self.writes)
+            .map(|(reads, writes)| match access {
+                Some(Access::Read) => u64::from(reads.get()),
+                Some(Access::Write) => u64::from(writes.get()),
+                None => u64::from(reads.get()) + u64::from(writes.get()),
+            })
+            .collect();
+        let max = (counts.iter().copied().max().unwrap_or(0) as f64).ln_1p();
+        let pixels: Vec<u8> = counts
+            .iter()
+            .map(|&count| match count {
+                0 => 0,
+                _ => 1 + (254.0 * (count as f64).ln_1p() / max) as u8,
+            })
+            .collect();
+        write!(w, "P5\n256 256\n255\n")?;
+        w.write_all(&pixels)
+    }
+
+    fn count(counts: &[Cell<u32>], i: usize) {
+        let count = &counts[i & 0xffff];
+        count.set(count.get().saturating_add(1));
+    }
+}
+
+impl<M: Memory> Memory for AccessCounts<M> {
+    fn read(&self, i: usize) -> u8 {
+        Self::count(&self.reads, i);
+        self.inner.read(i)
+    }
+
+    fn write(&mut self, i: usize, data: u8) {
+        Self::count(&self.writes, i);
+        self.inner.write(i, data);
+    }
+
+    fn read16(&self, i: usize) -> u16 {
+        Self::count(&self.reads, i);
+        Self::count(&self.reads, i + 1);
+        self.inner.read16(i)
+    }
+
+    fn write16(&mut self, i: usize, data: u16) {
+        Self::count(&self.writes, i);
+        Self::count(&self.writes, i + 1);
+        self.inner.write16(i, data);
+    }
+
+    fn peek(&self, i: usize) -> u8 {
+        self.inner.peek(i)
+    }
+
+    fn note_fetch(&self, addr: u16) {
+        self.inner.note_fetch(addr);
+    }
+
+    fn is_executable(&self, addr: u16) -> bool {
+        self.inner.is_executable(addr)
+    }
+
+    fn take_guard_hit(&self) -> Option<u16> {
+        self.inner.take_guard_hit()
+    }
+
+    fn take_contended_accesses(&self) -> u32 {
+        self.inner.take_contended_accesses()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::heatmap::{AccessCounts, PageCounts};
+    use crate::memory::{Access, Memory, Memory8080};
+
+    #[test]
+    fn counts_and_exports() {
+        let mut counts = AccessCounts::new(Memory8080::new_empty());
+        counts.write16(0xffff, 0x1234);
+        counts.read(0x0010);
+        counts.read(0x0011);
+        counts.read16(0x0010);
+        counts.peek(0x0010);
+        assert_eq!((counts.writes(0xffff), counts.writes(0x0000)), (1, 1));
+        assert_eq!((counts.reads(0x0010), counts.reads(0x0011)), (2, 2));
+        assert_eq!(counts.pages(0x8000), [
+            PageCounts { start: 0x0000, reads: 4, writes: 1 },
+            PageCounts { start: 0x8000, reads: 0, writes: 1 },
+        ]);
+
+        let mut csv = Vec::new();
+        counts.write_csv(&mut csv, 0x10).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), "address,reads,writes\n0000,0,1\n0010,4,0\nfff0,0,1\n");
+
+        let mut pgm = Vec::new();
+        counts.write_pgm(&mut pgm, Some(Access::Read)).unwrap();
+        let (header, pixels) = pgm.split_at(15);
+        assert_eq!(header, b"P5\n256 256\n255\n");
+        assert_eq!((pixels.len(), pixels[0x0010], pixels[0x0012], pixels[0xffff]), (0x10000, 255, 0, 0));
+
+        counts.clear();
+        assert_eq!(counts.pages(0x10000), [PageCounts { start: 0x0000, reads: 0, writes: 0 }]);
+    }
+}
@@ -0,0 +1,235 @@
+//! Callbacks run after instructions retire, subscribed by instruction
+//! class rather than by address.
+//!
+//! A tool that only cares about stores or control flow registers for that
+//! class and is called for nothing else. Which callbacks apply to which
+//! opcode is worked out when they are registered, so stepping through
+//! instructions no hook is interested in costs one table lookup.
+//!
+//! ```
+//! use i8080_emulator::cpu::CPU;
+//! use i8080_emulator::hooks::{Hooks, OpClass};
+//! use i8080_emulator::memory::Memory8080;
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! let mut memory = [0x00; 0x10000];
+//! memory[..4].copy_from_slice(&[0x21, 0x00, 0x20, 0x77]); // LXI H, 0x2000; MOV M, A
+//! let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+//! let stores = Rc::new(RefCell::new(Vec::new()));
+//! let log = stores.clone();
+//! let mut hooks = Hooks::new();
+//! hooks.on(OpClass::STORE, move |_cpu: &CPU, retired| log.borrow_mut().push(retired.pc));
+//! hooks.step(&mut cpu);
+//! hooks.step(&mut cpu);
+//! assert_eq!(*stores.borrow(), [0x0003]);
+//! ```
+
+use crate::cpu::{ClockCycles, Event, CPU};
+use crate::opcodes::OPCODES;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use alloc::vec;
+use core::ops::BitOr;
+
+/// A set of instruction classes. Classes overlap: CALL is both
+/// `CONTROL_FLOW` and `STACK`, `INR M` both `LOAD` and `STORE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpClass(u8);
+
+impl OpClass {
+    /// Register-to-register moves, NOP and the like.
+    pub const NONE: OpClass = OpClass(0x00);
+    /// Reads a data byte or word from memory, not counting the stack.
+    pub const LOAD: OpClass = OpClass(0x01);
+    /// Writes to memory, not counting the stack.
+    pub const STORE: OpClass = OpClass(0x02);
+    /// Pushes to or pops from the stack, or moves SP.
+    pub const STACK: OpClass = OpClass(0x04);
+    /// IN and OUT.
+    pub const IO: OpClass = OpClass(0x08);
+    /// Jumps, calls, returns, restarts and PCHL.
+    pub const CONTROL_FLOW: OpClass = OpClass(0x10);
+    /// Arithmetic, logic and rotates.
+    pub const ALU: OpClass = OpClass(0x20);
+    /// EI, DI and HLT.
+    pub const MACHINE: OpClass = OpClass(0x40);
+    pub const ALL: OpClass = OpClass(0x7f);
+
+    pub fn contains(self, other: OpClass) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(self, other: OpClass) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl BitOr for OpClass {
+    type Output = OpClass;
+
+    fn bitor(self, rhs: OpClass) -> OpClass {
+        OpClass(self.0 | rhs.0)
+    }
+}
+
+/// The classes `op` belongs to.
+pub fn classify(op: u8) -> OpClass {
+    let mnemonic = OPCODES[usize::from(op)].mnemonic;
+    let mut words = mnemonic.split([' ', ',']).filter(|w| !w.is_empty());
+    let name = words.next().unwrap_or("");
+    let operands: Vec<&str> = words.collect();
+    let reads_m = operands.last() == Some(&"M");
+    match name {
+        "MOV" if operands[0] == "M" => OpClass::STORE,
+        "MOV" if reads_m => OpClass::LOAD,
+        "MVI" if operands[0] == "M" => OpClass::STORE,
+        "INR" | "DCR" if reads_m => OpClass::LOAD | OpClass::STORE | OpClass::ALU,
+        "ADD" | "ADC" | "SUB" | "SBB" | "ANA" | "XRA" | "ORA" | "CMP" if reads_m => OpClass::LOAD | OpClass::ALU,
+        "ADD" | "ADC" | "SUB" | "SBB" | "ANA" | "XRA" | "ORA" | "CMP" | "ADI" | "ACI" | "SUI" | "SBI" | "ANI"
+        | "XRI" | "ORI" | "CPI" | "INR" | "DCR" | "DAD" | "DAA" | "RLC" | "RRC" | "RAL" | "RAR" | "CMA" | "STC"
+        | "CMC" => OpClass::ALU,
+        "LDA" | "LDAX" | "LHLD" => OpClass::LOAD,
+        "STA" | "STAX" | "SHLD" => OpClass::STORE,
+        "PUSH" | "POP" | "XTHL" | "SPHL" => OpClass::STACK,
+        "LXI" | "INX" | "DCX" if operands[0] == "SP" => OpClass::STACK,
+        "IN" | "OUT" => OpClass::IO,
+        "PCHL" => OpClass::CONTROL_FLOW,
+        "EI" | "DI" | "HLT" => OpClass::MACHINE,
+        _ if name.starts_with('J') => OpClass::CONTROL_FLOW,
+        _ if name.starts_with('C') || name.starts_with('R') => OpClass::CONTROL_FLOW | OpClass::STACK,
+        _ => OpClass::NONE,
+    }
+}
+
+/// An instruction that just finished, as passed to hooks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retired {
+    /// Address the instruction was fetched from.
+    pub pc: u16,
+    pub opcode: u8,
+    pub cycles: ClockCycles,
+}
+
+type Hook = Box<dyn FnMut(&CPU, &Retired)>;
+
+/// Callbacks keyed by instruction class. Step the CPU through `step` for
+/// them to run.
+pub struct Hooks {
+    hooks: Vec<Hook>,
+    // Per opcode, the indices of the hooks subscribed to it
+    by_opcode: Vec<Vec<usize>>,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Hooks {
+            hooks: Vec::new(),
+            by_opcode: vec![Vec::new(); 0x100],
+        }
+    }
+
+    /// Calls `hook` after every instruction in any of `classes` retires,
+    /// with the CPU as the instruction left it.
+    pub fn on(&mut self, classes: OpClass, hook: impl FnMut(&CPU, &Retired) + 'static) {
+        let index = self.hooks.len();
+        self.hooks.push(Box::new(hook));
+        for op in 0..=0xffu8 {
+            if classify(op).intersects(classes) {
+                self.by_opcode[usize::from(op)].push(index);
+            }
+        }
+    }
+
+    /// Executes one instruction on `cpu` and runs the hooks subscribed to
+    /// it. Faulting instructions never retired and run no hooks. Port
+    /// accesses are left to the caller as usual.
+    pub fn step(&mut self, cpu: &mut CPU) -> Event {
+        let pc = cpu.pc;
+        let halted = cpu.is_halted();
+        let opcode = cpu.fetch();
+        let event = cpu.exec(opcode);
+        if halted || matches!(event, Event::Fault(_)) {
+            return event;
+        }
+        let subscribed = &self.by_opcode[usize::from(opcode)];
+        if !subscribed.is_empty() {
+            let retired = Retired { pc, opcode, cycles: event.cycles() };
+            for &index in subscribed {
+                (self.hooks[index])(cpu, &retired);
+            }
+        }
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::hooks::{classify, Hooks, OpClass};
+    use crate::memory::Memory8080;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn classes() {
+        assert_eq!(classify(0x77), OpClass::STORE); // MOV M, A
+        assert_eq!(classify(0x7e), OpClass::LOAD); // MOV A, M
+        assert_eq!(classify(0x78), OpClass::NONE); // MOV A, B
+        assert_eq!(classify(0x34), OpClass::LOAD | OpClass::STORE | OpClass::ALU); // INR M
+        assert_eq!(classify(0xcd), OpClass::CONTROL_FLOW | OpClass::STACK); // CALL
+        assert_eq!(classify(0xc0), OpClass::CONTROL_FLOW | OpClass::STACK); // RNZ
+        assert_eq!(classify(0xff), OpClass::CONTROL_FLOW | OpClass::STACK); // RST 7
+        assert_eq!(classify(0xca), OpClass::CONTROL_FLOW); // JZ
+        assert_eq!(classify(0xfe), OpClass::ALU); // CPI
+        assert_eq!(classify(0x2f), OpClass::ALU); // CMA
+        assert_eq!(classify(0xdb), OpClass::IO); // IN
+        assert_eq!(classify(0x31), OpClass::STACK); // LXI SP
+        assert_eq!(classify(0x21), OpClass::NONE); // LXI H
+        assert_eq!(classify(0x76), OpClass::MACHINE); // HLT
+        // Every opcode but the data moves between registers belongs somewhere
+        for op in 0..=0xffu8 {
+            if classify(op) == OpClass::NONE {
+                let mnemonic = crate::opcodes::OPCODES[usize::from(op)].mnemonic;
+                assert!(["MOV", "MVI", "LXI", "INX", "DCX", "NOP", "XCHG"].iter().any(|m| mnemonic.starts_with(m)),
+                        "{}", mnemonic);
+            }
+        }
+    }
+
+    #[test]
+    fn hooks_see_only_their_classes() {
+        // LXI SP, 0x1000; MVI A, 0x42; STA 0x2000; CALL 0x0010; HLT;
+        // ... 0x0010: OUT 0x01; RET
+        let mut memory = [0x00; 0x10000];
+        let code = [0x31, 0x00, 0x10, 0x3e, 0x42, 0x32, 0x00, 0x20, 0xcd, 0x10, 0x00, 0x76];
+        memory[..code.len()].copy_from_slice(&code);
+        memory[0x10..0x13].copy_from_slice(&[0xd3, 0x01, 0xc9]);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut hooks = Hooks::new();
+        let log = seen.clone();
+        hooks.on(OpClass::STORE | OpClass::IO, move |cpu: &CPU, retired| {
+            log.borrow_mut().push((retired.pc, cpu.regs.a));
+        });
+        let flow = Rc::new(RefCell::new(Vec::new()));
+        let log = flow.clone();
+        hooks.on(OpClass::CONTROL_FLOW, move |cpu: &CPU, retired| {
+            log.borrow_mut().push((retired.pc, cpu.pc, retired.cycles));
+        });
+        for _ in 0..8 {
+            hooks.step(&mut cpu);
+        }
+        assert_eq!(*seen.borrow(), [(0x05, 0x42), (0x10, 0x42)]);
+        assert_eq!(*flow.borrow(), [(0x08, 0x10, 17), (0x12, 0x0b, 10)]);
+    }
+}
@@ -0,0 +1,168 @@
+//! Calls between host code and guest subroutines, for programs that are
+//! part Rust and part 8080 code, such as one being ported a routine at a
+//! time.
+//!
+//! `HostCalls::call` runs a guest subroutine from the host as if the
+//! instruction at PC had been a CALL, and hands back the registers once
+//! the subroutine has returned. `HostCalls::install` goes the other way:
+//! when the guest calls the address given, the Rust routine runs in place
+//! of guest code and control returns to the guest as RET would. Routines
+//! are CPU traps, as `CPU::add_trap` sets, that get the whole CPU.
+//!
+//! ```
+//! use i8080_emulator::cpu::CPU;
+//! use i8080_emulator::hostcall::HostCalls;
+//! use i8080_emulator::io::PortBus;
+//! use i8080_emulator::memory::Memory8080;
+//! use i8080_emulator::registers::Registers;
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! // 0x0100: MOV A, B; ADD C; CALL 0x0200; RET
+//! let mut memory = [0x00; 0x10000];
+//! memory[0x100..0x106].copy_from_slice(&[0x78, 0x81, 0xcd, 0x00, 0x02, 0xc9]);
+//! let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+//! let mut calls = HostCalls::new();
+//! // Doubles A
+//! HostCalls::install(&mut cpu, 0x0200, |cpu: &mut CPU| cpu.regs.a = cpu.regs.a.wrapping_mul(2));
+//! let regs = Registers { b: 3, c: 4, ..Registers::new() };
+//! let regs = calls.call(&mut cpu, &mut PortBus::new(), 0x0100, regs).unwrap();
+//! assert_eq!(regs.a, 14);
+//! ```
+
+use crate::cpu::{Event, CPU};
+use crate::error::EmulatorError;
+use crate::io::PortBus;
+use crate::memory::Memory;
+use crate::registers::Registers;
+
+use alloc::boxed::Box;
+
+/// Instructions `HostCalls::call` runs before giving up, unless set
+/// otherwise.
+pub const DEFAULT_STEP_LIMIT: u64 = 10_000_000;
+
+/// The means to call guest subroutines, and to install host routines
+/// at guest addresses.
+pub struct HostCalls {
+    step_limit: u64,
+}
+
+impl Default for HostCalls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HostCalls {
+    pub fn new() -> Self {
+        HostCalls {
+            step_limit: DEFAULT_STEP_LIMIT,
+        }
+    }
+
+    pub fn set_step_limit(&mut self, limit: u64) {
+        self.step_limit = limit;
+    }
+
+    /// Runs `routine` whenever PC reaches `addr`, in place of whatever is
+    /// in memory there, replacing any routine installed there before. The
+    /// routine gets the CPU as the guest called it, registers and all,
+    /// and what it leaves in them is what the guest gets back. The guest
+    /// then returns as RET does, taking as many cycles. `CPU::remove_trap`
+    /// takes the routine away again.
+    pub fn install<M: Memory>(cpu: &mut CPU<M>, addr: u16, routine: impl FnMut(&mut CPU<M>) + 'static) {
+        cpu.set_trap(addr, Box::new(routine));
+    }
+
+    /// Calls the guest subroutine at `addr` with `regs`, as a CALL at PC
+    /// would, and runs it until it returns. Returns the registers as the
+    /// subroutine left them, with PC and SP back where they were. On an
+    /// error the CPU is left where it stopped.
+    pub fn call<M: Memory>(&mut self, cpu: &mut CPU<M>, bus: &mut PortBus, addr: u16, regs: Registers) -> Result<Registers, EmulatorError> {
+        let (pc, sp) = (cpu.pc, cpu.sp());
+        let stack = sp.wrapping_sub(2);
+        cpu.memory.borrow_mut().write16(usize::from(stack), pc);
+        cpu.set_sp(stack);
+        cpu.regs = regs;
+        cpu.pc = addr;
+        for _ in 0..self.step_limit {
+            match bus.step(cpu) {
+                Event::Fault(fault) => return Err(EmulatorError::Fault(fault)),
+                Event::Halt(_) => return Err(EmulatorError::Halted(cpu.pc.wrapping_sub(1))),
+                _ => {}
+            }
+            if cpu.pc == pc && cpu.sp() == sp {
+                return Ok(cpu.regs);
+            }
+        }
+        Err(EmulatorError::StepLimit(self.step_limit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    #[cfg(feature = "std")]
+    use crate::devices::serial::Serial;
+    use crate::error::EmulatorError;
+    use crate::hostcall::HostCalls;
+    use crate::io::PortBus;
+    #[cfg(feature = "std")]
+    use crate::memory::Memory;
+    use crate::memory::Memory8080;
+    use crate::registers::Registers;
+    #[cfg(feature = "std")]
+    use crate::I8080Core;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn cpu_with(addr: usize, code: &[u8]) -> CPU {
+        let mut memory = [0x00; 0x10000];
+        memory[addr..addr + code.len()].copy_from_slice(code);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        cpu.set_sp(0x2000);
+        cpu.pc = 0x1234;
+        cpu
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn calls_both_ways() {
+        // 0x0100: PUSH B; MVI B, 0; CALL 0x0300; MOV A, L; OUT 0x11; POP B; RET
+        let code = [0xc5, 0x06, 0x00, 0xcd, 0x00, 0x03, 0x7d, 0xd3, 0x11, 0xc1, 0xc9];
+        let mut cpu = cpu_with(0x100, &code);
+        let serial = Rc::new(RefCell::new(Serial::new(0x10)));
+        let mut bus = PortBus::new();
+        bus.map(0x11, serial.clone());
+        let mut calls = HostCalls::new();
+        // HL = BC + DE
+        HostCalls::install(&mut cpu, 0x0300, |cpu: &mut CPU| {
+            let sum = cpu.regs.get_bc().wrapping_add(cpu.regs.get_de());
+            cpu.regs.set_hl(sum);
+        });
+        let regs = Registers { b: 0x01, c: 0x20, d: 0x00, e: 0x03, ..Registers::new() };
+        let out = calls.call(&mut cpu, &mut bus, 0x0100, regs).unwrap();
+        assert_eq!((out.get_hl(), out.get_bc(), out.a), (0x0023, 0x0120, 0x23));
+        assert_eq!(serial.borrow_mut().take_output(), [0x23]);
+        assert_eq!((cpu.pc, cpu.sp()), (0x1234, 0x2000));
+        assert_eq!(cpu.memory.borrow().read16(0x1ffe), 0x1234);
+        assert_eq!(cpu.cycles(), 11 + 7 + 17 + 10 + 5 + 10 + 10 + 10);
+        assert_eq!(cpu.state().pc, 0x1234);
+    }
+
+    #[test]
+    fn guest_errors() {
+        // loop: JMP loop
+        let mut cpu = cpu_with(0x100, &[0xc3, 0x00, 0x01]);
+        let mut calls = HostCalls::new();
+        calls.set_step_limit(100);
+        let result = calls.call(&mut cpu, &mut PortBus::new(), 0x0100, Registers::new());
+        assert_eq!(result, Err(EmulatorError::StepLimit(100)));
+
+        let mut cpu = cpu_with(0x100, &[0x00, 0x76]);
+        let result = calls.call(&mut cpu, &mut PortBus::new(), 0x0100, Registers::new());
+        assert_eq!(result, Err(EmulatorError::Halted(0x0101)));
+    }
+}
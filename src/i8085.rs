@@ -0,0 +1,294 @@
+//! The 8085's additions to the 8080, used when a CPU is set to
+//! `CpuModel::I8085`.
+//!
+//! The 8085 runs the 8080 instruction set with a few differences that
+//! matter to programs:
+//!
+//! - RIM (0x20) and SIM (0x30), NOPs on an 8080, read and set the
+//!   interrupt masks and drive the serial SID and SOD pins;
+//! - four interrupt inputs with fixed vectors besides INTR: TRAP
+//!   (0x0024), which cannot be masked or disabled, and RST 7.5 (0x003c),
+//!   6.5 (0x0034) and 5.5 (0x002c), each with a mask bit set by SIM;
+//! - different clock cycle counts for about a third of the opcodes.
+//!
+//! The undocumented 8085 opcodes are not implemented; they run as their
+//! 8080 aliases.
+//!
+//! ```
+//! use i8080_emulator::cpu::{CpuModel, CPU};
+//! use i8080_emulator::i8085::InterruptLine;
+//! use i8080_emulator::memory::Memory8080;
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! // MVI A, 0x08; SIM; EI; loop: JMP loop
+//! let mut memory = [0x00; 0x10000];
+//! memory[..7].copy_from_slice(&[0x3e, 0x08, 0x30, 0xfb, 0xc3, 0x04, 0x00]);
+//! let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+//! cpu.set_model(CpuModel::I8085);
+//! for _ in 0..4 {
+//!     cpu.step();
+//! }
+//! cpu.set_interrupt_line(InterruptLine::Rst65, true);
+//! assert!(cpu.service_interrupt_lines().is_some());
+//! assert_eq!(cpu.pc, 0x0034);
+//! ```
+
+use crate::cpu::ClockCycles;
+
+/// The 8085's interrupt inputs with fixed vectors, highest priority
+/// first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptLine {
+    /// Non-maskable, taken on a rising edge.
+    Trap,
+    /// Taken on a rising edge, which is latched until the interrupt is
+    /// accepted or SIM clears it.
+    Rst75,
+    /// Taken while high.
+    Rst65,
+    /// Taken while high.
+    Rst55,
+}
+
+impl InterruptLine {
+    pub const ALL: [InterruptLine; 4] = [InterruptLine::Trap, InterruptLine::Rst75, InterruptLine::Rst65, InterruptLine::Rst55];
+
+    /// Where the CPU goes when it accepts the interrupt.
+    pub fn vector(self) -> u16 {
+        match self {
+            InterruptLine::Trap => 0x0024,
+            InterruptLine::Rst75 => 0x003c,
+            InterruptLine::Rst65 => 0x0034,
+            InterruptLine::Rst55 => 0x002c,
+        }
+    }
+
+    // The line's mask bit in SIM and RIM; TRAP has none
+    fn mask(self) -> u8 {
+        match self {
+            InterruptLine::Trap => 0x00,
+            InterruptLine::Rst75 => 0x04,
+            InterruptLine::Rst65 => 0x02,
+            InterruptLine::Rst55 => 0x01,
+        }
+    }
+}
+
+/// Cycles the acknowledge of a TRAP or RST n.5 takes, pushing PC.
+pub const INTERRUPT_CYCLES: ClockCycles = 12;
+
+/// The pins and latches behind RIM and SIM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Lines {
+    // Mask bits as SIM sets them: bit 0 for 5.5 up to bit 2 for 7.5
+    masks: u8,
+    trap: bool,
+    trap_latch: bool,
+    rst75: bool,
+    rst75_latch: bool,
+    rst65: bool,
+    rst55: bool,
+    pub sid: bool,
+    pub sod: bool,
+}
+
+impl Default for Lines {
+    // As after RESET: every RST n.5 masked
+    fn default() -> Self {
+        Lines {
+            masks: 0x07,
+            trap: false,
+            trap_latch: false,
+            rst75: false,
+            rst75_latch: false,
+            rst65: false,
+            rst55: false,
+            sid: false,
+            sod: false,
+        }
+    }
+}
+
+impl Lines {
+    pub fn set(&mut self, line: InterruptLine, high: bool) {
+        match line {
+            InterruptLine::Trap => {
+                self.trap_latch |= high && !self.trap;
+                self.trap = high;
+            }
+            InterruptLine::Rst75 => {
+                self.rst75_latch |= high && !self.rst75;
+                self.rst75 = high;
+            }
+            InterruptLine::Rst65 => self.rst65 = high,
+            InterruptLine::Rst55 => self.rst55 = high,
+        }
+    }
+
+    /// The highest priority line with a request the CPU would take, given
+    /// whether interrupts are enabled.
+    pub fn pending(&self, enabled: bool) -> Option<InterruptLine> {
+        InterruptLine::ALL.iter().copied().find(|&line| match line {
+            InterruptLine::Trap => self.trap_latch,
+            line => enabled && self.masks & line.mask() == 0 && self.requested(line),
+        })
+    }
+
+    /// Clears the latch of an interrupt being accepted.
+    pub fn accept(&mut self, line: InterruptLine) {
+        match line {
+            InterruptLine::Trap => self.trap_latch = false,
+            InterruptLine::Rst75 => self.rst75_latch = false,
+            _ => {}
+        }
+    }
+
+    fn requested(&self, line: InterruptLine) -> bool {
+        match line {
+            InterruptLine::Trap => self.trap_latch,
+            InterruptLine::Rst75 => self.rst75_latch,
+            InterruptLine::Rst65 => self.rst65,
+            InterruptLine::Rst55 => self.rst55,
+        }
+    }
+
+    /// What RIM loads into A.
+    pub fn rim(&self, enabled: bool) -> u8 {
+        let mut a = self.masks;
+        if enabled {
+            a |= 0x08;
+        }
+        for &(line, bit) in &[(InterruptLine::Rst55, 0x10), (InterruptLine::Rst65, 0x20), (InterruptLine::Rst75, 0x40)] {
+            if self.requested(line) {
+                a |= bit;
+            }
+        }
+        if self.sid {
+            a |= 0x80;
+        }
+        a
+    }
+
+    /// What SIM does with A.
+    pub fn sim(&mut self, a: u8) {
+        if a & 0x08 != 0 {
+            self.masks = a & 0x07;
+        }
+        if a & 0x10 != 0 {
+            self.rst75_latch = false;
+        }
+        if a & 0x40 != 0 {
+            self.sod = a & 0x80 != 0;
+        }
+    }
+}
+
+/// The 8085's cycle count for `op`, given what it took on an 8080 and,
+/// for conditional jumps, whether the jump is taken.
+pub(crate) fn cycles(op: u8, i8080: ClockCycles, jump_taken: bool) -> ClockCycles {
+    let (x, y, z) = (op >> 6, (op >> 3) & 0x07, op & 0x07);
+    match (x, z) {
+        // MOV r, r
+        (1, _) if y != 6 && z != 6 => 4,
+        (1, 6) if y == 6 => 5, // HLT
+        // INR r, DCR r
+        (0, 4) | (0, 5) if y != 6 => 4,
+        // INX, DCX
+        (0, 3) => 6,
+        // Conditional returns: 5 or 11 on an 8080
+        (3, 0) => i8080 + 1,
+        // Conditional jumps
+        (3, 2) if !jump_taken => 7,
+        // Conditional calls: 11 or 17 on an 8080
+        (3, 4) if i8080 == 11 => 9,
+        (3, 4) => 18,
+        // CALL and its aliases
+        (3, 5) if y & 1 == 1 => 18,
+        // PUSH, RST
+        (3, 5) | (3, 7) => 12,
+        // XTHL, PCHL, SPHL
+        (3, 3) if y == 4 => 16,
+        (3, 1) if y == 5 || y == 7 => 6,
+        _ => i8080,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{CpuModel, CPU};
+    use crate::i8085::{cycles, InterruptLine};
+    use crate::memory::Memory8080;
+    use crate::opcodes::OPCODES;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn cycle_counts() {
+        let table = |op: u8| {
+            let info = OPCODES[usize::from(op)];
+            (cycles(op, u32::from(info.cycles), false), cycles(op, u32::from(info.cycles_taken), true))
+        };
+        // MOV B, C; MOV B, M; HLT; INR A; INR M; INX H; RNZ; JNZ; CNZ; CALL
+        // PUSH B; RST 7; XTHL; PCHL; SPHL; POP B; LDA; NOP
+        let expected = [
+            (0x41, (4, 4)), (0x46, (7, 7)), (0x76, (5, 5)), (0x3c, (4, 4)), (0x34, (10, 10)), (0x23, (6, 6)),
+            (0xc0, (6, 12)), (0xc2, (7, 10)), (0xc4, (9, 18)), (0xcd, (18, 18)), (0xc5, (12, 12)),
+            (0xff, (12, 12)), (0xe3, (16, 16)), (0xe9, (6, 6)), (0xf9, (6, 6)), (0xc1, (10, 10)),
+            (0x3a, (13, 13)), (0x00, (4, 4)),
+        ];
+        for (op, counts) in expected {
+            assert_eq!(table(op), counts, "opcode 0x{:02x}", op);
+        }
+    }
+
+    #[test]
+    fn rim_sim_and_lines() {
+        // SIM; RIM; EI; RIM; loop: JMP loop
+        let code = [0x30, 0x20, 0xfb, 0x20, 0xc3, 0x04, 0x00];
+        let mut memory = [0x00; 0x10000];
+        memory[..code.len()].copy_from_slice(&code);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        cpu.set_model(CpuModel::I8085);
+        cpu.set_sp(0x2000);
+
+        // Unmask 7.5 and 5.5 and raise SOD
+        cpu.regs.a = 0xca;
+        assert_eq!(cpu.step().1, 4);
+        assert!(cpu.sod());
+        cpu.set_sid(true);
+        cpu.set_interrupt_line(InterruptLine::Rst65, true);
+        cpu.set_interrupt_line(InterruptLine::Rst75, true);
+        cpu.set_interrupt_line(InterruptLine::Rst75, false);
+        cpu.step();
+        assert_eq!(cpu.regs.a, 0x80 | 0x40 | 0x20 | 0x02);
+        // Disabled: only TRAP gets through
+        assert!(cpu.service_interrupt_lines().is_none());
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.regs.a, 0x80 | 0x40 | 0x20 | 0x08 | 0x02);
+
+        // 6.5 is masked, so the latched 7.5 goes first
+        cpu.step();
+        assert!(cpu.service_interrupt_lines().is_some());
+        assert_eq!(cpu.pc, 0x003c);
+        assert!(!cpu.interrupts_enabled());
+        cpu.set_interrupt_line(InterruptLine::Trap, true);
+        assert!(cpu.service_interrupt_lines().is_some());
+        assert_eq!(cpu.pc, 0x0024);
+        assert!(cpu.service_interrupt_lines().is_none());
+        assert_eq!(cpu.cycles(), 4 + 4 + 4 + 4 + 10 + 12 + 12);
+
+        // The 8080 ignores all of it
+        cpu.set_model(CpuModel::I8080);
+        cpu.set_interrupts_enabled(true);
+        cpu.set_interrupt_line(InterruptLine::Trap, false);
+        cpu.set_interrupt_line(InterruptLine::Trap, true);
+        assert!(cpu.service_interrupt_lines().is_none());
+        cpu.pc = 0x0001;
+        cpu.regs.a = 0x00;
+        assert_eq!(cpu.step().1, 4);
+        assert_eq!(cpu.regs.a, 0x00);
+    }
+}
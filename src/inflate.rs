@@ -0,0 +1,282 @@
+// Decompression of zlib streams (RFC 1950) holding deflate data (RFC
+// 1951), for reading files other programs wrote compressed. Slow and
+// simple: the Huffman codes are decoded a bit at a time, as in zlib's
+// puff.
+
+use alloc::vec::Vec;
+
+const MAX_BITS: usize = 15;
+
+// Base lengths and extra bits of length codes 257 to 285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+// Base distances and extra bits of distance codes 0 to 29
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// The order code length code lengths come in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Why a stream could not be decompressed.
+pub(crate) type InflateError = &'static str;
+
+/// The data in the zlib stream `stream`, checked against its checksum.
+pub(crate) fn inflate_zlib(stream: &[u8]) -> Result<Vec<u8>, InflateError> {
+    let (&method, &flags) = match stream {
+        [method, flags, ..] => (method, flags),
+        _ => return Err("zlib stream ends early"),
+    };
+    if method & 0x0f != 8 || u16::from_be_bytes([method, flags]) % 31 != 0 {
+        return Err("not a zlib stream");
+    }
+    if flags & 0x20 != 0 {
+        return Err("zlib preset dictionaries are not supported");
+    }
+    let mut input = Bits { data: &stream[2..], position: 0, buffer: 0, count: 0 };
+    let data = inflate(&mut input)?;
+    let trailer = stream.get(2 + input.position..2 + input.position + 4).ok_or("zlib stream ends early")?;
+    if u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]) != adler32(&data) {
+        return Err("zlib stream fails its checksum");
+    }
+    Ok(data)
+}
+
+/// The Adler-32 checksum zlib streams end with.
+pub(crate) fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + u32::from(byte)) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+// Deflate data read least significant bit first
+struct Bits<'a> {
+    data: &'a [u8],
+    // Bytes taken from `data`
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn bits(&mut self, need: u32) -> Result<u32, InflateError> {
+        while self.count < need {
+            let byte = *self.data.get(self.position).ok_or("deflate data ends early")?;
+            self.position += 1;
+            self.buffer |= u32::from(byte) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << need) - 1);
+        self.buffer >>= need;
+        self.count -= need;
+        Ok(value)
+    }
+
+    // Drops what is left of the current byte
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+// A canonical Huffman code: how many codes there are of each length and
+// the symbols in code order
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, InflateError> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+        // No more codes of a length than there is room for
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err("deflate data has an oversubscribed code");
+            }
+        }
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = alloc::vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[usize::from(offsets[usize::from(length)])] = symbol as u16;
+                offsets[usize::from(length)] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, input: &mut Bits) -> Result<u16, InflateError> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= input.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("deflate data has a bad code")
+    }
+}
+
+fn inflate(input: &mut Bits) -> Result<Vec<u8>, InflateError> {
+    let mut output = Vec::new();
+    loop {
+        let last = input.bits(1)? == 1;
+        match input.bits(2)? {
+            0 => stored(input, &mut output)?,
+            1 => {
+                let (lengths, distances) = fixed()?;
+                codes(input, &mut output, &lengths, &distances)?
+            }
+            2 => {
+                let (lengths, distances) = dynamic(input)?;
+                codes(input, &mut output, &lengths, &distances)?
+            }
+            _ => return Err("deflate data has a bad block type"),
+        }
+        if last {
+            return Ok(output);
+        }
+    }
+}
+
+fn stored(input: &mut Bits, output: &mut Vec<u8>) -> Result<(), InflateError> {
+    input.align();
+    let header = input.data.get(input.position..input.position + 4).ok_or("deflate data ends early")?;
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    if len != !u16::from_le_bytes([header[2], header[3]]) {
+        return Err("deflate stored block has a bad length");
+    }
+    let start = input.position + 4;
+    let block = input.data.get(start..start + usize::from(len)).ok_or("deflate data ends early")?;
+    output.extend_from_slice(block);
+    input.position = start + usize::from(len);
+    Ok(())
+}
+
+fn fixed() -> Result<(Huffman, Huffman), InflateError> {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+fn dynamic(input: &mut Bits) -> Result<(Huffman, Huffman), InflateError> {
+    let literals = input.bits(5)? as usize + 257;
+    let distances = input.bits(5)? as usize + 1;
+    let code_lengths = input.bits(4)? as usize + 4;
+    if literals > 286 || distances > 30 {
+        return Err("deflate data has too many codes");
+    }
+    let mut lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[symbol] = input.bits(3)? as u8;
+    }
+    let code = Huffman::new(&lengths)?;
+
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let (value, repeat) = match code.decode(input)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or("deflate data repeats a length before the first")?, 3 + input.bits(2)?),
+            17 => (0, 3 + input.bits(3)?),
+            _ => (0, 11 + input.bits(7)?),
+        };
+        if lengths.len() + repeat as usize > literals + distances {
+            return Err("deflate data has too many lengths");
+        }
+        lengths.extend((0..repeat).map(|_| value));
+    }
+    if lengths[256] == 0 {
+        return Err("deflate data has no end-of-block code");
+    }
+    Ok((Huffman::new(&lengths[..literals])?, Huffman::new(&lengths[literals..])?))
+}
+
+fn codes(input: &mut Bits, output: &mut Vec<u8>, lengths: &Huffman, distances: &Huffman) -> Result<(), InflateError> {
+    loop {
+        let symbol = usize::from(lengths.decode(input)?);
+        if symbol < 256 {
+            output.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+        let index = symbol - 257;
+        if index >= LENGTH_BASE.len() {
+            return Err("deflate data has a bad length code");
+        }
+        let len = usize::from(LENGTH_BASE[index]) + input.bits(u32::from(LENGTH_EXTRA[index]))? as usize;
+        let index = usize::from(distances.decode(input)?);
+        if index >= DISTANCE_BASE.len() {
+            return Err("deflate data has a bad distance code");
+        }
+        let distance = usize::from(DISTANCE_BASE[index]) + input.bits(u32::from(DISTANCE_EXTRA[index]))? as usize;
+        if distance > output.len() {
+            return Err("deflate data reaches back too far");
+        }
+        // Byte at a time, as the copy can overlap what it writes
+        for _ in 0..len {
+            output.push(output[output.len() - distance]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inflate::{adler32, inflate_zlib};
+
+    #[test]
+    fn streams() {
+        // zlib.compress(b"hello hello hello hello\n"), fixed codes
+        let fixed = [
+            0x78, 0x9c, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00, 0x70, 0xbe, 0x08, 0xbb,
+        ];
+        assert_eq!(inflate_zlib(&fixed).unwrap(), b"hello hello hello hello\n");
+        // zlib.compress(b"hi", 0), a stored block
+        let stored = [0x78, 0x01, 0x01, 0x02, 0x00, 0xfd, 0xff, b'h', b'i', 0x01, 0x3b, 0x00, 0xd2];
+        assert_eq!(inflate_zlib(&stored).unwrap(), b"hi");
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+
+        let mut broken = fixed;
+        broken[16] ^= 0x01;
+        assert_eq!(inflate_zlib(&broken), Err("zlib stream fails its checksum"));
+        assert_eq!(inflate_zlib(&fixed[..10]), Err("deflate data ends early"));
+        assert_eq!(inflate_zlib(&[0x78, 0x9d]), Err("not a zlib stream"));
+    }
+
+    #[test]
+    fn dynamic_codes() {
+        // zlib.compress(text * 24, 9) of the text below
+        let stream = [
+            0x78, 0xda, 0xed, 0xcd, 0xc9, 0x0d, 0x80, 0x30, 0x10, 0x03, 0xc0, 0x56, 0xdc, 0x5a, 0x80, 0x85, 0x70, 0x2e,
+            0x09, 0x1b, 0xae, 0xea, 0x41, 0x40, 0x01, 0xe1, 0x6f, 0xff, 0x2c, 0x5b, 0x1a, 0xf3, 0x82, 0x90, 0xda, 0xb2,
+            0x47, 0x11, 0x75, 0x9b, 0x50, 0xeb, 0x8e, 0x2e, 0x8d, 0xf3, 0x02, 0x5d, 0x25, 0xc2, 0xee, 0x79, 0x70, 0xe7,
+            0x81, 0x4a, 0x9b, 0xa7, 0xe4, 0x7e, 0xdd, 0x17, 0x79, 0x63, 0x64, 0xc8, 0x90, 0x21, 0x43, 0x86, 0x0c, 0x19,
+            0x32, 0x64, 0xfe, 0x31, 0x17, 0xb6, 0xd0, 0x8f, 0xe6,
+        ];
+        let text = b"the quick brown fox jumps over the lazy dog the quick brown fox jumps over the lazy dog aaaaaaaaeeeeeee";
+        assert_eq!(inflate_zlib(&stream).unwrap(), text.repeat(24));
+    }
+}
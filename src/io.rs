@@ -0,0 +1,378 @@
+//! Routing IN and OUT instructions to peripherals.
+//!
+//! A `PortBus` maps each of the 256 port numbers to an input and an output
+//! device and resolves the CPU's `Event::Input` and `Event::Output` itself,
+//! so a machine only has to plug its peripherals in:
+//!
+//! ```
+//! use i8080_emulator::cpu::CPU;
+//! use i8080_emulator::io::{OutputDevice, PortBus};
+//! use i8080_emulator::memory::Memory8080;
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! struct Speaker(Vec<u8>);
+//!
+//! impl OutputDevice for Speaker {
+//!     fn output(&mut self, _port: u8, value: u8) {
+//!         self.0.push(value);
+//!     }
+//! }
+//!
+//! let mut memory = [0; 0x10000];
+//! memory[..2].copy_from_slice(&[0xd3, 0x03]); // OUT 0x03
+//! let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+//! let speaker = Rc::new(RefCell::new(Speaker(Vec::new())));
+//! let mut bus = PortBus::new();
+//! bus.map_output(0x03, speaker.clone());
+//! bus.step(&mut cpu);
+//! assert_eq!(speaker.borrow().0, [0x00]);
+//! ```
+
+use crate::cpu::{Event, Timestamp, CPU};
+use crate::device::Device;
+use crate::memory::Memory;
+
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+/// Something IN instructions can read from.
+pub trait InputDevice {
+    /// Returns the byte placed on the bus for an IN from `port`.
+    fn input(&mut self, port: u8) -> u8;
+}
+
+/// Something OUT instructions can write to.
+pub trait OutputDevice {
+    fn output(&mut self, port: u8, value: u8);
+}
+
+impl<D: Device> InputDevice for D {
+    fn input(&mut self, port: u8) -> u8 {
+        self.read(port)
+    }
+}
+
+impl<D: Device> OutputDevice for D {
+    fn output(&mut self, port: u8, value: u8) {
+        self.write(port, value)
+    }
+}
+
+/// Value read from a port with no input device attached, as from a
+/// floating bus with pull-ups.
+pub const UNMAPPED_INPUT: u8 = 0xff;
+
+type Noise = Box<dyn FnMut(u8, Timestamp) -> u8>;
+
+/// What an IN from a port with nothing attached reads on a `PortBus`.
+/// Some games seed their random numbers from such a read, or check for a
+/// copy protection dongle with one, and expect what the real board gave.
+pub enum Floating {
+    /// 0x00, as with pull-downs.
+    Zeros,
+    /// `UNMAPPED_INPUT`, as with pull-ups. The default.
+    Ones,
+    /// The last byte that went over the bus, in either direction, as
+    /// when nothing drives the data lines and they hold their charge.
+    LastValue,
+    /// Whatever the closure returns for the port and the CPU's cycle
+    /// count once the IN has run. See `Floating::noise`.
+    Custom(Noise),
+}
+
+impl Floating {
+    /// `Floating::Custom` with `source`, for example a frame counter or a
+    /// random number generator.
+    pub fn noise(source: impl FnMut(u8, Timestamp) -> u8 + 'static) -> Self {
+        Floating::Custom(Box::new(source))
+    }
+}
+
+/// Maps port numbers to devices. A device that is both read and written,
+/// like a shift register, is shared between its input and output ports
+/// through `Rc<RefCell<_>>`.
+///
+/// Devices can be attached with an alias mask for boards that only decode
+/// some of the port address bits. The device then answers on every port
+/// that matches in the masked bits, and always sees the port number it was
+/// attached with.
+pub struct PortBus {
+    inputs: Vec<Slot<dyn InputDevice>>,
+    outputs: Vec<Slot<dyn OutputDevice>>,
+    floating: Floating,
+    // The last byte read or written, for `Floating::LastValue`
+    last: u8,
+    // The cycle count of the access being resolved
+    now: Timestamp,
+}
+
+// A device and the port it was attached as
+type Slot<D> = Option<(u8, Rc<RefCell<D>>)>;
+
+impl Default for PortBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PortBus {
+    pub fn new() -> Self {
+        PortBus {
+            inputs: vec![None; 0x100],
+            outputs: vec![None; 0x100],
+            floating: Floating::Ones,
+            last: UNMAPPED_INPUT,
+            now: 0,
+        }
+    }
+
+    /// Sets what unmapped ports read. Defaults to `Floating::Ones`.
+    pub fn set_floating(&mut self, floating: Floating) {
+        self.floating = floating;
+    }
+
+    pub fn map_input(&mut self, port: u8, device: Rc<RefCell<dyn InputDevice>>) {
+        self.map_input_aliased(port, 0xff, device);
+    }
+
+    pub fn map_output(&mut self, port: u8, device: Rc<RefCell<dyn OutputDevice>>) {
+        self.map_output_aliased(port, 0xff, device);
+    }
+
+    /// Attaches `device` to `port` for both IN and OUT.
+    pub fn map<D: InputDevice + OutputDevice + 'static>(&mut self, port: u8, device: Rc<RefCell<D>>) {
+        self.map_aliased(port, 0xff, device);
+    }
+
+    /// Attaches `device` for IN on every port equal to `port` in the bits
+    /// set in `mask`. With a mask of 0x07, a device at port 2 also answers
+    /// on 0x0a, 0x12 and so on.
+    pub fn map_input_aliased(&mut self, port: u8, mask: u8, device: Rc<RefCell<dyn InputDevice>>) {
+        for alias in aliases(port, mask) {
+            self.inputs[usize::from(alias)] = Some((port, device.clone()));
+        }
+    }
+
+    /// Attaches `device` for OUT on every alias of `port` under `mask`.
+    pub fn map_output_aliased(&mut self, port: u8, mask: u8, device: Rc<RefCell<dyn OutputDevice>>) {
+        for alias in aliases(port, mask) {
+            self.outputs[usize::from(alias)] = Some((port, device.clone()));
+        }
+    }
+
+    /// Attaches `device` for both IN and OUT on every alias of `port`
+    /// under `mask`.
+    pub fn map_aliased<D: InputDevice + OutputDevice + 'static>(&mut self, port: u8, mask: u8, device: Rc<RefCell<D>>) {
+        self.map_input_aliased(port, mask, device.clone());
+        self.map_output_aliased(port, mask, device);
+    }
+
+    pub fn is_mapped(&self, port: u8) -> bool {
+        self.inputs[usize::from(port)].is_some() || self.outputs[usize::from(port)].is_some()
+    }
+
+    /// Reads `port`, returning what the floating bus gives if nothing is
+    /// attached. A custom source gets the cycle count of the last access
+    /// resolved with `dispatch`.
+    pub fn read(&mut self, port: u8) -> u8 {
+        let value = match &self.inputs[usize::from(port)] {
+            Some((attached, device)) => device.borrow_mut().input(*attached),
+            None => match &mut self.floating {
+                Floating::Zeros => 0x00,
+                Floating::Ones => UNMAPPED_INPUT,
+                Floating::LastValue => self.last,
+                Floating::Custom(source) => source(port, self.now),
+            },
+        };
+        self.last = value;
+        value
+    }
+
+    /// Writes `value` to `port`. Writes to unmapped ports are dropped.
+    pub fn write(&mut self, port: u8, value: u8) {
+        self.last = value;
+        if let Some((attached, device)) = &self.outputs[usize::from(port)] {
+            device.borrow_mut().output(*attached, value);
+        }
+    }
+
+    /// Resolves the port access behind `event`, which `cpu` just returned
+    /// from `exec`. Other events are left alone.
+    pub fn dispatch<M: Memory>(&mut self, cpu: &mut CPU<M>, event: &Event) {
+        self.now = cpu.cycles();
+        match *event {
+            Event::Input(port, _) => {
+                let value = self.read(port);
+                cpu.complete_input(value);
+            }
+            Event::Output(port, value, _) => self.write(port, value),
+            _ => {}
+        }
+    }
+
+    /// Executes one instruction on `cpu` and resolves any port access it
+    /// makes. The event is returned for the caller's bookkeeping; IN and
+    /// OUT need no further handling.
+    pub fn step<M: Memory>(&mut self, cpu: &mut CPU<M>) -> Event {
+        let op = cpu.fetch();
+        let event = cpu.exec(op);
+        self.dispatch(cpu, &event);
+        event
+    }
+
+    /// Runs `cpu` for at least `cycles` clock cycles as `CPU::run_for`
+    /// does, resolving port accesses along the way. Returns the cycles
+    /// actually run.
+    pub fn run_for<M: Memory>(&mut self, cpu: &mut CPU<M>, cycles: Timestamp) -> Timestamp {
+        let mut elapsed = 0;
+        while elapsed < cycles {
+            match self.step(cpu) {
+                Event::Fault(_) => break,
+                event => elapsed += Timestamp::from(event.cycles()),
+            }
+        }
+        elapsed
+    }
+}
+
+// Every port number matching `port` in the bits set in `mask`.
+fn aliases(port: u8, mask: u8) -> impl Iterator<Item = u8> {
+    (0..=0xffu8).filter(move |p| p & mask == port & mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::io::{Floating, InputDevice, OutputDevice, PortBus, UNMAPPED_INPUT};
+    use crate::memory::Memory8080;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // The Space Invaders barrel shifter: OUT 2 sets the shift amount, OUT 4
+    // shifts a byte in, IN 3 reads the result.
+    #[derive(Default)]
+    struct Shifter {
+        value: u16,
+        offset: u8,
+    }
+
+    impl InputDevice for Shifter {
+        fn input(&mut self, _port: u8) -> u8 {
+            (self.value >> (8 - self.offset)) as u8
+        }
+    }
+
+    impl OutputDevice for Shifter {
+        fn output(&mut self, port: u8, value: u8) {
+            match port {
+                2 => self.offset = value & 0x07,
+                _ => self.value = (self.value >> 8) | (u16::from(value) << 8),
+            }
+        }
+    }
+
+    // Remembers the last byte written and which ports it was accessed as
+    #[derive(Default)]
+    struct Latch {
+        value: u8,
+        ports: Vec<u8>,
+    }
+
+    impl InputDevice for Latch {
+        fn input(&mut self, port: u8) -> u8 {
+            self.ports.push(port);
+            self.value
+        }
+    }
+
+    impl OutputDevice for Latch {
+        fn output(&mut self, port: u8, value: u8) {
+            self.ports.push(port);
+            self.value = value;
+        }
+    }
+
+    fn cpu_with(code: &[u8]) -> CPU {
+        let mut memory = [0x00; 0x10000];
+        memory[..code.len()].copy_from_slice(code);
+        CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))))
+    }
+
+    #[test]
+    fn shift_register() {
+        // MVI A, 0xab; OUT 4; MVI A, 0xcd; OUT 4; MVI A, 4; OUT 2; IN 3
+        let mut cpu = cpu_with(&[0x3e, 0xab, 0xd3, 0x04, 0x3e, 0xcd, 0xd3, 0x04,
+                                 0x3e, 0x04, 0xd3, 0x02, 0xdb, 0x03]);
+        let shifter = Rc::new(RefCell::new(Shifter::default()));
+        let mut bus = PortBus::new();
+        bus.map_output(0x02, shifter.clone());
+        bus.map(0x03, shifter.clone());
+        bus.map_output(0x04, shifter);
+        for _ in 0..7 {
+            bus.step(&mut cpu);
+        }
+        assert_eq!(cpu.regs.a, 0xda);
+    }
+
+    #[test]
+    fn unmapped_ports() {
+        // OUT 0x10; IN 0x11
+        let mut cpu = cpu_with(&[0xd3, 0x10, 0xdb, 0x11]);
+        let mut bus = PortBus::new();
+        bus.step(&mut cpu);
+        bus.step(&mut cpu);
+        assert_eq!(cpu.regs.a, UNMAPPED_INPUT);
+        assert!(!bus.is_mapped(0x10));
+    }
+
+    #[test]
+    fn run_for_resolves_ports() {
+        // OUT 0x01, over and over
+        let mut cpu = cpu_with(&[0xd3, 0x01].repeat(0x100));
+        let latch = Rc::new(RefCell::new(Latch::default()));
+        let mut bus = PortBus::new();
+        bus.map(0x01, latch.clone());
+        assert_eq!(bus.run_for(&mut cpu, 100), 100);
+        assert_eq!(latch.borrow().ports.len(), 10);
+    }
+
+    #[test]
+    fn aliased_ports() {
+        // MVI A, 0x12; OUT 0x14; IN 0xf4
+        let mut cpu = cpu_with(&[0x3e, 0x12, 0xd3, 0x14, 0xdb, 0xf4]);
+        let latch = Rc::new(RefCell::new(Latch::default()));
+        let mut bus = PortBus::new();
+        bus.map_aliased(0x04, 0x0f, latch.clone());
+        for _ in 0..3 {
+            bus.step(&mut cpu);
+        }
+        assert_eq!(cpu.regs.a, 0x12);
+        assert_eq!(latch.borrow().ports, [0x04, 0x04]);
+        assert!(bus.is_mapped(0x14));
+        assert!(!bus.is_mapped(0x05));
+    }
+
+    #[test]
+    fn floating_bus() {
+        let mut bus = PortBus::new();
+        assert_eq!(bus.read(0x40), UNMAPPED_INPUT);
+        bus.set_floating(Floating::Zeros);
+        assert_eq!(bus.read(0x40), 0x00);
+        bus.set_floating(Floating::LastValue);
+        bus.write(0x41, 0x5a);
+        assert_eq!(bus.read(0x40), 0x5a);
+
+        // MVI A, 0x12; IN 0x40; MOV B, A; IN 0x41
+        let mut cpu = cpu_with(&[0x3e, 0x12, 0xdb, 0x40, 0x47, 0xdb, 0x41]);
+        bus.set_floating(Floating::noise(|port, cycle| port ^ cycle as u8));
+        for _ in 0..4 {
+            bus.step(&mut cpu);
+        }
+        assert_eq!((cpu.regs.b, cpu.regs.a), (0x40 ^ 17, 0x41 ^ 32));
+    }
+}
@@ -0,0 +1,198 @@
+//! The INT line, as devices drive it.
+//!
+//! `CPU::interrupt` is a one-shot request: if interrupts are disabled at
+//! that moment, it is simply refused. On a board, a device raises the INT
+//! line and it stays raised until the device lowers it, typically when
+//! the program acknowledges it through a port, so a request made while
+//! interrupts are disabled is taken as soon as EI lets it through. An
+//! `IrqLine` models that line and asks the CPU to accept it between
+//! instructions, with one of two behaviours:
+//!
+//! - `Trigger::Level`: the request is taken whenever the line is raised
+//!   and interrupts are enabled. A handler that runs EI before the device
+//!   has lowered the line is interrupted again straight away, as on real
+//!   hardware.
+//! - `Trigger::Edge`: raising the line latches one request, which is
+//!   taken once, however long the line stays up, and is not lost if the
+//!   line drops again before interrupts are enabled.
+//!
+//! ```
+//! use i8080_emulator::cpu::CPU;
+//! use i8080_emulator::irq::{IrqLine, Trigger};
+//! use i8080_emulator::memory::{Memory, Memory8080};
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! // DI; NOP; EI; NOP ... 0x0008 (RST 1): HLT
+//! let mut memory = [0x00; 0x10000];
+//! memory[..3].copy_from_slice(&[0xf3, 0x00, 0xfb]);
+//! memory[0x08] = 0x76;
+//! let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+//! let mut irq = IrqLine::new(Trigger::Level);
+//! irq.raise(0xcf);
+//! while !cpu.is_halted() {
+//!     if irq.service(&mut cpu).is_none() {
+//!         cpu.step();
+//!     }
+//! }
+//! // Taken once EI had run
+//! assert_eq!(cpu.memory.borrow().read16(0xfffe), 0x0003);
+//! ```
+
+use crate::cpu::{Event, CPU};
+use crate::memory::Memory;
+
+/// When a raised line is taken. See the module documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Level,
+    Edge,
+}
+
+/// The CPU's INT input. Devices sharing it hold it through
+/// `Rc<RefCell<IrqLine>>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IrqLine {
+    trigger: Trigger,
+    // The instruction the device puts on the bus while the line is up
+    raised: Option<u8>,
+    // A rising edge not yet taken
+    latched: Option<u8>,
+}
+
+impl IrqLine {
+    pub fn new(trigger: Trigger) -> Self {
+        IrqLine {
+            trigger,
+            raised: None,
+            latched: None,
+        }
+    }
+
+    /// Raises the line, with `op`, usually an RST, as the instruction the
+    /// device supplies on acknowledge. Raising it again while it is up
+    /// only changes `op`.
+    pub fn raise(&mut self, op: u8) {
+        if self.raised.is_none() || self.latched.is_some() {
+            self.latched = Some(op);
+        }
+        self.raised = Some(op);
+    }
+
+    /// Lowers the line. A latched edge stays pending.
+    pub fn lower(&mut self) {
+        self.raised = None;
+    }
+
+    pub fn is_raised(&self) -> bool {
+        self.raised.is_some()
+    }
+
+    /// Whether the CPU would take the line if interrupts were enabled.
+    pub fn is_pending(&self) -> bool {
+        match self.trigger {
+            Trigger::Level => self.raised.is_some(),
+            Trigger::Edge => self.latched.is_some(),
+        }
+    }
+
+    /// Has the CPU accept the line if it is pending and the CPU will take
+    /// it, returning the event of the instruction supplied. Call it
+    /// between instructions; `None` means step the CPU as usual.
+    pub fn service<M: Memory>(&mut self, cpu: &mut CPU<M>) -> Option<Event> {
+        let op = match self.trigger {
+            Trigger::Level => self.raised?,
+            Trigger::Edge => self.latched?,
+        };
+        let event = cpu.interrupt(op)?;
+        self.latched = None;
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::irq::{IrqLine, Trigger};
+    use crate::memory::Memory8080;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // 0x0000: LXI SP, 0x2000; DI; NOP; NOP; EI; loop: JMP loop
+    // 0x0010 (RST 2): INR B; EI; RET
+    fn cpu() -> CPU {
+        let mut memory = [0x00; 0x10000];
+        memory[..10].copy_from_slice(&[0x31, 0x00, 0x20, 0xf3, 0x00, 0x00, 0xfb, 0xc3, 0x07, 0x00]);
+        memory[0x10..0x13].copy_from_slice(&[0x04, 0xfb, 0xc9]);
+        CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))))
+    }
+
+    // Runs `steps` instructions or interrupts, calling `device` with the
+    // CPU, the line and the step number before each
+    fn run(cpu: &mut CPU, irq: &mut IrqLine, steps: usize, mut device: impl FnMut(&CPU, &mut IrqLine, usize)) {
+        for step in 0..steps {
+            device(cpu, irq, step);
+            if irq.service(cpu).is_none() {
+                cpu.step();
+            }
+        }
+    }
+
+    #[test]
+    fn level_is_taken_again_until_lowered() {
+        let mut cpu = cpu();
+        let mut irq = IrqLine::new(Trigger::Level);
+        irq.raise(0xd7);
+        run(&mut cpu, &mut irq, 12, |_, _, _| {});
+        // Taken after the first EI, then again after each handler's EI, at
+        // steps 5, 8 and 11
+        assert_eq!(cpu.regs.b, 2);
+        assert!(irq.is_pending());
+
+        // Lowered from the handler, as a device acknowledged through a
+        // port would: taken once
+        let mut cpu = self::cpu();
+        let mut irq = IrqLine::new(Trigger::Level);
+        irq.raise(0xd7);
+        run(&mut cpu, &mut irq, 20, |cpu, irq, _| {
+            if cpu.pc == 0x0010 {
+                irq.lower();
+            }
+        });
+        assert_eq!(cpu.regs.b, 1);
+    }
+
+    #[test]
+    fn missed_and_double_interrupts() {
+        // A pulse while interrupts are disabled is lost on a level line
+        // but kept on an edge one
+        for (trigger, taken) in [(Trigger::Level, 0), (Trigger::Edge, 1)] {
+            let mut cpu = cpu();
+            let mut irq = IrqLine::new(trigger);
+            run(&mut cpu, &mut irq, 12, |_, irq, step| match step {
+                2 => irq.raise(0xd7),
+                3 => irq.lower(),
+                _ => {}
+            });
+            assert_eq!(cpu.regs.b, taken, "{:?}", trigger);
+        }
+
+        // Raised twice without being lowered: one edge, one interrupt
+        let mut cpu = cpu();
+        let mut irq = IrqLine::new(Trigger::Edge);
+        run(&mut cpu, &mut irq, 12, |_, irq, step| {
+            if step == 2 || step == 3 {
+                irq.raise(0xd7);
+            }
+        });
+        assert_eq!(cpu.regs.b, 1);
+        assert!(irq.is_raised() && !irq.is_pending());
+
+        // Lowered and raised again: two
+        irq.lower();
+        irq.raise(0xd7);
+        run(&mut cpu, &mut irq, 4, |_, _, _| {});
+        assert_eq!(cpu.regs.b, 2);
+    }
+}
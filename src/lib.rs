@@ -1,10 +1,173 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
 pub mod cpu;
 pub mod memory;
+pub mod video;
 pub mod registers;
+pub mod i8085;
 pub mod device;
+pub mod io;
 pub mod disassembler;
+pub mod symbols;
+#[cfg(feature = "std")]
+pub mod analysis;
+pub mod alu;
+pub mod opcodes;
+pub mod error;
+#[cfg(feature = "std")]
+pub mod devices;
+#[cfg(feature = "std")]
+pub mod loader;
+#[cfg(feature = "std")]
+pub mod timeline;
+#[cfg(feature = "std")]
+pub mod flag_usage;
+#[cfg(feature = "std")]
+pub mod explain;
+#[cfg(feature = "std")]
+pub mod monitor;
+#[cfg(feature = "std")]
+pub mod bare;
+pub mod hooks;
+#[cfg(feature = "std")]
+pub mod machines;
+pub mod speedhack;
+#[cfg(feature = "std")]
+pub mod debugger;
+pub mod fixed;
+pub mod contention;
+#[cfg(feature = "std")]
+pub mod assembler;
+#[cfg(feature = "std")]
+pub mod linker;
+#[cfg(feature = "std")]
+pub mod terminal;
+#[cfg(feature = "std")]
+pub mod shutdown;
+#[cfg(feature = "std")]
+pub mod golden;
+#[cfg(feature = "std")]
+mod inflate;
+#[cfg(feature = "std")]
+pub mod bridge;
+pub mod irq;
+pub mod hostcall;
+pub mod scheduler;
+#[cfg(feature = "std")]
+pub mod clock;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "std")]
+pub mod profiler;
+pub mod coverage;
+#[cfg(feature = "std")]
+pub mod heatmap;
+#[cfg(feature = "std")]
+pub mod repl;
+#[cfg(feature = "std")]
+pub mod handle;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "threaded")]
+pub mod threaded;
+
+use crate::cpu::{ClockCycles, CoreCapabilities, CpuState, Event, Timestamp};
+use crate::error::EmulatorError;
+
+use core::convert::TryFrom;
+
+/// How long `Machine::run_with_limit` lets a program go on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// Steps of the machine, usually one instruction each.
+    Steps(u64),
+    /// Clock cycles.
+    Cycles(Timestamp),
+}
 
 pub trait Machine {
-     fn next(&mut self);
-     fn run(&mut self);
+    /// What a single step of the machine reports back to the caller.
+    type Event;
+
+    fn next(&mut self) -> Result<Self::Event, EmulatorError>;
+    fn run(&mut self) -> Result<(), EmulatorError>;
+    /// Pulls the RESET line: the CPU starts over at the machine's reset
+    /// address with interrupts disabled and devices go back to their
+    /// power-on state. Memory is left as it is.
+    fn reset(&mut self);
+    /// Clock cycles run since the machine was created.
+    fn cycles(&self) -> Timestamp;
+
+    /// Whether the machine has got to where `run` stops. Machines that
+    /// only stop on an error are never finished.
+    fn is_finished(&self) -> bool {
+        false
+    }
+
+    /// Same as `run`, but gives up with `EmulatorError::StepLimit` or
+    /// `EmulatorError::CycleLimit` once the program has had `limit`, so a
+    /// program stuck in a loop cannot hang the caller. The limit counts
+    /// from this call, not from when the machine was created.
+    fn run_with_limit(&mut self, limit: Limit) -> Result<(), EmulatorError> {
+        let start = self.cycles();
+        let mut steps = 0;
+        while !self.is_finished() {
+            match limit {
+                Limit::Steps(max) if steps >= max => return Err(EmulatorError::StepLimit(max)),
+                Limit::Cycles(max) if self.cycles().wrapping_sub(start) >= max => {
+                    return Err(EmulatorError::CycleLimit(max));
+                }
+                _ => {}
+            }
+            self.next()?;
+            steps += 1;
+        }
+        Ok(())
+    }
+
+    /// Steps the machine until at least `cycles` more clock cycles have
+    /// gone by, for a front-end that runs it in slices from its own event
+    /// loop. Returns how many cycles actually went by, which may overshoot
+    /// by part of an instruction, or `ClockCycles::MAX` if more did.
+    fn run_cycles(&mut self, cycles: ClockCycles) -> Result<ClockCycles, EmulatorError> {
+        let start = self.cycles();
+        while self.cycles().wrapping_sub(start) < Timestamp::from(cycles) {
+            self.next()?;
+        }
+        Ok(ClockCycles::try_from(self.cycles().wrapping_sub(start)).unwrap_or(ClockCycles::MAX))
+    }
+
+    /// Steps the machine until `done` says to stop, checking it before
+    /// every step.
+    fn run_until(&mut self, mut done: impl FnMut(&Self) -> bool) -> Result<(), EmulatorError>
+    where
+        Self: Sized,
+    {
+        while !done(self) {
+            self.next()?;
+        }
+        Ok(())
+    }
+}
+
+/// The interface of an 8080 execution engine. `cpu::CPU`, the reference
+/// interpreter, implements it, so alternative backends can be dropped in
+/// behind the same interface and checked against it instruction by
+/// instruction.
+pub trait I8080Core {
+    /// Executes one instruction.
+    fn step(&mut self) -> Event;
+    fn state(&self) -> CpuState;
+    fn set_state(&mut self, state: &CpuState);
+    /// Accepts an interrupt if interrupts are enabled, executing `op` as
+    /// the instruction on the data bus, usually an RST. Returns `None` if
+    /// the interrupt was not accepted. Same as `CPU::interrupt`.
+    fn interrupt(&mut self, op: u8) -> Option<Event>;
+    /// Accepts an interrupt vectoring to `addr` if interrupts are enabled,
+    /// returning `None` otherwise. Same as `CPU::inter_handle`.
+    fn interrupt_at(&mut self, addr: u16) -> Option<Event>;
+    /// The accuracy features this core has, as it is configured now.
+    fn capabilities(&self) -> CoreCapabilities;
 }
@@ -0,0 +1,294 @@
+//! Puts programs assembled a module at a time together.
+//!
+//! `assembler::assemble_object` turns each source file into an `Object`:
+//! its code assembled as if it started at 0, the offsets of the words in
+//! it that hold addresses within it, the symbols it makes public and the
+//! places where it uses other modules' symbols. `link` lays the modules
+//! out one after the other from any address, fixes all of those up and
+//! returns a binary for `loader::load_bytes` or a ROM at that address.
+//!
+//! Objects can be kept in files between assembling and linking with
+//! `Object::write` and `Object::read`. The format, all numbers little
+//! endian, is `MAGIC`, then the code as a u16 length and the bytes, the
+//! relocations as a u16 count and u16 offsets, the publics as a u16 count
+//! and for each a u8 name length, the name, the u16 value and 1 if it is
+//! relocatable, else 0, and the externals as a u16 count and for each the
+//! u16 offset, a u8 name length and the name.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// The bytes an object file starts with, the last being the format
+/// version.
+pub const MAGIC: [u8; 4] = *b"I8O\x01";
+
+/// One module of a program, as assembled by `assemble_object`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Object {
+    /// The code, as if loaded at 0.
+    pub code: Vec<u8>,
+    /// Offsets of the words in `code` that hold an address in the module,
+    /// to which the module's load address is added.
+    pub relocations: Vec<u16>,
+    pub publics: Vec<Public>,
+    pub externals: Vec<External>,
+}
+
+/// A symbol a module makes available to the others.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Public {
+    pub name: String,
+    pub value: u16,
+    /// Whether `value` is an address in the module, to which its load
+    /// address is added, rather than a constant.
+    pub relocatable: bool,
+}
+
+/// A word in a module to which the value of another module's symbol is
+/// added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct External {
+    pub offset: u16,
+    pub name: String,
+}
+
+/// Why `link` could not put the modules together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    /// No module makes this symbol public.
+    UndefinedSymbol(String),
+    /// More than one module makes this symbol public.
+    DuplicateSymbol(String),
+    /// The modules end at this address, past the top of memory.
+    TooLarge(u32),
+    /// A relocation or external at this offset in module `module`, counting
+    /// from 0, is not a whole word inside its code.
+    BadOffset { module: usize, offset: u16 },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::UndefinedSymbol(name) => write!(f, "undefined symbol `{}`", name),
+            LinkError::DuplicateSymbol(name) => write!(f, "`{}` is public in more than one module", name),
+            LinkError::TooLarge(end) => write!(f, "program runs to 0x{:x}, past the top of memory", end),
+            LinkError::BadOffset { module, offset } => write!(f, "module {} has a bad fixup offset 0x{:04x}", module, offset),
+        }
+    }
+}
+
+impl Error for LinkError {}
+
+/// Lays `objects` out one after the other from `org`, in order, and
+/// returns the program with every relocation and external symbol fixed
+/// up, ready to load at `org`.
+pub fn link(objects: &[Object], org: u16) -> Result<Vec<u8>, LinkError> {
+    // Where each module goes
+    let mut bases = Vec::with_capacity(objects.len());
+    let mut end = u32::from(org);
+    for object in objects {
+        bases.push(end as u16);
+        end += object.code.len() as u32;
+    }
+    if end > 0x10000 {
+        return Err(LinkError::TooLarge(end));
+    }
+
+    let mut symbols = HashMap::new();
+    for (object, &base) in objects.iter().zip(&bases) {
+        for public in &object.publics {
+            let value = if public.relocatable { public.value.wrapping_add(base) } else { public.value };
+            if symbols.insert(public.name.as_str(), value).is_some() {
+                return Err(LinkError::DuplicateSymbol(public.name.clone()));
+            }
+        }
+    }
+
+    let mut program = Vec::with_capacity((end - u32::from(org)) as usize);
+    for (module, (object, &base)) in objects.iter().zip(&bases).enumerate() {
+        let mut code = object.code.clone();
+        let mut add = |offset: u16, value: u16| {
+            let at = usize::from(offset);
+            match code.get(at..at + 2) {
+                Some(word) => {
+                    let fixed = u16::from_le_bytes([word[0], word[1]]).wrapping_add(value);
+                    code[at..at + 2].copy_from_slice(&fixed.to_le_bytes());
+                    Ok(())
+                }
+                None => Err(LinkError::BadOffset { module, offset }),
+            }
+        };
+        for &offset in &object.relocations {
+            add(offset, base)?;
+        }
+        for external in &object.externals {
+            let value = *symbols
+                .get(external.name.as_str())
+                .ok_or_else(|| LinkError::UndefinedSymbol(external.name.clone()))?;
+            add(external.offset, value)?;
+        }
+        program.extend_from_slice(&code);
+    }
+    Ok(program)
+}
+
+impl Object {
+    /// Writes the object in the format described in the module
+    /// documentation. Code past 64K or a name longer than 255 bytes is an
+    /// `InvalidInput` error.
+    pub fn write(&self, mut output: impl Write) -> io::Result<()> {
+        let count = |len: usize| {
+            u16::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "object too large"))
+        };
+        let name = |name: &str| {
+            u8::try_from(name.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "symbol name too long"))
+        };
+        output.write_all(&MAGIC)?;
+        output.write_all(&count(self.code.len())?.to_le_bytes())?;
+        output.write_all(&self.code)?;
+        output.write_all(&count(self.relocations.len())?.to_le_bytes())?;
+        for offset in &self.relocations {
+            output.write_all(&offset.to_le_bytes())?;
+        }
+        output.write_all(&count(self.publics.len())?.to_le_bytes())?;
+        for public in &self.publics {
+            output.write_all(&[name(&public.name)?])?;
+            output.write_all(public.name.as_bytes())?;
+            output.write_all(&public.value.to_le_bytes())?;
+            output.write_all(&[u8::from(public.relocatable)])?;
+        }
+        output.write_all(&count(self.externals.len())?.to_le_bytes())?;
+        for external in &self.externals {
+            output.write_all(&external.offset.to_le_bytes())?;
+            output.write_all(&[name(&external.name)?])?;
+            output.write_all(external.name.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reads an object written by `write`.
+    pub fn read(mut input: impl Read) -> io::Result<Self> {
+        let mut magic = [0x00; 4];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an object file"));
+        }
+        let len = word(&mut input)?;
+        let mut object = Object { code: bytes(&mut input, usize::from(len))?, ..Object::default() };
+        for _ in 0..word(&mut input)? {
+            object.relocations.push(word(&mut input)?);
+        }
+        for _ in 0..word(&mut input)? {
+            let name = name(&mut input)?;
+            let value = word(&mut input)?;
+            let relocatable = bytes(&mut input, 1)?[0] != 0;
+            object.publics.push(Public { name, value, relocatable });
+        }
+        for _ in 0..word(&mut input)? {
+            let offset = word(&mut input)?;
+            object.externals.push(External { offset, name: name(&mut input)? });
+        }
+        Ok(object)
+    }
+}
+
+fn bytes(input: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0x00; len];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn word(input: &mut impl Read) -> io::Result<u16> {
+    let mut word = [0x00; 2];
+    input.read_exact(&mut word)?;
+    Ok(u16::from_le_bytes(word))
+}
+
+fn name(input: &mut impl Read) -> io::Result<String> {
+    let len = bytes(input, 1)?[0];
+    String::from_utf8(bytes(input, usize::from(len))?)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "symbol name is not UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{assemble, assemble_object, AsmErrorKind};
+    use crate::linker::{link, LinkError, Object};
+
+    const MAIN: &str = "
+            EXTRN print, buffer
+            PUBLIC start, SIZE
+    SIZE    EQU 16
+    start:  LXI  H, message
+            LXI  D, buffer + 2
+            CALL print
+            JMP  start
+    message: DB 'ok', 0
+            DW   message - start, $
+    ";
+
+    const PRINT: &str = "
+            PUBLIC print, buffer
+    print:  MVI  B, 16
+            JMP  print
+    buffer: DB   0, 0, 0, 0
+    ";
+
+    #[test]
+    fn link_at_any_address() {
+        let print = assemble_object(PRINT).unwrap();
+        let main = assemble_object(MAIN).unwrap();
+        assert_eq!(main.relocations, [0x0001, 0x000a, 0x0011]);
+        assert_eq!(main.externals.iter().map(|e| (e.offset, e.name.as_str())).collect::<Vec<_>>(),
+                   [(0x0004, "BUFFER"), (0x0007, "PRINT")]);
+
+        // The same as assembling it all in one go at each address
+        for &org in &[0x0000, 0x0100, 0xe000] {
+            let whole = assemble(&format!("
+                    LXI  H, message
+                    LXI  D, buffer + 2
+                    CALL print
+                    JMP  $ - 9
+            message: DB 'ok', 0
+                    DW   message - 0x{org:x}, $
+            print:  MVI  B, 16
+                    JMP  print
+            buffer: DB 0, 0, 0, 0
+            ", org = org), org).unwrap();
+            assert_eq!(link(&[main.clone(), print.clone()], org).unwrap(), whole);
+        }
+    }
+
+    #[test]
+    fn object_files() {
+        let main = assemble_object(MAIN).unwrap();
+        let mut file = Vec::new();
+        main.write(&mut file).unwrap();
+        assert_eq!(Object::read(&file[..]).unwrap(), main);
+        assert!(Object::read(&file[..file.len() - 1]).is_err());
+        assert!(Object::read(&b"I80\x01"[..]).is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| assemble_object(source).unwrap_err().kind;
+        assert_eq!(error("x: MVI A, x"), AsmErrorKind::NotRelocatable("x".into()));
+        assert_eq!(error("x: DW x + x"), AsmErrorKind::NotRelocatable("x + x".into()));
+        assert_eq!(error("EXTRN a, b\nDW a + b"), AsmErrorKind::NotRelocatable("a + b".into()));
+        assert_eq!(error("EXTRN a\nDW -a"), AsmErrorKind::NotRelocatable("-a".into()));
+        assert_eq!(error("PUBLIC nothing"), AsmErrorKind::UndefinedSymbol("NOTHING".into()));
+        assert_eq!(assemble("EXTRN a", 0).unwrap_err().kind, AsmErrorKind::Syntax("EXTRN outside an object module"));
+
+        let print = assemble_object("PUBLIC print\nprint: RET").unwrap();
+        let main = assemble_object("EXTRN print, exit\nCALL print\nJMP exit").unwrap();
+        assert_eq!(link(&[main.clone(), print.clone()], 0), Err(LinkError::UndefinedSymbol("EXIT".into())));
+        assert_eq!(link(&[print.clone(), print.clone()], 0), Err(LinkError::DuplicateSymbol("PRINT".into())));
+        assert_eq!(link(&[main], 0xfffc), Err(LinkError::TooLarge(0x10002)));
+        let mut broken = print;
+        broken.relocations.push(0x0000);
+        assert_eq!(link(&[broken], 0), Err(LinkError::BadOffset { module: 0, offset: 0x0000 }));
+    }
+}
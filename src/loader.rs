@@ -0,0 +1,353 @@
+//! Building memory images from ROM files.
+//!
+//! A `LoadMap` lists which file fragments go where, so sets split over
+//! several EPROMs (or with gaps between them) can be loaded in one go:
+//!
+//! ```no_run
+//! use i8080_emulator::loader::LoadMap;
+//!
+//! let image = LoadMap::new(0x00)
+//!     .file("invaders.h", 0x0000)
+//!     .file("invaders.g", 0x0800)
+//!     .fragment("invaders.ef", 0x0800, 0x0800, 0x1800)
+//!     .load()
+//!     .unwrap();
+//! ```
+//!
+//! Single files go straight into any `Memory` with `load_file` for raw
+//! binaries and `load_hex` or `load_hex_file` for Intel HEX, or into a
+//! zero-filled image with `load_image`.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::memory::Memory;
+
+/// Why a memory image could not be built.
+#[derive(Debug)]
+pub enum LoadError {
+    /// A file could not be opened or read.
+    Io(PathBuf, io::Error),
+    /// `len` bytes placed at `addr` would run past 0xffff.
+    Overflow { addr: u16, len: usize },
+    /// The file ended before the requested fragment did.
+    ShortFile { path: PathBuf, wanted: usize, got: usize },
+    /// Reading from a source other than a file failed.
+    Read(io::Error),
+    /// Line `line` (counting from 1) is not a valid Intel HEX record.
+    BadHex { line: usize, reason: &'static str },
+    /// Line `line` (counting from 1) of a symbol file is not valid.
+    BadSymbol { line: usize, reason: &'static str },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            LoadError::Overflow { addr, len } => {
+                write!(f, "{} bytes at 0x{:04x} do not fit in the address space", len, addr)
+            }
+            LoadError::ShortFile { path, wanted, got } => {
+                write!(f, "{}: wanted {} bytes but the file only has {}", path.display(), wanted, got)
+            }
+            LoadError::Read(e) => write!(f, "{}", e),
+            LoadError::BadHex { line, reason } | LoadError::BadSymbol { line, reason } => {
+                write!(f, "line {}: {}", line, reason)
+            }
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io(_, e) | LoadError::Read(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// One entry of a `LoadMap`: `length` bytes of `path`, starting `offset`
+/// bytes into the file, placed at `addr`. A `length` of `None` takes the
+/// rest of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: Option<usize>,
+    pub addr: u16,
+}
+
+/// Describes a 64K memory image as file fragments placed at fixed
+/// addresses. Bytes not covered by any segment hold the fill byte; where
+/// segments overlap, the later one wins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadMap {
+    fill: u8,
+    segments: Vec<Segment>,
+}
+
+impl LoadMap {
+    pub fn new(fill: u8) -> Self {
+        LoadMap {
+            fill,
+            segments: Vec::new(),
+        }
+    }
+
+    /// Places the whole of `path` at `addr`.
+    pub fn file(self, path: impl Into<PathBuf>, addr: u16) -> Self {
+        self.segment(Segment { path: path.into(), offset: 0, length: None, addr })
+    }
+
+    /// Places `length` bytes of `path`, starting `offset` bytes in, at
+    /// `addr`.
+    pub fn fragment(self, path: impl Into<PathBuf>, offset: u64, length: usize, addr: u16) -> Self {
+        self.segment(Segment { path: path.into(), offset, length: Some(length), addr })
+    }
+
+    pub fn segment(mut self, segment: Segment) -> Self {
+        self.segments.push(segment);
+        self
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Reads every segment and returns the resulting memory image.
+    pub fn load(&self) -> Result<[u8; 0x10000], LoadError> {
+        let mut memory = [self.fill; 0x10000];
+        for segment in &self.segments {
+            let data = read_segment(segment)?;
+            check_fits(segment.addr, data.len())?;
+            let start = usize::from(segment.addr);
+            memory[start..start + data.len()].copy_from_slice(&data);
+        }
+        Ok(memory)
+    }
+}
+
+fn check_fits(addr: u16, len: usize) -> Result<(), LoadError> {
+    if len > 0x10000 - usize::from(addr) {
+        return Err(LoadError::Overflow { addr, len });
+    }
+    Ok(())
+}
+
+/// Copies `data` into `memory` starting at `addr` and returns the number of
+/// bytes written. Data of any length is accepted as long as it ends at or
+/// before 0xffff; otherwise nothing is written.
+///
+/// The bytes go through `Memory::write`, so load before restricting writes
+/// with a vector page policy.
+pub fn load_bytes(memory: &mut impl Memory, data: &[u8], addr: u16) -> Result<usize, LoadError> {
+    check_fits(addr, data.len())?;
+    for (i, b) in data.iter().enumerate() {
+        memory.write(usize::from(addr) + i, *b);
+    }
+    Ok(data.len())
+}
+
+/// Loads the whole of `path` into `memory` at `addr`, as `load_bytes` does.
+pub fn load_file(memory: &mut impl Memory, path: impl AsRef<Path>, addr: u16) -> Result<usize, LoadError> {
+    let path = path.as_ref();
+    let data = std::fs::read(path).map_err(|e| LoadError::Io(path.to_path_buf(), e))?;
+    load_bytes(memory, &data, addr)
+}
+
+/// Builds a memory image from the file at `path` placed at `addr`, with
+/// the rest of memory zeroed, ready for `Memory8080::new`. The file may be
+/// any size that fits above `addr`.
+pub fn load_image(path: impl AsRef<Path>, addr: u16) -> Result<[u8; 0x10000], LoadError> {
+    LoadMap::new(0x00).file(path.as_ref(), addr).load()
+}
+
+/// Loads the Intel HEX records read from `reader` into `memory` and
+/// returns the number of data bytes written. Reading stops at the
+/// end-of-file record. Start address records are ignored, as are
+/// extended address records for the first 64K; any that point above it
+/// are an error.
+pub fn load_hex(memory: &mut impl Memory, reader: impl BufRead) -> Result<usize, LoadError> {
+    let mut loaded = 0;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(LoadError::Read)?;
+        let bad = |reason| LoadError::BadHex { line: i + 1, reason };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let digits = line.strip_prefix(':').ok_or_else(|| bad("record does not start with ':'"))?;
+        if digits.len() % 2 != 0 || digits.len() < 10 {
+            return Err(bad("record too short"));
+        }
+        let bytes = (0..digits.len()).step_by(2)
+            .map(|j| digits.get(j..j + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| bad("not a hex digit"))?;
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(bad("checksum mismatch"));
+        }
+        let len = usize::from(bytes[0]);
+        if bytes.len() != len + 5 {
+            return Err(bad("length does not match the record"));
+        }
+        let addr = u16::from_be_bytes([bytes[1], bytes[2]]);
+        let data = &bytes[4..4 + len];
+        match bytes[3] {
+            0x00 => loaded += load_bytes(memory, data, addr)?,
+            0x01 => break,
+            0x02 | 0x04 if data.iter().any(|b| *b != 0) => return Err(bad("address beyond 64K")),
+            0x02..=0x05 => {}
+            _ => return Err(bad("unknown record type")),
+        }
+    }
+    Ok(loaded)
+}
+
+/// Loads the Intel HEX file at `path` into `memory`, as `load_hex` does.
+pub fn load_hex_file(memory: &mut impl Memory, path: impl AsRef<Path>) -> Result<usize, LoadError> {
+    let path = path.as_ref();
+    let f = File::open(path).map_err(|e| LoadError::Io(path.to_path_buf(), e))?;
+    load_hex(memory, BufReader::new(f))
+}
+
+fn read_segment(segment: &Segment) -> Result<Vec<u8>, LoadError> {
+    let io_error = |e| LoadError::Io(segment.path.clone(), e);
+    let mut f = File::open(&segment.path).map_err(io_error)?;
+    f.seek(SeekFrom::Start(segment.offset)).map_err(io_error)?;
+    let mut data = Vec::new();
+    match segment.length {
+        Some(length) => {
+            f.take(length as u64).read_to_end(&mut data).map_err(io_error)?;
+            if data.len() < length {
+                return Err(LoadError::ShortFile {
+                    path: segment.path.clone(),
+                    wanted: length,
+                    got: data.len(),
+                });
+            }
+        }
+        None => {
+            f.read_to_end(&mut data).map_err(io_error)?;
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::{load_bytes, load_file, load_hex, load_hex_file, load_image, LoadError, LoadMap};
+    use crate::memory::{Memory, Memory8080};
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    // Writes `data` to a file in the temp directory that is unique to the
+    // calling test.
+    fn rom(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("i8080_loader_{}_{}", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn segments_and_fill() {
+        let a = rom("segments_a", &[0x01, 0x02, 0x03]);
+        let b = rom("segments_b", &[0x10, 0x11, 0x12, 0x13]);
+        let image = LoadMap::new(0xff)
+            .file(&a, 0x0000)
+            .fragment(&b, 1, 2, 0x1000)
+            .load()
+            .unwrap();
+        assert_eq!(&image[0x0000..0x0004], &[0x01, 0x02, 0x03, 0xff]);
+        assert_eq!(&image[0x1000..0x1003], &[0x11, 0x12, 0xff]);
+        assert_eq!(image[0x8000], 0xff);
+    }
+
+    #[test]
+    fn later_segments_win() {
+        let a = rom("overlap_a", &[0x01, 0x01]);
+        let b = rom("overlap_b", &[0x02]);
+        let image = LoadMap::new(0x00).file(&a, 0x10).file(&b, 0x11).load().unwrap();
+        assert_eq!(&image[0x10..0x12], &[0x01, 0x02]);
+    }
+
+    #[test]
+    fn short_fragment() {
+        let a = rom("short", &[0x01, 0x02]);
+        match LoadMap::new(0x00).fragment(&a, 1, 4, 0x0000).load() {
+            Err(LoadError::ShortFile { wanted: 4, got: 1, .. }) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn overflow() {
+        let a = rom("overflow", &[0x00; 4]);
+        match LoadMap::new(0x00).file(&a, 0xfffe).load() {
+            Err(LoadError::Overflow { addr: 0xfffe, len: 4 }) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn missing_file() {
+        let result = LoadMap::new(0x00).file("/nonexistent/rom.bin", 0).load();
+        assert!(matches!(result, Err(LoadError::Io(..))));
+    }
+
+    #[test]
+    fn load_bytes_up_to_the_top() {
+        let mut memory = Memory8080::new_empty();
+        assert_eq!(load_bytes(&mut memory, &[0x01, 0x02, 0x03], 0xfffd).unwrap(), 3);
+        assert_eq!(memory.read(0xffff), 0x03);
+        assert!(matches!(load_bytes(&mut memory, &[0xaa; 4], 0xfffd),
+                         Err(LoadError::Overflow { addr: 0xfffd, len: 4 })));
+        assert_eq!(memory.read(0xfffd), 0x01);
+    }
+
+    #[test]
+    fn load_file_of_any_size() {
+        let small = rom("small", &[0xc3, 0x00, 0x01]);
+        let mut memory = Memory8080::new_empty();
+        assert_eq!(load_file(&mut memory, &small, 0x100).unwrap(), 3);
+        assert_eq!(memory.read16(0x101), 0x0100);
+        assert_eq!(load_file(&mut memory, rom("empty", &[]), 0x200).unwrap(), 0);
+
+        let image = load_image(&small, 0xfffd).unwrap();
+        assert_eq!((image[0x0000], image[0xfffd], image[0xffff]), (0x00, 0xc3, 0x01));
+        assert!(matches!(load_image(&small, 0xfffe), Err(LoadError::Overflow { addr: 0xfffe, len: 3 })));
+    }
+
+    #[test]
+    fn intel_hex() {
+        let hex = ":03010000210020BB\n\n:020000040000FA\n:010200007786\n:00000001FF\n:0100000000FF\n";
+        let mut memory = Memory8080::new_empty();
+        assert_eq!(load_hex(&mut memory, hex.as_bytes()).unwrap(), 4);
+        assert_eq!(memory.read16(0x101), 0x2000);
+        assert_eq!(memory.read(0x200), 0x77);
+        assert_eq!(load_hex_file(&mut memory, rom("hex", hex.as_bytes())).unwrap(), 4);
+    }
+
+    #[test]
+    fn bad_intel_hex() {
+        let mut memory = Memory8080::new_empty();
+        let reason = |hex: &str| match load_hex(&mut Memory8080::new_empty(), hex.as_bytes()) {
+            Err(LoadError::BadHex { line, reason }) => (line, reason),
+            other => panic!("unexpected result: {:?}", other),
+        };
+        assert_eq!(reason("0100"), (1, "record does not start with ':'"));
+        assert_eq!(reason(":0100000000FF\n:01020000778"), (2, "record too short"));
+        assert_eq!(reason(":010200007787"), (1, "checksum mismatch"));
+        assert_eq!(reason(":0102000077G6"), (1, "not a hex digit"));
+        assert_eq!(reason(":02020000778"), (1, "record too short"));
+        assert_eq!(reason(":020200007785"), (1, "length does not match the record"));
+        assert_eq!(reason(":020000040001F9"), (1, "address beyond 64K"));
+        assert!(matches!(load_hex(&mut memory, ":02FFFF00AABB9B".as_bytes()),
+                         Err(LoadError::Overflow { addr: 0xffff, len: 2 })));
+    }
+}
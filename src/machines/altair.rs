@@ -0,0 +1,264 @@
+//! An Altair 8800 as it was commonly set up to run BASIC: RAM from
+//! 0x0000, the front panel's sense switches on port 0xff and an 88-2SIO
+//! serial card on ports 0x10 (status and control) and 0x11 (data) as the
+//! console.
+//!
+//! The 2SIO's ACIA reports a received byte in bit 0 of its status and an
+//! empty transmitter in bit 1. Console output goes to the `Write` sink the
+//! machine was given and input comes from the reader set with
+//! `set_input`, such as stdin or a TCP stream. As with
+//! `cpm::CpmMachine`, a status read with no byte waiting reads ahead from
+//! the input, which blocks if the input is interactive.
+//!
+//! ```
+//! use i8080_emulator::machines::altair::AltairMachine;
+//! use i8080_emulator::Machine;
+//!
+//! // loop: IN 0x10; RRC; JNC loop; IN 0x11; OUT 0x11; JMP loop
+//! let echo = [0xdb, 0x10, 0x0f, 0xd2, 0x00, 0x00, 0xdb, 0x11, 0xd3, 0x11, 0xc3, 0x00, 0x00];
+//! let mut machine = AltairMachine::new(&echo, Vec::new()).unwrap();
+//! machine.set_input(&b"PRINT 2+2\r"[..]);
+//! machine.run().unwrap();
+//! assert_eq!(*machine.output(), b"PRINT 2+2\r");
+//! ```
+
+use crate::cpu::{Event, Timestamp, CPU};
+use crate::device::Device;
+use crate::error::EmulatorError;
+use crate::io::PortBus;
+use crate::loader::{load_bytes, load_file, LoadError};
+use crate::memory::Memory8080;
+use crate::Machine;
+
+use std::cell::{Ref, RefCell};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+/// The 88-2SIO's first port, the console: status and control here, data
+/// on the port after it.
+pub const SIO_PORT: u8 = 0x10;
+/// The front panel's sense switches, A8-A15.
+pub const SENSE_SWITCH_PORT: u8 = 0xff;
+
+/// ACIA status bit set while a received byte is waiting.
+pub const RDRF: u8 = 0x01;
+/// ACIA status bit set when the transmitter can take a byte.
+pub const TDRE: u8 = 0x02;
+
+/// The console channel of an 88-2SIO, bridged to a reader and a writer.
+struct Sio<W: Write> {
+    input: Box<dyn Read>,
+    output: W,
+    // A byte read ahead for the status port
+    received: Option<u8>,
+    // The guest polled with the input used up
+    starved: bool,
+    // The first write that failed, reported by the machine
+    error: Option<EmulatorError>,
+}
+
+impl<W: Write> Sio<W> {
+    fn poll(&mut self) {
+        if self.received.is_some() || self.error.is_some() {
+            return;
+        }
+        let mut byte = [0];
+        match self.input.read(&mut byte) {
+            Ok(0) => self.starved = true,
+            Ok(_) => self.received = Some(byte[0]),
+            Err(e) => self.error = Some(EmulatorError::Input(e.kind())),
+        }
+    }
+}
+
+impl<W: Write> Device for Sio<W> {
+    fn reset(&mut self) {
+        self.received = None;
+        self.starved = false;
+    }
+
+    fn read(&mut self, port: u8) -> u8 {
+        match port {
+            SIO_PORT => {
+                self.poll();
+                if self.received.is_some() { RDRF | TDRE } else { TDRE }
+            }
+            _ => self.received.take().unwrap_or(0x00),
+        }
+    }
+
+    // A control write of 0x03, the ACIA's master reset, is all BASIC ever
+    // needs; the line format bits change nothing here
+    fn write(&mut self, port: u8, value: u8) {
+        if port == SIO_PORT {
+            if value & 0x03 == 0x03 {
+                self.reset();
+            }
+            return;
+        }
+        let written = self.output.write_all(&[value]).and_then(|_| self.output.flush());
+        if let Err(e) = written {
+            self.error.get_or_insert(EmulatorError::Output(e.kind()));
+        }
+    }
+}
+
+// The sense switches, read only
+struct SenseSwitches(u8);
+
+impl Device for SenseSwitches {
+    fn read(&mut self, _port: u8) -> u8 {
+        self.0
+    }
+
+    fn write(&mut self, _port: u8, _value: u8) {}
+}
+
+/// An Altair with a 2SIO console going to `W`.
+pub struct AltairMachine<W: Write + 'static> {
+    pub cpu: CPU,
+    bus: PortBus,
+    sio: Rc<RefCell<Sio<W>>>,
+    switches: Rc<RefCell<SenseSwitches>>,
+}
+
+impl<W: Write + 'static> AltairMachine<W> {
+    /// Loads `program` at 0x0000, where the CPU starts, with the console
+    /// output going to `output`.
+    pub fn new(program: &[u8], output: W) -> Result<Self, LoadError> {
+        let mut memory = Memory8080::new_empty();
+        load_bytes(&mut memory, program, 0x0000)?;
+        Ok(Self::with_memory(memory, output))
+    }
+
+    /// Loads the binary at `path` as `new` does, such as a 4K BASIC image.
+    pub fn from_file(path: impl AsRef<Path>, output: W) -> Result<Self, LoadError> {
+        let mut memory = Memory8080::new_empty();
+        load_file(&mut memory, path, 0x0000)?;
+        Ok(Self::with_memory(memory, output))
+    }
+
+    fn with_memory(memory: Memory8080, output: W) -> Self {
+        let sio = Rc::new(RefCell::new(Sio {
+            input: Box::new(io::empty()),
+            output,
+            received: None,
+            starved: false,
+            error: None,
+        }));
+        let switches = Rc::new(RefCell::new(SenseSwitches(0x00)));
+        let mut bus = PortBus::new();
+        bus.map(SIO_PORT, sio.clone());
+        bus.map(SIO_PORT + 1, sio.clone());
+        bus.map(SENSE_SWITCH_PORT, switches.clone());
+        AltairMachine {
+            cpu: CPU::new(Rc::new(RefCell::new(memory))),
+            bus,
+            sio,
+            switches,
+        }
+    }
+
+    /// Sets where the console input comes from. Without it the guest
+    /// sees no input at all.
+    pub fn set_input(&mut self, input: impl Read + 'static) {
+        let mut sio = self.sio.borrow_mut();
+        sio.input = Box::new(input);
+        sio.received = None;
+        sio.starved = false;
+    }
+
+    /// Sets the sense switches, as BASIC's loader reads them to pick its
+    /// console device.
+    pub fn set_sense_switches(&mut self, switches: u8) {
+        self.switches.borrow_mut().0 = switches;
+    }
+
+    /// The console output sink.
+    pub fn output(&self) -> Ref<'_, W> {
+        Ref::map(self.sio.borrow(), |sio| &sio.output)
+    }
+}
+
+impl<W: Write + 'static> Machine for AltairMachine<W> {
+    type Event = Event;
+
+    fn next(&mut self) -> Result<Event, EmulatorError> {
+        let event = self.bus.step(&mut self.cpu);
+        if let Some(e) = self.sio.borrow_mut().error.take() {
+            return Err(e);
+        }
+        match event {
+            Event::Halt(_) => Err(EmulatorError::Halted(self.cpu.pc.wrapping_sub(1))),
+            Event::Fault(fault) => Err(EmulatorError::Fault(fault)),
+            event => Ok(event),
+        }
+    }
+
+    /// Runs until the guest polls the console for input after the input
+    /// has run out.
+    fn run(&mut self) -> Result<(), EmulatorError> {
+        while !self.is_finished() {
+            self.next()?;
+        }
+        Ok(())
+    }
+
+    /// Resets the CPU and the 2SIO; the sense switches stay as they are
+    /// set.
+    fn reset(&mut self) {
+        self.cpu.reset();
+        self.sio.borrow_mut().reset();
+    }
+
+    fn cycles(&self) -> Timestamp {
+        self.cpu.cycles()
+    }
+
+    /// Whether the guest has polled the console with the input used up.
+    fn is_finished(&self) -> bool {
+        self.sio.borrow().starved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::EmulatorError;
+    use crate::machines::altair::AltairMachine;
+    use crate::Machine;
+
+    use std::io::{self, Write};
+
+    #[test]
+    fn switches_and_console() {
+        // MVI A, 0x03; OUT 0x10; IN 0xff; OUT 0x11; IN 0x10; ANI 0x01; JZ 0x0008;
+        // IN 0x11; ADI 1; OUT 0x11; HLT
+        let program = [0x3e, 0x03, 0xd3, 0x10, 0xdb, 0xff, 0xd3, 0x11, 0xdb, 0x10, 0xe6, 0x01, 0xca, 0x08, 0x00,
+                       0xdb, 0x11, 0xc6, 0x01, 0xd3, 0x11, 0x76];
+        let mut machine = AltairMachine::new(&program, Vec::new()).unwrap();
+        machine.set_sense_switches(b'S');
+        machine.set_input(&b"A"[..]);
+        assert_eq!(machine.run(), Err(EmulatorError::Halted(0x0015)));
+        assert_eq!(*machine.output(), b"SB");
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_errors_stop_the_machine() {
+        // OUT 0x11
+        let mut machine = AltairMachine::new(&[0xd3, 0x11], Broken).unwrap();
+        assert!(matches!(machine.next(), Err(EmulatorError::Output(io::ErrorKind::BrokenPipe))));
+    }
+}
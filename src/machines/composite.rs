@@ -0,0 +1,362 @@
+//! A general-purpose machine put together from parts with
+//! `MachineBuilder`: memory, devices on ports and periodic interrupts,
+//! such as the video interrupts of an arcade board.
+//!
+//! ```
+//! use i8080_emulator::cpu::CpuState;
+//! use i8080_emulator::devices::serial::Serial;
+//! use i8080_emulator::machines::composite::{MachineBuilder, Rst};
+//! use i8080_emulator::memory::Memory8080;
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! let serial = Rc::new(RefCell::new(Serial::new(0x10)));
+//! let frames = Rc::new(RefCell::new(0));
+//! let counter = frames.clone();
+//! let mut machine = MachineBuilder::new()
+//!     .cpu(CpuState { pc: 0x0100, ..CpuState::default() })
+//!     .memory(Memory8080::new_empty())
+//!     .port(0x10, serial.clone())
+//!     .port(0x11, serial)
+//!     .interrupt_every(16_667, Rst(2))
+//!     .frame_hook(move |_cpu, _rst| *counter.borrow_mut() += 1)
+//!     .build();
+//! machine.run_for(100_000).unwrap();
+//! assert_eq!(*frames.borrow(), 5);
+//! ```
+
+use crate::cpu::{CpuState, Event, Timestamp, CPU};
+use crate::device::Device;
+use crate::error::EmulatorError;
+use crate::io::PortBus;
+use crate::memory::Memory8080;
+use crate::scheduler::{FrameStats, Scheduler, Task};
+use crate::{I8080Core, Machine};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// An RST instruction by number, as supplied by an interrupting device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rst(pub u8);
+
+impl Rst {
+    pub fn opcode(self) -> u8 {
+        0xc7 | (self.0 & 0x07) << 3
+    }
+}
+
+type FrameHook = Box<dyn FnMut(&CPU, Rst)>;
+
+/// Assembles a `CompositeMachine`. Everything is optional: by default the
+/// CPU starts at 0x0000 in 64K of zeroed RAM with nothing on the ports.
+pub struct MachineBuilder {
+    state: CpuState,
+    memory: Option<Memory8080>,
+    bus: PortBus,
+    devices: Vec<Rc<RefCell<dyn Device>>>,
+    // Each periodic interrupt and its period
+    interrupts: Vec<(Timestamp, Rst)>,
+    frame_hooks: Vec<FrameHook>,
+}
+
+impl Default for MachineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MachineBuilder {
+    pub fn new() -> Self {
+        MachineBuilder {
+            state: CpuState::default(),
+            memory: None,
+            bus: PortBus::new(),
+            devices: Vec::new(),
+            interrupts: Vec::new(),
+            frame_hooks: Vec::new(),
+        }
+    }
+
+    /// Sets the registers the CPU starts with, PC included.
+    pub fn cpu(mut self, state: CpuState) -> Self {
+        self.state = state;
+        self
+    }
+
+    pub fn memory(mut self, memory: Memory8080) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Attaches `device` to `port` for IN and OUT. The machine also ticks
+    /// it with the cycles of every instruction and resets it along with
+    /// the CPU, once however many ports it is attached to.
+    pub fn port<D: Device + 'static>(mut self, port: u8, device: Rc<RefCell<D>>) -> Self {
+        self.bus.map(port, device.clone());
+        let device: Rc<RefCell<dyn Device>> = device;
+        if !self.devices.iter().any(|attached| Rc::ptr_eq(attached, &device)) {
+            self.devices.push(device);
+        }
+        self
+    }
+
+    /// Raises the interrupt `rst` every `cycles` clock cycles. It stays
+    /// pending until the CPU accepts it with interrupts enabled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cycles` is 0.
+    pub fn interrupt_every(mut self, cycles: Timestamp, rst: Rst) -> Self {
+        assert!(cycles > 0, "a periodic interrupt needs a period");
+        self.interrupts.push((cycles, rst));
+        self
+    }
+
+    /// Calls `hook` whenever a periodic interrupt is raised, typically to
+    /// draw a frame from video memory, with the CPU as it stands at that
+    /// moment.
+    pub fn frame_hook(mut self, hook: impl FnMut(&CPU, Rst) + 'static) -> Self {
+        self.frame_hooks.push(Box::new(hook));
+        self
+    }
+
+    pub fn build(self) -> CompositeMachine {
+        let memory = self.memory.unwrap_or_else(Memory8080::new_empty);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(memory)));
+        cpu.set_state(&self.state);
+        let mut scheduler = Scheduler::new();
+        let hooks = Rc::new(RefCell::new(self.frame_hooks));
+        for (period, rst) in self.interrupts {
+            scheduler.every(period, Task::Interrupt(rst.opcode()));
+            if !hooks.borrow().is_empty() {
+                let hooks = hooks.clone();
+                scheduler.every(period, Task::call(move |cpu: &mut CPU| {
+                    for hook in hooks.borrow_mut().iter_mut() {
+                        hook(cpu, rst);
+                    }
+                }));
+            }
+        }
+        CompositeMachine {
+            cpu,
+            bus: self.bus,
+            devices: self.devices,
+            scheduler,
+        }
+    }
+}
+
+/// A machine assembled by `MachineBuilder`.
+pub struct CompositeMachine {
+    pub cpu: CPU,
+    pub bus: PortBus,
+    devices: Vec<Rc<RefCell<dyn Device>>>,
+    // The periodic interrupts and the frame hooks called with them
+    scheduler: Scheduler,
+}
+
+impl CompositeMachine {
+    /// Runs for at least `cycles` clock cycles and returns how many ran.
+    pub fn run_for(&mut self, cycles: Timestamp) -> Result<Timestamp, EmulatorError> {
+        let start = self.cpu.cycles();
+        while self.cpu.cycles().wrapping_sub(start) < cycles {
+            self.next()?;
+        }
+        Ok(self.cpu.cycles().wrapping_sub(start))
+    }
+
+    /// Runs a frame of `quota` clock cycles, less what the last frame ran
+    /// over, and returns its accounting. See `scheduler::FrameStats`. A
+    /// frame stopped by an error is not accounted for.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the clock is paused.
+    pub fn run_frame(&mut self, quota: Timestamp) -> Result<FrameStats, EmulatorError> {
+        let end = self.scheduler.now() + self.scheduler.begin_frame(quota);
+        while self.scheduler.now() < end {
+            self.next()?;
+        }
+        Ok(self.scheduler.end_frame())
+    }
+
+    /// The accounting of the last frame run with `run_frame`.
+    pub fn last_frame(&self) -> Option<FrameStats> {
+        self.scheduler.last_frame()
+    }
+
+    /// Stops the machine's clock for a debugger: until `resume_clock` the
+    /// CPU still runs, but periodic interrupts and frame hooks wait and
+    /// the devices are not ticked, so that their timeouts do not run out
+    /// while someone steps through the program.
+    pub fn pause_clock(&mut self) {
+        self.scheduler.pause();
+    }
+
+    pub fn resume_clock(&mut self) {
+        self.scheduler.resume();
+    }
+
+    pub fn is_clock_paused(&self) -> bool {
+        self.scheduler.is_paused()
+    }
+}
+
+impl Machine for CompositeMachine {
+    type Event = Event;
+
+    /// Executes one instruction, or accepts a pending interrupt in its
+    /// place. A halted CPU is only an error when no interrupt is ever
+    /// going to wake it up: none is scheduled, or interrupts are disabled.
+    fn next(&mut self) -> Result<Event, EmulatorError> {
+        let event = match self.scheduler.take_interrupt(&mut self.cpu) {
+            Some(event) => event,
+            None => self.bus.step(&mut self.cpu),
+        };
+        if let Event::Fault(fault) = event {
+            return Err(EmulatorError::Fault(fault));
+        }
+        if self.cpu.is_halted() && (self.scheduler.is_empty() || !self.cpu.interrupts_enabled()) {
+            return Err(EmulatorError::Halted(self.cpu.pc.wrapping_sub(1)));
+        }
+        if !self.scheduler.is_paused() {
+            for device in &self.devices {
+                device.borrow_mut().tick(event.cycles());
+            }
+        }
+        self.scheduler.advance(&mut self.cpu, event.cycles());
+        Ok(event)
+    }
+
+    /// Runs until something stops the machine with an error.
+    fn run(&mut self) -> Result<(), EmulatorError> {
+        loop {
+            self.next()?;
+        }
+    }
+
+    /// Resets the CPU and every attached device and drops pending
+    /// interrupts. The periodic interrupts keep their timing.
+    fn reset(&mut self) {
+        self.cpu.reset();
+        for device in &self.devices {
+            device.borrow_mut().reset();
+        }
+        self.scheduler.clear_pending();
+    }
+
+    fn cycles(&self) -> Timestamp {
+        self.cpu.cycles()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CpuState;
+    use crate::devices::serial::Serial;
+    use crate::devices::watchdog::Watchdog;
+    use crate::error::EmulatorError;
+    use crate::machines::composite::{MachineBuilder, Rst};
+    use crate::memory::Memory8080;
+    use crate::Machine;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn rst_opcodes() {
+        assert_eq!(Rst(0).opcode(), 0xc7);
+        assert_eq!(Rst(2).opcode(), 0xd7);
+        assert_eq!(Rst(7).opcode(), 0xff);
+    }
+
+    #[test]
+    fn interrupts_and_ports() {
+        // 0x0000: LXI SP, 0x2000; EI; loop: HLT; JMP loop
+        // 0x0010 (RST 2): INR B; MOV A, B; OUT 0x11; EI; RET
+        let mut image = [0x00; 0x10000];
+        image[..8].copy_from_slice(&[0x31, 0x00, 0x20, 0xfb, 0x76, 0xc3, 0x04, 0x00]);
+        image[0x10..0x16].copy_from_slice(&[0x04, 0x78, 0xd3, 0x11, 0xfb, 0xc9]);
+        let serial = Rc::new(RefCell::new(Serial::new(0x10)));
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let log = frames.clone();
+        let mut machine = MachineBuilder::new()
+            .memory(Memory8080::new(image))
+            .port(0x10, serial.clone())
+            .port(0x11, serial.clone())
+            .interrupt_every(1000, Rst(2))
+            .frame_hook(move |cpu, rst| log.borrow_mut().push((cpu.regs.b, rst)))
+            .build();
+        machine.run_for(3500).unwrap();
+        assert_eq!(serial.borrow_mut().take_output(), [1, 2, 3]);
+        assert_eq!(*frames.borrow(), [(0, Rst(2)), (1, Rst(2)), (2, Rst(2))]);
+        assert!(machine.cpu.is_halted());
+
+        // One more interrupt in a frame of the same length
+        let frame = machine.run_frame(1000).unwrap();
+        assert_eq!((frame.interrupts_raised, frame.interrupts_taken), (1, 1));
+        assert_eq!(frame.cycles - frame.budget, frame.carryover);
+        assert_eq!(machine.last_frame(), Some(frame));
+        assert_eq!(serial.borrow_mut().take_output(), [4]);
+
+        machine.reset();
+        assert_eq!(machine.cpu.pc, 0x0000);
+    }
+
+    #[test]
+    fn paused_clock() {
+        // 0x0000: LXI SP, 0x2000; EI; loop: JMP loop
+        // 0x0010 (RST 2): INR B; EI; RET
+        let mut image = [0x00; 0x10000];
+        image[..7].copy_from_slice(&[0x31, 0x00, 0x20, 0xfb, 0xc3, 0x04, 0x00]);
+        image[0x10..0x13].copy_from_slice(&[0x04, 0xfb, 0xc9]);
+        let watchdog = Rc::new(RefCell::new(Watchdog::new(0x06, 1000)));
+        let mut machine = MachineBuilder::new()
+            .memory(Memory8080::new(image))
+            .port(0x06, watchdog.clone())
+            .interrupt_every(1000, Rst(2))
+            .build();
+        machine.pause_clock();
+        assert!(machine.is_clock_paused());
+        machine.run_for(5000).unwrap();
+        assert_eq!(machine.cpu.regs.b, 0);
+        assert!(!watchdog.borrow_mut().take_expired());
+
+        // The first interrupt is a period of running away
+        machine.resume_clock();
+        machine.run_for(1100).unwrap();
+        assert_eq!(machine.cpu.regs.b, 1);
+        assert!(watchdog.borrow_mut().take_expired());
+    }
+
+    #[test]
+    fn halting_without_interrupts() {
+        let mut image = [0x00; 0x10000];
+        image[0x100] = 0x76;
+        let mut machine = MachineBuilder::new()
+            .cpu(CpuState { pc: 0x0100, ..CpuState::default() })
+            .memory(Memory8080::new(image))
+            .build();
+        assert_eq!(machine.run(), Err(EmulatorError::Halted(0x0100)));
+    }
+
+    #[test]
+    fn halting_with_interrupts_disabled() {
+        // DI; HLT
+        let mut image = [0x00; 0x10000];
+        image[..2].copy_from_slice(&[0xf3, 0x76]);
+        let mut machine = MachineBuilder::new()
+            .memory(Memory8080::new(image))
+            .interrupt_every(1000, Rst(1))
+            .build();
+        assert_eq!(machine.run(), Err(EmulatorError::Halted(0x0001)));
+        assert_eq!(machine.next(), Err(EmulatorError::Halted(0x0001)));
+    }
+
+    #[test]
+    #[should_panic(expected = "a periodic interrupt needs a period")]
+    fn interrupts_need_a_period() {
+        let _ = MachineBuilder::new().interrupt_every(0, Rst(1));
+    }
+}
@@ -0,0 +1,526 @@
+//! Just enough of CP/M to run `.COM` programs that talk to the console,
+//! such as the CPU test suites or vintage command-line tools.
+//!
+//! The program is loaded at 0x0100 and started there. A CALL to the BDOS
+//! entry at 0x0005 is trapped and the console functions are handled:
+//! 1 (C_READ), 2 (C_WRITE), 6 (C_RAWIO), 9 (C_WRITESTR), 10 (C_READSTR)
+//! and 11 (C_STAT), with output going to the `Write` sink the machine was
+//! given and input coming from the reader set with `set_input`. Function
+//! 0 (P_TERMCPM) or a jump to 0x0000, the warm boot, ends the program, and
+//! function 108 (P_CODE) sets the code it returns. Other functions return
+//! without doing anything.
+//!
+//! ```no_run
+//! use i8080_emulator::machines::cpm::CpmMachine;
+//! use i8080_emulator::Machine;
+//!
+//! let mut machine = CpmMachine::from_file("cpu_tests/TST8080.COM", std::io::stdout()).unwrap();
+//! machine.run().unwrap();
+//! ```
+//!
+//! `run` does the whole thing in one call, for using a CP/M tool as a
+//! build step:
+//!
+//! ```no_run
+//! let program = std::fs::read("ASM.COM").unwrap();
+//! let output = i8080_emulator::machines::cpm::run(&program, &["HELLO"], std::io::empty()).unwrap();
+//! assert!(output.status.is_ok() && output.code == 0);
+//! ```
+//!
+//! `run_test` runs one of the CPU test suites in `cpu_tests` and says
+//! whether it passed, going by what it printed, for checking the core
+//! from `cargo test`:
+//!
+//! ```no_run
+//! use i8080_emulator::machines::cpm::run_test;
+//! use i8080_emulator::Limit;
+//!
+//! let program = std::fs::read("cpu_tests/TST8080.COM").unwrap();
+//! let outcome = run_test(&program, Limit::Steps(1_000_000)).unwrap();
+//! assert!(outcome.passed, "{}", outcome.output);
+//! ```
+
+use crate::cpu::{Event, Timestamp, CPU};
+use crate::error::EmulatorError;
+use crate::loader::{load_bytes, load_file, LoadError};
+use crate::memory::{Memory, Memory8080};
+use crate::{Limit, Machine};
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+/// Entry point of the BDOS.
+pub const BDOS: u16 = 0x0005;
+/// Where CP/M loads and starts `.COM` programs.
+pub const TPA: u16 = 0x0100;
+/// The first default file control block, filled in from the first
+/// argument. The second one follows 16 bytes later.
+pub const FCB: u16 = 0x005c;
+/// Where the command tail, the arguments as typed, is left for the
+/// program: a length byte followed by the text.
+pub const COMMAND_TAIL: u16 = 0x0080;
+
+// What C_READ returns once the input has run out
+const EOF: u8 = 0x1a;
+
+/// What the CPU test suites print when they pass: TST8080, 8080PRE,
+/// 8080EXM and 8080EXER, and CPUTEST.
+pub const PASS_MESSAGES: [&str; 4] = ["CPU IS OPERATIONAL", "Preliminary tests complete", "Tests complete", "CPU TESTS OK"];
+/// What they print when something is wrong. 8080PRE only prints the
+/// address of the failed check, so it fails by not passing.
+pub const FAIL_MESSAGES: [&str; 3] = ["CPU HAS FAILED", "ERROR ****", "CPU FAILED"];
+
+/// Handles the BDOS console output call the CPU is making, if it is one,
+/// passing each character printed to `emit`. Call with PC at `BDOS`.
+pub(crate) fn console_call(cpu: &CPU, mut emit: impl FnMut(u8)) {
+    let regs = &cpu.regs;
+    match regs.c {
+        2 => emit(regs.e),
+        9 => {
+            let memory = cpu.memory.borrow();
+            let start = regs.get_de();
+            // Without a '$' the string ends where it would wrap back to
+            // its start
+            for offset in 0..=0xffff {
+                let c = memory.read(usize::from(start.wrapping_add(offset)));
+                if c == b'$' {
+                    break;
+                }
+                emit(c);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Fills in the file control block at `fcb` from a file name such as
+// `B:FOO.TXT`, the way the CCP does
+fn parse_fcb(memory: &mut Memory8080, fcb: u16, arg: &str) {
+    let arg = arg.to_ascii_uppercase();
+    let (drive, name) = match arg.as_bytes() {
+        [d @ b'A'..=b'P', b':', ..] => (d - b'A' + 1, &arg[2..]),
+        _ => (0, &arg[..]),
+    };
+    let (name, ext) = name.split_once('.').unwrap_or((name, ""));
+    let mut field = [b' '; 11];
+    for (part, range) in [(name, 0..8), (ext, 8..11)] {
+        let field = &mut field[range];
+        for (i, c) in part.bytes().take(field.len()).enumerate() {
+            if c == b'*' {
+                field[i..].fill(b'?');
+                break;
+            }
+            field[i] = c;
+        }
+    }
+    let fcb = usize::from(fcb);
+    memory.write(fcb, drive);
+    for (i, &c) in field.iter().enumerate() {
+        memory.write(fcb + 1 + i, c);
+    }
+}
+
+/// A CP/M program with the BDOS console calls going to `W`.
+pub struct CpmMachine<W: Write> {
+    pub cpu: CPU,
+    output: W,
+    input: Box<dyn Read>,
+    // A byte read ahead for C_STAT
+    peeked: Option<u8>,
+    // Set by the trap at `BDOS` for `next` to handle the call
+    bdos_called: Rc<Cell<bool>>,
+    finished: bool,
+    code: u16,
+}
+
+impl<W: Write> CpmMachine<W> {
+    /// Loads `program` at 0x0100, with its console output going to
+    /// `output`.
+    pub fn new(program: &[u8], output: W) -> Result<Self, LoadError> {
+        let mut memory = Memory8080::new_empty();
+        load_bytes(&mut memory, program, TPA)?;
+        Ok(Self::with_memory(memory, output))
+    }
+
+    /// Loads the `.COM` file at `path` as `new` does.
+    pub fn from_file(path: impl AsRef<Path>, output: W) -> Result<Self, LoadError> {
+        let mut memory = Memory8080::new_empty();
+        load_file(&mut memory, path, TPA)?;
+        Ok(Self::with_memory(memory, output))
+    }
+
+    fn with_memory(mut memory: Memory8080, output: W) -> Self {
+        // The BDOS returns straight away once the call is handled
+        memory.write(usize::from(BDOS), 0xc9);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(memory)));
+        cpu.pc = TPA;
+        let bdos_called = Rc::new(Cell::new(false));
+        let called = bdos_called.clone();
+        cpu.add_trap(BDOS, move |_, _| {
+            called.set(true);
+            None
+        });
+        let mut machine = CpmMachine {
+            cpu,
+            output,
+            input: Box::new(io::empty()),
+            peeked: None,
+            bdos_called,
+            finished: false,
+            code: 0,
+        };
+        machine.set_args(&[]);
+        machine
+    }
+
+    /// Passes `args` to the program as if typed after its name: the
+    /// command tail and the two default file control blocks are filled in,
+    /// uppercased as the CCP would.
+    pub fn set_args(&mut self, args: &[&str]) {
+        let mut memory = self.cpu.memory.borrow_mut();
+        let mut tail = Vec::new();
+        for arg in args {
+            tail.push(b' ');
+            tail.extend(arg.to_ascii_uppercase().bytes());
+        }
+        // Cut by bytes, which may split a character that is not ASCII
+        tail.truncate(127);
+        memory.write(usize::from(COMMAND_TAIL), tail.len() as u8);
+        for (i, &c) in tail.iter().enumerate() {
+            memory.write(usize::from(COMMAND_TAIL) + 1 + i, c);
+        }
+        for (n, &fcb) in [FCB, FCB + 16].iter().enumerate() {
+            parse_fcb(&mut memory, fcb, args.get(n).copied().unwrap_or(""));
+        }
+    }
+
+    /// Sets where the console input comes from. Without it the program
+    /// sees no input at all. Once `input` runs out, C_READ returns ^Z.
+    pub fn set_input(&mut self, input: impl Read + 'static) {
+        self.input = Box::new(input);
+        self.peeked = None;
+    }
+
+    /// The code last set with P_CODE, 0 if the program never set one.
+    pub fn return_code(&self) -> u16 {
+        self.code
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    pub fn into_output(self) -> W {
+        self.output
+    }
+
+    fn bdos(&mut self) -> Result<(), EmulatorError> {
+        let (c, e, de) = (self.cpu.regs.c, self.cpu.regs.e, self.cpu.regs.get_de());
+        let result = match c {
+            0 => {
+                self.finished = true;
+                0
+            }
+            1 => {
+                let c = self.read_input()?.unwrap_or(EOF);
+                self.write_output(&[c])?;
+                c
+            }
+            6 if e == 0xff => self.read_input()?.unwrap_or(0x00),
+            6 if e == 0xfe => self.input_status()?,
+            6 => {
+                self.write_output(&[e])?;
+                0
+            }
+            10 => {
+                self.read_line(de)?;
+                0
+            }
+            11 => self.input_status()?,
+            108 if de != 0xffff => {
+                self.code = de;
+                0
+            }
+            108 => {
+                self.cpu.regs.set_hl(self.code);
+                self.cpu.regs.a = self.code as u8;
+                return Ok(());
+            }
+            _ => {
+                let mut printed = Vec::new();
+                console_call(&self.cpu, |c| printed.push(c));
+                return self.write_output(&printed);
+            }
+        };
+        // Byte results come back in A and L
+        self.cpu.regs.a = result;
+        self.cpu.regs.set_hl(u16::from(result));
+        Ok(())
+    }
+
+    // C_READSTR into the buffer at `buf`: its size, then the count of
+    // characters read, then the characters
+    fn read_line(&mut self, buf: u16) -> Result<(), EmulatorError> {
+        let size = self.cpu.memory.borrow().read(usize::from(buf));
+        let mut line = Vec::new();
+        while line.len() < usize::from(size) {
+            match self.read_input()? {
+                None | Some(b'\n') => break,
+                Some(b'\r') => {
+                    // Take the LF of a CRLF along with it
+                    if self.input_status()? != 0 && self.peeked == Some(b'\n') {
+                        self.peeked = None;
+                    }
+                    break;
+                }
+                Some(c) => line.push(c),
+            }
+        }
+        let mut memory = self.cpu.memory.borrow_mut();
+        let start = usize::from(buf) + 1;
+        memory.write(start, line.len() as u8);
+        for (i, &c) in line.iter().enumerate() {
+            memory.write(start + 1 + i, c);
+        }
+        drop(memory);
+        line.push(b'\r');
+        self.write_output(&line)
+    }
+
+    fn read_input(&mut self) -> Result<Option<u8>, EmulatorError> {
+        if let Some(c) = self.peeked.take() {
+            return Ok(Some(c));
+        }
+        let mut byte = [0];
+        match self.input.read(&mut byte) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(e) => Err(EmulatorError::Input(e.kind())),
+        }
+    }
+
+    // 0xff if a character is waiting, as C_STAT returns it
+    fn input_status(&mut self) -> Result<u8, EmulatorError> {
+        if self.peeked.is_none() {
+            self.peeked = self.read_input()?;
+        }
+        Ok(if self.peeked.is_some() { 0xff } else { 0x00 })
+    }
+
+    fn write_output(&mut self, bytes: &[u8]) -> Result<(), EmulatorError> {
+        self.output.write_all(bytes).map_err(|e| EmulatorError::Output(e.kind()))
+    }
+}
+
+impl<W: Write> Machine for CpmMachine<W> {
+    type Event = Event;
+
+    fn next(&mut self) -> Result<Event, EmulatorError> {
+        let (event, _) = self.cpu.step();
+        match event {
+            // Programs only talk to the outside world through the BDOS
+            Event::Input(port, _) | Event::Output(port, _, _) => return Err(EmulatorError::UnmappedPort(port)),
+            Event::Halt(_) => return Err(EmulatorError::Halted(self.cpu.pc.wrapping_sub(1))),
+            Event::Fault(fault) => return Err(EmulatorError::Fault(fault)),
+            Event::Normal(_) => {}
+        }
+        if self.bdos_called.take() {
+            self.bdos()?;
+        }
+        if self.cpu.pc == 0x0000 {
+            self.finished = true;
+        }
+        if self.finished {
+            self.output.flush().map_err(|e| EmulatorError::Output(e.kind()))?;
+        }
+        Ok(event)
+    }
+
+    /// Runs the program until it warm boots.
+    fn run(&mut self) -> Result<(), EmulatorError> {
+        while !self.is_finished() {
+            self.next()?;
+        }
+        Ok(())
+    }
+
+    /// Starts the program over at 0x0100, as if it had been run again
+    /// without reloading it.
+    fn reset(&mut self) {
+        self.cpu.reset();
+        self.cpu.pc = TPA;
+        self.finished = false;
+        self.code = 0;
+    }
+
+    fn cycles(&self) -> Timestamp {
+        self.cpu.cycles()
+    }
+
+    /// Whether the program has ended with a warm boot.
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+/// What `run` got back from a program.
+#[derive(Debug)]
+pub struct Output {
+    /// Everything printed to the console.
+    pub stdout: Vec<u8>,
+    /// The code set with P_CODE, 0 if the program never set one.
+    pub code: u16,
+    /// `Ok` if the program ended with a warm boot, or the error that
+    /// stopped it otherwise. The output up to that point is kept.
+    pub status: Result<(), EmulatorError>,
+}
+
+/// How a test program run by `run_test` went.
+#[derive(Debug)]
+pub struct TestOutcome {
+    /// Whether the program ended with a warm boot having printed one of
+    /// `PASS_MESSAGES` and none of `FAIL_MESSAGES`.
+    pub passed: bool,
+    /// Everything printed to the console, with bytes that are not UTF-8
+    /// replaced.
+    pub output: String,
+    pub cycles: Timestamp,
+    /// `Ok` if the program ended with a warm boot, or the error that
+    /// stopped it otherwise, such as the limit running out.
+    pub status: Result<(), EmulatorError>,
+}
+
+/// Runs the test program `program` for at most `limit`, with no console
+/// input, and judges it by its output. Only a program too large for
+/// memory is an error.
+pub fn run_test(program: &[u8], limit: Limit) -> Result<TestOutcome, LoadError> {
+    let mut machine = CpmMachine::new(program, Vec::new())?;
+    let status = machine.run_with_limit(limit);
+    let cycles = machine.cycles();
+    let output = String::from_utf8_lossy(&machine.into_output()).into_owned();
+    let printed = |messages: &[&str]| messages.iter().any(|message| output.contains(message));
+    Ok(TestOutcome {
+        passed: status.is_ok() && printed(&PASS_MESSAGES) && !printed(&FAIL_MESSAGES),
+        output,
+        cycles,
+        status,
+    })
+}
+
+/// Runs the `.COM` program `program` with `args` after its name and
+/// `stdin` as its console input, and returns what it printed. Only a
+/// program too large for memory is an error; how the program itself
+/// ended is in `Output::status`.
+pub fn run(program: &[u8], args: &[&str], stdin: impl Read + 'static) -> Result<Output, LoadError> {
+    let mut machine = CpmMachine::new(program, Vec::new())?;
+    machine.set_args(args);
+    machine.set_input(stdin);
+    let status = machine.run();
+    Ok(Output {
+        code: machine.return_code(),
+        stdout: machine.into_output(),
+        status,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::EmulatorError;
+    use crate::machines::cpm::{run, run_test, CpmMachine};
+    use crate::memory::Memory;
+    use crate::{Limit, Machine};
+
+    #[test]
+    fn console_output_and_warm_boot() {
+        // MVI C, 9; LXI D, msg; CALL 5; MVI C, 2; MVI E, '!'; CALL 5; JMP 0;
+        // msg: DB 'Hello$'
+        let mut program = vec![0x0e, 0x09, 0x11, 0x12, 0x01, 0xcd, 0x05, 0x00,
+                               0x0e, 0x02, 0x1e, b'!', 0xcd, 0x05, 0x00, 0xc3, 0x00, 0x00];
+        program.extend_from_slice(b"Hello$");
+        let mut machine = CpmMachine::new(&program, Vec::new()).unwrap();
+        machine.run().unwrap();
+        assert!(machine.is_finished());
+        assert_eq!(machine.into_output(), b"Hello!");
+    }
+
+    #[test]
+    fn unterminated_string() {
+        // MVI C, 9; LXI D, 0; CALL 5; JMP 0, with no '$' in memory
+        let program = [0x0e, 0x09, 0x11, 0x00, 0x00, 0xcd, 0x05, 0x00, 0xc3, 0x00, 0x00];
+        let mut machine = CpmMachine::new(&program, Vec::new()).unwrap();
+        machine.run().unwrap();
+        assert_eq!(machine.into_output().len(), 0x10000);
+    }
+
+    #[test]
+    fn halt_and_ports_are_errors() {
+        let mut machine = CpmMachine::new(&[0x00, 0x76], Vec::new()).unwrap();
+        assert_eq!(machine.run(), Err(EmulatorError::Halted(0x0101)));
+        let mut machine = CpmMachine::new(&[0xdb, 0x02], Vec::new()).unwrap();
+        assert_eq!(machine.run(), Err(EmulatorError::UnmappedPort(0x02)));
+        assert!(CpmMachine::new(&[0x00; 0xff01], Vec::new()).is_err());
+    }
+
+    #[test]
+    fn arguments() {
+        let mut machine = CpmMachine::new(&[0x00], Vec::new()).unwrap();
+        machine.set_args(&["b:foo.txt", "*.c"]);
+        let memory = machine.cpu.memory.borrow();
+        let read = |from: usize, len: usize| (from..from + len).map(|i| memory.read(i)).collect::<Vec<_>>();
+        assert_eq!(read(0x80, 15), b"\x0e B:FOO.TXT *.C");
+        assert_eq!(read(0x5c, 12), b"\x02FOO     TXT");
+        assert_eq!(read(0x6c, 12), b"\x00????????C  ");
+        drop(memory);
+
+        // 127 bytes of tail end in the middle of the last 'é'
+        machine.set_args(&[&format!("a{}", "é".repeat(63))]);
+        let memory = machine.cpu.memory.borrow();
+        assert_eq!((memory.read(0x80), memory.read(0xfe), memory.read(0xff)), (127, 0xa9, 0xc3));
+    }
+
+    #[test]
+    fn console_input_and_return_code() {
+        // MVI C, 10; LXI D, buf; CALL 5; MVI C, 1; CALL 5; MVI C, 108;
+        // LXI D, 2; CALL 5; MVI C, 0; CALL 5
+        // buf: DB 8
+        let mut program = vec![0x0e, 0x0a, 0x11, 0x20, 0x01, 0xcd, 0x05, 0x00, 0x0e, 0x01, 0xcd, 0x05, 0x00,
+                               0x0e, 0x6c, 0x11, 0x02, 0x00, 0xcd, 0x05, 0x00, 0x0e, 0x00, 0xcd, 0x05, 0x00];
+        program.resize(0x20, 0x00);
+        program.push(8);
+        let output = run(&program, &[], &b"hello\r\nxyz"[..]).unwrap();
+        assert_eq!(output.stdout, b"hello\rx");
+        assert_eq!(output.code, 2);
+        assert_eq!(output.status, Ok(()));
+
+        // Without input the line is empty and C_READ gets ^Z
+        let output = run(&program, &[], std::io::empty()).unwrap();
+        assert_eq!(output.stdout, b"\r\x1a");
+        assert!(run(&[0x76], &[], std::io::empty()).unwrap().status.is_err());
+    }
+
+    #[test]
+    fn test_outcomes() {
+        // MVI C, 9; LXI D, msg; CALL 5; JMP 0
+        let program = |message: &[u8]| {
+            let mut program = vec![0x0e, 0x09, 0x11, 0x0b, 0x01, 0xcd, 0x05, 0x00, 0xc3, 0x00, 0x00];
+            program.extend_from_slice(message);
+            program
+        };
+        let outcome = run_test(&program(b"\r\n CPU IS OPERATIONAL$"), Limit::Steps(100)).unwrap();
+        assert!(outcome.passed && outcome.status.is_ok());
+        assert_eq!((outcome.output.as_str(), outcome.cycles), ("\r\n CPU IS OPERATIONAL", 7 + 10 + 17 + 10 + 10));
+
+        let outcome = run_test(&program(b"Tests complete ERROR **** crc expected$"), Limit::Steps(100)).unwrap();
+        assert!(!outcome.passed && outcome.status.is_ok());
+        assert!(!run_test(&program(b"0123$"), Limit::Steps(100)).unwrap().passed);
+
+        // Prints the message, then loops: JMP 0x0108
+        let mut looping = program(b"CPU TESTS OK$");
+        looping[9] = 0x08;
+        looping[10] = 0x01;
+        let outcome = run_test(&looping, Limit::Steps(100)).unwrap();
+        assert_eq!((outcome.passed, outcome.status), (false, Err(EmulatorError::StepLimit(100))));
+    }
+}
@@ -0,0 +1,6 @@
+//! Ready-made machines built around the CPU for common ways of running
+//! 8080 programs.
+
+pub mod altair;
+pub mod composite;
+pub mod cpm;
@@ -1,26 +1,280 @@
+use crate::device::{Device, DeviceId, DeviceState, StateError};
+
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::cell::{Cell, RefCell};
+use core::convert::TryInto;
+use core::fmt;
+use core::ops::RangeInclusive;
+
+/// A 64K address space. Addresses past 0xffff wrap round to the bottom,
+/// as the 8080's 16-bit address bus would, rather than panicking.
 pub trait Memory {
      fn read(&self, i: usize) -> u8;
      fn write(&mut self, i: usize, data: u8);
 
+     /// Reads the little-endian word at `i` and `i + 1`. The byte order is
+     /// the 8080's and does not depend on the host.
      fn read16(&self, i: usize) -> u16;
+     /// Writes `data` to `i` and `i + 1`, wrapping round at the top of
+     /// memory as `read16` does. The high byte goes first, in the order
+     /// PUSH, CALL and XTHL write a word to the stack.
      fn write16(&mut self, i: usize, data: u16);
+
+     /// Reads `i` without side effects: no device is called and no
+     /// debugging check is set off. For tools looking at memory.
+     fn peek(&self, i: usize) -> u8 {
+         self.read(i)
+     }
+
+     /// Whether the CPU may run code from `addr`.
+     fn is_executable(&self, _addr: u16) -> bool {
+         true
+     }
+
+     /// Called before the CPU fetches the opcode at `addr`, for memories
+     /// that keep track of what runs.
+     fn note_fetch(&self, _addr: u16) {}
+
+     /// Returns and clears the first guard address accessed since the last
+     /// call, for memories with guard ranges.
+     fn take_guard_hit(&self) -> Option<u16> {
+         None
+     }
+
+     /// Returns and clears the number of contended accesses since the
+     /// last call, for memories that count them.
+     fn take_contended_accesses(&self) -> u32 {
+         0
+     }
+
+     /// Formats `range` as a hex dump, 16 bytes per line with the
+     /// printable characters alongside:
+     ///
+     /// ```text
+     /// 2000  48 49 00 ff                                      |HI..|
+     /// ```
+     fn hexdump(&self, range: RangeInclusive<u16>) -> String {
+         self.hexdump_diff(range, &[])
+     }
+
+     /// Same as `hexdump`, but compares the bytes with `previous`, an
+     /// earlier copy of the memory starting at the start of `range`, and
+     /// marks every byte that differs with a `*`. Bytes past the end of
+     /// `previous` are not marked.
+     fn hexdump_diff(&self, range: RangeInclusive<u16>, previous: &[u8]) -> String {
+         let start = usize::from(*range.start());
+         let end = usize::from(*range.end());
+         let mut out = String::new();
+         for line in (start..=end).step_by(16) {
+             let addrs = line..=end.min(line + 15);
+             let mut hex = String::new();
+             let mut text = String::new();
+             for addr in addrs {
+                 let byte = self.peek(addr);
+                 let changed = previous.get(addr - start).is_some_and(|&old| old != byte);
+                 hex.push_str(&format!("{}{:02x}", if changed { '*' } else { ' ' }, byte));
+                 text.push(if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' });
+             }
+             out.push_str(&format!("{:04x} {:<48}  |{}|\n", line, hex, text));
+         }
+         out
+     }
+}
+
+/// Which way a watched memory access went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// What happens when something writes to the low page (0x0000-0x00ff),
+/// which holds the RST and interrupt vectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorPagePolicy {
+    /// Writes go through, as on systems with RAM in the low page.
+    Allow,
+    /// Writes are dropped, as on systems with the low page in ROM.
+    Ignore,
+    /// Writes are dropped and the last one is kept for
+    /// `take_trapped_write`, so a machine can stop and report it.
+    Trap,
+}
+
+/// What RAM holds at power-on, for `Memory8080::power_on`. Real DRAM comes
+/// up with more or less random contents, so a program that only works when
+/// RAM starts zeroed has a bug that the other patterns bring out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerOnFill {
+    Zeros,
+    Ones,
+    /// Alternating runs of 64 bytes of 0x00 and 64 of 0xff.
+    Stripes,
+    /// Pseudo-random bytes, the same for the same seed.
+    Random(u64),
+}
+
+impl PowerOnFill {
+    /// Fills `memory` with the pattern.
+    pub fn fill(self, memory: &mut [u8]) {
+        match self {
+            PowerOnFill::Zeros => memory.fill(0x00),
+            PowerOnFill::Ones => memory.fill(0xff),
+            PowerOnFill::Stripes => {
+                for (i, b) in memory.iter_mut().enumerate() {
+                    *b = if i & 0x40 == 0 { 0x00 } else { 0xff };
+                }
+            }
+            PowerOnFill::Random(seed) => {
+                // SplitMix64, which copes with any seed including 0
+                let mut state = seed;
+                for chunk in memory.chunks_mut(8) {
+                    state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                    let mut z = state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+            }
+        }
+    }
+}
+
+/// Sees every read and write a `Memory8080` makes, just before it is
+/// made. The observer is a type parameter rather than a callback, so one
+/// that does nothing, `Unchecked`, costs nothing at all.
+pub trait AccessObserver: Default {
+    fn access(&self, addr: u16, access: Access);
+
+    /// See `Memory::take_guard_hit`.
+    fn take_guard_hit(&self) -> Option<u16> {
+        None
+    }
+
+    /// See `Memory::take_contended_accesses`.
+    fn take_contended_accesses(&self) -> u32 {
+        0
+    }
+}
+
+/// The debugging checks of a `Memory8080`, its default observer: guard
+/// ranges, watches and contended accesses. Each check costs a test per
+/// access while nothing is set.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Checks {
+    guards: Vec<RangeInclusive<u16>>,
+    // First guard address touched since the last `take_guard_hit`. A Cell
+    // because reads count too.
+    guard_hit: Cell<Option<u16>>,
+    watches: Vec<(RangeInclusive<u16>, Access)>,
+    // First watched access since the last `take_watch_hit`
+    watch_hit: Cell<Option<(u16, Access)>>,
+    contended: Option<RangeInclusive<u16>>,
+    // Accesses to `contended` since the last `take_contended_accesses`
+    contended_accesses: Cell<u32>,
 }
 
-pub struct Memory8080 {
-    memory: [u8; 0x10000]
+impl AccessObserver for Checks {
+    fn access(&self, addr: u16, access: Access) {
+        if !self.guards.is_empty() && self.guard_hit.get().is_none() && self.guards.iter().any(|range| range.contains(&addr)) {
+            self.guard_hit.set(Some(addr));
+        }
+        if !self.watches.is_empty() && self.watch_hit.get().is_none()
+            && self.watches.iter().any(|(range, a)| *a == access && range.contains(&addr))
+        {
+            self.watch_hit.set(Some((addr, access)));
+        }
+        if let Some(range) = &self.contended {
+            if range.contains(&addr) {
+                self.contended_accesses.set(self.contended_accesses.get() + 1);
+            }
+        }
+    }
+
+    fn take_guard_hit(&self) -> Option<u16> {
+        self.guard_hit.take()
+    }
+
+    fn take_contended_accesses(&self) -> u32 {
+        self.contended_accesses.take()
+    }
 }
 
-impl Memory for Memory8080 {
+/// An observer that does nothing, for a `Memory8080` without guard
+/// ranges, watches or contention that does no work for them on each
+/// access either.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Unchecked;
+
+impl AccessObserver for Unchecked {
+    #[inline(always)]
+    fn access(&self, _addr: u16, _access: Access) {}
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Memory8080<O: AccessObserver = Checks> {
+    // Boxed so that moving a Memory8080 around, into an Rc for a CPU say,
+    // does not copy 64K through the stack
+    memory: Box<[u8; 0x10000]>,
+    vector_page: VectorPagePolicy,
+    trapped_write: Option<(u16, u8)>,
+    no_execute: Vec<RangeInclusive<u16>>,
+    observer: O,
+    // Addresses written and what they held before, while journaling
+    journal: Option<Vec<(u16, u8)>>,
+}
+
+// 64K of zeros, never on the stack
+fn zeroed() -> Box<[u8; 0x10000]> {
+    vec![0x00; 0x10000].into_boxed_slice().try_into().unwrap()
+}
+
+/// Shows the settings but not the 64K of contents.
+impl<O: AccessObserver + fmt::Debug> fmt::Debug for Memory8080<O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Memory8080")
+            .field("vector_page", &self.vector_page)
+            .field("no_execute", &self.no_execute)
+            .field("observer", &self.observer)
+            .field("journaling", &self.journal.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<O: AccessObserver> Memory for Memory8080<O> {
     fn read(&self, i: usize) -> u8 {
+        let i = i & 0xffff;
+        self.observer.access(i as u16, Access::Read);
         self.memory[i]
     }
 
     fn write(&mut self, i: usize, data: u8) {
+        let i = i & 0xffff;
+        self.observer.access(i as u16, Access::Write);
+        if i < 0x100 {
+            match self.vector_page {
+                VectorPagePolicy::Allow => {}
+                VectorPagePolicy::Ignore => return,
+                VectorPagePolicy::Trap => {
+                    self.trapped_write = Some((i as u16, data));
+                    return;
+                }
+            }
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.push((i as u16, self.memory[i]));
+        }
         self.memory[i] = data;
     }
 
     fn read16(&self, i: usize) -> u16 {
-        let hi = self.read(i + 1);
+        // A word at 0xffff has its high byte at 0x0000, as on the 8080
+        let hi = self.read((i + 1) & 0xffff);
         let lo = self.read(i);
 
         (u16::from(hi) << 8) | u16::from(lo)
@@ -30,28 +284,764 @@ impl Memory for Memory8080 {
         let hi = ((data & 0xff00) >> 8) as u8;
         let lo = (data & 0xff) as u8;
 
-        self.write(i + 1, hi);
+        self.write((i + 1) & 0xffff, hi);
         self.write(i, lo);
     }
+
+    fn peek(&self, i: usize) -> u8 {
+        self.memory[i & 0xffff]
+    }
+
+    fn is_executable(&self, addr: u16) -> bool {
+        !self.no_execute.iter().any(|range| range.contains(&addr))
+    }
+
+    fn take_guard_hit(&self) -> Option<u16> {
+        self.observer.take_guard_hit()
+    }
+
+    fn take_contended_accesses(&self) -> u32 {
+        self.observer.take_contended_accesses()
+    }
 }
 
 impl Memory8080 {
+    /// Zeroed memory, allocated straight on the heap.
     pub fn new_empty() -> Self {
-        Memory8080 {
-            memory: [0; 65536]
-        }
+        Memory8080::from_box(zeroed())
     }
 
+    /// Memory as it might look at power-on, filled according to `fill`.
+    pub fn power_on(fill: PowerOnFill) -> Self {
+        let mut memory = zeroed();
+        fill.fill(&mut memory[..]);
+        Memory8080::from_box(memory)
+    }
+
+    /// Memory holding `memory`. The array is copied to the heap; build it
+    /// there and use `from_box` to avoid having it on the stack at all.
     pub fn new(memory: [u8; 65536]) -> Self {
+        Memory8080::from_box(Box::new(memory))
+    }
+
+    pub fn from_box(memory: Box<[u8; 0x10000]>) -> Self {
+        Memory8080::with_observer(memory, Checks::default())
+    }
+
+    /// Adds a guard range, typically just below the stack, that must never
+    /// be read or written. Accesses still go through, but the first one is
+    /// remembered and the CPU reports it as `Event::Fault` after the
+    /// instruction that made it. Catches a stack growing into video RAM
+    /// at the push that does it.
+    pub fn set_guard(&mut self, range: RangeInclusive<u16>) {
+        self.observer.guards.push(range);
+    }
+
+    pub fn clear_guards(&mut self) {
+        self.observer.guards.clear();
+        self.observer.guard_hit.set(None);
+    }
+
+    /// Watches `range` for accesses going `access`'s way, for debuggers.
+    /// Unlike guards, a watch does not make the CPU fault; the first hit
+    /// is kept for `take_watch_hit`. Instruction fetches count as reads.
+    pub fn set_watch(&mut self, range: RangeInclusive<u16>, access: Access) {
+        self.observer.watches.push((range, access));
+    }
+
+    /// Removes the watches on exactly `range` going `access`'s way.
+    pub fn clear_watch(&mut self, range: RangeInclusive<u16>, access: Access) {
+        self.observer.watches.retain(|watch| *watch != (range.clone(), access));
+    }
+
+    /// Returns and clears the first watched access since the last call,
+    /// as `(address, access)`.
+    pub fn take_watch_hit(&self) -> Option<(u16, Access)> {
+        self.observer.watch_hit.take()
+    }
+
+    /// Counts reads and writes in `range`, for a contention model to turn
+    /// into wait states. `None` stops counting.
+    pub fn set_contended(&mut self, range: Option<RangeInclusive<u16>>) {
+        self.observer.contended = range;
+        self.observer.contended_accesses.set(0);
+    }
+}
+
+impl<O: AccessObserver> Memory8080<O> {
+    /// Memory holding `memory`, with `observer` seeing every access.
+    /// `Memory8080::with_observer(memory, Unchecked)` leaves out the
+    /// debugging checks.
+    pub fn with_observer(memory: Box<[u8; 0x10000]>, observer: O) -> Self {
         Memory8080 {
-            memory
+            memory,
+            vector_page: VectorPagePolicy::Allow,
+            trapped_write: None,
+            no_execute: Vec::new(),
+            observer,
+            journal: None,
+        }
+    }
+
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// Sets how writes to 0x0000-0x00ff are handled. The initial contents
+    /// given to `new` are not affected.
+    pub fn set_vector_page_policy(&mut self, policy: VectorPagePolicy) {
+        self.vector_page = policy;
+    }
+
+    /// Marks `range` as non-executable, so the CPU reports
+    /// `Event::Fault` instead of running code from it. The 8080 has no
+    /// such thing; this is an opt-in aid for catching wild jumps.
+    pub fn set_no_execute(&mut self, range: RangeInclusive<u16>) {
+        self.no_execute.push(range);
+    }
+
+    /// Makes all memory executable again.
+    pub fn clear_no_execute(&mut self) {
+        self.no_execute.clear();
+    }
+
+    /// The whole 64K, as read without going through `Memory::read`, so
+    /// guard ranges are not triggered.
+    pub fn contents(&self) -> &[u8] {
+        &self.memory[..]
+    }
+
+    /// Starts or stops keeping a journal of writes, so they can be undone.
+    /// Stopping throws away what has not been taken yet.
+    pub fn set_journal(&mut self, on: bool) {
+        self.journal = if on { Some(Vec::new()) } else { None };
+    }
+
+    /// Returns and clears the writes journaled since the last call, as
+    /// each address with the value it held before, oldest first.
+    pub fn take_journal(&mut self) -> Vec<(u16, u8)> {
+        self.journal.as_mut().map(core::mem::take).unwrap_or_default()
+    }
+
+    /// Puts back the values in `journal`, from `take_journal`, newest
+    /// first. Bypasses the vector page policy and is not journaled.
+    pub fn undo(&mut self, journal: &[(u16, u8)]) {
+        for &(addr, old) in journal.iter().rev() {
+            self.memory[usize::from(addr)] = old;
+        }
+    }
+
+    /// Replaces the whole 64K, bypassing the vector page policy.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `contents` is not exactly 64K.
+    pub fn set_contents(&mut self, contents: &[u8]) {
+        self.memory.copy_from_slice(contents);
+    }
+
+    /// Writes `bytes` from `addr` on as the host would, wrapping round at
+    /// the top of memory. Like `set_contents` it bypasses the vector page
+    /// policy, sets off no guard or watch and is not journaled, so a
+    /// debugger's patch is not undone by stepping back.
+    pub fn patch(&mut self, addr: u16, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.memory[(usize::from(addr) + i) & 0xffff] = byte;
+        }
+    }
+
+    /// Returns and clears the last write stopped by
+    /// `VectorPagePolicy::Trap`, as `(address, data)`.
+    pub fn take_trapped_write(&mut self) -> Option<(u16, u8)> {
+        self.trapped_write.take()
+    }
+}
+
+/// Something memory-mapped on a `Bus`. Accesses come with their offset
+/// from the start of the device's range.
+pub trait MemoryDevice {
+    fn read(&mut self, offset: u16) -> u8;
+    fn write(&mut self, offset: u16, value: u8);
+}
+
+enum Region {
+    Ram,
+    Rom,
+    // Accesses go to `base` plus the offset modulo `len`
+    Mirror { base: u16, len: usize },
+    Device(Rc<RefCell<dyn MemoryDevice>>),
+}
+
+// Where an access ends up once mirrors are followed
+enum Target<'a> {
+    Ram(usize),
+    Rom(usize),
+    Device(&'a RefCell<dyn MemoryDevice>, u16),
+}
+
+// Mirrors followed before giving up on a mirror that leads back to
+// itself and treating the address as plain RAM
+const MAX_MIRRORS: usize = 8;
+
+/// A 64K address space put together from regions: RAM, ROM, mirrors of
+/// other ranges and memory-mapped devices. Addresses not mapped to
+/// anything are RAM. Where ranges overlap, the one mapped last wins.
+///
+/// ```
+/// use i8080_emulator::cpu::CPU;
+/// use i8080_emulator::memory::{Bus, Memory};
+/// use std::cell::RefCell;
+/// use std::rc::Rc;
+///
+/// // LXI H, 0x4000; MVI M, 0x42; HLT
+/// let mut bus = Bus::new();
+/// bus.map_rom(0x0000..=0x1fff, &[0x21, 0x00, 0x40, 0x36, 0x42, 0x76]);
+/// bus.mirror(0x4000..=0x5fff, 0x2000..=0x3fff);
+/// let mut cpu = CPU::new(Rc::new(RefCell::new(bus)));
+/// while !cpu.is_halted() {
+///     cpu.step();
+/// }
+/// assert_eq!(cpu.memory.borrow().read(0x2000), 0x42);
+/// ```
+pub struct Bus {
+    memory: Vec<u8>,
+    regions: Vec<(RangeInclusive<u16>, Region)>,
+    rom_write: Option<(u16, u8)>,
+    swap_hooks: Vec<SwapHook>,
+}
+
+type SwapHook = Box<dyn FnMut(&RangeInclusive<u16>)>;
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus {
+    /// 64K of zeroed RAM with nothing mapped.
+    pub fn new() -> Self {
+        Bus {
+            memory: vec![0x00; 0x10000],
+            regions: Vec::new(),
+            rom_write: None,
+            swap_hooks: Vec::new(),
+        }
+    }
+
+    pub fn map_ram(&mut self, range: RangeInclusive<u16>) {
+        self.regions.push((range, Region::Ram));
+    }
+
+    /// Maps `range` as ROM holding `contents` from its start. Writes to it
+    /// are dropped; the first is kept for `take_rom_write`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `contents` is longer than `range`.
+    pub fn map_rom(&mut self, range: RangeInclusive<u16>, contents: &[u8]) {
+        let start = usize::from(*range.start());
+        assert!(contents.len() <= usize::from(*range.end()) - start + 1, "ROM contents larger than its range");
+        self.memory[start..start + contents.len()].copy_from_slice(contents);
+        self.regions.push((range, Region::Rom));
+    }
+
+    /// Replaces the image of the ROM mapped at `range`, as when a board
+    /// switches cartridges or game ROM banks. Bytes past the end of
+    /// `contents` read as 0x00. Every hook set with `on_rom_swap` is then
+    /// called, so that anything holding on to what the old image decoded
+    /// to can drop it. Swap between instructions, as a port write does.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no ROM is mapped at exactly `range`, or if `contents` is
+    /// longer than it.
+    pub fn swap_rom(&mut self, range: RangeInclusive<u16>, contents: &[u8]) {
+        assert!(self.regions.iter().any(|(r, region)| *r == range && matches!(region, Region::Rom)),
+                "no ROM mapped at 0x{:04x}-0x{:04x}", range.start(), range.end());
+        let start = usize::from(*range.start());
+        let image = &mut self.memory[start..=usize::from(*range.end())];
+        assert!(contents.len() <= image.len(), "ROM contents larger than its range");
+        let (loaded, rest) = image.split_at_mut(contents.len());
+        loaded.copy_from_slice(contents);
+        rest.fill(0x00);
+        for hook in &mut self.swap_hooks {
+            hook(&range);
+        }
+    }
+
+    /// Calls `hook` with the range of every ROM swapped by `swap_rom`. The
+    /// bus is borrowed while hooks run.
+    pub fn on_rom_swap(&mut self, hook: impl FnMut(&RangeInclusive<u16>) + 'static) {
+        self.swap_hooks.push(Box::new(hook));
+    }
+
+    /// Makes `range` an image of `of`, repeated if `range` is the larger.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `of` ends before it starts.
+    pub fn mirror(&mut self, range: RangeInclusive<u16>, of: RangeInclusive<u16>) {
+        assert!(of.start() <= of.end(), "cannot mirror the empty range 0x{:04x}-0x{:04x}", of.start(), of.end());
+        let len = usize::from(*of.end()) - usize::from(*of.start()) + 1;
+        self.regions.push((range, Region::Mirror { base: *of.start(), len }));
+    }
+
+    /// Hands accesses to `range` to `device`.
+    pub fn map_device<D: MemoryDevice + 'static>(&mut self, range: RangeInclusive<u16>, device: Rc<RefCell<D>>) {
+        self.regions.push((range, Region::Device(device)));
+    }
+
+    /// Returns and clears the first write to ROM since the last call, as
+    /// `(address, data)`.
+    pub fn take_rom_write(&mut self) -> Option<(u16, u8)> {
+        self.rom_write.take()
+    }
+
+    fn resolve(&self, i: usize) -> Target<'_> {
+        let mut addr = i as u16;
+        for _ in 0..MAX_MIRRORS {
+            let region = self.regions.iter().rev().find(|(range, _)| range.contains(&addr));
+            match region {
+                None | Some((_, Region::Ram)) => return Target::Ram(usize::from(addr)),
+                Some((_, Region::Rom)) => return Target::Rom(usize::from(addr)),
+                Some((range, Region::Device(device))) => return Target::Device(device, addr - range.start()),
+                Some((range, Region::Mirror { base, len })) => {
+                    let offset = usize::from(addr - range.start()) % len;
+                    addr = base.wrapping_add(offset as u16);
+                }
+            }
+        }
+        Target::Ram(usize::from(addr))
+    }
+}
+
+impl Memory for Bus {
+    fn read(&self, i: usize) -> u8 {
+        match self.resolve(i) {
+            Target::Ram(addr) | Target::Rom(addr) => self.memory[addr],
+            Target::Device(device, offset) => device.borrow_mut().read(offset),
+        }
+    }
+
+    fn write(&mut self, i: usize, data: u8) {
+        match self.resolve(i) {
+            Target::Ram(addr) => self.memory[addr] = data,
+            Target::Rom(addr) => {
+                if self.rom_write.is_none() {
+                    self.rom_write = Some((addr as u16, data));
+                }
+            }
+            Target::Device(device, offset) => device.borrow_mut().write(offset, data),
+        }
+    }
+
+    fn read16(&self, i: usize) -> u16 {
+        u16::from_le_bytes([self.read(i), self.read((i + 1) & 0xffff)])
+    }
+
+    fn write16(&mut self, i: usize, data: u16) {
+        let [lo, hi] = data.to_le_bytes();
+        self.write((i + 1) & 0xffff, hi);
+        self.write(i, lo);
+    }
+
+    /// Devices are not called; their addresses read as 0xff.
+    fn peek(&self, i: usize) -> u8 {
+        match self.resolve(i) {
+            Target::Ram(addr) | Target::Rom(addr) => self.memory[addr],
+            Target::Device(..) => 0xff,
+        }
+    }
+}
+
+/// A game ROM bank latch on a port, for multi-game boards: an OUT swaps
+/// the ROM at `range` on a `Bus` for one of several images, numbered from
+/// 0 and wrapping round the number of images. An IN reads back the image
+/// selected. Image 0 is put in place when the latch is created and on
+/// reset.
+///
+/// ```
+/// use i8080_emulator::device::Device;
+/// use i8080_emulator::memory::{Bus, Memory, RomBankSelect};
+/// use std::cell::RefCell;
+/// use std::rc::Rc;
+///
+/// let bus = Rc::new(RefCell::new(Bus::new()));
+/// bus.borrow_mut().map_rom(0x0000..=0x1fff, &[]);
+/// let mut latch = RomBankSelect::new(bus.clone(), 0x0000..=0x1fff, vec![vec![0x11], vec![0x22]]);
+/// latch.write(0x00, 1);
+/// assert_eq!(bus.borrow().read(0x0000), 0x22);
+/// ```
+pub struct RomBankSelect {
+    bus: Rc<RefCell<Bus>>,
+    range: RangeInclusive<u16>,
+    images: Vec<Vec<u8>>,
+    selected: usize,
+}
+
+impl RomBankSelect {
+    pub const STATE_ID: DeviceId = *b"ROMB";
+    pub const STATE_VERSION: u16 = 1;
+
+    /// # Panics
+    ///
+    /// Panics if `images` is empty, or as `Bus::swap_rom` does.
+    pub fn new(bus: Rc<RefCell<Bus>>, range: RangeInclusive<u16>, images: Vec<Vec<u8>>) -> Self {
+        assert!(!images.is_empty(), "RomBankSelect needs at least one image");
+        let mut latch = RomBankSelect { bus, range, images, selected: 0 };
+        latch.select(0);
+        latch
+    }
+
+    fn select(&mut self, image: usize) {
+        self.selected = image;
+        self.bus.borrow_mut().swap_rom(self.range.clone(), &self.images[image]);
+    }
+}
+
+impl Device for RomBankSelect {
+    fn reset(&mut self) {
+        self.select(0);
+    }
+
+    fn read(&mut self, _port: u8) -> u8 {
+        self.selected as u8
+    }
+
+    fn write(&mut self, _port: u8, value: u8) {
+        self.select(usize::from(value) % self.images.len());
+    }
+
+    /// The image selected, which goes back on the bus when loaded.
+    fn save_state(&self) -> Option<DeviceState> {
+        let mut state = DeviceState::new(RomBankSelect::STATE_ID, RomBankSelect::STATE_VERSION);
+        state.push_u8(self.selected as u8);
+        Some(state)
+    }
+
+    fn load_state(&mut self, state: &DeviceState) -> Result<(), StateError> {
+        let mut fields = state.reader();
+        let selected = usize::from(fields.u8()?);
+        fields.end()?;
+        if selected >= self.images.len() {
+            return Err(StateError::Corrupt);
+        }
+        self.select(selected);
+        Ok(())
+    }
+}
+
+/// More than 64K for systems such as CP/M 3 and MP/M: the low part of the
+/// address space is one of several banks, and the top part, from
+/// `common` up, is the same whatever bank is selected. The bank is chosen
+/// with `select`, or by OUT instructions through a `BankSelect` on a
+/// port.
+///
+/// ```
+/// use i8080_emulator::cpu::CPU;
+/// use i8080_emulator::io::PortBus;
+/// use i8080_emulator::memory::{BankSelect, BankedMemory, Memory};
+/// use std::cell::RefCell;
+/// use std::rc::Rc;
+///
+/// // MVI A, 1; OUT 0x40; STA 0x0100; STA 0xf000; HLT, in common memory
+/// let memory = Rc::new(RefCell::new(BankedMemory::new(2, 0xc000)));
+/// memory.borrow_mut().load(0xc000, &[0x3e, 0x01, 0xd3, 0x40, 0x32, 0x00, 0x01, 0x32, 0x00, 0xf0, 0x76]);
+/// let mut cpu = CPU::new(memory.clone());
+/// cpu.pc = 0xc000;
+/// let mut bus = PortBus::new();
+/// bus.map(0x40, Rc::new(RefCell::new(BankSelect::new(memory.clone()))));
+/// while !cpu.is_halted() {
+///     bus.step(&mut cpu);
+/// }
+/// let mut memory = memory.borrow_mut();
+/// assert_eq!((memory.read(0x0100), memory.read(0xf000)), (0x01, 0x01));
+/// memory.select(0);
+/// assert_eq!((memory.read(0x0100), memory.read(0xf000)), (0x00, 0x01));
+/// ```
+pub struct BankedMemory {
+    // Each covers 0x0000 up to `common`
+    banks: Vec<Vec<u8>>,
+    common: Vec<u8>,
+    common_start: u16,
+    selected: usize,
+}
+
+impl BankedMemory {
+    /// `banks` zeroed banks below `common`, with bank 0 selected. A
+    /// `common` of 0x0000 makes all memory common.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `banks` is 0.
+    pub fn new(banks: usize, common: u16) -> Self {
+        assert!(banks > 0, "BankedMemory needs at least one bank");
+        BankedMemory {
+            banks: vec![vec![0x00; usize::from(common)]; banks],
+            common: vec![0x00; 0x10000 - usize::from(common)],
+            common_start: common,
+            selected: 0,
+        }
+    }
+
+    pub fn bank_count(&self) -> usize {
+        self.banks.len()
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// Switches the banked part of the address space to `bank`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no such bank.
+    pub fn select(&mut self, bank: usize) {
+        assert!(bank < self.banks.len(), "no bank {}", bank);
+        self.selected = bank;
+    }
+
+    /// The contents of `bank`, which need not be selected.
+    pub fn bank(&self, bank: usize) -> &[u8] {
+        &self.banks[bank]
+    }
+
+    pub fn bank_mut(&mut self, bank: usize) -> &mut [u8] {
+        &mut self.banks[bank]
+    }
+
+    /// Copies `contents` to `addr` as the CPU would see it with the
+    /// current bank selected.
+    pub fn load(&mut self, addr: u16, contents: &[u8]) {
+        for (i, &byte) in contents.iter().enumerate() {
+            self.write((usize::from(addr) + i) & 0xffff, byte);
+        }
+    }
+}
+
+impl Memory for BankedMemory {
+    fn read(&self, i: usize) -> u8 {
+        let i = i & 0xffff;
+        match i.checked_sub(usize::from(self.common_start)) {
+            Some(offset) => self.common[offset],
+            None => self.banks[self.selected][i],
+        }
+    }
+
+    fn write(&mut self, i: usize, data: u8) {
+        let i = i & 0xffff;
+        match i.checked_sub(usize::from(self.common_start)) {
+            Some(offset) => self.common[offset] = data,
+            None => self.banks[self.selected][i] = data,
+        }
+    }
+
+    fn read16(&self, i: usize) -> u16 {
+        u16::from_le_bytes([self.read(i), self.read((i + 1) & 0xffff)])
+    }
+
+    fn write16(&mut self, i: usize, data: u16) {
+        let [lo, hi] = data.to_le_bytes();
+        self.write((i + 1) & 0xffff, hi);
+        self.write(i, lo);
+    }
+}
+
+/// The bank-select latch of a `BankedMemory`, for attaching to a port. An
+/// OUT selects a bank, wrapping round the number of banks as a latch with
+/// more bits than banks would; an IN reads back the bank selected.
+pub struct BankSelect(Rc<RefCell<BankedMemory>>);
+
+impl BankSelect {
+    pub const STATE_ID: DeviceId = *b"BANK";
+    pub const STATE_VERSION: u16 = 1;
+
+    pub fn new(memory: Rc<RefCell<BankedMemory>>) -> Self {
+        BankSelect(memory)
+    }
+}
+
+impl Device for BankSelect {
+    fn reset(&mut self) {
+        self.0.borrow_mut().select(0);
+    }
+
+    fn read(&mut self, _port: u8) -> u8 {
+        self.0.borrow().selected as u8
+    }
+
+    fn write(&mut self, _port: u8, value: u8) {
+        let mut memory = self.0.borrow_mut();
+        memory.selected = usize::from(value) % memory.banks.len();
+    }
+
+    /// The bank selected and what is in every bank, as a CPU's save state
+    /// only sees the selected one. Common memory is left to the CPU's.
+    fn save_state(&self) -> Option<DeviceState> {
+        let memory = self.0.borrow();
+        let mut state = DeviceState::new(BankSelect::STATE_ID, BankSelect::STATE_VERSION);
+        state.push_u16(memory.selected as u16);
+        state.push_u16(memory.banks.len() as u16);
+        for bank in &memory.banks {
+            state.push_bytes(bank);
+        }
+        Some(state)
+    }
+
+    fn load_state(&mut self, state: &DeviceState) -> Result<(), StateError> {
+        let mut memory = self.0.borrow_mut();
+        let mut fields = state.reader();
+        let (selected, count) = (usize::from(fields.u16()?), usize::from(fields.u16()?));
+        if count != memory.banks.len() || selected >= count {
+            return Err(StateError::Corrupt);
+        }
+        let mut banks = Vec::with_capacity(count);
+        for _ in 0..count {
+            let bank = fields.bytes()?;
+            if bank.len() != usize::from(memory.common_start) {
+                return Err(StateError::Corrupt);
+            }
+            banks.push(bank.to_vec());
+        }
+        fields.end()?;
+        memory.selected = selected;
+        memory.banks = banks;
+        Ok(())
+    }
+}
+
+type WatchCallback = Box<dyn FnMut(u16, u8, u8)>;
+
+// A callback on accesses to `range` going `access`'s way
+struct Watcher {
+    range: RangeInclusive<u16>,
+    access: Access,
+    callback: RefCell<WatchCallback>,
+}
+
+/// Any `Memory` with callbacks on reads and writes of address ranges, for
+/// redrawing only the parts of video RAM that changed or stopping a
+/// debugger when data is touched. A write callback gets the address, the
+/// byte there before and the byte there after, which for ROM is the same;
+/// a read callback gets the byte read twice. Words are seen a byte at a
+/// time.
+///
+/// ```
+/// use i8080_emulator::memory::{Access, Memory, Memory8080, WatchedMemory};
+/// use std::cell::RefCell;
+/// use std::rc::Rc;
+///
+/// let dirty = Rc::new(RefCell::new(Vec::new()));
+/// let log = dirty.clone();
+/// let mut memory = WatchedMemory::new(Memory8080::new_empty());
+/// memory.watch(0x2400..=0x3fff, Access::Write, move |addr, old, new| {
+///     log.borrow_mut().push((addr, old, new));
+/// });
+/// memory.write(0x2000, 0xff);
+/// memory.write(0x2400, 0xff);
+/// assert_eq!(*dirty.borrow(), [(0x2400, 0x00, 0xff)]);
+/// ```
+pub struct WatchedMemory<M: Memory> {
+    inner: M,
+    watchers: Vec<Watcher>,
+}
+
+impl<M: Memory> WatchedMemory<M> {
+    pub fn new(inner: M) -> Self {
+        WatchedMemory { inner, watchers: Vec::new() }
+    }
+
+    /// Calls `callback` on every access to `range` going `access`'s way.
+    /// Instruction fetches count as reads; `peek` does not.
+    pub fn watch(&mut self, range: RangeInclusive<u16>, access: Access, callback: impl FnMut(u16, u8, u8) + 'static) {
+        self.watchers.push(Watcher { range, access, callback: RefCell::new(Box::new(callback)) });
+    }
+
+    /// Removes every callback on exactly `range` going `access`'s way.
+    pub fn unwatch(&mut self, range: RangeInclusive<u16>, access: Access) {
+        self.watchers.retain(|watcher| watcher.range != range || watcher.access != access);
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// The wrapped memory, for changing it without setting off callbacks.
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    fn notify(&self, i: usize, access: Access, old: u8, new: u8) {
+        let addr = i as u16;
+        for watcher in self.watchers.iter().filter(|w| w.access == access && w.range.contains(&addr)) {
+            (watcher.callback.borrow_mut())(addr, old, new);
         }
     }
 }
 
+impl<M: Memory> Memory for WatchedMemory<M> {
+    fn read(&self, i: usize) -> u8 {
+        let i = i & 0xffff;
+        let data = self.inner.read(i);
+        self.notify(i, Access::Read, data, data);
+        data
+    }
+
+    fn write(&mut self, i: usize, data: u8) {
+        let i = i & 0xffff;
+        let old = self.inner.peek(i);
+        self.inner.write(i, data);
+        self.notify(i, Access::Write, old, self.inner.peek(i));
+    }
+
+    fn read16(&self, i: usize) -> u16 {
+        u16::from_le_bytes([self.read(i), self.read((i + 1) & 0xffff)])
+    }
+
+    fn write16(&mut self, i: usize, data: u16) {
+        // High byte first, as Memory8080 and a PUSH do
+        let [lo, hi] = data.to_le_bytes();
+        self.write((i + 1) & 0xffff, hi);
+        self.write(i, lo);
+    }
+
+    fn peek(&self, i: usize) -> u8 {
+        self.inner.peek(i)
+    }
+
+    fn is_executable(&self, addr: u16) -> bool {
+        self.inner.is_executable(addr)
+    }
+
+    fn note_fetch(&self, addr: u16) {
+        self.inner.note_fetch(addr)
+    }
+
+    fn take_guard_hit(&self) -> Option<u16> {
+        self.inner.take_guard_hit()
+    }
+
+    fn take_contended_accesses(&self) -> u32 {
+        self.inner.take_contended_accesses()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::memory::{Memory8080, Memory};
+    use crate::cpu::CPU;
+    use crate::device::{restore, Device, StateError};
+    use crate::memory::{Access, BankSelect, BankedMemory, Bus, Memory8080, Memory, MemoryDevice, PowerOnFill, RomBankSelect, Unchecked, VectorPagePolicy, WatchedMemory};
+    use crate::registers::Registers;
+
+    use std::cell::RefCell;
+    use std::ops::RangeInclusive;
+    use std::rc::Rc;
 
     #[test]
     fn read() {
@@ -68,6 +1058,67 @@ mod tests {
         assert_eq!(memory.memory[4], 0xff);
     }
 
+    #[test]
+    fn clone_compare_and_debug() {
+        let mut memory = Memory8080::new_empty();
+        let copy = memory.clone();
+        assert_eq!(memory, copy);
+        memory.write(0x1234, 0x01);
+        assert!(memory != copy);
+        assert!(format!("{:?}", memory).starts_with("Memory8080 { vector_page: Allow"));
+    }
+
+    #[test]
+    fn unchecked() {
+        assert_eq!(core::mem::size_of::<Unchecked>(), 0);
+        // LXI SP, 0x2400; PUSH B; HLT
+        let mut memory = Memory8080::with_observer(Box::new([0x00; 0x10000]), Unchecked);
+        for (i, &b) in [0x31, 0x00, 0x24, 0xc5, 0x76].iter().enumerate() {
+            memory.write(i, b);
+        }
+        let mut cpu = CPU::new(Rc::new(RefCell::new(memory)));
+        cpu.regs.set_bc(0x1234);
+        while !cpu.is_halted() {
+            cpu.step();
+        }
+        assert_eq!(cpu.memory.borrow().read16(0x23fe), 0x1234);
+        assert_eq!(cpu.memory.borrow().take_guard_hit(), None);
+    }
+
+    #[test]
+    fn built_on_the_heap() {
+        const REGS: Registers = Registers::new();
+        let mut contents = Box::new([0x00; 0x10000]);
+        contents[0x1234] = 0x01;
+        let mut memory = Memory8080::new_empty();
+        memory.write(0x1234, 0x01);
+        assert_eq!(Memory8080::from_box(contents), memory);
+        // Small enough to move around freely
+        assert!(std::mem::size_of::<Memory8080>() < 0x400);
+
+        let cpu = CPU::new_empty();
+        assert_eq!((cpu.regs, cpu.pc), (REGS, 0x0000));
+        assert!(cpu.memory.borrow().contents().iter().all(|&byte| byte == 0x00));
+    }
+
+    #[test]
+    fn words_wrap_at_the_top() {
+        let mut memory = Memory8080::new_empty();
+        memory.write16(0xffff, 0x1234);
+        assert_eq!((memory.memory[0xffff], memory.memory[0x0000]), (0x34, 0x12));
+        assert_eq!(memory.read16(0xffff), 0x1234);
+    }
+
+    #[test]
+    fn addresses_past_the_top_wrap() {
+        let mut memory = Memory8080::new_empty();
+        memory.write(0x10004, 0x12);
+        assert_eq!((memory.memory[4], memory.read(0x10004), memory.peek(usize::MAX)), (0x12, 0x12, 0x00));
+        let mut banked = BankedMemory::new(2, 0xc000);
+        banked.write(0x1c000, 0x34);
+        assert_eq!(banked.read(0xc000), 0x34);
+    }
+
     #[test]
     fn read16() {
         let mut memory = Memory8080::new_empty();
@@ -83,4 +1134,281 @@ mod tests {
         assert_eq!(memory.memory[5], 0xff);
         assert_eq!(memory.memory[4], 0x02);
     }
+
+    #[test]
+    fn word_order_is_little_endian_on_any_host() {
+        let mut memory = Memory8080::new_empty();
+        for &data in &[0x0000, 0x00ff, 0xff00, 0x1234, 0xfffe] {
+            memory.write16(0x200, data);
+            assert_eq!([memory.read(0x200), memory.read(0x201)], u16::to_le_bytes(data));
+            assert_eq!(memory.read16(0x200), data);
+        }
+    }
+
+    #[test]
+    fn no_execute_ranges() {
+        let mut memory = Memory8080::new_empty();
+        memory.set_no_execute(0x2400..=0x3fff);
+        assert!(memory.is_executable(0x23ff));
+        assert!(!memory.is_executable(0x2400));
+        assert!(!memory.is_executable(0x3fff));
+        memory.clear_no_execute();
+        assert!(memory.is_executable(0x2400));
+    }
+
+    #[test]
+    fn guard_ranges() {
+        let mut memory = Memory8080::new_empty();
+        memory.set_guard(0x2300..=0x23ff);
+        memory.write(0x2400, 0x01);
+        assert_eq!(memory.take_guard_hit(), None);
+        assert_eq!(memory.read(0x2380), 0x00);
+        memory.write16(0x23fe, 0x1234);
+        assert_eq!(memory.take_guard_hit(), Some(0x2380));
+        assert_eq!(memory.take_guard_hit(), None);
+        assert_eq!(memory.read16(0x23fe), 0x1234);
+    }
+
+    #[test]
+    fn watches() {
+        let mut memory = Memory8080::new_empty();
+        memory.set_watch(0x2000..=0x2001, Access::Write);
+        memory.set_watch(0x3000..=0x3000, Access::Read);
+        memory.read(0x2000);
+        memory.write(0x3000, 0x01);
+        assert_eq!(memory.take_watch_hit(), None);
+        memory.write16(0x1fff, 0xabcd);
+        memory.read(0x3000);
+        assert_eq!(memory.take_watch_hit(), Some((0x2000, Access::Write)));
+        memory.read(0x3000);
+        assert_eq!(memory.take_watch_hit(), Some((0x3000, Access::Read)));
+        memory.clear_watch(0x3000..=0x3000, Access::Read);
+        memory.read(0x3000);
+        assert_eq!(memory.take_watch_hit(), None);
+    }
+
+    #[test]
+    fn watch_callbacks() {
+        // MVI A, 0x5a; STA 0x2400; LDA 0x2400; PUSH B, with SP at 0x2402
+        let mut contents = [0x00; 0x10000];
+        contents[..9].copy_from_slice(&[0x3e, 0x5a, 0x32, 0x00, 0x24, 0x3a, 0x00, 0x24, 0xc5]);
+        let mut memory = WatchedMemory::new(Memory8080::new(contents));
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let writes = log.clone();
+        memory.watch(0x2400..=0x3fff, Access::Write, move |addr, old, new| writes.borrow_mut().push((Access::Write, addr, old, new)));
+        let reads = log.clone();
+        memory.watch(0x2400..=0x2400, Access::Read, move |addr, old, new| reads.borrow_mut().push((Access::Read, addr, old, new)));
+        let mut cpu = CPU::new(Rc::new(RefCell::new(memory)));
+        cpu.set_sp(0x2402);
+        cpu.regs.set_bc(0x1234);
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(*log.borrow(), [
+            (Access::Write, 0x2400, 0x00, 0x5a),
+            (Access::Read, 0x2400, 0x5a, 0x5a),
+            (Access::Write, 0x2401, 0x00, 0x12),
+            (Access::Write, 0x2400, 0x5a, 0x34),
+        ]);
+
+        let mut memory = cpu.memory.borrow_mut();
+        memory.unwatch(0x2400..=0x2400, Access::Read);
+        memory.inner_mut().write(0x2400, 0x00);
+        assert_eq!((memory.read(0x2400), memory.peek(0x2401)), (0x00, 0x12));
+        assert_eq!(log.borrow().len(), 4);
+    }
+
+    #[test]
+    fn vector_page_allow() {
+        let mut memory = Memory8080::new_empty();
+        memory.write(0x05, 0xc9);
+        assert_eq!(memory.read(0x05), 0xc9);
+        assert_eq!(memory.take_trapped_write(), None);
+    }
+
+    #[test]
+    fn vector_page_ignore() {
+        let mut rom = [0; 0x10000];
+        rom[0x38] = 0xc3;
+        let mut memory = Memory8080::new(rom);
+        memory.set_vector_page_policy(VectorPagePolicy::Ignore);
+        memory.write(0x38, 0x00);
+        memory.write(0x100, 0x01);
+        assert_eq!(memory.read(0x38), 0xc3);
+        assert_eq!(memory.read(0x100), 0x01);
+    }
+
+    #[test]
+    fn vector_page_trap() {
+        let mut memory = Memory8080::new_empty();
+        memory.set_vector_page_policy(VectorPagePolicy::Trap);
+        memory.write16(0x08, 0x1234);
+        assert_eq!(memory.read16(0x08), 0x0000);
+        assert_eq!(memory.take_trapped_write(), Some((0x08, 0x34)));
+        assert_eq!(memory.take_trapped_write(), None);
+    }
+
+    #[test]
+    fn power_on_fill() {
+        assert_eq!(Memory8080::power_on(PowerOnFill::Zeros).read(0x1234), 0x00);
+        assert_eq!(Memory8080::power_on(PowerOnFill::Ones).read(0x1234), 0xff);
+        let stripes = Memory8080::power_on(PowerOnFill::Stripes);
+        assert_eq!([stripes.read(0x3f), stripes.read(0x40), stripes.read(0x7f), stripes.read(0x80)],
+                   [0x00, 0xff, 0xff, 0x00]);
+        let bytes = |memory: &Memory8080| (0..0x10000).map(|i| memory.read(i)).collect::<Vec<_>>();
+        let random = bytes(&Memory8080::power_on(PowerOnFill::Random(0)));
+        assert_eq!(random, bytes(&Memory8080::power_on(PowerOnFill::Random(0))));
+        assert_ne!(random, bytes(&Memory8080::power_on(PowerOnFill::Random(1))));
+        // Roughly half the bits are set
+        let ones: u32 = random.iter().map(|b| b.count_ones()).sum();
+        assert!((240_000..285_000).contains(&ones), "{}", ones);
+    }
+
+    #[test]
+    fn hexdump() {
+        let mut memory = Memory8080::new_empty();
+        for (i, b) in b"Hello, 8080!\x00\x01\xff".iter().enumerate() {
+            memory.write(0x2000 + i, *b);
+        }
+        assert_eq!(memory.hexdump(0x2000..=0x2011), concat!(
+            "2000  48 65 6c 6c 6f 2c 20 38 30 38 30 21 00 01 ff 00  |Hello, 8080!....|\n",
+            "2010  00 00                                            |..|\n",
+        ));
+        let previous: Vec<u8> = (0x2008..0x2010).map(|i| memory.read(i)).collect();
+        memory.write(0x2009, b'1');
+        // Past the end of the snapshot, so not marked
+        memory.write(0x2010, 0x42);
+        assert_eq!(memory.hexdump_diff(0x2008..=0x2010, &previous),
+                   "2008  30*31 30 21 00 01 ff 00 42                       |010!....B|\n");
+    }
+
+    // Remembers what is written to it and reads back the offset
+    struct Latch(Vec<(u16, u8)>);
+
+    impl MemoryDevice for Latch {
+        fn read(&mut self, offset: u16) -> u8 {
+            offset as u8
+        }
+
+        fn write(&mut self, offset: u16, value: u8) {
+            self.0.push((offset, value));
+        }
+    }
+
+    #[test]
+    fn bus_regions() {
+        let latch = Rc::new(RefCell::new(Latch(Vec::new())));
+        let mut bus = Bus::new();
+        bus.map_rom(0x0000..=0x00ff, &[0x01, 0x02]);
+        bus.mirror(0x4000..=0x5fff, 0x2000..=0x20ff);
+        bus.map_device(0x6000..=0x60ff, latch.clone());
+        bus.map_ram(0x0080..=0x00ff);
+
+        bus.write(0x0000, 0xff);
+        bus.write(0x0001, 0xff);
+        assert_eq!(bus.take_rom_write(), Some((0x0000, 0xff)));
+        assert_eq!(bus.take_rom_write(), None);
+        assert_eq!(bus.read16(0x0000), 0x0201);
+        bus.write(0x0080, 0x33);
+        assert_eq!(bus.read(0x0080), 0x33);
+
+        bus.write(0x4101, 0x44);
+        assert_eq!((bus.read(0x2001), bus.read(0x5f01)), (0x44, 0x44));
+
+        bus.write16(0x6010, 0xbeef);
+        assert_eq!(latch.borrow().0, [(0x11, 0xbe), (0x10, 0xef)]);
+        assert_eq!(bus.read(0x6020), 0x20);
+        assert_eq!(bus.peek(0x6020), 0xff);
+        // Dumps peek, so the device is not read
+        assert!(bus.hexdump(0x6020..=0x6021).starts_with("6020  ff ff"));
+
+        // A mirror of itself gives up rather than looping
+        bus.mirror(0x8000..=0x80ff, 0x8000..=0x80ff);
+        bus.write(0x8001, 0x55);
+        assert_eq!(bus.read(0x8001), 0x55);
+    }
+
+    #[test]
+    fn banked_memory() {
+        let banked = Rc::new(RefCell::new(BankedMemory::new(3, 0xc000)));
+        let mut latch = BankSelect::new(banked.clone());
+        let mut memory = banked.borrow_mut();
+        assert_eq!(memory.bank_count(), 3);
+        memory.write(0x0000, 0x10);
+        memory.write16(0xbfff, 0x2211);
+        memory.select(2);
+        assert_eq!(memory.read16(0xbfff), 0x2200);
+        memory.write(0x0000, 0x12);
+        assert_eq!(memory.bank(0)[0], 0x10);
+        assert_eq!(memory.bank(2)[0], 0x12);
+        memory.bank_mut(1)[0] = 0x11;
+
+        drop(memory);
+
+        // Through the latch
+        latch.write(0x40, 1);
+        assert_eq!((banked.borrow().read(0x0000), latch.read(0x40)), (0x11, 1));
+        latch.write(0x40, 5);
+        assert_eq!(banked.borrow().selected(), 2);
+        latch.reset();
+        let mut memory = banked.borrow_mut();
+        assert_eq!(memory.read(0x0000), 0x10);
+
+        // Words wrap from common memory into the selected bank
+        memory.write16(0xffff, 0xbbaa);
+        assert_eq!((memory.read(0xffff), memory.bank(0)[0]), (0xaa, 0xbb));
+        drop(memory);
+
+        // Every bank is saved, not just the one the CPU sees
+        latch.write(0x40, 1);
+        let saved = latch.save_state().unwrap();
+        let copy = Rc::new(RefCell::new(BankedMemory::new(3, 0xc000)));
+        restore(&mut BankSelect::new(copy.clone()), saved.clone()).unwrap();
+        assert_eq!(copy.borrow().selected(), 1);
+        assert_eq!((copy.borrow().bank(0)[0], copy.borrow().bank(1)[0], copy.borrow().bank(2)[0]), (0xbb, 0x11, 0x12));
+        let fewer = Rc::new(RefCell::new(BankedMemory::new(2, 0xc000)));
+        assert_eq!(restore(&mut BankSelect::new(fewer), saved), Err(StateError::Corrupt));
+    }
+
+    #[test]
+    fn rom_swaps() {
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        let swaps = Rc::new(RefCell::new(Vec::new()));
+        let log = swaps.clone();
+        bus.borrow_mut().map_rom(0x1000..=0x10ff, &[]);
+        bus.borrow_mut().on_rom_swap(move |range| log.borrow_mut().push(range.clone()));
+        let mut latch = RomBankSelect::new(bus.clone(), 0x1000..=0x10ff, vec![vec![0xaa, 0xbb], vec![0xcc]]);
+        assert_eq!(bus.borrow().read16(0x1000), 0xbbaa);
+        latch.write(0x00, 3);
+        assert_eq!((bus.borrow().read16(0x1000), latch.read(0x00)), (0x00cc, 1));
+        latch.reset();
+        assert_eq!(bus.borrow().read(0x1000), 0xaa);
+        assert_eq!(swaps.borrow().len(), 3);
+        assert_eq!(swaps.borrow()[0], 0x1000..=0x10ff);
+
+        // Still ROM after a swap
+        bus.borrow_mut().write(0x1000, 0x00);
+        assert_eq!(bus.borrow_mut().take_rom_write(), Some((0x1000, 0x00)));
+        assert_eq!(bus.borrow().read(0x1000), 0xaa);
+
+        latch.write(0x00, 1);
+        let saved = latch.save_state().unwrap();
+        latch.reset();
+        restore(&mut latch, saved).unwrap();
+        assert_eq!(bus.borrow().read(0x1000), 0xcc);
+    }
+
+    #[test]
+    #[should_panic(expected = "no ROM mapped at 0x2000-0x20ff")]
+    fn rom_swaps_need_a_rom() {
+        let mut bus = Bus::new();
+        bus.map_ram(0x2000..=0x20ff);
+        bus.swap_rom(0x2000..=0x20ff, &[0x00]);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot mirror the empty range 0x20ff-0x2000")]
+    fn mirrors_need_a_range() {
+        Bus::new().mirror(0x4000..=0x40ff, RangeInclusive::new(0x20ff, 0x2000));
+    }
 }
@@ -0,0 +1,187 @@
+//! A built-in machine profile that boots into a tiny serial monitor, so
+//! there is something interactive to run without any external ROM files.
+//!
+//! The monitor ROM (source in `roms/monitor.asm`) sits at 0x0000 and talks
+//! over a `Serial` port at `SERIAL_PORT`. Its stack and line buffer live
+//! around 0xff00; the rest of memory is free RAM. It prints a banner and a
+//! `> ` prompt and takes one command per line, with hex arguments:
+//!
+//! | Command         | Effect                                          |
+//! |-----------------|-------------------------------------------------|
+//! | `D aaaa`        | dump 16 bytes from `aaaa`                       |
+//! | `E aaaa bb ...` | deposit bytes from `aaaa` on                    |
+//! | `G aaaa`        | call `aaaa`; a RET returns to the monitor       |
+//! | `L`             | load Intel HEX records until an end record      |
+//!
+//! ```
+//! use i8080_emulator::monitor::MonitorMachine;
+//! use i8080_emulator::Machine;
+//!
+//! let mut machine = MonitorMachine::new();
+//! machine.run().unwrap();
+//! assert_eq!(machine.take_output(), "8080 MONITOR\r\n> ");
+//! ```
+
+use crate::cpu::{Event, Timestamp, CPU};
+use crate::device::Device;
+use crate::devices::serial::Serial;
+use crate::error::EmulatorError;
+use crate::io::PortBus;
+use crate::loader::load_bytes;
+use crate::memory::Memory8080;
+use crate::Machine;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The assembled monitor, loaded at 0x0000.
+pub const ROM: &[u8] = include_bytes!("../roms/monitor.bin");
+
+/// The serial status port; data is on the port after it.
+pub const SERIAL_PORT: u8 = 0x10;
+
+/// RAM everywhere, the monitor ROM at 0x0000 and a serial console.
+pub struct MonitorMachine {
+    pub cpu: CPU,
+    bus: PortBus,
+    serial: Rc<RefCell<Serial>>,
+}
+
+impl Default for MonitorMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MonitorMachine {
+    pub fn new() -> Self {
+        let mut memory = Memory8080::new_empty();
+        load_bytes(&mut memory, ROM, 0x0000).expect("the monitor ROM fits in memory");
+        let cpu = CPU::new(Rc::new(RefCell::new(memory)));
+        let serial = Rc::new(RefCell::new(Serial::new(SERIAL_PORT)));
+        let mut bus = PortBus::new();
+        bus.map(SERIAL_PORT, serial.clone());
+        bus.map(SERIAL_PORT + 1, serial.clone());
+        MonitorMachine { cpu, bus, serial }
+    }
+
+    /// Types `text` into the console.
+    pub fn send(&mut self, text: &str) {
+        self.serial.borrow_mut().send(text.as_bytes());
+    }
+
+    /// Returns what the monitor printed since the last call.
+    pub fn take_output(&mut self) -> String {
+        String::from_utf8_lossy(&self.serial.borrow_mut().take_output()).into_owned()
+    }
+}
+
+impl Machine for MonitorMachine {
+    type Event = Event;
+
+    fn next(&mut self) -> Result<Event, EmulatorError> {
+        match self.bus.step(&mut self.cpu) {
+            Event::Halt(_) => Err(EmulatorError::Halted(self.cpu.pc.wrapping_sub(1))),
+            Event::Fault(fault) => Err(EmulatorError::Fault(fault)),
+            event => Ok(event),
+        }
+    }
+
+    /// Runs until the monitor has consumed everything sent to it and is
+    /// waiting for more input.
+    fn run(&mut self) -> Result<(), EmulatorError> {
+        while !self.is_finished() {
+            self.next()?;
+        }
+        Ok(())
+    }
+
+    /// Restarts the monitor. Anything sent but not yet read is dropped.
+    fn reset(&mut self) {
+        self.cpu.reset();
+        self.serial.borrow_mut().reset();
+    }
+
+    fn cycles(&self) -> Timestamp {
+        self.cpu.cycles()
+    }
+
+    /// Whether the monitor is waiting for input that has not been sent.
+    fn is_finished(&self) -> bool {
+        self.serial.borrow().is_starved()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::EmulatorError;
+    use crate::memory::Memory;
+    use crate::monitor::{MonitorMachine, ROM};
+    use crate::Machine;
+
+    // Boots the monitor and returns it at its first prompt
+    fn booted() -> MonitorMachine {
+        let mut machine = MonitorMachine::new();
+        machine.run().unwrap();
+        assert_eq!(machine.take_output(), "8080 MONITOR\r\n> ");
+        machine
+    }
+
+    fn command(machine: &mut MonitorMachine, line: &str) -> String {
+        machine.send(line);
+        machine.run().unwrap();
+        machine.take_output()
+    }
+
+    #[test]
+    fn dump() {
+        let mut machine = booted();
+        let bytes: String = ROM[..16].iter().map(|b| format!(" {:02X}", b)).collect();
+        assert_eq!(command(&mut machine, "d 0000\r"), format!("D 0000\r\n0000:{}\r\n> ", bytes));
+    }
+
+    #[test]
+    fn deposit_and_dump() {
+        let mut machine = booted();
+        assert_eq!(command(&mut machine, "E 2000 12 34 5\r"), "E 2000 12 34 5\r\n> ");
+        assert_eq!(&command(&mut machine, "D 2000\r")[8..29], "2000: 12 34 05 00 00 ");
+    }
+
+    #[test]
+    fn go_returns_to_the_monitor() {
+        let mut machine = booted();
+        // MVI A, '!'; OUT 0x11; RET
+        command(&mut machine, "E 3000 3E 21 D3 11 C9\r");
+        assert_eq!(command(&mut machine, "G 3000\r"), "G 3000\r\n!> ");
+    }
+
+    #[test]
+    fn load_intel_hex() {
+        let mut machine = booted();
+        let output = command(&mut machine, "L\r:03410000010203B6\n:00000001FF\n");
+        assert_eq!(output, "L\r\n..\r\n> ");
+        assert_eq!(machine.cpu.memory.borrow().read(0x4101), 0x02);
+        // A bad checksum is reported. Line feeds between records are
+        // ignored, so they do not show up as empty commands afterwards.
+        assert_eq!(command(&mut machine, "L\r:0141000001BE\n"), "L\r\n?\r\n> ");
+    }
+
+    #[test]
+    fn errors_and_editing() {
+        let mut machine = booted();
+        assert_eq!(command(&mut machine, "X\r"), "X\r\n?\r\n> ");
+        assert_eq!(command(&mut machine, "D\r"), "D\r\n?\r\n> ");
+        assert_eq!(command(&mut machine, "\r"), "\r\n> ");
+        // Backspace erases on screen and in the line
+        assert_eq!(command(&mut machine, "Q\x08E 2000 7\r"), "Q\x08 \x08E 2000 7\r\n> ");
+        assert_eq!(machine.cpu.memory.borrow().read(0x2000), 0x07);
+    }
+
+    #[test]
+    fn halting_program() {
+        let mut machine = booted();
+        command(&mut machine, "E 3000 76\r");
+        machine.send("G 3000\r");
+        assert_eq!(machine.run(), Err(EmulatorError::Halted(0x3000)));
+    }
+}
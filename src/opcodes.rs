@@ -0,0 +1,515 @@
+//! Static metadata for all 256 opcodes, usable in const contexts.
+//!
+//! This is meant to be the single source of truth for instruction lengths,
+//! timings, operands and flags. Tests check it against what `CPU::exec`
+//! does, and downstream crates can build their own const lookup structures
+//! on top of it.
+//!
+//! `write_json` exports the table along with the flags each instruction
+//! reads and writes, for diffing against other emulators and datasheets:
+//!
+//! ```
+//! let mut json = Vec::new();
+//! i8080_emulator::opcodes::write_json(&mut json).unwrap();
+//! let json = String::from_utf8(json).unwrap();
+//! assert!(json.contains(r#"{"opcode":135,"mnemonic":"ADD A","length":1,"operand":"None","cycles":4,"cycles_taken":4,"flags_written":"SZAPC","flags_read":""}"#));
+//! ```
+
+#[cfg(feature = "std")]
+use std::io::{self, Write};
+
+/// Flag masks in flag register layout, for `OpcodeInfo::flags_written`
+/// and `flags_read`.
+pub mod flags {
+    pub const S: u8 = 0x80;
+    pub const Z: u8 = 0x40;
+    pub const A: u8 = 0x10;
+    pub const P: u8 = 0x04;
+    pub const C: u8 = 0x01;
+    pub const SZAP: u8 = S | Z | A | P;
+    pub const ALL: u8 = SZAP | C;
+}
+
+use flags::{A, ALL, C, P, S, SZAP, Z};
+
+/// What follows the opcode byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandKind {
+    None,
+    /// A data byte, as in MVI or ADI.
+    Imm8,
+    /// A data word, as in LXI.
+    Imm16,
+    /// A memory address, as in LDA, JMP or CALL.
+    Addr,
+    /// A port number, for IN and OUT.
+    Port,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpcodeInfo {
+    /// Mnemonic with any fixed register operands, e.g. `"MOV B, M"` or
+    /// `"LXI SP"`. Immediate data and addresses are not included.
+    pub mnemonic: &'static str,
+    /// Instruction length in bytes, including the opcode.
+    pub length: u8,
+    /// Clock cycles taken. For conditional calls and returns this is the
+    /// not-taken case.
+    pub cycles: u8,
+    /// Clock cycles taken when a conditional call or return is taken. Equal
+    /// to `cycles` for every other instruction.
+    pub cycles_taken: u8,
+    pub operand: OperandKind,
+    /// Flags set or cleared, as a mask in flag register layout.
+    pub flags_written: u8,
+    /// Flags whose value the instruction depends on, as a mask in flag
+    /// register layout.
+    pub flags_read: u8,
+}
+
+const fn op(mnemonic: &'static str, length: u8, cycles: u8, cycles_taken: u8) -> OpcodeInfo {
+    OpcodeInfo { mnemonic, length, cycles, cycles_taken, operand: OperandKind::None, flags_written: 0, flags_read: 0 }
+}
+
+/// Metadata for every opcode, indexed by the opcode byte. The undocumented
+/// opcodes are listed as the instruction the CPU executes them as.
+pub const OPCODES: [OpcodeInfo; 256] = with_operands_and_flags(TIMINGS);
+
+// Fills in what follows from the opcode's bit pattern
+const fn with_operands_and_flags(mut table: [OpcodeInfo; 256]) -> [OpcodeInfo; 256] {
+    let mut i = 0;
+    while i < 256 {
+        let op = i as u8;
+        table[i].operand = match (table[i].length, op) {
+            (2, 0xd3) | (2, 0xdb) => OperandKind::Port,
+            (2, _) => OperandKind::Imm8,
+            (3, _) if op & 0xcf == 0x01 => OperandKind::Imm16,
+            (3, _) => OperandKind::Addr,
+            _ => OperandKind::None,
+        };
+        table[i].flags_written = flags_written(op);
+        table[i].flags_read = flags_read(op);
+        i += 1;
+    }
+    table
+}
+
+const fn flags_written(op: u8) -> u8 {
+    match op {
+        0x80..=0xbf => ALL,
+        0xc6 | 0xce | 0xd6 | 0xde | 0xe6 | 0xee | 0xf6 | 0xfe => ALL,
+        0x27 | 0xf1 => ALL,
+        _ if op & 0xc6 == 0x04 => SZAP, // INR, DCR
+        _ if op & 0xcf == 0x09 => C,    // DAD
+        0x07 | 0x0f | 0x17 | 0x1f | 0x37 | 0x3f => C,
+        _ => 0,
+    }
+}
+
+const fn flags_read(op: u8) -> u8 {
+    // Conditional returns, jumps and calls
+    if op & 0xc1 == 0xc0 && op & 0x07 != 0x06 {
+        return [Z, C, P, S][((op >> 4) & 0x03) as usize];
+    }
+    match op {
+        0x88..=0x8f | 0x98..=0x9f | 0xce | 0xde => C, // ADC, SBB, ACI, SBI
+        0x17 | 0x1f | 0x3f => C,                      // RAL, RAR, CMC
+        0x27 => A | C,                                // DAA
+        0xf5 => ALL,                                  // PUSH PSW
+        _ => 0,
+    }
+}
+
+// Mnemonics, lengths and timings
+const TIMINGS: [OpcodeInfo; 256] = [
+    op("NOP",       1,  4,  4), // 0x00
+    op("LXI B",     3, 10, 10), // 0x01
+    op("STAX B",    1,  7,  7), // 0x02
+    op("INX B",     1,  5,  5), // 0x03
+    op("INR B",     1,  5,  5), // 0x04
+    op("DCR B",     1,  5,  5), // 0x05
+    op("MVI B",     2,  7,  7), // 0x06
+    op("RLC",       1,  4,  4), // 0x07
+    op("NOP",       1,  4,  4), // 0x08
+    op("DAD B",     1, 10, 10), // 0x09
+    op("LDAX B",    1,  7,  7), // 0x0a
+    op("DCX B",     1,  5,  5), // 0x0b
+    op("INR C",     1,  5,  5), // 0x0c
+    op("DCR C",     1,  5,  5), // 0x0d
+    op("MVI C",     2,  7,  7), // 0x0e
+    op("RRC",       1,  4,  4), // 0x0f
+
+    op("NOP",       1,  4,  4), // 0x10
+    op("LXI D",     3, 10, 10), // 0x11
+    op("STAX D",    1,  7,  7), // 0x12
+    op("INX D",     1,  5,  5), // 0x13
+    op("INR D",     1,  5,  5), // 0x14
+    op("DCR D",     1,  5,  5), // 0x15
+    op("MVI D",     2,  7,  7), // 0x16
+    op("RAL",       1,  4,  4), // 0x17
+    op("NOP",       1,  4,  4), // 0x18
+    op("DAD D",     1, 10, 10), // 0x19
+    op("LDAX D",    1,  7,  7), // 0x1a
+    op("DCX D",     1,  5,  5), // 0x1b
+    op("INR E",     1,  5,  5), // 0x1c
+    op("DCR E",     1,  5,  5), // 0x1d
+    op("MVI E",     2,  7,  7), // 0x1e
+    op("RAR",       1,  4,  4), // 0x1f
+
+    op("NOP",       1,  4,  4), // 0x20
+    op("LXI H",     3, 10, 10), // 0x21
+    op("SHLD",      3, 16, 16), // 0x22
+    op("INX H",     1,  5,  5), // 0x23
+    op("INR H",     1,  5,  5), // 0x24
+    op("DCR H",     1,  5,  5), // 0x25
+    op("MVI H",     2,  7,  7), // 0x26
+    op("DAA",       1,  4,  4), // 0x27
+    op("NOP",       1,  4,  4), // 0x28
+    op("DAD H",     1, 10, 10), // 0x29
+    op("LHLD",      3, 16, 16), // 0x2a
+    op("DCX H",     1,  5,  5), // 0x2b
+    op("INR L",     1,  5,  5), // 0x2c
+    op("DCR L",     1,  5,  5), // 0x2d
+    op("MVI L",     2,  7,  7), // 0x2e
+    op("CMA",       1,  4,  4), // 0x2f
+
+    op("NOP",       1,  4,  4), // 0x30
+    op("LXI SP",    3, 10, 10), // 0x31
+    op("STA",       3, 13, 13), // 0x32
+    op("INX SP",    1,  5,  5), // 0x33
+    op("INR M",     1, 10, 10), // 0x34
+    op("DCR M",     1, 10, 10), // 0x35
+    op("MVI M",     2, 10, 10), // 0x36
+    op("STC",       1,  4,  4), // 0x37
+    op("NOP",       1,  4,  4), // 0x38
+    op("DAD SP",    1, 10, 10), // 0x39
+    op("LDA",       3, 13, 13), // 0x3a
+    op("DCX SP",    1,  5,  5), // 0x3b
+    op("INR A",     1,  5,  5), // 0x3c
+    op("DCR A",     1,  5,  5), // 0x3d
+    op("MVI A",     2,  7,  7), // 0x3e
+    op("CMC",       1,  4,  4), // 0x3f
+
+    op("MOV B, B",  1,  5,  5), // 0x40
+    op("MOV B, C",  1,  5,  5), // 0x41
+    op("MOV B, D",  1,  5,  5), // 0x42
+    op("MOV B, E",  1,  5,  5), // 0x43
+    op("MOV B, H",  1,  5,  5), // 0x44
+    op("MOV B, L",  1,  5,  5), // 0x45
+    op("MOV B, M",  1,  7,  7), // 0x46
+    op("MOV B, A",  1,  5,  5), // 0x47
+    op("MOV C, B",  1,  5,  5), // 0x48
+    op("MOV C, C",  1,  5,  5), // 0x49
+    op("MOV C, D",  1,  5,  5), // 0x4a
+    op("MOV C, E",  1,  5,  5), // 0x4b
+    op("MOV C, H",  1,  5,  5), // 0x4c
+    op("MOV C, L",  1,  5,  5), // 0x4d
+    op("MOV C, M",  1,  7,  7), // 0x4e
+    op("MOV C, A",  1,  5,  5), // 0x4f
+
+    op("MOV D, B",  1,  5,  5), // 0x50
+    op("MOV D, C",  1,  5,  5), // 0x51
+    op("MOV D, D",  1,  5,  5), // 0x52
+    op("MOV D, E",  1,  5,  5), // 0x53
+    op("MOV D, H",  1,  5,  5), // 0x54
+    op("MOV D, L",  1,  5,  5), // 0x55
+    op("MOV D, M",  1,  7,  7), // 0x56
+    op("MOV D, A",  1,  5,  5), // 0x57
+    op("MOV E, B",  1,  5,  5), // 0x58
+    op("MOV E, C",  1,  5,  5), // 0x59
+    op("MOV E, D",  1,  5,  5), // 0x5a
+    op("MOV E, E",  1,  5,  5), // 0x5b
+    op("MOV E, H",  1,  5,  5), // 0x5c
+    op("MOV E, L",  1,  5,  5), // 0x5d
+    op("MOV E, M",  1,  7,  7), // 0x5e
+    op("MOV E, A",  1,  5,  5), // 0x5f
+
+    op("MOV H, B",  1,  5,  5), // 0x60
+    op("MOV H, C",  1,  5,  5), // 0x61
+    op("MOV H, D",  1,  5,  5), // 0x62
+    op("MOV H, E",  1,  5,  5), // 0x63
+    op("MOV H, H",  1,  5,  5), // 0x64
+    op("MOV H, L",  1,  5,  5), // 0x65
+    op("MOV H, M",  1,  7,  7), // 0x66
+    op("MOV H, A",  1,  5,  5), // 0x67
+    op("MOV L, B",  1,  5,  5), // 0x68
+    op("MOV L, C",  1,  5,  5), // 0x69
+    op("MOV L, D",  1,  5,  5), // 0x6a
+    op("MOV L, E",  1,  5,  5), // 0x6b
+    op("MOV L, H",  1,  5,  5), // 0x6c
+    op("MOV L, L",  1,  5,  5), // 0x6d
+    op("MOV L, M",  1,  7,  7), // 0x6e
+    op("MOV L, A",  1,  5,  5), // 0x6f
+
+    op("MOV M, B",  1,  7,  7), // 0x70
+    op("MOV M, C",  1,  7,  7), // 0x71
+    op("MOV M, D",  1,  7,  7), // 0x72
+    op("MOV M, E",  1,  7,  7), // 0x73
+    op("MOV M, H",  1,  7,  7), // 0x74
+    op("MOV M, L",  1,  7,  7), // 0x75
+    op("HLT",       1,  7,  7), // 0x76
+    op("MOV M, A",  1,  7,  7), // 0x77
+    op("MOV A, B",  1,  5,  5), // 0x78
+    op("MOV A, C",  1,  5,  5), // 0x79
+    op("MOV A, D",  1,  5,  5), // 0x7a
+    op("MOV A, E",  1,  5,  5), // 0x7b
+    op("MOV A, H",  1,  5,  5), // 0x7c
+    op("MOV A, L",  1,  5,  5), // 0x7d
+    op("MOV A, M",  1,  7,  7), // 0x7e
+    op("MOV A, A",  1,  5,  5), // 0x7f
+
+    op("ADD B",     1,  4,  4), // 0x80
+    op("ADD C",     1,  4,  4), // 0x81
+    op("ADD D",     1,  4,  4), // 0x82
+    op("ADD E",     1,  4,  4), // 0x83
+    op("ADD H",     1,  4,  4), // 0x84
+    op("ADD L",     1,  4,  4), // 0x85
+    op("ADD M",     1,  7,  7), // 0x86
+    op("ADD A",     1,  4,  4), // 0x87
+    op("ADC B",     1,  4,  4), // 0x88
+    op("ADC C",     1,  4,  4), // 0x89
+    op("ADC D",     1,  4,  4), // 0x8a
+    op("ADC E",     1,  4,  4), // 0x8b
+    op("ADC H",     1,  4,  4), // 0x8c
+    op("ADC L",     1,  4,  4), // 0x8d
+    op("ADC M",     1,  7,  7), // 0x8e
+    op("ADC A",     1,  4,  4), // 0x8f
+
+    op("SUB B",     1,  4,  4), // 0x90
+    op("SUB C",     1,  4,  4), // 0x91
+    op("SUB D",     1,  4,  4), // 0x92
+    op("SUB E",     1,  4,  4), // 0x93
+    op("SUB H",     1,  4,  4), // 0x94
+    op("SUB L",     1,  4,  4), // 0x95
+    op("SUB M",     1,  7,  7), // 0x96
+    op("SUB A",     1,  4,  4), // 0x97
+    op("SBB B",     1,  4,  4), // 0x98
+    op("SBB C",     1,  4,  4), // 0x99
+    op("SBB D",     1,  4,  4), // 0x9a
+    op("SBB E",     1,  4,  4), // 0x9b
+    op("SBB H",     1,  4,  4), // 0x9c
+    op("SBB L",     1,  4,  4), // 0x9d
+    op("SBB M",     1,  7,  7), // 0x9e
+    op("SBB A",     1,  4,  4), // 0x9f
+
+    op("ANA B",     1,  4,  4), // 0xa0
+    op("ANA C",     1,  4,  4), // 0xa1
+    op("ANA D",     1,  4,  4), // 0xa2
+    op("ANA E",     1,  4,  4), // 0xa3
+    op("ANA H",     1,  4,  4), // 0xa4
+    op("ANA L",     1,  4,  4), // 0xa5
+    op("ANA M",     1,  7,  7), // 0xa6
+    op("ANA A",     1,  4,  4), // 0xa7
+    op("XRA B",     1,  4,  4), // 0xa8
+    op("XRA C",     1,  4,  4), // 0xa9
+    op("XRA D",     1,  4,  4), // 0xaa
+    op("XRA E",     1,  4,  4), // 0xab
+    op("XRA H",     1,  4,  4), // 0xac
+    op("XRA L",     1,  4,  4), // 0xad
+    op("XRA M",     1,  7,  7), // 0xae
+    op("XRA A",     1,  4,  4), // 0xaf
+
+    op("ORA B",     1,  4,  4), // 0xb0
+    op("ORA C",     1,  4,  4), // 0xb1
+    op("ORA D",     1,  4,  4), // 0xb2
+    op("ORA E",     1,  4,  4), // 0xb3
+    op("ORA H",     1,  4,  4), // 0xb4
+    op("ORA L",     1,  4,  4), // 0xb5
+    op("ORA M",     1,  7,  7), // 0xb6
+    op("ORA A",     1,  4,  4), // 0xb7
+    op("CMP B",     1,  4,  4), // 0xb8
+    op("CMP C",     1,  4,  4), // 0xb9
+    op("CMP D",     1,  4,  4), // 0xba
+    op("CMP E",     1,  4,  4), // 0xbb
+    op("CMP H",     1,  4,  4), // 0xbc
+    op("CMP L",     1,  4,  4), // 0xbd
+    op("CMP M",     1,  7,  7), // 0xbe
+    op("CMP A",     1,  4,  4), // 0xbf
+
+    op("RNZ",       1,  5, 11), // 0xc0
+    op("POP B",     1, 10, 10), // 0xc1
+    op("JNZ",       3, 10, 10), // 0xc2
+    op("JMP",       3, 10, 10), // 0xc3
+    op("CNZ",       3, 11, 17), // 0xc4
+    op("PUSH B",    1, 11, 11), // 0xc5
+    op("ADI",       2,  7,  7), // 0xc6
+    op("RST 0",     1, 11, 11), // 0xc7
+    op("RZ",        1,  5, 11), // 0xc8
+    op("RET",       1, 10, 10), // 0xc9
+    op("JZ",        3, 10, 10), // 0xca
+    op("JMP",       3, 10, 10), // 0xcb
+    op("CZ",        3, 11, 17), // 0xcc
+    op("CALL",      3, 17, 17), // 0xcd
+    op("ACI",       2,  7,  7), // 0xce
+    op("RST 1",     1, 11, 11), // 0xcf
+
+    op("RNC",       1,  5, 11), // 0xd0
+    op("POP D",     1, 10, 10), // 0xd1
+    op("JNC",       3, 10, 10), // 0xd2
+    op("OUT",       2, 10, 10), // 0xd3
+    op("CNC",       3, 11, 17), // 0xd4
+    op("PUSH D",    1, 11, 11), // 0xd5
+    op("SUI",       2,  7,  7), // 0xd6
+    op("RST 2",     1, 11, 11), // 0xd7
+    op("RC",        1,  5, 11), // 0xd8
+    op("RET",       1, 10, 10), // 0xd9
+    op("JC",        3, 10, 10), // 0xda
+    op("IN",        2, 10, 10), // 0xdb
+    op("CC",        3, 11, 17), // 0xdc
+    op("CALL",      3, 17, 17), // 0xdd
+    op("SBI",       2,  7,  7), // 0xde
+    op("RST 3",     1, 11, 11), // 0xdf
+
+    op("RPO",       1,  5, 11), // 0xe0
+    op("POP H",     1, 10, 10), // 0xe1
+    op("JPO",       3, 10, 10), // 0xe2
+    op("XTHL",      1, 18, 18), // 0xe3
+    op("CPO",       3, 11, 17), // 0xe4
+    op("PUSH H",    1, 11, 11), // 0xe5
+    op("ANI",       2,  7,  7), // 0xe6
+    op("RST 4",     1, 11, 11), // 0xe7
+    op("RPE",       1,  5, 11), // 0xe8
+    op("PCHL",      1,  5,  5), // 0xe9
+    op("JPE",       3, 10, 10), // 0xea
+    op("XCHG",      1,  5,  5), // 0xeb
+    op("CPE",       3, 11, 17), // 0xec
+    op("CALL",      3, 17, 17), // 0xed
+    op("XRI",       2,  7,  7), // 0xee
+    op("RST 5",     1, 11, 11), // 0xef
+
+    op("RP",        1,  5, 11), // 0xf0
+    op("POP PSW",   1, 10, 10), // 0xf1
+    op("JP",        3, 10, 10), // 0xf2
+    op("DI",        1,  4,  4), // 0xf3
+    op("CP",        3, 11, 17), // 0xf4
+    op("PUSH PSW",  1, 11, 11), // 0xf5
+    op("ORI",       2,  7,  7), // 0xf6
+    op("RST 6",     1, 11, 11), // 0xf7
+    op("RM",        1,  5, 11), // 0xf8
+    op("SPHL",      1,  5,  5), // 0xf9
+    op("JM",        3, 10, 10), // 0xfa
+    op("EI",        1,  4,  4), // 0xfb
+    op("CM",        3, 11, 17), // 0xfc
+    op("CALL",      3, 17, 17), // 0xfd
+    op("CPI",       2,  7,  7), // 0xfe
+    op("RST 7",     1, 11, 11), // 0xff
+];
+
+/// Writes `OPCODES` as a JSON array of one object per opcode, in opcode
+/// order, with the flags written and read by each as a string of flag
+/// letters such as `"SZAP"`. Needs the `std` feature.
+#[cfg(feature = "std")]
+pub fn write_json(w: &mut impl Write) -> io::Result<()> {
+    write!(w, "[")?;
+    for (op, info) in OPCODES.iter().enumerate() {
+        if op > 0 {
+            write!(w, ",")?;
+        }
+        write!(w, "\n{{\"opcode\":{},\"mnemonic\":\"{}\",\"length\":{},\"operand\":\"{:?}\",\"cycles\":{},\"cycles_taken\":{},",
+               op, info.mnemonic, info.length, info.operand, info.cycles, info.cycles_taken)?;
+        write!(w, "\"flags_written\":\"{}\",\"flags_read\":\"{}\"}}",
+               flag_letters(info.flags_written), flag_letters(info.flags_read))?;
+    }
+    writeln!(w, "\n]")
+}
+
+#[cfg(feature = "std")]
+fn flag_letters(mask: u8) -> String {
+    [('S', 7), ('Z', 6), ('A', 4), ('P', 2), ('C', 0)].iter()
+        .filter(|&&(_, bit)| mask & 1 << bit != 0)
+        .map(|&(letter, _)| letter)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::memory::{Memory, Memory8080};
+    use crate::opcodes::{flags, OperandKind, OPCODES};
+    #[cfg(feature = "std")]
+    use crate::opcodes::write_json;
+    use crate::registers::Flag;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Executes `op` at 0x103 and returns the cycles and the PC afterwards
+    fn run(op: u8, flags: bool) -> (u32, u16) {
+        let memory = Rc::new(RefCell::new(Memory8080::new_empty()));
+        // LXI SP, 0x8000 followed by the instruction under test
+        memory.borrow_mut().write(0x100, 0x31);
+        memory.borrow_mut().write16(0x101, 0x8000);
+        memory.borrow_mut().write(0x103, op);
+        memory.borrow_mut().write16(0x104, 0x0103);
+        let mut cpu = CPU::new(memory);
+        cpu.pc = 0x100;
+        let lxi = cpu.fetch();
+        cpu.exec(lxi);
+        cpu.regs.set_bc(0x1000);
+        cpu.regs.set_de(0x1000);
+        cpu.regs.set_hl(0x0103);
+        for f in [Flag::S, Flag::Z, Flag::P, Flag::C] {
+            cpu.regs.set_flag(f, flags);
+        }
+        let op = cpu.fetch();
+        let cycles = cpu.exec(op).cycles();
+        (cycles, cpu.pc)
+    }
+
+    #[test]
+    fn cycles_match_exec() {
+        for op in 0..=0xff {
+            let info = OPCODES[op as usize];
+            let (a, _) = run(op, false);
+            let (b, _) = run(op, true);
+            assert_eq!(u32::from(info.cycles), a.min(b), "opcode {:02x}", op);
+            assert_eq!(u32::from(info.cycles_taken), a.max(b), "opcode {:02x}", op);
+        }
+    }
+
+    #[test]
+    fn lengths_match_exec() {
+        for op in 0..=0xff {
+            let info = OPCODES[op as usize];
+            // Only instructions that fall through say anything about length
+            let (_, pc) = run(op, false);
+            if pc > 0x103 && pc < 0x107 {
+                assert_eq!(pc - 0x103, u16::from(info.length), "opcode {:02x}", op);
+            }
+        }
+    }
+
+    #[test]
+    fn const_usable() {
+        const HLT: u8 = OPCODES[0x76].cycles;
+        assert_eq!(HLT, 7);
+        assert_eq!(OPCODES[0x3e].mnemonic, "MVI A");
+        assert_eq!(OPCODES[0xcd].length, 3);
+    }
+
+    #[test]
+    fn operands_and_flags() {
+        let kinds = [(0x00, OperandKind::None), (0x06, OperandKind::Imm8), (0x21, OperandKind::Imm16),
+                     (0x32, OperandKind::Addr), (0xc2, OperandKind::Addr), (0xdb, OperandKind::Port)];
+        for (op, kind) in kinds {
+            assert_eq!(OPCODES[op].operand, kind, "opcode {:02x}", op);
+        }
+        assert_eq!((OPCODES[0x8e].flags_written, OPCODES[0x8e].flags_read), (flags::ALL, flags::C));
+        assert_eq!((OPCODES[0x34].flags_written, OPCODES[0x34].flags_read), (flags::SZAP, 0));
+        assert_eq!((OPCODES[0xe2].flags_written, OPCODES[0xe2].flags_read), (0, flags::P));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn json_export() {
+        let mut json = Vec::new();
+        write_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        let lines: Vec<_> = json.lines().collect();
+        assert_eq!(lines.len(), 258);
+        assert_eq!((lines[0], lines[257]), ("[", "]"));
+        assert_eq!(lines[1 + 0x09], r#"{"opcode":9,"mnemonic":"DAD B","length":1,"operand":"None","cycles":10,"cycles_taken":10,"flags_written":"C","flags_read":""},"#);
+        assert_eq!(lines[1 + 0xcc], r#"{"opcode":204,"mnemonic":"CZ","length":3,"operand":"Addr","cycles":11,"cycles_taken":17,"flags_written":"","flags_read":"Z"},"#);
+        assert!(lines[1 + 0xff].ends_with("\"flags_read\":\"\"}"));
+    }
+}
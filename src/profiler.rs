@@ -0,0 +1,293 @@
+//! Counts how often each opcode and each address is executed, and how many
+//! cycles are spent at each address, to find a program's hot spots.
+//!
+//! ```
+//! use i8080_emulator::cpu::CPU;
+//! use i8080_emulator::memory::Memory8080;
+//! use i8080_emulator::profiler::Profiler;
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! // MVI B, 3; loop: DCR B; JNZ loop; HLT
+//! let mut memory = [0x00; 0x10000];
+//! memory[..7].copy_from_slice(&[0x06, 0x03, 0x05, 0xc2, 0x02, 0x00, 0x76]);
+//! let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+//! let mut profiler = Profiler::new();
+//! while !cpu.is_halted() {
+//!     profiler.record(&cpu);
+//!     cpu.step();
+//! }
+//! assert_eq!(profiler.top_n(2), [(0x0002, 3), (0x0003, 3)]);
+//! ```
+//!
+//! Named spans, opened and closed by the host around phases of the
+//! program, add up the instructions and cycles each phase took, for
+//! comparing the same phase across runs.
+
+use crate::cpu::{Timestamp, CPU};
+use crate::memory::Memory;
+use crate::opcodes::OPCODES;
+use crate::trace::Instrument;
+
+use std::io::{self, Write};
+
+/// Totals for every span of one name, from `Profiler::spans`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SpanTotals {
+    /// How many spans of the name were ended.
+    pub count: u64,
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+/// Execution counts per opcode and per address.
+pub struct Profiler {
+    opcodes: [u64; 0x100],
+    addresses: Vec<u64>,
+    cycles: Vec<u64>,
+    // The instruction recorded last and the cycle count when it started,
+    // to charge its cycles once the next one comes along
+    last: Option<(u16, Timestamp)>,
+    // Totals per span name, in the order first ended
+    spans: Vec<(String, SpanTotals)>,
+    // Spans begun and not yet ended, innermost last, with the instruction
+    // and cycle counts they began at
+    open_spans: Vec<(String, u64, Timestamp)>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            opcodes: [0; 0x100],
+            addresses: vec![0; 0x10000],
+            cycles: vec![0; 0x10000],
+            last: None,
+            spans: Vec::new(),
+            open_spans: Vec::new(),
+        }
+    }
+
+    /// Counts the instruction `cpu` is about to run. Call it between
+    /// instructions. The cycles since the previous call, including those
+    /// of any interrupt taken, are charged to the previous instruction.
+    pub fn record<M: Memory>(&mut self, cpu: &CPU<M>) {
+        let now = cpu.cycles();
+        if let Some((pc, start)) = self.last {
+            self.cycles[usize::from(pc)] += now.saturating_sub(start);
+        }
+        let op = cpu.memory.borrow().peek(usize::from(cpu.pc));
+        self.opcodes[usize::from(op)] += 1;
+        self.addresses[usize::from(cpu.pc)] += 1;
+        self.last = Some((cpu.pc, now));
+    }
+
+    /// How many times `op` was executed.
+    pub fn opcode_count(&self, op: u8) -> u64 {
+        self.opcodes[usize::from(op)]
+    }
+
+    /// How many times the instruction at `addr` was executed.
+    pub fn address_count(&self, addr: u16) -> u64 {
+        self.addresses[usize::from(addr)]
+    }
+
+    /// Cycles spent in the instruction at `addr`. The last instruction
+    /// recorded is not counted yet.
+    pub fn address_cycles(&self, addr: u16) -> u64 {
+        self.cycles[usize::from(addr)]
+    }
+
+    /// Total instructions recorded.
+    pub fn total(&self) -> u64 {
+        self.opcodes.iter().sum()
+    }
+
+    /// The `n` most executed addresses with their counts, most executed
+    /// first and lower addresses first among equals.
+    pub fn top_n(&self, n: usize) -> Vec<(u16, u64)> {
+        top(&self.addresses, n).into_iter().map(|(addr, count)| (addr as u16, count)).collect()
+    }
+
+    /// The `n` addresses most cycles were spent at, with their cycles.
+    pub fn top_n_by_cycles(&self, n: usize) -> Vec<(u16, u64)> {
+        top(&self.cycles, n).into_iter().map(|(addr, cycles)| (addr as u16, cycles)).collect()
+    }
+
+    /// The `n` most executed opcodes with their counts.
+    pub fn top_opcodes(&self, n: usize) -> Vec<(u8, u64)> {
+        top(&self.opcodes, n).into_iter().map(|(op, count)| (op as u8, count)).collect()
+    }
+
+    /// Starts a span named `name`, inside any span still open, from the
+    /// instruction `cpu` is about to run.
+    pub fn begin_span<M: Memory>(&mut self, name: &str, cpu: &CPU<M>) {
+        self.open_spans.push((name.to_string(), self.total(), cpu.cycles()));
+    }
+
+    /// Ends the innermost open span, adding what ran since it began to
+    /// the totals for its name, or does nothing if no span is open.
+    pub fn end_span<M: Memory>(&mut self, cpu: &CPU<M>) {
+        let (name, instructions, cycles) = match self.open_spans.pop() {
+            Some(span) => span,
+            None => return,
+        };
+        let index = match self.spans.iter().position(|(span, _)| *span == name) {
+            Some(index) => index,
+            None => {
+                self.spans.push((name, SpanTotals::default()));
+                self.spans.len() - 1
+            }
+        };
+        let ran = self.total() - instructions;
+        let totals = &mut self.spans[index].1;
+        totals.count += 1;
+        totals.instructions += ran;
+        totals.cycles += cpu.cycles().saturating_sub(cycles);
+    }
+
+    /// The totals of every span name ended so far, in the order each was
+    /// first ended.
+    pub fn spans(&self) -> &[(String, SpanTotals)] {
+        &self.spans
+    }
+
+    pub fn clear(&mut self) {
+        *self = Profiler::new();
+    }
+
+    /// Writes the `n` hottest addresses and opcodes as a text report,
+    /// followed by the span totals if there are any.
+    pub fn write_report(&self, w: &mut impl Write, n: usize) -> io::Result<()> {
+        let total = self.total().max(1) as f64;
+        writeln!(w, "{} instructions", self.total())?;
+        writeln!(w, "addr  executions        %      cycles")?;
+        for (addr, count) in self.top_n(n) {
+            writeln!(w, "{:04x}  {:>10}  {:>6.2}  {:>10}", addr, count, count as f64 * 100.0 / total, self.address_cycles(addr))?;
+        }
+        writeln!(w, "opcode      executions        %")?;
+        for (op, count) in self.top_opcodes(n) {
+            writeln!(w, "{:02x} {:<9}  {:>10}  {:>6.2}", op, OPCODES[usize::from(op)].mnemonic, count, count as f64 * 100.0 / total)?;
+        }
+        if !self.spans.is_empty() {
+            writeln!(w, "span                   count  instructions      cycles")?;
+            for (name, totals) in &self.spans {
+                writeln!(w, "{:<20} {:>7}  {:>12}  {:>10}", name, totals.count, totals.instructions, totals.cycles)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<M: Memory> Instrument<M> for Profiler {
+    fn record(&mut self, cpu: &CPU<M>) {
+        Profiler::record(self, cpu);
+    }
+}
+
+// The indices of the `n` largest non-zero counts, largest first
+fn top(counts: &[u64], n: usize) -> Vec<(usize, u64)> {
+    let mut hot: Vec<_> = counts.iter().copied().enumerate().filter(|&(_, count)| count > 0).collect();
+    hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    hot.truncate(n);
+    hot
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::memory::Memory8080;
+    use crate::devices::control::{ControlPort, ControlRequest};
+    use crate::io::PortBus;
+    use crate::profiler::{Profiler, SpanTotals};
+    use crate::timeline::{Timeline, TimelineEvent};
+    use crate::trace::{Instrument, TraceBuffer};
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn counts_and_report() {
+        // MVI B, 2; loop: DCR B; JNZ loop; HLT
+        let mut memory = [0x00; 0x10000];
+        memory[..7].copy_from_slice(&[0x06, 0x02, 0x05, 0xc2, 0x02, 0x00, 0x76]);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        let mut profiler = Profiler::new();
+        let mut trace = TraceBuffer::new(8);
+        while !cpu.is_halted() {
+            for instrument in [&mut profiler as &mut dyn Instrument, &mut trace] {
+                instrument.record(&cpu);
+            }
+            cpu.step();
+        }
+        assert_eq!(profiler.total(), 6);
+        assert_eq!(trace.len(), 6);
+        assert_eq!(profiler.opcode_count(0x05), 2);
+        assert_eq!(profiler.address_count(0x0006), 1);
+        assert_eq!(profiler.address_cycles(0x0003), 20);
+        assert_eq!(profiler.address_cycles(0x0006), 0);
+        assert_eq!(profiler.top_opcodes(2), [(0x05, 2), (0xc2, 2)]);
+        assert_eq!(profiler.top_n_by_cycles(1), [(0x0003, 20)]);
+
+        let mut report = Vec::new();
+        profiler.write_report(&mut report, 1).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(lines[0], "6 instructions");
+        assert_eq!(lines[2], "0002           2   33.33          10");
+        assert_eq!(lines[4], "05 DCR B               2   33.33");
+
+        profiler.clear();
+        assert!(profiler.top_n(5).is_empty());
+    }
+
+    #[test]
+    fn spans_from_the_control_port() {
+        // loop: MVI A, 1; OUT 0xff; MVI A, 6; OUT 0xfe; NOP; MVI A, 7;
+        // OUT 0xfe; JMP loop
+        let mut memory = [0x00; 0x10000];
+        memory[..17].copy_from_slice(&[
+            0x3e, 0x01, 0xd3, 0xff, 0x3e, 0x06, 0xd3, 0xfe, 0x00, 0x3e, 0x07, 0xd3, 0xfe, 0xc3, 0x00, 0x00, 0x00,
+        ]);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        let control = Rc::new(RefCell::new(ControlPort::new(0xfe)));
+        control.borrow_mut().set_label(1, "frame");
+        let mut bus = PortBus::new();
+        bus.map(0xfe, control.clone());
+        bus.map(0xff, control.clone());
+        let mut profiler = Profiler::new();
+        let mut timeline = Timeline::new();
+        for _ in 0..16 {
+            profiler.record(&cpu);
+            bus.step(&mut cpu);
+            let requests = control.borrow_mut().take_requests();
+            for request in requests {
+                timeline.record_control(cpu.cycles(), &request, &control.borrow());
+                match request {
+                    ControlRequest::SpanBegin(id) => profiler.begin_span(&control.borrow().label(id), &cpu),
+                    ControlRequest::SpanEnd => profiler.end_span(&cpu),
+                    _ => {}
+                }
+            }
+        }
+        // NOP, MVI and OUT in each
+        assert_eq!(profiler.spans(), [("frame".to_string(), SpanTotals { count: 2, instructions: 6, cycles: 2 * 21 })]);
+        let events: Vec<_> = timeline.events().iter().map(|(cycle, event)| (*cycle, event.clone())).collect();
+        assert_eq!(events, [
+            (34, TimelineEvent::SpanBegin("frame".to_string())),
+            (55, TimelineEvent::SpanEnd("frame".to_string())),
+            (99, TimelineEvent::SpanBegin("frame".to_string())),
+            (120, TimelineEvent::SpanEnd("frame".to_string())),
+        ]);
+
+        let mut report = Vec::new();
+        profiler.write_report(&mut report, 1).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.ends_with("frame                      2             6          42\n"));
+    }
+}
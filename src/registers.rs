@@ -1,5 +1,8 @@
-use std::ops::BitOr;
+use core::fmt;
+use core::ops::BitOr;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Registers {
     pub b: u8,
     pub c: u8,
@@ -11,6 +14,7 @@ pub struct Registers {
     pub a: u8,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Flag {
     S = 7, // Sign flag
     Z = 6, // Zero flag
@@ -19,6 +23,39 @@ pub enum Flag {
     C = 0, // Carry flag
 }
 
+/// A flag register value with the bits that are fixed on an 8080 as the
+/// chip has them: bit 1 set, bits 3 and 5 clear. Whatever is loaded into
+/// the flags, by POP PSW for instance, comes out this way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const fn new(bits: u8) -> Flags {
+        Flags(bits & 0xd5 | 0x02)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+}
+
+/// The five flags as `SZAPC`, with a `-` for each one that is clear.
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (flag, name) in [(Flag::S, 'S'), (Flag::Z, 'Z'), (Flag::A, 'A'), (Flag::P, 'P'), (Flag::C, 'C')].iter() {
+            let set = Flag::is_flag(self.0, *flag);
+            write!(f, "{}", if set { *name } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+impl From<Flags> for u8 {
+    fn from(flags: Flags) -> u8 {
+        flags.bits()
+    }
+}
+
 impl Registers {
     pub fn get_af(&self) -> u16 {
         (u16::from(self.a) << 8) | u16::from(self.f)
@@ -38,7 +75,7 @@ impl Registers {
 
     pub fn set_af(&mut self, data: u16) {
         self.a = (data >> 8) as u8;
-        self.f = (data & 0x00d5 | 0x0002) as u8;
+        self.f = Flags::new(data as u8).bits();
     }
 
     pub fn set_bc(&mut self, data: u16) {
@@ -99,8 +136,22 @@ impl Registers {
     }
 }
 
+/// One line of registers, such as `A=3E F=SZ-P- BC=0000 DE=1234 HL=2400`.
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "A={:02X} F={} BC={:04X} DE={:04X} HL={:04X}",
+               self.a, Flags::new(self.f), self.get_bc(), self.get_de(), self.get_hl())
+    }
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Registers {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Registers {
             b: 0,
             c: 0,
@@ -109,19 +160,19 @@ impl Registers {
             h: 0,
             l: 0,
             a: 0,
-            f: 0x02,
+            f: Flags::new(0x00).bits(),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::registers::{Registers, Flag};
+    use crate::registers::{Flags, Registers, Flag};
 
     #[test]
     fn is_flag() {
         let x = 0x11;
-        assert_eq!(Flag::is_flag(x, Flag::C), true);
+        assert!(Flag::is_flag(x, Flag::C));
     }
 
     #[test]
@@ -136,6 +187,29 @@ mod tests {
         assert_eq!(regs.get_af(), 0xff02);
     }
 
+    #[test]
+    fn fixed_flag_bits() {
+        assert_eq!(Flags::new(0xff).bits(), 0xd7);
+        assert_eq!(u8::from(Flags::new(0x00)), 0x02);
+        let mut regs = Registers::new();
+        assert_eq!(regs.f, 0x02);
+        regs.set_af(0x12ff);
+        assert_eq!((regs.a, regs.f), (0x12, 0xd7));
+        regs.set_af(0x1228);
+        assert_eq!(regs.get_af(), 0x1202);
+    }
+
+    #[test]
+    fn display() {
+        let mut regs = Registers::new();
+        regs.a = 0x3e;
+        regs.f = Flag::S | Flag::Z | Flag::P;
+        regs.set_de(0x1234);
+        regs.set_hl(0x2400);
+        assert_eq!(regs.to_string(), "A=3E F=SZ-P- BC=0000 DE=1234 HL=2400");
+        assert_eq!(Flags::new(0x11).to_string(), "--A-C");
+    }
+
     #[test]
     fn set_register_pair() {
         let mut regs = Registers::new();
@@ -144,11 +218,27 @@ mod tests {
         assert_eq!(regs.l, 0x02);
     }
 
+    #[test]
+    fn register_pairs_put_high_byte_first() {
+        let mut regs = Registers::new();
+        for &data in &[0x0000, 0x00ff, 0xff00, 0x1234] {
+            regs.set_bc(data);
+            regs.set_de(data);
+            regs.set_hl(data);
+            assert_eq!([regs.b, regs.c], u16::to_be_bytes(data));
+            assert_eq!([regs.d, regs.e], u16::to_be_bytes(data));
+            assert_eq!([regs.h, regs.l], u16::to_be_bytes(data));
+            assert_eq!(regs.get_bc(), data);
+            assert_eq!(regs.get_de(), data);
+            assert_eq!(regs.get_hl(), data);
+        }
+    }
+
     #[test]
     fn get_flag() {
         let mut regs = Registers::new();
         regs.f |= 0x80;
-        assert_eq!(regs.get_flag(Flag::S), true);
+        assert!(regs.get_flag(Flag::S));
     }
 
     #[test]
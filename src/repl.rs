@@ -0,0 +1,440 @@
+//! A command line for poking at a machine while it runs, in the style of
+//! the old ROM monitors but on the host side.
+//!
+//! `Repl` works with any machine whose CPU it can reach, which the
+//! `Inspect` trait provides. Commands are one per line, numbers in hex
+//! with or without `0x`:
+//!
+//! | Command          | Effect                                          |
+//! |------------------|-------------------------------------------------|
+//! | `x addr [len]`   | examine `len` bytes of memory, 64 by default    |
+//! | `r`              | show the registers                              |
+//! | `u [addr] [n]`   | disassemble `n` instructions, 8 by default      |
+//! | `s [n]`          | step `n` instructions, 1 by default             |
+//! | `c`              | continue until a breakpoint, watchpoint, error  |
+//! |                  | or HLT with interrupts disabled                 |
+//! | `b [addr]`       | set a breakpoint, or list them                  |
+//! | `d addr`         | delete a breakpoint                             |
+//! | `w addr`         | stop after any write to `addr`                  |
+//! | `a addr: insn`   | assemble `insn` into memory at `addr`; also     |
+//! |                  | `assemble`. `insn` is in `assembler` syntax     |
+//! | `q`              | quit                                            |
+//!
+//! While the `Repl` has a machine, its clock only runs during `c`: the
+//! timers, device timeouts and periodic interrupts of a
+//! `CompositeMachine` stand still while the user steps and looks around,
+//! as if the machine had been frozen between two instructions.
+//!
+//! ```
+//! use i8080_emulator::bare::BareMachine;
+//! use i8080_emulator::repl::Repl;
+//!
+//! // MVI A, 'H'; OUT 0; HLT
+//! let mut repl = Repl::new(BareMachine::new(&[0x3e, b'H', 0xd3, 0x00, 0x76]));
+//! let mut out = Vec::new();
+//! repl.run("b 2\nc\nr\nq\n".as_bytes(), &mut out).unwrap();
+//! let out = String::from_utf8(out).unwrap();
+//! assert!(out.contains("breakpoint at 0002"));
+//! assert!(out.contains("A=48"));
+//! ```
+
+use crate::assembler;
+use crate::bare::BareMachine;
+use crate::cpu::CPU;
+use crate::disassembler::Disassembler;
+use crate::machines::altair::AltairMachine;
+use crate::machines::composite::CompositeMachine;
+use crate::machines::cpm::CpmMachine;
+use crate::memory::{Access, Memory, Memory8080};
+use crate::monitor::MonitorMachine;
+use crate::{I8080Core, Machine};
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+/// A machine whose CPU, and through it memory, can be looked at.
+pub trait Inspect: Machine {
+    fn cpu(&self) -> &CPU;
+    fn cpu_mut(&mut self) -> &mut CPU;
+
+    /// Stops or restarts the machine's timers and periodic interrupts
+    /// while a debugger has it. Machines timed by nothing but the CPU
+    /// have nothing to stop.
+    fn set_clock_paused(&mut self, _paused: bool) {}
+}
+
+macro_rules! inspect_cpu_field {
+    ($($machine:ty),*) => {
+        $(impl Inspect for $machine {
+            fn cpu(&self) -> &CPU {
+                &self.cpu
+            }
+
+            fn cpu_mut(&mut self) -> &mut CPU {
+                &mut self.cpu
+            }
+        })*
+    };
+}
+
+inspect_cpu_field!(BareMachine, MonitorMachine);
+
+impl Inspect for CompositeMachine {
+    fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    fn set_clock_paused(&mut self, paused: bool) {
+        if paused {
+            self.pause_clock();
+        } else {
+            self.resume_clock();
+        }
+    }
+}
+
+impl<W: Write> Inspect for CpmMachine<W> {
+    fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+}
+
+impl<W: Write + 'static> Inspect for AltairMachine<W> {
+    fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+}
+
+/// A machine under the control of typed commands. See the module
+/// documentation.
+pub struct Repl<T: Inspect> {
+    machine: T,
+    breakpoints: BTreeSet<u16>,
+    disassembler: Disassembler,
+    // Where a `u` without an address carries on from
+    listing: Option<u16>,
+}
+
+impl<T: Inspect> Repl<T> {
+    /// Takes over `machine`, pausing its clock until `c` or `into_inner`.
+    pub fn new(mut machine: T) -> Self {
+        machine.set_clock_paused(true);
+        Repl {
+            machine,
+            breakpoints: BTreeSet::new(),
+            disassembler: Disassembler::new(),
+            listing: None,
+        }
+    }
+
+    pub fn machine(&self) -> &T {
+        &self.machine
+    }
+
+    pub fn machine_mut(&mut self) -> &mut T {
+        &mut self.machine
+    }
+
+    /// Gives the machine back with its clock running.
+    pub fn into_inner(mut self) -> T {
+        self.machine.set_clock_paused(false);
+        self.machine
+    }
+
+    pub fn set_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// Reads commands from `input` until `q` or the end of the input,
+    /// prompting for each on `out`.
+    pub fn run(&mut self, input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+        write!(out, "-")?;
+        out.flush()?;
+        for line in input.lines() {
+            if !self.execute(&line?, &mut out)? {
+                return Ok(());
+            }
+            write!(out, "-")?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Carries out one command, writing what it has to say to `out`.
+    /// Returns `false` for `q`. Mistakes in the command are reported to
+    /// `out`, not as errors.
+    pub fn execute(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(true),
+        };
+        if command == "a" || command == "assemble" {
+            let rest = line.trim_start()[command.len()..].trim();
+            self.listing = None;
+            return self.assemble(rest, out).map(|_| true);
+        }
+        let args: Result<Vec<u16>, _> = words.map(parse_number).collect();
+        let args = match args {
+            Ok(args) => args,
+            Err(word) => {
+                writeln!(out, "bad number: {}", word)?;
+                return Ok(true);
+            }
+        };
+        if command != "u" {
+            self.listing = None;
+        }
+        match (command, args.as_slice()) {
+            ("x", [addr]) => self.examine(*addr, 64, out)?,
+            ("x", [addr, len]) => self.examine(*addr, *len, out)?,
+            ("r", []) => self.registers(out)?,
+            ("u", []) => self.unassemble(None, 8, out)?,
+            ("u", [addr]) => self.unassemble(Some(*addr), 8, out)?,
+            ("u", [addr, n]) => self.unassemble(Some(*addr), *n, out)?,
+            ("s", []) => self.step(1, out)?,
+            ("s", [n]) => self.step(*n, out)?,
+            ("c", []) => self.cont(out)?,
+            ("b", []) => {
+                for addr in &self.breakpoints {
+                    writeln!(out, "{:04x}", addr)?;
+                }
+            }
+            ("b", [addr]) => self.set_breakpoint(*addr),
+            ("d", [addr]) => {
+                if !self.breakpoints.remove(addr) {
+                    writeln!(out, "no breakpoint at {:04x}", addr)?;
+                }
+            }
+            ("w", [addr]) => self.machine.cpu().memory.borrow_mut().set_watch(*addr..=*addr, Access::Write),
+            ("q", []) => return Ok(false),
+            _ => writeln!(out, "commands: x addr [len], r, u [addr] [n], s [n], c, b [addr], d addr, w addr, a addr: insn, q")?,
+        }
+        Ok(true)
+    }
+
+    // A copy of memory, so that looking at it sets off no watchpoint or
+    // guard
+    fn snapshot(&self) -> Memory8080 {
+        let mut memory = Memory8080::new_empty();
+        memory.set_contents(self.machine.cpu().memory.borrow().contents());
+        memory
+    }
+
+    fn examine(&self, addr: u16, len: u16, out: &mut impl Write) -> io::Result<()> {
+        if len > 0 {
+            let end = addr.saturating_add(len - 1);
+            write!(out, "{}", self.snapshot().hexdump(addr..=end))?;
+        }
+        Ok(())
+    }
+
+    fn registers(&self, out: &mut impl Write) -> io::Result<()> {
+        let cpu = self.machine.cpu();
+        let s = cpu.state();
+        writeln!(out, "A={:02x} F={:02x} B={:02x} C={:02x} D={:02x} E={:02x} H={:02x} L={:02x} SP={:04x} PC={:04x}{} cycles={}",
+                 s.a, s.f, s.b, s.c, s.d, s.e, s.h, s.l, s.sp, s.pc, if s.inte { " EI" } else { "" }, cpu.cycles())
+    }
+
+    fn unassemble(&mut self, addr: Option<u16>, n: u16, out: &mut impl Write) -> io::Result<()> {
+        let memory = self.snapshot();
+        let hl = self.machine.cpu().regs.get_hl();
+        let mut pc = addr.or(self.listing).unwrap_or(self.machine.cpu().pc);
+        for instruction in self.disassembler.instructions(&memory, pc).hl(hl).take(usize::from(n)) {
+            let marker = if self.breakpoints.contains(&instruction.addr) { '*' } else { ' ' };
+            writeln!(out, "{}{}", marker, instruction.text)?;
+            pc = instruction.addr.wrapping_add(instruction.length());
+        }
+        self.listing = Some(pc);
+        Ok(())
+    }
+
+    fn disassembly_line(&self, memory: &Memory8080, pc: u16, hl: u16, out: &mut impl Write) -> io::Result<()> {
+        let op = memory.peek(usize::from(pc));
+        let marker = if self.breakpoints.contains(&pc) { '*' } else { ' ' };
+        writeln!(out, "{}{}", marker, self.disassembler.disassemble(memory, &pc, &op, &hl))
+    }
+
+    // `addr: insn`, assembled and written over what is there, ROM or not
+    fn assemble(&mut self, command: &str, out: &mut impl Write) -> io::Result<()> {
+        let (addr, insn) = match command.split_once(':') {
+            Some((addr, insn)) => (addr.trim(), insn),
+            None => return writeln!(out, "usage: a addr: insn"),
+        };
+        let addr = match parse_number(addr) {
+            Ok(addr) => addr,
+            Err(word) => return writeln!(out, "bad number: {}", word),
+        };
+        match assembler::assemble(insn, addr) {
+            Ok(code) => {
+                self.machine.cpu().memory.borrow_mut().patch(addr, &code);
+                writeln!(out, "{:04x}", addr.wrapping_add(code.len() as u16))
+            }
+            Err(e) => writeln!(out, "{}", e.kind),
+        }
+    }
+
+    fn step(&mut self, n: u16, out: &mut impl Write) -> io::Result<()> {
+        for _ in 0..n {
+            if !self.next(out)? {
+                break;
+            }
+        }
+        self.show_next(out)
+    }
+
+    fn cont(&mut self, out: &mut impl Write) -> io::Result<()> {
+        self.machine.set_clock_paused(false);
+        let stopped = self.run_to_stop(out);
+        self.machine.set_clock_paused(true);
+        stopped?;
+        self.show_next(out)
+    }
+
+    fn run_to_stop(&mut self, out: &mut impl Write) -> io::Result<()> {
+        while self.next(out)? {
+            let pc = self.machine.cpu().pc;
+            if self.breakpoints.contains(&pc) {
+                return writeln!(out, "breakpoint at {:04x}", pc);
+            }
+        }
+        Ok(())
+    }
+
+    // Runs one step of the machine, returning false if something worth
+    // stopping for happened
+    fn next(&mut self, out: &mut impl Write) -> io::Result<bool> {
+        let pc = self.machine.cpu().pc;
+        if let Err(e) = self.machine.next() {
+            writeln!(out, "stopped: {}", e)?;
+            return Ok(false);
+        }
+        let cpu = self.machine.cpu();
+        if let Some((addr, _)) = cpu.memory.borrow().take_watch_hit() {
+            writeln!(out, "write to {:04x} at {:04x}", addr, pc)?;
+            return Ok(false);
+        }
+        // Nothing can wake it up
+        if cpu.is_halted() && !cpu.interrupts_enabled() {
+            writeln!(out, "halted at {:04x}", pc)?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn show_next(&self, out: &mut impl Write) -> io::Result<()> {
+        let cpu = self.machine.cpu();
+        self.disassembly_line(&self.snapshot(), cpu.pc, cpu.regs.get_hl(), out)
+    }
+}
+
+// Hex, with or without a 0x prefix
+fn parse_number(word: &str) -> Result<u16, &str> {
+    let digits = word.strip_prefix("0x").unwrap_or(word);
+    u16::from_str_radix(digits, 16).map_err(|_| word)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bare::BareMachine;
+    use crate::machines::composite::{MachineBuilder, Rst};
+    use crate::memory::Memory8080;
+    use crate::repl::Repl;
+
+    fn session(code: &[u8], commands: &str) -> String {
+        let mut repl = Repl::new(BareMachine::new(code));
+        let mut out = Vec::new();
+        repl.run(commands.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn commands() {
+        // LXI H, 0x2000; loop: INR M; JMP loop
+        let code = [0x21, 0x00, 0x20, 0x34, 0xc3, 0x03, 0x00];
+        let out = session(&code, "u 0 2\nu 4 1\nu\ns 3\nx 0x2000 4\nb 4\nb\nc\nd 4\nd 4\nw 2000\nc\nr\nx zz\nq\nr\n");
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines, [
+            "- 0    LXI H 0x2000",
+            " 3    INR $(0x0)",
+            "- 4    JMP $(0x3)",
+            "- 7    NOP",
+            " 8    NOP",
+            " 9    NOP",
+            " a    NOP",
+            " b    NOP",
+            " c    NOP",
+            " d    NOP",
+            " e    NOP",
+            "- 3    INR $(0x2000)",
+            "-2000  01 00 00 00                                      |....|",
+            "--0004",
+            "-breakpoint at 0004",
+            "*4    JMP $(0x3)",
+            "--no breakpoint at 0004",
+            "--write to 2000 at 0003",
+            " 4    JMP $(0x3)",
+            "-A=00 F=06 B=00 C=00 D=00 E=00 H=20 L=00 SP=0000 PC=0004 cycles=60",
+            "-bad number: zz",
+            "-",
+        ]);
+    }
+
+    #[test]
+    fn assemble() {
+        // loop: INR B; JMP loop
+        let out = session(&[0x04, 0xc3, 0x00, 0x00], "a 1: JMP 0x2000\nassemble 0x2000: MVI B, 80h\nu 1 2\nb 2002\nc\nr\na 3000\na 3000: JMP nowhere\nq\n");
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines, [
+            "-0004",
+            "-2002",
+            "- 1    JMP $(0x2000)",
+            " 4    NOP",
+            "--breakpoint at 2002",
+            "*2002    NOP",
+            "-A=00 F=02 B=80 C=00 D=00 E=00 H=00 L=00 SP=0000 PC=2002 cycles=22",
+            "-usage: a addr: insn",
+            "-undefined symbol `NOWHERE`",
+            "-",
+        ]);
+    }
+
+    #[test]
+    fn errors_stop_the_machine() {
+        assert!(session(&[0x00, 0x76], "c\n").starts_with("-halted at 0001\n"));
+        // OUT 0x05
+        assert!(session(&[0xd3, 0x05], "s\n").starts_with("-stopped: "));
+        assert!(session(&[], "?\n").contains("commands: x addr"));
+    }
+
+    #[test]
+    fn clock_stands_still_while_stepping() {
+        // 0x0000: LXI SP, 0x2000; EI; loop: JMP loop
+        // 0x0010 (RST 2): INR B; EI; RET
+        let mut image = [0x00; 0x10000];
+        image[..7].copy_from_slice(&[0x31, 0x00, 0x20, 0xfb, 0xc3, 0x04, 0x00]);
+        image[0x10..0x13].copy_from_slice(&[0x04, 0xfb, 0xc9]);
+        let machine = MachineBuilder::new().memory(Memory8080::new(image)).interrupt_every(100, Rst(2)).build();
+        let mut repl = Repl::new(machine);
+        assert!(repl.machine().is_clock_paused());
+        let mut out = Vec::new();
+        repl.run("s 50\nb 10\nc\nq\n".as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[..3], ["- 4    JMP $(0x4)", "--breakpoint at 0010", "*10    INR B"]);
+        assert!(repl.machine().is_clock_paused());
+        assert!(!repl.into_inner().is_clock_paused());
+    }
+}
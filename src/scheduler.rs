@@ -0,0 +1,567 @@
+//! Things that happen after a number of clock cycles: periodic interrupts
+//! such as a video board's VBlank, and timers that call back into the
+//! host.
+//!
+//! A `Scheduler` keeps its own count of cycles, advanced by the cycles
+//! each instruction reports. Interrupts it raises stay pending until the
+//! CPU accepts them, which `step` takes care of between instructions.
+//!
+//! ```
+//! use i8080_emulator::cpu::CPU;
+//! use i8080_emulator::io::PortBus;
+//! use i8080_emulator::memory::Memory8080;
+//! use i8080_emulator::scheduler::{Scheduler, Task};
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! // EI; loop: JMP loop ... 0x0008 (RST 1): INR B; EI; RET
+//! let mut memory = [0x00; 0x10000];
+//! memory[..4].copy_from_slice(&[0xfb, 0xc3, 0x01, 0x00]);
+//! memory[0x08..0x0b].copy_from_slice(&[0x04, 0xfb, 0xc9]);
+//! let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+//! let mut scheduler = Scheduler::new();
+//! scheduler.every(16_666, Task::Interrupt(0xcf));
+//! let mut bus = PortBus::new();
+//! while scheduler.now() < 101_000 {
+//!     scheduler.step(&mut cpu, &mut bus);
+//! }
+//! assert_eq!(cpu.regs.b, 6);
+//! ```
+//!
+//! A debugger stepping through the program pauses the scheduler with
+//! `pause`, so that interrupts and timers do not fire under the user's
+//! feet and the machine's timing picks up where it left off on `resume`.
+//!
+//! Frontends that run a frame's worth of cycles at a time use
+//! `run_frame`, or `begin_frame` and `end_frame` around their own loop.
+//! The last instruction of a frame usually runs past the quota, and the
+//! overshoot is carried over and taken off the next frame, so frames
+//! average out to the quota. Each frame's accounting comes back as a
+//! `FrameStats`.
+//!
+//! A `Lockstep` runs several machines side by side, for boards with more
+//! than one CPU. It always steps whichever machine is furthest behind, so
+//! none gets more than an instruction ahead of the others and two CPUs
+//! sharing memory see each other's writes in the order they would on
+//! the board.
+
+use crate::cpu::{ClockCycles, Event, Timestamp, CPU};
+use crate::error::EmulatorError;
+use crate::io::PortBus;
+use crate::memory::{Memory, Memory8080};
+use crate::Machine;
+
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+/// What to do when an event comes due.
+pub enum Task<M: Memory = Memory8080> {
+    /// Requests an interrupt with the given instruction, usually an RST.
+    Interrupt(u8),
+    /// Calls back into the host.
+    Call(Callback<M>),
+}
+
+type Callback<M> = Box<dyn FnMut(&mut CPU<M>)>;
+
+impl<M: Memory> Task<M> {
+    pub fn call(f: impl FnMut(&mut CPU<M>) + 'static) -> Self {
+        Task::Call(Box::new(f))
+    }
+}
+
+/// The accounting for one frame run by `Scheduler::run_frame` or between
+/// `begin_frame` and `end_frame`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameStats {
+    /// Cycles the frame was to run: the quota less the carryover from the
+    /// frame before.
+    pub budget: Timestamp,
+    /// Cycles run this frame.
+    pub cycles: Timestamp,
+    /// Cycles run past the budget, taken off the next frame.
+    pub carryover: Timestamp,
+    /// Interrupts raised by scheduled events, not counting one raised
+    /// again while still pending.
+    pub interrupts_raised: u32,
+    /// Interrupts the CPU accepted.
+    pub interrupts_taken: u32,
+}
+
+// Where the frame being run started
+struct Frame {
+    budget: Timestamp,
+    start: Timestamp,
+    raised: u64,
+    taken: u64,
+}
+
+/// Identifies a scheduled event, for `Scheduler::cancel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventId(u64);
+
+struct Entry<M: Memory> {
+    id: EventId,
+    due: Timestamp,
+    // For events that repeat
+    period: Option<Timestamp>,
+    task: Task<M>,
+}
+
+/// Events ordered by the cycle they come due at. See the module
+/// documentation.
+pub struct Scheduler<M: Memory = Memory8080> {
+    now: Timestamp,
+    entries: Vec<Entry<M>>,
+    next_id: u64,
+    // Interrupts raised but not accepted yet, oldest first
+    pending: Vec<u8>,
+    // Interrupts raised and accepted since the start
+    raised: u64,
+    taken: u64,
+    carryover: Timestamp,
+    frame: Option<Frame>,
+    last_frame: Option<FrameStats>,
+    paused: bool,
+}
+
+impl<M: Memory> Default for Scheduler<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Memory> Scheduler<M> {
+    pub fn new() -> Self {
+        Scheduler {
+            now: 0,
+            entries: Vec::new(),
+            next_id: 0,
+            pending: Vec::new(),
+            raised: 0,
+            taken: 0,
+            carryover: 0,
+            frame: None,
+            last_frame: None,
+            paused: false,
+        }
+    }
+
+    /// Cycles the scheduler has been advanced by.
+    pub fn now(&self) -> Timestamp {
+        self.now
+    }
+
+    /// Runs `task` every `period` cycles from now.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is 0.
+    pub fn every(&mut self, period: Timestamp, task: Task<M>) -> EventId {
+        assert!(period > 0, "a periodic event needs a period");
+        self.schedule(period, Some(period), task)
+    }
+
+    /// Runs `task` once, `delay` cycles from now.
+    pub fn after(&mut self, delay: Timestamp, task: Task<M>) -> EventId {
+        self.schedule(delay, None, task)
+    }
+
+    fn schedule(&mut self, delay: Timestamp, period: Option<Timestamp>, task: Task<M>) -> EventId {
+        let id = EventId(self.next_id);
+        self.next_id += 1;
+        self.entries.push(Entry { id, due: self.now + delay, period, task });
+        id
+    }
+
+    /// Drops an event. Interrupts it has already raised stay pending.
+    pub fn cancel(&mut self, id: EventId) {
+        self.entries.retain(|entry| entry.id != id);
+    }
+
+    /// Whether no event is left to come due.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Interrupts raised but not yet accepted by the CPU.
+    pub fn pending(&self) -> &[u8] {
+        &self.pending
+    }
+
+    /// Drops the interrupts raised but not yet accepted. Scheduled events
+    /// keep their timing.
+    pub fn clear_pending(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Stops the clock: until `resume`, `advance` leaves time where it is
+    /// and runs no tasks, and pending interrupts wait, while the CPU goes
+    /// on being stepped.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Moves time on by `cycles` and runs every task that comes due, in
+    /// order, a periodic one as many times as its period has passed.
+    /// An interrupt already pending is not raised twice.
+    pub fn advance(&mut self, cpu: &mut CPU<M>, cycles: ClockCycles) {
+        if self.paused {
+            return;
+        }
+        self.now += Timestamp::from(cycles);
+        loop {
+            let now = self.now;
+            let next = self.entries.iter_mut()
+                .enumerate()
+                .filter(|(_, entry)| entry.due <= now)
+                .min_by_key(|(_, entry)| (entry.due, entry.id.0));
+            let index = match next {
+                Some((index, _)) => index,
+                None => return,
+            };
+            let entry = &mut self.entries[index];
+            match &mut entry.task {
+                Task::Interrupt(op) => {
+                    if !self.pending.contains(op) {
+                        self.pending.push(*op);
+                        self.raised += 1;
+                    }
+                }
+                Task::Call(f) => f(cpu),
+            }
+            match entry.period {
+                Some(period) => entry.due += period,
+                None => {
+                    self.entries.remove(index);
+                }
+            }
+        }
+    }
+
+    /// Has the CPU accept the oldest pending interrupt, if it will.
+    pub fn take_interrupt(&mut self, cpu: &mut CPU<M>) -> Option<Event> {
+        if self.paused {
+            return None;
+        }
+        let op = *self.pending.first()?;
+        let event = cpu.interrupt(op)?;
+        self.pending.remove(0);
+        self.taken += 1;
+        Some(event)
+    }
+
+    /// Accepts a pending interrupt or executes one instruction, resolving
+    /// port accesses on `bus`, then advances by the cycles it took.
+    pub fn step(&mut self, cpu: &mut CPU<M>, bus: &mut PortBus) -> Event {
+        let event = match self.take_interrupt(cpu) {
+            Some(event) => event,
+            None => bus.step(cpu),
+        };
+        self.advance(cpu, event.cycles());
+        event
+    }
+
+    /// Starts a frame of `quota` cycles and returns how many to run in
+    /// it, which is `quota` less what the last frame ran over. Run until
+    /// `now` has moved on by at least that much, then call `end_frame`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the scheduler is paused, as the frame would never end.
+    pub fn begin_frame(&mut self, quota: Timestamp) -> Timestamp {
+        assert!(!self.paused, "a paused scheduler cannot run a frame");
+        let budget = quota.saturating_sub(self.carryover);
+        self.frame = Some(Frame { budget, start: self.now, raised: self.raised, taken: self.taken });
+        budget
+    }
+
+    /// Ends the frame started by `begin_frame`, keeping what it ran over
+    /// for the next, and returns its accounting.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no frame was begun.
+    pub fn end_frame(&mut self) -> FrameStats {
+        let frame = self.frame.take().expect("end_frame needs a frame begun with begin_frame");
+        let cycles = self.now - frame.start;
+        self.carryover = cycles.saturating_sub(frame.budget);
+        let stats = FrameStats {
+            budget: frame.budget,
+            cycles,
+            carryover: self.carryover,
+            interrupts_raised: (self.raised - frame.raised) as u32,
+            interrupts_taken: (self.taken - frame.taken) as u32,
+        };
+        self.last_frame = Some(stats);
+        stats
+    }
+
+    /// Runs a frame of `quota` cycles with `step`. See the module
+    /// documentation.
+    pub fn run_frame(&mut self, cpu: &mut CPU<M>, bus: &mut PortBus, quota: Timestamp) -> FrameStats {
+        let end = self.now + self.begin_frame(quota);
+        while self.now < end {
+            self.step(cpu, bus);
+        }
+        self.end_frame()
+    }
+
+    /// The accounting of the last frame ended, if any.
+    pub fn last_frame(&self) -> Option<FrameStats> {
+        self.last_frame
+    }
+
+    /// Cycles the last frame ran over, to be taken off the next.
+    pub fn carryover(&self) -> Timestamp {
+        self.carryover
+    }
+}
+
+type Shared<E> = Rc<RefCell<dyn Machine<Event = E>>>;
+
+/// Machines run in step with each other by cycle count. See the module
+/// documentation. The caller keeps its own handle on each machine, for
+/// looking at it between steps.
+pub struct Lockstep<E = Event> {
+    // Each machine and its cycle count when it was added
+    machines: Vec<(Shared<E>, Timestamp)>,
+}
+
+impl<E> Default for Lockstep<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E> Lockstep<E> {
+    pub fn new() -> Self {
+        Lockstep { machines: Vec::new() }
+    }
+
+    /// Adds `machine`, which starts at the same time as the others, and
+    /// returns its index.
+    pub fn add(&mut self, machine: Rc<RefCell<dyn Machine<Event = E>>>) -> usize {
+        let start = machine.borrow().cycles();
+        self.machines.push((machine, start));
+        self.machines.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.machines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.machines.is_empty()
+    }
+
+    /// Cycles machine `index` has run since it was added.
+    pub fn elapsed(&self, index: usize) -> Timestamp {
+        let (machine, start) = &self.machines[index];
+        machine.borrow().cycles().wrapping_sub(*start)
+    }
+
+    /// Cycles every machine has run, which is how far the slowest has got.
+    pub fn now(&self) -> Timestamp {
+        (0..self.len()).map(|index| self.elapsed(index)).min().unwrap_or(0)
+    }
+
+    /// Steps the machine furthest behind, the first added on a tie, and
+    /// returns its index and event. An error comes with the index of the
+    /// machine that reported it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no machines.
+    pub fn step(&mut self) -> Result<(usize, E), (usize, EmulatorError)> {
+        let index = (0..self.len())
+            .min_by_key(|&index| self.elapsed(index))
+            .expect("Lockstep::step needs a machine to run");
+        let event = self.machines[index].0.borrow_mut().next();
+        event.map(|event| (index, event)).map_err(|error| (index, error))
+    }
+
+    /// Steps the machines until every one has run at least `cycles` more
+    /// cycles.
+    pub fn run_cycles(&mut self, cycles: Timestamp) -> Result<(), (usize, EmulatorError)> {
+        let end = self.now() + cycles;
+        while self.now() < end {
+            self.step()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{Event, Timestamp, CPU};
+    use crate::error::EmulatorError;
+    use crate::memory::{Memory, Memory8080};
+    use crate::io::PortBus;
+    use crate::scheduler::{FrameStats, Lockstep, Scheduler, Task};
+    use crate::Machine;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn cpu() -> CPU {
+        CPU::new(Rc::new(RefCell::new(Memory8080::new_empty())))
+    }
+
+    #[test]
+    fn tasks_run_in_order() {
+        let mut cpu = cpu();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut scheduler = Scheduler::new();
+        let (a, b, c) = (log.clone(), log.clone(), log.clone());
+        let tick = scheduler.every(100, Task::call(move |cpu: &mut CPU| a.borrow_mut().push(("tick", cpu.regs.b))));
+        scheduler.after(150, Task::call(move |_: &mut CPU| b.borrow_mut().push(("once", 0))));
+        scheduler.after(100, Task::call(move |cpu: &mut CPU| {
+            cpu.regs.b += 1;
+            c.borrow_mut().push(("first", 0));
+        }));
+        scheduler.advance(&mut cpu, 99);
+        assert!(log.borrow().is_empty());
+        scheduler.advance(&mut cpu, 251);
+        assert_eq!(*log.borrow(), [("tick", 0), ("first", 0), ("once", 0), ("tick", 1), ("tick", 1)]);
+
+        scheduler.cancel(tick);
+        scheduler.advance(&mut cpu, 1000);
+        assert_eq!(log.borrow().len(), 5);
+        assert_eq!(scheduler.now(), 1350);
+    }
+
+    #[test]
+    fn interrupts_wait_until_accepted() {
+        let mut cpu = cpu();
+        let mut scheduler = Scheduler::new();
+        scheduler.every(10, Task::Interrupt(0xcf));
+        scheduler.after(15, Task::Interrupt(0xd7));
+        scheduler.advance(&mut cpu, 30);
+        assert_eq!(scheduler.pending(), [0xcf, 0xd7]);
+        assert!(scheduler.take_interrupt(&mut cpu).is_none());
+
+        cpu.set_interrupts_enabled(true);
+        assert!(scheduler.take_interrupt(&mut cpu).is_some());
+        assert_eq!((cpu.pc, scheduler.pending()), (0x0008, &[0xd7][..]));
+    }
+
+    #[test]
+    fn paused_clock() {
+        let mut cpu = cpu();
+        cpu.set_interrupts_enabled(true);
+        let mut scheduler = Scheduler::new();
+        scheduler.every(10, Task::Interrupt(0xcf));
+        scheduler.advance(&mut cpu, 10);
+        scheduler.pause();
+        scheduler.advance(&mut cpu, 100);
+        assert_eq!(scheduler.now(), 10);
+        assert!(scheduler.take_interrupt(&mut cpu).is_none());
+
+        scheduler.resume();
+        assert!(scheduler.take_interrupt(&mut cpu).is_some());
+        scheduler.advance(&mut cpu, 5);
+        assert!(scheduler.pending().is_empty());
+        scheduler.advance(&mut cpu, 5);
+        assert_eq!(scheduler.pending(), [0xcf]);
+    }
+
+    #[test]
+    fn frames_carry_over() {
+        // LXI SP, 0x2000; EI; loop: JMP loop ... 0x0008 (RST 1): EI; RET
+        let mut bytes = [0x00; 0x10000];
+        bytes[..7].copy_from_slice(&[0x31, 0x00, 0x20, 0xfb, 0xc3, 0x04, 0x00]);
+        bytes[0x08..0x0a].copy_from_slice(&[0xfb, 0xc9]);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(bytes))));
+        let mut bus = PortBus::new();
+        let mut scheduler = Scheduler::new();
+        scheduler.every(100, Task::Interrupt(0xcf));
+        assert_eq!(scheduler.last_frame(), None);
+
+        // LXI (10), EI (4) then JMPs (10 each) up to 104
+        let first = scheduler.run_frame(&mut cpu, &mut bus, 95);
+        assert_eq!(first, FrameStats { budget: 95, cycles: 104, carryover: 9, interrupts_raised: 1, interrupts_taken: 0 });
+        // RST (11), EI (4), RET (10), then JMPs up to 199
+        let second = scheduler.run_frame(&mut cpu, &mut bus, 95);
+        assert_eq!(second, FrameStats { budget: 86, cycles: 95, carryover: 9, interrupts_raised: 0, interrupts_taken: 1 });
+        assert_eq!(scheduler.last_frame(), Some(second));
+        assert_eq!(scheduler.now(), 199);
+
+        let mut totals = 0;
+        for _ in 0..100 {
+            totals += scheduler.run_frame(&mut cpu, &mut bus, 95).cycles;
+        }
+        // Frames average out to the quota
+        assert!(totals.abs_diff(100 * 95) <= 10);
+        assert!(scheduler.carryover() < 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "end_frame needs a frame begun with begin_frame")]
+    fn frames_need_a_beginning() {
+        Scheduler::<Memory8080>::new().end_frame();
+    }
+
+    // A CPU on its own, sharing memory with others
+    struct Core(CPU);
+
+    impl Machine for Core {
+        type Event = Event;
+
+        fn next(&mut self) -> Result<Event, EmulatorError> {
+            match self.0.step() {
+                (Event::Halt(_), _) => Err(EmulatorError::Halted(self.0.pc.wrapping_sub(1))),
+                (event, _) => Ok(event),
+            }
+        }
+
+        fn run(&mut self) -> Result<(), EmulatorError> {
+            loop {
+                self.next()?;
+            }
+        }
+
+        fn reset(&mut self) {
+            self.0.reset();
+        }
+
+        fn cycles(&self) -> Timestamp {
+            self.0.cycles()
+        }
+    }
+
+    #[test]
+    fn lockstep_through_a_mailbox() {
+        // 0x0000: loop: LDA 0x1000; ORA A; JZ loop; STA 0x1001; HLT
+        // 0x0100: MVI A, 0x42; NOP x 20; STA 0x1000; HLT
+        let mut bytes = [0x00; 0x10000];
+        bytes[..12].copy_from_slice(&[0x3a, 0x00, 0x10, 0xb7, 0xca, 0x00, 0x00, 0x32, 0x01, 0x10, 0x76, 0x00]);
+        bytes[0x100..0x102].copy_from_slice(&[0x3e, 0x42]);
+        bytes[0x116..0x11a].copy_from_slice(&[0x32, 0x00, 0x10, 0x76]);
+        let memory = Rc::new(RefCell::new(Memory8080::new(bytes)));
+        let reader = Rc::new(RefCell::new(Core(CPU::new(memory.clone()))));
+        let writer = Rc::new(RefCell::new(Core(CPU::new(memory.clone()))));
+        writer.borrow_mut().0.pc = 0x0100;
+
+        let mut lockstep = Lockstep::new();
+        assert_eq!((lockstep.add(reader.clone()), lockstep.add(writer.clone())), (0, 1));
+        // The writer stores at cycle 7 + 20 * 4 and halts 13 cycles later
+        assert_eq!(lockstep.run_cycles(1000).unwrap_err(), (1, EmulatorError::Halted(0x0119)));
+        assert_eq!(lockstep.elapsed(1), 7 + 20 * 4 + 13 + 7);
+        while lockstep.step().is_ok() {}
+        assert_eq!(memory.borrow().read(0x1001), 0x42);
+        // Neither got more than an instruction ahead
+        assert!(lockstep.elapsed(0).abs_diff(lockstep.elapsed(1)) <= 17);
+        assert!(reader.borrow().0.is_halted());
+    }
+}
@@ -0,0 +1,122 @@
+//! Stopping a long run cleanly when the host asks, so that a batch job
+//! killed with SIGINT or SIGTERM still leaves its diagnostics behind.
+//!
+//! Next to nothing is safe to do inside a signal handler, so the handler
+//! `Shutdown::on_signals` installs only sets a flag. The run loop checks
+//! `is_requested` between steps, with the machine between instructions,
+//! and on its way out does what it could not have done in the handler:
+//! finishes its trace and writes the machine's final state with
+//! `write_snapshot`.
+//!
+//! ```no_run
+//! use i8080_emulator::bare::BareMachine;
+//! use i8080_emulator::shutdown::{write_snapshot, Shutdown};
+//! use i8080_emulator::trace::TraceWriter;
+//! use i8080_emulator::Machine;
+//! use std::fs::File;
+//!
+//! # #[cfg(feature = "signals")]
+//! # fn main() {
+//! let shutdown = Shutdown::on_signals().unwrap();
+//! let mut machine = BareMachine::new(&std::fs::read("batch.bin").unwrap());
+//! let mut trace = TraceWriter::create("batch.trace").unwrap();
+//! machine.run_until(|machine| {
+//!     trace.record(&machine.cpu);
+//!     shutdown.is_requested()
+//! }).unwrap();
+//! trace.finish().unwrap();
+//! let state = machine.cpu.save_state().unwrap();
+//! write_snapshot(&state, &mut File::create("batch.state").unwrap()).unwrap();
+//! # }
+//! # #[cfg(not(feature = "signals"))]
+//! # fn main() {}
+//! ```
+//!
+//! Catching the signals needs the `signals` feature.
+
+use crate::cpu::SaveState;
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Whether the host has asked the emulator to stop. Clones share the
+/// same flag.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    /// A flag nothing has set yet, for `request` to set, say from another
+    /// thread.
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    /// A flag that SIGINT and SIGTERM set, or Ctrl-C and closing the
+    /// console on Windows. A process can only have one; asking again is
+    /// an error.
+    #[cfg(feature = "signals")]
+    pub fn on_signals() -> io::Result<Self> {
+        let shutdown = Shutdown::new();
+        let requested = shutdown.requested.clone();
+        ctrlc::set_handler(move || requested.store(true, Ordering::Relaxed)).map_err(io::Error::other)?;
+        Ok(shutdown)
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+}
+
+/// Writes `state` for a person to read: the registers as `CpuState`
+/// shows them, the cycle count, then memory 16 bytes to a line, leaving
+/// out lines of zeros.
+pub fn write_snapshot(state: &SaveState, output: &mut impl Write) -> io::Result<()> {
+    writeln!(output, "{}", state.cpu)?;
+    writeln!(output, "cycles: {}", state.cycles)?;
+    for (line, bytes) in state.memory.chunks(16).enumerate() {
+        if bytes.iter().all(|&byte| byte == 0x00) {
+            continue;
+        }
+        write!(output, "{:04X}:", line * 16)?;
+        for byte in bytes {
+            write!(output, " {:02X}", byte)?;
+        }
+        writeln!(output)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bare::BareMachine;
+    use crate::shutdown::{write_snapshot, Shutdown};
+    use crate::Machine;
+
+    use std::thread;
+
+    #[test]
+    fn stop_between_instructions() {
+        // JMP 0x0000
+        let mut machine = BareMachine::new(&[0xc3, 0x00, 0x00]);
+        let shutdown = Shutdown::new();
+        let stop = shutdown.clone();
+        thread::spawn(move || stop.request());
+        machine.run_until(|_| shutdown.is_requested()).unwrap();
+        assert!(shutdown.is_requested());
+
+        let mut snapshot = Vec::new();
+        write_snapshot(&machine.cpu.save_state().unwrap(), &mut snapshot).unwrap();
+        let snapshot = String::from_utf8(snapshot).unwrap();
+        let lines: Vec<&str> = snapshot.lines().collect();
+        assert!(lines[0].ends_with("PC=0000"));
+        assert_eq!(lines[1], format!("cycles: {}", machine.cycles()));
+        assert_eq!(&lines[2..], ["0000: C3 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00"]);
+    }
+}
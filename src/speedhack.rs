@@ -0,0 +1,301 @@
+//! An opt-in speed hack that fast-forwards busy-wait delay loops.
+//!
+//! Programs written for real hardware burn time in loops such as
+//!
+//! ```text
+//! loop: DCR B            loop: DCX D
+//!       JNZ loop               MOV A, D
+//!                              ORA E
+//!                              JNZ loop
+//! ```
+//!
+//! which are pure overhead when the host does not need to run in real
+//! time, a CP/M compile for instance. `DelayLoops` steps the CPU like
+//! `CPU::step`, keeping a short history of instruction pointers. Once PC
+//! comes back round to the head of one of these loops, the address is
+//! marked as a delay loop and every later visit skips all but the last
+//! iteration in one go: the counter is set to 1 and the loop finishes
+//! normally, so registers and flags end up exactly as if it had run.
+//!
+//! Plain stepping stays the default; nothing is skipped unless a machine
+//! steps through `DelayLoops`.
+//!
+//! ```
+//! use i8080_emulator::cpu::CPU;
+//! use i8080_emulator::memory::Memory8080;
+//! use i8080_emulator::speedhack::DelayLoops;
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! // MVI B, 0; loop: DCR B; JNZ loop; HLT
+//! let mut memory = [0x00; 0x10000];
+//! memory[..7].copy_from_slice(&[0x06, 0x00, 0x05, 0xc2, 0x02, 0x00, 0x76]);
+//! let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+//! let mut delays = DelayLoops::new();
+//! let mut steps = 0;
+//! while !cpu.is_halted() {
+//!     delays.step(&mut cpu);
+//!     steps += 1;
+//! }
+//! assert_eq!(cpu.cycles(), 7 + 256 * 15 + 7);
+//! assert!(steps < 10);
+//! ```
+
+use crate::cpu::{ClockCycles, Event, CPU};
+use crate::memory::Memory;
+
+use alloc::collections::{BTreeSet, VecDeque};
+
+// How many instruction pointers are remembered when looking for loops
+const HISTORY: usize = 8;
+
+/// The delay loops recognized, by their loop counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DelayLoop {
+    /// `DCR r; JNZ loop`, with `r` numbered as in the opcode.
+    Count8(u8),
+    /// `DCX rp; MOV A, hi; ORA lo; JNZ loop` or with `lo` and `hi` the
+    /// other way round, with `rp` numbered as in the opcode.
+    Count16(u8),
+}
+
+impl DelayLoop {
+    // Recognizes the delay loop starting at `head`, if there is one
+    fn at(memory: &impl Memory, head: u16) -> Option<DelayLoop> {
+        let byte = |offset: u16| memory.peek(usize::from(head.wrapping_add(offset)));
+        let jnz_head = |offset: u16| byte(offset) == 0xc2 && u16::from_le_bytes([byte(offset + 1), byte(offset + 2)]) == head;
+        let op = byte(0);
+        // DCR r, for any r but M
+        if op & 0xc7 == 0x05 && op != 0x35 && jnz_head(1) {
+            return Some(DelayLoop::Count8((op >> 3) & 0x07));
+        }
+        // DCX B, D or H
+        if op & 0xcf == 0x0b && op != 0x3b && jnz_head(3) {
+            let rp = (op >> 4) & 0x03;
+            let (hi, lo) = (rp * 2, rp * 2 + 1);
+            let mov_a = |r: u8| 0x78 | r;
+            let ora = |r: u8| 0xb0 | r;
+            let tests_pair = (byte(1) == mov_a(hi) && byte(2) == ora(lo)) || (byte(1) == mov_a(lo) && byte(2) == ora(hi));
+            if tests_pair {
+                return Some(DelayLoop::Count16(rp));
+            }
+        }
+        None
+    }
+
+    fn cycles_per_iteration(self) -> ClockCycles {
+        match self {
+            DelayLoop::Count8(_) => 5 + 10,
+            DelayLoop::Count16(_) => 5 + 5 + 4 + 10,
+        }
+    }
+
+    // Iterations left to run with the CPU at the head of the loop
+    fn remaining(self, cpu: &CPU) -> u32 {
+        let regs = &cpu.regs;
+        let (counter, wrap) = match self {
+            DelayLoop::Count8(r) => (u32::from(reg8(cpu, r)), 0x100),
+            DelayLoop::Count16(0) => (u32::from(regs.get_bc()), 0x10000),
+            DelayLoop::Count16(1) => (u32::from(regs.get_de()), 0x10000),
+            DelayLoop::Count16(_) => (u32::from(regs.get_hl()), 0x10000),
+        };
+        if counter == 0 { wrap } else { counter }
+    }
+
+    fn set_remaining(self, cpu: &mut CPU, remaining: u32) {
+        let regs = &mut cpu.regs;
+        match self {
+            DelayLoop::Count8(r) => {
+                let value = remaining as u8;
+                match r {
+                    0 => regs.b = value,
+                    1 => regs.c = value,
+                    2 => regs.d = value,
+                    3 => regs.e = value,
+                    4 => regs.h = value,
+                    5 => regs.l = value,
+                    _ => regs.a = value,
+                }
+            }
+            DelayLoop::Count16(0) => regs.set_bc(remaining as u16),
+            DelayLoop::Count16(1) => regs.set_de(remaining as u16),
+            DelayLoop::Count16(_) => regs.set_hl(remaining as u16),
+        }
+    }
+}
+
+fn reg8(cpu: &CPU, r: u8) -> u8 {
+    let regs = &cpu.regs;
+    match r {
+        0 => regs.b,
+        1 => regs.c,
+        2 => regs.d,
+        3 => regs.e,
+        4 => regs.h,
+        5 => regs.l,
+        _ => regs.a,
+    }
+}
+
+/// Steps a CPU, skipping through the delay loops it recognizes. See the
+/// module documentation.
+pub struct DelayLoops {
+    history: VecDeque<u16>,
+    // Loop heads seen running, checked again on every visit in case the
+    // code has changed since
+    heads: BTreeSet<u16>,
+    max_skip: ClockCycles,
+    skipped: u64,
+}
+
+impl Default for DelayLoops {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DelayLoops {
+    pub fn new() -> Self {
+        DelayLoops {
+            history: VecDeque::with_capacity(HISTORY),
+            heads: BTreeSet::new(),
+            max_skip: ClockCycles::MAX,
+            skipped: 0,
+        }
+    }
+
+    /// Limits how many cycles a single skip may cover, so a machine can
+    /// still deliver its interrupts on time. Unlimited by default.
+    pub fn set_max_skip(&mut self, cycles: ClockCycles) {
+        self.max_skip = cycles;
+    }
+
+    /// Loop iterations skipped so far.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Executes one instruction on `cpu`, or skips through a delay loop
+    /// starting at PC. A skip is reported as `Event::Normal` with the
+    /// cycles the skipped iterations would have taken, which also count
+    /// towards `CPU::cycles`. Port accesses are left to the caller as
+    /// with `CPU::step`.
+    pub fn step(&mut self, cpu: &mut CPU) -> Event {
+        let pc = cpu.pc;
+        if !cpu.is_halted() {
+            if self.heads.contains(&pc) || self.history.contains(&pc) {
+                if let Some(event) = self.skip(cpu) {
+                    return event;
+                }
+            }
+            if self.history.len() == HISTORY {
+                self.history.pop_front();
+            }
+            self.history.push_back(pc);
+        }
+        cpu.step().0
+    }
+
+    fn skip(&mut self, cpu: &mut CPU) -> Option<Event> {
+        let pc = cpu.pc;
+        let delay = DelayLoop::at(&*cpu.memory.borrow(), pc);
+        let delay = match delay {
+            Some(delay) => delay,
+            None => {
+                self.heads.remove(&pc);
+                return None;
+            }
+        };
+        self.heads.insert(pc);
+        let per_iteration = delay.cycles_per_iteration();
+        let remaining = delay.remaining(cpu);
+        // The last iteration always runs for real, to leave the flags and
+        // A as the loop itself would
+        let skip = (remaining - 1).min(self.max_skip / per_iteration);
+        if skip == 0 {
+            return None;
+        }
+        delay.set_remaining(cpu, remaining - skip);
+        let cycles = skip * per_iteration;
+        cpu.advance_cycles(cycles);
+        self.skipped += u64::from(skip);
+        Some(Event::Normal(cycles))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{Event, CPU};
+    use crate::memory::{Memory, Memory8080};
+    use crate::speedhack::DelayLoops;
+    use crate::I8080Core;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn cpu_with(code: &[u8]) -> CPU {
+        let mut memory = [0x00; 0x10000];
+        memory[..code.len()].copy_from_slice(code);
+        CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))))
+    }
+
+    // Runs `code` to its HLT both plainly and through `DelayLoops`,
+    // checking the two agree, and returns the number of steps skipping took
+    fn compare(code: &[u8], delays: &mut DelayLoops) -> usize {
+        let mut plain = cpu_with(code);
+        while !plain.is_halted() {
+            plain.step();
+        }
+        let mut fast = cpu_with(code);
+        let mut steps = 0;
+        while !fast.is_halted() {
+            delays.step(&mut fast);
+            steps += 1;
+        }
+        assert_eq!(fast.state(), plain.state());
+        assert_eq!(fast.cycles(), plain.cycles());
+        steps
+    }
+
+    #[test]
+    fn eight_bit_loop() {
+        // MVI C, 0x20; loop: DCR C; JNZ loop; HLT
+        let mut delays = DelayLoops::new();
+        assert_eq!(compare(&[0x0e, 0x20, 0x0d, 0xc2, 0x02, 0x00, 0x76], &mut delays), 7);
+        assert_eq!(delays.skipped(), 0x1e);
+    }
+
+    #[test]
+    fn sixteen_bit_loop() {
+        // LXI D, 0x1234; loop: DCX D; MOV A, D; ORA E; JNZ loop; HLT
+        let code = [0x11, 0x34, 0x12, 0x1b, 0x7a, 0xb3, 0xc2, 0x03, 0x00, 0x76];
+        let mut delays = DelayLoops::new();
+        assert_eq!(compare(&code, &mut delays), 11);
+        // MOV A, E; ORA D works too
+        let code = [0x11, 0x34, 0x12, 0x1b, 0x7b, 0xb2, 0xc2, 0x03, 0x00, 0x76];
+        assert_eq!(compare(&code, &mut DelayLoops::new()), 11);
+    }
+
+    #[test]
+    fn skips_are_limited_and_checked() {
+        // MVI B, 0; loop: DCR B; JNZ loop; HLT
+        let code = [0x06, 0x00, 0x05, 0xc2, 0x02, 0x00, 0x76];
+        let mut delays = DelayLoops::new();
+        delays.set_max_skip(100);
+        compare(&code, &mut delays);
+        assert_eq!(delays.skipped(), 254);
+
+        let mut cpu = cpu_with(&code);
+        let mut delays = DelayLoops::new();
+        for _ in 0..3 {
+            delays.step(&mut cpu);
+        }
+        assert!(matches!(delays.step(&mut cpu), Event::Normal(c) if c == 254 * 15));
+        // A loop that has been overwritten is no longer skipped
+        cpu.memory.borrow_mut().write(0x02, 0x00);
+        cpu.pc = 0x02;
+        cpu.regs.b = 0x10;
+        assert!(matches!(delays.step(&mut cpu), Event::Normal(4)));
+        assert_eq!(cpu.regs.b, 0x10);
+    }
+}
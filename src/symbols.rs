@@ -0,0 +1,188 @@
+//! Names for addresses, so listings and traces can say `CALL PrintChar`
+//! instead of `CALL $(0x1a2)`.
+//!
+//! A `SymbolTable` is filled by hand or parsed from one of two formats:
+//!
+//! - `.SYM` files as CP/M assemblers and linkers write them: hex address
+//!   and name pairs separated by white space, any number to a line;
+//! - tab-separated `name<TAB>address` lines, with the address in hex and
+//!   `#` starting a comment line.
+//!
+//! Hand the table to a `Disassembler` with `set_symbols`.
+//!
+//! ```
+//! use i8080_emulator::symbols::SymbolTable;
+//!
+//! let symbols = SymbolTable::parse_sym("0100 START\t0123 LOOP\n").unwrap();
+//! assert_eq!(symbols.address("LOOP"), Some(0x0123));
+//! assert_eq!(symbols.describe(0x0125).as_deref(), Some("LOOP+0x2"));
+//! ```
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use core::fmt;
+
+/// Line `line` (counting from 1) of a symbol file could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolError {
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl core::error::Error for SymbolError {}
+
+/// Names and the addresses they stand for. An address has at most one
+/// name; giving it another replaces the first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    names: BTreeMap<u16, String>,
+    addresses: BTreeMap<String, u16>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a `.SYM` file: `AAAA NAME` pairs, any number per line.
+    pub fn parse_sym(text: &str) -> Result<Self, SymbolError> {
+        let mut symbols = SymbolTable::new();
+        for (i, line) in text.lines().enumerate() {
+            let bad = |reason| SymbolError { line: i + 1, reason };
+            let mut words = line.split_whitespace();
+            while let Some(addr) = words.next() {
+                let addr = parse_address(addr).ok_or(bad("bad address"))?;
+                let name = words.next().ok_or(bad("address without a name"))?;
+                symbols.insert(name, addr);
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Parses `name<TAB>address` lines. Blank lines and lines starting
+    /// with `#` are skipped.
+    pub fn parse_tsv(text: &str) -> Result<Self, SymbolError> {
+        let mut symbols = SymbolTable::new();
+        for (i, line) in text.lines().enumerate() {
+            let bad = |reason| SymbolError { line: i + 1, reason };
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, addr) = line.split_once('\t').ok_or(bad("expected a name and an address"))?;
+            let addr = parse_address(addr.trim()).ok_or(bad("bad address"))?;
+            symbols.insert(name.trim(), addr);
+        }
+        Ok(symbols)
+    }
+
+    /// Reads the symbol file at `path`, as a `.SYM` file if its extension
+    /// says so and as tab-separated values otherwise.
+    #[cfg(feature = "std")]
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> Result<Self, crate::loader::LoadError> {
+        use crate::loader::LoadError;
+
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| LoadError::Io(path.to_path_buf(), e))?;
+        let is_sym = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("sym"));
+        let symbols = if is_sym { Self::parse_sym(&text) } else { Self::parse_tsv(&text) };
+        symbols.map_err(|e| LoadError::BadSymbol { line: e.line, reason: e.reason })
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, addr: u16) {
+        let name = name.into();
+        if let Some(old) = self.names.insert(addr, name.clone()) {
+            self.addresses.remove(&old);
+        }
+        if let Some(old) = self.addresses.insert(name, addr) {
+            if old != addr {
+                self.names.remove(&old);
+            }
+        }
+    }
+
+    /// The name of `addr` itself.
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    /// The closest symbol at or below `addr`, with how far past it `addr`
+    /// is: the routine a PC is in, if symbols mark where routines start.
+    pub fn enclosing(&self, addr: u16) -> Option<(&str, u16)> {
+        self.names.range(..=addr).next_back().map(|(&start, name)| (name.as_str(), addr - start))
+    }
+
+    /// `addr` as `NAME` or `NAME+0xN`, going by `enclosing`.
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        self.enclosing(addr).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            offset => format!("{}+0x{:x}", name, offset),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Every symbol, in address order.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.names.iter().map(|(&addr, name)| (addr, name.as_str()))
+    }
+}
+
+// Hex, optionally with a 0x prefix or an h suffix
+fn parse_address(word: &str) -> Option<u16> {
+    let digits = word.strip_prefix("0x").or_else(|| word.strip_suffix(['h', 'H'])).unwrap_or(word);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::symbols::{SymbolError, SymbolTable};
+
+    #[test]
+    fn parse_formats() {
+        let sym = SymbolTable::parse_sym("0000 BOOT\t0005 BDOS\n\n0100 START 01A2 PRINT\n").unwrap();
+        assert_eq!(sym.iter().collect::<Vec<_>>(), [(0x0000, "BOOT"), (0x0005, "BDOS"), (0x0100, "START"), (0x01a2, "PRINT")]);
+        assert_eq!(SymbolTable::parse_sym("0100 START\n0200\n"), Err(SymbolError { line: 2, reason: "address without a name" }));
+        assert_eq!(SymbolTable::parse_sym("START 0100\n").unwrap_err().reason, "bad address");
+
+        let tsv = SymbolTable::parse_tsv("# name\taddress\nPrintChar\t0x01a2\nscore\t2000h\n").unwrap();
+        assert_eq!(tsv.address("PrintChar"), Some(0x01a2));
+        assert_eq!(tsv.name(0x2000), Some("score"));
+        assert_eq!(SymbolTable::parse_tsv("loop 0100\n").unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn lookups() {
+        let mut symbols = SymbolTable::new();
+        assert!(symbols.is_empty());
+        symbols.insert("START", 0x0100);
+        symbols.insert("LOOP", 0x0110);
+        assert_eq!(symbols.enclosing(0x00ff), None);
+        assert_eq!(symbols.enclosing(0x010f), Some(("START", 0x0f)));
+        assert_eq!(symbols.describe(0x0110).as_deref(), Some("LOOP"));
+
+        // A new name for an address, or a new address for a name,
+        // replaces the old one
+        symbols.insert("MAIN", 0x0100);
+        symbols.insert("LOOP", 0x0120);
+        assert_eq!(symbols.iter().collect::<Vec<_>>(), [(0x0100, "MAIN"), (0x0120, "LOOP")]);
+        assert_eq!(symbols.address("START"), None);
+        assert_eq!(symbols.len(), 2);
+    }
+}
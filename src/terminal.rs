@@ -0,0 +1,220 @@
+//! The host terminal as a guest's console, for interactive CP/M and
+//! Altair sessions.
+//!
+//! A terminal normally hands over input a line at a time, echoes it and
+//! turns Ctrl-C into a signal that kills the emulator. `RawMode` switches
+//! all of that off while it is held, so keystrokes reach the guest one at
+//! a time and unechoed, as they would from a serial terminal. `Keyboard`
+//! reads them and passes them on to the console device as its input,
+//! deciding what Ctrl-C does:
+//!
+//! ```no_run
+//! use i8080_emulator::machines::cpm::CpmMachine;
+//! use i8080_emulator::terminal::{CtrlC, Keyboard, RawMode};
+//! use i8080_emulator::Machine;
+//! use std::sync::atomic::Ordering;
+//!
+//! let mut machine = CpmMachine::from_file("MBASIC.COM", std::io::stdout()).unwrap();
+//! let _raw = RawMode::enter().unwrap();
+//! let keyboard = Keyboard::stdin(CtrlC::PassThrough);
+//! let exit = keyboard.exit_requested();
+//! machine.set_input(keyboard);
+//! while !machine.is_finished() && !exit.load(Ordering::Relaxed) {
+//!     machine.next().unwrap();
+//! }
+//! ```
+//!
+//! Raw mode is set with `stty`, so it is only available on Unix.
+
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+
+/// Ctrl-C as a byte.
+pub const CTRL_C: u8 = 0x03;
+/// Ctrl-], the key that leaves the emulator when Ctrl-C goes to the
+/// guest, as in telnet.
+pub const EXIT_KEY: u8 = 0x1d;
+
+/// What `Keyboard` does with Ctrl-C.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CtrlC {
+    /// Ctrl-C goes to the guest, where CP/M takes it to mean a warm boot
+    /// or a break; `EXIT_KEY` leaves the emulator.
+    PassThrough,
+    /// Ctrl-C leaves the emulator, as it would without raw mode.
+    Exit,
+}
+
+/// The host terminal in raw mode: no line editing, no echo and no signals
+/// from the keyboard. The previous settings come back when it is dropped,
+/// including on a panic that unwinds.
+pub struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    /// Puts the terminal on stdin in raw mode. Fails if stdin is not a
+    /// terminal.
+    #[cfg(unix)]
+    pub fn enter() -> io::Result<Self> {
+        if !io::stdin().is_terminal() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "stdin is not a terminal"));
+        }
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        Ok(RawMode { saved: saved.trim().to_string() })
+    }
+
+    /// Raw mode is set with `stty`, which only Unix has.
+    #[cfg(not(unix))]
+    pub fn enter() -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "raw mode needs stty"))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+// Runs stty on the terminal stdin is, returning what it printed
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = std::process::Command::new("stty")
+        .args(args)
+        .stdin(std::process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Keystrokes for a console device, read from the host on a thread of
+/// their own. As a `Read` it blocks until a key is pressed, like a guest
+/// waiting at a prompt, and ends once the host input does or the user
+/// asks to leave with `EXIT_KEY`, or with Ctrl-C under `CtrlC::Exit`.
+pub struct Keyboard {
+    keys: Receiver<Vec<u8>>,
+    buffered: VecDeque<u8>,
+    ctrl_c: CtrlC,
+    exit: Arc<AtomicBool>,
+}
+
+impl Keyboard {
+    /// A keyboard reading `input`.
+    pub fn new(mut input: impl Read + Send + 'static, ctrl_c: CtrlC) -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0; 64];
+            loop {
+                match input.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if tx.send(buffer[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+        });
+        Keyboard { keys: rx, buffered: VecDeque::new(), ctrl_c, exit: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// A keyboard on the host's stdin, which should be in raw mode.
+    pub fn stdin(ctrl_c: CtrlC) -> Self {
+        Keyboard::new(io::stdin(), ctrl_c)
+    }
+
+    /// Set once the user has asked to leave, for the run loop to check.
+    /// The guest sees the input end at the same moment.
+    pub fn exit_requested(&self) -> Arc<AtomicBool> {
+        self.exit.clone()
+    }
+
+    fn is_exit_key(&self, key: u8) -> bool {
+        key == EXIT_KEY || (key == CTRL_C && self.ctrl_c == CtrlC::Exit)
+    }
+}
+
+impl Read for Keyboard {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.exit.load(Ordering::Relaxed) {
+            return Ok(0);
+        }
+        if self.buffered.is_empty() {
+            match self.keys.recv() {
+                Ok(keys) => self.buffered.extend(keys),
+                Err(_) => return Ok(0),
+            }
+        }
+        let mut n = 0;
+        while n < buf.len() {
+            let key = match self.buffered.pop_front() {
+                Some(key) => key,
+                None => break,
+            };
+            if self.is_exit_key(key) {
+                self.exit.store(true, Ordering::Relaxed);
+                self.buffered.clear();
+                break;
+            }
+            buf[n] = key;
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::machines::cpm::CpmMachine;
+    use crate::terminal::{CtrlC, Keyboard, RawMode, CTRL_C, EXIT_KEY};
+    use crate::Machine;
+
+    use std::io::Read;
+    use std::sync::atomic::Ordering;
+
+    fn typed(keys: &'static [u8], ctrl_c: CtrlC) -> (Vec<u8>, bool) {
+        let mut keyboard = Keyboard::new(keys, ctrl_c);
+        let exit = keyboard.exit_requested();
+        let mut read = Vec::new();
+        keyboard.read_to_end(&mut read).unwrap();
+        (read, exit.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn ctrl_c() {
+        assert_eq!(typed(b"DIR\r", CtrlC::PassThrough), (b"DIR\r".to_vec(), false));
+        assert_eq!(typed(&[b'A', CTRL_C, b'B', EXIT_KEY, b'C'], CtrlC::PassThrough), (vec![b'A', CTRL_C, b'B'], true));
+        assert_eq!(typed(&[b'A', CTRL_C, b'B'], CtrlC::Exit), (vec![b'A'], true));
+    }
+
+    #[test]
+    fn keys_to_a_cp_m_program() {
+        // C_READ; MOV E, A; MVI C, 2; CALL BDOS; JMP 0x0100
+        let program = [0x0e, 0x01, 0xcd, 0x05, 0x00, 0x5f, 0x0e, 0x02, 0xcd, 0x05, 0x00, 0xc3, 0x00, 0x01];
+        let mut machine = CpmMachine::new(&program, Vec::new()).unwrap();
+        let keyboard = Keyboard::new(&[b'h', b'i', CTRL_C, EXIT_KEY][..], CtrlC::PassThrough);
+        let exit = keyboard.exit_requested();
+        machine.set_input(keyboard);
+        while !exit.load(Ordering::Relaxed) {
+            machine.next().unwrap();
+        }
+        assert!(machine.output().starts_with(&[b'h', b'h', b'i', b'i', CTRL_C, CTRL_C]));
+    }
+
+    #[test]
+    fn raw_mode_needs_a_terminal() {
+        // Under cargo test stdin is not a terminal, unless run by hand
+        if !std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+            assert!(RawMode::enter().is_err());
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! A dispatch-table interpreter backend, enabled with the `threaded`
+//! feature.
+//!
+//! Every opcode gets its own handler function, a copy of `CPU::exec`
+//! specialized for that opcode, and instructions are dispatched by
+//! indexing a table of them. This is as close to computed-goto threading as
+//! stable Rust gets. Compare it with the match-based interpreter with
+//! `cargo bench --features threaded --bench alu`.
+
+use crate::cpu::{CoreCapabilities, CpuState, Event, CPU};
+use crate::I8080Core;
+
+type Handler = fn(&mut CPU) -> Event;
+
+fn handler<const OP: u8>(cpu: &mut CPU) -> Event {
+    cpu.exec_inline(OP)
+}
+
+static HANDLERS: [Handler; 256] = [
+    handler::<0x00>, handler::<0x01>, handler::<0x02>, handler::<0x03>, handler::<0x04>, handler::<0x05>, handler::<0x06>, handler::<0x07>,
+    handler::<0x08>, handler::<0x09>, handler::<0x0a>, handler::<0x0b>, handler::<0x0c>, handler::<0x0d>, handler::<0x0e>, handler::<0x0f>,
+    handler::<0x10>, handler::<0x11>, handler::<0x12>, handler::<0x13>, handler::<0x14>, handler::<0x15>, handler::<0x16>, handler::<0x17>,
+    handler::<0x18>, handler::<0x19>, handler::<0x1a>, handler::<0x1b>, handler::<0x1c>, handler::<0x1d>, handler::<0x1e>, handler::<0x1f>,
+    handler::<0x20>, handler::<0x21>, handler::<0x22>, handler::<0x23>, handler::<0x24>, handler::<0x25>, handler::<0x26>, handler::<0x27>,
+    handler::<0x28>, handler::<0x29>, handler::<0x2a>, handler::<0x2b>, handler::<0x2c>, handler::<0x2d>, handler::<0x2e>, handler::<0x2f>,
+    handler::<0x30>, handler::<0x31>, handler::<0x32>, handler::<0x33>, handler::<0x34>, handler::<0x35>, handler::<0x36>, handler::<0x37>,
+    handler::<0x38>, handler::<0x39>, handler::<0x3a>, handler::<0x3b>, handler::<0x3c>, handler::<0x3d>, handler::<0x3e>, handler::<0x3f>,
+    handler::<0x40>, handler::<0x41>, handler::<0x42>, handler::<0x43>, handler::<0x44>, handler::<0x45>, handler::<0x46>, handler::<0x47>,
+    handler::<0x48>, handler::<0x49>, handler::<0x4a>, handler::<0x4b>, handler::<0x4c>, handler::<0x4d>, handler::<0x4e>, handler::<0x4f>,
+    handler::<0x50>, handler::<0x51>, handler::<0x52>, handler::<0x53>, handler::<0x54>, handler::<0x55>, handler::<0x56>, handler::<0x57>,
+    handler::<0x58>, handler::<0x59>, handler::<0x5a>, handler::<0x5b>, handler::<0x5c>, handler::<0x5d>, handler::<0x5e>, handler::<0x5f>,
+    handler::<0x60>, handler::<0x61>, handler::<0x62>, handler::<0x63>, handler::<0x64>, handler::<0x65>, handler::<0x66>, handler::<0x67>,
+    handler::<0x68>, handler::<0x69>, handler::<0x6a>, handler::<0x6b>, handler::<0x6c>, handler::<0x6d>, handler::<0x6e>, handler::<0x6f>,
+    handler::<0x70>, handler::<0x71>, handler::<0x72>, handler::<0x73>, handler::<0x74>, handler::<0x75>, handler::<0x76>, handler::<0x77>,
+    handler::<0x78>, handler::<0x79>, handler::<0x7a>, handler::<0x7b>, handler::<0x7c>, handler::<0x7d>, handler::<0x7e>, handler::<0x7f>,
+    handler::<0x80>, handler::<0x81>, handler::<0x82>, handler::<0x83>, handler::<0x84>, handler::<0x85>, handler::<0x86>, handler::<0x87>,
+    handler::<0x88>, handler::<0x89>, handler::<0x8a>, handler::<0x8b>, handler::<0x8c>, handler::<0x8d>, handler::<0x8e>, handler::<0x8f>,
+    handler::<0x90>, handler::<0x91>, handler::<0x92>, handler::<0x93>, handler::<0x94>, handler::<0x95>, handler::<0x96>, handler::<0x97>,
+    handler::<0x98>, handler::<0x99>, handler::<0x9a>, handler::<0x9b>, handler::<0x9c>, handler::<0x9d>, handler::<0x9e>, handler::<0x9f>,
+    handler::<0xa0>, handler::<0xa1>, handler::<0xa2>, handler::<0xa3>, handler::<0xa4>, handler::<0xa5>, handler::<0xa6>, handler::<0xa7>,
+    handler::<0xa8>, handler::<0xa9>, handler::<0xaa>, handler::<0xab>, handler::<0xac>, handler::<0xad>, handler::<0xae>, handler::<0xaf>,
+    handler::<0xb0>, handler::<0xb1>, handler::<0xb2>, handler::<0xb3>, handler::<0xb4>, handler::<0xb5>, handler::<0xb6>, handler::<0xb7>,
+    handler::<0xb8>, handler::<0xb9>, handler::<0xba>, handler::<0xbb>, handler::<0xbc>, handler::<0xbd>, handler::<0xbe>, handler::<0xbf>,
+    handler::<0xc0>, handler::<0xc1>, handler::<0xc2>, handler::<0xc3>, handler::<0xc4>, handler::<0xc5>, handler::<0xc6>, handler::<0xc7>,
+    handler::<0xc8>, handler::<0xc9>, handler::<0xca>, handler::<0xcb>, handler::<0xcc>, handler::<0xcd>, handler::<0xce>, handler::<0xcf>,
+    handler::<0xd0>, handler::<0xd1>, handler::<0xd2>, handler::<0xd3>, handler::<0xd4>, handler::<0xd5>, handler::<0xd6>, handler::<0xd7>,
+    handler::<0xd8>, handler::<0xd9>, handler::<0xda>, handler::<0xdb>, handler::<0xdc>, handler::<0xdd>, handler::<0xde>, handler::<0xdf>,
+    handler::<0xe0>, handler::<0xe1>, handler::<0xe2>, handler::<0xe3>, handler::<0xe4>, handler::<0xe5>, handler::<0xe6>, handler::<0xe7>,
+    handler::<0xe8>, handler::<0xe9>, handler::<0xea>, handler::<0xeb>, handler::<0xec>, handler::<0xed>, handler::<0xee>, handler::<0xef>,
+    handler::<0xf0>, handler::<0xf1>, handler::<0xf2>, handler::<0xf3>, handler::<0xf4>, handler::<0xf5>, handler::<0xf6>, handler::<0xf7>,
+    handler::<0xf8>, handler::<0xf9>, handler::<0xfa>, handler::<0xfb>, handler::<0xfc>, handler::<0xfd>, handler::<0xfe>, handler::<0xff>,
+];
+
+/// Wraps a `CPU` and executes it through the handler table. State,
+/// interrupts and memory are the wrapped CPU's.
+pub struct ThreadedCore {
+    pub cpu: CPU,
+}
+
+impl ThreadedCore {
+    pub fn new(cpu: CPU) -> Self {
+        ThreadedCore { cpu }
+    }
+}
+
+impl I8080Core for ThreadedCore {
+    fn step(&mut self) -> Event {
+        let op = self.cpu.fetch();
+        HANDLERS[op as usize](&mut self.cpu)
+    }
+
+    fn state(&self) -> CpuState {
+        self.cpu.state()
+    }
+
+    fn set_state(&mut self, state: &CpuState) {
+        self.cpu.set_state(state)
+    }
+
+    fn interrupt(&mut self, op: u8) -> Option<Event> {
+        self.cpu.interrupt(op)
+    }
+
+    fn interrupt_at(&mut self, addr: u16) -> Option<Event> {
+        self.cpu.inter_handle(addr)
+    }
+
+    fn capabilities(&self) -> CoreCapabilities {
+        self.cpu.capabilities()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{CpuState, CPU};
+    use crate::memory::Memory8080;
+    use crate::threaded::ThreadedCore;
+    use crate::I8080Core;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn matches_interpreter() {
+        // LXI SP, 0x2400; MVI A, 0x35; ADI 0x47; DAA; PUSH PSW; CALL 0x0010;
+        // ... 0x0010: INR A; RET
+        let mut memory = [0x00; 0x10000];
+        let code = [0x31, 0x00, 0x24, 0x3e, 0x35, 0xc6, 0x47, 0x27, 0xf5, 0xcd, 0x10, 0x00];
+        memory[..code.len()].copy_from_slice(&code);
+        memory[0x10] = 0x3c;
+        memory[0x11] = 0xc9;
+
+        let mut interpreter = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        let mut threaded = ThreadedCore::new(CPU::new(Rc::new(RefCell::new(Memory8080::new(memory)))));
+        for _ in 0..8 {
+            let (_, a) = interpreter.step();
+            let b = threaded.step().cycles();
+            assert_eq!(a, b);
+            assert_eq!(interpreter.state(), threaded.state());
+        }
+        assert_ne!(threaded.state(), CpuState::default());
+    }
+}
@@ -0,0 +1,244 @@
+//! Recording what a machine did over time and exporting it in the Chrome
+//! trace event format, which Perfetto (ui.perfetto.dev) and
+//! `chrome://tracing` open directly.
+//!
+//! The timeline does not watch the CPU by itself; the machine loop records
+//! events as it handles them, stamped with its running cycle count.
+//!
+//! Markers and named spans divide a run into the phases of the program,
+//! such as "level load" or "frame 12", so that timelines of two runs can
+//! be lined up. The host places them with `mark`, `begin_span` and
+//! `end_span`, and guest code through a `ControlPort`, whose requests go
+//! in with `record_control`.
+
+use crate::cpu::{Event, Fault, Timestamp};
+use crate::devices::control::{ControlPort, ControlRequest};
+
+use std::io::{self, Write};
+
+/// Something worth seeing on the timeline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimelineEvent {
+    /// An interrupt was accepted, vectoring to the given address.
+    Interrupt(u16),
+    PortRead(u8),
+    PortWrite(u8, u8),
+    /// The start of a new video frame, numbered from 0.
+    Frame(u64),
+    Breakpoint(u16),
+    Halt,
+    Fault(Fault),
+    /// A named marker placed by the host or the guest.
+    Marker(String),
+    /// The start of a named span, which lasts until the matching
+    /// `SpanEnd`. Spans nest.
+    SpanBegin(String),
+    SpanEnd(String),
+}
+
+impl TimelineEvent {
+    fn name(&self) -> String {
+        match self {
+            TimelineEvent::Interrupt(addr) => format!("interrupt 0x{:04x}", addr),
+            TimelineEvent::PortRead(port) => format!("IN 0x{:02x}", port),
+            TimelineEvent::PortWrite(port, value) => format!("OUT 0x{:02x} <- 0x{:02x}", port, value),
+            TimelineEvent::Frame(n) => format!("frame {}", n),
+            TimelineEvent::Breakpoint(addr) => format!("breakpoint 0x{:04x}", addr),
+            TimelineEvent::Halt => "HLT".to_string(),
+            TimelineEvent::Fault(fault) => fault.to_string(),
+            TimelineEvent::Marker(name) | TimelineEvent::SpanBegin(name) | TimelineEvent::SpanEnd(name) => name.clone(),
+        }
+    }
+
+    // Perfetto shows each category as its own track.
+    fn category(&self) -> &'static str {
+        match self {
+            TimelineEvent::Interrupt(_) => "interrupt",
+            TimelineEvent::PortRead(_) | TimelineEvent::PortWrite(..) => "io",
+            TimelineEvent::Frame(_) => "frame",
+            TimelineEvent::Breakpoint(_) | TimelineEvent::Halt | TimelineEvent::Fault(_) => "debug",
+            TimelineEvent::Marker(_) => "marker",
+            TimelineEvent::SpanBegin(_) | TimelineEvent::SpanEnd(_) => "span",
+        }
+    }
+
+    // The Chrome trace phase: spans begin and end, the rest are instants
+    fn phase(&self) -> &'static str {
+        match self {
+            TimelineEvent::SpanBegin(_) => "B",
+            TimelineEvent::SpanEnd(_) => "E",
+            _ => "i",
+        }
+    }
+}
+
+/// Events in the order they were recorded, each stamped with the cycle
+/// count at which it happened.
+pub struct Timeline {
+    clock_hz: u64,
+    events: Vec<(Timestamp, TimelineEvent)>,
+    // Spans begun and not yet ended, innermost last
+    open_spans: Vec<String>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Timeline {
+            clock_hz: 2_000_000,
+            events: Vec::new(),
+            open_spans: Vec::new(),
+        }
+    }
+
+    /// Sets the CPU clock used to turn cycles into time on export.
+    /// Defaults to 2 MHz.
+    pub fn with_clock_rate(mut self, hz: u64) -> Self {
+        self.clock_hz = hz;
+        self
+    }
+
+    pub fn record(&mut self, cycle: Timestamp, event: TimelineEvent) {
+        self.events.push((cycle, event));
+    }
+
+    /// Records the port access, halt or fault behind a CPU event, if any.
+    pub fn record_cpu_event(&mut self, cycle: Timestamp, event: &Event) {
+        match *event {
+            Event::Output(port, value, _) => self.record(cycle, TimelineEvent::PortWrite(port, value)),
+            Event::Input(port, _) => self.record(cycle, TimelineEvent::PortRead(port)),
+            Event::Halt(_) => self.record(cycle, TimelineEvent::Halt),
+            Event::Fault(fault) => self.record(cycle, TimelineEvent::Fault(fault)),
+            Event::Normal(_) => {}
+        }
+    }
+
+    /// Places a marker named `name`.
+    pub fn mark(&mut self, cycle: Timestamp, name: &str) {
+        self.record(cycle, TimelineEvent::Marker(name.to_string()));
+    }
+
+    /// Starts a span named `name`, inside any span still open.
+    pub fn begin_span(&mut self, cycle: Timestamp, name: &str) {
+        self.open_spans.push(name.to_string());
+        self.record(cycle, TimelineEvent::SpanBegin(name.to_string()));
+    }
+
+    /// Ends the innermost open span and returns its name, or does nothing
+    /// and returns `None` if no span is open.
+    pub fn end_span(&mut self, cycle: Timestamp) -> Option<String> {
+        let name = self.open_spans.pop()?;
+        self.record(cycle, TimelineEvent::SpanEnd(name.clone()));
+        Some(name)
+    }
+
+    /// Records a marker or span the guest asked for through `control`,
+    /// named with `ControlPort::label`. Other requests are left alone.
+    pub fn record_control(&mut self, cycle: Timestamp, request: &ControlRequest, control: &ControlPort) {
+        match *request {
+            ControlRequest::Marker(id) => self.mark(cycle, &control.label(id)),
+            ControlRequest::SpanBegin(id) => self.begin_span(cycle, &control.label(id)),
+            ControlRequest::SpanEnd => {
+                self.end_span(cycle);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn events(&self) -> &[(Timestamp, TimelineEvent)] {
+        &self.events
+    }
+
+    /// Writes the timeline as a Chrome trace JSON document: spans as
+    /// duration events and everything else as instant events.
+    pub fn write_chrome_trace(&self, w: &mut impl Write) -> io::Result<()> {
+        write!(w, "{{\"traceEvents\":[")?;
+        for (i, (cycle, event)) in self.events.iter().enumerate() {
+            if i > 0 {
+                write!(w, ",")?;
+            }
+            // Timestamps are in microseconds
+            let ts = *cycle as f64 * 1_000_000.0 / self.clock_hz as f64;
+            write!(w, "\n{{\"name\":")?;
+            write_json_string(w, &event.name())?;
+            write!(w, ",\"cat\":\"{}\",\"ph\":\"{}\"", event.category(), event.phase())?;
+            if event.phase() == "i" {
+                write!(w, ",\"s\":\"g\"")?;
+            }
+            write!(w, ",\"ts\":{},\"pid\":1,\"tid\":1,\"args\":{{\"cycle\":{}}}}}", ts, cycle)?;
+        }
+        writeln!(w, "\n]}}")
+    }
+}
+
+fn write_json_string(w: &mut impl Write, s: &str) -> io::Result<()> {
+    write!(w, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(w, "\\\"")?,
+            '\\' => write!(w, "\\\\")?,
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+            c => write!(w, "{}", c)?,
+        }
+    }
+    write!(w, "\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Event;
+    use crate::timeline::{Timeline, TimelineEvent};
+
+    #[test]
+    fn records_cpu_events() {
+        let mut timeline = Timeline::new();
+        timeline.record_cpu_event(10, &Event::Normal(4));
+        timeline.record_cpu_event(20, &Event::Output(0x03, 0xff, 10));
+        timeline.record_cpu_event(30, &Event::Halt(7));
+        assert_eq!(timeline.events(), &[
+            (20, TimelineEvent::PortWrite(0x03, 0xff)),
+            (30, TimelineEvent::Halt),
+        ]);
+    }
+
+    #[test]
+    fn chrome_trace() {
+        let mut timeline = Timeline::new();
+        timeline.record(0, TimelineEvent::Frame(0));
+        timeline.record(33_334, TimelineEvent::Interrupt(0x10));
+        timeline.record(33_334, TimelineEvent::Marker("level \"1\"".to_string()));
+        let mut out = Vec::new();
+        timeline.write_chrome_trace(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out, concat!(
+            "{\"traceEvents\":[\n",
+            "{\"name\":\"frame 0\",\"cat\":\"frame\",\"ph\":\"i\",\"s\":\"g\",\"ts\":0,\"pid\":1,\"tid\":1,\"args\":{\"cycle\":0}},\n",
+            "{\"name\":\"interrupt 0x0010\",\"cat\":\"interrupt\",\"ph\":\"i\",\"s\":\"g\",\"ts\":16667,\"pid\":1,\"tid\":1,\"args\":{\"cycle\":33334}},\n",
+            "{\"name\":\"level \\\"1\\\"\",\"cat\":\"marker\",\"ph\":\"i\",\"s\":\"g\",\"ts\":16667,\"pid\":1,\"tid\":1,\"args\":{\"cycle\":33334}}\n",
+            "]}\n",
+        ));
+    }
+
+    #[test]
+    fn spans() {
+        let mut timeline = Timeline::new().with_clock_rate(1_000_000);
+        assert_eq!(timeline.end_span(0), None);
+        timeline.begin_span(10, "level load");
+        timeline.begin_span(12, "decompress");
+        timeline.mark(15, "checkpoint");
+        assert_eq!(timeline.end_span(20), Some("decompress".to_string()));
+        assert_eq!(timeline.end_span(30), Some("level load".to_string()));
+        let mut out = Vec::new();
+        timeline.write_chrome_trace(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[1], "{\"name\":\"level load\",\"cat\":\"span\",\"ph\":\"B\",\"ts\":10,\"pid\":1,\"tid\":1,\"args\":{\"cycle\":10}},");
+        assert_eq!(lines[3], "{\"name\":\"checkpoint\",\"cat\":\"marker\",\"ph\":\"i\",\"s\":\"g\",\"ts\":15,\"pid\":1,\"tid\":1,\"args\":{\"cycle\":15}},");
+        assert_eq!(lines[4], "{\"name\":\"decompress\",\"cat\":\"span\",\"ph\":\"E\",\"ts\":20,\"pid\":1,\"tid\":1,\"args\":{\"cycle\":20}},");
+    }
+}
@@ -0,0 +1,447 @@
+//! A record of the last instructions executed, for finding out how a
+//! program got where it went wrong.
+//!
+//! A `TraceBuffer` keeps the most recent instructions, each with its
+//! bytes, the registers before it ran and the cycle count, and dumps them
+//! as text or CSV. Unlike the CPU's trace hook, nothing is printed or
+//! formatted while the program runs.
+//!
+//! For runs too long to keep in memory, `TraceWriter` streams every
+//! instruction to a compact binary file, a fixed-size record each, and
+//! `TraceReader` reads one back. `first_divergence` finds where two such
+//! traces part ways, to compare a run against a known good one.
+//!
+//! Tools that look at each instruction before it runs, like this one and
+//! `profiler::Profiler`, implement `Instrument` so a run loop can drive
+//! any of them.
+//!
+//! ```
+//! use i8080_emulator::cpu::CPU;
+//! use i8080_emulator::memory::Memory8080;
+//! use i8080_emulator::trace::TraceBuffer;
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! // MVI A, 0x42; INR A; HLT
+//! let mut memory = [0x00; 0x10000];
+//! memory[..4].copy_from_slice(&[0x3e, 0x42, 0x3c, 0x76]);
+//! let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+//! let mut trace = TraceBuffer::new(2);
+//! while !cpu.is_halted() {
+//!     trace.record(&cpu);
+//!     cpu.step();
+//! }
+//! let mut text = Vec::new();
+//! trace.write_text(&mut text).unwrap();
+//! assert!(String::from_utf8(text).unwrap().starts_with("0002  3c        INR A"));
+//! ```
+
+use crate::cpu::{CpuState, Timestamp, CPU};
+use crate::memory::{Memory, Memory8080};
+use crate::opcodes::OPCODES;
+use crate::I8080Core;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// The bytes a binary trace starts with, the last being the format
+/// version.
+pub const MAGIC: [u8; 4] = *b"I80\x01";
+
+/// Bytes per instruction in a binary trace, after `MAGIC`: the cycle
+/// count as a little endian u64, PC, the three instruction bytes, A, F,
+/// B, C, D, E, H and L, SP, then 1 for INTE plus 2 for HALTED. Bytes past
+/// the instruction's length are 0x00.
+pub const RECORD_SIZE: usize = 24;
+
+/// Something that looks at every instruction before it runs.
+pub trait Instrument<M: Memory = Memory8080> {
+    /// Called between instructions with the CPU about to run the next.
+    fn record(&mut self, cpu: &CPU<M>);
+}
+
+/// One instruction in a `TraceBuffer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub pc: u16,
+    /// The instruction's bytes; only the first `len` count.
+    pub bytes: [u8; 3],
+    pub len: u8,
+    /// Registers and flags before the instruction ran.
+    pub state: CpuState,
+    /// `CPU::cycles` before the instruction ran.
+    pub cycles: Timestamp,
+}
+
+impl Entry {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..usize::from(self.len)]
+    }
+
+    // The instruction `cpu` is about to run. Memory is peeked at, so no
+    // device or debugging check is set off.
+    fn capture<M: Memory>(cpu: &CPU<M>) -> Self {
+        let memory = cpu.memory.borrow();
+        let op = memory.peek(usize::from(cpu.pc));
+        let len = OPCODES[usize::from(op)].length;
+        let mut bytes = [op, 0x00, 0x00];
+        for (i, byte) in bytes.iter_mut().enumerate().take(usize::from(len)).skip(1) {
+            *byte = memory.peek(usize::from(cpu.pc.wrapping_add(i as u16)));
+        }
+        Entry { pc: cpu.pc, bytes, len, state: cpu.state(), cycles: cpu.cycles() }
+    }
+
+    /// The entry as a binary trace record.
+    pub fn to_record(&self) -> [u8; RECORD_SIZE] {
+        let s = &self.state;
+        let mut record = [0x00; RECORD_SIZE];
+        record[..8].copy_from_slice(&self.cycles.to_le_bytes());
+        record[8..10].copy_from_slice(&self.pc.to_le_bytes());
+        record[10..13].copy_from_slice(&self.bytes);
+        record[13..21].copy_from_slice(&[s.a, s.f, s.b, s.c, s.d, s.e, s.h, s.l]);
+        record[21..23].copy_from_slice(&s.sp.to_le_bytes());
+        record[23] = u8::from(s.inte) | u8::from(s.halted) << 1;
+        record
+    }
+
+    /// Reads a binary trace record back. The length comes from the
+    /// opcode.
+    pub fn from_record(record: &[u8; RECORD_SIZE]) -> Self {
+        let word = |i: usize| u16::from_le_bytes([record[i], record[i + 1]]);
+        let mut cycles = [0x00; 8];
+        cycles.copy_from_slice(&record[..8]);
+        let pc = word(8);
+        let r = &record[13..21];
+        let state = CpuState {
+            a: r[0], f: r[1], b: r[2], c: r[3], d: r[4], e: r[5], h: r[6], l: r[7],
+            pc,
+            sp: word(21),
+            inte: record[23] & 0x01 != 0,
+            halted: record[23] & 0x02 != 0,
+        };
+        Entry {
+            pc,
+            bytes: [record[10], record[11], record[12]],
+            len: OPCODES[usize::from(record[10])].length,
+            state,
+            cycles: Timestamp::from_le_bytes(cycles),
+        }
+    }
+}
+
+/// The last instructions recorded, up to a fixed number.
+pub struct TraceBuffer {
+    entries: VecDeque<Entry>,
+    capacity: usize,
+}
+
+impl TraceBuffer {
+    pub fn new(capacity: usize) -> Self {
+        TraceBuffer {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Records the instruction `cpu` is about to run, dropping the oldest
+    /// one once the buffer is full. Call it between instructions. Memory
+    /// is peeked at, so no device or debugging check is set off.
+    pub fn record<M: Memory>(&mut self, cpu: &CPU<M>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry::capture(cpu));
+    }
+
+    /// The entries recorded, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Writes one line per instruction, oldest first:
+    ///
+    /// ```text
+    /// 0100  3e 42     MVI A      A=00 F=02 B=00 C=00 D=00 E=00 H=00 L=00 SP=0000 cycles=0
+    /// ```
+    pub fn write_text(&self, w: &mut impl Write) -> io::Result<()> {
+        for entry in &self.entries {
+            let s = &entry.state;
+            writeln!(w, "{:04x}  {:<8}  {:<9}  A={:02x} F={:02x} B={:02x} C={:02x} D={:02x} E={:02x} H={:02x} L={:02x} SP={:04x} cycles={}",
+                     entry.pc, hex(entry.bytes()), OPCODES[usize::from(entry.bytes[0])].mnemonic,
+                     s.a, s.f, s.b, s.c, s.d, s.e, s.h, s.l, s.sp, entry.cycles)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the entries as CSV with a header line, numbers in hex but
+    /// for the cycle count.
+    pub fn write_csv(&self, w: &mut impl Write) -> io::Result<()> {
+        writeln!(w, "pc,bytes,a,f,b,c,d,e,h,l,sp,cycles")?;
+        for entry in &self.entries {
+            let s = &entry.state;
+            writeln!(w, "{:04x},{},{:02x},{:02x},{:02x},{:02x},{:02x},{:02x},{:02x},{:02x},{:04x},{}",
+                     entry.pc, hex(entry.bytes()), s.a, s.f, s.b, s.c, s.d, s.e, s.h, s.l, s.sp, entry.cycles)?;
+        }
+        Ok(())
+    }
+}
+
+impl<M: Memory> Instrument<M> for TraceBuffer {
+    fn record(&mut self, cpu: &CPU<M>) {
+        TraceBuffer::record(self, cpu);
+    }
+}
+
+/// Streams every instruction recorded to a binary trace. Give it a
+/// buffered writer; `create` does. The first write error stops the
+/// trace, and `finish` reports it.
+pub struct TraceWriter<W: Write> {
+    output: W,
+    records: u64,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Starts a trace by writing `MAGIC` to `output`.
+    pub fn new(mut output: W) -> io::Result<Self> {
+        output.write_all(&MAGIC)?;
+        Ok(TraceWriter { output, records: 0, error: None })
+    }
+
+    /// Writes the instruction `cpu` is about to run. Call it between
+    /// instructions.
+    pub fn record<M: Memory>(&mut self, cpu: &CPU<M>) {
+        if self.error.is_some() {
+            return;
+        }
+        match self.output.write_all(&Entry::capture(cpu).to_record()) {
+            Ok(()) => self.records += 1,
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    /// How many instructions have been written.
+    pub fn len(&self) -> u64 {
+        self.records
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records == 0
+    }
+
+    /// Flushes the trace and hands back the writer, or the first error.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+impl TraceWriter<BufWriter<File>> {
+    /// A trace written to a new file at `path`.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        TraceWriter::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<M: Memory, W: Write> Instrument<M> for TraceWriter<W> {
+    fn record(&mut self, cpu: &CPU<M>) {
+        TraceWriter::record(self, cpu);
+    }
+}
+
+/// Reads a binary trace, as an iterator over its entries in order or, on
+/// a seekable input, an entry at a time by index.
+pub struct TraceReader<R: Read> {
+    input: R,
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Checks that `input` starts with `MAGIC`.
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut magic = [0x00; 4];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a binary trace"));
+        }
+        Ok(TraceReader { input })
+    }
+
+    // The next record, or `None` at the end of the trace
+    fn next_entry(&mut self) -> io::Result<Option<Entry>> {
+        let mut record = [0x00; RECORD_SIZE];
+        let mut filled = 0;
+        while filled < RECORD_SIZE {
+            match self.input.read(&mut record[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "trace ends mid-record")),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Some(Entry::from_record(&record)))
+    }
+}
+
+impl TraceReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        TraceReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> TraceReader<R> {
+    /// How many instructions the trace holds.
+    pub fn len(&mut self) -> io::Result<u64> {
+        let position = self.input.stream_position()?;
+        let end = self.input.seek(SeekFrom::End(0))?;
+        self.input.seek(SeekFrom::Start(position))?;
+        Ok((end - MAGIC.len() as u64) / RECORD_SIZE as u64)
+    }
+
+    pub fn is_empty(&mut self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The instruction at `index`, counting from 0, or `None` past the
+    /// end. Iteration carries on after it.
+    pub fn get(&mut self, index: u64) -> io::Result<Option<Entry>> {
+        self.input.seek(SeekFrom::Start(MAGIC.len() as u64 + index * RECORD_SIZE as u64))?;
+        self.next_entry()
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<io::Result<Entry>> {
+        self.next_entry().transpose()
+    }
+}
+
+/// The index of the first instruction where two traces differ, counting
+/// one that only one of them has, or `None` if they are the same.
+pub fn first_divergence<A: Read, B: Read>(mut a: TraceReader<A>, mut b: TraceReader<B>) -> io::Result<Option<u64>> {
+    let mut index = 0;
+    loop {
+        match (a.next_entry()?, b.next_entry()?) {
+            (None, None) => return Ok(None),
+            (a, b) if a != b => return Ok(Some(index)),
+            _ => index += 1,
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::memory::Memory8080;
+    use crate::trace::{first_divergence, TraceBuffer, TraceReader, TraceWriter, MAGIC, RECORD_SIZE};
+
+    use std::cell::RefCell;
+    use std::io::{Cursor, ErrorKind};
+    use std::rc::Rc;
+
+    // Runs `steps` instructions of MVI B, `b`; INR B; JMP 0x0002,
+    // tracing them both ways
+    fn run(b: u8, steps: usize) -> (TraceBuffer, Vec<u8>) {
+        let mut memory = [0x00; 0x10000];
+        memory[..7].copy_from_slice(&[0x06, b, 0x04, 0xc3, 0x02, 0x00, 0x00]);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        let mut buffer = TraceBuffer::new(steps);
+        let mut writer = TraceWriter::new(Vec::new()).unwrap();
+        for _ in 0..steps {
+            buffer.record(&cpu);
+            writer.record(&cpu);
+            cpu.step();
+        }
+        assert_eq!(writer.len(), steps as u64);
+        (buffer, writer.finish().unwrap())
+    }
+
+    #[test]
+    fn keeps_the_last_instructions() {
+        // LXI SP, 0x2000; MVI B, 0x07; JMP 0x0000
+        let code = [0x31, 0x00, 0x20, 0x06, 0x07, 0xc3, 0x00, 0x00];
+        let mut memory = [0x00; 0x10000];
+        memory[..code.len()].copy_from_slice(&code);
+        let mut cpu = CPU::new(Rc::new(RefCell::new(Memory8080::new(memory))));
+        let mut trace = TraceBuffer::new(3);
+        for _ in 0..4 {
+            trace.record(&cpu);
+            cpu.step();
+        }
+        assert_eq!(trace.len(), 3);
+        let pcs: Vec<_> = trace.entries().map(|e| (e.pc, e.bytes().to_vec(), e.cycles)).collect();
+        assert_eq!(pcs, [(0x0003, vec![0x06, 0x07], 10), (0x0005, vec![0xc3, 0x00, 0x00], 17), (0x0000, vec![0x31, 0x00, 0x20], 27)]);
+
+        let mut csv = Vec::new();
+        trace.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines[0], "pc,bytes,a,f,b,c,d,e,h,l,sp,cycles");
+        assert_eq!(lines[3], "0000,31 00 20,00,02,07,00,00,00,00,00,2000,27");
+
+        let mut text = Vec::new();
+        trace.write_text(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert_eq!(text.lines().nth(1).unwrap(),
+                   "0005  c3 00 00  JMP        A=00 F=02 B=07 C=00 D=00 E=00 H=00 L=00 SP=2000 cycles=17");
+
+        trace.clear();
+        assert!(trace.is_empty());
+        TraceBuffer::new(0).record(&cpu);
+    }
+
+    #[test]
+    fn binary_traces() {
+        let (buffer, trace) = run(0x10, 5);
+        assert_eq!(trace.len(), MAGIC.len() + 5 * RECORD_SIZE);
+        let entries: Vec<_> = TraceReader::new(&trace[..]).unwrap().map(Result::unwrap).collect();
+        assert_eq!(entries, buffer.entries().copied().collect::<Vec<_>>());
+
+        let mut reader = TraceReader::new(Cursor::new(&trace)).unwrap();
+        assert_eq!(reader.len().unwrap(), 5);
+        let entry = reader.get(3).unwrap().unwrap();
+        assert_eq!((entry.pc, entry.bytes(), entry.state.b, entry.cycles), (0x0002, &[0x04][..], 0x11, 22));
+        assert_eq!(reader.next().unwrap().unwrap().pc, 0x0003);
+        assert!(reader.next().is_none());
+        assert!(reader.get(5).unwrap().is_none());
+
+        let truncated = TraceReader::new(&trace[..trace.len() - 1]).unwrap().last().unwrap();
+        assert_eq!(truncated.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(TraceReader::new(&b"I80\x02"[..]).err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn divergence() {
+        let trace = |b, steps| TraceReader::new(Cursor::new(run(b, steps).1)).unwrap();
+        assert_eq!(first_divergence(trace(0x10, 6), trace(0x10, 6)).unwrap(), None);
+        // The MVI operands already differ
+        assert_eq!(first_divergence(trace(0x10, 6), trace(0x20, 6)).unwrap(), Some(0));
+        assert_eq!(first_divergence(trace(0x10, 6), trace(0x10, 4)).unwrap(), Some(4));
+    }
+}
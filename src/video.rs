@@ -0,0 +1,191 @@
+//! Turning 1 bit per pixel video RAM into RGBA pixels for a front-end to
+//! put on screen.
+//!
+//! Video RAM holds the picture a row at a time, `width / 8` bytes per
+//! row, with the leftmost pixel of each byte in its lowest bit. Cabinets
+//! often mounted the monitor on its side, which `Rotation` undoes, and
+//! laid strips of colored cellophane over it, which `Overlay`s stand for.
+//! Pixels are RGBA packed as 0xRRGGBBAA.
+//!
+//! ```
+//! use i8080_emulator::memory::{Memory, Memory8080};
+//! use i8080_emulator::video::{Framebuffer, BLACK, WHITE};
+//!
+//! let mut memory = Memory8080::new_empty();
+//! memory.write(0x2400, 0b0000_0101);
+//! let pixels = Framebuffer::new(0x2400, 8, 1).render(&memory);
+//! assert_eq!(pixels, [WHITE, BLACK, WHITE, BLACK, BLACK, BLACK, BLACK, BLACK]);
+//! ```
+
+use crate::memory::Memory;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+pub const BLACK: u32 = 0x0000_00ff;
+pub const WHITE: u32 = 0xffff_ffff;
+pub const RED: u32 = 0xff00_00ff;
+pub const GREEN: u32 = 0x00ff_00ff;
+
+/// How the picture is turned on its way to the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    None,
+    /// A quarter turn clockwise: the first row of video RAM ends up as the
+    /// rightmost column.
+    Clockwise,
+    /// A quarter turn counterclockwise: the first row of video RAM ends up
+    /// as the leftmost column, bottom to top.
+    Counterclockwise,
+}
+
+/// A rectangle of the screen, after rotation, where lit pixels take
+/// `color` instead of the foreground.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overlay {
+    pub rows: Range<usize>,
+    pub columns: Range<usize>,
+    pub color: u32,
+}
+
+/// Where a 1 bit per pixel picture is in memory and how to show it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framebuffer {
+    pub base: u16,
+    /// Pixels per row of video RAM, a multiple of 8.
+    pub width: usize,
+    /// Rows of video RAM.
+    pub height: usize,
+    pub rotation: Rotation,
+    pub foreground: u32,
+    pub background: u32,
+    /// Later overlays win where they overlap.
+    pub overlays: Vec<Overlay>,
+}
+
+impl Framebuffer {
+    /// A `width` by `height` picture at `base`, white on black and not
+    /// rotated.
+    pub fn new(base: u16, width: usize, height: usize) -> Self {
+        Framebuffer {
+            base,
+            width,
+            height,
+            rotation: Rotation::None,
+            foreground: WHITE,
+            background: BLACK,
+            overlays: Vec::new(),
+        }
+    }
+
+    /// Space Invaders: 256 by 224 at 0x2400 on a monitor turned
+    /// counterclockwise, with a red strip over the flying saucer and a
+    /// green one over the player and the spare bases.
+    pub fn space_invaders() -> Self {
+        let mut framebuffer = Framebuffer::new(0x2400, 256, 224);
+        framebuffer.rotation = Rotation::Counterclockwise;
+        framebuffer.overlays = vec![
+            Overlay { rows: 32..64, columns: 0..224, color: RED },
+            Overlay { rows: 184..240, columns: 0..224, color: GREEN },
+            Overlay { rows: 240..256, columns: 16..134, color: GREEN },
+        ];
+        framebuffer
+    }
+
+    /// Width and height of the picture on screen, after rotation.
+    pub fn size(&self) -> (usize, usize) {
+        match self.rotation {
+            Rotation::None => (self.width, self.height),
+            Rotation::Clockwise | Rotation::Counterclockwise => (self.height, self.width),
+        }
+    }
+
+    /// The picture as `size()` pixels, row by row from the top left.
+    pub fn render(&self, memory: &impl Memory) -> Vec<u32> {
+        let (width, height) = self.size();
+        let mut pixels = vec![0; width * height];
+        self.render_into(memory, &mut pixels);
+        pixels
+    }
+
+    /// `render` into a buffer the front-end keeps from frame to frame.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pixels` holds fewer than `size()` pixels.
+    pub fn render_into(&self, memory: &impl Memory, pixels: &mut [u32]) {
+        let (screen_width, _) = self.size();
+        let bytes_per_row = self.width / 8;
+        for y in 0..self.height {
+            for column in 0..bytes_per_row {
+                let addr = usize::from(self.base) + y * bytes_per_row + column;
+                let byte = memory.read(addr & 0xffff);
+                for bit in 0..8 {
+                    let x = column * 8 + bit;
+                    let (col, row) = match self.rotation {
+                        Rotation::None => (x, y),
+                        Rotation::Clockwise => (self.height - 1 - y, x),
+                        Rotation::Counterclockwise => (y, self.width - 1 - x),
+                    };
+                    let color = if byte & (1 << bit) != 0 { self.lit(row, col) } else { self.background };
+                    pixels[row * screen_width + col] = color;
+                }
+            }
+        }
+    }
+
+    // The color of a lit pixel at `row` and `col` on screen
+    fn lit(&self, row: usize, col: usize) -> u32 {
+        self.overlays
+            .iter()
+            .rev()
+            .find(|overlay| overlay.rows.contains(&row) && overlay.columns.contains(&col))
+            .map_or(self.foreground, |overlay| overlay.color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::{Memory, Memory8080};
+    use crate::video::{Framebuffer, Overlay, Rotation, BLACK, GREEN, RED, WHITE};
+
+    // Renders a 16 by 2 picture with the first and last pixels of its
+    // first row lit
+    fn render(rotation: Rotation) -> Vec<u32> {
+        let mut memory = Memory8080::new_empty();
+        memory.write(0x1000, 0x01);
+        memory.write(0x1001, 0x80);
+        let mut framebuffer = Framebuffer::new(0x1000, 16, 2);
+        framebuffer.rotation = rotation;
+        framebuffer.render(&memory)
+    }
+
+    fn lit(pixels: &[u32]) -> Vec<usize> {
+        pixels.iter().enumerate().filter(|(_, &p)| p == WHITE).map(|(i, _)| i).collect()
+    }
+
+    #[test]
+    fn rotations() {
+        assert_eq!(lit(&render(Rotation::None)), [0, 15]);
+        // 2 wide and 16 tall: the first row becomes the right column, top
+        // to bottom, or the left column, bottom to top
+        assert_eq!(lit(&render(Rotation::Clockwise)), [1, 31]);
+        assert_eq!(lit(&render(Rotation::Counterclockwise)), [0, 30]);
+        assert_eq!(render(Rotation::None)[1], BLACK);
+    }
+
+    #[test]
+    fn overlays() {
+        let mut memory = Memory8080::new_empty();
+        memory.write(0x0000, 0xff);
+        let mut framebuffer = Framebuffer::new(0x0000, 8, 1);
+        framebuffer.overlays.push(Overlay { rows: 0..1, columns: 2..6, color: RED });
+        framebuffer.overlays.push(Overlay { rows: 0..1, columns: 4..8, color: GREEN });
+        assert_eq!(framebuffer.render(&memory), [WHITE, WHITE, RED, RED, GREEN, GREEN, GREEN, GREEN]);
+
+        let invaders = Framebuffer::space_invaders();
+        assert_eq!(invaders.size(), (224, 256));
+        assert_eq!(invaders.render(&memory).len(), 224 * 256);
+    }
+}
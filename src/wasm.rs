@@ -0,0 +1,144 @@
+//! A wasm-bindgen wrapper for browser front-ends, enabled with the `wasm`
+//! feature.
+//!
+//! `WasmMachine` is a CPU in 64K of RAM with a ROM loaded, 256 input
+//! ports the page sets from key state and a log of output port writes.
+//! The page calls `tick` once per animation frame and draws the
+//! framebuffer from `read_memory`. See `examples/wasm/index.html`.
+
+use crate::cpu::{Event, Timestamp, CPU};
+use crate::device::Device;
+use crate::io::PortBus;
+use crate::irq::{IrqLine, Trigger};
+use crate::memory::{Memory, Memory8080};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+
+// Input ports the page sets and output writes it collects
+struct Ports {
+    inputs: [u8; 0x100],
+    // Port and value of every OUT since the page last looked
+    writes: Vec<u8>,
+}
+
+impl Device for Ports {
+    fn read(&mut self, port: u8) -> u8 {
+        self.inputs[usize::from(port)]
+    }
+
+    fn write(&mut self, port: u8, value: u8) {
+        self.writes.extend([port, value]);
+    }
+}
+
+/// A machine for JavaScript to drive.
+#[wasm_bindgen]
+pub struct WasmMachine {
+    cpu: CPU,
+    bus: PortBus,
+    ports: Rc<RefCell<Ports>>,
+    irq: IrqLine,
+}
+
+#[wasm_bindgen]
+impl WasmMachine {
+    /// Loads `rom` at `org` and starts running there. Bytes past the top
+    /// of memory are dropped.
+    #[wasm_bindgen(constructor)]
+    pub fn new(rom: &[u8], org: u16) -> WasmMachine {
+        let mut memory = Memory8080::new_empty();
+        for (addr, &byte) in (usize::from(org)..0x10000).zip(rom) {
+            memory.write(addr, byte);
+        }
+        let mut cpu = CPU::new(Rc::new(RefCell::new(memory)));
+        cpu.pc = org;
+        let ports = Rc::new(RefCell::new(Ports { inputs: [0x00; 0x100], writes: Vec::new() }));
+        let mut bus = PortBus::new();
+        for port in 0..=0xff {
+            bus.map(port, ports.clone());
+        }
+        WasmMachine { cpu, bus, ports, irq: IrqLine::new(Trigger::Edge) }
+    }
+
+    /// Runs for at least `cycles` clock cycles, taking a requested
+    /// interrupt as soon as the program enables interrupts. Returns the
+    /// cycles run, short of `cycles` only if a fault stopped the CPU.
+    pub fn tick(&mut self, cycles: u32) -> u32 {
+        let start = self.cpu.cycles();
+        while self.cpu.cycles() - start < Timestamp::from(cycles) {
+            let event = match self.irq.service(&mut self.cpu) {
+                Some(event) => event,
+                None => self.bus.step(&mut self.cpu),
+            };
+            if let Event::Fault(_) = event {
+                break;
+            }
+        }
+        (self.cpu.cycles() - start) as u32
+    }
+
+    /// Requests an interrupt with `op`, usually an RST. It is taken once,
+    /// when interrupts are next enabled.
+    pub fn interrupt(&mut self, op: u8) {
+        self.irq.raise(op);
+        self.irq.lower();
+    }
+
+    /// Sets what IN reads from `port`, such as the state of the keys
+    /// wired to it.
+    pub fn set_port(&mut self, port: u8, value: u8) {
+        self.ports.borrow_mut().inputs[usize::from(port)] = value;
+    }
+
+    /// Returns and clears the OUT writes since the last call, as port and
+    /// value pairs.
+    pub fn take_port_writes(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.ports.borrow_mut().writes)
+    }
+
+    /// Copies `len` bytes from `start`, wrapping round the top of memory,
+    /// for drawing a framebuffer.
+    pub fn read_memory(&self, start: u16, len: u16) -> Vec<u8> {
+        let memory = self.cpu.memory.borrow();
+        (0..len).map(|i| memory.read(usize::from(start.wrapping_add(i)))).collect()
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.cpu.pc
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.cpu.is_halted()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wasm::WasmMachine;
+
+    #[test]
+    fn drives_a_program() {
+        // 0x0000: JMP 0x0100
+        // 0x0008 (RST 1): MVI A, 0x99; STA 0x2401; EI; RET
+        // 0x0100: LXI SP, 0x2000; EI
+        // loop: IN 0x01; STA 0x2400; OUT 0x02; JMP loop
+        let mut rom = vec![0x00; 0x100];
+        rom[..3].copy_from_slice(&[0xc3, 0x00, 0x01]);
+        rom[0x08..0x0f].copy_from_slice(&[0x3e, 0x99, 0x32, 0x01, 0x24, 0xfb, 0xc9]);
+        rom.extend([0x31, 0x00, 0x20, 0xfb, 0xdb, 0x01, 0x32, 0x00, 0x24, 0xd3, 0x02, 0xc3, 0x04, 0x01]);
+        let mut machine = WasmMachine::new(&rom, 0x0000);
+        machine.set_port(0x01, 0x42);
+        assert!(machine.tick(200) >= 200);
+        assert_eq!(machine.read_memory(0x2400, 2), [0x42, 0x00]);
+        assert_eq!(machine.take_port_writes()[..2], [0x02, 0x42]);
+        assert!(machine.take_port_writes().is_empty());
+
+        machine.interrupt(0xcf);
+        machine.tick(100);
+        assert_eq!(machine.read_memory(0x2401, 1), [0x99]);
+        assert_eq!(machine.read_memory(0xffff, 2), [0x00, 0xc3]);
+        assert!(!machine.is_halted());
+    }
+}
@@ -0,0 +1,32 @@
+//! The CPU test suites in `cpu_tests`, run as CP/M programs.
+
+use i8080_emulator::machines::cpm::run_test;
+use i8080_emulator::Limit;
+
+fn check(name: &str, limit: Limit) {
+    let program = std::fs::read(format!("cpu_tests/{}.COM", name)).unwrap();
+    let outcome = run_test(&program, limit).unwrap();
+    assert!(outcome.passed, "{} did not pass ({:?}):\n{}", name, outcome.status, outcome.output);
+}
+
+#[test]
+fn tst8080() {
+    check("TST8080", Limit::Steps(1_000_000));
+}
+
+#[test]
+fn preliminary() {
+    check("8080PRE", Limit::Steps(1_000_000));
+}
+
+#[test]
+fn cputest() {
+    check("CPUTEST", Limit::Steps(1_000_000_000));
+}
+
+// Takes billions of instructions; run with `cargo test --release -- --ignored`
+#[test]
+#[ignore]
+fn exerciser() {
+    check("8080EXM", Limit::Steps(u64::MAX));
+}